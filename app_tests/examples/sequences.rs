@@ -10,10 +10,8 @@ extern crate pippin_app_tests;
 
 use std::path::{Path};
 use std::process::exit;
-use std::cmp::{min, max};
 
 use docopt::Docopt;
-use rand::distributions::{IndependentSample, LogNormal};
 
 use pippin::pip::*;
 use pippin_app_tests::seq::*;
@@ -79,23 +77,13 @@ fn run(path: &Path,
     let merge_solver = TwoWaySolverChain::new(&solver1, &solver2);
     
     let mut rng = rand::thread_rng();
-    let mut generate = |state: &mut StateWrite<_>|
+    let mut generate = |state: &mut MutPartState<_>|
             if let Some(num) = generate_n
         {
-            let len_range = LogNormal::new(1., 2.);
-            let max_len = 1_000;
-            let mut longest = 0;
-            let mut total = 0;
-            for _ in 0..num {
-                let gen = GeneratorEnum::new_random(&mut rng);
-                let len = min(len_range.ind_sample(&mut rng) as usize, max_len);
-                longest = max(longest, len);
-                total += len;
-                let seq = gen.generate(len).into();
-                state.insert_new(seq).expect("insert element");
-            }
+            let spec = GenSpec { num_elts: num, ..Default::default() };
+            let stats = spec.fill_state(state, &mut rng).expect("insert elements");
             println!("Generated {} sequences; longest length {}, average {}",
-                    num, longest, (total as f64) / (num as f64));
+                    num, stats.longest, stats.average_len());
         } else {}
     ;
     
@@ -32,8 +32,22 @@ Usage:
   pippincmd [-h] -H PATH
   pippincmd [-h] [-p NUM] [-P] [-S] [-L] [-C] PATH
   pippincmd [-h] [-f] [-p NUM] [-c COMMIT] [-s] [-E | -g ELT | -e ELT | -v ELT | -d ELT] PATH
+  pippincmd [-h] log PATH
+  pippincmd [-h] show STATESUM PATH
+  pippincmd [-h] diff FROM TO PATH
+  pippincmd [-h] [-c COMMIT] cat-element ELT PATH
   pippincmd --help | --version
 
+Inspection commands (these never write to the repository):
+  log                   List all commits, newest first, with metadata.
+  show STATESUM         Show metadata of commit STATESUM and the elements it
+                        changed relative to its first parent.
+  diff FROM TO          List elements differing between two commits.
+  cat-element ELT       Write the serialised bytes of element ELT (from the
+                        latest state or COMMIT) to standard output.
+  
+  Commits may be abbreviated to any unique prefix of the state sum.
+
 Options:
   -n --new PREFIX       Create a new partition with file name prefix PREFIX.
                         A default state (no elements) is created.
@@ -89,6 +103,14 @@ struct Args {
     flag_force: bool,
     flag_help: bool,
     flag_version: bool,
+    cmd_log: bool,
+    cmd_show: bool,
+    cmd_diff: bool,
+    cmd_cat_element: bool,
+    arg_STATESUM: Option<String>,
+    arg_FROM: Option<String>,
+    arg_TO: Option<String>,
+    arg_ELT: Option<String>,
 }
 
 #[derive(Debug)]
@@ -106,6 +128,14 @@ enum Operation {
     Header,
     List(bool /*list snapshot files?*/, bool /*list log files?*/, bool /*list commits?*/),
     OnPartition(PartitionOp),
+    Inspect(InspectOp),
+}
+#[derive(Debug)]
+enum InspectOp {
+    Log,
+    Show(String),
+    Diff(String, String),
+    CatElement(String),
 }
 
 fn main() {
@@ -126,7 +156,15 @@ fn main() {
         println!("pippincmd version: 1.0.0");
     } else {
         // Rely on docopt to spot invalid conflicting flags
-        let op = if let Some(name) = args.flag_new {
+        let op = if args.cmd_log {
+                Operation::Inspect(InspectOp::Log)
+            } else if args.cmd_show {
+                Operation::Inspect(InspectOp::Show(args.arg_STATESUM.unwrap()))
+            } else if args.cmd_diff {
+                Operation::Inspect(InspectOp::Diff(args.arg_FROM.unwrap(), args.arg_TO.unwrap()))
+            } else if args.cmd_cat_element {
+                Operation::Inspect(InspectOp::CatElement(args.arg_ELT.unwrap()))
+            } else if let Some(name) = args.flag_new {
                 Operation::NewPartition(name, args.flag_repo_name)
             } else if args.flag_header {
                 Operation::Header
@@ -348,6 +386,91 @@ fn inner(path: PathBuf, op: Operation, args: Rest) -> Result<()>
             }
             Ok(())
         },
+        Operation::Inspect(inspect_op) => {
+            let mut part_files = part_from_path(&path)?;
+            part_files.set_readonly(true);
+            let control = DefaultControl::<DataElt, _>::new(part_files);
+            let mut part = Partition::open_read_only(control, true)?;
+            part.load_all()?;
+            match inspect_op {
+                InspectOp::Log => {
+                    let mut states: Vec<_> = part.states_iter().collect();
+                    states.sort_by_key(|s| (s.meta().number(), s.statesum().clone()));
+                    for state in states.into_iter().rev() {
+                        print_meta(&state);
+                        println!();
+                    }
+                },
+                InspectOp::Show(ss) => {
+                    let state = part.state_from_string(ss)?;
+                    print_meta(state);
+                    match state.parents().first().and_then(|p| part.state(p)) {
+                        Some(parent) => print_diff(parent, state),
+                        None if state.parents().is_empty() => println!("(initial state)"),
+                        None => println!("(parent state not available)"),
+                    }
+                },
+                InspectOp::Diff(from, to) => {
+                    let from = part.state_from_string(from)?;
+                    let to = part.state_from_string(to)?;
+                    print_diff(from, to);
+                },
+                InspectOp::CatElement(elt) => {
+                    let id: u64 = elt.parse()?;
+                    let state = match args.commit {
+                        Some(ss) => part.state_from_string(ss)?,
+                        None => part.tip()?,
+                    };
+                    let mut out = std::io::stdout();
+                    state.get(id.into())?.write_buf(&mut out)?;
+                    out.flush()?;
+                },
+            }
+            Ok(())
+        },
+    }
+}
+
+// Print a state's commit metadata
+fn print_meta(state: &PartState<DataElt>) {
+    let meta = state.meta();
+    println!("Commit {}", state.statesum());
+    println!("Number: {}", meta.number());
+    println!("Date:   {}", meta.date_time());
+    for parent in state.parents() {
+        println!("Parent: {}", parent);
+    }
+    if let Some(summary) = meta.summary() {
+        println!("Result: {} elements ({:+} bytes)", summary.elements, summary.byte_delta);
+    }
+    for p in meta.provenance() {
+        println!("Received from replica {} at {}", p.source, p.received);
+    }
+    let extra = meta.extra().text_lossy();
+    if !extra.is_empty() {
+        println!("Extra:  {}", extra);
+    }
+}
+
+// Print elements which differ between two states
+fn print_diff(from: &PartState<DataElt>, to: &PartState<DataElt>) {
+    let commit = match Commit::from_diff(from, to) {
+        Some(commit) => commit,
+        None => {
+            println!("No changes");
+            return;
+        }
+    };
+    let mut changes: Vec<_> = commit.changes_iter().collect();
+    changes.sort_by_key(|&(id, _)| *id);
+    for (id, change) in changes {
+        match *change {
+            EltChange::Deletion => println!("  deleted  {}", id),
+            EltChange::Insertion(ref e) => println!("  inserted {}: {}", id, e),
+            EltChange::Replacement(ref e) => println!("  replaced {}: {}", id, e),
+            EltChange::Erased(ref sum) => println!("  erased   {} (sum {})", id, sum),
+            EltChange::Operation(ref ops, _) => println!("  modified {} ({} operations)", id, ops.len()),
+        }
     }
 }
 
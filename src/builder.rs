@@ -0,0 +1,116 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: convenient construction of partitions
+
+#[cfg(feature = "file-io")]
+use std::path::{Path, PathBuf};
+
+use control::{Control, DefaultControl};
+use elt::Element;
+use error::{Result, ArgError};
+#[cfg(feature = "file-io")]
+use io::discover::part_from_path;
+#[cfg(feature = "file-io")]
+use io::file::RepoFileIO;
+use io::RepoIO;
+use part::Partition;
+
+
+/// Builder for a `Partition`.
+/// 
+/// First choose how data is stored: `with_control` (any `Control`),
+/// `with_io` (a `DefaultControl` around any `RepoIO`), or with the
+/// `file-io` feature `at_prefix` or `discover` (a `DefaultControl` using
+/// files). Then finish with `create`, `open` or `open_or_create`.
+/// 
+/// Example:
+/// 
+/// ```no_run
+/// use pippin::pip::{PartitionBuilder, DefaultControl};
+/// 
+/// let part = PartitionBuilder::<DefaultControl<String, _>>::at_prefix("data/notes")
+///         .open_or_create("notes")
+///         .unwrap();
+/// ```
+pub struct PartitionBuilder<C: Control> {
+    control: C,
+}
+
+impl<C: Control> PartitionBuilder<C> {
+    /// Use the given `Control`.
+    pub fn with_control(control: C) -> PartitionBuilder<C> {
+        PartitionBuilder { control }
+    }
+    
+    /// Create a new partition with the given name (see `Partition::create`).
+    pub fn create(self, name: &str) -> Result<Partition<C>> {
+        Partition::create(self.control, name)
+    }
+    
+    /// Open an existing partition and load its latest state (see
+    /// `Partition::open`).
+    pub fn open(self) -> Result<Partition<C>> {
+        Partition::open(self.control, true)
+    }
+    
+    /// Open an existing partition without loading data; see
+    /// `Partition::open`.
+    pub fn open_unloaded(self) -> Result<Partition<C>> {
+        Partition::open(self.control, false)
+    }
+    
+    /// Open an existing partition read-only and load its latest state (see
+    /// `Partition::open_read_only`).
+    pub fn open_read_only(self) -> Result<Partition<C>> {
+        Partition::open_read_only(self.control, true)
+    }
+    
+    /// Open the partition if any files exist, otherwise create it.
+    /// 
+    /// When opening, fails if the partition's name does not equal `name`.
+    pub fn open_or_create(self, name: &str) -> Result<Partition<C>> {
+        if self.control.io().ss_len() == 0 {
+            return self.create(name);
+        }
+        let part = self.open()?;
+        if part.name() != name {
+            return ArgError::err("partition name does not match that expected");
+        }
+        Ok(part)
+    }
+}
+
+impl<E: Element, IO: RepoIO> PartitionBuilder<DefaultControl<E, IO>> {
+    /// Use a `DefaultControl` with the given I/O provider.
+    pub fn with_io(io: IO) -> PartitionBuilder<DefaultControl<E, IO>> {
+        PartitionBuilder::with_control(DefaultControl::new(io))
+    }
+}
+
+#[cfg(feature = "file-io")]
+impl<E: Element> PartitionBuilder<DefaultControl<E, RepoFileIO>> {
+    /// Use a `DefaultControl` with files named from `prefix` (a directory
+    /// plus partial file name; see `RepoFileIO::new`).
+    /// 
+    /// Existing files with this prefix are found (see `part_from_path`),
+    /// thus this can be used both to create and to open a partition.
+    pub fn at_prefix<P: Into<PathBuf>>(prefix: P) -> PartitionBuilder<DefaultControl<E, RepoFileIO>> {
+        let prefix = prefix.into();
+        let mut file = prefix.as_os_str().to_os_string();
+        file.push("-ss0.pip");
+        // Discovery fails if no files exist, in which case we start afresh:
+        let io = match part_from_path(&file) {
+            Ok(io) => io,
+            Err(_) => RepoFileIO::new(prefix),
+        };
+        PartitionBuilder::with_io(io)
+    }
+    
+    /// Use a `DefaultControl` with files discovered from `path` (see
+    /// `part_from_path`). Fails if no partition files are found.
+    pub fn discover<P: AsRef<Path>>(path: P) -> Result<PartitionBuilder<DefaultControl<E, RepoFileIO>>> {
+        Ok(PartitionBuilder::with_io(part_from_path(path)?))
+    }
+}
@@ -10,34 +10,139 @@ use std::rc::Rc;
 use std::u32;
 use std::cmp::max;
 use std::ops::BitOr;
+use std::borrow::Cow;
+
+use byteorder::{ByteOrder, BigEndian};
 
 use chrono::{DateTime, NaiveDateTime, UTC};
 
-use state::{PartState, MutPartState, StateWrite};
+use state::{PartState, MutPartState, StateRead, StateWrite};
 use elt::{Element, EltId};
-use sum::Sum;
-use error::{Result, ElementOp, OtherError};
+use sum::{Sum, SUM_BYTES};
+use error::{Result, ElementOp, PatchOp, ArgError, RepoError};
 
 
 /// User-specified extra commit metadata. This allows users to tag commits with extra information
 /// (e.g. author, comment).
 /// 
-/// Currently the only supported non-empty type is UTF-8 text (designated XMTT
-/// in files), but the file format and API allows for future extensions.
+/// Supported non-empty types are UTF-8 text (designated XMTT in files) and
+/// binary data (XMBB); the file format and API allows for future extensions.
+/// 
+/// Only the content is included in state checksums, not the type, thus
+/// `Text(t)` and `Bytes(t.into_bytes())` yield the same checksum.
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum UserMeta {
     /// No extra metadata
     None,
     /// Extra metadata as a simple text field
     Text(String),
+    /// Extra metadata as binary data
+    Bytes(Vec<u8>),
+}
+
+impl UserMeta {
+    /// Get the content as a byte slice (empty for `None`).
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            UserMeta::None => &[],
+            UserMeta::Text(ref text) => text.as_bytes(),
+            UserMeta::Bytes(ref data) => data,
+        }
+    }
+    
+    /// Get the content as text. Invalid UTF-8 sequences in `Bytes` content
+    /// are replaced (see `String::from_utf8_lossy`).
+    pub fn text_lossy<'a>(&'a self) -> Cow<'a, str> {
+        match *self {
+            UserMeta::None => Cow::Borrowed(""),
+            UserMeta::Text(ref text) => Cow::Borrowed(text),
+            UserMeta::Bytes(ref data) => String::from_utf8_lossy(data),
+        }
+    }
+    
+    /// Check the content against the given limits.
+    pub fn validate(&self, limits: &UserMetaLimits) -> Result<(), ArgError> {
+        if self.as_bytes().len() > limits.max_len as usize {
+            return Err(ArgError::new("user metadata exceeds length limit"));
+        }
+        Ok(())
+    }
+}
+
+/// How to handle text `UserMeta` which is not valid UTF-8 when reading.
+/// 
+/// Note that replacing invalid characters on reading is not an option since
+/// this would invalidate checksums; use `UserMeta::text_lossy()` instead.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InvalidText {
+    /// Fail to read the file (the default)
+    Error,
+    /// Read the content as `UserMeta::Bytes`
+    Bytes,
+}
+
+/// Limits applied to `UserMeta`, when creating commits and when reading
+/// files (which may have been written by other replicas). Get via
+/// `Control::user_meta_limits()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UserMetaLimits {
+    /// Maximum length of content, in bytes. Default: 1 MiB.
+    pub max_len: u32,
+    /// Handling of invalid text on reading. Default: `InvalidText::Error`.
+    pub invalid_text: InvalidText,
+}
+impl Default for UserMetaLimits {
+    fn default() -> Self {
+        UserMetaLimits {
+            max_len: 1 << 20,
+            invalid_text: InvalidText::Error,
+        }
+    }
 }
 
 // reclassify bit: deprecated and ignored
 // const FLAG_RECLASSIFY_BIT: u16 = 0b10;
 // const FLAG_RECLASSIFY_MASK: u16 = 0b11;
 
+// acknowledgements: extension data holds ack records (inessential)
+const FLAG_ACKS: u16 = 0b1000;
+// provenance: extension data holds a count and provenance records, before
+// any ack records (inessential)
+const FLAG_PROVENANCE: u16 = 0b10_0000;
+// summary: extension data holds a summary record, after any provenance data
+// and before any ack records (inessential)
+const FLAG_SUMMARY: u16 = 0b1000_0000;
+// author: extension data holds an author record, after any summary and
+// before any ack records (inessential)
+const FLAG_AUTHOR: u16 = 0b10_0000_0000;
+
 const FLAG_ESSENTIAL: u16 = 0b01010101_01010101;
-const FLAG_UNKNOWN: u16 = 0b11111111_11111100;
+const FLAG_UNKNOWN: u16 = 0b11111101_01010100;
+
+// Length of an ack record in extension data
+const ACK_BYTES: usize = 8 + SUM_BYTES;
+// Length of a provenance record in extension data
+const PROVENANCE_BYTES: usize = 16;
+// Length of a summary record in extension data
+const SUMMARY_BYTES: usize = 16;
+
+/// Maximum length in bytes of a commit's author identifier (see
+/// `CommitMeta::set_author`).
+pub const MAX_AUTHOR_BYTES: usize = 64;
+
+/// Maximum number of entries in a commit's provenance chain (see
+/// `CommitMeta::add_provenance`).
+pub const MAX_PROVENANCE: usize = 7;
+
+/// Maximum number of acknowledgements which can be stored in one commit's
+/// metadata (see `CommitMeta::set_acks`). Space is reserved for a full
+/// provenance chain, a summary and an author.
+pub const MAX_ACKS: usize = (255 * 8 - 8 - MAX_PROVENANCE * PROVENANCE_BYTES - SUMMARY_BYTES
+        - 8 - MAX_AUTHOR_BYTES) / ACK_BYTES;
+
+/// Identifier of a replica, as used in acknowledgements (see
+/// `Partition::mark_acked`). Assignment of identifiers is up to the user.
+pub type ReplicaId = u64;
 
 /// Abstraction around metadata flags.
 // TODO: should this be `Eq`? What does equality mean on unknown flags anyway?
@@ -65,6 +170,31 @@ impl MetaFlags {
     pub fn zero() -> MetaFlags {
         MetaFlags { flags: 0 }
     }
+    /// Copy, clearing flags of extensions which file format version
+    /// `format_ver` does not define. In files of older versions such flags
+    /// can only have been inherited (without extension data) via software
+    /// unaware of the extension, and interpreting them would misplace the
+    /// data of other extensions.
+    pub fn defined_in(self, format_ver: u32) -> MetaFlags {
+        let mut flags = self.flags;
+        // versions from 20261022 may have provenance data (see HEAD_VERSIONS)
+        if format_ver < 2026_10_22 {
+            flags &= !FLAG_PROVENANCE;
+        }
+        // versions from 20261023 may have summary data
+        if format_ver < 2026_10_23 {
+            flags &= !FLAG_SUMMARY;
+        }
+        // versions from 20261024 may have author data
+        if format_ver < 2026_10_24 {
+            flags &= !FLAG_AUTHOR;
+        }
+        MetaFlags { flags }
+    }
+    // Copy, without flags describing extension data (which is not inherited)
+    fn inherited(self) -> MetaFlags {
+        MetaFlags { flags: self.flags & !(FLAG_ACKS | FLAG_PROVENANCE | FLAG_SUMMARY | FLAG_AUTHOR) }
+    }
 }
 
 impl BitOr<MetaFlags> for MetaFlags {
@@ -82,7 +212,11 @@ impl BitOr<MetaFlags> for MetaFlags {
 /// *   A time-stamp (usually the UTC time of creation)
 /// 
 /// Additionally, users may attach information via the `UserMeta` struct.
-#[derive(Debug, PartialEq, Clone)]
+/// 
+/// Acknowledgements (see `acks`), provenance (see `provenance`), a summary
+/// (see `summary`) and an author (see `author`) may also be attached. These
+/// are not part of the state sum and are ignored when comparing metadata.
+#[derive(Debug, Clone)]
 pub struct CommitMeta {
     /// Commit number. First (real) commit has number 1, each subsequent commit
     /// has max-parent-number + 1. Can be used to identify commits but is not
@@ -100,6 +234,44 @@ pub struct CommitMeta {
     ext_flags: MetaFlags,
     /// User-provided extra metadata
     extra: UserMeta,
+    /// Acknowledgements of states by replicas (stored as extension data)
+    acks: Vec<(ReplicaId, Sum)>,
+    /// Replicas via which this commit was received (stored as extension data)
+    provenance: Vec<Provenance>,
+    /// Summary of the commit's effect (stored as extension data)
+    summary: Option<CommitSummary>,
+    /// Identifier of the author or device making the commit (stored as
+    /// extension data)
+    author: Option<String>,
+}
+
+/// Records receipt of a commit from another replica (see
+/// `CommitMeta::provenance`)
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Provenance {
+    /// The replica from which the commit was received
+    pub source: ReplicaId,
+    /// Time of receipt (UNIX time-stamp; see `CommitMeta::timestamp`)
+    pub received: i64,
+}
+
+/// Summary of the effect of a commit, recorded when the commit is made (see
+/// `CommitMeta::summary`)
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CommitSummary {
+    /// Number of elements in the resulting state
+    pub elements: u64,
+    /// Change in the total size of serialised element data, relative to the
+    /// first parent (or squash base)
+    pub byte_delta: i64,
+}
+
+impl PartialEq for CommitMeta {
+    fn eq(&self, other: &CommitMeta) -> bool {
+        self.number == other.number && self.timestamp == other.timestamp &&
+            self.ext_flags.inherited() == other.ext_flags.inherited() &&
+            self.extra == other.extra
+    }
 }
 
 /// Partial version of metadata (used by some functions on `CommitMeta`).
@@ -115,28 +287,96 @@ impl CommitMeta {
     pub fn new_parents(parents: Vec<(&Sum, &CommitMeta)>, mcm: &MakeCommitMeta) -> Self {
         let number = parents.iter().fold(0, |prev, &p| max(prev, p.1.next_number()));
         let ext_flags = parents.iter().fold(MetaFlags::zero(), |prev, &p| prev | p.1.ext_flags());
-        CommitMeta {
+        let mut meta = CommitMeta {
             number: number,
             timestamp: mcm.make_commit_timestamp(),
-            ext_flags: ext_flags,
+            ext_flags: ext_flags.inherited(),
             extra: mcm.make_commit_extra(number, parents),
-        }
+            acks: vec![],
+            provenance: vec![],
+            summary: None,
+            author: None,
+        };
+        meta.set_author_from(mcm);
+        meta
     }
     /// Create, explicitly providing all fields.
+    /// 
+    /// Extension data is interpreted according to `ext_flags`; currently
+    /// provenance and acknowledgements are stored there.
     pub fn new_explicit(number: u32, timestamp: i64, ext_flags: MetaFlags,
-            _ext_data: Vec<u8>, extra: UserMeta) -> Result<Self, OtherError>
+            ext_data: Vec<u8>, extra: UserMeta) -> Result<Self, RepoError>
     {
         if (ext_flags.unknown_essential()) {
-            return Err(OtherError::new("found essential unknown commit meta flag"));
+            return Err(RepoError::UnknownMetaFlags { flags: ext_flags.raw() });
+        }
+        let mut ext_data = &ext_data[..];
+        let mut provenance = vec![];
+        if ext_flags.raw() & FLAG_PROVENANCE != 0 {
+            let n = if ext_data.len() >= 8 { BigEndian::read_u64(&ext_data[0..8]) as usize } else { 0 };
+            if ext_data.len() < 8 || n > MAX_PROVENANCE || ext_data.len() < 8 + n * PROVENANCE_BYTES {
+                // the extension is inessential, so we do not fail
+                warn!("ignoring malformed provenance data in commit meta");
+                ext_data = &[];
+            } else {
+                for rec in ext_data[8..8 + n * PROVENANCE_BYTES].chunks(PROVENANCE_BYTES) {
+                    provenance.push(Provenance {
+                        source: BigEndian::read_u64(&rec[0..8]),
+                        received: BigEndian::read_i64(&rec[8..16]),
+                    });
+                }
+                ext_data = &ext_data[8 + n * PROVENANCE_BYTES..];
+            }
+        }
+        let mut summary = None;
+        if ext_flags.raw() & FLAG_SUMMARY != 0 {
+            if ext_data.len() < SUMMARY_BYTES {
+                // the extension is inessential, so we do not fail
+                warn!("ignoring malformed summary data in commit meta");
+                ext_data = &[];
+            } else {
+                summary = Some(CommitSummary {
+                    elements: BigEndian::read_u64(&ext_data[0..8]),
+                    byte_delta: BigEndian::read_i64(&ext_data[8..16]),
+                });
+                ext_data = &ext_data[SUMMARY_BYTES..];
+            }
         }
-        // ignore ext_data because we can and don't currently store anything there
-        Ok(CommitMeta { number: number, timestamp: timestamp, ext_flags: ext_flags, extra: extra })
+        let mut author = None;
+        if ext_flags.raw() & FLAG_AUTHOR != 0 {
+            let n = if ext_data.len() >= 8 { BigEndian::read_u64(&ext_data[0..8]) as usize } else { 0 };
+            let len = 8 * n.div_ceil(8);
+            if ext_data.len() < 8 || n > MAX_AUTHOR_BYTES || ext_data.len() < 8 + len {
+                // the extension is inessential, so we do not fail
+                warn!("ignoring malformed author data in commit meta");
+                ext_data = &[];
+            } else {
+                match String::from_utf8(ext_data[8..8 + n].to_vec()) {
+                    Ok(name) => author = Some(name),
+                    Err(_) => warn!("ignoring invalid author in commit meta"),
+                }
+                ext_data = &ext_data[8 + len..];
+            }
+        }
+        let mut acks = vec![];
+        if ext_flags.raw() & FLAG_ACKS != 0 {
+            if ext_data.len() % ACK_BYTES != 0 {
+                // the extension is inessential, so we do not fail
+                warn!("ignoring malformed acknowledgement data in commit meta");
+            } else {
+                for rec in ext_data.chunks(ACK_BYTES) {
+                    acks.push((BigEndian::read_u64(&rec[0..8]), Sum::load(&rec[8..])));
+                }
+            }
+        }
+        Ok(CommitMeta { number: number, timestamp: timestamp, ext_flags: ext_flags, extra: extra,
+                acks, provenance, summary, author })
     }
     /// Create a partial new version from a single parent.
     /// 
     /// This is for use with `from_partial()`.
     pub fn new_partial(par_sum: Sum, par_meta: CommitMeta) -> CommitMetaPartial {
-        let ext_flags = par_meta.ext_flags();   // copied to allow modification
+        let ext_flags = par_meta.ext_flags().inherited();   // copied to allow modification
         CommitMetaPartial {
             parent: (par_sum, par_meta),
             ext_flags: ext_flags,
@@ -149,17 +389,25 @@ impl CommitMeta {
         let number = partial.parent.1.next_number();
         let parent = (&partial.parent.0, &partial.parent.1);
         
-        CommitMeta {
+        let mut meta = CommitMeta {
             number: number,
             timestamp: mcm.make_commit_timestamp(),
             ext_flags: partial.ext_flags,
             extra: mcm.make_commit_extra(number, vec![parent]),
-        }
+            acks: vec![],
+            provenance: vec![],
+            summary: None,
+            author: None,
+        };
+        meta.set_author_from(mcm);
+        meta
     }
     
     /// Utility method to create a timestamp representing this moment.
     /// 
-    /// Code is `UTC::now().timestamp()`, using `chrono::UTC`.
+    /// Code is `UTC::now().timestamp()`, using `chrono::UTC`. Only available
+    /// with the `system-clock` feature.
+    #[cfg(feature = "system-clock")]
     pub fn timestamp_now() -> i64 {
         UTC::now().timestamp()
     }
@@ -200,6 +448,125 @@ impl CommitMeta {
     pub fn extra(&self) -> &UserMeta {
         &self.extra
     }
+    
+    /// Get acknowledgements recorded with this commit: pairs of replica
+    /// identifier and state sum acknowledged by that replica (see
+    /// `Partition::mark_acked`).
+    pub fn acks(&self) -> &[(ReplicaId, Sum)] {
+        &self.acks
+    }
+    
+    /// Set acknowledgements recorded with this commit. This does not affect
+    /// the state sum. Fails if more than `MAX_ACKS` are given.
+    pub fn set_acks(&mut self, acks: Vec<(ReplicaId, Sum)>) -> Result<(), ArgError> {
+        if acks.len() > MAX_ACKS {
+            return Err(ArgError::new("too many acknowledgements for commit meta"));
+        }
+        if acks.is_empty() {
+            self.ext_flags = MetaFlags::from_raw(self.ext_flags.raw() & !FLAG_ACKS);
+        } else {
+            self.ext_flags = MetaFlags::from_raw(self.ext_flags.raw() | FLAG_ACKS);
+        }
+        self.acks = acks;
+        Ok(())
+    }
+    
+    /// Get the provenance chain: the replicas via which this commit was
+    /// received, starting with the first receipt. This is empty for commits
+    /// made locally.
+    pub fn provenance(&self) -> &[Provenance] {
+        &self.provenance
+    }
+    
+    /// Record receipt of this commit from replica `source` at time
+    /// `received`. This does not affect the state sum. The chain holds at
+    /// most `MAX_PROVENANCE` entries; further entries are not recorded (and
+    /// false is returned), thus the origin is always kept.
+    pub fn add_provenance(&mut self, source: ReplicaId, received: i64) -> bool {
+        if self.provenance.len() >= MAX_PROVENANCE {
+            return false;
+        }
+        self.provenance.push(Provenance { source, received });
+        self.ext_flags = MetaFlags::from_raw(self.ext_flags.raw() | FLAG_PROVENANCE);
+        true
+    }
+    
+    /// Get the summary of this commit's effect, if recorded. Partitions
+    /// record this for commits they add (see `Partition::push_state`);
+    /// commits made by older versions have none.
+    pub fn summary(&self) -> Option<CommitSummary> {
+        self.summary
+    }
+    
+    /// Set or clear the summary. This does not affect the state sum.
+    pub fn set_summary(&mut self, summary: Option<CommitSummary>) {
+        if summary.is_some() {
+            self.ext_flags = MetaFlags::from_raw(self.ext_flags.raw() | FLAG_SUMMARY);
+        } else {
+            self.ext_flags = MetaFlags::from_raw(self.ext_flags.raw() & !FLAG_SUMMARY);
+        }
+        self.summary = summary;
+    }
+    
+    /// Get the identifier of the author or device which made this commit, if
+    /// recorded (see `MakeCommitMeta::make_commit_author`).
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+    
+    /// Set or clear the author identifier. This does not affect the state
+    /// sum. Fails if longer than `MAX_AUTHOR_BYTES` or empty.
+    pub fn set_author(&mut self, author: Option<String>) -> Result<(), ArgError> {
+        if let Some(ref a) = author {
+            if a.is_empty() || a.len() > MAX_AUTHOR_BYTES {
+                return Err(ArgError::new("invalid author length for commit meta"));
+            }
+            self.ext_flags = MetaFlags::from_raw(self.ext_flags.raw() | FLAG_AUTHOR);
+        } else {
+            self.ext_flags = MetaFlags::from_raw(self.ext_flags.raw() & !FLAG_AUTHOR);
+        }
+        self.author = author;
+        Ok(())
+    }
+    
+    // Set the author from `mcm`, ignoring invalid identifiers
+    fn set_author_from(&mut self, mcm: &MakeCommitMeta) {
+        if let Err(e) = self.set_author(mcm.make_commit_author()) {
+            warn!("ignoring author: {}", e);
+        }
+    }
+    
+    /// Get extension data, as written to files
+    pub fn ext_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        if !self.provenance.is_empty() {
+            data.resize(8 + self.provenance.len() * PROVENANCE_BYTES, 0);
+            BigEndian::write_u64(&mut data[0..8], self.provenance.len() as u64);
+            for (rec, p) in data[8..].chunks_mut(PROVENANCE_BYTES).zip(&self.provenance) {
+                BigEndian::write_u64(&mut rec[0..8], p.source);
+                BigEndian::write_i64(&mut rec[8..16], p.received);
+            }
+        }
+        if let Some(summary) = self.summary {
+            let start = data.len();
+            data.resize(start + SUMMARY_BYTES, 0);
+            BigEndian::write_u64(&mut data[start..start + 8], summary.elements);
+            BigEndian::write_i64(&mut data[start + 8..start + 16], summary.byte_delta);
+        }
+        if let Some(ref author) = self.author {
+            let start = data.len();
+            data.resize(start + 8 + 8 * author.len().div_ceil(8), 0);
+            BigEndian::write_u64(&mut data[start..start + 8], author.len() as u64);
+            data[start + 8..start + 8 + author.len()].copy_from_slice(author.as_bytes());
+        }
+        let start = data.len();
+        data.resize(start + self.acks.len() * ACK_BYTES, 0);
+        for (rec, ack) in data[start..].chunks_mut(ACK_BYTES).zip(&self.acks) {
+            BigEndian::write_u64(&mut rec[0..8], ack.0);
+            ack.1.write_to(&mut &mut rec[8..]).expect("writing to buf");
+        }
+        data
+    }
 }
 
 impl CommitMetaPartial {
@@ -225,15 +592,61 @@ impl CommitMetaPartial {
 }
 
 
+/// A source of the current time, used for commit timestamps.
+/// 
+/// This allows use on targets without a system clock (e.g. WASM or embedded
+/// targets); see `MakeCommitMeta::clock()`.
+pub trait Clock {
+    /// Get the current time as seconds since the UNIX epoch (UTC), or `None`
+    /// if the time is not available.
+    fn now(&self) -> Option<i64>;
+}
+
+/// Clock using the system time (via `chrono`). Only available with the
+/// `system-clock` feature (enabled by default).
+#[cfg(feature = "system-clock")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+#[cfg(feature = "system-clock")]
+impl Clock for SystemClock {
+    fn now(&self) -> Option<i64> {
+        Some(CommitMeta::timestamp_now())
+    }
+}
+
+/// Clock which never knows the time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoClock;
+impl Clock for NoClock {
+    fn now(&self) -> Option<i64> {
+        None
+    }
+}
+
+#[cfg(feature = "system-clock")]
+static DEFAULT_CLOCK: SystemClock = SystemClock;
+#[cfg(not(feature = "system-clock"))]
+static DEFAULT_CLOCK: NoClock = NoClock;
+
 /// Interface used to customise commit metadata
 pub trait MakeCommitMeta {
-    /// Controls creation of commit timestamps. The default implementation simply wraps
-    /// `CommitMeta::timestamp_now()`.
+    /// Get the clock used by the default implementation of
+    /// `make_commit_timestamp`.
+    /// 
+    /// The default implementation returns a `SystemClock` when the
+    /// `system-clock` feature is enabled (the default), and a `NoClock`
+    /// otherwise.
+    fn clock(&self) -> &Clock {
+        &DEFAULT_CLOCK
+    }
+    
+    /// Controls creation of commit timestamps. The default implementation
+    /// uses `clock()`, and uses timestamp 0 when the time is not available.
     /// 
     /// The library itself does not depend on the value of these timestamps, it simply provides
     /// them as a convenience.
     fn make_commit_timestamp(&self) -> i64 {
-        CommitMeta::timestamp_now()
+        self.clock().now().unwrap_or(0)
     }
     
     /// Make an extra-metadata item. The default implementation simply
@@ -245,6 +658,13 @@ pub trait MakeCommitMeta {
     fn make_commit_extra(&self, _number: u32, _parents: Vec<(&Sum, &CommitMeta)>) -> UserMeta {
         UserMeta::None
     }
+    
+    /// Get an identifier of the author or device making new commits (at most
+    /// `MAX_AUTHOR_BYTES` long), recorded with each commit (see
+    /// `CommitMeta::author`). The default implementation returns `None`.
+    fn make_commit_author(&self) -> Option<String> {
+        None
+    }
 }
 
 
@@ -263,6 +683,9 @@ pub struct Commit<E: Element> {
     changes: HashMap<EltId, EltChange<E>>,
     /// Meta-data
     meta: CommitMeta,
+    /// If this is a squashed commit (see `new_squash`), the state which
+    /// changes are relative to. This is not a parent.
+    base: Option<Sum>,
 }
 
 /// Per-element changes
@@ -274,6 +697,14 @@ pub enum EltChange<E: Element> {
     Insertion(Rc<E>),
     /// Element was replaced (full data)
     Replacement(Rc<E>),
+    /// Element was inserted or replaced, but its data has since been erased;
+    /// only the sum of the new element is known (see
+    /// `Partition::erase_element_history`). This is also used where the
+    /// data could not be deserialised (see `EltReadPolicy::Skip`).
+    Erased(Sum),
+    /// Element was modified by applying serialised operations in order (see
+    /// `ApplyOp`); the sum is that of the resulting element
+    Operation(Vec<Vec<u8>>, Sum),
 }
 impl<E: Element> EltChange<E> {
     /// Create an `Insertion`
@@ -292,7 +723,7 @@ impl<E: Element> EltChange<E> {
     pub fn element(&self) -> Option<&Rc<E>> {
         use commit::EltChange::*;
         match *self {
-            Deletion => None,
+            Deletion | Erased(_) | Operation(..) => None,
             Insertion(ref elt) | Replacement(ref elt) => Some(elt),
         }
     }
@@ -311,7 +742,114 @@ impl<E: Element> Commit<E> {
     {
         assert!(parents.len() >= 1 && parents.len() < 0x100);
         Commit { statesum: statesum, parents: parents, changes: changes,
-                meta: meta }
+                meta: meta, base: None }
+    }
+    
+    /// Create a *squashed* commit: one which yields state `target` (with
+    /// its parents, metadata and state-sum) when applied to an ancestor
+    /// `base`, skipping all intermediate states. This is used to compact
+    /// history (see `Partition::compact_history`).
+    /// 
+    /// Changes are taken from the difference between the states, including
+    /// erased elements. `PartState::from_state_commit` may be used to check
+    /// the result applies.
+    pub fn new_squash(base: &PartState<E>, target: &PartState<E>) -> Commit<E> {
+        let mut commit = Commit::diff_states(base, target);
+        commit.base = Some(base.statesum().clone());
+        commit
+    }
+    
+    /// Re-create the commit which yielded `state` from `parent`, its first
+    /// parent (including parents and metadata; this works for merge commits
+    /// and commits without changes). This is used to send history to other
+    /// replicas.
+    /// 
+    /// This panics if `parent` is not the first parent of `state`.
+    pub fn recreate(parent: &PartState<E>, state: &PartState<E>) -> Commit<E> {
+        assert_eq!(state.parents().first(), Some(parent.statesum()));
+        Commit::diff_states(parent, state)
+    }
+    
+    /// Create the inverse of this commit: a commit on this commit's state
+    /// which restores all elements it changed to their values in `parent`
+    /// (its first parent). Metadata is created via `mcm` as for a new
+    /// commit. See `Partition::revert`.
+    /// 
+    /// Fails with `PatchOp::WrongParent` if `parent` is not the first parent
+    /// and with `PatchOp::PatchApply` if this commit does not apply or if
+    /// the data of a changed element was erased (in `parent` or by this
+    /// commit), since it can then not be restored.
+    pub fn invert(&self, parent: &PartState<E>, mcm: &MakeCommitMeta)
+            -> Result<Commit<E>, PatchOp>
+    {
+        if parent.statesum() != self.first_parent() {
+            return Err(PatchOp::WrongParent);
+        }
+        let state = PartState::from_state_commit(parent, self)?;
+        let mut changes = HashMap::new();
+        for id in self.changes.keys() {
+            if parent.erased_sum(*id).is_some() || state.erased_sum(*id).is_some() {
+                return Err(PatchOp::PatchApply);
+            }
+            let change = match (parent.get_rc(*id).ok(), state.is_avail(*id)) {
+                (Some(elt), true) => EltChange::replacement(elt.clone()),
+                (Some(elt), false) => EltChange::insertion(elt.clone()),
+                (None, true) => EltChange::deletion(),
+                (None, false) => continue,
+            };
+            changes.insert(*id, change);
+        }
+        let parents = vec![state.statesum().clone()];
+        let meta = CommitMeta::new_parents(vec![(state.statesum(), state.meta())], mcm);
+        let statesum = &(parent.statesum() ^ &parent.metasum()) ^
+                &Sum::state_meta_sum(&parents, &meta);
+        Ok(Commit { statesum, parents, changes, meta, base: None })
+    }
+    
+    // Commit with all changes from `base` to `target` and `target`'s
+    // parents, metadata and sum
+    fn diff_states(base: &PartState<E>, target: &PartState<E>) -> Commit<E> {
+        let mut changes = HashMap::new();
+        for (id, elt) in target.elts_iter() {
+            match base.get_rc(id) {
+                Ok(old) if old == elt => {},
+                Ok(_) => { changes.insert(id, EltChange::replacement(elt.clone())); },
+                Err(_) => { changes.insert(id, EltChange::insertion(elt.clone())); },
+            }
+        }
+        for (id, sum) in target.erased_iter() {
+            if base.erased_sum(*id) != Some(sum) {
+                changes.insert(*id, EltChange::Erased(sum.clone()));
+            }
+        }
+        for (id, _) in base.elts_iter() {
+            if !target.is_avail(id) && target.erased_sum(id).is_none() {
+                changes.insert(id, EltChange::deletion());
+            }
+        }
+        for (id, _) in base.erased_iter() {
+            if !target.is_avail(*id) && target.erased_sum(*id).is_none() {
+                changes.insert(*id, EltChange::deletion());
+            }
+        }
+        Commit {
+            statesum: target.statesum().clone(),
+            parents: target.parents().to_vec(),
+            changes,
+            meta: target.meta().clone(),
+            base: None,
+        }
+    }
+    
+    /// Create a squashed commit from parts (see `new_squash`).
+    /// 
+    /// This panics if parents.len() == 0 or parents.len() >= 256.
+    pub fn new_squash_explicit(statesum: Sum, base: Sum, parents: Vec<Sum>,
+            changes: HashMap<EltId, EltChange<E>>,
+            meta: CommitMeta) -> Commit<E>
+    {
+        assert!(!parents.is_empty() && parents.len() < 0x100);
+        Commit { statesum, parents, changes, meta, base: Some(base) }
     }
     
     /// Create a commit from an old state and a new state. Return the commit if
@@ -351,10 +889,72 @@ impl<E: Element> Commit<E> {
                 parents: vec![old_state.statesum().clone()],
                 changes: changes,
                 meta: new_state.meta().clone(),
+                base: None,
             })
         }
     }
     
+    /// Check, without modifying anything, that this commit applies cleanly
+    /// to `parent` and that the resulting state-sum matches the commit's.
+    /// 
+    /// This allows sync code to verify incoming commits before admitting
+    /// them to a partition (and quarantine those which fail). Only the first
+    /// parent is checked. It is cheaper than `PartState::from_state_commit`,
+    /// which reports the same errors, since no state is built.
+    /// 
+    /// Fails with `PatchOp::WrongParent` if `parent` is not the commit's
+    /// first parent and with `PatchOp::PatchApply` if a change does not
+    /// apply or the state-sum does not match.
+    pub fn verify_applies(&self, parent: &PartState<E>) -> Result<(), PatchOp> {
+        if parent.statesum() != self.first_parent() {
+            return Err(PatchOp::WrongParent);
+        }
+        let mut sum = parent.statesum() ^ &parent.metasum();
+        for (id, change) in &self.changes {
+            let old = parent.get_rc(*id).ok();
+            let old_erased = parent.erased_sum(*id);
+            match *change {
+                EltChange::Deletion if old_erased.is_some() => {
+                    sum.permute(old_erased.unwrap());
+                },
+                EltChange::Deletion => {
+                    let old = old.ok_or(PatchOp::PatchApply)?;
+                    sum.permute(&old.sum(*id));
+                },
+                EltChange::Insertion(ref elt) => {
+                    if old.is_some() {
+                        return Err(PatchOp::PatchApply);
+                    }
+                    sum.permute(&elt.sum(*id));
+                },
+                EltChange::Replacement(ref elt) => {
+                    let old = old.ok_or(PatchOp::PatchApply)?;
+                    sum.permute(&old.sum(*id));
+                    sum.permute(&elt.sum(*id));
+                },
+                EltChange::Erased(ref elt_sum) => {
+                    if let Some(old) = old {
+                        sum.permute(&old.sum(*id));
+                    } else if let Some(old_sum) = old_erased {
+                        sum.permute(old_sum);
+                    }
+                    sum.permute(elt_sum);
+                },
+                EltChange::Operation(ref ops, ref elt_sum) => {
+                    let old = old.ok_or(PatchOp::PatchApply)?;
+                    apply_ops(*id, &**old, ops, elt_sum)?;
+                    sum.permute(&old.sum(*id));
+                    sum.permute(elt_sum);
+                },
+            }
+        }
+        sum.permute(&Sum::state_meta_sum(&self.parents, &self.meta));
+        if sum != self.statesum {
+            return Err(PatchOp::PatchApply);
+        }
+        Ok(())
+    }
+    
     /// Apply this commit to a `MutPartState`. This does not verify the final
     /// statesum and does not use the metadata stored in this commit.
     /// 
@@ -364,6 +964,9 @@ impl<E: Element> Commit<E> {
     pub fn apply_mut(&self, mut_state: &mut MutPartState<E>) -> Result<(), ElementOp> {
         for (id, change) in &self.changes {
             match *change {
+                EltChange::Deletion if mut_state.is_erased(*id) => {
+                    mut_state.remove_erased(*id)?;
+                },
                 EltChange::Deletion => {
                     mut_state.remove(*id)?;
                 },
@@ -373,11 +976,53 @@ impl<E: Element> Commit<E> {
                 EltChange::Replacement(ref elt) => {
                     mut_state.replace_rc(*id, elt.clone())?;
                 }
+                EltChange::Erased(ref sum) => {
+                    if mut_state.is_avail(*id) {
+                        mut_state.remove(*id)?;
+                    }
+                    mut_state.set_erased(*id, sum.clone())?;
+                }
+                EltChange::Operation(ref ops, ref sum) => {
+                    let elt = apply_ops(*id, &**mut_state.get_rc(*id)?, ops, sum)?;
+                    mut_state.replace_rc(*id, Rc::new(elt))?;
+                }
             }
         }
         Ok(())
     }
     
+    /// Erase the data of element `id` from this commit, if inserted or
+    /// replaced here, leaving only its sum (see `EltChange::Erased`). The
+    /// commit's state-sum is unaffected.
+    /// 
+    /// Returns true if anything was erased.
+    pub fn erase(&mut self, id: EltId) -> bool {
+        let sum = match self.changes.get(&id) {
+            Some(EltChange::Operation(_, sum)) => sum.clone(),
+            Some(change) => match change.element() {
+                Some(elt) => elt.sum(id),
+                None => return false,
+            },
+            None => return false,
+        };
+        self.changes.insert(id, EltChange::Erased(sum));
+        true
+    }
+    
+    /// Record element replacements as operations: for each element in `ops`
+    /// replaced by this commit, replace the change with an
+    /// `EltChange::Operation` (these operations must yield the new element).
+    /// Used by `Partition::push_state` (see `MutPartState::apply_op`).
+    pub fn set_operations(&mut self, ops: HashMap<EltId, Vec<Vec<u8>>>) {
+        for (id, ops) in ops {
+            let sum = match self.changes.get(&id) {
+                Some(EltChange::Replacement(elt)) => elt.sum(id),
+                _ => continue,
+            };
+            self.changes.insert(id, EltChange::Operation(ops, sum));
+        }
+    }
+    
     /// Mutate the metadata in order to yield a new `statesum()` while
     /// otherwise not changing the state.
     /// 
@@ -397,12 +1042,24 @@ impl<E: Element> Commit<E> {
     /// Get the first parent. This is the one the commit is applied against.
     /// 
     /// This is identical to calling `commit.parents()[0]`, but clarifies that
-    /// the first parent is special and always present.
-    pub fn first_parent(&self) -> &Sum { &self.parents[0] }
+    /// the first parent is special and always present. Exception: for
+    /// squashed commits, this is the base state (see `squash_base`).
+    pub fn first_parent(&self) -> &Sum { self.base.as_ref().unwrap_or(&self.parents[0]) }
+    /// If this is a squashed commit (see `new_squash`), get the state which
+    /// it is applied against. This is an ancestor, but not a parent.
+    pub fn squash_base(&self) -> Option<&Sum> { self.base.as_ref() }
     /// Get the number of changes in the "patch"
     pub fn num_changes(&self) -> usize { self.changes.len() }
+    /// True if this is a merge commit without changes relative to its first
+    /// parent, i.e. one which only joins history (see
+    /// `CompactMode::ElideEmptyMerges`)
+    pub fn is_empty_merge(&self) -> bool {
+        self.parents.len() > 1 && self.changes.is_empty() && self.base.is_none()
+    }
     /// Get an iterator over changes
     pub fn changes_iter(&self) -> hash_map::Iter<EltId, EltChange<E>> { self.changes.iter() }
+    /// Take the changes, discarding the rest of the commit
+    pub fn into_changes(self) -> HashMap<EltId, EltChange<E>> { self.changes }
     /// Get a specific change, if this element was changed
     pub fn change(&self, id: EltId) -> Option<&EltChange<E>> {
         self.changes.get(&id)
@@ -411,4 +1068,124 @@ impl<E: Element> Commit<E> {
     pub fn meta(&self) -> &CommitMeta { &self.meta }
     /// Write acces to the commit's meta-data
     pub fn meta_mut(&mut self) -> &mut CommitMeta { &mut self.meta }
+    /// Get the identifier of the commit's author or device, if recorded
+    /// (shortcut for `meta().author()`)
+    pub fn author(&self) -> Option<&str> { self.meta.author() }
+}
+
+// Apply operations to element `elt` with identifier `id` (see
+// `EltChange::Operation`), checking that the result has sum `sum`.
+fn apply_ops<E: Element>(id: EltId, elt: &E, ops: &[Vec<u8>], sum: &Sum) -> Result<E, ElementOp> {
+    let f = E::apply_op_fn().ok_or(ElementOp::OpFailed)?;
+    let mut ops = ops.iter();
+    let first = ops.next().ok_or(ElementOp::OpFailed)?;
+    let mut result = f(elt, first).map_err(|_| ElementOp::OpFailed)?;
+    for op in ops {
+        result = f(&result, op).map_err(|_| ElementOp::OpFailed)?;
+    }
+    if result.sum(id) != *sum {
+        return Err(ElementOp::SumMismatch);
+    }
+    Ok(result)
+}
+
+
+// —————  Commit chains  —————
+
+/// Builds a chain of commits in memory, each parented on the previous. The
+/// result can be pushed to a partition in one step with
+/// `Partition::push_chain`, thus a multi-step operation (e.g. a data
+/// migration) appears in history as distinct commits yet cannot be partially
+/// applied.
+/// 
+/// Example:
+/// 
+/// ```
+/// use pippin::pip::*;
+/// 
+/// let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+/// let mut part = Partition::create(control, "chain example").unwrap();
+/// let mut mcm = DefaultControl::<String, _>::new(DummyRepoIO::new());
+/// 
+/// let mut chain = CommitChain::new(part.tip().unwrap());
+/// let mut state = chain.tip().clone_mut();
+/// state.insert_new("step one".to_string()).unwrap();
+/// chain.push_state(state, &mut mcm).unwrap();
+/// let mut state = chain.tip().clone_mut();
+/// state.insert_new("step two".to_string()).unwrap();
+/// chain.push_state(state, &mut mcm).unwrap();
+/// 
+/// assert_eq!(part.push_chain(chain.into_commits()), Ok(2));
+/// ```
+pub struct CommitChain<E: Element> {
+    // Latest state in the chain (initially the base)
+    tip: PartState<E>,
+    commits: Vec<Commit<E>>,
+}
+impl<E: Element> CommitChain<E> {
+    /// Start a new chain from a base state (usually the partition's tip).
+    pub fn new(base: &PartState<E>) -> CommitChain<E> {
+        CommitChain { tip: base.clone_exact(), commits: vec![] }
+    }
+    
+    /// Get the latest state in the chain. Use `tip().clone_mut()` to make
+    /// the next modification.
+    pub fn tip(&self) -> &PartState<E> {
+        &self.tip
+    }
+    
+    /// Add a commit to the chain from a state derived from `tip()`.
+    /// Metadata is created via `mcm`.
+    /// 
+    /// Returns `Ok(true)` on success or `Ok(false)` if there are no changes,
+    /// and fails if the state was not derived from `tip()`.
+    pub fn push_state(&mut self, mut state: MutPartState<E>, mcm: &mut MakeCommitMeta) ->
+            Result<bool, PatchOp>
+    {
+        if state.parent() != self.tip.statesum() {
+            return Err(PatchOp::WrongParent);
+        }
+        let ops = state.take_ops();
+        let new_state = PartState::from_mut(state, mcm);
+        Ok(if let Some(mut commit) = Commit::from_diff(&self.tip, &new_state) {
+            commit.set_operations(ops);
+            self.commits.push(commit);
+            self.tip = new_state;
+            true
+        } else {
+            false
+        })
+    }
+    
+    /// Get the number of commits in the chain
+    pub fn len(&self) -> usize {
+        self.commits.len()
+    }
+    /// True if the chain has no commits
+    pub fn is_empty(&self) -> bool {
+        self.commits.is_empty()
+    }
+    /// Get the commits, in order
+    pub fn commits(&self) -> &[Commit<E>] {
+        &self.commits
+    }
+    /// Consume, returning the commits in order (for `Partition::push_chain`)
+    pub fn into_commits(self) -> Vec<Commit<E>> {
+        self.commits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn flags_inessential() {
+        // Readers from before these extensions use this mask:
+        let old = MetaFlags::from_raw(FLAG_ACKS | FLAG_PROVENANCE | FLAG_SUMMARY | FLAG_AUTHOR);
+        assert_eq!(old.raw() & FLAG_ESSENTIAL & 0b11111111_11111100, 0);
+        assert!(!MetaFlags::from_raw(FLAG_ACKS).unknown_essential());
+        assert!(!old.unknown_essential());
+        assert!(MetaFlags::from_raw(0b0100).unknown_essential());
+    }
 }
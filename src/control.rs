@@ -5,14 +5,29 @@
 //! Pippin: control traits
 
 use std::usize;
+use std::cmp::{min, max};
+use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
-use commit::MakeCommitMeta;
-use elt::Element;
-use error::Result;
+use commit::{MakeCommitMeta, UserMetaLimits, ReplicaId};
+use elt::{Element, EltReadPolicy, TextCanon};
+use error::{Result, ElementOp};
+use index::IndexFn;
 use io::RepoIO;
+#[cfg(feature = "file-io")]
+use io::cache::StateCache;
+use merge::{TwoWaySolver, AncestorSolver2W};
+use rw::compress::Compression;
+use rw::encrypt::Cipher;
 use rw::header::{UserData, FileHeader};
+use state::{PartState, MutPartState};
 
+/// Suggested size limit for appending commits to an existing log (see
+/// `Control::log_append_limit`): 1 MiB
+pub const DEFAULT_LOG_APPEND_LIMIT: u64 = 1 << 20;
 
 /// Allows the user to control various repository operations. Library-provided implementations
 /// should be sufficient for many use-cases, but can be overridden or replaced if necessary.
@@ -72,6 +87,295 @@ pub trait Control: MakeCommitMeta {
     fn read_header(&mut self, _header: &FileHeader) -> Result<()> {
         Ok(())
     }
+    
+    /// Get the limits applied to user metadata (`UserMeta`) of new commits
+    /// and of commits and snapshots read from files.
+    /// 
+    /// The default implementation returns `UserMetaLimits::default()`.
+    fn user_meta_limits(&self) -> UserMetaLimits {
+        UserMetaLimits::default()
+    }
+    
+    /// Get the policy applied when element data cannot be deserialised while
+    /// loading snapshots and commit logs. Elements skipped or replaced by
+    /// placeholders are listed by `Partition::skipped_elts()`.
+    /// 
+    /// The default implementation returns `EltReadPolicy::Fail`.
+    fn elt_read_policy(&self) -> EltReadPolicy {
+        EltReadPolicy::Fail
+    }
+    
+    /// Get the compression method used for new snapshot and commit log
+    /// files (see `Compression`). Files are read whatever their compression,
+    /// so long as the method is supported.
+    /// 
+    /// Compressed snapshots have no usable element index (see
+    /// `Partition::load_lazy`) and compressed commit logs are not indexed
+    /// (see `Partition::check_log`).
+    /// 
+    /// The default implementation returns `Compression::None`.
+    fn compression(&self) -> Compression {
+        Compression::None
+    }
+    
+    /// Get the cipher used to encrypt new snapshot and commit log files and
+    /// to decrypt files read (see `rw::encrypt`). Headers are not encrypted.
+    /// 
+    /// As with compression, encrypted snapshots have no usable element index
+    /// and encrypted commit logs are not indexed. Cached states (see
+    /// `state_cache`) are not used while a cipher is set, since these are
+    /// stored in plain text.
+    /// 
+    /// The default implementation returns `None` (no encryption; encrypted
+    /// files cannot be read).
+    fn cipher(&self) -> Option<Rc<Cipher>> {
+        None
+    }
+    
+    /// Get an optional canonicaliser of text in elements (see `TextCanon`),
+    /// applied by `Partition::push_state` to changed elements before sums
+    /// are computed and data stored.
+    /// 
+    /// The default implementation returns `None` (no canonicalisation).
+    fn text_canon(&self) -> Option<Rc<TextCanon>> {
+        None
+    }
+    
+    /// Get an optional receiver of progress reports from long operations
+    /// (see `ProgressSink`): loading (e.g. `Partition::load_range`) and
+    /// writing snapshots.
+    /// 
+    /// The default implementation returns `None`.
+    fn progress(&self) -> Option<Rc<ProgressSink>> {
+        None
+    }
+    
+    /// Get an optional cancellation flag. Once this is set (by any thread),
+    /// long operations stop at the next safe point, failing with
+    /// `RepoError::Cancelled`: `Partition::load_range` (and `load_all`)
+    /// before each snapshot, `merge` and `merge_n` before each merge step,
+    /// `write_full` before writing a snapshot and `write_snapshot` while
+    /// writing (the partial file is removed). Data loaded or commits made
+    /// before this remain valid. The flag is not cleared by the library.
+    /// 
+    /// The default implementation returns `None`.
+    fn cancel_flag(&self) -> Option<Arc<AtomicBool>> {
+        None
+    }
+    
+    /// If true, new snapshots store the data of elements with identical data
+    /// once, other elements referencing this by element sum (see
+    /// `write_snapshot_dedup`); when read, such elements share memory. Such
+    /// snapshots cannot be read by versions of this library without support,
+    /// and have no element index (see `Partition::load_lazy`).
+    /// 
+    /// The default implementation returns false.
+    fn dedup_snapshots(&self) -> bool {
+        false
+    }
+    
+    /// Get an optional window (in seconds, as for `make_commit_timestamp`)
+    /// within which successive calls to `Partition::push_state` are
+    /// coalesced: if the new state's parent is the latest unsaved commit,
+    /// made no more than this long before the new one, the two are replaced
+    /// by a single commit against the original parent. This reduces history
+    /// noise and log size where applications commit on every small edit.
+    /// 
+    /// The default implementation returns `None` (no coalescing).
+    fn coalesce_window(&self) -> Option<i64> {
+        None
+    }
+    
+    /// Get an optional size limit (in bytes) below which new commits are
+    /// appended to the latest commit log instead of a new log being
+    /// created. This avoids creating a file per write where commits are
+    /// written frequently. Commits are only appended to logs written by
+    /// this library version without compression or encryption, and only
+    /// where the `RepoIO` supports this (see `RepoIO::append_ss_cl_at`).
+    /// 
+    /// The default implementation returns `None` (always create a new log);
+    /// `DEFAULT_LOG_APPEND_LIMIT` is a suitable limit.
+    fn log_append_limit(&self) -> Option<u64> {
+        None
+    }
+    
+    /// Get the solver used by `Partition::merge_default()`. Since each
+    /// partition has its own `Control`, this allows the merge policy to be
+    /// chosen per partition (e.g. according to the type of data stored).
+    /// 
+    /// The default implementation returns an `AncestorSolver2W`.
+    fn merge_solver(&self) -> Box<TwoWaySolver<Self::Element>> {
+        Box::new(AncestorSolver2W::new())
+    }
+    
+    /// This function is called each time a new file has been written and
+    /// closed, or commits have been appended to an existing log, allowing applications to react immediately (e.g. trigger
+    /// external synchronisation) instead of polling the directory.
+    /// 
+    /// The file path (if any) may be retrieved from the I/O provider; e.g.
+    /// with `RepoFileIO`, use `paths().get_ss(ss)` or `paths().get_cl(ss, cl)`.
+    /// 
+    /// The default implementation does nothing.
+    fn file_written(&mut self, _file: WrittenFile) {}
+    
+    /// Called by `Partition::close` after all data has been written. This
+    /// should release any locks held and flush or close the I/O provider,
+    /// reporting failures.
+    /// 
+    /// The default implementation does nothing.
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Get the identifier of this replica, if any. When set, this is sent
+    /// along with commits during synchronisation (see `sync`), allowing
+    /// the receiving replica to record provenance of imported commits.
+    /// 
+    /// The default implementation returns `None`.
+    fn replica_id(&self) -> Option<ReplicaId> {
+        None
+    }
+    
+    /// Get an optional limit on the memory used by loaded states, in bytes
+    /// (as estimated by `Partition::mem_usage()`).
+    /// 
+    /// When loading data causes the limit to be exceeded, historical (non-tip)
+    /// states are evicted; if this is insufficient, loading fails with a
+    /// `MemLimit` error (data loaded remains in memory).
+    /// 
+    /// The default implementation returns `None` (no limit).
+    fn mem_limit(&self) -> Option<usize> {
+        None
+    }
+    
+    /// Get an optional limit on the number of historical states retained in
+    /// memory: if set, after loading and merging only tips, this number of
+    /// their nearest ancestors and the states of unsaved commits are kept
+    /// (see `Partition::retain_states`). This is skipped while a merge is
+    /// required, since merges need common ancestors.
+    /// 
+    /// The default implementation returns `None` (keep all loaded states).
+    fn keep_states(&self) -> Option<usize> {
+        None
+    }
+    
+    /// Get an optional limit on the number of unsaved commits (see
+    /// `Partition::unsaved_len()`).
+    /// 
+    /// Where writing fails repeatedly (e.g. because the disk is full), new
+    /// commits would otherwise accumulate in memory without bound. When the
+    /// limit is reached, adding further commits (`push_state`, `push_commit`
+    /// etc.) fails with `PatchOp::UnsavedLimit` until commits have been
+    /// written (see `Partition::write_fast`).
+    /// 
+    /// The default implementation returns `None` (no limit).
+    fn max_unsaved(&self) -> Option<usize> {
+        None
+    }
+    
+    /// If true, snapshot files are written reproducibly: the bytes written
+    /// depend only on the partition name, any tag and the state (thus
+    /// equal states produce identical files, allowing e.g. content-addressed
+    /// storage and deduplicating backups to work). To achieve this,
+    /// `make_user_data` is not called for snapshots and per-element
+    /// metadata is omitted (see `rw::snapshot::write_snapshot_reproducible`).
+    /// 
+    /// The default implementation returns false.
+    fn reproducible_snapshots(&self) -> bool {
+        false
+    }
+    
+    /// Get an optional on-disk cache of states. When loading, states found
+    /// in the cache are used instead of replaying the corresponding commit,
+    /// and tips reached by replaying at least `StateCache::min_commits()`
+    /// commits are added to the cache.
+    /// 
+    /// The default implementation returns `None` (no cache).
+    #[cfg(feature = "file-io")]
+    fn state_cache(&mut self) -> Option<&mut StateCache> {
+        None
+    }
+    
+    /// Get an optional hook deriving secondary elements when commits are
+    /// made (see `DerivedElements`).
+    /// 
+    /// The default implementation returns `None`.
+    fn derived_elements(&mut self) -> Option<&mut DerivedElements<Self::Element>> {
+        None
+    }
+    
+    /// Get functions defining secondary indexes, queried via
+    /// `Partition::find_by_index` (identified by their position in this
+    /// list). The list should not change while a partition is loaded; if it
+    /// does, indexes are rebuilt.
+    /// 
+    /// The default implementation returns an empty list.
+    fn index_fns(&self) -> &[IndexFn<Self::Element>] {
+        &[]
+    }
+}
+
+/// Hook maintaining secondary elements (e.g. summaries, aggregates or
+/// indexes) derived from other elements, such that these are always
+/// consistent with their source data (see `Control::derived_elements`).
+/// 
+/// This is invoked by `Partition::push_state` when the pushed state has
+/// changes; changes made by the hook become part of the same commit. It is
+/// not invoked for commits pushed directly (e.g. merge commits and commits
+/// from other replicas); these are expected to contain derived elements
+/// already.
+pub trait DerivedElements<E: Element>: Debug {
+    /// Update derived elements in `state`, whose changes relative to `parent`
+    /// are listed by `state.changed_ids()`.
+    /// 
+    /// If this fails, nothing is committed and `push_state` fails with
+    /// `PatchOp::PatchApply`.
+    fn derive(&mut self, parent: &PartState<E>, state: &mut MutPartState<E>)
+            -> Result<(), ElementOp>;
+}
+
+/// Identifies a file newly written by a partition (see `Control::file_written`).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum WrittenFile {
+    /// A snapshot file, with the given snapshot number
+    Snapshot(usize),
+    /// A commit log file, with the given snapshot number and log number
+    CommitLog(usize, usize),
+}
+
+/// Receives progress reports from long operations (see `Control::progress`),
+/// e.g. to drive a progress bar. Totals are not reported; file sizes may be
+/// found via `RepoIO::ss_cl_size` and similar.
+/// 
+/// Each method may fail, in which case the operation is aborted and fails
+/// with the same error; this may be used to cancel an operation. Loaded
+/// data remains consistent (as after an I/O error), but files may be only
+/// partially loaded. All default implementations do nothing.
+pub trait ProgressSink: Debug {
+    /// Called as elements are read from snapshots and commit logs, with the
+    /// size of the element's data in bytes.
+    fn bytes_read(&self, _bytes: usize) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Called when a snapshot or commit log has been read by
+    /// `Partition::load_range` (or similar).
+    fn file_read(&self, _file: WrittenFile) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Called for each commit read from logs by `Partition::load_range` (or
+    /// similar) as it is replayed (applied to its parent state), or skipped
+    /// where its state is already known.
+    fn commit_replayed(&self) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Called as snapshot data is written, with the number of bytes (before
+    /// compression and encryption).
+    fn bytes_written(&self, _bytes: usize) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// An interface allowing configuration of snapshot policy.
@@ -97,21 +401,185 @@ pub trait SnapshotPolicy {
     /// commits * 5 + edits > 150
     /// ```
     fn want_snapshot(&self) -> bool;
+    
+    /// Record the timestamp of a commit counted (see `count`). Used by
+    /// policies based on elapsed time; the default implementation does
+    /// nothing.
+    fn count_time(&mut self, _timestamp: i64) {}
+    
+    /// Record this many bytes written to commit logs. Used by policies based
+    /// on log size; the default implementation does nothing.
+    fn count_log_bytes(&mut self, _bytes: u64) {}
+    
+    /// Get a representation of this policy to persist in snapshot headers,
+    /// allowing other writers of the repository to share the policy (see
+    /// `read_user_data`). The default implementation returns `None`.
+    fn user_data(&self) -> Option<UserData> {
+        None
+    }
+    
+    /// Called with each user data field of snapshot headers loaded. If the
+    /// field is a persisted policy (see `user_data`), the policy should adopt
+    /// it and return true. The default implementation returns false.
+    fn read_user_data(&mut self, _data: &UserData) -> bool {
+        false
+    }
 }
 
 /// A convenient implementation of `Control`.
 /// 
-/// Uses `DefaultSnapshot` snapshot policy.
+/// Uses `SnapshotConfig` snapshot policy.
 #[derive(Debug)]
 pub struct DefaultControl<E: Element, IO: RepoIO + 'static> {
     _elt_type: PhantomData<E>,
     io: IO,
-    ss_policy: DefaultSnapshot,
+    ss_policy: SnapshotConfig,
+    reproducible: bool,
+    replica_id: Option<ReplicaId>,
+    elt_read_policy: EltReadPolicy,
+    compression: Compression,
+    cipher: Option<Rc<Cipher>>,
+    text_canon: Option<Rc<TextCanon>>,
+    progress: Option<Rc<ProgressSink>>,
+    cancel: Option<Arc<AtomicBool>>,
+    dedup: bool,
+    author: Option<String>,
+    coalesce_window: Option<i64>,
+    log_append_limit: Option<u64>,
+    max_unsaved: Option<usize>,
+    keep_states: Option<usize>,
+    derived: Option<Box<DerivedElements<E>>>,
+    index_fns: Vec<IndexFn<E>>,
+    #[cfg(feature = "file-io")]
+    state_cache: Option<StateCache>,
 }
 impl<E: Element, IO: RepoIO> DefaultControl<E, IO> {
     /// Create, given I/O provider
     pub fn new(io: IO) -> Self {
-        DefaultControl { _elt_type: Default::default(), io: io, ss_policy: Default::default() }
+        DefaultControl { _elt_type: Default::default(), io: io, ss_policy: Default::default(),
+                reproducible: false, replica_id: None, elt_read_policy: EltReadPolicy::Fail,
+                compression: Compression::None, cipher: None, text_canon: None, dedup: false,
+                progress: None, cancel: None, author: None,
+                coalesce_window: None, log_append_limit: None,
+                max_unsaved: None, keep_states: None, derived: None,
+                index_fns: Vec::new(),
+                #[cfg(feature = "file-io")]
+                state_cache: None }
+    }
+    
+    /// Set or clear the state cache (see `Control::state_cache`; default none).
+    #[cfg(feature = "file-io")]
+    pub fn set_state_cache(&mut self, cache: Option<StateCache>) {
+        self.state_cache = cache;
+    }
+    
+    /// Set whether snapshots are written reproducibly (see
+    /// `Control::reproducible_snapshots`; default false).
+    pub fn set_reproducible_snapshots(&mut self, reproducible: bool) {
+        self.reproducible = reproducible;
+    }
+    
+    /// Set or clear this replica's identifier (see `Control::replica_id`;
+    /// default none).
+    pub fn set_replica_id(&mut self, id: Option<ReplicaId>) {
+        self.replica_id = id;
+    }
+    
+    /// Set the policy applied when elements cannot be deserialised (see
+    /// `Control::elt_read_policy`; default `EltReadPolicy::Fail`).
+    pub fn set_elt_read_policy(&mut self, policy: EltReadPolicy) {
+        self.elt_read_policy = policy;
+    }
+    
+    /// Set the compression of new files (see `Control::compression`;
+    /// default `Compression::None`).
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    
+    /// Set or clear the cipher used to encrypt files (see `Control::cipher`;
+    /// default none).
+    pub fn set_cipher(&mut self, cipher: Option<Rc<Cipher>>) {
+        self.cipher = cipher;
+    }
+    
+    /// Set or clear the canonicaliser of text in elements (see
+    /// `Control::text_canon`; default none).
+    pub fn set_text_canon(&mut self, canon: Option<Rc<TextCanon>>) {
+        self.text_canon = canon;
+    }
+    
+    /// Set or clear the receiver of progress reports (see
+    /// `Control::progress`; default none).
+    pub fn set_progress(&mut self, progress: Option<Rc<ProgressSink>>) {
+        self.progress = progress;
+    }
+    
+    /// Set or clear the cancellation flag (see `Control::cancel_flag`;
+    /// default none).
+    pub fn set_cancel_flag(&mut self, flag: Option<Arc<AtomicBool>>) {
+        self.cancel = flag;
+    }
+    
+    /// Set whether snapshots deduplicate element data (see
+    /// `Control::dedup_snapshots`; default false).
+    pub fn set_dedup_snapshots(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+    
+    /// Set or clear the author or device identifier recorded with new
+    /// commits (see `MakeCommitMeta::make_commit_author`; default none).
+    pub fn set_author(&mut self, author: Option<String>) {
+        self.author = author;
+    }
+    
+    /// Set or clear the window for coalescing commits (see
+    /// `Control::coalesce_window`; default none).
+    pub fn set_coalesce_window(&mut self, window: Option<i64>) {
+        self.coalesce_window = window;
+    }
+    
+    /// Set or clear the size limit for appending to commit logs (see
+    /// `Control::log_append_limit`; default none).
+    pub fn set_log_append_limit(&mut self, limit: Option<u64>) {
+        self.log_append_limit = limit;
+    }
+    
+    /// Set or clear the limit on unsaved commits (see `Control::max_unsaved`;
+    /// default none).
+    pub fn set_max_unsaved(&mut self, limit: Option<usize>) {
+        self.max_unsaved = limit;
+    }
+    
+    /// Set or clear the number of historical states retained (see
+    /// `Control::keep_states`; default none).
+    pub fn set_keep_states(&mut self, keep: Option<usize>) {
+        self.keep_states = keep;
+    }
+    
+    /// Set or clear the hook deriving secondary elements (see
+    /// `Control::derived_elements`; default none).
+    pub fn set_derived_elements(&mut self, derived: Option<Box<DerivedElements<E>>>) {
+        self.derived = derived;
+    }
+    
+    /// Add a secondary index (see `Control::index_fns`), returning its
+    /// identifier.
+    pub fn add_index(&mut self, f: IndexFn<E>) -> usize {
+        self.index_fns.push(f);
+        self.index_fns.len() - 1
+    }
+    
+    /// Set the snapshot policy (default: `SnapshotConfig::default()`). Note
+    /// that a policy found in a loaded snapshot replaces this (see
+    /// `SnapshotConfig`), thus this should be set after loading.
+    pub fn set_snapshot_config(&mut self, config: SnapshotConfig) {
+        self.ss_policy = config;
+    }
+    
+    /// Get the snapshot policy
+    pub fn snapshot_config(&self) -> &SnapshotConfig {
+        &self.ss_policy
     }
     
     /// Get direct access to the held `IO`
@@ -121,7 +589,11 @@ impl<E: Element, IO: RepoIO> DefaultControl<E, IO> {
     /// Unwrap the held `IO`
     pub fn unwrap_io(self) -> IO { self.io }
 }
-impl<E: Element, IO: RepoIO> MakeCommitMeta for DefaultControl<E, IO> {}
+impl<E: Element, IO: RepoIO> MakeCommitMeta for DefaultControl<E, IO> {
+    fn make_commit_author(&self) -> Option<String> {
+        self.author.clone()
+    }
+}
 impl<E: Element, IO: RepoIO> Control for DefaultControl<E, IO> {
     type Element = E;
     fn io(&self) -> &RepoIO {
@@ -135,6 +607,58 @@ impl<E: Element, IO: RepoIO> Control for DefaultControl<E, IO> {
     }
     fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
     fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+    fn reproducible_snapshots(&self) -> bool {
+        self.reproducible
+    }
+    fn replica_id(&self) -> Option<ReplicaId> {
+        self.replica_id
+    }
+    fn elt_read_policy(&self) -> EltReadPolicy {
+        self.elt_read_policy
+    }
+    fn compression(&self) -> Compression {
+        self.compression
+    }
+    fn cipher(&self) -> Option<Rc<Cipher>> {
+        self.cipher.clone()
+    }
+    fn text_canon(&self) -> Option<Rc<TextCanon>> {
+        self.text_canon.clone()
+    }
+    fn progress(&self) -> Option<Rc<ProgressSink>> {
+        self.progress.clone()
+    }
+    fn cancel_flag(&self) -> Option<Arc<AtomicBool>> {
+        self.cancel.clone()
+    }
+    fn dedup_snapshots(&self) -> bool {
+        self.dedup
+    }
+    fn coalesce_window(&self) -> Option<i64> {
+        self.coalesce_window
+    }
+    fn log_append_limit(&self) -> Option<u64> {
+        self.log_append_limit
+    }
+    fn max_unsaved(&self) -> Option<usize> {
+        self.max_unsaved
+    }
+    fn keep_states(&self) -> Option<usize> {
+        self.keep_states
+    }
+    fn index_fns(&self) -> &[IndexFn<E>] {
+        &self.index_fns
+    }
+    fn derived_elements(&mut self) -> Option<&mut DerivedElements<E>> {
+        match self.derived {
+            Some(ref mut derived) => Some(&mut **derived),
+            None => None,
+        }
+    }
+    #[cfg(feature = "file-io")]
+    fn state_cache(&mut self) -> Option<&mut StateCache> {
+        self.state_cache.as_mut()
+    }
 }
 
 /// Default snapshot policy: snapshot when `commits * 5 + edits > 150`.
@@ -162,3 +686,158 @@ impl SnapshotPolicy for DefaultSnapshot {
         self.counter > 150
     }
 }
+
+/// Configurable snapshot policy: snapshot when any of the given limits is
+/// exceeded since the last snapshot. Limits which are `None` are not checked.
+/// 
+/// Unless equal to the default, the policy is persisted in snapshot headers
+/// as a remark (see `SnapshotPolicy::user_data`) and adopted when a snapshot
+/// is loaded, thus all writers of a repository use the policy of whoever
+/// last wrote a snapshot. (Snapshots written reproducibly do not include
+/// user data.)
+/// 
+/// Counts are of commits loaded or added since the last snapshot; elapsed
+/// time is between the first and last such commit's timestamp; log bytes
+/// are those written by this process only.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotConfig {
+    /// Maximum number of commits
+    pub max_commits: Option<usize>,
+    /// Maximum number of edits (element changes)
+    pub max_edits: Option<usize>,
+    /// Maximum time elapsed, in seconds (see `count_time`)
+    pub max_elapsed: Option<i64>,
+    /// Maximum bytes written to commit logs
+    pub max_log_bytes: Option<u64>,
+    commits: usize,
+    edits: usize,
+    times: Option<(i64, i64)>,
+    log_bytes: u64,
+    forced: bool,
+}
+
+/// Prefix of the remark used to persist a `SnapshotConfig`
+const SNAPSHOT_CONFIG_REMARK: &str = "snapshot-policy";
+
+impl SnapshotConfig {
+    /// Create with the given limits (see field documentation)
+    pub fn new(max_commits: Option<usize>, max_edits: Option<usize>,
+            max_elapsed: Option<i64>, max_log_bytes: Option<u64>) -> Self
+    {
+        SnapshotConfig { max_commits, max_edits, max_elapsed, max_log_bytes,
+                commits: 0, edits: 0, times: None, log_bytes: 0, forced: false }
+    }
+    
+    /// True if `other` has the same limits (counters are not compared)
+    pub fn same_limits(&self, other: &SnapshotConfig) -> bool {
+        (self.max_commits, self.max_edits, self.max_elapsed, self.max_log_bytes) ==
+            (other.max_commits, other.max_edits, other.max_elapsed, other.max_log_bytes)
+    }
+    
+    /// Encode limits as text, e.g. `snapshot-policy commits=30 edits=150`
+    pub fn to_text(&self) -> String {
+        let mut text = SNAPSHOT_CONFIG_REMARK.to_string();
+        if let Some(n) = self.max_commits { text.push_str(&format!(" commits={}", n)); }
+        if let Some(n) = self.max_edits { text.push_str(&format!(" edits={}", n)); }
+        if let Some(n) = self.max_elapsed { text.push_str(&format!(" elapsed={}", n)); }
+        if let Some(n) = self.max_log_bytes { text.push_str(&format!(" log-bytes={}", n)); }
+        text
+    }
+    
+    /// Decode limits from text (see `to_text`). Returns `None` if this is not
+    /// a snapshot policy or is malformed.
+    pub fn from_text(text: &str) -> Option<Self> {
+        let mut parts = text.split(' ');
+        if parts.next() != Some(SNAPSHOT_CONFIG_REMARK) {
+            return None;
+        }
+        let mut config = SnapshotConfig::new(None, None, None, None);
+        for part in parts {
+            let mut kv = part.splitn(2, '=');
+            let (key, value) = (kv.next()?, kv.next()?);
+            match key {
+                "commits" => config.max_commits = Some(value.parse().ok()?),
+                "edits" => config.max_edits = Some(value.parse().ok()?),
+                "elapsed" => config.max_elapsed = Some(value.parse().ok()?),
+                "log-bytes" => config.max_log_bytes = Some(value.parse().ok()?),
+                _ => {
+                    warn!("ignoring unknown snapshot policy limit: {}", key);
+                },
+            }
+        }
+        Some(config)
+    }
+}
+
+impl Default for SnapshotConfig {
+    /// Snapshot after more than 30 commits or 150 edits
+    fn default() -> Self {
+        SnapshotConfig::new(Some(30), Some(150), None, None)
+    }
+}
+
+impl SnapshotPolicy for SnapshotConfig {
+    fn reset(&mut self) {
+        self.commits = 0;
+        self.edits = 0;
+        self.times = None;
+        self.log_bytes = 0;
+        self.forced = false;
+    }
+    
+    fn force_snapshot(&mut self) {
+        self.forced = true;
+    }
+    
+    fn count(&mut self, commits: usize, edits: usize) {
+        self.commits += commits;
+        self.edits += edits;
+    }
+    
+    fn want_snapshot(&self) -> bool {
+        self.forced ||
+            self.max_commits.is_some_and(|n| self.commits > n) ||
+            self.max_edits.is_some_and(|n| self.edits > n) ||
+            self.max_elapsed.is_some_and(|n| self.times.is_some_and(|(t0, t1)| t1 - t0 > n)) ||
+            self.max_log_bytes.is_some_and(|n| self.log_bytes > n)
+    }
+    
+    fn count_time(&mut self, timestamp: i64) {
+        self.times = Some(match self.times {
+            Some((t0, t1)) => (min(t0, timestamp), max(t1, timestamp)),
+            None => (timestamp, timestamp),
+        });
+    }
+    
+    fn count_log_bytes(&mut self, bytes: u64) {
+        self.log_bytes += bytes;
+    }
+    
+    fn user_data(&self) -> Option<UserData> {
+        if self.same_limits(&SnapshotConfig::default()) {
+            None
+        } else {
+            Some(UserData::Text(self.to_text()))
+        }
+    }
+    
+    fn read_user_data(&mut self, data: &UserData) -> bool {
+        let config = match *data {
+            UserData::Text(ref text) => SnapshotConfig::from_text(text),
+            UserData::Data(_) => None,
+        };
+        match config {
+            Some(config) => {
+                if !self.same_limits(&config) {
+                    debug!("adopting snapshot policy: {}", config.to_text());
+                }
+                self.max_commits = config.max_commits;
+                self.max_edits = config.max_edits;
+                self.max_elapsed = config.max_elapsed;
+                self.max_log_bytes = config.max_log_bytes;
+                true
+            },
+            None => false,
+        }
+    }
+}
@@ -7,12 +7,16 @@
 use std::fmt;
 use std::fmt::Debug;
 use std::io::{/*Read,*/ Write};
+use std::mem;
 use std::str::from_utf8;
 
+use byteorder::{ByteOrder, BigEndian};
 use rand::random;
+#[cfg(feature = "unicode")]
+use unicode_normalization::UnicodeNormalization;
 
 use sum::Sum;
-use error::Result;
+use error::{Result, ArgError};
 
 /// An element identifier.
 /// 
@@ -50,6 +54,57 @@ impl fmt::Display for EltId {
     }
 }
 
+/// An inclusive range of element identifiers, e.g. the elements handled by
+/// one shard (see `Partition::read_snapshot_filtered`)
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct EltIdRange {
+    /// First identifier in the range
+    pub first: EltId,
+    /// Last identifier in the range (inclusive)
+    pub last: EltId,
+}
+impl EltIdRange {
+    /// Create, given the first and last identifiers (inclusive)
+    pub fn new(first: EltId, last: EltId) -> EltIdRange {
+        EltIdRange { first, last }
+    }
+    /// The range of all identifiers
+    pub fn all() -> EltIdRange {
+        EltIdRange { first: EltId::from(0), last: EltId::from(u64::MAX) }
+    }
+    /// True if `id` is within the range
+    pub fn contains(self, id: EltId) -> bool {
+        self.first <= id && id <= self.last
+    }
+}
+
+/// Per-element metadata: provenance of an element's current value.
+/// 
+/// This records which commit last modified (inserted or replaced) the
+/// element. It is tracked by states when known and stored in snapshots
+/// (from file format version 2026_10_17), thus querying it does not require
+/// replaying commit logs. It is not known for elements read from older
+/// snapshots which have not since been modified.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct EltMeta {
+    number: u32,
+    statesum: Sum,
+}
+impl EltMeta {
+    /// Create, from the number and state-sum of the modifying commit.
+    pub fn new(number: u32, statesum: Sum) -> EltMeta {
+        EltMeta { number, statesum }
+    }
+    /// Get the number of the commit which last modified the element.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+    /// Get the state-sum of the commit which last modified the element.
+    pub fn statesum(&self) -> &Sum {
+        &self.statesum
+    }
+}
+
 /// Whatever element type the user wishes to store must implement this trait.
 /// 
 /// ### Read-only
@@ -83,7 +138,9 @@ impl fmt::Display for EltId {
 /// 
 /// It is recommended that an implementation is written specific to each
 /// use-case (using an enum if variadic data typing is needed). There is
-/// however a default implementation for `String`.
+/// however a default implementation for `String`. Alternatively, elements of
+/// several independent types can be stored via `registry::Tagged`, which
+/// records a type tag with each element.
 /// 
 /// A trivial example:
 /// 
@@ -156,6 +213,18 @@ pub trait Element where Self: Sized+PartialEq+Eq+Debug+'static {
         Self::from_vec(vec)
     }
     
+    /// Create a placeholder for element `id`, whose data `vec` could not be
+    /// deserialised (see `EltReadPolicy::Placeholder`).
+    /// 
+    /// The placeholder must keep the data: `write_buf` must write `vec`
+    /// unchanged and `sum` must return `sum`, otherwise states containing it
+    /// can no longer be verified or written.
+    /// 
+    /// The default implementation returns `None` (not supported).
+    fn placeholder(_id: EltId, _vec: Vec<u8>, _sum: Sum) -> Option<Self> {
+        None
+    }
+    
     /// This can either return a copy of an internally cached element sum or
     /// calculate one on the fly. It is used when inserting, removing or
     /// replacing an element in a state, and when merging states where the
@@ -169,6 +238,157 @@ pub trait Element where Self: Sized+PartialEq+Eq+Debug+'static {
         self.write_buf(&mut &mut buf).expect("write_buf does not fail in get_sum");
         Sum::elt_sum(id, &buf)
     }
+    
+    /// Approximate memory used by this element in bytes, including heap
+    /// allocations it owns. Used for memory accounting (see
+    /// `Partition::mem_usage()`).
+    /// 
+    /// The default implementation returns `size_of::<Self>()`, which
+    /// excludes heap allocations; implement this for more accuracy.
+    fn mem_size(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+    
+    /// Register support for operation-based changes (see `ApplyOp`). Types
+    /// implementing `ApplyOp` should return `Some(<Self as ApplyOp>::apply_op)`.
+    /// 
+    /// The default implementation returns `None` (not supported).
+    fn apply_op_fn() -> Option<ApplyOpFn<Self>> {
+        None
+    }
+    
+    /// Get the canonical form of this element, canonicalising text via
+    /// `canon` (see `Control::text_canon`), or `None` if the element is
+    /// already canonical. Element types containing text should canonicalise
+    /// each text field.
+    /// 
+    /// The default implementation returns `None` (no text). For `String`,
+    /// the whole element is canonicalised.
+    fn canonicalise(&self, _canon: &TextCanon) -> Option<Self> {
+        None
+    }
+}
+
+/// Handling of element data which cannot be deserialised when loading
+/// snapshots and commit logs (see `Control::elt_read_policy`).
+/// 
+/// Elements which are skipped or replaced are reported by
+/// `Partition::skipped_elts`, allowing repair later.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EltReadPolicy {
+    /// Fail, aborting the load
+    Fail,
+    /// Skip the element, with a warning. The element is kept as an erased
+    /// element (see `PartState::erase`): states remain verifiable but the
+    /// element's data is unavailable.
+    Skip,
+    /// Replace the element with a placeholder (see `Element::placeholder`),
+    /// with a warning. Where placeholders are not supported, skip instead.
+    Placeholder,
+}
+impl Default for EltReadPolicy {
+    fn default() -> Self {
+        EltReadPolicy::Fail
+    }
+}
+
+/// Result of `MaskPolicy::mask` for one element.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Masked<E: Element> {
+    /// Export the element unchanged
+    Keep,
+    /// Export this value instead (e.g. with sensitive fields redacted)
+    Replace(E),
+    /// Omit the element from the export
+    Omit,
+}
+
+/// Redaction or transformation of element data when exporting a partition
+/// (see `PartitionVfs::with_mask`), allowing production data to be inspected
+/// without leaking sensitive content.
+/// 
+/// Element fields are opaque to Pippin, thus the policy receives each whole
+/// element. Only exported copies are affected; stored data is not changed.
+pub trait MaskPolicy<E: Element> {
+    /// Decide how to export element `elt` with identifier `id`.
+    fn mask(&self, id: EltId, elt: &E) -> Masked<E>;
+}
+
+/// Canonicalisation of text within elements (see `Control::text_canon`).
+/// 
+/// Where replicas differ only in encoding details of text (e.g. Unicode
+/// normalisation form or line endings), element sums differ and spurious
+/// conflicts result. When a canonicaliser is set, `Partition::push_state`
+/// replaces changed elements by their canonical form (via
+/// `Element::canonicalise`) before sums are computed and data is stored.
+/// All replicas should use the same canonicalisation.
+pub trait TextCanon: Debug {
+    /// Get the canonical form of `text`, or `None` if `text` is canonical.
+    fn canonicalise(&self, text: &str) -> Option<String>;
+}
+
+/// Standard text canonicalisation: optionally normalises line endings and
+/// (with feature `unicode`) converts to Unicode normalisation form C.
+/// Created with nothing enabled.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct StdTextCanon {
+    line_endings: bool,
+    #[cfg(feature = "unicode")]
+    nfc: bool,
+}
+impl StdTextCanon {
+    /// Create, with no canonicalisation enabled
+    pub fn new() -> StdTextCanon {
+        StdTextCanon::default()
+    }
+    
+    /// Convert line endings `\r\n` and `\r` to `\n`
+    pub fn with_line_endings(mut self) -> StdTextCanon {
+        self.line_endings = true;
+        self
+    }
+    
+    /// Convert text to Unicode normalisation form C (canonical composition)
+    #[cfg(feature = "unicode")]
+    pub fn with_nfc(mut self) -> StdTextCanon {
+        self.nfc = true;
+        self
+    }
+}
+impl TextCanon for StdTextCanon {
+    fn canonicalise(&self, text: &str) -> Option<String> {
+        let mut result = None;
+        if self.line_endings && text.contains('\r') {
+            result = Some(text.replace("\r\n", "\n").replace('\r', "\n"));
+        }
+        #[cfg(feature = "unicode")]
+        {
+            let s = result.as_ref().map_or(text, |s| &s[..]);
+            if self.nfc && !unicode_normalization::is_nfc(s) {
+                result = Some(s.nfc().collect());
+            }
+        }
+        result
+    }
+}
+
+/// Function applying an operation to an element (see `ApplyOp`).
+pub type ApplyOpFn<E> = fn(&E, &[u8]) -> Result<E>;
+
+/// Operation-based changes to elements.
+/// 
+/// For element types which are collections or records, a change can often be
+/// described by a small operation (e.g. "append item" or "set field"),
+/// serialised by the application. Operations applied via
+/// `MutPartState::apply_op` are recorded in commit logs instead of the full
+/// new element and re-applied to the previous version when the log is read.
+/// 
+/// Support must be registered via `Element::apply_op_fn`. Operations must be
+/// deterministic: applying an operation to equal elements must always yield
+/// equal results (this is verified via element checksums).
+pub trait ApplyOp: Element {
+    /// Apply serialised operation `op` to this element, returning the result.
+    fn apply_op(&self, op: &[u8]) -> Result<Self>;
 }
 
 impl Element for String {
@@ -183,4 +403,36 @@ impl Element for String {
     fn from_vec(vec: Vec<u8>) -> Result<Self>{
         Ok(String::from_utf8(vec)?)
     }
+    fn mem_size(&self) -> usize {
+        mem::size_of::<Self>() + self.capacity()
+    }
+    fn canonicalise(&self, canon: &TextCanon) -> Option<Self> {
+        canon.canonicalise(self)
+    }
+    fn apply_op_fn() -> Option<ApplyOpFn<Self>> {
+        Some(<Self as ApplyOp>::apply_op)
+    }
+}
+
+/// The only operation on text replaces a range: it is encoded as the start
+/// and end of the range as byte offsets (each a big-endian `u64`), followed
+/// by the replacement text. Offsets must lie on character boundaries.
+impl ApplyOp for String {
+    fn apply_op(&self, op: &[u8]) -> Result<Self> {
+        if op.len() < 16 {
+            return ArgError::err("text operation too short");
+        }
+        let start = BigEndian::read_u64(&op[0..8]) as usize;
+        let end = BigEndian::read_u64(&op[8..16]) as usize;
+        if start > end || end > self.len() ||
+            !self.is_char_boundary(start) || !self.is_char_boundary(end)
+        {
+            return ArgError::err("text operation: invalid range");
+        }
+        let mut result = String::with_capacity(self.len() - (end - start) + op.len() - 16);
+        result.push_str(&self[..start]);
+        result.push_str(from_utf8(&op[16..])?);
+        result.push_str(&self[end..]);
+        Ok(result)
+    }
 }
@@ -82,6 +82,12 @@ impl ErrorTrait for ReadError {
             Wrapped::ErrT(ref e) => e.description(),
         }
     }
+    fn source(&self) -> Option<&(ErrorTrait + 'static)> {
+        match self.detail {
+            Wrapped::Msg(_) => None,
+            Wrapped::ErrT(ref e) => Some(&**e),
+        }
+    }
 }
 impl fmt::Display for ReadError {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
@@ -170,6 +176,11 @@ pub enum ElementOp {
     /// Identifier already in use. An insertion failed since the given
     /// identifier is already in use.
     IdClash,
+    /// A conditional replacement failed since the element's current sum does
+    /// not match that expected (see `MutPartState::replace_if`).
+    SumMismatch,
+    /// An operation-based change could not be applied (see `ApplyOp`)
+    OpFailed,
 }
 impl ErrorTrait for ElementOp {
     fn description(&self) -> &'static str {
@@ -177,6 +188,8 @@ impl ErrorTrait for ElementOp {
             ElementOp::EltNotFound => "element not found",
             ElementOp::IdGenFailure => "id generation failed to find a free identifier",
             ElementOp::IdClash => "identifier already in use",
+            ElementOp::SumMismatch => "element does not match expected sum",
+            ElementOp::OpFailed => "element operation failed or is unsupported",
         }
     }
 }
@@ -199,6 +212,17 @@ pub enum PatchOp {
     WrongParent,
     /// Patch fails to apply cleanly
     PatchApply,
+    /// Commit's user metadata exceeds limits (see `UserMetaLimits`)
+    MetaLimit,
+    /// Too many unsaved commits (see `Control::max_unsaved`)
+    UnsavedLimit,
+    /// Partition was opened read-only (see `Partition::open_read_only`)
+    ReadOnly,
+    /// Elements to be changed were changed by later commits (see
+    /// `Partition::revert`)
+    Conflict,
+    /// Partition is archived (see `Partition::archive`)
+    Archived,
 }
 impl ErrorTrait for PatchOp {
     fn description(&self) -> &'static str {
@@ -206,6 +230,11 @@ impl ErrorTrait for PatchOp {
             PatchOp::NoParent => "parent state of commit not found",
             PatchOp::WrongParent => "applying commit patch failed: wrong parent",
             PatchOp::PatchApply => "applying commit patch failed: data mismatch",
+            PatchOp::MetaLimit => "commit user metadata exceeds limits",
+            PatchOp::UnsavedLimit => "too many unsaved commits",
+            PatchOp::ReadOnly => "cannot add commits: partition is opened read-only",
+            PatchOp::Conflict => "changes conflict with later commits",
+            PatchOp::Archived => "cannot add commits: partition is archived",
         }
     }
 }
@@ -258,14 +287,14 @@ impl fmt::Display for PathError {
 pub enum MatchError {
     /// No matching string found
     NoMatch,
-    /// Multiple matching strings found; two examples follow
-    MultiMatch(String, String),
+    /// Multiple matching strings found; all matches follow (sorted)
+    MultiMatch(Vec<String>),
 }
 impl ErrorTrait for MatchError {
     fn description(&self) -> &str {
         match *self {
             MatchError::NoMatch => "no matching string",
-            MatchError::MultiMatch(_,_) => "multiple matching strings",
+            MatchError::MultiMatch(_) => "multiple matching strings",
         }
     }
 }
@@ -273,8 +302,8 @@ impl fmt::Display for MatchError {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
         match *self {
             MatchError::NoMatch => write!(f, "no match found"),
-            MatchError::MultiMatch(ref m1, ref m2) =>
-                write!(f, "multiple matches found ({}, {}, ...)", m1, m2)
+            MatchError::MultiMatch(ref matches) =>
+                write!(f, "multiple matches found: {}", matches.join(", "))
         }
     }
 }
@@ -317,6 +346,11 @@ pub enum MergeError {
     NotSolved,
     /// Patching failed
     PatchOp(PatchOp),
+    /// Another process (or instance) holds the merge lock (see
+    /// `RepoIO::try_lock_merge`)
+    InProgress,
+    /// More states were given than a commit may have parents (255)
+    TooManyStates,
 }
 impl ErrorTrait for MergeError {
     fn description(&self) -> &str {
@@ -325,6 +359,8 @@ impl ErrorTrait for MergeError {
             MergeError::NoCommonAncestor => "merge: could not find a common ancestor",
             MergeError::NotSolved => "merge: solver failed",
             MergeError::PatchOp(ref p) => p.description(),
+            MergeError::InProgress => "merge: another merge of this partition is in progress",
+            MergeError::TooManyStates => "merge: too many states (at most 255 may be merged at once)",
         }
     }
 }
@@ -364,6 +400,37 @@ impl fmt::Display for ReadOnly {
 }
 
 
+// —————  MemLimit  —————
+/// Loaded data exceeds the memory limit (see `Control::mem_limit`).
+#[derive(PartialEq, Eq, Debug)]
+pub struct MemLimit {
+    /// Estimated memory usage, in bytes
+    pub usage: usize,
+    /// The limit, in bytes
+    pub limit: usize,
+}
+impl MemLimit {
+    /// Create
+    pub fn new(usage: usize, limit: usize) -> MemLimit {
+        MemLimit { usage, limit }
+    }
+    /// Create, wrapped with `Err`
+    pub fn err<T>(usage: usize, limit: usize) -> Result<T> {
+        Err(Box::new(MemLimit::new(usage, limit)))
+    }
+}
+impl ErrorTrait for MemLimit {
+    fn description(&self) -> &str {
+        "memory limit exceeded"
+    }
+}
+impl fmt::Display for MemLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "memory limit exceeded: using {} bytes (limit {})", self.usage, self.limit)
+    }
+}
+
+
 // —————  UserError  —————
 /// An error the user may return
 #[derive(PartialEq, Eq, Debug)]
@@ -389,8 +456,281 @@ impl ErrorTrait for UserError {
 }
 
 
+// —————  RepoError  —————
+/// Principal error type for failures of partition and file operations,
+/// carrying context (e.g. snapshot and log numbers) such that callers can
+/// match on failure modes. Since functions return the compound `Error`,
+/// use `RepoError::find(&*err)` (or `err.downcast_ref::<RepoError>()`).
+/// 
+/// Each variant has a stable numeric code (see `code`), suitable for
+/// logging or reporting across process boundaries.
+#[derive(Debug)]
+pub enum RepoError {
+    /// A file's header names partition `found`, not `expected` (wrong
+    /// repository?)
+    NameMismatch {
+        /// Name of the partition loading the file
+        expected: String,
+        /// Name found in the file header
+        found: String,
+    },
+    /// Snapshot `ss_num` already exists
+    SnapshotExists {
+        /// Snapshot number
+        ss_num: usize,
+    },
+    /// Commit log `cl_num` of snapshot `ss_num` already exists
+    LogExists {
+        /// Snapshot number
+        ss_num: usize,
+        /// Log number
+        cl_num: usize,
+    },
+    /// No free snapshot number was found (the last tried being `ss_num`)
+    SnapshotNumberOverflow {
+        /// Last snapshot number tried
+        ss_num: usize,
+    },
+    /// No free commit log number was found for snapshot `ss_num`
+    LogNumberOverflow {
+        /// Snapshot number
+        ss_num: usize,
+    },
+    /// No snapshot was found (nor may a blank state be assumed)
+    NoSnapshot,
+    /// Snapshot `ss_num` was not found
+    SnapshotNotFound {
+        /// Snapshot number
+        ss_num: usize,
+    },
+    /// Snapshot `ss_num` has no element index (see `Partition::load_lazy`)
+    NoEltIndex {
+        /// Snapshot number
+        ss_num: usize,
+    },
+    /// Snapshot `ss_num` is encrypted thus cannot be read on demand
+    SnapshotEncrypted {
+        /// Snapshot number
+        ss_num: usize,
+    },
+    /// Unexpected data follows the end of a snapshot
+    TrailingData,
+    /// On-demand reads require `Partition::load_lazy` to be called first
+    NotLazyLoaded,
+    /// No state at or before `timestamp` is available
+    NoStateAt {
+        /// The time requested
+        timestamp: i64,
+    },
+    /// The operation requires unsaved commits to be written first
+    UnsavedCommits,
+    /// The state has elements which could not be read (see
+    /// `Partition::skipped_elts`)
+    SkippedElements,
+    /// A squashed commit does not reproduce its target state
+    SquashMismatch,
+    /// A snapshot (`cl_num` is `None`) or commit log could not be replaced
+    ReplaceFailed {
+        /// Snapshot number
+        ss_num: usize,
+        /// Log number, if a commit log
+        cl_num: Option<usize>,
+    },
+    /// The compression method used by a file is not supported (not enabled
+    /// at compile time)
+    CompressionUnsupported {
+        /// Name of the method
+        method: &'static str,
+    },
+    /// A file is encrypted with scheme `scheme`, not that of the configured
+    /// cipher
+    CipherMismatch {
+        /// Scheme recorded in the file header
+        scheme: String,
+    },
+    /// A file is encrypted but no cipher is configured
+    NoCipher,
+    /// Encryption failed
+    EncryptionFailed,
+    /// Decryption or authentication of encrypted data failed
+    DecryptionFailed,
+    /// Commit metadata has unknown essential flags
+    UnknownMetaFlags {
+        /// Raw flags
+        flags: u16,
+    },
+    /// A snapshot uses the removed element-move feature
+    EltMovesUnsupported,
+    /// A sync message has an invalid length
+    SyncMessageLength {
+        /// Length of the message
+        len: usize,
+    },
+    /// The sync connection was closed
+    SyncClosed,
+    /// The remote end reported failure of a sync request
+    SyncRemoteFailure,
+    /// A sync message violates the protocol
+    SyncProtocol(&'static str),
+    /// A test vector does not match (see `rw::compat`)
+    TestVectorMismatch(&'static str),
+    /// The operation was cancelled (see `Control::cancel_flag`)
+    Cancelled,
+    /// Failure while processing snapshot `ss_num` or one of its logs
+    InFile {
+        /// Snapshot number
+        ss_num: usize,
+        /// Log number, if a commit log
+        cl_num: Option<usize>,
+        /// The error
+        source: Error,
+    },
+    /// A snapshot (`cl_num` is `None`) or commit log was not found
+    FileNotFound {
+        /// Snapshot number
+        ss_num: usize,
+        /// Log number, if a commit log
+        cl_num: Option<usize>,
+    },
+}
+impl RepoError {
+    /// Create, wrapped with `Err`
+    pub fn err<T>(e: RepoError) -> Result<T> {
+        Err(Box::new(e))
+    }
+    /// Wrap `source` with the file concerned (see `InFile`)
+    pub fn in_file(ss_num: usize, cl_num: Option<usize>, source: Error) -> Error {
+        Box::new(RepoError::InFile { ss_num, cl_num, source })
+    }
+    
+    /// Find a `RepoError` in `err` or its chain of sources. Where `InFile`
+    /// wraps a `RepoError`, the wrapped error is returned.
+    pub fn find<'a>(err: &'a (ErrorTrait + 'static)) -> Option<&'a RepoError> {
+        let mut next = Some(err);
+        let mut found = None;
+        while let Some(e) = next {
+            if let Some(e) = e.downcast_ref::<RepoError>() {
+                found = Some(e);
+            }
+            next = e.source();
+        }
+        found
+    }
+    
+    /// Stable numeric code of this error
+    pub fn code(&self) -> u32 {
+        match *self {
+            RepoError::NameMismatch { .. } => 1,
+            RepoError::SnapshotExists { .. } => 2,
+            RepoError::LogExists { .. } => 3,
+            RepoError::SnapshotNumberOverflow { .. } => 4,
+            RepoError::LogNumberOverflow { .. } => 5,
+            RepoError::NoSnapshot => 6,
+            RepoError::SnapshotNotFound { .. } => 7,
+            RepoError::NoEltIndex { .. } => 8,
+            RepoError::SnapshotEncrypted { .. } => 9,
+            RepoError::TrailingData => 10,
+            RepoError::NotLazyLoaded => 11,
+            RepoError::NoStateAt { .. } => 12,
+            RepoError::UnsavedCommits => 13,
+            RepoError::SkippedElements => 14,
+            RepoError::SquashMismatch => 15,
+            RepoError::ReplaceFailed { .. } => 16,
+            RepoError::CompressionUnsupported { .. } => 17,
+            RepoError::CipherMismatch { .. } => 18,
+            RepoError::NoCipher => 19,
+            RepoError::EncryptionFailed => 20,
+            RepoError::DecryptionFailed => 21,
+            RepoError::UnknownMetaFlags { .. } => 22,
+            RepoError::EltMovesUnsupported => 23,
+            RepoError::SyncMessageLength { .. } => 24,
+            RepoError::SyncClosed => 25,
+            RepoError::SyncRemoteFailure => 26,
+            RepoError::SyncProtocol(_) => 27,
+            RepoError::TestVectorMismatch(_) => 28,
+            RepoError::InFile { .. } => 29,
+            RepoError::Cancelled => 30,
+            RepoError::FileNotFound { .. } => 31,
+        }
+    }
+}
+// Display a file as "snapshot 1" or "commit log 1-2"
+struct FileName(usize, Option<usize>);
+impl fmt::Display for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match self.1 {
+            None => write!(f, "snapshot {}", self.0),
+            Some(cl) => write!(f, "commit log {}-{}", self.0, cl),
+        }
+    }
+}
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            RepoError::NameMismatch { ref expected, ref found } =>
+                write!(f, "partition name {:?} does not match {:?} (wrong repo?)",
+                        found, expected),
+            RepoError::SnapshotExists { ss_num } => write!(f, "snapshot {} already exists", ss_num),
+            RepoError::LogExists { ss_num, cl_num } =>
+                write!(f, "commit log {}-{} already exists", ss_num, cl_num),
+            RepoError::SnapshotNumberOverflow { ss_num } =>
+                write!(f, "snapshot number too high ({})", ss_num),
+            RepoError::LogNumberOverflow { ss_num } =>
+                write!(f, "commit log number too high (snapshot {})", ss_num),
+            RepoError::NoSnapshot => write!(f, "no snapshot found"),
+            RepoError::SnapshotNotFound { ss_num } => write!(f, "snapshot {} not found", ss_num),
+            RepoError::NoEltIndex { ss_num } =>
+                write!(f, "snapshot {} has no element index", ss_num),
+            RepoError::SnapshotEncrypted { ss_num } =>
+                write!(f, "snapshot {} is encrypted", ss_num),
+            RepoError::TrailingData => write!(f, "unexpected data after end of snapshot"),
+            RepoError::NotLazyLoaded => write!(f, "not loaded (see load_lazy)"),
+            RepoError::NoStateAt { timestamp } =>
+                write!(f, "no state at or before time {} is available", timestamp),
+            RepoError::UnsavedCommits => write!(f, "there are unsaved commits"),
+            RepoError::SkippedElements => write!(f, "state has unreadable (skipped) elements"),
+            RepoError::SquashMismatch => write!(f, "squashed commit does not reproduce state"),
+            RepoError::ReplaceFailed { ss_num, cl_num } =>
+                write!(f, "unable to replace {}", FileName(ss_num, cl_num)),
+            RepoError::CompressionUnsupported { method } =>
+                write!(f, "file compression method not supported: {}", method),
+            RepoError::CipherMismatch { ref scheme } =>
+                write!(f, "file encrypted with a different scheme: {}", scheme),
+            RepoError::NoCipher => write!(f, "file is encrypted but no cipher is configured"),
+            RepoError::EncryptionFailed => write!(f, "encryption failed"),
+            RepoError::DecryptionFailed => write!(f, "decryption failed"),
+            RepoError::UnknownMetaFlags { flags } =>
+                write!(f, "found essential unknown commit meta flag (flags: {:#06x})", flags),
+            RepoError::EltMovesUnsupported => write!(f, "element move support removed"),
+            RepoError::SyncMessageLength { len } => write!(f, "sync: bad message length {}", len),
+            RepoError::SyncClosed => write!(f, "sync: connection closed"),
+            RepoError::SyncRemoteFailure => write!(f, "sync: request failed remotely"),
+            RepoError::SyncProtocol(msg) => write!(f, "sync: {}", msg),
+            RepoError::TestVectorMismatch(msg) => write!(f, "test vector mismatch: {}", msg),
+            RepoError::Cancelled => write!(f, "operation cancelled"),
+            RepoError::InFile { ss_num, cl_num, ref source } =>
+                write!(f, "{}: {}", FileName(ss_num, cl_num), source),
+            RepoError::FileNotFound { ss_num, cl_num } =>
+                write!(f, "{} not found", FileName(ss_num, cl_num)),
+        }
+    }
+}
+impl ErrorTrait for RepoError {
+    fn description(&self) -> &str {
+        "repository operation failed"
+    }
+    fn source(&self) -> Option<&(ErrorTrait + 'static)> {
+        match *self {
+            RepoError::InFile { ref source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+
 // —————  OtherError  —————
-/// Unclassified, generally not recoverable errors
+/// Unclassified, generally not recoverable errors. Failures of this library
+/// are reported via `RepoError`; this remains for use by applications.
 #[derive(PartialEq, Eq, Debug)]
 pub struct OtherError {
     msg: &'static str,
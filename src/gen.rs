@@ -0,0 +1,329 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Bulk random data generation (feature `gen`)
+//! 
+//! This provides a simple element type, `Sequence`, a set of number-sequence
+//! generators and `GenSpec`, a description of how many elements to generate
+//! and of what size. Given a seeded random number generator, the same
+//! specification always produces the same data, thus benchmarks and
+//! reproduction cases can be compared across users and machines.
+
+use std::io::Write;
+use std::cmp::{min, max};
+use std::mem::size_of;
+use std::fmt::Debug;
+
+use rand::Rng;
+use rand::distributions::{IndependentSample, Range, Normal, LogNormal};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use control::Control;
+use elt::{EltId, Element};
+use error::{Result, ReadError};
+use part::Partition;
+use state::{MutPartState, StateWrite};
+
+
+// —————  Sequence type itself  —————
+
+/// Type of sequence elements.
+pub type R = f64;
+/// Type is a wrapper around a vector of f64. The reason for this is that we
+/// can only implement `Element` for new types, thus cannot use the vector type
+/// directly (see #44).
+#[derive(PartialEq, Debug)]
+pub struct Sequence {
+    v: Vec<R>,
+}
+impl Eq for Sequence {}
+impl Sequence {
+    /// Get length of sequence
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+    /// True if the sequence has length zero
+    pub fn is_empty(&self) -> bool {
+        self.v.is_empty()
+    }
+    /// Access numbers of the sequence
+    pub fn as_slice(&self) -> &[R] {
+        &self.v
+    }
+}
+impl From<Vec<R>> for Sequence {
+    fn from(v: Vec<R>) -> Self {
+        Sequence { v }
+    }
+}
+
+impl Element for Sequence {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        for x in &self.v {
+            writer.write_f64::<LittleEndian>(*x)?;
+        }
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        if buf.len() % size_of::<R>() != 0 {
+            return ReadError::err("invalid data length", 0, (0, buf.len()));
+        }
+        let r: &mut &[u8] = &mut &buf[..];
+        let n = buf.len() / size_of::<R>();
+        let mut v = Vec::with_capacity(n);
+        for _ in 0..n {
+            v.push(r.read_f64::<LittleEndian>()?);
+        }
+        Ok(Sequence { v })
+    }
+}
+
+
+// —————  Generators  —————
+/// A generator can generate a sequence of numbers.
+pub trait Generator: Debug {
+    /// Generate a sequence of `n` numbers.
+    fn generate(&self, n: usize) -> Vec<R>;
+}
+/// Arithmetic sequence (e.g. 1, 4, 7, 10)
+#[derive(Debug)]
+pub struct Arithmetic {
+    /// First number
+    pub start: R,
+    /// Difference between consecutive numbers
+    pub step: R,
+}
+/// Geometric sequence (e.g. 2, 6, 18, 54)
+#[derive(Debug)]
+pub struct Geometric {
+    /// First number
+    pub start: R,
+    /// Ratio between consecutive numbers
+    pub factor: R,
+}
+/// Fibonacci sequence (usually 1, 1, 2, 3, 5, 8, ..., but starting numbers
+/// can be changed)
+#[derive(Debug)]
+pub struct Fibonacci {
+    /// First number
+    pub x1: R,
+    /// Second number
+    pub x2: R,
+}
+/// Power sequence (e.g. 3, 9, 27, 81)
+#[derive(Debug)]
+pub struct Power {
+    /// Exponent: the sequence is `i^e` for `i = 0, 1, 2, ...`
+    pub e: R,
+}
+
+impl Generator for Arithmetic {
+    fn generate(&self, n: usize) -> Vec<R> {
+        let mut v = Vec::with_capacity(n);
+        let mut x = self.start;
+        while v.len() < n {
+            v.push(x);
+            x += self.step;
+        }
+        v
+    }
+}
+impl Generator for Geometric {
+    fn generate(&self, n: usize) -> Vec<R> {
+        let mut v = Vec::with_capacity(n);
+        let mut x = self.start;
+        while v.len() < n {
+            v.push(x);
+            x *= self.factor;
+        }
+        v
+    }
+}
+impl Generator for Fibonacci {
+    fn generate(&self, n: usize) -> Vec<R> {
+        let mut v = Vec::with_capacity(n);
+        let (mut x1, mut x2) = (self.x1, self.x2);
+        while v.len() < n {
+            v.push(x1);
+            let x = x1 + x2;
+            x1 = x2;
+            x2 = x;
+        }
+        v
+    }
+}
+impl Generator for Power {
+    fn generate(&self, n: usize) -> Vec<R> {
+        let mut v = Vec::with_capacity(n);
+        let mut i: R = 0.0;
+        while v.len() < n {
+            v.push(i.powf(self.e));
+            i += 1.0;
+        }
+        v
+    }
+}
+
+/// Enum of all generator types
+#[derive(Debug)]
+pub enum GeneratorEnum {
+    /// Arithmetic sequence
+    Arithmetic(Arithmetic),
+    /// Geometric sequence
+    Geometric(Geometric),
+    /// Fibonacci sequence
+    Fibonacci(Fibonacci),
+    /// Power sequence
+    Power(Power),
+}
+impl GeneratorEnum {
+    /// Randomly create a new generator.
+    pub fn new_random(mut rng: &mut Rng) -> GeneratorEnum {
+        match Range::new(0, 4).ind_sample(&mut rng) {
+            0 => {
+                GeneratorEnum::Arithmetic(Arithmetic {
+                    start: LogNormal::new(0., 100.).ind_sample(&mut rng),
+                    step: Normal::new(0., 10.).ind_sample(&mut rng),
+                })
+            },
+            1 => {
+                GeneratorEnum::Geometric(Geometric {
+                    start: LogNormal::new(0., 100.).ind_sample(&mut rng),
+                    factor: Normal::new(0., 2.).ind_sample(&mut rng),
+                })
+            },
+            2 => {
+                GeneratorEnum::Fibonacci(Fibonacci {
+                    x1: Normal::new(1., 1.).ind_sample(&mut rng),
+                    x2: Normal::new(1., 1.).ind_sample(&mut rng),
+                })
+            },
+            3 => {
+                GeneratorEnum::Power(Power {
+                    e: LogNormal::new(0., 1.).ind_sample(&mut rng),
+                })
+            },
+            _ => { panic!("invalid sample"); }
+        }
+    }
+}
+impl Generator for GeneratorEnum {
+    fn generate(&self, n: usize) -> Vec<R> {
+        match *self {
+            GeneratorEnum::Arithmetic(ref gen) => gen.generate(n),
+            GeneratorEnum::Geometric(ref gen) => gen.generate(n),
+            GeneratorEnum::Fibonacci(ref gen) => gen.generate(n),
+            GeneratorEnum::Power(ref gen) => gen.generate(n),
+        }
+    }
+}
+
+
+// —————  Bulk generation  —————
+
+/// Distribution of sequence lengths
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LenDist {
+    /// Every sequence has this length
+    Fixed(usize),
+    /// Uniformly distributed over `[low, high)` (requires `low < high`)
+    Uniform(usize, usize),
+    /// Log-normal with the given mean and standard deviation of the logarithm
+    LogNormal(f64, f64),
+}
+impl LenDist {
+    fn sample<G: Rng>(&self, rng: &mut G) -> usize {
+        match *self {
+            LenDist::Fixed(n) => n,
+            LenDist::Uniform(low, high) => Range::new(low, high).ind_sample(rng),
+            LenDist::LogNormal(mean, std_dev) =>
+                LogNormal::new(mean, std_dev).ind_sample(rng) as usize,
+        }
+    }
+}
+
+/// Specification of data to generate.
+/// 
+/// The default generates 50 elements per commit with log-normally
+/// distributed lengths (`LenDist::LogNormal(1., 2.)`) capped at 1000.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GenSpec {
+    /// Number of elements to insert per commit
+    pub num_elts: usize,
+    /// Distribution of sequence lengths
+    pub len_dist: LenDist,
+    /// Maximum length of any sequence
+    pub max_len: usize,
+}
+impl Default for GenSpec {
+    fn default() -> Self {
+        GenSpec { num_elts: 50, len_dist: LenDist::LogNormal(1., 2.), max_len: 1_000 }
+    }
+}
+
+/// Statistics on generated data
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct GenStats {
+    /// Number of elements inserted
+    pub num_elts: usize,
+    /// Length of the longest sequence
+    pub longest: usize,
+    /// Sum of the lengths of all sequences
+    pub total_len: usize,
+}
+impl GenStats {
+    /// Average sequence length (zero if nothing was generated)
+    pub fn average_len(&self) -> f64 {
+        if self.num_elts == 0 { 0.0 } else {
+            (self.total_len as f64) / (self.num_elts as f64)
+        }
+    }
+    fn add(&mut self, other: &GenStats) {
+        self.num_elts += other.num_elts;
+        self.longest = max(self.longest, other.longest);
+        self.total_len += other.total_len;
+    }
+}
+
+impl GenSpec {
+    /// Insert `self.num_elts` new random sequences into `state`.
+    /// 
+    /// Identifiers are drawn from `rng` (within the low 24 bits, adjusted to
+    /// the nearest free identifier), thus the result depends only on the
+    /// specification, the initial state and the state of `rng`.
+    pub fn fill_state<G: Rng>(&self, state: &mut MutPartState<Sequence>, rng: &mut G)
+            -> Result<GenStats>
+    {
+        let mut stats = GenStats::default();
+        for _ in 0..self.num_elts {
+            let gen = GeneratorEnum::new_random(rng);
+            let len = min(self.len_dist.sample(rng), self.max_len);
+            let seq = gen.generate(len).into();
+            let initial = EltId::from((rng.gen::<u32>() & 0xFF_FFFF) as u64);
+            let id = state.free_id_near(initial)?;
+            state.insert(id, seq)?;
+            stats.num_elts += 1;
+            stats.longest = max(stats.longest, len);
+            stats.total_len += len;
+        }
+        Ok(stats)
+    }
+    
+    /// Populate a partition: `commits` times, generate elements as in
+    /// `fill_state` on top of the partition's tip and push the result.
+    /// 
+    /// Changes are not written; call `part.write_full()` or similar after.
+    pub fn populate<C, G: Rng>(&self, part: &mut Partition<C>, commits: usize,
+            rng: &mut G) -> Result<GenStats>
+            where C: Control<Element = Sequence>
+    {
+        let mut stats = GenStats::default();
+        for _ in 0..commits {
+            let mut state = part.tip()?.clone_mut();
+            stats.add(&self.fill_state(&mut state, rng)?);
+            part.push_state(state)?;
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,159 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Secondary indexes over element data
+//! 
+//! The user supplies index functions via `Control::index_fns`; each maps an
+//! element to any number of keys. A partition maintains maps from keys to
+//! elements for its tip, updated incrementally as commits are applied on top
+//! of the indexed state and rebuilt otherwise (e.g. after a merge choosing
+//! another parent), and answers queries via `Partition::find_by_index`
+//! without scanning all elements.
+
+use std::collections::{HashMap, HashSet};
+
+use commit::Commit;
+use elt::{Element, EltId};
+use state::{PartState, StateRead};
+use sum::Sum;
+
+
+/// A key of a secondary index (see `IndexFn`)
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct IndexKey(Vec<u8>);
+
+impl IndexKey {
+    /// Get the key's bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+impl From<Vec<u8>> for IndexKey {
+    fn from(bytes: Vec<u8>) -> IndexKey {
+        IndexKey(bytes)
+    }
+}
+impl<'a> From<&'a [u8]> for IndexKey {
+    fn from(bytes: &'a [u8]) -> IndexKey {
+        IndexKey(bytes.to_vec())
+    }
+}
+impl From<String> for IndexKey {
+    fn from(text: String) -> IndexKey {
+        IndexKey(text.into_bytes())
+    }
+}
+impl<'a> From<&'a str> for IndexKey {
+    fn from(text: &'a str) -> IndexKey {
+        IndexKey(text.as_bytes().to_vec())
+    }
+}
+impl From<u64> for IndexKey {
+    /// Numbers are stored big-endian, thus keys order as numbers do
+    fn from(n: u64) -> IndexKey {
+        let bytes: Vec<u8> = (0..8).rev().map(|i| (n >> (8 * i)) as u8).collect();
+        IndexKey(bytes)
+    }
+}
+
+/// Function deriving index keys from an element. This must be deterministic
+/// and should be cheap, since it is called for each element changed.
+pub type IndexFn<E> = fn(&E) -> Vec<IndexKey>;
+
+/// Maps from index keys to elements for one state (see module documentation)
+#[derive(Debug, Default)]
+pub struct Index {
+    // State indexed, if any
+    state: Option<Sum>,
+    // For each index function, elements by key
+    maps: Vec<HashMap<IndexKey, HashSet<EltId>>>,
+    // For each index function, keys by element
+    keys: Vec<HashMap<EltId, Vec<IndexKey>>>,
+}
+
+impl Index {
+    /// Create, with nothing indexed
+    pub fn new() -> Self {
+        Default::default()
+    }
+    
+    /// True if `state` is indexed, using `num_fns` index functions
+    pub fn is_current(&self, state: &Sum, num_fns: usize) -> bool {
+        self.state.as_ref() == Some(state) && self.maps.len() == num_fns
+    }
+    
+    /// Drop all maps (nothing is then indexed)
+    pub fn clear(&mut self) {
+        self.state = None;
+        self.maps.clear();
+        self.keys.clear();
+    }
+    
+    /// Index all elements of `state`
+    pub fn rebuild<E: Element>(&mut self, state: &PartState<E>, fns: &[IndexFn<E>]) {
+        self.clear();
+        self.maps.resize(fns.len(), HashMap::new());
+        self.keys.resize(fns.len(), HashMap::new());
+        for (id, elt) in state.elts_iter() {
+            self.insert(id, &**elt, fns);
+        }
+        self.state = Some(state.statesum().clone());
+    }
+    
+    /// Update after `commit` was applied, given its resulting `state`. This
+    /// does nothing unless the commit's first parent is the indexed state.
+    pub fn apply<E: Element>(&mut self, commit: &Commit<E>, state: &PartState<E>,
+            fns: &[IndexFn<E>])
+    {
+        if self.state.as_ref() != Some(commit.first_parent()) || self.maps.len() != fns.len() {
+            return;
+        }
+        for (id, _) in commit.changes_iter() {
+            self.remove(*id);
+            if let Ok(elt) = state.get(*id) {
+                self.insert(*id, elt, fns);
+            }
+        }
+        self.state = Some(state.statesum().clone());
+    }
+    
+    /// Find elements with `key` in index `index` (sorted by identifier).
+    /// Returns `None` if there is no such index.
+    pub fn find(&self, index: usize, key: &IndexKey) -> Option<Vec<EltId>> {
+        self.maps.get(index).map(|map| {
+            let mut ids: Vec<EltId> = map.get(key)
+                    .map_or_else(Vec::new, |ids| ids.iter().cloned().collect());
+            ids.sort();
+            ids
+        })
+    }
+    
+    fn insert<E: Element>(&mut self, id: EltId, elt: &E, fns: &[IndexFn<E>]) {
+        for (i, f) in fns.iter().enumerate() {
+            let mut keys = f(elt);
+            keys.sort();
+            keys.dedup();
+            for key in &keys {
+                self.maps[i].entry(key.clone()).or_default().insert(id);
+            }
+            if !keys.is_empty() {
+                self.keys[i].insert(id, keys);
+            }
+        }
+    }
+    
+    fn remove(&mut self, id: EltId) {
+        for (map, keys) in self.maps.iter_mut().zip(self.keys.iter_mut()) {
+            for key in keys.remove(&id).unwrap_or_default() {
+                let empty = map.get_mut(&key).is_some_and(|ids| {
+                    ids.remove(&id);
+                    ids.is_empty()
+                });
+                if empty {
+                    map.remove(&key);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Export and import of partitions to a portable archive
+//! 
+//! An archive is a single self-contained stream holding the snapshots,
+//! commit logs and audit logs of one or more partitions, e.g. for backups
+//! or migration between `RepoIO` implementations, without needing to know
+//! how files are named or stored.
+//! 
+//! Format (numbers are big-endian):
+//! 
+//! *   16 bytes: `PIPPIN ARCHIVE` followed by a zero byte and format number 1
+//! *   manifest: a `u32` number of partitions, then for each a `u16` name
+//!     length, the name (UTF-8) and a `u64` snapshot count (`ss_len`)
+//! *   entries, each: a `u8` kind (1: snapshot, 2: commit log, 3: audit log),
+//!     a `u32` partition number (index in the manifest), `u64` snapshot and
+//!     log numbers, a `u64` length, the file contents and a checksum of the
+//!     contents (`Sum::calculate`)
+//! *   end marker: a `u8` zero and a `u64` number of entries
+//! 
+//! Log indexes are not included since these are optional.
+
+use std::io::{Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use error::{Result, ReadError, ArgError, RepoError};
+use io::RepoIO;
+use sum::{Sum, SUM_BYTES};
+
+const MAGIC: [u8; 16] = *b"PIPPIN ARCHIVE\x00\x01";
+const KIND_END: u8 = 0;
+const KIND_SS: u8 = 1;
+const KIND_CL: u8 = 2;
+const KIND_AUDIT: u8 = 3;
+
+/// Write all files of each partition to an archive stream.
+/// 
+/// Each partition is given a name, which must be unique and is passed to
+/// `import_archive`. Returns the number of files written.
+pub fn export_archive(parts: &[(&str, &RepoIO)], w: &mut Write) -> Result<usize> {
+    w.write_all(&MAGIC)?;
+    w.write_u32::<BigEndian>(parts.len() as u32)?;
+    for (i, &(name, io)) in parts.iter().enumerate() {
+        if name.len() > u16::MAX as usize {
+            return ArgError::err("partition name too long");
+        }
+        if parts[..i].iter().any(|p| p.0 == name) {
+            return ArgError::err("partition names must be unique");
+        }
+        w.write_u16::<BigEndian>(name.len() as u16)?;
+        w.write_all(name.as_bytes())?;
+        w.write_u64::<BigEndian>(io.ss_len() as u64)?;
+    }
+    
+    let mut num = 0;
+    let mut data = Vec::new();
+    for (part, &(_, io)) in parts.iter().enumerate() {
+        for ss in 0..io.ss_len() {
+            if let Some(mut r) = io.read_ss(ss)? {
+                data.clear();
+                r.read_to_end(&mut data)?;
+                write_entry(w, KIND_SS, part, ss, 0, &data)?;
+                num += 1;
+            }
+            for cl in 0..io.ss_cl_len(ss) {
+                if let Some(mut r) = io.read_ss_cl(ss, cl)? {
+                    data.clear();
+                    r.read_to_end(&mut data)?;
+                    write_entry(w, KIND_CL, part, ss, cl, &data)?;
+                    num += 1;
+                }
+            }
+        }
+        if let Some(mut r) = io.read_audit()? {
+            data.clear();
+            r.read_to_end(&mut data)?;
+            if !data.is_empty() {
+                write_entry(w, KIND_AUDIT, part, 0, 0, &data)?;
+                num += 1;
+            }
+        }
+    }
+    
+    w.write_u8(KIND_END)?;
+    w.write_u64::<BigEndian>(num as u64)?;
+    Ok(num)
+}
+
+fn write_entry(w: &mut Write, kind: u8, part: usize, ss: usize, cl: usize, data: &[u8])
+        -> Result<()>
+{
+    w.write_u8(kind)?;
+    w.write_u32::<BigEndian>(part as u32)?;
+    w.write_u64::<BigEndian>(ss as u64)?;
+    w.write_u64::<BigEndian>(cl as u64)?;
+    w.write_u64::<BigEndian>(data.len() as u64)?;
+    w.write_all(data)?;
+    Sum::calculate(data).write_to(w)?;
+    Ok(())
+}
+
+/// Read an archive written by `export_archive`, writing the files of each
+/// partition to a new `RepoIO` created by `make_io` (given the partition's
+/// name). Returns each partition's name and `RepoIO`, in archive order.
+/// 
+/// Checksums are verified before each file is written. Fails if a file
+/// already exists in the target `RepoIO` (which should thus be empty) or if
+/// the target does not support audit logs and the archive includes one.
+pub fn import_archive<IO, F>(r: &mut Read, mut make_io: F) -> Result<Vec<(String, IO)>>
+    where IO: RepoIO, F: FnMut(&str) -> Result<IO>
+{
+    let mut magic = [0u8; 16];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return ReadError::err("not a Pippin archive", 0, (0, 16));
+    }
+    let mut pos = 16;
+    let num_parts = r.read_u32::<BigEndian>()? as usize;
+    pos += 4;
+    let mut parts = Vec::with_capacity(num_parts);
+    for _ in 0..num_parts {
+        let len = r.read_u16::<BigEndian>()? as usize;
+        let mut name = vec![0; len];
+        r.read_exact(&mut name)?;
+        let name = match String::from_utf8(name) {
+            Ok(name) => name,
+            Err(_) => return ReadError::err("partition name not valid UTF-8", pos, (2, 2 + len)),
+        };
+        let _ss_len = r.read_u64::<BigEndian>()?;
+        pos += 10 + len;
+        let io = make_io(&name)?;
+        parts.push((name, io));
+    }
+    
+    let mut num = 0;
+    let mut sum_buf = [0u8; SUM_BYTES];
+    loop {
+        let kind = r.read_u8()?;
+        if kind == KIND_END {
+            if r.read_u64::<BigEndian>()? != num {
+                return ReadError::err("wrong number of entries in archive", pos, (1, 9));
+            }
+            break;
+        }
+        let part = r.read_u32::<BigEndian>()? as usize;
+        let ss = r.read_u64::<BigEndian>()? as usize;
+        let cl = r.read_u64::<BigEndian>()? as usize;
+        let len = r.read_u64::<BigEndian>()?;
+        let mut data = Vec::new();
+        Read::take(&mut *r, len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return ReadError::err("unexpected end of archive", pos, (29, 29));
+        }
+        r.read_exact(&mut sum_buf)?;
+        if Sum::load(&sum_buf) != Sum::calculate(&data) {
+            return ReadError::err("checksum mismatch", pos, (0, 29));
+        }
+        let io = match parts.get_mut(part) {
+            Some(p) => &mut p.1,
+            None => return ReadError::err("invalid partition number", pos, (1, 5)),
+        };
+        let w = match kind {
+            KIND_SS => io.new_ss(ss)?,
+            KIND_CL => io.new_ss_cl(ss, cl)?,
+            KIND_AUDIT => io.append_audit()?,
+            _ => return ReadError::err("unknown entry kind", pos, (0, 1)),
+        };
+        match w {
+            Some(mut w) => {
+                w.write_all(&data)?;
+                // Some `RepoIO`s only complete the file when flushed:
+                w.flush()?;
+            },
+            None if kind == KIND_SS => return RepoError::err(RepoError::SnapshotExists { ss_num: ss }),
+            None if kind == KIND_CL => return RepoError::err(RepoError::LogExists { ss_num: ss, cl_num: cl }),
+            None => return ArgError::err("unable to append audit entry"),
+        }
+        num += 1;
+        pos += 29 + data.len() + SUM_BYTES;
+    }
+    Ok(parts)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::mem::MemRepoIO;
+    
+    #[test]
+    fn archive() {
+        let mut a = MemRepoIO::new();
+        a.insert_ss(0, b"snapshot 0".to_vec());
+        a.insert_ss_cl(0, 0, b"log 0-0".to_vec());
+        a.insert_ss_cl(0, 1, b"log 0-1".to_vec());
+        a.insert_ss(1, b"snapshot 1".to_vec());
+        a.append_audit().unwrap().unwrap().write_all(b"1 gc removed 0\n").unwrap();
+        let mut b = MemRepoIO::new();
+        b.insert_ss(0, b"another".to_vec());
+        
+        let mut buf = Vec::new();
+        let parts: [(&str, &RepoIO); 2] = [("a", &a), ("b", &b)];
+        assert_eq!(export_archive(&parts, &mut buf).unwrap(), 6);
+        assert!(export_archive(&[("a", &a), ("a", &b)], &mut Vec::new()).is_err());
+        
+        let imported = import_archive(&mut &buf[..], |_| Ok(MemRepoIO::new())).unwrap();
+        assert_eq!(imported, vec![("a".to_string(), a), ("b".to_string(), b)]);
+        
+        // Corruption is detected:
+        let n = buf.len() - 9 - SUM_BYTES - 1;
+        buf[n] ^= 1;
+        assert!(import_archive(&mut &buf[..], |_| Ok(MemRepoIO::new())).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! On-disk cache of materialised states
+//! 
+//! Loading a partition requires replaying each commit since the last
+//! snapshot. `StateCache` stores states reached this way in a local
+//! directory, keyed by state-sum, such that later loads can use the cached
+//! state instead of replaying the commit (see `Control::state_cache`).
+//! 
+//! Cached states are stored in snapshot format in files named
+//! `<state-sum>.pipsc`. The cache is bounded in size; least-recently-used
+//! entries are removed first. Each state is verified against its sum when
+//! read; invalid entries are removed.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use commit::UserMetaLimits;
+use elt::{Element, EltId};
+use error::Result;
+use rw::EltReader;
+use rw::compress::{Compression, decompress};
+use rw::header::{FileHeader, FileType, read_head, write_head};
+use rw::snapshot::{read_snapshot, write_snapshot};
+use state::{PartState, StateRead};
+use sum::Sum;
+
+const EXTENSION: &str = "pipsc";
+
+// Per-entry data: size in bytes and time of last use
+#[derive(Debug)]
+struct Entry {
+    size: u64,
+    used: u64,
+}
+
+/// A size-limited directory of cached states.
+/// 
+/// One directory should be used per partition.
+#[derive(Debug)]
+pub struct StateCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    min_commits: usize,
+    total: u64,
+    // Counter used to order entries by use
+    clock: u64,
+    entries: HashMap<Sum, Entry>,
+}
+
+impl StateCache {
+    /// Open a cache in directory `dir`, creating the directory if necessary.
+    /// Existing entries are kept (ordered by modification time) subject to
+    /// the size limit `max_bytes`.
+    pub fn open(dir: &Path, max_bytes: u64) -> Result<StateCache> {
+        fs::create_dir_all(dir)?;
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != EXTENSION) { continue; }
+            let sum = match path.file_stem().and_then(|s| s.to_str()).map(Sum::from_hex) {
+                Some(Ok(sum)) => sum,
+                _ => continue,
+            };
+            let meta = entry.metadata()?;
+            found.push((meta.modified().ok(), sum, meta.len()));
+        }
+        found.sort_by_key(|entry| entry.0);
+        
+        let mut cache = StateCache { dir: dir.to_path_buf(), max_bytes, min_commits: 10,
+                total: 0, clock: 0, entries: HashMap::new() };
+        for (_, sum, size) in found {
+            cache.clock += 1;
+            cache.total += size;
+            cache.entries.insert(sum, Entry { size, used: cache.clock });
+        }
+        cache.evict(None)?;
+        Ok(cache)
+    }
+    
+    /// Get the directory used
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    
+    /// Get the number of replayed commits after which a state is worth
+    /// caching (see `set_min_commits`).
+    pub fn min_commits(&self) -> usize {
+        self.min_commits
+    }
+    /// Set the number of commits which must be replayed when loading
+    /// before the resulting tip is cached by `Partition` (default 10).
+    pub fn set_min_commits(&mut self, n: usize) {
+        self.min_commits = n;
+    }
+    
+    /// Number of states cached
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    /// True if no states are cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Total size of cached states, in bytes
+    pub fn total_bytes(&self) -> u64 {
+        self.total
+    }
+    
+    /// True if a state with this sum is cached
+    pub fn contains(&self, sum: &Sum) -> bool {
+        self.entries.contains_key(sum)
+    }
+    
+    /// Get a cached state, if present.
+    /// 
+    /// The state read is verified against `sum`; on mismatch or read failure
+    /// the entry is removed and `None` is returned. Errors are only returned
+    /// on I/O failures other than a missing file.
+    pub fn get<E: Element>(&mut self, sum: &Sum, limits: &UserMetaLimits)
+            -> Result<Option<PartState<E>>>
+    {
+        if !self.entries.contains_key(sum) {
+            return Ok(None);
+        }
+        let path = self.path(sum);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                self.forget(sum);
+                return Ok(None);
+            },
+            Err(e) => return Err(Box::new(e)),
+        };
+        let mut r = BufReader::new(file);
+        let result = read_head(&mut r).and_then(|head| {
+            let mut r = decompress(&mut r, head.compression)?;
+            read_snapshot(&mut r, head.ftype.ver(), head.dedup, limits, &mut EltReader::default())
+        });
+        match result {
+            Ok(ref state) if state.statesum() == sum => {},
+            Ok(_) => {
+                warn!("State cache: {} has wrong sum; removing", path.display());
+                self.remove(sum)?;
+                return Ok(None);
+            },
+            Err(e) => {
+                warn!("State cache: failed to read {}: {}; removing", path.display(), e);
+                self.remove(sum)?;
+                return Ok(None);
+            },
+        }
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(sum) {
+            entry.used = self.clock;
+        }
+        result.map(Some)
+    }
+    
+    /// Cache a state (`name` is the partition name, written to the file
+    /// header). Does nothing if already cached. Least-recently-used entries
+    /// are removed to keep within the size limit; if the state alone is
+    /// larger than this, it is not kept.
+    pub fn put<E: Element>(&mut self, name: &str, state: &PartState<E>) -> Result<()> {
+        let sum = state.statesum().clone();
+        if self.entries.contains_key(&sum) {
+            return Ok(());
+        }
+        let path = self.path(&sum);
+        let temp = path.with_extension("tmp");
+        {
+            let mut w = BufWriter::new(File::create(&temp)?);
+            let header = FileHeader {
+                ftype: FileType::Snapshot(0),
+                name: name.to_string(),
+                user: vec![],
+                tag: None,
+                compression: Compression::None,
+                dedup: false,
+                cipher: None,
+                archived: false,
+            };
+            write_head(&header, &mut w)?;
+            write_snapshot(state, &mut w)?;
+            w.flush()?;
+        }
+        fs::rename(&temp, &path)?;
+        let size = fs::metadata(&path)?.len();
+        
+        self.clock += 1;
+        self.total += size;
+        self.entries.insert(sum.clone(), Entry { size, used: self.clock });
+        trace!("State cache: added {} ({} bytes)", sum, size);
+        self.evict(Some(&sum))?;
+        if size > self.max_bytes {
+            self.remove(&sum)?;
+        }
+        Ok(())
+    }
+    
+    /// Remove a cached state. Returns true if it was present.
+    pub fn remove(&mut self, sum: &Sum) -> Result<bool> {
+        if !self.forget(sum) {
+            return Ok(false);
+        }
+        match fs::remove_file(self.path(sum)) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    
+    /// Remove all cached states holding element `id` (e.g. since its history
+    /// was erased). Unreadable entries are also removed. Returns the number
+    /// of states removed because they hold the element.
+    pub fn remove_containing<E: Element>(&mut self, id: EltId, limits: &UserMetaLimits)
+            -> Result<usize>
+    {
+        let sums: Vec<Sum> = self.entries.keys().cloned().collect();
+        let mut n = 0;
+        for sum in sums {
+            if let Some(state) = self.get::<E>(&sum, limits)? {
+                if state.is_avail(id) {
+                    self.remove(&sum)?;
+                    n += 1;
+                }
+            }
+        }
+        Ok(n)
+    }
+    
+    /// Remove all cached states
+    pub fn clear(&mut self) -> Result<()> {
+        let sums: Vec<Sum> = self.entries.keys().cloned().collect();
+        for sum in sums {
+            self.remove(&sum)?;
+        }
+        Ok(())
+    }
+    
+    fn path(&self, sum: &Sum) -> PathBuf {
+        self.dir.join(format!("{}.{}", sum.to_hex(), EXTENSION))
+    }
+    
+    // Remove entry from the index only; returns true if present
+    fn forget(&mut self, sum: &Sum) -> bool {
+        if let Some(entry) = self.entries.remove(sum) {
+            self.total -= entry.size;
+            true
+        } else {
+            false
+        }
+    }
+    
+    // Remove least-recently-used entries (except `keep`) until within limit
+    fn evict(&mut self, keep: Option<&Sum>) -> Result<()> {
+        while self.total > self.max_bytes {
+            let oldest = self.entries.iter()
+                    .filter(|&(sum, _)| Some(sum) != keep)
+                    .min_by_key(|&(_, entry)| entry.used)
+                    .map(|(sum, _)| sum.clone());
+            match oldest {
+                Some(sum) => {
+                    trace!("State cache: evicting {}", sum);
+                    self.remove(&sum)?;
+                },
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    
+    use super::StateCache;
+    use commit::UserMetaLimits;
+    use control::DefaultControl;
+    use io::DummyRepoIO;
+    use state::{PartState, StateWrite};
+    use sum::Sum;
+    
+    #[test]
+    fn state_cache() {
+        let dir = env::temp_dir().join(format!("pippin-state-cache-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let limits = UserMetaLimits::default();
+        
+        let mut states = vec![PartState::<String>::new(&mut control)];
+        for i in 0..3 {
+            let mut state = states[i].clone_mut();
+            state.insert_new(format!("element {}", i)).expect("inserting elt");
+            let state = PartState::from_mut(state, &mut control);
+            states.push(state);
+        }
+        
+        let mut cache = StateCache::open(&dir, 1 << 20).expect("opening cache");
+        for state in &states[1..] {
+            cache.put("cache", state).expect("caching");
+        }
+        assert_eq!(cache.len(), 3);
+        let state = cache.get::<String>(states[2].statesum(), &limits).expect("reading");
+        assert_eq!(state.as_ref(), Some(&states[2]));
+        assert_eq!(cache.get::<String>(&Sum::zero(), &limits).expect("reading"), None);
+        
+        // Entries persist; a corrupt entry is detected and removed:
+        let mut cache = StateCache::open(&dir, 1 << 20).expect("opening cache");
+        assert_eq!(cache.len(), 3);
+        fs::write(cache.path(states[1].statesum()), b"garbage").expect("writing");
+        assert_eq!(cache.get::<String>(states[1].statesum(), &limits).expect("reading"), None);
+        assert_eq!(cache.len(), 2);
+        
+        // Limiting size evicts least-recently-used entries:
+        cache.get::<String>(states[2].statesum(), &limits).expect("reading");
+        let size = cache.total_bytes();
+        cache.max_bytes = size - 1;
+        cache.evict(None).expect("evicting");
+        assert!(cache.contains(states[2].statesum()) && !cache.contains(states[3].statesum()));
+        
+        cache.clear().expect("clearing");
+        assert!(cache.is_empty() && cache.total_bytes() == 0);
+        fs::remove_dir_all(&dir).expect("removing dir");
+    }
+}
@@ -4,7 +4,7 @@
 
 //! Pippin: file discovery
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::read_dir;
 
 use regex::Regex;
@@ -24,7 +24,22 @@ use error::{Result, PathError};
 /// 
 /// #0040: consider supporting blobs or partial file names (i.e. patterns of
 /// some kind). Is there any use-case besides lazy entry in command-line tools?
+/// 
+/// This uses the default `DiscoverFilter`; see `part_from_path_filtered`.
 pub fn part_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
+    part_from_path_filtered(path, &DiscoverFilter::new()).map(|(io, _)| io)
+}
+
+/// Version of `part_from_path` applying a custom filter to the names of files
+/// found.
+/// 
+/// Files whose name is rejected by the filter are ignored. Files which look
+/// like sync-conflict copies (see `DiscoverFilter::is_conflict_copy`) are not
+/// loaded but are logged as warnings and returned, so that the user can be
+/// asked to resolve them (e.g. by importing with `Partition::reconcile`).
+pub fn part_from_path_filtered<P: AsRef<Path>>(path: P, filter: &DiscoverFilter) ->
+        Result<(RepoFileIO, Vec<PathBuf>)>
+{
     let path = path.as_ref();
     let ss_pat = Regex::new("^((?:.*)-)?ss(0|[1-9][0-9]*)\\.pip$").expect("valid regex");
     let cl_pat = Regex::new("^((?:.*)-)?ss(0|[1-9][0-9]*)-cl(0|[1-9][0-9]*)\\.piplog$").expect("valid regex");
@@ -39,7 +54,8 @@ pub fn part_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
         if let Some(bname) = discover_basename(fname) {
             let dir = path.parent().ok_or_else(|| PathError::new("path has no parent", path))?;
             info!("Scanning for partition files matching: {}/{}*", dir.display(), bname);
-            basename = Some(bname);
+            // Patterns below capture the basename with its "-" separator:
+            basename = Some(format!("{}-", bname));
             dir
         } else {
             return PathError::err("discover::part_from_path: not a Pippin file", path);
@@ -49,6 +65,7 @@ pub fn part_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
     };
     
     let mut part_paths = PartPaths::new();
+    let mut conflicts = Vec::new();
     
     {   // new scope for filter_skip closure
     let mut filter_skip = |bname: &str| -> Result<bool> {
@@ -74,6 +91,15 @@ pub fn part_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
             Some(s) if s.ends_with(".pip") || s.ends_with(".piplog") => s,
             _ => { continue; },
         };
+        if !filter.accepts(fname) {
+            trace!("Ignoring filtered file: {}", fpath.display());
+            continue;
+        }
+        if filter.is_conflict_copy(fname) {
+            warn!("Not loading possible sync-conflict copy: {}", fpath.display());
+            conflicts.push(entry.path());
+            continue;
+        }
         
         // —— Match, filter and add ——
         if let Some(caps) = ss_pat.captures(fname) {
@@ -107,7 +133,7 @@ pub fn part_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
             // RepoFileIO does not expect '-' separator in prefix
             bname.pop();
         }
-        Ok(RepoFileIO::for_paths(dir.join(bname), part_paths))
+        Ok((RepoFileIO::for_paths(dir.join(bname), part_paths), conflicts))
     } else {
         Err(Box::new(PathError::new("discover::part_from_path: no Pippin files found in", path)))
     }
@@ -123,3 +149,78 @@ pub fn discover_basename(fname: &str) -> Option<String> {
     pat.captures(fname)
             .map(|caps| caps.at(1).expect("cap").to_string())
 }
+
+
+/// File-name filters used by `part_from_path_filtered`.
+/// 
+/// Patterns are regular expressions matched against file names (not the
+/// full path), and apply only to `.pip` and `.piplog` files (other files are
+/// always ignored). A file is considered if it matches any include pattern
+/// (or there are none) and no exclude pattern.
+/// 
+/// Additionally, files matching a *conflict* pattern are treated as copies
+/// made by file synchronisation tools when two replicas changed the same
+/// file. By default the patterns used by several common tools are detected
+/// (`conflicted copy`, `.sync-conflict-` and `(conflict`, case insensitive).
+#[derive(Clone, Debug)]
+pub struct DiscoverFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    conflict: Vec<Regex>,
+}
+
+impl DiscoverFilter {
+    /// Create a filter accepting all files and with the default conflict
+    /// patterns.
+    pub fn new() -> DiscoverFilter {
+        let conflict = ["(?i)conflicted copy", "(?i)\\.sync-conflict-", "(?i)\\(conflict"]
+            .iter()
+            .map(|pat| Regex::new(pat).expect("valid regex"))
+            .collect();
+        DiscoverFilter { include: vec![], exclude: vec![], conflict }
+    }
+    
+    /// Only consider files whose name matches this pattern (or another include
+    /// pattern). Fails if the pattern is not a valid regular expression.
+    pub fn include(mut self, pattern: &str) -> Result<DiscoverFilter> {
+        self.include.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+    
+    /// Ignore files whose name matches this pattern. Fails if the pattern is
+    /// not a valid regular expression.
+    pub fn exclude(mut self, pattern: &str) -> Result<DiscoverFilter> {
+        self.exclude.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+    
+    /// Add a pattern identifying sync-conflict copies. Fails if the pattern is
+    /// not a valid regular expression.
+    pub fn conflict(mut self, pattern: &str) -> Result<DiscoverFilter> {
+        self.conflict.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+    
+    /// Remove all conflict patterns (including the defaults).
+    pub fn no_conflicts(mut self) -> DiscoverFilter {
+        self.conflict.clear();
+        self
+    }
+    
+    /// True if a file with this name should be considered.
+    pub fn accepts(&self, fname: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|re| re.is_match(fname))) &&
+            !self.exclude.iter().any(|re| re.is_match(fname))
+    }
+    
+    /// True if a file with this name looks like a sync-conflict copy.
+    pub fn is_conflict_copy(&self, fname: &str) -> bool {
+        self.conflict.iter().any(|re| re.is_match(fname))
+    }
+}
+
+impl Default for DiscoverFilter {
+    fn default() -> DiscoverFilter {
+        DiscoverFilter::new()
+    }
+}
@@ -5,20 +5,22 @@
 //! Pippin: data access for repositories.
 
 use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
-use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::fs::{File, OpenOptions, TryLockError, read_dir, remove_file, metadata, rename};
 use std::ops::Add;
+use std::sync::{Arc, Mutex};
 
 use vec_map::{VecMap, Entry};
 
-use io::RepoIO;
+use io::{RepoIO, MergeLock};
 use error::{Result, ReadOnly};
 
 
 // —————  Partition  —————
 
 /// Data structure used in a `RepoFileIO` to actually store file paths.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
 pub struct PartPaths {
     // First key is snapshot number. Value is (if found) a path to the snapshot
     // file and a map of log paths.
@@ -89,14 +91,61 @@ impl PartPaths {
     }
 }
 
+/// Options for file access via `RepoFileIO`, trading durability and safety
+/// for speed.
+/// 
+/// All are enabled by default.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileIoOptions {
+    /// When a writer is flushed, sync file data to disk (and, for new files,
+    /// the directory entry). Partitions flush after writing each file.
+    /// Otherwise files written are synced only by `RepoIO::sync` (see
+    /// `Partition::barrier`).
+    pub fsync: bool,
+    /// Write new snapshots and log indexes to a temporary file (the path
+    /// with `.tmp` appended), renamed into place when the writer is dropped
+    /// after being flushed; if dropped without flushing (or after writing
+    /// since the last flush), the temporary file is removed. A crash thus
+    /// cannot leave an incomplete snapshot.
+    pub atomic_rename: bool,
+    /// Use an advisory lock on a lock file (the prefix with `.lock`
+    /// appended) to coordinate with other processes: readers hold a shared
+    /// lock while a read stream is open and writers an exclusive lock while
+    /// a write stream is open (or while removing files). Where the lock file
+    /// cannot be created (e.g. read-only media), reads proceed unlocked.
+    /// 
+    /// One lock is shared by all streams of a `RepoFileIO` (and its clones),
+    /// thus holding a read stream while writing does not deadlock. Separate
+    /// `RepoFileIO` instances on the same files each have their own lock,
+    /// thus one thread must not hold a stream of one while opening a stream
+    /// of the other, or it will deadlock.
+    /// 
+    /// This also enables the merge lock (see `RepoIO::try_lock_merge`), a
+    /// lock file with `.merge-lock` appended to the prefix.
+    pub lock: bool,
+}
+
+impl Default for FileIoOptions {
+    fn default() -> Self {
+        FileIoOptions { fsync: true, atomic_rename: true, lock: true }
+    }
+}
+
 /// Remembers a set of file names associated with a partition, opens read
 /// and write streams on these and creates new partition files.
 #[derive(Debug, Clone)]
 pub struct RepoFileIO {
     readonly: bool,
+    options: FileIoOptions,
     // Appended with snapshot/log number and extension to get a file path
     prefix: PathBuf,
     paths: PartPaths,
+    // Files written without fsync since the last call to `sync`
+    unsynced: Vec<PathBuf>,
+    // Sizes of commit logs when last scanned, to detect appends (see `refresh`)
+    log_sizes: HashMap<PathBuf, u64>,
+    // Lock on the lock file, shared by all streams (and clones)
+    lock: SharedLock,
 }
 
 impl RepoFileIO {
@@ -118,10 +167,15 @@ impl RepoFileIO {
     {
         let prefix = prefix.into();
         trace!("New RepoFileIO; prefix: {}, ss_len: {}", prefix.display(), paths.ss_len());
+        let log_sizes = log_sizes(&paths);
         RepoFileIO {
             readonly: false,
+            options: FileIoOptions::default(),
             prefix: prefix,
             paths: paths,
+            unsynced: Vec::new(),
+            log_sizes,
+            lock: SharedLock::default(),
         }
     }
     
@@ -132,11 +186,22 @@ impl RepoFileIO {
     }
     
     /// Set readonly. If this is readonly, file creation and modification through this object will
-    /// be inhibited (operations will return a `ReadOnly` error).
+    /// be inhibited (operations will return a `ReadOnly` error). No lock file is created either;
+    /// reads take a shared lock only if the lock file already exists.
     pub fn set_readonly(&mut self, readonly: bool) {
         self.readonly = readonly;
     }
     
+    /// Get the options used when writing files
+    pub fn options(&self) -> FileIoOptions {
+        self.options
+    }
+    
+    /// Set the options used when writing files (see `FileIoOptions`)
+    pub fn set_options(&mut self, options: FileIoOptions) {
+        self.options = options;
+    }
+    
     /// Get a reference to the prefix
     pub fn prefix(&self) -> &Path {
         &self.prefix
@@ -169,7 +234,8 @@ impl RepoIO for RepoFileIO {
             Some(&(ref p, _)) => {
                 if let Some(ref path) = *p {
                     trace!("Reading snapshot file: {}", path.display());
-                    Some(Box::new(File::open(path)?))
+                    let lock = self.lock_shared()?;
+                    Some(Box::new(LockedReader { file: File::open(path)?, _lock: lock }))
                 } else {
                     None
                 }
@@ -178,16 +244,52 @@ impl RepoIO for RepoFileIO {
         })
     }
     
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        match self.paths.paths.get(ss_num) {
+            Some(&(Some(ref path), _)) => {
+                let _lock = self.lock_shared()?;
+                Ok(Some(metadata(path)?.len()))
+            },
+            _ => Ok(None),
+        }
+    }
+    
+    fn read_ss_range(&self, ss_num: usize, pos: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        match self.paths.paths.get(ss_num) {
+            Some(&(Some(ref path), _)) => {
+                trace!("Reading {} bytes at {} from snapshot file: {}", len, pos, path.display());
+                let _lock = self.lock_shared()?;
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(pos))?;
+                let mut buf = vec![0; len];
+                file.read_exact(&mut buf)?;
+                Ok(Some(buf))
+            },
+            _ => Ok(None),
+        }
+    }
+    
     fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
         Ok(match self.paths.paths.get(ss_num).and_then(|&(_, ref logs)| logs.get(cl_num)) {
             Some(p) => {
                 trace!("Reading log file: {}", p.display());
-                Some(Box::new(File::open(p)?))
+                let lock = self.lock_shared()?;
+                Some(Box::new(LockedReader { file: File::open(p)?, _lock: lock }))
             },
             None => None,
         })
     }
     
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        match self.paths.paths.get(ss_num).and_then(|&(_, ref logs)| logs.get(cl_num)) {
+            Some(path) => {
+                let _lock = self.lock_shared()?;
+                Ok(Some(metadata(path)?.len()))
+            },
+            None => Ok(None),
+        }
+    }
+    
     fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
         if self.readonly {
             return ReadOnly::err();
@@ -195,12 +297,16 @@ impl RepoIO for RepoFileIO {
         let mut p = self.prefix.as_os_str().to_os_string();
         p.push(format!("-ss{}.pip", ss_num));
         let p = PathBuf::from(p);
+        let lock = self.lock_exclusive()?;
         if self.paths.paths.get(ss_num).map_or(false, |&(ref p, _)| p.is_some()) || p.exists() {
             // File already exists in internal map or on filesystem
             return Ok(None);
         }
         trace!("Creating snapshot file: {}", p.display());
-        let stream = File::create(&p)?;
+        let stream = FileWriter::create(&p, self.options)?.with_lock(lock);
+        if !self.options.fsync {
+            self.unsynced.push(p.clone());
+        }
         match self.paths.paths.entry(ss_num) {
             Entry::Occupied(mut entry) => { entry.get_mut().0 = Some(p); },
             Entry::Vacant(entry) => { entry.insert((Some(p), VecMap::new())); },
@@ -215,7 +321,12 @@ impl RepoIO for RepoFileIO {
         Ok(match self.paths.paths.get(ss_num).and_then(|&(_, ref logs)| logs.get(cl_num)) {
             Some(p) => {
                 trace!("Appending to log file: {}", p.display());
-                Some(Box::new(OpenOptions::new().write(true).append(true).open(p)?))
+                let lock = self.lock_exclusive()?;
+                let file = OpenOptions::new().write(true).append(true).open(p)?;
+                if !self.options.fsync && !self.unsynced.contains(p) {
+                    self.unsynced.push(p.clone());
+                }
+                Some(Box::new(FileWriter::new(file, self.options.fsync, None, None).with_lock(lock)))
             },
             None => None
         })
@@ -224,17 +335,673 @@ impl RepoIO for RepoFileIO {
         if self.readonly {
             return ReadOnly::err();
         }
+        let lock = self.lock_exclusive()?;
         let mut logs = &mut self.paths.paths.entry(ss_num).or_insert_with(|| (None, VecMap::new())).1;
         let mut p = self.prefix.as_os_str().to_os_string();
         p.push(format!("-ss{}-cl{}.piplog", ss_num, cl_num));
         let p = PathBuf::from(p);
-        if logs.contains_key(cl_num) || p.exists() {
-            // File already exists in internal map or on filesystem
+        if logs.contains_key(cl_num) {
+            // File already exists in internal map
             return Ok(None);
         }
         trace!("Creating log file: {}", p.display());
-        let stream = OpenOptions::new().create(true).write(true).append(true).open(&p)?;
+        // Fail if the file exists, even if created since checking:
+        let file = match OpenOptions::new().create_new(true).write(true).append(true).open(&p) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let new_entry = if self.options.fsync { Some(p.clone()) } else { None };
+        if !self.options.fsync {
+            self.unsynced.push(p.clone());
+        }
         logs.insert(cl_num, p);
-        Ok(Some(Box::new(stream)))
+        Ok(Some(Box::new(FileWriter::new(file, self.options.fsync, None, new_entry).with_lock(lock))))
+    }
+    
+    fn append_ss_cl_at(&mut self, ss_num: usize, cl_num: usize, len: u64, data: &[u8]) ->
+            Result<bool>
+    {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let p = match self.paths.get_cl(ss_num, cl_num) {
+            Some(p) => p.to_path_buf(),
+            None => return Ok(false),
+        };
+        let _lock = self.lock_exclusive()?;
+        let mut file = OpenOptions::new().write(true).append(true).open(&p)?;
+        if file.metadata()?.len() != len {
+            return Ok(false);
+        }
+        trace!("Appending {} bytes to log file: {}", data.len(), p.display());
+        let fsync = self.options.fsync;
+        let result = file.write_all(data).and_then(|_| if fsync { file.sync_data() } else { Ok(()) });
+        if let Err(e) = result {
+            // Do not leave a partial commit at the end of the log
+            if let Err(e) = file.set_len(len) {
+                warn!("Failed to truncate {}: {}", p.display(), e);
+            }
+            return Err(Box::new(e));
+        }
+        if !fsync && !self.unsynced.contains(&p) {
+            self.unsynced.push(p);
+        }
+        Ok(true)
+    }
+    
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let _lock = self.lock_exclusive()?;
+        if let Some(&mut (ref mut p, _)) = self.paths.paths.get_mut(ss_num) {
+            if let Some(ref path) = *p {
+                trace!("Removing snapshot file: {}", path.display());
+                remove_file(path)?;
+            } else {
+                return Ok(false);
+            }
+            *p = None;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let _lock = self.lock_exclusive()?;
+        if let Some(&mut (_, ref mut logs)) = self.paths.paths.get_mut(ss_num) {
+            if let Some(p) = logs.get(cl_num) {
+                trace!("Removing log file: {}", p.display());
+                remove_file(p)?;
+                let index = index_path(p);
+                if index.exists() {
+                    trace!("Removing log index: {}", index.display());
+                    remove_file(index)?;
+                }
+            } else {
+                return Ok(false);
+            }
+            logs.remove(cl_num);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+    
+    fn replace_ss(&mut self, ss_num: usize, data: &[u8]) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let _lock = self.lock_exclusive()?;
+        match self.paths.get_ss(ss_num) {
+            Some(path) => {
+                trace!("Replacing snapshot file: {}", path.display());
+                replace_file(path, data, self.options.fsync)?;
+            },
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+    fn replace_ss_cl(&mut self, ss_num: usize, cl_num: usize, data: &[u8]) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let _lock = self.lock_exclusive()?;
+        match self.paths.get_cl(ss_num, cl_num) {
+            Some(path) => {
+                trace!("Replacing log file: {}", path.display());
+                replace_file(path, data, self.options.fsync)?;
+                let index = index_path(path);
+                if index.exists() {
+                    trace!("Removing log index: {}", index.display());
+                    remove_file(index)?;
+                }
+            },
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+    
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        if let Some(p) = self.paths.get_cl(ss_num, cl_num) {
+            let index = index_path(p);
+            let lock = self.lock_shared()?;
+            if index.exists() {
+                trace!("Reading log index: {}", index.display());
+                return Ok(Some(Box::new(LockedReader { file: File::open(index)?, _lock: lock })));
+            }
+        }
+        Ok(None)
+    }
+    
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        if let Some(p) = self.paths.get_cl(ss_num, cl_num) {
+            let index = index_path(p);
+            trace!("Writing log index: {}", index.display());
+            let lock = self.lock_exclusive()?;
+            return Ok(Some(Box::new(FileWriter::create(&index, self.options)?.with_lock(lock))));
+        }
+        Ok(None)
+    }
+    
+    fn check_status(&self) -> Result<()> {
+        read_dir(self.dir())?;
+        Ok(())
+    }
+    
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced.is_empty() {
+            return Ok(());
+        }
+        for p in &self.unsynced {
+            // The file may since have been removed
+            if p.exists() {
+                trace!("Syncing file: {}", p.display());
+                OpenOptions::new().write(true).open(p)?.sync_all()?;
+            }
+        }
+        sync_dir(&self.prefix)?;
+        self.unsynced.clear();
+        Ok(())
+    }
+    
+    fn refresh(&mut self) -> Result<bool> {
+        let mut paths = self.scan()?;
+        // Snapshot numbers are retained so that `ss_len()` does not decrease:
+        for ss in self.paths.paths.keys() {
+            paths.paths.entry(ss).or_insert_with(|| (None, VecMap::new()));
+        }
+        // Commits may also have been appended to existing logs:
+        let sizes = log_sizes(&paths);
+        if paths == self.paths && sizes == self.log_sizes {
+            return Ok(false);
+        }
+        debug!("Found changes to partition files with prefix {}", self.prefix.display());
+        self.paths = paths;
+        self.log_sizes = sizes;
+        Ok(true)
+    }
+    
+    fn read_audit<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        let p = self.audit_path();
+        let lock = self.lock_shared()?;
+        if !p.exists() {
+            return Ok(None);
+        }
+        trace!("Reading audit log: {}", p.display());
+        Ok(Some(Box::new(LockedReader { file: File::open(p)?, _lock: lock })))
+    }
+    
+    fn append_audit<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let p = self.audit_path();
+        trace!("Appending to audit log: {}", p.display());
+        let lock = self.lock_exclusive()?;
+        let new_entry = if self.options.fsync && !p.exists() { Some(p.clone()) } else { None };
+        let file = OpenOptions::new().create(true).append(true).open(&p)?;
+        Ok(Some(Box::new(FileWriter::new(file, self.options.fsync, None, new_entry).with_lock(lock))))
+    }
+    fn read_sum_filter<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        let p = self.sum_filter_path();
+        let lock = self.lock_shared()?;
+        if !p.exists() {
+            return Ok(None);
+        }
+        trace!("Reading sum filter: {}", p.display());
+        Ok(Some(Box::new(LockedReader { file: File::open(p)?, _lock: lock })))
+    }
+    
+    fn write_sum_filter<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let p = self.sum_filter_path();
+        trace!("Writing sum filter: {}", p.display());
+        let lock = self.lock_exclusive()?;
+        Ok(Some(Box::new(FileWriter::create(&p, self.options)?.with_lock(lock))))
+    }
+    
+    fn try_lock_merge(&self) -> Result<Option<MergeLock>> {
+        if !self.options.lock || self.readonly {
+            return Ok(Some(MergeLock::unlocked()));
+        }
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push(".merge-lock");
+        let file = OpenOptions::new().create(true).truncate(false).write(true)
+                .open(PathBuf::from(p))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(MergeLock::new(Box::new(file)))),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(Box::new(e)),
+        }
+    }
+}
+
+// Sizes of all commit logs in `paths` (omitting those which cannot be read)
+fn log_sizes(paths: &PartPaths) -> HashMap<PathBuf, u64> {
+    paths.paths.values()
+            .flat_map(|&(_, ref logs)| logs.values())
+            .filter_map(|p| metadata(p).ok().map(|m| (p.clone(), m.len())))
+            .collect()
+}
+
+impl RepoFileIO {
+    // Path of the audit log: the prefix with `-audit.txt` appended
+    fn audit_path(&self) -> PathBuf {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push("-audit.txt");
+        PathBuf::from(p)
+    }
+    
+    // Path of the sum filter: the prefix with `-sums.filter` appended
+    fn sum_filter_path(&self) -> PathBuf {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push("-sums.filter");
+        PathBuf::from(p)
+    }
+    
+    // Open the lock file
+    fn lock_file(&self) -> io::Result<File> {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push(".lock");
+        if self.readonly {
+            // Never create files; locking works on a file opened for reading
+            return OpenOptions::new().read(true).open(PathBuf::from(p));
+        }
+        OpenOptions::new().create(true).truncate(false).write(true).open(PathBuf::from(p))
+    }
+    
+    // Acquire a shared lock, if enabled and possible; released on drop
+    fn lock_shared(&self) -> Result<Option<LockGuard>> {
+        if !self.options.lock {
+            return Ok(None);
+        }
+        match self.lock.acquire(false, || self.lock_file()) {
+            Ok(guard) => Ok(Some(guard)),
+            Err(LockError::Open(e)) => {
+                trace!("Reading without lock: {}", e);
+                Ok(None)
+            },
+            Err(LockError::Lock(e)) => Err(Box::new(e)),
+        }
+    }
+    
+    // Acquire an exclusive lock, if enabled; released on drop
+    fn lock_exclusive(&self) -> Result<Option<LockGuard>> {
+        if !self.options.lock {
+            return Ok(None);
+        }
+        match self.lock.acquire(true, || self.lock_file()) {
+            Ok(guard) => Ok(Some(guard)),
+            Err(LockError::Open(e)) | Err(LockError::Lock(e)) => Err(Box::new(e)),
+        }
+    }
+    
+    // Find all files with our prefix
+    // Directory containing partition files
+    fn dir(&self) -> PathBuf {
+        match self.prefix.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+    
+    fn scan(&self) -> Result<PartPaths> {
+        let dir = self.dir();
+        let base = match self.prefix.file_name().and_then(|name| name.to_str()) {
+            Some(name) => format!("{}-ss", name),
+            None => return Ok(PartPaths::new()),
+        };
+        let mut paths = PartPaths::new();
+        for entry in read_dir(dir)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if !name.starts_with(&base) {
+                continue;
+            }
+            let rest = &name[base.len()..];
+            if let Some(ss) = rest.strip_suffix(".pip").and_then(parse_num) {
+                paths.insert_ss(ss, entry.path());
+            } else if let Some(rest) = rest.strip_suffix(".piplog") {
+                let mut parts = rest.splitn(2, "-cl");
+                if let (Some(ss), Some(cl)) = (parts.next().and_then(parse_num),
+                        parts.next().and_then(parse_num)) {
+                    paths.insert_cl(ss, cl, entry.path());
+                }
+            }
+        }
+        Ok(paths)
+    }
+}
+
+// Parse a file number (without leading zeros)
+fn parse_num(s: &str) -> Option<usize> {
+    if s.is_empty() || (s.len() > 1 && s.starts_with('0')) || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+// State of a `SharedLock`: the open lock file while any lock is held, and
+// the number of guards of each kind
+#[derive(Debug, Default)]
+struct LockState {
+    file: Option<File>,
+    shared: usize,
+    exclusive: usize,
+}
+
+// Advisory lock on a lock file, shared by the streams of a `RepoFileIO`.
+// Since `flock` locks belong to an open file, all holders use one handle:
+// the lock is exclusive while any exclusive guard exists, otherwise shared
+// while any shared guard exists, and released (the file closed) after that.
+#[derive(Debug, Clone, Default)]
+struct SharedLock {
+    state: Arc<Mutex<LockState>>,
+}
+
+enum LockError {
+    // Failed to open the lock file
+    Open(io::Error),
+    // Failed to acquire the lock
+    Lock(io::Error),
+}
+
+impl SharedLock {
+    fn state(&self) -> ::std::sync::MutexGuard<'_, LockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    // Acquire a shared or exclusive hold, opening the lock file with `open`
+    // if not already open
+    fn acquire<F>(&self, exclusive: bool, open: F) -> ::std::result::Result<LockGuard, LockError>
+        where F: FnOnce() -> io::Result<File>
+    {
+        let mut state = self.state();
+        if state.file.is_none() {
+            state.file = Some(open().map_err(LockError::Open)?);
+        }
+        {
+            let file = state.file.as_ref().unwrap();
+            let result = if exclusive && state.exclusive == 0 {
+                file.lock()
+            } else if !exclusive && state.exclusive == 0 && state.shared == 0 {
+                file.lock_shared()
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                if state.exclusive == 0 && state.shared == 0 {
+                    state.file = None;
+                }
+                return Err(LockError::Lock(e));
+            }
+        }
+        if exclusive {
+            state.exclusive += 1;
+        } else {
+            state.shared += 1;
+        }
+        Ok(LockGuard { lock: self.clone(), exclusive })
+    }
+}
+
+// A hold on a `SharedLock`, released on drop
+struct LockGuard {
+    lock: SharedLock,
+    exclusive: bool,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        let mut state = self.lock.state();
+        if self.exclusive {
+            state.exclusive -= 1;
+        } else {
+            state.shared -= 1;
+        }
+        if state.exclusive == 0 && state.shared == 0 {
+            // Closing the file releases the lock
+            state.file = None;
+        } else if self.exclusive && state.exclusive == 0 {
+            if let Err(e) = state.file.as_ref().unwrap().lock_shared() {
+                warn!("Failed to downgrade lock: {}", e);
+            }
+        }
+    }
+}
+
+// Reader holding a lock (if any) until dropped
+struct LockedReader {
+    file: File,
+    _lock: Option<LockGuard>,
+}
+
+impl Read for LockedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+// Path of the index of the commit log at `log` (the log path with `.idx` appended)
+fn index_path(log: &Path) -> PathBuf {
+    let mut p = log.as_os_str().to_os_string();
+    p.push(".idx");
+    PathBuf::from(p)
+}
+
+// Write `data` to a temporary file (`path` with `.tmp` appended) and rename
+// it over `path`, so that `path` holds either the old or the new contents
+fn replace_file(path: &Path, data: &[u8], fsync: bool) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let result = File::create(&temp).and_then(|mut file| {
+        file.write_all(data)?;
+        if fsync {
+            file.sync_data()?;
+        }
+        rename(&temp, path)
+    });
+    if let Err(e) = result {
+        if let Err(e) = remove_file(&temp) {
+            warn!("Failed to remove {}: {}", temp.display(), e);
+        }
+        return Err(e);
+    }
+    if fsync {
+        sync_dir(path)?;
+    }
+    Ok(())
+}
+
+// Writer on a new or existing file, applying `FileIoOptions` when flushed
+struct FileWriter {
+    file: File,
+    fsync: bool,
+    // Temporary path and final path, if the file is to be renamed
+    rename: Option<(PathBuf, PathBuf)>,
+    // True if flushed without writing since (the temporary file is then
+    // renamed into place on drop)
+    complete: bool,
+    // Path of a new file whose directory entry should be synced
+    new_entry: Option<PathBuf>,
+    // Lock held until dropped
+    lock: Option<LockGuard>,
+}
+
+impl FileWriter {
+    fn new(file: File, fsync: bool, rename: Option<(PathBuf, PathBuf)>,
+            new_entry: Option<PathBuf>) -> FileWriter
+    {
+        FileWriter { file, fsync, rename, complete: false, new_entry, lock: None }
+    }
+    
+    // Hold `lock` until dropped
+    fn with_lock(mut self, lock: Option<LockGuard>) -> FileWriter {
+        self.lock = lock;
+        self
+    }
+    
+    // Create (or truncate) the file at `path`, via a temporary file if required
+    fn create(path: &Path, options: FileIoOptions) -> Result<FileWriter> {
+        let new_entry = if options.fsync { Some(path.to_path_buf()) } else { None };
+        Ok(if options.atomic_rename {
+            let mut temp = path.as_os_str().to_os_string();
+            temp.push(".tmp");
+            let temp = PathBuf::from(temp);
+            let file = File::create(&temp)?;
+            FileWriter::new(file, options.fsync, Some((temp, path.to_path_buf())), new_entry)
+        } else {
+            FileWriter::new(File::create(path)?, options.fsync, None, new_entry)
+        })
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.complete = false;
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.complete = false;
+        self.file.flush()?;
+        if self.fsync {
+            self.file.sync_data()?;
+        }
+        // The entry of a renamed file is synced after renaming (on drop)
+        if self.rename.is_none() {
+            if let Some(path) = self.new_entry.take() {
+                sync_dir(&path)?;
+            }
+        }
+        self.complete = true;
+        Ok(())
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        if let Some((temp, path)) = self.rename.take() {
+            if self.complete {
+                trace!("Renaming {} to {}", temp.display(), path.display());
+                match rename(&temp, &path) {
+                    Ok(()) => {
+                        if let Some(path) = self.new_entry.take() {
+                            if let Err(e) = sync_dir(&path) {
+                                warn!("Failed to sync directory of {}: {}", path.display(), e);
+                            }
+                        }
+                        return;
+                    },
+                    Err(e) => warn!("Failed to rename {}: {}", temp.display(), e),
+                }
+            } else {
+                warn!("Abandoned incomplete write of {}", path.display());
+            }
+            if let Err(e) = remove_file(&temp) {
+                warn!("Failed to remove {}: {}", temp.display(), e);
+            }
+        }
+    }
+}
+
+// Sync the directory containing `path`, making a new entry durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    
+    #[test]
+    fn rename_on_drop() {
+        let dir = ::std::env::temp_dir().join(format!("pippin-file-writer-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut io = RepoFileIO::new(dir.join("part"));
+        let path = dir.join("part-ss0.pip");
+        let temp = dir.join("part-ss0.pip.tmp");
+        let flushed;
+        {
+            let mut w = io.new_ss(0).unwrap().unwrap();
+            w.write_all(b"first half").unwrap();
+            w.flush().unwrap();
+            flushed = (path.exists(), temp.exists());
+            w.write_all(b", second half").unwrap();
+            w.flush().unwrap();
+        }
+        let renamed = (path.exists(), temp.exists());
+        let data = fs::read(&path).unwrap();
+        
+        // Writing after the last flush abandons the file:
+        {
+            let mut w = io.new_ss(1).unwrap().unwrap();
+            w.write_all(b"complete").unwrap();
+            w.flush().unwrap();
+            w.write_all(b" or not").unwrap();
+        }
+        let abandoned = dir.join("part-ss1.pip").exists() || dir.join("part-ss1.pip.tmp").exists();
+        fs::remove_dir_all(&dir).unwrap();
+        
+        assert_eq!(flushed, (false, true));
+        assert_eq!(renamed, (true, false));
+        assert_eq!(data, b"first half, second half");
+        assert!(!abandoned);
+    }
+    
+    #[test]
+    fn shared_lock() {
+        let dir = ::std::env::temp_dir().join(format!("pippin-shared-lock-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut io = RepoFileIO::new(dir.join("part"));
+        {
+            let mut w = io.new_ss(0).unwrap().unwrap();
+            w.write_all(b"snapshot").unwrap();
+            w.flush().unwrap();
+        }
+        
+        // Writing while a clone holds a read stream does not deadlock:
+        let reader = io.clone();
+        let mut r = reader.read_ss(0).unwrap().unwrap();
+        {
+            let mut w = io.new_ss(1).unwrap().unwrap();
+            w.write_all(b"another").unwrap();
+            w.flush().unwrap();
+        }
+        let mut data = Vec::new();
+        r.read_to_end(&mut data).unwrap();
+        assert!(reader.read_sum_filter().unwrap().is_none());
+        drop(r);
+        
+        // All holds are released:
+        let other = File::open(dir.join("part.lock")).unwrap();
+        let released = other.try_lock().is_ok();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(data, b"snapshot");
+        assert!(released);
     }
 }
@@ -0,0 +1,55 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: import of existing data from plain files
+
+use std::path::Path;
+
+use walkdir::WalkDir;
+
+use control::Control;
+use error::Result;
+use part::Partition;
+use state::StateWrite;
+
+
+/// Create elements from files found by walking a directory, as a first-run
+/// migration of an existing dataset into a partition.
+/// 
+/// All regular files under `path` (recursively, in sorted order) are passed
+/// to `mapper`, which may read the file and return an element, or `None` to
+/// skip the file. All elements are added to the partition's tip in a single
+/// commit, which is written to a commit log, then a snapshot is written (thus
+/// loading need not replay the commit). The dataset is therefore stored
+/// twice; the commit log may be removed later with `Partition::gc`.
+/// 
+/// The partition must be ready (have a single tip; see `Partition::tip()`).
+/// Nothing is committed if walking the directory or the mapper fails.
+/// 
+/// Returns the number of elements inserted.
+pub fn ingest<C, P, F>(part: &mut Partition<C>, path: P, mut mapper: F) -> Result<usize>
+    where C: Control, P: AsRef<Path>,
+        F: FnMut(&Path) -> Result<Option<C::Element>>
+{
+    let mut state = part.tip()?.clone_mut();
+    let mut n = 0;
+    for entry in WalkDir::new(path).sort_by(|a, b| a.cmp(b)) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Some(elt) = mapper(entry.path())? {
+            state.insert_new(elt)?;
+            n += 1;
+        }
+    }
+    info!("Ingested {} elements into partition {}", n, part.name());
+    
+    if n > 0 {
+        part.push_state(state)?;
+        part.write_fast()?;
+        part.write_snapshot()?;
+    }
+    Ok(n)
+}
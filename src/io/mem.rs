@@ -0,0 +1,333 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! In-memory implementation of `RepoIO`
+//! 
+//! `MemRepoIO` keeps all files in memory, thus may be used without a file
+//! system (e.g. on wasm32 targets) and for deterministic tests. Instances can
+//! be cloned and serialised (`write_to` / `read_from`) for use as fixtures.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use error::{Result, ReadError};
+use io::RepoIO;
+
+// Identifier at the start of serialised data
+const MAGIC: [u8; 16] = *b"PIPPIN MEMREPO\x00\x01";
+const KIND_SS: u8 = 0;
+const KIND_CL: u8 = 1;
+const KIND_INDEX: u8 = 2;
+const KIND_AUDIT: u8 = 3;
+const KIND_SUM_FILTER: u8 = 4;
+
+/// Stores snapshots, commit logs and log indexes in memory buffers, keyed by
+/// snapshot number and (snapshot, log) number pairs, and the audit log.
+/// 
+/// All `RepoIO` operations are supported, including removal of files and
+/// storage of log indexes, the audit log and the sum filter.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct MemRepoIO {
+    // Never decreases (see `RepoIO::ss_len`)
+    ss_len: usize,
+    ss: BTreeMap<usize, Vec<u8>>,
+    cl: BTreeMap<(usize, usize), Vec<u8>>,
+    index: BTreeMap<(usize, usize), Vec<u8>>,
+    audit: Vec<u8>,
+    sum_filter: Option<Vec<u8>>,
+}
+
+impl MemRepoIO {
+    /// Create a new, empty instance
+    pub fn new() -> MemRepoIO {
+        Default::default()
+    }
+    
+    /// Get the contents of a snapshot, if present
+    pub fn ss_data(&self, ss_num: usize) -> Option<&[u8]> {
+        self.ss.get(&ss_num).map(|v| &v[..])
+    }
+    /// Get the contents of a commit log, if present
+    pub fn ss_cl_data(&self, ss_num: usize, cl_num: usize) -> Option<&[u8]> {
+        self.cl.get(&(ss_num, cl_num)).map(|v| &v[..])
+    }
+    /// Get mutable access to the contents of a snapshot (e.g. to simulate
+    /// corruption in tests)
+    pub fn ss_data_mut(&mut self, ss_num: usize) -> Option<&mut Vec<u8>> {
+        self.ss.get_mut(&ss_num)
+    }
+    /// Get mutable access to the contents of a commit log
+    pub fn ss_cl_data_mut(&mut self, ss_num: usize, cl_num: usize) -> Option<&mut Vec<u8>> {
+        self.cl.get_mut(&(ss_num, cl_num))
+    }
+    
+    /// Insert (or replace) a snapshot
+    pub fn insert_ss(&mut self, ss_num: usize, data: Vec<u8>) {
+        if ss_num >= self.ss_len {
+            self.ss_len = ss_num + 1;
+        }
+        self.ss.insert(ss_num, data);
+    }
+    /// Insert (or replace) a commit log
+    pub fn insert_ss_cl(&mut self, ss_num: usize, cl_num: usize, data: Vec<u8>) {
+        if ss_num >= self.ss_len {
+            self.ss_len = ss_num + 1;
+        }
+        self.cl.insert((ss_num, cl_num), data);
+    }
+    
+    /// Get the contents of the audit log
+    pub fn audit_data(&self) -> &[u8] {
+        &self.audit
+    }
+    
+    /// Total number of bytes stored (snapshots, logs, indexes, the audit
+    /// log and the sum filter)
+    pub fn total_bytes(&self) -> usize {
+        self.ss.values().chain(self.cl.values()).chain(self.index.values())
+                .chain(self.sum_filter.iter())
+                .map(|v| v.len()).sum::<usize>() + self.audit.len()
+    }
+    
+    /// Serialise all contents to a stream
+    pub fn write_to(&self, w: &mut Write) -> Result<()> {
+        w.write_all(&MAGIC)?;
+        w.write_u64::<BigEndian>(self.ss_len as u64)?;
+        let audit = if self.audit.is_empty() { None } else { Some(&self.audit) };
+        let num = self.ss.len() + self.cl.len() + self.index.len() + audit.iter().count() +
+                self.sum_filter.iter().count();
+        w.write_u64::<BigEndian>(num as u64)?;
+        let entries = self.ss.iter().map(|(ss, data)| (KIND_SS, *ss, 0, data))
+            .chain(self.cl.iter().map(|(&(ss, cl), data)| (KIND_CL, ss, cl, data)))
+            .chain(self.index.iter().map(|(&(ss, cl), data)| (KIND_INDEX, ss, cl, data)))
+            .chain(audit.map(|data| (KIND_AUDIT, 0, 0, data)))
+            .chain(self.sum_filter.iter().map(|data| (KIND_SUM_FILTER, 0, 0, data)));
+        for (kind, ss, cl, data) in entries {
+            w.write_u8(kind)?;
+            w.write_u64::<BigEndian>(ss as u64)?;
+            w.write_u64::<BigEndian>(cl as u64)?;
+            w.write_u64::<BigEndian>(data.len() as u64)?;
+            w.write_all(data)?;
+        }
+        Ok(())
+    }
+    
+    /// Deserialise from a stream (as written by `write_to`)
+    pub fn read_from(r: &mut Read) -> Result<MemRepoIO> {
+        let mut magic = [0u8; 16];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return ReadError::err("not a serialised MemRepoIO", 0, (0, 16));
+        }
+        let mut io = MemRepoIO::new();
+        io.ss_len = r.read_u64::<BigEndian>()? as usize;
+        let num = r.read_u64::<BigEndian>()?;
+        let mut pos = 32;
+        for _ in 0..num {
+            let kind = r.read_u8()?;
+            let ss = r.read_u64::<BigEndian>()? as usize;
+            let cl = r.read_u64::<BigEndian>()? as usize;
+            let len = r.read_u64::<BigEndian>()? as usize;
+            let mut data = Vec::new();
+            Read::take(&mut *r, len as u64).read_to_end(&mut data)?;
+            if data.len() != len {
+                return ReadError::err("unexpected end of data", pos, (25, 25));
+            }
+            match kind {
+                KIND_SS => { io.ss.insert(ss, data); },
+                KIND_CL => { io.cl.insert((ss, cl), data); },
+                KIND_INDEX => { io.index.insert((ss, cl), data); },
+                KIND_AUDIT => { io.audit = data; },
+                KIND_SUM_FILTER => { io.sum_filter = Some(data); },
+                _ => return ReadError::err("unknown entry kind", pos, (0, 1)),
+            }
+            pos += 25 + len;
+        }
+        Ok(io)
+    }
+}
+
+impl RepoIO for MemRepoIO {
+    fn ss_len(&self) -> usize {
+        self.ss_len
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.cl.range((ss_num, 0)..(ss_num + 1, 0)).next_back()
+                .map_or(0, |(&(_, cl), _)| cl + 1)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.ss.contains_key(&ss_num)
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        match self.ss.get(&ss_num) {
+            Some(data) => Ok(Some(Box::new(&data[..]))),
+            None => Ok(None),
+        }
+    }
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        Ok(self.ss.get(&ss_num).map(|data| data.len() as u64))
+    }
+    fn read_ss_range(&self, ss_num: usize, pos: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        match self.ss.get(&ss_num) {
+            Some(data) => {
+                let pos = pos as usize;
+                if pos.checked_add(len).is_none_or(|end| end > data.len()) {
+                    return ReadError::err("range outside of snapshot", pos, (0, len));
+                }
+                Ok(Some(data[pos..pos + len].to_vec()))
+            },
+            None => Ok(None),
+        }
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        match self.cl.get(&(ss_num, cl_num)) {
+            Some(data) => Ok(Some(Box::new(&data[..]))),
+            None => Ok(None),
+        }
+    }
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        Ok(self.cl.get(&(ss_num, cl_num)).map(|data| data.len() as u64))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        if self.ss.contains_key(&ss_num) {
+            return Ok(None);
+        }
+        if ss_num >= self.ss_len {
+            self.ss_len = ss_num + 1;
+        }
+        Ok(Some(Box::new(self.ss.entry(ss_num).or_default())))
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        match self.cl.get_mut(&(ss_num, cl_num)) {
+            Some(data) => Ok(Some(Box::new(data))),
+            None => Ok(None),
+        }
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        if self.cl.contains_key(&(ss_num, cl_num)) {
+            return Ok(None);
+        }
+        if ss_num >= self.ss_len {
+            self.ss_len = ss_num + 1;
+        }
+        Ok(Some(Box::new(self.cl.entry((ss_num, cl_num)).or_default())))
+    }
+    fn append_ss_cl_at(&mut self, ss_num: usize, cl_num: usize, len: u64, data: &[u8]) ->
+            Result<bool>
+    {
+        match self.cl.get_mut(&(ss_num, cl_num)) {
+            Some(log) if log.len() as u64 == len => {
+                log.extend_from_slice(data);
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        Ok(self.ss.remove(&ss_num).is_some())
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        self.index.remove(&(ss_num, cl_num));
+        Ok(self.cl.remove(&(ss_num, cl_num)).is_some())
+    }
+    fn replace_ss(&mut self, ss_num: usize, data: &[u8]) -> Result<bool> {
+        Ok(match self.ss.get_mut(&ss_num) {
+            Some(v) => { *v = data.to_vec(); true },
+            None => false,
+        })
+    }
+    fn replace_ss_cl(&mut self, ss_num: usize, cl_num: usize, data: &[u8]) -> Result<bool> {
+        Ok(match self.cl.get_mut(&(ss_num, cl_num)) {
+            Some(v) => {
+                *v = data.to_vec();
+                self.index.remove(&(ss_num, cl_num));
+                true
+            },
+            None => false,
+        })
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        match self.index.get(&(ss_num, cl_num)) {
+            Some(data) => Ok(Some(Box::new(&data[..]))),
+            None => Ok(None),
+        }
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        if !self.cl.contains_key(&(ss_num, cl_num)) {
+            return Ok(None);
+        }
+        let data = self.index.entry((ss_num, cl_num)).or_default();
+        data.clear();
+        Ok(Some(Box::new(data)))
+    }
+    fn read_audit<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        if self.audit.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(&self.audit[..])))
+    }
+    fn append_audit<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        Ok(Some(Box::new(&mut self.audit)))
+    }
+    fn read_sum_filter<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        Ok(match self.sum_filter {
+            Some(ref data) => Some(Box::new(&data[..])),
+            None => None,
+        })
+    }
+    fn write_sum_filter<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        let data = self.sum_filter.get_or_insert_with(Vec::new);
+        data.clear();
+        Ok(Some(Box::new(data)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::MemRepoIO;
+    use io::RepoIO;
+    use control::DefaultControl;
+    use part::Partition;
+    use state::StateWrite;
+    
+    #[test]
+    fn mem_repo_io() {
+        type Control = DefaultControl<String, MemRepoIO>;
+        let mut part = Partition::create(Control::new(MemRepoIO::new()), "mem")
+                .expect("creating partition");
+        let mut state = part.tip().expect("has tip").clone_mut();
+        let id = state.insert_new("one".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        part.write_snapshot().expect("writing snapshot");
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.replace(id, "two".to_string()).expect("replacing elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        let tip = part.tip_key().expect("has tip").clone();
+        
+        let io = part.unwrap_control().io().clone();
+        assert_eq!((io.ss_len(), io.ss_cl_len(0), io.ss_cl_len(1)), (2, 1, 1));
+        assert!(io.ss_data(1).is_some() && io.ss_cl_data(1, 0).is_some());
+        
+        let mut buf = Vec::new();
+        io.write_to(&mut buf).expect("serialising");
+        let io2 = MemRepoIO::read_from(&mut &buf[..]).expect("deserialising");
+        assert_eq!(io, io2);
+        assert!(MemRepoIO::read_from(&mut &buf[..buf.len() - 1]).is_err());
+        
+        let mut part = Partition::open(Control::new(io2), true).expect("opening partition");
+        part.load_all().expect("loading");
+        assert_eq!(part.tip_key().expect("has tip"), &tip);
+    }
+}
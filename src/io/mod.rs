@@ -4,13 +4,26 @@
 
 //! Pippin: I/O traits
 
-use std::io::{Read, Write};
+use std::any::Any;
+use std::io::{self, Read, Write};
 use std::fmt::Debug;
 
-use error::Result;
+use error::{Result, RepoError};
 
+pub mod archive;
+#[cfg(feature = "file-io")]
+pub mod cache;
+#[cfg(feature = "file-io")]
 pub mod discover;
+#[cfg(feature = "file-io")]
 pub mod file;
+#[cfg(feature = "file-io")]
+pub mod ingest;
+pub mod mem;
+pub mod retry;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod vfs;
 
 
 /// An interface providing read and/or write access to a suitable location.
@@ -18,6 +31,14 @@ pub mod file;
 /// Note: lifetimes on some functions are more restrictive than might seem
 /// necessary; this is to allow an implementation which reads and writes to
 /// internal streams.
+/// 
+/// Read operations take `&self` and each must return an independent stream
+/// (e.g. a newly opened file), with no position or other state shared with
+/// other streams or held by the provider. Thus several readers may be open
+/// at once (e.g. on different snapshots) and, where the provider is `Sync`,
+/// used from different threads. Providers needing mutable state to read
+/// must use interior mutability (with locking if `Sync`). `RepoFileIO` and
+/// `MemRepoIO` are `Send + Sync`.
 pub trait RepoIO: Debug {
     /// Return one greater than the snapshot number of the latest snapshot file
     /// or log file found.
@@ -53,6 +74,36 @@ pub trait RepoIO: Debug {
     /// This can fail due to IO operations failing.
     fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>>;
     
+    /// Get the length in bytes of a snapshot, or None if not present.
+    /// 
+    /// The default implementation reads the whole snapshot via `read_ss`;
+    /// providers which can do better should override this.
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        match self.read_ss(ss_num)? {
+            Some(mut r) => Ok(Some(io::copy(&mut r, &mut io::sink())?)),
+            None => Ok(None),
+        }
+    }
+    
+    /// Read `len` bytes of a snapshot, starting at byte `pos`. Returns None
+    /// if no such snapshot is present and fails if the range is not within
+    /// the snapshot. This is used to read single elements on demand (see
+    /// `Partition::load_lazy`).
+    /// 
+    /// The default implementation reads via `read_ss`, skipping preceding
+    /// data; providers supporting seeking should override this.
+    fn read_ss_range(&self, ss_num: usize, pos: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        match self.read_ss(ss_num)? {
+            Some(mut r) => {
+                io::copy(&mut Read::take(&mut r, pos), &mut io::sink())?;
+                let mut buf = vec![0; len];
+                r.read_exact(&mut buf)?;
+                Ok(Some(buf))
+            },
+            None => Ok(None),
+        }
+    }
+    
     /// Get a commit log (numbered `cl_num`) file for a snapshot (numbered
     /// `ss_num`). If none is found, return Ok(None).
     /// 
@@ -62,13 +113,26 @@ pub trait RepoIO: Debug {
     /// This can fail due to IO operations failing.
     fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>>;
     
+    /// Get the length in bytes of a commit log, or None if not present.
+    /// 
+    /// The default implementation reads the whole log via `read_ss_cl`;
+    /// providers which can do better should override this.
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        match self.read_ss_cl(ss_num, cl_num)? {
+            Some(mut r) => Ok(Some(io::copy(&mut r, &mut io::sink())?)),
+            None => Ok(None),
+        }
+    }
+    
     /// Open a write stream on a new snapshot file, numbered ss_num.
     /// This will increase the number returned by ss_len().
     /// 
     /// Returns None if a snapshot with number ss_num already exists.
     /// 
     /// Returns a heap-allocated write stream, either to some external resource
-    /// (such as a file) or to an internal data-structure.
+    /// (such as a file) or to an internal data-structure. The stream is
+    /// flushed once the snapshot is complete; providers may defer making the
+    /// file visible until the stream is then dropped (see `FileIoOptions`).
     /// 
     /// This can fail due to IO operations failing.
     fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>>;
@@ -100,6 +164,164 @@ pub trait RepoIO: Debug {
     /// This can fail due to IO operations failing.
     // #0012: verify atomicity of writes
     fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>>;
+    
+    /// Append `data` to an existing commit log, only if the log is exactly
+    /// `len` bytes long. The length must be checked and the data written
+    /// under one lock (or transaction), such that no other writer can append
+    /// in between. If writing fails, the log must be restored to `len` bytes
+    /// (e.g. truncated) before the error is returned.
+    /// 
+    /// Returns false (writing nothing) if no such log exists, its length
+    /// differs or this is not supported by this provider (the default
+    /// implementation returns false).
+    fn append_ss_cl_at(&mut self, _ss_num: usize, _cl_num: usize, _len: u64, _data: &[u8]) ->
+            Result<bool>
+    {
+        Ok(false)
+    }
+    
+    /// Remove a snapshot file (but not any associated commit logs). This is
+    /// used to prune old history; it must not be called on the latest
+    /// snapshot since `ss_len()` may not decrease.
+    /// 
+    /// Returns true if the file was removed and false if no such file exists
+    /// or removal is not supported by this provider (the default
+    /// implementation does nothing and returns false).
+    /// 
+    /// This can fail due to IO operations failing.
+    fn remove_ss(&mut self, _ss_num: usize) -> Result<bool> {
+        Ok(false)
+    }
+    
+    /// Remove a commit log file. Otherwise as for `remove_ss`.
+    fn remove_ss_cl(&mut self, _ss_num: usize, _cl_num: usize) -> Result<bool> {
+        Ok(false)
+    }
+    
+    /// Replace the contents of an existing snapshot file with `data`. This
+    /// must be atomic: on failure, the file must hold either its old or its
+    /// new contents (e.g. write a temporary file then rename it into place).
+    /// 
+    /// Returns true if the file was replaced and false if no such file
+    /// exists. The default implementation fails with
+    /// `RepoError::ReplaceFailed` (not supported).
+    fn replace_ss(&mut self, ss_num: usize, _data: &[u8]) -> Result<bool> {
+        RepoError::err(RepoError::ReplaceFailed { ss_num, cl_num: None })
+    }
+    
+    /// Replace the contents of an existing commit log file with `data`,
+    /// removing any index of the log. Otherwise as for `replace_ss`.
+    fn replace_ss_cl(&mut self, ss_num: usize, cl_num: usize, _data: &[u8]) -> Result<bool> {
+        RepoError::err(RepoError::ReplaceFailed { ss_num, cl_num: Some(cl_num) })
+    }
+    
+    /// Get the stored index of a commit log (see `LogIndex`), if any.
+    /// 
+    /// Indexes are optional; the default implementation returns `Ok(None)`.
+    fn read_ss_cl_index<'a>(&'a self, _ss_num: usize, _cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        Ok(None)
+    }
+    
+    /// Open a write stream to store (replacing any existing) the index of a
+    /// commit log. Returns None if the log does not exist or indexes are not
+    /// stored by this provider (the default implementation).
+    fn write_ss_cl_index<'a>(&'a mut self, _ss_num: usize, _cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        Ok(None)
+    }
+    
+    /// Rescan storage for files created, removed or appended to by other
+    /// processes (or other `RepoIO` instances) since this instance was
+    /// created or last refreshed (see `Partition::refresh`). Returns true if
+    /// anything changed. `ss_len()` must not decrease.
+    /// 
+    /// The default implementation does nothing and returns false.
+    fn refresh(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+    
+    /// Open a read stream on the audit log of administrative operations (see
+    /// `Partition::audit_log`), if any.
+    /// 
+    /// The audit log is optional; the default implementation returns
+    /// `Ok(None)`.
+    fn read_audit<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        Ok(None)
+    }
+    
+    /// Open a write stream appending to the audit log, creating it if
+    /// necessary. Returns None if the audit log is not stored by this
+    /// provider (the default implementation).
+    fn append_audit<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        Ok(None)
+    }
+    
+    /// Open a read stream on the stored filter of state-sums (see
+    /// `Partition::may_have_state`), if any.
+    /// 
+    /// The filter is optional; the default implementation returns
+    /// `Ok(None)`.
+    fn read_sum_filter<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        Ok(None)
+    }
+    
+    /// Open a write stream replacing the stored filter of state-sums.
+    /// Returns None if the filter is not stored by this provider (the
+    /// default implementation).
+    fn write_sum_filter<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        Ok(None)
+    }
+    
+    /// Try to take the advisory merge lock of the partition, used by
+    /// `Partition::merge` to avoid concurrent merges by several processes
+    /// (or instances) producing redundant merge commits. Returns `Ok(None)`
+    /// if the lock is held elsewhere; otherwise the lock is held until the
+    /// returned `MergeLock` is dropped.
+    /// 
+    /// The default implementation does no locking and always succeeds.
+    fn try_lock_merge(&self) -> Result<Option<MergeLock>> {
+        Ok(Some(MergeLock::unlocked()))
+    }
+    
+    /// Check that storage is currently accessible (e.g. that a directory
+    /// can be listed or a database queried), for health reporting (see
+    /// `Partition::health`).
+    /// 
+    /// The default implementation always succeeds.
+    fn check_status(&self) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Make everything written via streams from this provider durable (e.g.
+    /// synced to disk), where this is not already done when streams are
+    /// flushed. Used by `Partition::barrier`.
+    /// 
+    /// The default implementation does nothing, which is correct where
+    /// data is durable once a stream is flushed (or where durability is not
+    /// possible, as for in-memory storage).
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A held merge lock (see `RepoIO::try_lock_merge`), released on drop.
+#[derive(Debug)]
+pub struct MergeLock {
+    // Whatever holds the lock (e.g. a locked file)
+    _guard: Option<Box<Any>>,
+}
+impl MergeLock {
+    /// Create, holding the lock until `guard` is dropped
+    pub fn new(guard: Box<Any>) -> MergeLock {
+        MergeLock { _guard: Some(guard) }
+    }
+    /// Create without locking anything
+    pub fn unlocked() -> MergeLock {
+        MergeLock { _guard: None }
+    }
 }
 
 /// Doesn't provide any IO.
@@ -154,9 +376,18 @@ impl RepoIO for Box<RepoIO> {
     fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
         (**self).read_ss(ss_num)
     }
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        (**self).ss_size(ss_num)
+    }
+    fn read_ss_range(&self, ss_num: usize, pos: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        (**self).read_ss_range(ss_num, pos, len)
+    }
     fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
         (**self).read_ss_cl(ss_num, cl_num)
     }
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        (**self).ss_cl_size(ss_num, cl_num)
+    }
     fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
         (**self).new_ss(ss_num)
     }
@@ -169,4 +400,55 @@ impl RepoIO for Box<RepoIO> {
     {
         (**self).new_ss_cl(ss_num, cl_num)
     }
+    fn append_ss_cl_at(&mut self, ss_num: usize, cl_num: usize, len: u64, data: &[u8]) ->
+            Result<bool>
+    {
+        (**self).append_ss_cl_at(ss_num, cl_num, len, data)
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        (**self).remove_ss(ss_num)
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        (**self).remove_ss_cl(ss_num, cl_num)
+    }
+    fn replace_ss(&mut self, ss_num: usize, data: &[u8]) -> Result<bool> {
+        (**self).replace_ss(ss_num, data)
+    }
+    fn replace_ss_cl(&mut self, ss_num: usize, cl_num: usize, data: &[u8]) -> Result<bool> {
+        (**self).replace_ss_cl(ss_num, cl_num, data)
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        (**self).read_ss_cl_index(ss_num, cl_num)
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        (**self).write_ss_cl_index(ss_num, cl_num)
+    }
+    fn refresh(&mut self) -> Result<bool> {
+        (**self).refresh()
+    }
+    fn read_audit<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        (**self).read_audit()
+    }
+    fn append_audit<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        (**self).append_audit()
+    }
+    fn read_sum_filter<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        (**self).read_sum_filter()
+    }
+    fn write_sum_filter<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        (**self).write_sum_filter()
+    }
+    fn try_lock_merge(&self) -> Result<Option<MergeLock>> {
+        (**self).try_lock_merge()
+    }
+    fn check_status(&self) -> Result<()> {
+        (**self).check_status()
+    }
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
 }
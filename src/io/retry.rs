@@ -0,0 +1,336 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Retrying of transient I/O errors
+//! 
+//! `RetryingRepoIO` wraps another `RepoIO`, retrying operations which fail
+//! with a transient error (see `RetryPolicy::is_transient`), e.g. an
+//! interrupted system call or a time-out on a network file system, after an
+//! exponentially increasing delay.
+//! 
+//! Only operations which may safely be repeated are retried: read
+//! operations (including reads from streams returned), removal of files and
+//! `refresh`. Opening a write stream may have side effects (e.g. creating a
+//! file) and a failed write may have been partially completed, thus these
+//! errors are passed to the caller, who may retry the whole operation (e.g.
+//! `Partition::write_fast`).
+
+use std::io::{self, Read, Write, ErrorKind};
+use std::thread;
+use std::time::Duration;
+
+use error::Result;
+use io::{RepoIO, MergeLock};
+
+/// Configuration of `RetryingRepoIO`: how often and after what delay to
+/// retry operations failing with a transient error.
+/// 
+/// The delay starts at `initial_delay` and is doubled after each failed
+/// retry, up to `max_delay`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of retries of each operation (zero disables retrying)
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Maximum delay between retries
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Three retries, starting after 10ms
+    fn default() -> Self {
+        RetryPolicy::new(3, Duration::from_millis(10), Duration::from_secs(1))
+    }
+}
+
+impl RetryPolicy {
+    /// Create a policy
+    pub fn new(max_retries: u32, initial_delay: Duration, max_delay: Duration) -> Self {
+        RetryPolicy { max_retries, initial_delay, max_delay }
+    }
+    
+    /// A policy which never retries
+    pub fn none() -> Self {
+        RetryPolicy::new(0, Duration::from_millis(0), Duration::from_millis(0))
+    }
+    
+    /// Delay before retry number `retry` (starting from zero)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::MAX);
+        self.initial_delay.checked_mul(factor).map_or(self.max_delay,
+                |d| if d > self.max_delay { self.max_delay } else { d })
+    }
+    
+    /// True if an I/O error of this kind is considered transient (worth
+    /// retrying): `Interrupted`, `WouldBlock`, `TimedOut`, `ConnectionReset`
+    /// and `ConnectionAborted`.
+    pub fn is_transient(kind: ErrorKind) -> bool {
+        matches!(kind, ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut |
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted)
+    }
+    
+    // Retry `op` while it fails with a transient error, subject to the policy
+    fn run<T, F: FnMut() -> Result<T>>(&self, mut op: F) -> Result<T> {
+        let mut retry = 0;
+        loop {
+            match op() {
+                Err(ref e) if retry < self.max_retries && e.downcast_ref::<io::Error>()
+                        .is_some_and(|e| RetryPolicy::is_transient(e.kind())) => {
+                    warn!("retrying after transient I/O error: {}", e);
+                }
+                result => return result,
+            }
+            self.sleep(retry);
+            retry += 1;
+        }
+    }
+    
+    fn sleep(&self, retry: u32) {
+        let delay = self.delay(retry);
+        if delay > Duration::from_millis(0) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Read stream retrying reads failing with a transient error. This is safe
+/// since a failed read does not consume any data.
+struct RetryingRead<'a> {
+    inner: Box<Read + 'a>,
+    policy: RetryPolicy,
+}
+
+impl<'a> Read for RetryingRead<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut retry = 0;
+        loop {
+            match self.inner.read(buf) {
+                Err(ref e) if retry < self.policy.max_retries &&
+                        RetryPolicy::is_transient(e.kind()) => {
+                    warn!("retrying read after transient I/O error: {}", e);
+                }
+                result => return result,
+            }
+            self.policy.sleep(retry);
+            retry += 1;
+        }
+    }
+}
+
+/// Wraps a `RepoIO`, retrying operations failing with a transient error
+/// according to a `RetryPolicy` (see module documentation).
+#[derive(Debug)]
+pub struct RetryingRepoIO<IO: RepoIO> {
+    io: IO,
+    policy: RetryPolicy,
+}
+
+impl<IO: RepoIO> RetryingRepoIO<IO> {
+    /// Wrap `io` using the default policy
+    pub fn new(io: IO) -> Self {
+        RetryingRepoIO::with_policy(io, RetryPolicy::default())
+    }
+    
+    /// Wrap `io` using the given policy
+    pub fn with_policy(io: IO, policy: RetryPolicy) -> Self {
+        RetryingRepoIO { io, policy }
+    }
+    
+    /// Get the policy
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+    
+    /// Set the policy
+    pub fn set_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+    
+    /// Get the wrapped `RepoIO`
+    pub fn io(&self) -> &IO {
+        &self.io
+    }
+    
+    /// Get the wrapped `RepoIO`, mutably
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+    
+    /// Unwrap, returning the wrapped `RepoIO`
+    pub fn unwrap_io(self) -> IO {
+        self.io
+    }
+    
+    fn wrap_read<'a>(&self, r: Option<Box<Read + 'a>>) -> Option<Box<Read + 'a>> {
+        let policy = self.policy;
+        r.map(|inner| Box::new(RetryingRead { inner, policy }) as Box<Read + 'a>)
+    }
+}
+
+impl<IO: RepoIO> RepoIO for RetryingRepoIO<IO> {
+    fn ss_len(&self) -> usize { self.io.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.io.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.io.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        let r = self.policy.run(|| self.io.read_ss(ss_num))?;
+        Ok(self.wrap_read(r))
+    }
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        self.policy.run(|| self.io.ss_size(ss_num))
+    }
+    fn read_ss_range(&self, ss_num: usize, pos: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        self.policy.run(|| self.io.read_ss_range(ss_num, pos, len))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        let r = self.policy.run(|| self.io.read_ss_cl(ss_num, cl_num))?;
+        Ok(self.wrap_read(r))
+    }
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        self.policy.run(|| self.io.ss_cl_size(ss_num, cl_num))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>>
+    {
+        self.io.new_ss_cl(ss_num, cl_num)
+    }
+    fn append_ss_cl_at(&mut self, ss_num: usize, cl_num: usize, len: u64, data: &[u8]) ->
+            Result<bool>
+    {
+        // Safe to retry since the log is restored on failure:
+        let (policy, io) = (self.policy, &mut self.io);
+        policy.run(|| io.append_ss_cl_at(ss_num, cl_num, len, data))
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        let (policy, io) = (self.policy, &mut self.io);
+        policy.run(|| io.remove_ss(ss_num))
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        let (policy, io) = (self.policy, &mut self.io);
+        policy.run(|| io.remove_ss_cl(ss_num, cl_num))
+    }
+    fn replace_ss(&mut self, ss_num: usize, data: &[u8]) -> Result<bool> {
+        let (policy, io) = (self.policy, &mut self.io);
+        policy.run(|| io.replace_ss(ss_num, data))
+    }
+    fn replace_ss_cl(&mut self, ss_num: usize, cl_num: usize, data: &[u8]) -> Result<bool> {
+        let (policy, io) = (self.policy, &mut self.io);
+        policy.run(|| io.replace_ss_cl(ss_num, cl_num, data))
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        let r = self.policy.run(|| self.io.read_ss_cl_index(ss_num, cl_num))?;
+        Ok(self.wrap_read(r))
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        self.io.write_ss_cl_index(ss_num, cl_num)
+    }
+    fn refresh(&mut self) -> Result<bool> {
+        let (policy, io) = (self.policy, &mut self.io);
+        policy.run(|| io.refresh())
+    }
+    fn read_audit<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        let r = self.policy.run(|| self.io.read_audit())?;
+        Ok(self.wrap_read(r))
+    }
+    fn append_audit<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_audit()
+    }
+    fn read_sum_filter<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        let r = self.policy.run(|| self.io.read_sum_filter())?;
+        Ok(self.wrap_read(r))
+    }
+    fn write_sum_filter<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.write_sum_filter()
+    }
+    fn try_lock_merge(&self) -> Result<Option<MergeLock>> {
+        self.policy.run(|| self.io.try_lock_merge())
+    }
+    fn check_status(&self) -> Result<()> {
+        self.policy.run(|| self.io.check_status())
+    }
+    fn sync(&mut self) -> Result<()> {
+        let (policy, io) = (self.policy, &mut self.io);
+        policy.run(|| io.sync())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use super::*;
+    use io::mem::MemRepoIO;
+    
+    // Fails the first `failures` reads of snapshots with `kind`
+    #[derive(Debug)]
+    struct Flaky {
+        io: MemRepoIO,
+        failures: Cell<u32>,
+        kind: ErrorKind,
+    }
+    
+    impl RepoIO for Flaky {
+        fn ss_len(&self) -> usize { self.io.ss_len() }
+        fn ss_cl_len(&self, ss_num: usize) -> usize { self.io.ss_cl_len(ss_num) }
+        fn has_ss(&self, ss_num: usize) -> bool { self.io.has_ss(ss_num) }
+        fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(Box::new(io::Error::new(self.kind, "flaky")));
+            }
+            self.io.read_ss(ss_num)
+        }
+        fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+            self.io.read_ss_cl(ss_num, cl_num)
+        }
+        fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+            self.io.new_ss(ss_num)
+        }
+        fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+                Result<Option<Box<Write+'a>>>
+        {
+            self.io.append_ss_cl(ss_num, cl_num)
+        }
+        fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+                Result<Option<Box<Write+'a>>>
+        {
+            self.io.new_ss_cl(ss_num, cl_num)
+        }
+    }
+    
+    #[test]
+    fn retry() {
+        let policy = RetryPolicy::new(2, Duration::from_millis(1), Duration::from_millis(3));
+        assert_eq!(policy.delay(0), Duration::from_millis(1));
+        assert_eq!(policy.delay(1), Duration::from_millis(2));
+        assert_eq!(policy.delay(2), Duration::from_millis(3));
+        assert_eq!(policy.delay(40), Duration::from_millis(3));
+        
+        let mut mem = MemRepoIO::new();
+        mem.new_ss(0).unwrap().unwrap().write_all(b"snapshot").unwrap();
+        let flaky = Flaky { io: mem, failures: Cell::new(2), kind: ErrorKind::Interrupted };
+        let mut io = RetryingRepoIO::with_policy(flaky, policy);
+        let mut buf = Vec::new();
+        io.read_ss(0).unwrap().unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"snapshot");
+        
+        io.io_mut().failures.set(3);
+        assert!(io.read_ss(0).is_err());
+        io.io_mut().failures.set(1);
+        io.io_mut().kind = ErrorKind::NotFound;
+        assert!(io.read_ss(0).is_err());
+        assert!(io.read_ss(0).unwrap().is_some());
+    }
+}
@@ -0,0 +1,385 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! SQLite implementation of `RepoIO`
+//! 
+//! `RepoSqliteIO` stores snapshots, commit logs and log indexes as blobs in a
+//! single SQLite database file. Compared to `RepoFileIO` this gives atomic
+//! writes, single-file deployment and easy backup, which is useful on
+//! platforms where many small files are problematic (e.g. Android).
+//! 
+//! Multiple partitions may share one database; each is identified by a key
+//! (usually the partition name). Tables used are `pippin_parts` and
+//! `pippin_files`; other tables may be used by the application.
+
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::path::Path;
+
+use rusqlite::{Connection, OptionalExtension};
+
+use error::Result;
+use io::RepoIO;
+
+// Values of the `kind` column
+const KIND_SS: i64 = 0;
+const KIND_CL: i64 = 1;
+const KIND_INDEX: i64 = 2;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS pippin_parts (
+    part TEXT PRIMARY KEY NOT NULL,
+    ss_len INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pippin_files (
+    part TEXT NOT NULL,
+    ss INTEGER NOT NULL,
+    cl INTEGER NOT NULL,
+    kind INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (part, ss, cl, kind)
+);";
+
+/// Stores all files of a partition in an SQLite database.
+/// 
+/// All `RepoIO` operations are supported, including removal of files and
+/// storage of log indexes. Snapshots and log indexes are stored atomically
+/// when their write stream is flushed or dropped; each write to a commit log
+/// is appended atomically.
+#[derive(Debug)]
+pub struct RepoSqliteIO {
+    conn: Connection,
+    part: String,
+    // Never decreases (see `RepoIO::ss_len`); also stored in the database
+    ss_len: usize,
+}
+
+impl RepoSqliteIO {
+    /// Open (or create) a database file at `path` and use it to store the
+    /// partition identified by `part`.
+    pub fn open<P: AsRef<Path>>(path: P, part: &str) -> Result<RepoSqliteIO> {
+        RepoSqliteIO::from_connection(Connection::open(path)?, part)
+    }
+    
+    /// Use an existing database connection to store the partition identified
+    /// by `part`. Tables are created if not already present.
+    pub fn from_connection(conn: Connection, part: &str) -> Result<RepoSqliteIO> {
+        conn.execute_batch(SCHEMA)?;
+        let stored: Option<i64> = conn.query_row(
+                "SELECT ss_len FROM pippin_parts WHERE part = ?1",
+                [part], |row| row.get(0)).optional()?;
+        let found: Option<i64> = conn.query_row(
+                "SELECT MAX(ss) + 1 FROM pippin_files WHERE part = ?1",
+                [part], |row| row.get(0))?;
+        let ss_len = stored.unwrap_or(0).max(found.unwrap_or(0)) as usize;
+        Ok(RepoSqliteIO { conn, part: part.to_string(), ss_len })
+    }
+    
+    /// Get the key identifying the partition
+    pub fn part(&self) -> &str {
+        &self.part
+    }
+    
+    /// Get the database connection
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+    
+    /// Unwrap, returning the database connection
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+    
+    // Increase ss_len if necessary
+    fn use_ss(&mut self, ss_num: usize) -> Result<()> {
+        if ss_num >= self.ss_len {
+            self.ss_len = ss_num + 1;
+            self.conn.execute("INSERT OR REPLACE INTO pippin_parts (part, ss_len) VALUES (?1, ?2)",
+                    (&self.part, self.ss_len as i64))?;
+        }
+        Ok(())
+    }
+    
+    fn exists(&self, ss_num: usize, cl_num: usize, kind: i64) -> Result<bool> {
+        let found: Option<i64> = self.conn.query_row(
+                "SELECT 1 FROM pippin_files WHERE part = ?1 AND ss = ?2 AND cl = ?3 AND kind = ?4",
+                (&self.part, ss_num as i64, cl_num as i64, kind), |row| row.get(0)).optional()?;
+        Ok(found.is_some())
+    }
+    
+    fn read<'a>(&'a self, ss_num: usize, cl_num: usize, kind: i64) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        let data: Option<Vec<u8>> = self.conn.query_row(
+                "SELECT data FROM pippin_files WHERE part = ?1 AND ss = ?2 AND cl = ?3 AND kind = ?4",
+                (&self.part, ss_num as i64, cl_num as i64, kind), |row| row.get(0)).optional()?;
+        Ok(data.map(|data| Box::new(Cursor::new(data)) as Box<Read>))
+    }
+    
+    fn remove(&mut self, ss_num: usize, cl_num: usize, kind: i64) -> Result<bool> {
+        let n = self.conn.execute(
+                "DELETE FROM pippin_files WHERE part = ?1 AND ss = ?2 AND cl = ?3 AND kind = ?4",
+                (&self.part, ss_num as i64, cl_num as i64, kind))?;
+        Ok(n > 0)
+    }
+    
+    // Replace the data of an existing entry, removing the index of a log in
+    // the same transaction
+    fn replace(&mut self, ss_num: usize, cl_num: usize, kind: i64, data: &[u8]) -> Result<bool> {
+        let tx = self.conn.transaction()?;
+        let n = tx.execute("UPDATE pippin_files SET data = ?5 \
+                WHERE part = ?1 AND ss = ?2 AND cl = ?3 AND kind = ?4",
+                (&self.part, ss_num as i64, cl_num as i64, kind, data))?;
+        if n == 0 {
+            return Ok(false);
+        }
+        if kind == KIND_CL {
+            tx.execute("DELETE FROM pippin_files WHERE part = ?1 AND ss = ?2 AND cl = ?3 AND kind = ?4",
+                    (&self.part, ss_num as i64, cl_num as i64, KIND_INDEX))?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+    
+    fn writer(&self, ss_num: usize, cl_num: usize, kind: i64, buffered: bool) -> SqliteWriter<'_> {
+        SqliteWriter {
+            io: self,
+            ss: ss_num as i64,
+            cl: cl_num as i64,
+            kind,
+            buf: if buffered { Some(Vec::new()) } else { None },
+        }
+    }
+}
+
+impl RepoIO for RepoSqliteIO {
+    fn ss_len(&self) -> usize {
+        self.ss_len
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        let result: rusqlite::Result<Option<i64>> = self.conn.query_row(
+                "SELECT MAX(cl) + 1 FROM pippin_files WHERE part = ?1 AND ss = ?2 AND kind = ?3",
+                (&self.part, ss_num as i64, KIND_CL), |row| row.get(0));
+        result.unwrap_or_else(|e| {
+            warn!("RepoSqliteIO: failed to query logs: {}", e);
+            None
+        }).unwrap_or(0) as usize
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.exists(ss_num, 0, KIND_SS).unwrap_or_else(|e| {
+            warn!("RepoSqliteIO: failed to query snapshots: {}", e);
+            false
+        })
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.read(ss_num, 0, KIND_SS)
+    }
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        let len: Option<i64> = self.conn.query_row(
+                "SELECT length(data) FROM pippin_files WHERE part = ?1 AND ss = ?2 AND cl = 0 AND kind = ?3",
+                (&self.part, ss_num as i64, KIND_SS), |row| row.get(0)).optional()?;
+        Ok(len.map(|len| len as u64))
+    }
+    fn read_ss_range(&self, ss_num: usize, pos: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        let data: Option<Vec<u8>> = self.conn.query_row(
+                "SELECT substr(data, ?4, ?5) FROM pippin_files WHERE part = ?1 AND ss = ?2 AND cl = 0 AND kind = ?3",
+                (&self.part, ss_num as i64, KIND_SS, pos as i64 + 1, len as i64),
+                |row| row.get(0)).optional()?;
+        match data {
+            Some(ref data) if data.len() != len => {
+                Err(Box::new(io::Error::new(ErrorKind::UnexpectedEof, "range outside of snapshot")))
+            },
+            data => Ok(data),
+        }
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.read(ss_num, cl_num, KIND_CL)
+    }
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        let len: Option<i64> = self.conn.query_row(
+                "SELECT length(data) FROM pippin_files WHERE part = ?1 AND ss = ?2 AND cl = ?3 AND kind = ?4",
+                (&self.part, ss_num as i64, cl_num as i64, KIND_CL), |row| row.get(0)).optional()?;
+        Ok(len.map(|len| len as u64))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        if self.exists(ss_num, 0, KIND_SS)? {
+            return Ok(None);
+        }
+        self.use_ss(ss_num)?;
+        trace!("RepoSqliteIO: creating snapshot {}", ss_num);
+        Ok(Some(Box::new(self.writer(ss_num, 0, KIND_SS, true))))
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        if !self.exists(ss_num, cl_num, KIND_CL)? {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(self.writer(ss_num, cl_num, KIND_CL, false))))
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let n = self.conn.execute("INSERT OR IGNORE INTO pippin_files (part, ss, cl, kind, data) \
+                VALUES (?1, ?2, ?3, ?4, x'')",
+                (&self.part, ss_num as i64, cl_num as i64, KIND_CL))?;
+        if n == 0 {
+            return Ok(None);
+        }
+        self.use_ss(ss_num)?;
+        trace!("RepoSqliteIO: creating log {}-{}", ss_num, cl_num);
+        Ok(Some(Box::new(self.writer(ss_num, cl_num, KIND_CL, false))))
+    }
+    fn append_ss_cl_at(&mut self, ss_num: usize, cl_num: usize, len: u64, data: &[u8]) ->
+            Result<bool>
+    {
+        // Not committed (thus rolled back) on failure:
+        let tx = self.conn.transaction()?;
+        let n = tx.execute("UPDATE pippin_files SET data = CAST(data || ?6 AS BLOB) \
+                WHERE part = ?1 AND ss = ?2 AND cl = ?3 AND kind = ?4 AND length(data) = ?5",
+                (&self.part, ss_num as i64, cl_num as i64, KIND_CL, len as i64, data))?;
+        if n == 0 {
+            return Ok(false);
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        self.remove(ss_num, 0, KIND_SS)
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        self.remove(ss_num, cl_num, KIND_INDEX)?;
+        self.remove(ss_num, cl_num, KIND_CL)
+    }
+    fn replace_ss(&mut self, ss_num: usize, data: &[u8]) -> Result<bool> {
+        trace!("RepoSqliteIO: replacing snapshot {}", ss_num);
+        self.replace(ss_num, 0, KIND_SS, data)
+    }
+    fn replace_ss_cl(&mut self, ss_num: usize, cl_num: usize, data: &[u8]) -> Result<bool> {
+        trace!("RepoSqliteIO: replacing log {}-{}", ss_num, cl_num);
+        self.replace(ss_num, cl_num, KIND_CL, data)
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        self.read(ss_num, cl_num, KIND_INDEX)
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        if !self.exists(ss_num, cl_num, KIND_CL)? {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(self.writer(ss_num, cl_num, KIND_INDEX, true))))
+    }
+    fn check_status(&self) -> Result<()> {
+        let _: i64 = self.conn.query_row("SELECT COUNT(*) FROM pippin_files WHERE part = ?1",
+                (&self.part,), |row| row.get(0))?;
+        Ok(())
+    }
+}
+
+// Write stream on a database entry. Buffered streams replace the entry when
+// flushed (or dropped); unbuffered ones append to it on each write.
+struct SqliteWriter<'a> {
+    io: &'a RepoSqliteIO,
+    ss: i64,
+    cl: i64,
+    kind: i64,
+    buf: Option<Vec<u8>>,
+}
+
+impl<'a> Write for SqliteWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut buf) = self.buf {
+            buf.extend_from_slice(data);
+            return Ok(data.len());
+        }
+        self.io.conn.execute("UPDATE pippin_files SET data = CAST(data || ?5 AS BLOB) \
+                WHERE part = ?1 AND ss = ?2 AND cl = ?3 AND kind = ?4",
+                (&self.io.part, self.ss, self.cl, self.kind, data))
+            .map_err(io::Error::other)?;
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(ref buf) = self.buf {
+            self.io.conn.execute("INSERT OR REPLACE INTO pippin_files (part, ss, cl, kind, data) \
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    (&self.io.part, self.ss, self.cl, self.kind, buf))
+                .map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for SqliteWriter<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("RepoSqliteIO: failed to write {}-{}: {}", self.ss, self.cl, e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    
+    use super::RepoSqliteIO;
+    use control::DefaultControl;
+    use std::io::Read;
+    
+    use io::RepoIO;
+    use part::Partition;
+    use state::{StateRead, StateWrite};
+    
+    #[test]
+    fn sqlite_io() {
+        type Control = DefaultControl<String, RepoSqliteIO>;
+        let conn = Connection::open_in_memory().expect("opening db");
+        let io = RepoSqliteIO::from_connection(conn, "sqlite").expect("creating io");
+        let mut part = Partition::create(Control::new(io), "sqlite").expect("creating partition");
+        let mut state = part.tip().expect("has tip").clone_mut();
+        let id = state.insert_new("one".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        part.write_snapshot().expect("writing snapshot");
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert_new("two".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        let tip = part.tip_key().expect("has tip").clone();
+        
+        let io = part.unwrap_control().unwrap_io();
+        assert_eq!(io.ss_len(), 2);
+        assert!(io.has_ss(0) && io.has_ss(1) && !io.has_ss(2));
+        assert_eq!(io.ss_cl_len(0), 1);
+        assert!(io.read_ss_cl_index(1, 0).expect("reading").is_some());
+        let size = io.ss_size(1).expect("size").expect("has ss");
+        let mut all = Vec::new();
+        io.read_ss(1).expect("reading").expect("has ss").read_to_end(&mut all).expect("reading");
+        assert_eq!(all.len() as u64, size);
+        assert_eq!(io.read_ss_range(1, 4, 8).expect("reading"), Some(all[4..12].to_vec()));
+        assert!(io.read_ss_range(1, size - 4, 8).is_err());
+        
+        // Another partition in the same database is independent:
+        let other = RepoSqliteIO::from_connection(io.into_connection(), "other").expect("io");
+        assert_eq!(other.ss_len(), 0);
+        let io = RepoSqliteIO::from_connection(other.into_connection(), "sqlite").expect("io");
+        assert_eq!(io.ss_len(), 2);
+        
+        let mut part = Partition::open(Control::new(io), true).expect("opening partition");
+        part.load_all().expect("loading");
+        assert_eq!(part.tip_key().expect("has tip"), &tip);
+        assert_eq!(part.tip().expect("has tip").get(id).expect("has elt"), "one");
+        let mut io = part.unwrap_control().unwrap_io();
+        assert!(io.replace_ss_cl(1, 0, b"log").expect("replacing"));
+        assert!(io.read_ss_cl_index(1, 0).expect("reading").is_none());
+        assert_eq!(io.ss_cl_size(1, 0).expect("size"), Some(3));
+        assert!(!io.append_ss_cl_at(1, 0, 2, b"!").expect("appending"));
+        assert!(io.append_ss_cl_at(1, 0, 3, b"!").expect("appending"));
+        assert_eq!(io.ss_cl_size(1, 0).expect("size"), Some(4));
+        assert!(!io.replace_ss(2, b"ss").expect("replacing"));
+        assert!(io.remove_ss(0).expect("removing"));
+        assert!(!io.has_ss(0));
+        assert_eq!(io.ss_len(), 2);
+    }
+}
@@ -0,0 +1,268 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: read-only virtual filesystem view of a partition
+//! 
+//! The `Vfs` trait is a minimal read-only filesystem interface, intended to
+//! be adapted to a filesystem binding (e.g. a FUSE crate) so that Pippin data
+//! can be inspected with standard tools without exporting copies.
+
+use std::cmp::min;
+use std::io::{ErrorKind, Read};
+
+use control::Control;
+use elt::{Element, EltId, Masked, MaskPolicy};
+use error::{Result, make_io_err};
+use part::Partition;
+use state::StateRead;
+
+
+/// Kind of an entry in a `Vfs`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VfsKind {
+    /// A directory
+    Dir,
+    /// A regular (read-only) file
+    File,
+}
+
+/// An entry (file or directory) in a `Vfs`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VfsEntry {
+    /// Name of the entry within its directory (empty for the root)
+    pub name: String,
+    /// Kind of entry
+    pub kind: VfsKind,
+    /// Size in bytes (zero for directories)
+    pub size: u64,
+}
+
+impl VfsEntry {
+    /// Create a directory entry
+    pub fn dir(name: String) -> VfsEntry {
+        VfsEntry { name, kind: VfsKind::Dir, size: 0 }
+    }
+    /// Create a file entry
+    pub fn file(name: String, size: u64) -> VfsEntry {
+        VfsEntry { name, kind: VfsKind::File, size }
+    }
+}
+
+/// A read-only virtual filesystem.
+/// 
+/// Paths are relative to the root and separated by `/`; leading and trailing
+/// separators are ignored (thus both `""` and `"/"` refer to the root).
+/// Missing paths cause an `io::Error` of kind `NotFound`; using a file as a
+/// directory or vice-versa causes one of kind `InvalidInput`.
+pub trait Vfs {
+    /// List the entries of the directory at `path`.
+    fn list(&self, path: &str) -> Result<Vec<VfsEntry>>;
+    
+    /// Read from the file at `path`, starting at byte `offset`, into `buf`.
+    /// Returns the number of bytes read (zero at or beyond the end).
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize>;
+    
+    /// Get the entry at `path`.
+    /// 
+    /// The default implementation finds the entry by listing its parent.
+    fn stat(&self, path: &str) -> Result<VfsEntry> {
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            return Ok(VfsEntry::dir(String::new()));
+        }
+        let (parent, name) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path),
+        };
+        self.list(parent)?.into_iter().find(|entry| entry.name == name)
+            .map_or_else(|| make_io_err(ErrorKind::NotFound, "no such file or directory"), Ok)
+    }
+}
+
+
+/// A `Vfs` view of a partition.
+/// 
+/// The root contains three directories:
+/// 
+/// *   `snapshots`, containing snapshot files, named `ssN.pip`
+/// *   `logs`, containing commit logs, named `ssN-clM.piplog`
+/// *   `current`, containing one file per element of the tip state, named by
+///     element identifier and holding the element's data as written by
+///     `Element::write_buf` (empty if the partition is not ready; see
+///     `Partition::tip()`)
+/// 
+/// Files are read through the partition's `RepoIO` on each access, and must
+/// be read in full to determine sizes; this view is intended for inspection,
+/// not performance.
+/// 
+/// A view created with `with_mask` passes elements in `current` through a
+/// `MaskPolicy` and omits the `snapshots` and `logs` directories, since raw
+/// files cannot be masked.
+pub struct PartitionVfs<'a, C: Control + 'a> {
+    part: &'a Partition<C>,
+    mask: Option<&'a MaskPolicy<C::Element>>,
+}
+
+// Parsed path
+enum Node {
+    Root,
+    Snapshots,
+    Logs,
+    Current,
+    Snapshot(usize),
+    Log(usize, usize),
+    Elt(EltId),
+}
+
+impl<'a, C: Control> PartitionVfs<'a, C> {
+    /// Create a view of a partition
+    pub fn new(part: &'a Partition<C>) -> PartitionVfs<'a, C> {
+        PartitionVfs { part, mask: None }
+    }
+    
+    /// Create a view of a partition, masking element data with `mask`
+    pub fn with_mask(part: &'a Partition<C>, mask: &'a MaskPolicy<C::Element>)
+            -> PartitionVfs<'a, C>
+    {
+        PartitionVfs { part, mask: Some(mask) }
+    }
+    
+    fn parse(&self, path: &str) -> Result<Node> {
+        let path = path.trim_matches('/');
+        let (dir, name) = match path.find('/') {
+            Some(i) => (&path[..i], Some(&path[i + 1..])),
+            None => (path, None),
+        };
+        let node = match (dir, name) {
+            ("", None) => Some(Node::Root),
+            ("snapshots", None) => Some(Node::Snapshots),
+            ("logs", None) => Some(Node::Logs),
+            ("current", None) => Some(Node::Current),
+            ("snapshots", Some(name)) => parse_ss(name).map(Node::Snapshot),
+            ("logs", Some(name)) => parse_cl(name).map(|(ss, cl)| Node::Log(ss, cl)),
+            ("current", Some(name)) => name.parse::<u64>().ok().map(|id| Node::Elt(id.into())),
+            _ => None,
+        };
+        let node = match node {
+            Some(Node::Snapshots) | Some(Node::Logs) | Some(Node::Snapshot(_)) |
+                Some(Node::Log(..)) if self.mask.is_some() => None,
+            node => node,
+        };
+        node.map_or_else(|| make_io_err(ErrorKind::NotFound, "no such file or directory"), Ok)
+    }
+    
+    // Get the contents of a file, or None if not found
+    fn contents(&self, node: &Node) -> Result<Option<Vec<u8>>> {
+        let io = self.part.control().io();
+        let reader = match *node {
+            Node::Snapshot(ss) => io.read_ss(ss)?,
+            Node::Log(ss, cl) => io.read_ss_cl(ss, cl)?,
+            Node::Elt(id) => {
+                let elt = match self.part.tip().ok().and_then(|tip| tip.get(id).ok()) {
+                    Some(elt) => elt,
+                    None => return Ok(None),
+                };
+                let mut buf = Vec::new();
+                match self.mask.map_or(Masked::Keep, |mask| mask.mask(id, elt)) {
+                    Masked::Keep => elt.write_buf(&mut buf)?,
+                    Masked::Replace(elt) => elt.write_buf(&mut buf)?,
+                    Masked::Omit => return Ok(None),
+                }
+                return Ok(Some(buf));
+            },
+            _ => return make_io_err(ErrorKind::InvalidInput, "is a directory"),
+        };
+        match reader {
+            Some(mut r) => {
+                let mut buf = Vec::new();
+                r.read_to_end(&mut buf)?;
+                Ok(Some(buf))
+            },
+            None => Ok(None),
+        }
+    }
+    
+    fn file_entry(&self, name: String, node: Node) -> Result<Option<VfsEntry>> {
+        Ok(self.contents(&node)?.map(|data| VfsEntry::file(name, data.len() as u64)))
+    }
+}
+
+impl<'a, C: Control> Vfs for PartitionVfs<'a, C> {
+    fn list(&self, path: &str) -> Result<Vec<VfsEntry>> {
+        let io = self.part.control().io();
+        let mut entries = Vec::new();
+        match self.parse(path)? {
+            Node::Root => {
+                let names: &[&str] = if self.mask.is_some() {
+                    &["current"]
+                } else {
+                    &["snapshots", "logs", "current"]
+                };
+                for name in names {
+                    entries.push(VfsEntry::dir(name.to_string()));
+                }
+            },
+            Node::Snapshots => {
+                for ss in 0..io.ss_len() {
+                    if io.has_ss(ss) {
+                        entries.extend(self.file_entry(format!("ss{}.pip", ss), Node::Snapshot(ss))?);
+                    }
+                }
+            },
+            Node::Logs => {
+                for ss in 0..io.ss_len() {
+                    for cl in 0..io.ss_cl_len(ss) {
+                        entries.extend(self.file_entry(format!("ss{}-cl{}.piplog", ss, cl),
+                                Node::Log(ss, cl))?);
+                    }
+                }
+            },
+            Node::Current => {
+                if let Ok(tip) = self.part.tip() {
+                    let mut ids: Vec<EltId> = tip.elts_iter().map(|(id, _)| id).collect();
+                    ids.sort();
+                    for id in ids {
+                        entries.extend(self.file_entry(id.to_string(), Node::Elt(id))?);
+                    }
+                }
+            },
+            _ => return make_io_err(ErrorKind::InvalidInput, "not a directory"),
+        }
+        Ok(entries)
+    }
+    
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let node = self.parse(path)?;
+        let data = match self.contents(&node)? {
+            Some(data) => data,
+            None => return make_io_err(ErrorKind::NotFound, "no such file or directory"),
+        };
+        if offset >= data.len() as u64 {
+            return Ok(0);
+        }
+        let data = &data[offset as usize..];
+        let n = min(buf.len(), data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+}
+
+// Parse "ssN.pip"
+fn parse_ss(name: &str) -> Option<usize> {
+    if name.starts_with("ss") && name.ends_with(".pip") {
+        name[2..name.len() - 4].parse().ok()
+    } else {
+        None
+    }
+}
+
+// Parse "ssN-clM.piplog"
+fn parse_cl(name: &str) -> Option<(usize, usize)> {
+    if !name.starts_with("ss") || !name.ends_with(".piplog") {
+        return None;
+    }
+    let name = &name[2..name.len() - 7];
+    let i = name.find("-cl")?;
+    Some((name[..i].parse().ok()?, name[i + 3..].parse().ok()?))
+}
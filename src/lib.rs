@@ -11,13 +11,15 @@
 //! without requiring any central repository.
 //! 
 //! Objects may be of any user-defined type, such that (a) the same type is
-//! used for all objects (dealing with polymorphism internally if required),
+//! used for all objects (dealing with polymorphism internally if required,
+//! e.g. via an `enum` or the type-tagged elements of the `registry` module),
 //! (b) objects are normally read-only with explicit copy-on-write, and (c)
 //! objects can be serialised to and deserialised from a byte stream.
 //! 
-//! TODO: scalability. The current code requires reading all data on start-up;
+//! TODO: scalability. Normal loading requires reading all data on start-up;
 //! the original approach (partitioning) was abandoned for a host of different
-//! reasons; an alternative approach (reading data on-demand) is planned.
+//! reasons. Single elements of the latest snapshot may be read on demand (see
+//! `Partition::load_lazy`), but commit logs are not yet supported there.
 //! 
 //! Historical data may be deleted easily, since full snapshots are written
 //! periodically. The limitation here is that distributed synchronisation
@@ -77,23 +79,42 @@ extern crate crypto;
 extern crate chrono;
 extern crate byteorder;
 extern crate hashindexed;
+#[cfg(feature = "file-io")]
 extern crate regex;
 extern crate vec_map;
 extern crate rand;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(feature = "zlib")]
+extern crate flate2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "aes-gcm")]
+extern crate aes_gcm;
+#[cfg(feature = "file-io")]
 extern crate walkdir;
+#[cfg(feature = "unicode")]
+extern crate unicode_normalization;
 #[macro_use]
 extern crate log;
 
+pub mod builder;
 pub mod commit;
 pub mod control;
 pub mod elt;
 pub mod error;
+#[cfg(feature = "gen")]
+pub mod gen;
+pub mod index;
 pub mod io;
 pub mod merge;
 pub mod part;
 pub mod pip;
+pub mod registry;
 pub mod rw;
 pub mod state;
+pub mod subscribe;
+pub mod sync;
 pub mod sum;
 pub mod util;
 
@@ -27,19 +27,26 @@
 //! *   As above, but let the user choose which states to merge
 //! *   Everything at once with an 'n-to-one' merge method
 //! 
-//! For simplicity we currently only implement two-to-one merge with a common
-//! ancestor, recursively selecting two states to merge. Various solvers are
+//! We implement two-to-one merge with a common ancestor, recursively selecting
+//! two states to merge (`TwoWayMerge`), and an 'n-to-one' merge of all tips
+//! with a common ancestor of all (`NWayMerge`). Various solvers are
 //! available, but for conflicting changes to a single element either a naive
 //! solver must be used or a custom solver supplied.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "chaos")]
+use std::cell::RefCell;
 use std::marker::PhantomData;
 use std::rc::Rc;
 
+#[cfg(feature = "chaos")]
+use rand::Rng;
+
 use commit::{Commit, CommitMeta, EltChange, MakeCommitMeta};
 use state::{PartState, StateRead};
 use elt::{EltId, Element};
 use sum::Sum;
+use error::MergeError;
 
 /// This struct controls the merging of two states into one.
 /// 
@@ -51,10 +58,26 @@ pub struct TwoWayMerge<'a, E: Element+'a> {
     // Second tip
     b: &'a PartState<E>,
     // Common ancestor
-    c: &'a PartState<E>,
+    c: Base<'a, E>,
     // List of conflicts
     v: Vec<(EltId, EltMerge<E>)>,
 }
+
+// Common ancestor of a `TwoWayMerge`: either a stored state or a virtual
+// state constructed by merging several ancestors.
+enum Base<'a, E: Element+'a> {
+    Borrowed(&'a PartState<E>),
+    Owned(PartState<E>),
+}
+impl<'a, E: Element> Base<'a, E> {
+    fn get_rc(&self, id: EltId) -> Option<&Rc<E>> {
+        match *self {
+            Base::Borrowed(state) => state.get_rc(id).ok(),
+            Base::Owned(ref state) => state.get_rc(id).ok(),
+        }
+    }
+}
+
 impl<'a, E: Element> TwoWayMerge<'a, E> {
     /// Create an instance. `c` should be a common ancestor state of `a` and `b`.
     /// 
@@ -82,7 +105,19 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
             // Have elt in state 2 but not 1
             v.push((id, EltMerge::Fail));
         }
-        TwoWayMerge { a: a, b: b, c: c, v: v }
+        TwoWayMerge { a: a, b: b, c: Base::Borrowed(c), v: v }
+    }
+    
+    /// Create an instance with an owned common ancestor state `c`. This is
+    /// intended for *virtual* bases, e.g. a state constructed by merging
+    /// several common ancestors of `a` and `b` (see
+    /// `Partition::merge_two_with`). Otherwise this is identical to `new`.
+    pub fn with_base<'b>(a: &'b PartState<E>, b: &'b PartState<E>,
+        c: PartState<E>) -> TwoWayMerge<'b, E>
+    {
+        let mut merge = TwoWayMerge::new(a, b, a);
+        merge.c = Base::Owned(c);
+        merge
     }
     
     /// Run a solver over all still-ambiguous cases. This need not resolve all
@@ -92,7 +127,7 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
     pub fn solve<S>(&mut self, s: &S) where S: TwoWaySolver<E> {
         for &mut (id, ref mut result) in &mut self.v {
             if *result == EltMerge::Fail {
-                *result = s.solve(self.a.get_rc(id).ok(), self.b.get_rc(id).ok(), self.c.get_rc(id).ok());
+                *result = s.solve(self.a.get_rc(id).ok(), self.b.get_rc(id).ok(), self.c.get_rc(id));
             }
         }
     }
@@ -126,7 +161,7 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
     /// Operation is `O(1)`.
     pub fn solve_one<S>(&mut self, i: usize, s: &S) where S: TwoWaySolver<E> {
         let id = self.v[i].0;
-        self.v[i].1 = s.solve(self.a.get_rc(id).ok(), self.b.get_rc(id).ok(), self.c.get_rc(id).ok());
+        self.v[i].1 = s.solve(self.a.get_rc(id).ok(), self.b.get_rc(id).ok(), self.c.get_rc(id));
     }
     
     /// Get the number of unsolved conflicts.
@@ -412,6 +447,14 @@ pub trait TwoWaySolver<E: Element> {
         c: Option<&'a Rc<E>>) -> EltMerge<E>;
 }
 
+impl<E: Element> TwoWaySolver<E> for Box<TwoWaySolver<E>> {
+    fn solve<'a>(&self, a: Option<&'a Rc<E>>, b: Option<&'a Rc<E>>,
+        c: Option<&'a Rc<E>>) -> EltMerge<E>
+    {
+        (**self).solve(a, b, c)
+    }
+}
+
 /// Implementation of `TwoWaySolver` which always selects state A.
 pub struct TwoWaySolveUseA<E: Element>{
     p: PhantomData<E>
@@ -515,6 +558,58 @@ impl<'a, E: Element, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a> TwoWaySolver<
     }
 }
 
+/// Failure-injection wrapper around another solver (feature `chaos`; intended
+/// for test builds only).
+/// 
+/// Each call randomly swaps the `a` and `b` inputs before calling the wrapped
+/// solver (and swaps the result back), thus a symmetric solver gives the same
+/// results as without this wrapper. Together with `shuffle_merge` and
+/// `Partition::merge_chaos` this may be used to check that merge outcomes do
+/// not depend on the order of inputs. Given a seeded generator, decisions are
+/// reproducible.
+#[cfg(feature = "chaos")]
+pub struct ChaosSolver<'a, E: Element, S: TwoWaySolver<E>+'a, G: Rng> {
+    s: &'a S,
+    rng: RefCell<G>,
+    p: PhantomData<E>
+}
+#[cfg(feature = "chaos")]
+impl<'a, E: Element, S: TwoWaySolver<E>+'a, G: Rng> ChaosSolver<'a, E, S, G> {
+    /// Create an instance, wrapping solver `s` and using random number
+    /// generator `rng`
+    pub fn new(s: &'a S, rng: G) -> ChaosSolver<'a, E, S, G> {
+        ChaosSolver { s, rng: RefCell::new(rng), p: PhantomData }
+    }
+    
+    /// Randomly permute a slice
+    pub fn shuffle<T>(&self, v: &mut [T]) {
+        self.rng.borrow_mut().shuffle(v);
+    }
+    
+    /// Randomly permute the order in which conflicts of `merge` are solved
+    pub fn shuffle_merge(&self, merge: &mut TwoWayMerge<E>) {
+        self.shuffle(&mut merge.v);
+    }
+}
+#[cfg(feature = "chaos")]
+impl<'a, E: Element, S: TwoWaySolver<E>+'a, G: Rng> TwoWaySolver<E>
+    for ChaosSolver<'a, E, S, G>
+{
+    fn solve(&self, a: Option<&Rc<E>>, b: Option<&Rc<E>>,
+        c: Option<&Rc<E>>) -> EltMerge<E>
+    {
+        let swap: bool = self.rng.borrow_mut().gen();
+        if !swap {
+            return self.s.solve(a, b, c);
+        }
+        match self.s.solve(b, a, c) {
+            EltMerge::A => EltMerge::B,
+            EltMerge::B => EltMerge::A,
+            result => result,
+        }
+    }
+}
+
 /// Solver which tries to make sensible choices by comparing to the common
 /// ancestor. In brief, if one state has element equal to that in the ancestor
 /// (or neither has the element in question), the element from the other state
@@ -571,3 +666,260 @@ impl<E: Element> TwoWaySolver<E> for RenamingSolver2W<E> {
         }
     }
 }
+
+
+// —————  N-way merge  —————
+
+/// This struct controls the merging of any number of states into one in a
+/// single step, producing one merge commit with all states as parents.
+/// 
+/// Unlike repeated use of `TwoWayMerge`, each element is solved exactly once
+/// (seeing all states at once) and no intermediate commits are created.
+/// A common ancestor of all states is required.
+pub struct NWayMerge<'a, E: Element+'a> {
+    // Tips (at least two)
+    tips: Vec<&'a PartState<E>>,
+    // Common ancestor
+    c: &'a PartState<E>,
+    // List of conflicts
+    v: Vec<(EltId, NWayEltMerge<E>)>,
+}
+impl<'a, E: Element> NWayMerge<'a, E> {
+    /// Create an instance. `c` should be a common ancestor state of all
+    /// `tips`. Panics unless at least two tips are given.
+    /// 
+    /// An element is considered a conflict unless all tips have equal
+    /// versions of it (or all lack it).
+    /// 
+    /// Operation is `O(N × T)` where `N` is the number of tips and `T` the
+    /// total number of elements over all tips.
+    pub fn new<'b>(tips: Vec<&'b PartState<E>>, c: &'b PartState<E>) -> NWayMerge<'b, E> {
+        assert!(tips.len() >= 2, "NWayMerge requires at least two states");
+        let mut ids = HashSet::new();
+        for tip in &tips {
+            ids.extend(tip.elts_iter().map(|(id, _)| id));
+        }
+        let mut v: Vec<(EltId, NWayEltMerge<E>)> = Vec::new();
+        for id in ids {
+            let first = tips[0].get_rc(id).ok();
+            if tips[1..].iter().any(|tip| tip.get_rc(id).ok() != first) {
+                v.push((id, NWayEltMerge::Fail));
+            }
+        }
+        // Sort to make solving order deterministic
+        v.sort_by_key(|&(id, _)| id);
+        NWayMerge { tips, c, v }
+    }
+    
+    /// Get the number of states being merged.
+    pub fn num_tips(&self) -> usize { self.tips.len() }
+    
+    /// Run a solver over all still-ambiguous cases. This need not resolve all
+    /// of them.
+    /// 
+    /// Operation is `O(X × N)`.
+    pub fn solve<S>(&mut self, s: &S) where S: NWaySolver<E> {
+        let mut elts = Vec::with_capacity(self.tips.len());
+        for &mut (id, ref mut result) in &mut self.v {
+            if *result == NWayEltMerge::Fail {
+                elts.clear();
+                elts.extend(self.tips.iter().map(|tip| tip.get_rc(id).ok()));
+                *result = s.solve(&elts, self.c.get_rc(id).ok());
+            }
+        }
+    }
+    
+    /// Run a solver. Same as `solve()` but consumes and returns self to allow
+    /// chaining.
+    pub fn solve_inline<S>(mut self, s: &S) -> Self where S: NWaySolver<E> {
+        self.solve(s);
+        self
+    }
+    
+    /// Get the number of conflicts, solved or not.
+    pub fn len(&self) -> usize { self.v.len() }
+    
+    /// True if there are no conflicts.
+    pub fn is_empty(&self) -> bool { self.v.is_empty() }
+    
+    /// Get the current resolution for conflict `i` (where `0 <= i < len()`).
+    /// `NWayEltMerge::Fail` means not-yet-solved.
+    pub fn status(&self, i: usize) -> &(EltId, NWayEltMerge<E>) {
+        &self.v[i]
+    }
+    
+    /// Get the number of unsolved conflicts.
+    pub fn num_unsolved(&self) -> usize {
+        self.v.iter().filter(|(_, result)| *result == NWayEltMerge::Fail).count()
+    }
+    
+    /// Check whether all conflicts have been resolved.
+    pub fn is_solved(&self) -> bool {
+        self.v.iter().all(|(_, result)| *result != NWayEltMerge::Fail)
+    }
+    
+    /// Create a merge commit with all tips as parents.
+    /// 
+    /// This fails with `MergeError::NotSolved` unless `is_solved()` returns
+    /// true, and with `MergeError::TooManyStates` if there are more than 255
+    /// tips (the maximum number of parents of a commit). The commit's
+    /// changes are relative to whichever tip requires the fewest changes;
+    /// this tip is the first parent.
+    /// 
+    /// Operation is `O(X × N)`.
+    pub fn make_commit(self, mcm: &MakeCommitMeta) -> Result<Commit<E>, MergeError> {
+        let n = self.tips.len();
+        if n >= 0x100 {
+            return Err(MergeError::TooManyStates);
+        }
+        let mut changes: Vec<HashMap<EltId, EltChange<E>>> = (0..n).map(|_| HashMap::new()).collect();
+        let mut sums: Vec<Sum> = self.tips.iter()
+                .map(|tip| tip.statesum() ^ &tip.metasum()).collect();
+        let tips = self.tips;
+        
+        for (id, result) in self.v {
+            let target = match result {
+                NWayEltMerge::Tip(j) => {
+                    if j >= n { return Err(MergeError::NotSolved); }
+                    tips[j].get_rc(id).ok().cloned()
+                },
+                NWayEltMerge::Value(elt) => Some(elt),
+                NWayEltMerge::Delete => None,
+                NWayEltMerge::Fail => return Err(MergeError::NotSolved),
+            };
+            for (i, tip) in tips.iter().enumerate() {
+                match (tip.get_rc(id).ok(), target.as_ref()) {
+                    (Some(old), Some(new)) => {
+                        if old != new {
+                            sums[i].permute(&old.sum(id));
+                            sums[i].permute(&new.sum(id));
+                            changes[i].insert(id, EltChange::replacement(new.clone()));
+                        }
+                    },
+                    (Some(old), None) => {
+                        sums[i].permute(&old.sum(id));
+                        changes[i].insert(id, EltChange::deletion());
+                    },
+                    (None, Some(new)) => {
+                        sums[i].permute(&new.sum(id));
+                        changes[i].insert(id, EltChange::insertion(new.clone()));
+                    },
+                    (None, None) => {},
+                }
+            }
+        }
+        assert!(sums.iter().all(|sum| *sum == sums[0])); // sums must be equal
+        
+        let first = (0..n).min_by_key(|&i| changes[i].len()).expect("have tips");
+        trace!("Created {}-way merge from parent: {}", n, tips[first].statesum());
+        let order: Vec<usize> = Some(first).into_iter()
+                .chain((0..n).filter(|&i| i != first)).collect();
+        
+        let parents = order.iter()
+                .map(|&i| (tips[i].statesum(), tips[i].meta())).collect();
+        let meta = CommitMeta::new_parents(parents, mcm);
+        
+        let parents: Vec<Sum> = order.iter().map(|&i| tips[i].statesum().clone()).collect();
+        let statesum = &sums[first] ^ &Sum::state_meta_sum(&parents, &meta);
+        
+        let changes = changes.swap_remove(first);
+        Ok(Commit::new_explicit(statesum, parents, changes, meta))
+    }
+}
+
+/// Return type of an N-way merge solver.
+#[derive(PartialEq, Eq)]
+pub enum NWayEltMerge<E: Element> {
+    /// Use the value (or absence) from the tip with this index
+    Tip(usize),
+    /// Use a custom value (specified in full)
+    Value(Rc<E>),
+    /// Remove the element
+    Delete,
+    /// Give up
+    Fail,
+}
+
+/// Implementations solve N-way merges on an element-by-element basis.
+pub trait NWaySolver<E: Element> {
+    /// This function should take possibly-present elements from each tip
+    /// (in order) and from common ancestor state `c`, which all have the same
+    /// identifier, and return an `NWayEltMerge` object.
+    fn solve<'a>(&self, tips: &[Option<&'a Rc<E>>], c: Option<&'a Rc<E>>) -> NWayEltMerge<E>;
+}
+
+/// Solver which uses the ancestor state: if all tips which changed the
+/// element (relative to the ancestor) agree, their version is used; otherwise
+/// this returns `NWayEltMerge::Fail`.
+/// 
+/// This is the N-way equivalent of `AncestorSolver2W`, but the result does
+/// not depend on the order of tips.
+pub struct AncestorSolverNW<E: Element>{
+    p: PhantomData<E>
+}
+impl<E: Element> AncestorSolverNW<E> {
+    /// Create an instance (requires no parameters)
+    pub fn new() -> Self {
+        AncestorSolverNW { p: PhantomData }
+    }
+}
+impl<E: Element> Default for AncestorSolverNW<E> {
+    fn default() -> Self {
+        AncestorSolverNW::new()
+    }
+}
+impl<E: Element> NWaySolver<E> for AncestorSolverNW<E> {
+    fn solve<'a>(&self, tips: &[Option<&'a Rc<E>>], c: Option<&'a Rc<E>>) -> NWayEltMerge<E> {
+        let mut changed = tips.iter().enumerate().filter(|&(_, elt)| *elt != c);
+        let (i, first) = match changed.next() {
+            Some((i, elt)) => (i, elt),
+            None => return NWayEltMerge::Fail,  // no conflict; shouldn't happen
+        };
+        if changed.all(|(_, elt)| elt == first) {
+            NWayEltMerge::Tip(i)
+        } else {
+            NWayEltMerge::Fail
+        }
+    }
+}
+
+/// Adapts a `TwoWaySolver` for use with `NWayMerge`: the first tip's version
+/// is merged with each other tip's version in turn, using the common ancestor.
+/// 
+/// Unless the two-way solver is symmetric and associative, the result may
+/// depend on the order of tips. `EltMerge::Rename` is not supported and is
+/// treated as failure.
+pub struct TwoWayAdapter<'a, E: Element, S: TwoWaySolver<E>+'a> {
+    s: &'a S,
+    p: PhantomData<E>,
+}
+impl<'a, E: Element, S: TwoWaySolver<E>+'a> TwoWayAdapter<'a, E, S> {
+    /// Create, wrapping a two-way solver
+    pub fn new(s: &'a S) -> TwoWayAdapter<'a, E, S> {
+        TwoWayAdapter { s, p: PhantomData }
+    }
+}
+impl<'a, E: Element, S: TwoWaySolver<E>+'a> NWaySolver<E> for TwoWayAdapter<'a, E, S> {
+    fn solve<'b>(&self, tips: &[Option<&'b Rc<E>>], c: Option<&'b Rc<E>>) -> NWayEltMerge<E> {
+        let mut result = match tips.first() {
+            Some(elt) => elt.cloned(),
+            None => return NWayEltMerge::Fail,
+        };
+        for elt in &tips[1..] {
+            if result.as_ref() == *elt {
+                continue;
+            }
+            result = match self.s.solve(result.as_ref(), *elt, c) {
+                EltMerge::A => result,
+                EltMerge::B => elt.cloned(),
+                EltMerge::Value(v) => Some(v),
+                EltMerge::Delete => None,
+                EltMerge::Rename | EltMerge::Fail => return NWayEltMerge::Fail,
+            };
+        }
+        match result {
+            Some(v) => NWayEltMerge::Value(v),
+            None => NWayEltMerge::Delete,
+        }
+    }
+}
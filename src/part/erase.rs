@@ -0,0 +1,162 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: erasure of element history
+
+use commit::Commit;
+use control::{Control, WrittenFile};
+use elt::EltId;
+use error::{Result, ArgError, TipError};
+use rw::EltReader;
+use rw::audit::AuditOp;
+use rw::compress::{Compression, CompressWriter, compress_file};
+use rw::encrypt::{EncryptWriter, encrypt_file};
+use rw::header::{read_head, write_head};
+use rw::snapshot::{read_snapshot, write_snapshot, write_snapshot_reproducible, write_snapshot_dedup};
+use rw::commitlog::{read_log, start_log, write_commit, LogIndex};
+use state::{PartState, StateRead};
+use sum::Sum;
+
+use super::{Partition, body_reader};
+
+impl<C: Control> Partition<C> {
+    /// Erase all versions of an element's data from history (e.g. to honour
+    /// a data erasure request). Each snapshot and commit log containing data
+    /// of element `id` is rewritten with a *tombstone* in place of the data:
+    /// this records only the element's sum, thus state-sums (and so history)
+    /// remain consistent. States held in memory are erased likewise, and
+    /// states holding the element are removed from the state cache (see
+    /// `Control::state_cache`).
+    /// 
+    /// The element must first be removed from all tips (and this will fail
+    /// otherwise); unsaved commits are written first. Each file is replaced
+    /// atomically (see `RepoIO::replace_ss`); where the `RepoIO` does not
+    /// support this, this fails with `RepoError::ReplaceFailed`. Copies of
+    /// files elsewhere (e.g. backups) are of course not affected.
+    /// 
+    /// Rewritten files use the latest file format version (2026-10-18 or
+    /// later), which older versions of this library cannot read.
+    /// 
+    /// Returns the number of files rewritten.
+    pub fn erase_element_history(&mut self, id: EltId) -> Result<usize> {
+        self.check_writable()?;
+        if !self.is_loaded() {
+            return Err(Box::new(TipError::NotReady));
+        }
+        for tip in &self.tips {
+            if self.states.get(tip).is_some_and(|state| state.is_avail(id)) {
+                return ArgError::err("element must be removed from all tips before erasing its history");
+            }
+        }
+        self.write_fast()?;
+        
+        let limits = self.control.user_meta_limits();
+        let reproducible = self.control.reproducible_snapshots();
+        let mut n_files = 0;
+        for ss in 0..self.control.io().ss_len() {
+            let opt_ss = if let Some(mut r) = self.control.io().read_ss(ss)? {
+                let header = read_head(&mut r)?;
+                let mut r = body_reader(r, &header, self.control.cipher())?;
+                let state: PartState<C::Element> = read_snapshot(&mut r, header.ftype.ver(),
+                        header.dedup, &limits, &mut EltReader::default())?;
+                Some((header, state))
+            } else {
+                None
+            };
+            if let Some((mut header, mut state)) = opt_ss {
+                if state.erase(id) {
+                    let cipher = self.rewrite_cipher(&mut header);
+                    let mut buf = Vec::new();
+                    write_head(&header, &mut buf)?;
+                    {
+                        let mut enc = EncryptWriter::new(&mut buf, &header, cipher);
+                        let mut w = CompressWriter::new(&mut enc, header.compression)?;
+                        if header.dedup {
+                            write_snapshot_dedup(&state, &mut w, reproducible)?;
+                        } else if reproducible {
+                            write_snapshot_reproducible(&state, &mut w)?;
+                        } else {
+                            write_snapshot(&state, &mut w)?;
+                        }
+                        w.finish()?;
+                        enc.finish()?;
+                    }
+                    self.replace_file(ss, None, &buf)?;
+                    self.control.file_written(WrittenFile::Snapshot(ss));
+                    n_files += 1;
+                }
+            }
+            
+            for cl in 0..self.control.io().ss_cl_len(ss) {
+                let opt_cl = if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+                    let header = read_head(&mut r)?;
+                    let mut r = body_reader(r, &header, self.control.cipher())?;
+                    let mut commits: Vec<Commit<C::Element>> = Vec::new();
+                    read_log(&mut r, &mut commits, header.ftype.ver(), &limits, &mut EltReader::default())?;
+                    Some((header, commits))
+                } else {
+                    None
+                };
+                if let Some((mut header, mut commits)) = opt_cl {
+                    let mut erased = false;
+                    for commit in &mut commits {
+                        erased = commit.erase(id) || erased;
+                    }
+                    if erased {
+                        let cipher = self.rewrite_cipher(&mut header);
+                        let mut buf = Vec::new();
+                        write_head(&header, &mut buf)?;
+                        let head_len = buf.len();
+                        start_log(&mut buf)?;
+                        let mut index = LogIndex::new(buf.len() as u64);
+                        for commit in &commits {
+                            let sum = write_commit(commit, &mut buf)?;
+                            index.push(buf.len() as u64, sum);
+                        }
+                        let buf = compress_file(buf, head_len, header.compression)?;
+                        let buf = encrypt_file(buf, head_len, &header, cipher)?;
+                        self.replace_file(ss, Some(cl), &buf)?;
+                        self.control.file_written(WrittenFile::CommitLog(ss, cl));
+                        if header.compression == Compression::None && header.cipher.is_none() {
+                            if let Err(e) = self.write_log_index(ss, cl, &index) {
+                                warn!("Partition {}: failed to write index of log {}-{}: {}",
+                                        self.name, ss, cl, e);
+                            }
+                        }
+                        n_files += 1;
+                    }
+                }
+            }
+        }
+        
+        self.erase_cached(id)?;
+        
+        let keys: Vec<Sum> = self.states.iter()
+                .filter(|state| state.is_avail(id))
+                .map(|state| state.statesum().clone())
+                .collect();
+        for key in keys {
+            let mut state = self.states.remove(&key).expect("state");
+            state.erase(id);
+            self.states.insert(state);
+        }
+        info!("Partition {}: erased history of element {} from {} files", self.name, id, n_files);
+        self.record_audit(AuditOp::EraseElement, format!("element {}; rewrote {} files", id, n_files));
+        Ok(n_files)
+    }
+    
+    // Remove cached states holding element `id` (since these would otherwise
+    // restore its data on load)
+    #[cfg(feature = "file-io")]
+    fn erase_cached(&mut self, id: EltId) -> Result<()> {
+        let limits = self.control.user_meta_limits();
+        if let Some(cache) = self.control.state_cache() {
+            let n = cache.remove_containing::<C::Element>(id, &limits)?;
+            debug!("Partition {}: removed {} cached states holding element {}", self.name, n, id);
+        }
+        Ok(())
+    }
+    #[cfg(not(feature = "file-io"))]
+    fn erase_cached(&mut self, _id: EltId) -> Result<()> { Ok(()) }
+}
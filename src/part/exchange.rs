@@ -0,0 +1,275 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: adoption of files and receipt of commits from other replicas
+
+use std::io::Read;
+use std::fs::File;
+use std::path::Path;
+use std::collections::HashMap;
+
+use commit::{Commit, ReplicaId};
+use control::{Control, WrittenFile};
+use elt::Element;
+use error::{Result, PatchOp, RepoError};
+use rw::{EltReader, LATEST_VERSION};
+use rw::compress::{Compression, compress_file};
+use rw::encrypt::encrypt_file;
+use rw::header::{FileType, read_head, write_head};
+use rw::snapshot::{read_snapshot, read_index_footer, read_index, INDEX_FOOTER_BYTES};
+use rw::commitlog::{read_log, start_log, write_commit};
+use state::PartState;
+use sum::Sum;
+
+use super::{Partition, body_reader};
+
+impl<C: Control> Partition<C> {
+    /// Adopt a snapshot or commit log file received out-of-band (e.g. via
+    /// file-based sync): see `adopt_stream`.
+    pub fn adopt_file<P: AsRef<Path>>(&mut self, path: P) -> Result<WrittenFile> {
+        let mut file = File::open(path)?;
+        self.adopt_stream(&mut file)
+    }
+    
+    
+    /// Adopt a snapshot or commit log received out-of-band. The whole file
+    /// is read and validated without modifying anything: the format version
+    /// must be supported, the repository name must match (and
+    /// `Control::read_header` must accept the header) and all checksums must
+    /// be correct. Commits whose parent state is known (loaded or earlier in
+    /// the log) are also checked to apply correctly.
+    /// 
+    /// Only if valid is the file copied into the `RepoIO`: a snapshot with the
+    /// next free snapshot number, a log with the next free log number of the
+    /// latest snapshot. The file is not loaded; use e.g. `load_latest` for
+    /// this.
+    /// 
+    /// Returns the new file's numbers.
+    pub fn adopt_stream(&mut self, r: &mut Read) -> Result<WrittenFile> {
+        self.adopt_stream_impl(r, None)
+    }
+    
+    
+    /// As `adopt_stream`, but recording that the data was received from
+    /// replica `source`: where a commit log is adopted, provenance (see
+    /// `CommitMeta::provenance`) is added to each commit, with the current
+    /// time (from `MakeCommitMeta::make_commit_timestamp`) as time of
+    /// receipt. The log is therefore rewritten (in the latest format) before
+    /// it is stored. Snapshots are stored unmodified.
+    pub fn adopt_stream_from(&mut self, r: &mut Read, source: ReplicaId) -> Result<WrittenFile> {
+        self.adopt_stream_impl(r, Some(source))
+    }
+    
+    
+    fn adopt_stream_impl(&mut self, r: &mut Read, source: Option<ReplicaId>)
+            -> Result<WrittenFile>
+    {
+        self.check_writable()?;
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        let limits = self.control.user_meta_limits();
+        let mut reader = &data[..];
+        let header = read_head(&mut reader)?;
+        let compression = header.compression;
+        let mut body = Vec::new();
+        if compression != Compression::None || header.cipher.is_some() {
+            body_reader(reader, &header, self.control.cipher())?.read_to_end(&mut body)?;
+            reader = &body[..];
+        }
+        let body_len = reader.len() as u64;
+        let ver = header.ftype.ver();
+        let dedup = header.dedup;
+        let is_snapshot = match header.ftype {
+            FileType::Snapshot(_) => true,
+            FileType::CommitLog(_) => false,
+        };
+        self.verify_header(header)?;
+        
+        let mut sums = Vec::new();
+        if is_snapshot {
+            let state: PartState<C::Element> = read_snapshot(&mut reader, ver, dedup, &limits,
+                    &mut EltReader::default())?;
+            sums.push(state.statesum().clone());
+            // Anything following must be a valid element index (versions
+            // from 20261020; see HEAD_VERSIONS):
+            if !reader.is_empty() {
+                if ver < 2026_10_20 {
+                    return RepoError::err(RepoError::TrailingData);
+                }
+                let n = reader.len();
+                let index_pos = body_len - n as u64;
+                match read_index_footer(&reader[n.saturating_sub(INDEX_FOOTER_BYTES)..]) {
+                    Some((pos, len)) if pos == index_pos && len == body_len => {
+                        read_index(&reader[..n - INDEX_FOOTER_BYTES])?;
+                    },
+                    _ => return RepoError::err(RepoError::TrailingData),
+                }
+            }
+        } else {
+            let mut commits: Vec<Commit<C::Element>> = Vec::new();
+            read_log(&mut reader, &mut commits, ver, &limits, &mut EltReader::default())?;
+            sums.extend(commits.iter().map(|commit| commit.statesum().clone()));
+            if let Some(source) = source {
+                let received = self.control.make_commit_timestamp();
+                for commit in &mut commits {
+                    commit.meta_mut().add_provenance(source, received);
+                }
+            }
+            let mut new_states = HashMap::new();
+            for commit in &commits {
+                let state = match self.states.get(commit.first_parent())
+                        .or_else(|| new_states.get(commit.first_parent()))
+                {
+                    Some(parent) => PartState::from_state_commit(parent, commit)?,
+                    None => continue,
+                };
+                new_states.insert(state.statesum().clone(), state);
+            }
+            if source.is_some() {
+                let mut header = read_head(&mut &data[..])?;
+                let cipher = self.rewrite_cipher(&mut header);
+                let mut buf = Vec::new();
+                write_head(&header, &mut buf)?;
+                let head_len = buf.len();
+                start_log(&mut buf)?;
+                for commit in &commits {
+                    write_commit(commit, &mut buf)?;
+                }
+                data = encrypt_file(compress_file(buf, head_len, compression)?, head_len, &header, cipher)?;
+            }
+        }
+        
+        let file = if is_snapshot {
+            let mut ss = self.control.io().ss_len();
+            loop {
+                if let Some(mut writer) = self.control.io_mut().new_ss(ss)? {
+                    writer.write_all(&data)?;
+                    writer.flush()?;
+                    break;
+                }
+                if ss > 1000_000 {
+                    return RepoError::err(RepoError::SnapshotNumberOverflow { ss_num: ss });
+                }
+                ss += 1;
+            }
+            WrittenFile::Snapshot(ss)
+        } else {
+            let ss = match self.control.io().ss_len() {
+                0 => return RepoError::err(RepoError::NoSnapshot),
+                n => n - 1,
+            };
+            let mut cl = self.control.io().ss_cl_len(ss);
+            loop {
+                if let Some(mut writer) = self.control.io_mut().new_ss_cl(ss, cl)? {
+                    writer.write_all(&data)?;
+                    writer.flush()?;
+                    break;
+                }
+                if cl > 1000_000 {
+                    return RepoError::err(RepoError::LogNumberOverflow { ss_num: ss });
+                }
+                cl += 1;
+            }
+            WrittenFile::CommitLog(ss, cl)
+        };
+        info!("Partition {}: adopted file {:?}", self.name, file);
+        self.control.file_written(file);
+        self.filter_sums(Some(file), &sums);
+        self.save_sum_filter();
+        Ok(file)
+    }
+    
+    
+    /// Receive commits from a stream in the commit log format (the log start
+    /// marker followed by commits, without a file header; see
+    /// `doc/file-format.md`), e.g. as sent by another replica over any
+    /// transport.
+    /// 
+    /// The whole stream is read and checksums verified first; if this fails
+    /// nothing is applied. Each commit is then checked and added as with
+    /// `push_commit`. Commits may be in any order: those whose parent is not
+    /// yet known are retried after others have been added. Commits whose
+    /// parent is never found are deferred (returned in the report, so that
+    /// they may be pushed after loading more history); commits which fail
+    /// to apply or exceed metadata limits are rejected.
+    /// 
+    /// Added commits are written on the next `write_fast` as usual.
+    pub fn receive_commits(&mut self, r: &mut Read) -> Result<ReceiveReport<C::Element>> {
+        self.receive_commits_impl(r, None)
+    }
+    
+    
+    /// As `receive_commits`, but recording replica `source` as provenance of
+    /// each commit (see `CommitMeta::provenance`).
+    pub fn receive_commits_from(&mut self, r: &mut Read, source: ReplicaId)
+            -> Result<ReceiveReport<C::Element>>
+    {
+        self.receive_commits_impl(r, Some(source))
+    }
+    
+    
+    fn receive_commits_impl(&mut self, r: &mut Read, source: Option<ReplicaId>)
+            -> Result<ReceiveReport<C::Element>>
+    {
+        let mut commits: Vec<Commit<C::Element>> = Vec::new();
+        let mut elts = EltReader::new(self.control.elt_read_policy());
+        read_log(r, &mut commits, LATEST_VERSION, &self.control.user_meta_limits(), &mut elts)?;
+        if let Some(source) = source {
+            let received = self.control.make_commit_timestamp();
+            for commit in &mut commits {
+                commit.meta_mut().add_provenance(source, received);
+            }
+        }
+        
+        let mut report = ReceiveReport::default();
+        let mut pending = commits;
+        loop {
+            let mut deferred = Vec::new();
+            let n_pending = pending.len();
+            for commit in pending {
+                if !self.states.contains(commit.first_parent()) {
+                    deferred.push(commit);
+                    continue;
+                }
+                let sum = commit.statesum().clone();
+                match self.push_commit(commit) {
+                    Ok(true) => report.applied.push(sum),
+                    Ok(false) => report.known.push(sum),
+                    Err(e) => report.rejected.push((sum, e)),
+                }
+            }
+            pending = deferred;
+            if pending.is_empty() || pending.len() == n_pending {
+                break;
+            }
+        }
+        report.deferred = pending;
+        info!("Partition {}: received {} commits ({} known, {} deferred, {} rejected)", self.name,
+                report.applied.len(), report.known.len(), report.deferred.len(), report.rejected.len());
+        Ok(report)
+    }
+}
+
+/// Outcome of `Partition::receive_commits`
+#[derive(Debug)]
+pub struct ReceiveReport<E: Element> {
+    /// Sums of commits added
+    pub applied: Vec<Sum>,
+    /// Sums of commits matching already known states (not added)
+    pub known: Vec<Sum>,
+    /// Commits whose parent state is not known (not added)
+    pub deferred: Vec<Commit<E>>,
+    /// Sums of commits which could not be added, with the reason
+    pub rejected: Vec<(Sum, PatchOp)>,
+}
+impl<E: Element> Default for ReceiveReport<E> {
+    fn default() -> Self {
+        ReceiveReport {
+            applied: Vec::new(),
+            known: Vec::new(),
+            deferred: Vec::new(),
+            rejected: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: history queries (log, ancestry, diffs and element history)
+
+use std::collections::{HashMap, BinaryHeap};
+use std::rc::Rc;
+
+use commit::{Commit, CommitMeta, EltChange};
+use control::Control;
+use elt::{Element, EltId};
+use error::{Result, ArgError, RepoError};
+use rw::commitlog::Recovery;
+use state::{PartState, StateRead};
+use sum::Sum;
+
+use super::Partition;
+
+impl<C: Control> Partition<C> {
+    /// Iterate over the history of a state: the state `from_tip` followed by
+    /// its loaded ancestors, in topological order (each state precedes all
+    /// of its ancestors; otherwise higher commit numbers come first).
+    /// 
+    /// Only loaded states are visited; use `load_all` for full history.
+    /// Fails if `from_tip` is not loaded.
+    pub fn log_iter(&self, from_tip: &Sum) -> Result<LogIter<C::Element>> {
+        if !self.states.contains(from_tip) {
+            return ArgError::err("state not loaded");
+        }
+        let ancestors = self.loaded_ancestors(from_tip);
+        // Count children of each state within the walk:
+        let mut children: HashMap<&Sum, usize> = HashMap::new();
+        for sum in &ancestors {
+            for parent in self.states.get(sum).unwrap().parents() {
+                if ancestors.contains(parent) {
+                    *children.entry(parent).or_insert(0) += 1;
+                }
+            }
+        }
+        // A state is ready once all its children have been listed:
+        let mut ready = BinaryHeap::new();
+        let state = self.states.get(from_tip).unwrap();
+        ready.push((state.meta().number(), state.statesum()));
+        let mut states = Vec::with_capacity(ancestors.len());
+        while let Some((_, sum)) = ready.pop() {
+            let state = self.states.get(sum).unwrap();
+            states.push(state);
+            for parent in state.parents() {
+                if let Some(n) = children.get_mut(parent) {
+                    *n -= 1;
+                    if *n == 0 {
+                        let p = self.states.get(parent).unwrap();
+                        ready.push((p.meta().number(), p.statesum()));
+                    }
+                }
+            }
+        }
+        Ok(LogIter { iter: states.into_iter() })
+    }
+    
+    
+    /// Iterate over the loaded ancestors of state `sum` (not including the
+    /// state itself), in the same order as `log_iter`.
+    /// 
+    /// Fails if `sum` is not loaded.
+    pub fn ancestors(&self, sum: &Sum) -> Result<LogIter<C::Element>> {
+        let mut iter = self.log_iter(sum)?;
+        iter.next();
+        Ok(iter)
+    }
+    
+    
+    /// Get the state as of time `time` (seconds since the UNIX epoch; see
+    /// `CommitMeta::timestamp`): the latest state whose commit timestamp is
+    /// at or before `time`, following first parents from the tip.
+    /// 
+    /// The latest state is loaded if nothing is loaded, and older snapshots
+    /// are loaded as required. Fails if there is not a single tip, or if no
+    /// such state is available (e.g. `time` precedes the creation of the
+    /// partition or older history was removed, see `gc`).
+    pub fn state_at(&mut self, time: i64) -> Result<&PartState<C::Element>> {
+        if !self.is_loaded() {
+            self.load_latest()?;
+        }
+        let found = loop {
+            let mut sum = self.tip_key()?.clone();
+            let missing = loop {
+                let state = self.states.get(&sum).unwrap();
+                if state.meta().timestamp() <= time {
+                    break None;
+                }
+                match state.parents().first() {
+                    Some(parent) if self.states.contains(parent) => sum = parent.clone(),
+                    Some(parent) => break Some(parent.clone()),
+                    None => return RepoError::err(RepoError::NoStateAt { timestamp: time }),
+                }
+            };
+            if missing.is_none() {
+                break sum;
+            }
+            if self.ss0 == 0 {
+                return RepoError::err(RepoError::NoStateAt { timestamp: time });
+            }
+            // Load the previous snapshot and retry
+            let ss0 = self.ss0;
+            debug!("Partition {}: loading snapshot {} for state_at", self.name, ss0 - 1);
+            self.load_range_impl(ss0 - 1, ss0, Recovery::Strict, false)?;
+            if self.ss0 == ss0 {
+                return RepoError::err(RepoError::NoStateAt { timestamp: time });
+            }
+        };
+        Ok(self.states.get(&found).unwrap())
+    }
+    
+    
+    /// Get the changes needed to go from state `sum_a` to state `sum_b`
+    /// (as a commit from `sum_a` to `sum_b` would contain), sorted by
+    /// element identifier. States need not be related.
+    /// 
+    /// Fails if either state is not loaded.
+    pub fn diff(&self, sum_a: &Sum, sum_b: &Sum) -> Result<Vec<(EltId, EltChange<C::Element>)>> {
+        let (a, b) = match (self.states.get(sum_a), self.states.get(sum_b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return ArgError::err("state not loaded"),
+        };
+        let mut changes: Vec<_> = Commit::from_diff(a, b)
+                .map_or_else(HashMap::new, |c| c.into_changes())
+                .into_iter().collect();
+        changes.sort_by_key(|&(id, _)| id);
+        Ok(changes)
+    }
+    
+    
+    /// Iterate over changes to element `id` across loaded history: for each
+    /// loaded state in which the element differs from that of the first
+    /// parent, yield the state-sum, commit metadata and the new version
+    /// (`None` where the element was removed). States whose first parent is
+    /// not loaded are included where they have the element.
+    /// 
+    /// Items are ordered by commit number (oldest first), then state-sum.
+    /// Only loaded states are visited; use `load_all` for full history.
+    pub fn elt_history(&self, id: EltId) -> EltHistory<C::Element> {
+        let mut states: Vec<_> = self.states.iter().collect();
+        states.sort_by_key(|state| (state.meta().number(), state.statesum()));
+        let mut items = Vec::new();
+        for state in states {
+            let elt = state.get_rc(id).ok();
+            let changed = match state.parents().first().and_then(|p| self.states.get(p)) {
+                Some(parent) => elt != parent.get_rc(id).ok(),
+                None => elt.is_some(),
+            };
+            if changed {
+                items.push((state.statesum(), state.meta(), elt.cloned()));
+            }
+        }
+        EltHistory { iter: items.into_iter() }
+    }
+    
+}
+
+/// Iterator over a state and its ancestors (see `Partition::log_iter`)
+pub struct LogIter<'a, E: Element+'a> {
+    iter: ::std::vec::IntoIter<&'a PartState<E>>,
+}
+impl<'a, E: Element+'a> Iterator for LogIter<'a, E> {
+    type Item = &'a PartState<E>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+impl<'a, E: Element+'a> ExactSizeIterator for LogIter<'a, E> {}
+
+/// Iterator over changes to an element (see `Partition::elt_history`)
+pub struct EltHistory<'a, E: Element+'a> {
+    iter: ::std::vec::IntoIter<(&'a Sum, &'a CommitMeta, Option<Rc<E>>)>,
+}
+impl<'a, E: Element+'a> Iterator for EltHistory<'a, E> {
+    type Item = (&'a Sum, &'a CommitMeta, Option<Rc<E>>);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+impl<'a, E: Element+'a> ExactSizeIterator for EltHistory<'a, E> {}
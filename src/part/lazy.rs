@@ -0,0 +1,174 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: reading elements on demand, without loading a state
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use control::Control;
+use elt::{EltId, EltIdRange};
+use error::{Result, RepoError};
+use rw::EltReader;
+use rw::header::read_head;
+use rw::snapshot::{read_snapshot, read_index_footer, read_index, read_element_head, read_element,
+        INDEX_FOOTER_BYTES, ELEMENT_HEAD_BYTES};
+use state::{PartState, StateRead};
+
+use super::{Partition, HeaderInfo, LazyIndex, body_reader};
+
+impl<C: Control> Partition<C> {
+    /// Prepare to read single elements on demand (see `get_elt_on_demand`)
+    /// without loading any state: only the header and element index of the
+    /// latest snapshot are read; the index is kept in memory.
+    /// 
+    /// Snapshots with fewer than `INDEX_MIN_ELTS` elements (see
+    /// `rw::snapshot`), compressed or deduplicated snapshots and those of
+    /// file format versions before 2026 10 20 have no element index; in this
+    /// case the whole snapshot is read and its elements kept in memory
+    /// instead (but not loaded as a state).
+    /// 
+    /// On-demand reads see only the snapshot, not changes in commit logs
+    /// following it. Returns true if no commit log follows the snapshot (thus
+    /// on-demand reads see the latest state).
+    pub fn load_lazy(&mut self) -> Result<bool> {
+        let mut ss = self.control.io().ss_len();
+        while ss > 0 && !self.control.io().has_ss(ss - 1) { ss -= 1; }
+        if ss == 0 {
+            return RepoError::err(RepoError::NoSnapshot);
+        }
+        let ss = ss - 1;
+        
+        let header = match self.control.io().read_ss(ss)? {
+            Some(mut r) => read_head(&mut r)?,
+            None => return RepoError::err(RepoError::NoSnapshot),
+        };
+        if header.cipher.is_some() {
+            return RepoError::err(RepoError::SnapshotEncrypted { ss_num: ss });
+        }
+        if self.header.as_ref().is_none_or(|info| info.ss <= ss) {
+            self.header = Some(HeaderInfo::new(ss, &header));
+        }
+        // versions from 20261020 may have an element index (see HEAD_VERSIONS)
+        let has_index = header.ftype.ver() >= 2026_10_20;
+        self.verify_header(header)?;
+        
+        let size = self.control.io().ss_size(ss)?.unwrap_or(0);
+        let footer = if has_index && size >= INDEX_FOOTER_BYTES as u64 {
+            self.control.io().read_ss_range(ss, size - INDEX_FOOTER_BYTES as u64,
+                    INDEX_FOOTER_BYTES)?
+        } else { None };
+        let (index_pos, len) = match footer.as_ref().and_then(|footer| read_index_footer(footer)) {
+            Some((index_pos, len)) if len <= size => (index_pos, len),
+            _ => {
+                debug!("Partition {}: snapshot {} has no element index; reading in full",
+                        self.name, ss);
+                let state = self.read_snapshot_state(ss)?;
+                self.lazy = Some(LazyIndex::Full(state));
+                return Ok(self.control.io().ss_cl_len(ss) == 0);
+            },
+        };
+        let start = size - len;
+        let index_len = (len - index_pos) as usize - INDEX_FOOTER_BYTES;
+        let data = match self.control.io().read_ss_range(ss, start + index_pos, index_len)? {
+            Some(data) => data,
+            None => return RepoError::err(RepoError::SnapshotNotFound { ss_num: ss }),
+        };
+        let offsets = read_index(&data)?;
+        debug!("Partition {}: read index of snapshot {} ({} elements)", self.name, ss,
+                offsets.len());
+        self.lazy = Some(LazyIndex::Index { ss, start, offsets });
+        Ok(self.control.io().ss_cl_len(ss) == 0)
+    }
+    
+    
+    // Read the state of snapshot `ss`, without adding it to the partition
+    fn read_snapshot_state(&self, ss: usize) -> Result<PartState<C::Element>> {
+        let mut r = match self.control.io().read_ss(ss)? {
+            Some(r) => r,
+            None => return RepoError::err(RepoError::SnapshotNotFound { ss_num: ss }),
+        };
+        let header = read_head(&mut r)?;
+        let mut r = body_reader(r, &header, self.control.cipher())?;
+        read_snapshot(&mut r, header.ftype.ver(), header.dedup,
+                &self.control.user_meta_limits(), &mut EltReader::default())
+    }
+    
+    
+    /// Get an element, reading only that element from the snapshot if
+    /// necessary.
+    /// 
+    /// If the partition is ready (has a single tip), the element is taken
+    /// from the tip. Otherwise, if `load_lazy` was called, the element's
+    /// record is read from the snapshot and its checksum verified. Otherwise
+    /// this fails.
+    /// 
+    /// Returns `None` if the element is not present.
+    pub fn get_elt_on_demand(&self, id: EltId) -> Result<Option<Rc<C::Element>>> {
+        if self.tips.len() == 1 {
+            return Ok(self.tip()?.get_rc(id).ok().cloned());
+        }
+        match self.lazy {
+            Some(LazyIndex::Index { ss, start, ref offsets }) => match offsets.get(&id) {
+                Some(pos) => self.read_lazy_elt(ss, start + *pos, id).map(Some),
+                None => Ok(None),
+            },
+            Some(LazyIndex::Full(ref state)) => Ok(state.get_rc(id).ok().cloned()),
+            None => RepoError::err(RepoError::NotLazyLoaded),
+        }
+    }
+    
+    
+    /// Read all elements whose identifiers are in `range` from the snapshot
+    /// prepared by `load_lazy`, e.g. to serve one shard of a partition
+    /// without loading the whole state. Only the selected element records
+    /// are read (located via the snapshot's element index), and each
+    /// record's checksum is verified.
+    /// 
+    /// As with `get_elt_on_demand`, changes in commit logs following the
+    /// snapshot are not seen. Fails if `load_lazy` was not called.
+    pub fn read_snapshot_filtered(&self, range: EltIdRange)
+            -> Result<HashMap<EltId, Rc<C::Element>>>
+    {
+        let (ss, start, offsets) = match self.lazy {
+            Some(LazyIndex::Index { ss, start, ref offsets }) => (ss, start, offsets),
+            Some(LazyIndex::Full(ref state)) => {
+                return Ok(state.elts_iter().filter(|&(id, _)| range.contains(id))
+                        .map(|(id, elt)| (id, elt.clone())).collect());
+            },
+            None => return RepoError::err(RepoError::NotLazyLoaded),
+        };
+        if range.first > range.last {
+            return Ok(HashMap::new());
+        }
+        let mut records: Vec<(u64, EltId)> = offsets.range(range.first..=range.last)
+                .map(|(id, pos)| (*pos, *id))
+                .collect();
+        // read in file order:
+        records.sort();
+        let mut elts = HashMap::with_capacity(records.len());
+        for (pos, id) in records {
+            elts.insert(id, self.read_lazy_elt(ss, start + pos, id)?);
+        }
+        Ok(elts)
+    }
+    
+    
+    // Read element `id`, whose record is at `pos`, from snapshot `ss`
+    fn read_lazy_elt(&self, ss: usize, pos: u64, id: EltId) -> Result<Rc<C::Element>> {
+        let io = self.control.io();
+        let (head, data) = match io.read_ss_range(ss, pos, ELEMENT_HEAD_BYTES)? {
+            Some(head) => {
+                let len = read_element_head(id, &head)?;
+                match io.read_ss_range(ss, pos + ELEMENT_HEAD_BYTES as u64, len)? {
+                    Some(data) => (head, data),
+                    None => return RepoError::err(RepoError::SnapshotNotFound { ss_num: ss }),
+                }
+            },
+            None => return RepoError::err(RepoError::SnapshotNotFound { ss_num: ss }),
+        };
+        Ok(Rc::new(read_element(id, &head, &data)?))
+    }
+    
+}
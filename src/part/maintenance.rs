@@ -0,0 +1,589 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: partition maintenance (removal and compaction of history,
+//! archiving)
+
+use std::collections::{HashMap, HashSet};
+use std::cmp::{min, max};
+
+use commit::Commit;
+use control::{Control, WrittenFile};
+use elt::Element;
+use error::{Result, RepoError, ReadOnly};
+use rw::EltReader;
+use rw::audit::AuditOp;
+use rw::compress::{Compression, compress_file};
+use rw::encrypt::encrypt_file;
+use rw::header::{FileType, read_head, write_head};
+use rw::snapshot::read_snapshot;
+use rw::commitlog::{read_log, start_log, write_commit, LogIndex};
+use state::PartState;
+use sum::Sum;
+
+use super::{Partition, body_reader};
+
+impl<C: Control> Partition<C> {
+    /// Summarise the status of this partition, for exposure via a service
+    /// health check. This is cheap: nothing is read except as required by
+    /// `RepoIO::check_status`.
+    pub fn health(&self) -> PartitionHealth {
+        PartitionHealth {
+            loaded: self.is_loaded(),
+            tips: self.tips.len(),
+            unsaved: self.unsaved.len(),
+            read_only: self.read_only,
+            last_log_write: self.last_log_write,
+            last_snapshot_write: self.last_ss_write,
+            io_error: self.control.io().check_status().err().map(|e| e.to_string()),
+        }
+    }
+    
+    /// Get suggestions for tuning, based on usage since this partition was
+    /// created or opened. Returns an empty list if nothing is suggested.
+    /// 
+    /// Currently this reports high write amplification (see
+    /// `WriteStats::amplification`), attributing it to snapshots (see
+    /// `Control::snapshot_policy`) or to small commit logs (suggesting
+    /// fewer calls to `write_fast`). Nothing is suggested for archived
+    /// partitions.
+    pub fn maintenance_advice(&self) -> Vec<String> {
+        let mut advice = Vec::new();
+        if self.archived {
+            return advice;
+        }
+        let stats = &self.stats;
+        if let Some(amp) = stats.amplification() {
+            if amp > ADVISE_AMPLIFICATION {
+                advice.push(format!("high write amplification: {:.1} bytes written per \
+                        byte changed ({:.1} from snapshots, {:.1} from commit logs)",
+                        amp, stats.snapshot_amplification().unwrap_or(0.0),
+                        stats.log_amplification().unwrap_or(0.0)));
+                if stats.snapshot_bytes > stats.log_bytes {
+                    advice.push("snapshots dominate writes; consider a snapshot policy \
+                            which snapshots less often".to_string());
+                } else if stats.logs > 0 && stats.commits / stats.logs < 2 {
+                    advice.push("commit logs hold few commits each; consider writing \
+                            (write_fast / write_full) less often".to_string());
+                }
+            }
+        }
+        advice
+    }
+    
+    /// Rewrite the partition compactly. This is intended for scheduled
+    /// maintenance and does the following:
+    /// 
+    /// 1.  write all unsaved commits, then a fresh snapshot from the tip
+    /// 2.  drop all states except the tip from memory (history may be
+    ///     reloaded via `load_range` so long as the files are retained)
+    /// 3.  remove all snapshot and commit log files from before the new
+    ///     snapshot except those belonging to the last `keep` snapshots
+    /// 
+    /// As with `gc`, older files holding commits which are not ancestors of
+    /// the new snapshot or retained files (e.g. an unmerged branch) are kept,
+    /// along with all later files. Files are only removed if the `RepoIO`
+    /// supports removal. Snapshot numbers are not compacted, thus `ss_len()`
+    /// does not decrease (see `doc/enhancements.md` for why).
+    /// 
+    /// Fails when not ready (see `tip()`). Returns the number of files
+    /// removed. Archived partitions (see `archive`) are skipped, returning 0.
+    pub fn vacuum(&mut self, keep: usize) -> Result<usize> {
+        self.check_writable()?;
+        if self.archived {
+            return Ok(0);
+        }
+        // fail early if not ready:
+        self.tip_key()?;
+        self.write_fast()?;
+        self.write_snapshot()?;
+        
+        let ss_new = self.ss1 - 1;
+        self.evict_states(|_| true);
+        self.ss0 = ss_new;
+        
+        let keep_from = self.gc_keep_from(ss_new.saturating_sub(keep))?;
+        let mut n_removed = 0;
+        for ss in 0..keep_from {
+            // Remove in reverse order since some `RepoIO`s renumber logs
+            for cl in (0..self.control.io().ss_cl_len(ss)).rev() {
+                if self.control.io_mut().remove_ss_cl(ss, cl)? {
+                    n_removed += 1;
+                }
+            }
+            if self.control.io_mut().remove_ss(ss)? {
+                n_removed += 1;
+            }
+        }
+        debug!("Partition {}: vacuum removed {} files", self.name, n_removed);
+        self.record_audit(AuditOp::Vacuum, format!("keep {}; new snapshot {}; removed {} files",
+                keep, ss_new, n_removed));
+        Ok(n_removed)
+    }
+    
+    /// Remove snapshot and commit log files no longer needed to reconstruct
+    /// the states retained by `policy` (see `GcPolicy`). Unlike `vacuum`,
+    /// nothing is written: a snapshot is retained from which the oldest
+    /// retained state can be reconstructed, and all older snapshots and
+    /// their commit logs are removed. The latest snapshot is always retained.
+    /// 
+    /// Older files are only removed if every commit they hold is an ancestor
+    /// of some retained state: if an old commit log holds a commit which was
+    /// never merged (e.g. an abandoned branch), that log's snapshot and all
+    /// later files are retained (all old files are read to determine this).
+    /// 
+    /// States held in memory are not dropped, but history older than the
+    /// oldest retained snapshot can no longer be loaded. Files are only
+    /// removed if the `RepoIO` supports removal (see `RepoIO::remove_ss`).
+    /// Note that replicas which have not yet acknowledged removed history
+    /// (see `latest_fully_acked`) may be unable to merge with this one.
+    /// 
+    /// Returns the number of files removed. Archived partitions (see
+    /// `archive`) are skipped, returning 0.
+    pub fn gc(&mut self, policy: GcPolicy) -> Result<usize> {
+        self.check_writable()?;
+        if self.archived {
+            return Ok(0);
+        }
+        let ss_len = self.control.io().ss_len();
+        let keep_from = match policy {
+            GcPolicy::KeepSnapshots(n) => ss_len.saturating_sub(max(n, 1)),
+            GcPolicy::KeepSince(time) => {
+                let mut keep_from = 0;
+                for ss in (0..ss_len).rev() {
+                    if self.control.io().has_ss(ss) &&
+                        self.read_ss_state(ss)?.meta().timestamp() <= time
+                    {
+                        keep_from = ss;
+                        break;
+                    }
+                }
+                keep_from
+            },
+        };
+        let keep_from = self.gc_keep_from(keep_from)?;
+        
+        let mut n_removed = 0;
+        for ss in 0..keep_from {
+            // Remove in reverse order since some `RepoIO`s renumber logs
+            for cl in (0..self.control.io().ss_cl_len(ss)).rev() {
+                if self.control.io_mut().remove_ss_cl(ss, cl)? {
+                    n_removed += 1;
+                }
+            }
+            if self.control.io_mut().remove_ss(ss)? {
+                n_removed += 1;
+            }
+        }
+        if self.ss1 > self.ss0 && self.ss0 < keep_from {
+            self.ss0 = keep_from;
+        }
+        debug!("Partition {}: gc removed {} files before snapshot {}",
+                self.name, n_removed, keep_from);
+        self.record_audit(AuditOp::Gc, format!("{:?}; removed {} files before snapshot {}",
+                policy, n_removed, keep_from));
+        Ok(n_removed)
+    }
+    
+    /// Compact history: for each snapshot `ss` with `ss0 <= ss < ss1`, where
+    /// the commit logs of `ss` hold a linear history leading from snapshot
+    /// `ss` to snapshot `ss + 1`, replace these logs with a single log
+    /// holding one *squashed* commit (see `Commit::new_squash`).
+    /// 
+    /// State-sums of snapshots and of the squashed state are unchanged, but
+    /// intermediate states (and their metadata) are lost. Snapshots whose
+    /// logs branch, contain commits not leading to the next snapshot or
+    /// contain fewer than two commits are skipped, as is the latest
+    /// snapshot. Unsaved commits are written first.
+    /// 
+    /// The new log is written before the old ones are removed; the `RepoIO`
+    /// must support removing files (see `RepoIO::remove_ss_cl`), otherwise
+    /// this fails with `ReadOnly`. New logs use the latest file format
+    /// version (2026-10-21 or later), which older versions of this library
+    /// cannot read.
+    /// 
+    /// Returns the number of commits removed (replaced commits minus those
+    /// written). Archived partitions (see `archive`) are skipped, returning 0.
+    /// Failure to read a log is reported as `RepoError::InFile`.
+    pub fn compact_history(&mut self, ss0: usize, ss1: usize) -> Result<usize> {
+        self.compact_history_with(ss0, ss1, CompactMode::Squash)
+    }
+    
+    /// As `compact_history`, with the given `mode`. With
+    /// `CompactMode::ElideEmptyMerges`, only empty merge commits (and the
+    /// branches they join) are removed from history, keeping the graph
+    /// small for partitions with frequent automatic merges while retaining
+    /// other commits and their metadata.
+    pub fn compact_history_with(&mut self, ss0: usize, ss1: usize, mode: CompactMode)
+            -> Result<usize>
+    {
+        self.check_writable()?;
+        if self.archived {
+            return Ok(0);
+        }
+        self.write_fast()?;
+        let limits = self.control.user_meta_limits();
+        let ss1 = min(ss1, self.control.io().ss_len().saturating_sub(1));
+        let mut n_removed = 0;
+        for ss in ss0..ss1 {
+            let n_logs = self.control.io().ss_cl_len(ss);
+            if !self.control.io().has_ss(ss) || !self.control.io().has_ss(ss + 1) {
+                continue;
+            }
+            
+            let mut commits: Vec<Commit<C::Element>> = Vec::new();
+            let mut complete = true;
+            for cl in 0..n_logs {
+                if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+                    let cipher = self.control.cipher();
+                    let result = (|| -> Result<()> {
+                        let header = read_head(&mut r)?;
+                        let mut r = body_reader(r, &header, cipher)?;
+                        read_log(&mut r, &mut commits, header.ftype.ver(), &limits,
+                                &mut EltReader::default())
+                    })();
+                    result.map_err(|e| RepoError::in_file(ss, Some(cl), e))?;
+                } else {
+                    complete = false;
+                }
+            }
+            if !complete || commits.len() < 2 {
+                continue;
+            }
+            let base = self.read_ss_state(ss)?;
+            let target = self.read_ss_state(ss + 1)?;
+            
+            // Replay, checking that all commits lead to the target:
+            let mut replayed: HashMap<Sum, PartState<C::Element>> = HashMap::new();
+            let mut leaves = HashSet::new();
+            leaves.insert(base.statesum().clone());
+            let mut linear = true;
+            for commit in &commits {
+                let state = {
+                    let parent = if commit.first_parent() == base.statesum() {
+                        &base
+                    } else if let Some(state) = replayed.get(commit.first_parent()) {
+                        state
+                    } else {
+                        linear = false;
+                        break;
+                    };
+                    PartState::from_state_commit(parent, commit)?
+                };
+                leaves.remove(commit.first_parent());
+                for parent in commit.parents() {
+                    leaves.remove(parent);
+                }
+                leaves.insert(state.statesum().clone());
+                replayed.insert(state.statesum().clone(), state);
+            }
+            if !linear || leaves.len() != 1 || !leaves.contains(target.statesum()) {
+                debug!("Partition {}: not compacting logs of snapshot {}", self.name, ss);
+                continue;
+            }
+            
+            let n_commits = commits.len();
+            let new_commits = match mode {
+                CompactMode::Squash => vec![Commit::new_squash(&base, &target)],
+                CompactMode::ElideEmptyMerges => {
+                    match elide_empty_merges(&base, target.statesum(), commits, &replayed) {
+                        Some(new_commits) => new_commits,
+                        None => continue,
+                    }
+                },
+            };
+            for commit in &new_commits {
+                if let Some(squash_base) = commit.squash_base() {
+                    let parent = replayed.get(squash_base).unwrap_or(&base);
+                    if PartState::from_state_commit(parent, commit)?.statesum() != commit.statesum() {
+                        return RepoError::err(RepoError::SquashMismatch);
+                    }
+                }
+            }
+            
+            let mut buf = Vec::new();
+            let header = self.make_header(FileType::CommitLog(0))?;
+            write_head(&header, &mut buf)?;
+            let head_len = buf.len();
+            start_log(&mut buf)?;
+            let mut index = LogIndex::new(buf.len() as u64);
+            for commit in &new_commits {
+                let sum = write_commit(commit, &mut buf)?;
+                index.push(buf.len() as u64, sum);
+            }
+            let buf = compress_file(buf, head_len, header.compression)?;
+            let buf = encrypt_file(buf, head_len, &header, self.control.cipher())?;
+            match self.control.io_mut().new_ss_cl(ss, n_logs)? {
+                Some(mut w) => {
+                    w.write_all(&buf)?;
+                    w.flush()?;
+                },
+                None => return RepoError::err(RepoError::LogExists { ss_num: ss, cl_num: n_logs }),
+            }
+            self.control.file_written(WrittenFile::CommitLog(ss, n_logs));
+            if header.compression == Compression::None && header.cipher.is_none() {
+                if let Err(e) = self.write_log_index(ss, n_logs, &index) {
+                    warn!("Partition {}: failed to write index of log {}-{}: {}",
+                            self.name, ss, n_logs, e);
+                }
+            }
+            // Remove in reverse order since some `RepoIO`s renumber logs
+            for cl in (0..n_logs).rev() {
+                if !self.control.io_mut().remove_ss_cl(ss, cl)? {
+                    return ReadOnly::err();
+                }
+            }
+            self.sum_filter.uncover_logs(ss);
+            for cl in 0..self.control.io().ss_cl_len(ss) {
+                self.filter_sums(Some(WrittenFile::CommitLog(ss, cl)),
+                        new_commits.iter().map(|c| c.statesum()));
+            }
+            self.save_sum_filter();
+            
+            for commit in &new_commits {
+                self.record_squash(commit);
+            }
+            info!("Partition {}: compacted {} commits of snapshot {} to {}",
+                    self.name, n_commits, ss, new_commits.len());
+            n_removed += n_commits - new_commits.len();
+        }
+        if n_removed > 0 {
+            self.record_audit(AuditOp::CompactHistory, format!("snapshots {}..{}; {:?}; removed {} commits",
+                    ss0, ss1, mode, n_removed));
+        }
+        Ok(n_removed)
+    }
+    
+    /// Archive the partition: write unsaved commits and a final snapshot
+    /// marking the partition archived, then release all data from memory.
+    /// 
+    /// While archived (including when opened later), data is not read by
+    /// `open` or `load_auto`, maintenance (`vacuum`, `gc`, `compact_history`
+    /// and `maintenance_advice`) does nothing and adding commits fails with
+    /// `PatchOp::Archived`. Data may still be read explicitly (e.g. via
+    /// `load_latest`). See `unarchive`.
+    /// 
+    /// The latest state is loaded first if nothing is loaded. Fails if a merge
+    /// is required or if read-only. Returns false if already archived.
+    pub fn archive(&mut self) -> Result<bool> {
+        self.check_writable()?;
+        if self.archived {
+            return Ok(false);
+        }
+        if !self.is_loaded() {
+            self.load_latest()?;
+        }
+        let tip = self.tip_key()?.clone();
+        self.write_fast()?;
+        self.archived = true;
+        if let Err(e) = self.write_snapshot_of(&tip, None) {
+            self.archived = false;
+            return Err(e);
+        }
+        let ss = self.ss1 - 1;
+        info!("Partition {}: archived at snapshot {}", self.name, ss);
+        self.record_audit(AuditOp::Archive, format!("snapshot {}", ss));
+        self.unload(true);
+        // Nothing is loaded; the next load starts from the latest snapshot
+        self.ss0 = self.ss1;
+        self.lazy = None;
+        Ok(true)
+    }
+    
+    /// Reverse `archive`: load the latest state and write a snapshot without
+    /// the archived mark, after which the partition may be used as usual.
+    /// 
+    /// Fails if read-only. Returns false if not archived.
+    pub fn unarchive(&mut self) -> Result<bool> {
+        self.check_writable()?;
+        if !self.archived {
+            return Ok(false);
+        }
+        if !self.is_loaded() {
+            self.load_latest()?;
+        }
+        let tip = self.tip_key()?.clone();
+        self.archived = false;
+        if let Err(e) = self.write_snapshot_of(&tip, None) {
+            self.archived = true;
+            return Err(e);
+        }
+        let ss = self.ss1 - 1;
+        info!("Partition {}: unarchived at snapshot {}", self.name, ss);
+        self.record_audit(AuditOp::Unarchive, format!("snapshot {}", ss));
+        Ok(true)
+    }
+    
+    /// True if the partition is archived (see `archive`). This is known from
+    /// the header of the latest snapshot read (also by `open` without
+    /// reading data).
+    pub fn is_archived(&self) -> bool {
+        self.archived
+    }
+    
+    // Lower `keep_from` to retain any old commit which is not an ancestor of
+    // a state recorded in snapshot `keep_from` or later files (see `gc`).
+    fn gc_keep_from(&self, keep_from: usize) -> Result<usize> {
+        if keep_from == 0 {
+            return Ok(0);
+        }
+        let limits = self.control.user_meta_limits();
+        let mut parents: HashMap<Sum, Vec<Sum>> = HashMap::new();
+        // Statesums of commits in old logs, with snapshot number:
+        let mut old_commits: Vec<(usize, Sum)> = Vec::new();
+        // Statesums recorded in retained files:
+        let mut retained: Vec<Sum> = Vec::new();
+        for ss in 0..self.control.io().ss_len() {
+            if self.control.io().has_ss(ss) {
+                let state = self.read_ss_state(ss)?;
+                if ss >= keep_from {
+                    retained.push(state.statesum().clone());
+                }
+                parents.insert(state.statesum().clone(), state.parents().to_vec());
+            }
+            for cl in 0..self.control.io().ss_cl_len(ss) {
+                if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+                    let header = read_head(&mut r)?;
+                    let mut r = body_reader(r, &header, self.control.cipher())?;
+                    let mut commits: Vec<Commit<C::Element>> = Vec::new();
+                    read_log(&mut r, &mut commits, header.ftype.ver(), &limits,
+                            &mut EltReader::default())?;
+                    for commit in commits {
+                        if ss >= keep_from {
+                            retained.push(commit.statesum().clone());
+                        } else {
+                            old_commits.push((ss, commit.statesum().clone()));
+                        }
+                        parents.insert(commit.statesum().clone(), commit.parents().to_vec());
+                    }
+                }
+            }
+        }
+        
+        let mut reached = HashSet::new();
+        while let Some(sum) = retained.pop() {
+            if reached.insert(sum.clone()) {
+                if let Some(p) = parents.get(&sum) {
+                    retained.extend(p.iter().cloned());
+                }
+            }
+        }
+        Ok(old_commits.iter()
+                .filter(|&&(_, ref sum)| !reached.contains(sum))
+                .map(|&(ss, _)| ss)
+                .min()
+                .map_or(keep_from, |ss| {
+                    debug!("Partition {}: gc retaining unmerged commits from snapshot {}",
+                            self.name, ss);
+                    ss
+                }))
+    }
+    
+    // Read the state of snapshot `ss`
+    fn read_ss_state(&self, ss: usize) -> Result<PartState<C::Element>> {
+        match self.control.io().read_ss(ss)? {
+            Some(mut r) => {
+                let header = read_head(&mut r)?;
+                let mut r = body_reader(r, &header, self.control.cipher())?;
+                read_snapshot(&mut r, header.ftype.ver(), header.dedup, &self.control.user_meta_limits(),
+                        &mut EltReader::default())
+            },
+            None => RepoError::err(RepoError::SnapshotNotFound { ss_num: ss }),
+        }
+    }
+}
+
+/// Which history to retain when removing old files (see `Partition::gc`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GcPolicy {
+    /// Retain the last `n` snapshots (at least one) and their commit logs.
+    KeepSnapshots(usize),
+    /// Retain all states with a commit timestamp at or after the given time
+    /// (seconds since the UNIX epoch; see `CommitMeta::timestamp`). This
+    /// retains the latest snapshot of a state committed no later than this
+    /// time, and everything after.
+    KeepSince(i64),
+}
+
+/// How history is compacted (see `Partition::compact_history_with`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompactMode {
+    /// Replace the logs of a snapshot with a single squashed commit
+    Squash,
+    /// Keep commits on the first-parent path from one snapshot to the next,
+    /// except that empty merges (see `Commit::is_empty_merge`) are elided:
+    /// each run of these is squashed together with the following commit.
+    /// Other commits (those on merged branches) are dropped. Logs without
+    /// empty merges are not changed.
+    ElideEmptyMerges,
+}
+
+/// Status of a partition; see `Partition::health()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PartitionHealth {
+    /// True if any state is loaded (see `Partition::is_loaded`)
+    pub loaded: bool,
+    /// Number of tips (more than one if a merge is required)
+    pub tips: usize,
+    /// Number of commits not yet written (see `Partition::unsaved_len`)
+    pub unsaved: usize,
+    /// True if opened read-only
+    pub read_only: bool,
+    /// Time of the last commit log written since opening (as from
+    /// `Control::clock`), if any and known
+    pub last_log_write: Option<i64>,
+    /// Time of the last snapshot written since opening, if any and known
+    pub last_snapshot_write: Option<i64>,
+    /// Error reported by the `RepoIO` backend (see `RepoIO::check_status`)
+    pub io_error: Option<String>,
+}
+impl PartitionHealth {
+    /// True if the partition is loaded, needs no merge and storage is
+    /// accessible.
+    pub fn is_healthy(&self) -> bool {
+        self.loaded && self.tips == 1 && self.io_error.is_none()
+    }
+}
+
+// Write amplification above which `maintenance_advice` reports it
+const ADVISE_AMPLIFICATION: f64 = 8.0;
+
+// Rewrite the first-parent path of `commits` from `base` to `target` (all
+// states of which are in `replayed`), squashing each run of empty merges
+// together with the following commit (see `CompactMode::ElideEmptyMerges`).
+// Returns `None` if the path contains no empty merge.
+fn elide_empty_merges<E: Element>(base: &PartState<E>, target: &Sum, commits: Vec<Commit<E>>,
+        replayed: &HashMap<Sum, PartState<E>>) -> Option<Vec<Commit<E>>>
+{
+    let mut by_sum: HashMap<Sum, Commit<E>> = commits.into_iter()
+            .map(|c| (c.statesum().clone(), c))
+            .collect();
+    let mut path = Vec::new();
+    let mut sum = target.clone();
+    while sum != *base.statesum() {
+        let commit = by_sum.remove(&sum).expect("replayed commit");
+        sum = commit.first_parent().clone();
+        path.push(commit);
+    }
+    if !path.iter().any(|c| c.is_empty_merge()) {
+        return None;
+    }
+    
+    let state = |sum: &Sum| if sum == base.statesum() { base } else { &replayed[sum] };
+    let mut result = Vec::new();
+    let mut squash_from: Option<Sum> = None;
+    for commit in path.into_iter().rev() {
+        if commit.is_empty_merge() {
+            squash_from.get_or_insert_with(|| commit.first_parent().clone());
+        } else if let Some(from) = squash_from.take() {
+            result.push(Commit::new_squash(state(&from), state(commit.statesum())));
+        } else {
+            result.push(commit);
+        }
+    }
+    if let Some(from) = squash_from {
+        result.push(Commit::new_squash(state(&from), state(target)));
+    }
+    Some(result)
+}
@@ -0,0 +1,586 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: merging tips and finding common ancestors
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+use commit::Commit;
+use control::Control;
+use elt::EltId;
+use error::{Result, ArgError, MergeError};
+use merge::{TwoWayMerge, TwoWaySolver, TwoWaySolveUseC, NWayMerge, NWaySolver};
+#[cfg(feature = "chaos")]
+use merge::ChaosSolver;
+#[cfg(feature = "chaos")]
+use rand::Rng;
+use rw::audit::AuditOp;
+use rw::commitlog::Recovery;
+use state::{PartState, StateRead, StateWrite};
+use sum::Sum;
+
+use super::Partition;
+
+impl<C: Control> Partition<C> {
+    /// Merge all latest states into a single tip.
+    /// This is a convenience wrapper around `merge_two(...)`.
+    /// 
+    /// Example:
+    /// 
+    /// ```no_run
+    /// use std::path::Path;
+    /// use pippin::pip::{Partition, DefaultControl, part_from_path,
+    ///         TwoWaySolverChain, AncestorSolver2W, RenamingSolver2W};
+    /// 
+    /// let path = Path::new("./my-partition");
+    /// let io = part_from_path(path).unwrap();
+    /// let control = DefaultControl::<String, _>::new(io);
+    /// let mut partition = Partition::open(control, true)
+    ///         .expect("failed to open partition");
+    /// 
+    /// // Customise with your own solver:
+    /// let ancestor_solver = AncestorSolver2W::new();
+    /// let renaming_solver = RenamingSolver2W::new();
+    /// let solver = TwoWaySolverChain::new(&ancestor_solver, &renaming_solver);
+    /// 
+    /// partition.merge(&solver, true).expect("merge failed");
+    /// ```
+    /// 
+    /// This works through all 'tip' states in an order determined by a
+    /// `HashSet`'s random keying, thus the exact result may not be repeatable
+    /// if the program were run multiple times with the same initial state.
+    /// 
+    /// If `auto_load` is true, additional history will be loaded as necessary
+    /// to find a common ancestor.
+    /// 
+    /// To avoid concurrent merges by several processes (or instances), the
+    /// merge lock is taken first (see `RepoIO::try_lock_merge`) and held
+    /// until the merge commits are written (see `write_fast`); if it is held
+    /// elsewhere this fails with `MergeError::InProgress`. Once the lock is
+    /// taken, commits written by others are loaded (see `refresh`), thus a
+    /// merge written meanwhile is used instead of making a new one.
+    /// 
+    /// The merge may be cancelled between steps (see `Control::cancel_flag`);
+    /// merge commits already made are kept.
+    pub fn merge<S: TwoWaySolver<C::Element>>(&mut self, solver: &S, auto_load: bool) -> Result<()> {
+        self.merge_impl(solver, auto_load, |_| (), |_| ())
+    }
+    
+    
+    /// Merge all latest states like `merge`, but choose which tips to merge
+    /// next at random, randomly permute the order in which conflicts are
+    /// solved and randomly swap solver inputs (see `merge::ChaosSolver`).
+    /// Requires feature `chaos`; intended for test builds only.
+    /// 
+    /// The result should have the same elements (see `MutPartState::elt_sum`) as
+    /// that of `merge` for any `rng`, provided the solver is symmetric. On
+    /// success, exactly one tip remains.
+    #[cfg(feature = "chaos")]
+    pub fn merge_chaos<S, G>(&mut self, solver: &S, auto_load: bool, rng: G) -> Result<()>
+            where S: TwoWaySolver<C::Element>, G: Rng
+    {
+        let chaos = ChaosSolver::new(solver, rng);
+        self.merge_impl(&chaos, auto_load,
+                |tips| chaos.shuffle(tips), |merge| chaos.shuffle_merge(merge))?;
+        if self.tips.len() != 1 {
+            return Err(Box::new(MergeError::NotSolved));
+        }
+        Ok(())
+    }
+    
+    
+    // Implementation of `merge`: `order` may permute tips (after sorting)
+    // and `prepare` may adjust each `TwoWayMerge` before solving.
+    fn merge_impl<S, F, G>(&mut self, solver: &S, auto_load: bool,
+            order: F, prepare: G) -> Result<()>
+            where S: TwoWaySolver<C::Element>,
+            F: FnMut(&mut [&Sum]), G: FnMut(&mut TwoWayMerge<C::Element>)
+    {
+        let n_unsaved = self.lock_merge()?;
+        let result = self.merge_impl_locked(solver, auto_load, order, prepare);
+        self.unlock_merge(n_unsaved);
+        self.apply_retention();
+        result
+    }
+    
+    
+    fn merge_impl_locked<S, F, G>(&mut self, solver: &S, auto_load: bool,
+            mut order: F, mut prepare: G) -> Result<()>
+            where S: TwoWaySolver<C::Element>,
+            F: FnMut(&mut [&Sum]), G: FnMut(&mut TwoWayMerge<C::Element>)
+    {
+        let mut start_ss = self.ss0;
+        while self.tips.len() > 1 {
+            self.check_cancelled()?;
+            if start_ss < self.ss0 {
+                let ss0 = self.ss0;
+                self.load_range_impl(start_ss, ss0, Recovery::Strict, false)?;
+            }
+            
+            let (tip1, tip2): (Sum, Sum) = {
+                // We sort tips in order to make the operation deterministic.
+                let mut tips: Vec<_> = self.tips.iter().collect();
+                tips.sort();
+                order(&mut tips);
+                (tips[0].clone(), tips[1].clone())
+            };
+            trace!("Partition {}: attempting merge of tips {} and {}", self.name, &tip1, &tip2);
+            let c = match self.merge_two(&tip1, &tip2) {
+                Ok(mut merge) => {
+                    prepare(&mut merge);
+                    merge.solve_inline(solver).make_commit(self.control.as_mcm_ref())
+                },
+                Err(MergeError::NoCommonAncestor) if auto_load && self.ss0 > 0 => {
+                    // Iteratively load previous history and retry until success or error.
+                    start_ss = self.ss0 - 1;
+                    continue;
+                },
+                Err(e) => return Err(Box::new(e)),
+            };
+            if let Some(commit) = c {
+                trace!("Pushing merge commit: {} ({} changes)",
+                        commit.statesum(), commit.num_changes());
+                self.push_commit(commit)?;
+            } else {
+                return Err(Box::new(MergeError::NotSolved));
+            }
+        }
+        Ok(())
+    }
+    
+    
+    /// Merge all latest states into a single tip, using the solver provided
+    /// by `Control::merge_solver()`. Otherwise this is identical to `merge`.
+    pub fn merge_default(&mut self, auto_load: bool) -> Result<()> {
+        let solver = self.control.merge_solver();
+        self.merge(&solver, auto_load)
+    }
+    
+    
+    /// Creates a `TwoWayMerge` for two given states (presumably tip states,
+    /// but not required).
+    /// 
+    /// It is recommended to use `merge` instead unless you need control over merge order with more
+    /// than two tips. In order to use this function, you'll need code like:
+    /// 
+    /// ```no_compile
+    /// let commit = partition.merge_two(&tip1, &tip2)?
+    ///         .solve_inline(&solver)
+    ///         .make_commit(&mcm)
+    ///         .expect("merge failed");
+    /// partition.add_commit(commit)?;
+    /// ```
+    /// 
+    /// Note that this function can fail with `MergeError::NoCommonAncestor` if not enough history
+    /// is available. In this case you might try calling `part.load_all()?;` or
+    /// `let ss0 = part.oldest_ss_loaded(); part.load_range(ss0 - 1, ss0, Recovery::Strict);`, then retrying.
+    pub fn merge_two(&self, tip1: &Sum, tip2: &Sum) -> Result<TwoWayMerge<C::Element>, MergeError> {
+        self.merge_two_with(tip1, tip2, &MergeBase::First)
+    }
+    
+    
+    /// Creates a `TwoWayMerge` like `merge_two`, but with control over the
+    /// common ancestor used where `merge_bases(tip1, tip2)` finds several
+    /// (criss-cross history). See `MergeBase`.
+    pub fn merge_two_with(&self, tip1: &Sum, tip2: &Sum, base: &MergeBase)
+            -> Result<TwoWayMerge<C::Element>, MergeError>
+    {
+        let s1 = self.states.get(tip1).ok_or(MergeError::NoState)?;
+        let s2 = self.states.get(tip2).ok_or(MergeError::NoState)?;
+        let bases = match *base {
+            MergeBase::Given(ref sum) => vec![sum.clone()],
+            _ => self.merge_bases(tip1, tip2),
+        };
+        if bases.is_empty() {
+            return Err(MergeError::NoCommonAncestor);
+        }
+        if bases.len() > 1 && *base == MergeBase::Recursive {
+            return Ok(TwoWayMerge::with_base(s1, s2, self.virtual_base(&bases)?));
+        }
+        let s3 = self.states.get(&bases[0]).ok_or(MergeError::NoState)?;
+        Ok(TwoWayMerge::new(s1, s2, s3))
+    }
+    
+    
+    /// Merge all latest states into a single tip in one step, producing a
+    /// single merge commit whose parents are all tips (see `NWayMerge`).
+    /// 
+    /// Unlike `merge`, no intermediate commits are created and each element
+    /// is solved once, thus the result does not depend on the order of tips
+    /// (provided the solver does not). Since a commit may have at most 255
+    /// parents, more tips than this are merged in several steps.
+    /// 
+    /// If `auto_load` is true, additional history will be loaded as necessary
+    /// to find a common ancestor. The merge lock is used as by `merge`.
+    pub fn merge_n<S: NWaySolver<C::Element>>(&mut self, solver: &S, auto_load: bool) -> Result<()> {
+        let n_unsaved = self.lock_merge()?;
+        let result = self.merge_n_locked(solver, auto_load);
+        self.unlock_merge(n_unsaved);
+        self.apply_retention();
+        result
+    }
+    
+    
+    fn merge_n_locked<S: NWaySolver<C::Element>>(&mut self, solver: &S, auto_load: bool) -> Result<()> {
+        while self.tips.len() > 1 {
+            self.check_cancelled()?;
+            let tips: Vec<Sum> = {
+                let mut tips: Vec<_> = self.tips.iter().cloned().collect();
+                tips.sort();
+                tips.truncate(0xFF);
+                tips
+            };
+            trace!("Partition {}: attempting merge of {} tips", self.name, tips.len());
+            let commit = match self.merge_tips(&tips) {
+                Ok(merge) => merge.solve_inline(solver).make_commit(self.control.as_mcm_ref())?,
+                Err(MergeError::NoCommonAncestor) if auto_load && self.ss0 > 0 => {
+                    let ss0 = self.ss0;
+                    self.load_range_impl(ss0 - 1, ss0, Recovery::Strict, false)?;
+                    continue;
+                },
+                Err(e) => return Err(Box::new(e)),
+            };
+            trace!("Pushing merge commit: {} ({} changes)",
+                    commit.statesum(), commit.num_changes());
+            self.push_commit(commit)?;
+        }
+        Ok(())
+    }
+    
+    
+    // If a merge is required, take the merge lock (unless held) and load
+    // commits written by others. Returns the number of unsaved commits.
+    fn lock_merge(&mut self) -> Result<usize> {
+        if self.tips.len() > 1 && self.merge_lock.is_none() {
+            match self.control.io().try_lock_merge()? {
+                Some(lock) => self.merge_lock = Some(lock),
+                None => return Err(Box::new(MergeError::InProgress)),
+            }
+            if let Err(e) = self.refresh() {
+                self.merge_lock = None;
+                return Err(e);
+            }
+        }
+        Ok(self.unsaved.len())
+    }
+    
+    
+    // Release the merge lock if no commits were added since `lock_merge`
+    // (otherwise it is released by `write_fast`)
+    fn unlock_merge(&mut self, n_unsaved: usize) {
+        if self.unsaved.len() == n_unsaved {
+            self.merge_lock = None;
+        }
+    }
+    
+    
+    /// Creates an `NWayMerge` for the given states (presumably tip states,
+    /// but not required; at least two must be given).
+    /// 
+    /// Like `merge_two`, this can fail with `MergeError::NoCommonAncestor` if
+    /// not enough history is loaded. At most 255 states may be given.
+    pub fn merge_tips(&self, tips: &[Sum]) -> Result<NWayMerge<C::Element>, MergeError> {
+        if tips.len() < 2 {
+            return Err(MergeError::NoState);
+        }
+        if tips.len() >= 0x100 {
+            return Err(MergeError::TooManyStates);
+        }
+        let mut common = self.latest_common_ancestor(&tips[0], &tips[1])?;
+        for tip in &tips[2..] {
+            common = self.latest_common_ancestor(&common, tip)?;
+        }
+        let mut states = Vec::with_capacity(tips.len());
+        for tip in tips {
+            states.push(self.states.get(tip).ok_or(MergeError::NoState)?);
+        }
+        let c = self.states.get(&common).ok_or(MergeError::NoState)?;
+        Ok(NWayMerge::new(states, c))
+    }
+    
+    
+    /// Check whether `merge_two(tip1, tip2)` can succeed with the history
+    /// currently loaded, without attempting the merge.
+    /// 
+    /// If `MergeReadiness::NeedsHistory(ss0, ss1)` is returned, the caller
+    /// may call `load_range(ss0, ss1, ...)` and check again. Note that states
+    /// dropped by `evict_history` or `compact_memory` are not restored by
+    /// loading; in this case `Unavailable` may be returned.
+    pub fn can_merge(&self, tip1: &Sum, tip2: &Sum) -> MergeReadiness {
+        if !self.has_state(tip1) || !self.has_state(tip2) {
+            return MergeReadiness::NoState;
+        }
+        match self.latest_common_ancestor(tip1, tip2) {
+            Ok(ref sum) if self.has_state(sum) => MergeReadiness::Ready,
+            _ if self.ss0 > 0 => MergeReadiness::NeedsHistory(self.ss0 - 1, self.ss0),
+            _ => MergeReadiness::Unavailable,
+        }
+    }
+    
+    
+    /// Reconcile with a divergent replica which shares no common history with
+    /// this partition (e.g. both were initialised independently), where
+    /// `merge` would fail with `MergeError::NoCommonAncestor`.
+    /// 
+    /// `foreign` is the tip state of the other replica. Elements of `foreign`
+    /// and of this partition's tip are paired by the `identity` function;
+    /// paired foreign elements are renumbered to the identifier used here,
+    /// and unpaired ones are given a free identifier if theirs is in use.
+    /// A synthetic common base holding the paired elements which are equal
+    /// in both states is used to merge via `solver` (thus unpaired elements
+    /// are kept and paired elements which differ are conflicts, though the
+    /// solver is passed no ancestor for these).
+    /// 
+    /// Nothing is changed unless the merge is solved. Then, after writing
+    /// unsaved commits, `foreign` is *grafted* into this partition by
+    /// writing it to a snapshot tagged `"graft"`; the renumbering and merge
+    /// commits are then written, followed by a snapshot of the new tip (so
+    /// that loading the latest state does not require the pre-graft
+    /// history). Since the foreign tip is now an ancestor here, future syncs
+    /// with the other replica can merge normally.
+    /// 
+    /// Fails if not ready (see `tip()`) or if `foreign` is already known.
+    /// Returns the state-sum of the new tip.
+    pub fn reconcile<K, F, S>(&mut self, foreign: &PartState<C::Element>, identity: F,
+            solver: &S) -> Result<Sum>
+        where K: Eq + Hash, F: Fn(&C::Element) -> K, S: TwoWaySolver<C::Element>
+    {
+        self.check_writable()?;
+        let tip_key = self.tip_key()?.clone();
+        if self.states.contains(foreign.statesum()) || self.ancestors.contains(foreign.statesum()) {
+            return ArgError::err("foreign state is already known");
+        }
+        
+        let (renumbered, renumber_commit, merge_commit) = {
+            let tip = self.states.get(&tip_key).expect("tip state");
+            let mut ours: HashMap<K, EltId> = HashMap::new();
+            for (id, elt) in tip.elts_iter() {
+                ours.entry(identity(elt)).or_insert(id);
+            }
+            
+            // Choose new identifiers for foreign elements:
+            let mut moves = Vec::new();
+            let mut used = HashSet::new();
+            let mut unpaired = Vec::new();
+            let mut base = tip.clone_mut();
+            let mut in_base = HashSet::new();
+            for (id, elt) in foreign.elts_iter() {
+                if let Some(our_id) = ours.remove(&identity(elt)) {
+                    used.insert(our_id);
+                    if id != our_id {
+                        moves.push((id, our_id));
+                    }
+                    if tip.get_rc(our_id).ok() == Some(elt) {
+                        in_base.insert(our_id);
+                    }
+                } else {
+                    unpaired.push(id);
+                }
+            }
+            for id in unpaired {
+                if tip.is_avail(id) || used.contains(&id) {
+                    let mut new_id = id;
+                    while tip.is_avail(new_id) || used.contains(&new_id) ||
+                        foreign.is_avail(new_id)
+                    {
+                        new_id = EltId::random();
+                    }
+                    moves.push((id, new_id));
+                    used.insert(new_id);
+                } else {
+                    used.insert(id);
+                }
+            }
+            
+            let mut state = foreign.clone_mut();
+            let mut moved = Vec::with_capacity(moves.len());
+            for (from, to) in moves {
+                moved.push((to, state.remove(from)?));
+            }
+            for (to, elt) in moved {
+                state.insert_rc(to, elt)?;
+            }
+            let renumbered = PartState::from_mut(state, self.control.as_mcm_ref_mut());
+            let renumber_commit = Commit::from_diff(foreign, &renumbered);
+            
+            let ids: Vec<EltId> = base.elts_iter().map(|(id, _)| id).collect();
+            for id in ids {
+                if !in_base.contains(&id) {
+                    base.remove(id)?;
+                }
+            }
+            let base = PartState::from_mut(base, self.control.as_mcm_ref_mut());
+            
+            let merge_commit = TwoWayMerge::new(tip, &renumbered, &base)
+                    .solve_inline(solver)
+                    .make_commit(self.control.as_mcm_ref())
+                    .ok_or(MergeError::NotSolved)?;
+            (renumbered, renumber_commit, merge_commit)
+        };
+        
+        self.write_fast()?;
+        let foreign = foreign.clone_exact();
+        let foreign_key = foreign.statesum().clone();
+        for parent in foreign.parents() {
+            self.ancestors.insert(parent.clone());
+        }
+        self.states.insert(foreign);
+        self.write_snapshot_of(&foreign_key, Some("graft"))?;
+        self.add_tag("graft".to_string(), foreign_key.clone());
+        if let Some(commit) = renumber_commit {
+            self.add_pair(commit, renumbered);
+        }
+        self.push_commit(merge_commit)?;
+        self.write_fast()?;
+        self.write_snapshot()?;
+        let tip_key = self.tip_key()?.clone();
+        self.record_audit(AuditOp::Reconcile, format!("grafted {}; new tip {}", foreign_key, tip_key));
+        Ok(tip_key)
+    }
+    
+    
+    // Take self and two sums. Return a copy of a key to avoid lifetime issues.
+    fn latest_common_ancestor(&self, k1: &Sum, k2: &Sum) -> Result<Sum, MergeError> {
+        self.merge_bases(k1, k2).into_iter().next().ok_or(MergeError::NoCommonAncestor)
+    }
+    
+    
+    /// Find the best common ancestors of states `k1` and `k2`: those common
+    /// ancestors which are not themselves ancestors of another common
+    /// ancestor. Usually there is only one, but criss-cross merges can result
+    /// in several (see `MergeBase`).
+    /// 
+    /// Only loaded history is searched; the result is empty if no common
+    /// ancestor is found.
+    pub fn merge_bases(&self, k1: &Sum, k2: &Sum) -> Vec<Sum> {
+        // #0019: there are multiple strategies here; we just find all
+        // ancestors of one, then of the other. This simplifies lopic.
+        let mut a1 = HashSet::new();
+        
+        let mut next = VecDeque::new();
+        next.push_back(k1);
+        while let Some(k) = next.pop_back() {
+            if a1.contains(k) { continue; }
+            a1.insert(k);
+            if let Some(state) = self.states.get(k) {
+                for p in state.parents() {
+                    next.push_back(p);
+                }
+            }
+            next.extend(self.squashed.get(k));
+        }
+        
+        // Find common ancestors reachable from k2 without passing through
+        // another common ancestor. We track ancestors of k2 to avoid loops.
+        let mut a2 = HashSet::new();
+        let mut common = Vec::new();
+        
+        // next is empty
+        next.push_back(k2);
+        while let Some(k) = next.pop_back() {
+            if a2.contains(k) { continue; }
+            a2.insert(k);
+            if a1.contains(k) {
+                common.push(k);
+                continue;
+            }
+            if let Some(state) = self.states.get(k) {
+                for p in state.parents() {
+                    next.push_back(p);
+                }
+            }
+            next.extend(self.squashed.get(k));
+        }
+        
+        // Where multiple candidates exist (e.g. after merges), prefer one
+        // which is not an ancestor of another.
+        let mut superseded = HashSet::new();
+        if common.len() > 1 {
+            for k in &common {
+                let state = match self.states.get(*k) { Some(s) => s, None => continue };
+                next.extend(state.parents());
+                while let Some(k) = next.pop_back() {
+                    if superseded.insert(k) {
+                        if let Some(state) = self.states.get(k) {
+                            next.extend(state.parents());
+                        }
+                        next.extend(self.squashed.get(k));
+                    }
+                }
+            }
+        }
+        common.into_iter().filter(|k| !superseded.contains(k)).cloned().collect()
+    }
+    
+    
+    // Build a virtual common ancestor by merging `bases` into the first in
+    // turn, each relative to the (possibly virtual) base of that base and the
+    // first. Conflicts which the control's solver cannot resolve take the
+    // value from the inner base.
+    fn virtual_base(&self, bases: &[Sum]) -> Result<PartState<C::Element>, MergeError> {
+        let mut state = self.states.get(&bases[0]).ok_or(MergeError::NoState)?.clone_exact();
+        for base in &bases[1..] {
+            let other = self.states.get(base).ok_or(MergeError::NoState)?;
+            let commit = {
+                let inner = self.merge_bases(&bases[0], base);
+                let merge = match inner.len() {
+                    0 => return Err(MergeError::NoCommonAncestor),
+                    1 => TwoWayMerge::new(&state, other,
+                            self.states.get(&inner[0]).ok_or(MergeError::NoState)?),
+                    _ => TwoWayMerge::with_base(&state, other, self.virtual_base(&inner)?),
+                };
+                merge.solve_inline(&self.control.merge_solver())
+                    .solve_inline(&TwoWaySolveUseC::new())
+                    .make_commit(self.control.as_mcm_ref())
+            };
+            let commit = commit.ok_or(MergeError::NotSolved)?;
+            state = {
+                let parent = if commit.first_parent() == state.statesum() { &state } else { other };
+                PartState::from_state_commit(parent, &commit)
+                        .map_err(|_| MergeError::NotSolved)?
+            };
+        }
+        Ok(state)
+    }
+    
+}
+
+/// Choice of common ancestor for a two-way merge (see
+/// `Partition::merge_two_with`).
+/// 
+/// After criss-cross merges two tips may have several best common ancestors
+/// (`Partition::merge_bases`), none of which is an ancestor of another.
+/// Picking one arbitrarily can make changes made since another appear as
+/// conflicts or even be reverted.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MergeBase {
+    /// Use the first best common ancestor found (as `merge_two` does).
+    First,
+    /// Use the given state, which should be a loaded common ancestor.
+    Given(Sum),
+    /// Where there are several best common ancestors, merge these into a
+    /// virtual ancestor state (recursively, where they in turn have several
+    /// best common ancestors) and use that. Conflicts between ancestors are
+    /// solved with `Control::merge_solver()` where possible, otherwise by
+    /// taking the value from their own common ancestor.
+    Recursive,
+}
+impl Default for MergeBase {
+    fn default() -> Self { MergeBase::First }
+}
+
+/// Result of `Partition::can_merge`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MergeReadiness {
+    /// A common ancestor is loaded; the merge can proceed
+    Ready,
+    /// Older history is required: snapshots `ss` with `ss0 <= ss < ss1`
+    /// should be loaded (see `Partition::load_range`)
+    NeedsHistory(usize, usize),
+    /// No common ancestor can be found in the history available
+    Unavailable,
+    /// One of the given states is not loaded
+    NoState,
+}
@@ -0,0 +1,2878 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: partition
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc::Receiver;
+use std::collections::{HashMap, HashSet, VecDeque, BTreeMap};
+use std::collections::hash_set as hs;
+use std::result;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::usize;
+use std::cmp::{min, max};
+use std::mem::size_of;
+
+use hashindexed::{HashIndexed, Iter};
+
+use commit::{Commit, CommitMeta, CommitSummary, EltChange, ReplicaId, MAX_ACKS};
+use control::{Control, WrittenFile, ProgressSink};
+use elt::{Element, EltId, TextCanon};
+use index::{Index, IndexKey};
+use io::{MergeLock, RepoIO};
+use error::{Result, Error, ArgError, TipError, PatchOp, MatchError, RepoError,
+        MemLimit, ReadOnly, ElementOp};
+use rw::EltReader;
+use rw::audit::{AuditOp, AuditEntry, read_audit, write_audit_entry};
+use rw::compress::{Compression, CompressWriter, decompress};
+use rw::encrypt::{Cipher, CipherInfo, EncryptWriter, decrypt};
+use rw::sumfilter::SumFilter;
+use rw::header::{FileType, FileHeader, UserData, validate_repo_name, read_head, write_head};
+use rw::snapshot::{read_snapshot, write_snapshot, write_snapshot_reproducible,
+        write_snapshot_dedup};
+use rw::commitlog::{read_log, read_log_tolerant, Recovery, RecoveryReport, start_log,
+        write_commit, LogIndex};
+use state::{PartState, MutPartState, StateRead, StateWrite, PartStateSumComparator};
+use subscribe::{Subscriptions, SubscriptionId, Notification, WatchFilter};
+use sum::Sum;
+use util::CountingWriter;
+
+mod erase;
+mod exchange;
+mod history;
+mod lazy;
+mod maintenance;
+mod merging;
+mod verify;
+
+pub use self::exchange::ReceiveReport;
+pub use self::history::{LogIter, EltHistory};
+pub use self::maintenance::{GcPolicy, CompactMode, PartitionHealth};
+pub use self::merging::{MergeBase, MergeReadiness};
+pub use self::verify::{FormatReport, VerifyLevel, VerifyProblem, VerifyReport};
+
+
+/// A *partition* is a sub-set of the entire set such that (a) each element is
+/// in exactly one partition, (b) a partition is small enough to be loaded into
+/// memory in its entirety, (c) there is some user control over the number of
+/// partitions and how elements are assigned partitions and (d) each partition
+/// can be managed independently of other partitions.
+///
+/// Partitions are the *only* method by which the entire set may grow beyond
+/// available memory, thus smart allocation of elements to partitions will be
+/// essential for some use-cases.
+/// 
+/// A partition is in one of three possible states: (1) unloaded, (2) loaded
+/// but requiring a merge (multiple tips), (3) ready for use.
+/// 
+/// Terminology: a *tip* (as in *point* or *peak*) is a state without a known
+/// successor. Normally there is exactly one tip, but see `is_ready`,
+/// `is_loaded` and `merge_required`.
+pub struct Partition<C: Control> {
+    // User control trait object
+    control: C,
+    // Repository name. Used to identify loaded files.
+    name: String,
+    // Number of first snapshot file loaded (equal to ss1 if nothing is loaded)
+    ss0: usize,
+    // Number of latest snapshot file loaded + 1; 0 if nothing loaded and never less than ss0
+    ss1: usize,
+    // Known committed states indexed by statesum 
+    states: HashIndexed<PartState<C::Element>, Sum, PartStateSumComparator>,
+    // All states not in `states` which are known to be superceded
+    ancestors: HashSet<Sum>,
+    // All states without a known successor
+    tips: HashSet<Sum>,
+    // Commits created but not yet saved to disk. First in at front; use as queue.
+    unsaved: VecDeque<Commit<C::Element>>,
+    // Tagged states (from loaded snapshot headers)
+    tags: HashMap<String, Vec<Sum>>,
+    // Data written since creation / opening
+    stats: WriteStats,
+    // Times (see `Control::clock`) of the last commit log and snapshot written
+    last_log_write: Option<i64>,
+    last_ss_write: Option<i64>,
+    // Element subscriptions and pending notifications
+    subs: Subscriptions,
+    // Secondary indexes of the tip (see `find_by_index`)
+    index: Index,
+    // States acknowledged by each replica (excluding known ancestors)
+    acks: HashMap<ReplicaId, HashSet<Sum>>,
+    // Acknowledgements not yet written
+    unsaved_acks: Vec<(ReplicaId, Sum)>,
+    // Metadata from the header of the newest snapshot read
+    header: Option<HeaderInfo>,
+    // Element index for on-demand reads (see `load_lazy`)
+    lazy: Option<LazyIndex<C::Element>>,
+    // Base (an ancestor) of each state loaded from a squashed commit
+    squashed: HashMap<Sum, Sum>,
+    // Elements which could not be deserialised (see `EltReadPolicy`)
+    skipped: HashMap<EltId, Sum>,
+    // If true, never write (see `open_read_only`)
+    read_only: bool,
+    // True if the latest snapshot marks the partition archived (see `archive`)
+    archived: bool,
+    // Merge lock, held from a merge until its commits are written
+    merge_lock: Option<MergeLock>,
+    // Filter of sums of states in files read or written (see `may_have_state`)
+    sum_filter: SumFilter,
+    // True if `sum_filter` changed since it was last stored
+    sum_filter_changed: bool,
+    // Snapshot and log number and index of the last log written, if this
+    // may be appended to (see `append_log`)
+    log_tail: Option<(usize, usize, LogIndex)>,
+}
+
+// Methods creating a partition, loading its data or checking status
+impl<C: Control> Partition<C> {
+    /// Create a partition, assigning an IO provider (this can only be done at
+    /// time of creation). Create a blank state in the partition, write an
+    /// empty snapshot to the provided `RepoIO`, and mark self as *ready
+    /// for use*.
+    /// 
+    /// Example:
+    /// 
+    /// ```
+    /// use pippin::pip::{Partition, RepoIO, DefaultControl, DummyRepoIO};
+    /// 
+    /// let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+    /// let partition = Partition::create(control, "example repo");
+    /// ```
+    pub fn create(mut control: C, name: &str) -> Result<Partition<C>> {
+        validate_repo_name(name)?;
+        let ss = 0;
+        info!("Creating partiton; writing snapshot {}", ss);
+        
+        let state = PartState::new(control.as_mcm_ref_mut());
+        let mut part = Partition {
+            control: control,
+            name: name.into(),
+            ss0: ss,
+            ss1: ss + 1,
+            states: HashIndexed::new(),
+            ancestors: HashSet::new(),
+            tips: HashSet::new(),
+            unsaved: VecDeque::new(),
+            tags: HashMap::new(),
+            stats: WriteStats::default(),
+            last_log_write: None,
+            last_ss_write: None,
+            subs: Subscriptions::new(),
+            index: Index::new(),
+            acks: HashMap::new(),
+            unsaved_acks: Vec::new(),
+            header: None,
+            lazy: None,
+            squashed: HashMap::new(),
+            skipped: HashMap::new(),
+            read_only: false,
+            archived: false,
+            merge_lock: None,
+            sum_filter: SumFilter::new(),
+            sum_filter_changed: false,
+            log_tail: None,
+        };
+        let header = part.make_header(FileType::Snapshot(0))?;
+        let reproducible = part.control.reproducible_snapshots();
+        let cipher = part.control.cipher();
+        
+        if let Some(writer) = part.control.io_mut().new_ss(ss)? {
+            let mut writer = CountingWriter::new(writer);
+            write_head(&header, &mut writer)?;
+            let mut enc = EncryptWriter::new(&mut writer, &header, cipher);
+            let mut w = CompressWriter::new(&mut enc, header.compression)?;
+            if header.dedup {
+                write_snapshot_dedup(&state, &mut w, reproducible)?;
+            } else if reproducible {
+                write_snapshot_reproducible(&state, &mut w)?;
+            } else {
+                write_snapshot(&state, &mut w)?;
+            }
+            w.finish()?;
+            enc.finish()?;
+            part.stats.snapshots += 1;
+            part.stats.snapshot_bytes += writer.count();
+        } else {
+            return RepoError::err(RepoError::SnapshotExists { ss_num: ss });
+        }
+        part.control.file_written(WrittenFile::Snapshot(ss));
+        part.last_ss_write = part.control.clock().now();
+        part.filter_sums(Some(WrittenFile::Snapshot(ss)), Some(state.statesum()));
+        part.save_sum_filter();
+        
+        part.tips.insert(state.statesum().clone());
+        part.states.insert(state);
+        
+        Ok(part)
+    }
+    
+    /// Open a partition, assigning an IO provider (this can only be done at
+    /// time of creation).
+    /// 
+    /// If `read_data` is true, the latest state is read into memory immediately.
+    /// Otherwise, initialise the partition without reading data (currently requires reading a
+    /// snapshot header). In this case the partition will not be *ready to use* until data is
+    /// loaded with one of the load operations. Until then most operations will fail.
+    /// 
+    /// Example:
+    /// 
+    /// ```no_run
+    /// use std::path::Path;
+    /// use pippin::pip::{Partition, DefaultControl, part_from_path};
+    /// 
+    /// let path = Path::new("./my-partition");
+    /// let io = part_from_path(path).unwrap();
+    /// let control = DefaultControl::<String, _>::new(io);
+    /// let partition = Partition::open(control, true);
+    /// ```
+    pub fn open(control: C, read_data: bool) -> Result<Partition<C>> {
+        Partition::open_impl(control, read_data, false)
+    }
+    
+    /// Open a partition in read-only mode, as `open`, e.g. to run reports
+    /// against a repository owned by another process.
+    /// 
+    /// Nothing is ever written via the `RepoIO` (or to any state cache):
+    /// methods which would write files fail with a `ReadOnly` error, and
+    /// adding commits (`push_state`, `push_commit`, merging etc.) fails with
+    /// `PatchOp::ReadOnly`. Loading and `refresh` work as usual.
+    /// 
+    /// For file-based repositories it is also recommended to set
+    /// `RepoFileIO::set_readonly`, which avoids creating a lock file.
+    pub fn open_read_only(control: C, read_data: bool) -> Result<Partition<C>> {
+        Partition::open_impl(control, read_data, true)
+    }
+    
+    fn open_impl(control: C, read_data: bool, read_only: bool) -> Result<Partition<C>> {
+        trace!("Opening partition");
+        // We need to read a header for classification purposes
+        
+        let ss_len = control.io().ss_len();
+        let progress = control.progress();
+        let mut elts = EltReader::new(control.elt_read_policy()).with_progress(progress.clone());
+        for ss in (0..ss_len).rev() {
+            debug!("Partition: reading snapshot {}", ss);
+            let result = if let Some(mut ssf) = control.io().read_ss(ss)? {
+                let head = read_head(&mut *ssf)?;
+                trace!("Partition: name: {}", head.name);
+                let info = HeaderInfo::new(ss, &head);
+                
+                let state = if read_data && !head.archived {
+                    let mut r = body_reader(ssf, &head, control.cipher())?;
+                    let state = read_snapshot(&mut r, head.ftype.ver(), head.dedup,
+                            &control.user_meta_limits(),
+                            &mut elts)?;
+                    if let Some(ref progress) = progress {
+                        progress.file_read(WrittenFile::Snapshot(ss))?;
+                    }
+                    Some(state)
+                } else {
+                    None
+                };
+                
+                Some((head.name, head.tag, info, state))
+            } else {
+                warn!("Partition: missing snapshot {}", ss);
+                None
+            };
+            if let Some((name, tag, info, opt_state)) = result {
+                let sum_filter = load_sum_filter(control.io());
+                let archived = info.archived;
+                let mut part = Partition {
+                    control,
+                    name,
+                    ss0: 0,
+                    ss1: 0,
+                    states: HashIndexed::new(),
+                    ancestors: HashSet::new(),
+                    tips: HashSet::new(),
+                    unsaved: VecDeque::new(),
+                    tags: HashMap::new(),
+                    stats: WriteStats::default(),
+                    last_log_write: None,
+                    last_ss_write: None,
+                    subs: Subscriptions::new(),
+                    index: Index::new(),
+                    acks: HashMap::new(),
+                    unsaved_acks: Vec::new(),
+                    header: Some(info),
+                    lazy: None,
+                    squashed: HashMap::new(),
+                    skipped: HashMap::new(),
+                    read_only,
+                    archived,
+                    merge_lock: None,
+                    sum_filter,
+                    sum_filter_changed: false,
+                    log_tail: None,
+                };
+                part.skipped.extend(elts.take_skipped());
+                if let Some(ref info) = part.header {
+                    for data in &info.user {
+                        part.control.snapshot_policy().read_user_data(data);
+                    }
+                }
+                
+                if let Some(state) = opt_state {
+                    if let Some(tag) = tag {
+                        part.add_tag(tag, state.statesum().clone());
+                    }
+                    part.filter_sums(Some(WrittenFile::Snapshot(ss)), Some(state.statesum()));
+                    part.tips.insert(state.statesum().clone());
+                    for parent in state.parents() {
+                        part.ancestors.insert(parent.clone());
+                    }
+                    part.states.insert(state);
+                    part.control.snapshot_policy().reset();
+                    part.ss0 = ss;
+                    for ss2 in ss..ss_len {
+                        part.read_commits_for_ss(ss2, false, Recovery::Strict)?;
+                    }
+                    part.ss1 = ss_len;
+                    part.save_sum_filter();
+                    part.check_mem_limit()?;
+                }
+                
+                return Ok(part);
+            }
+        }
+        RepoError::err(RepoError::NoSnapshot)
+    }
+    
+    /// Get the repo name, contained in each file's header.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    
+    /// True if opened in read-only mode (see `open_read_only`)
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    
+    /// Get metadata from the header of the newest snapshot read, if any.
+    /// 
+    /// This is recorded when a snapshot header is first read (by `open` or a
+    /// load operation), even if reading the snapshot's data later fails, thus
+    /// calling this does no I/O.
+    pub fn header_info(&self) -> Option<&HeaderInfo> {
+        self.header.as_ref()
+    }
+    
+    /// Load all history. Shortcut for
+    /// `load_range(0, usize::MAX, Recovery::Strict)`.
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_range(0, usize::MAX, Recovery::Strict).map(|_| ())
+    }
+    /// Load latest state from history (usually including some historical
+    /// data). Shortcut for `load_range(usize::MAX, usize::MAX, Recovery::Strict)`.
+    pub fn load_latest(&mut self) -> Result<()> {
+        self.load_range(usize::MAX, usize::MAX, Recovery::Strict).map(|_| ())
+    }
+    
+    /// Load as much history as is likely needed for `goal`, sparing the
+    /// application from choosing between `load_latest` and `load_all`:
+    /// 
+    /// *   `Read`: the latest state is loaded (unless something is loaded)
+    /// *   `Edit`: as `Read`; if a merge is then required, history is loaded
+    ///     as for `Merge`
+    /// *   `Merge`: the latest state and at least the previous snapshot's
+    ///     history (foreign commits, e.g. from `sync`, usually branch from
+    ///     recent history), then older history until each tip has a common
+    ///     ancestor with the others (see `can_merge`) or nothing more is
+    ///     available
+    /// 
+    /// States dropped by `evict_history` or `retain_states` are not
+    /// reloaded (see `reload_history`), and `Control::keep_states` is not
+    /// applied to history loaded for merging.
+    /// 
+    /// Does nothing for archived partitions (see `archive`) while nothing is
+    /// loaded.
+    pub fn load_auto(&mut self, goal: LoadGoal) -> Result<()> {
+        if self.archived && !self.is_loaded() {
+            return Ok(());
+        }
+        if !self.is_loaded() {
+            self.load_latest()?;
+        }
+        let merge = match goal {
+            LoadGoal::Read => false,
+            LoadGoal::Edit => self.merge_required(),
+            LoadGoal::Merge => {
+                if self.ss0 > 0 && self.ss0 + 1 >= self.ss1 {
+                    let ss0 = self.ss0;
+                    self.load_range_impl(ss0 - 1, ss0, Recovery::Strict, false)?;
+                }
+                true
+            },
+        };
+        if !merge {
+            return Ok(());
+        }
+        
+        loop {
+            let mut tips: Vec<Sum> = self.tips.iter().cloned().collect();
+            tips.sort();
+            let mut needs = None;
+            for tip in &tips[min(1, tips.len())..] {
+                match self.can_merge(&tips[0], tip) {
+                    MergeReadiness::Ready => {},
+                    MergeReadiness::NeedsHistory(ss0, ss1) => {
+                        needs = Some((ss0, ss1));
+                        break;
+                    },
+                    MergeReadiness::Unavailable | MergeReadiness::NoState => {},
+                }
+            }
+            match needs {
+                Some((ss0, ss1)) => {
+                    debug!("Partition {}: loading snapshot {} for merge", self.name, ss0);
+                    self.load_range_impl(ss0, ss1, Recovery::Strict, false)?;
+                },
+                None => return Ok(()),
+            }
+        }
+    }
+    
+    /// Detect files written by other processes (or via other `RepoIO`
+    /// instances) since loading (see `RepoIO::refresh`), and if anything is
+    /// loaded, load new commits from the logs of the latest loaded snapshot
+    /// and any newer snapshots with their logs. Tips are updated
+    /// accordingly; a merge may then be required.
+    /// 
+    /// The logs of the latest loaded snapshot are read again in full, but
+    /// commits already known (including those dropped from memory) are
+    /// skipped. Returns true if changes to files were found.
+    pub fn refresh(&mut self) -> Result<bool> {
+        if !self.control.io_mut().refresh()? {
+            return Ok(false);
+        }
+        if self.ss1 > self.ss0 {
+            let ss = self.ss1 - 1;
+            debug!("Partition {}: refreshing logs of snapshot {}", self.name, ss);
+            self.read_commits_for_ss(ss, true, Recovery::Strict)?;
+            let ss1 = self.ss1;
+            self.load_range(ss1, usize::MAX, Recovery::Strict)?;
+        }
+        Ok(true)
+    }
+    
+    /// Load snapshots `ss` where `ss0 <= ss < ss1`, and all log files for each
+    /// snapshot loaded. If `ss0` is beyond the latest snapshot found, it will
+    /// be reduced to the number of the last snapshot. `ss1` may be large. For
+    /// example, `(0, usize::MAX)` means load everything available and
+    /// `(usize::MAX, usize::MAX)` means load only the latest state.
+    /// 
+    /// Special behaviour: if some snapshots are already loaded and the range
+    /// does not overlap with this range, all snapshots in between will be
+    /// loaded.
+    /// 
+    /// With `Recovery::Tolerant`, unreadable sections of commit logs are
+    /// skipped (see `read_log_tolerant`) and commits which cannot be applied
+    /// (e.g. since their parent was lost) are dropped. A report is returned
+    /// for each damaged log, keyed by snapshot and log number; recovered
+    /// commits listed are those which were loaded. With `Recovery::Strict`,
+    /// any error causes failure and the result is empty.
+    /// 
+    /// If `Control::keep_states` is set and there is a single tip, older
+    /// states are then dropped from memory (see `retain_states`).
+    /// 
+    /// Progress is reported to `Control::progress`, if any; if this fails,
+    /// loading is aborted (see `ProgressSink`). Loading may be cancelled
+    /// between snapshots (see `Control::cancel_flag`).
+    /// 
+    /// TODO: allow loading new & extended log files when snapshot is already loaded.
+    pub fn load_range(&mut self, ss0: usize, ss1: usize, recovery: Recovery)
+            -> Result<BTreeMap<(usize, usize), RecoveryReport>>
+    {
+        self.load_range_impl(ss0, ss1, recovery, true)
+    }
+    
+    // As `load_range`; the retention policy is only applied if `retain`.
+    fn load_range_impl(&mut self, ss0: usize, ss1: usize, recovery: Recovery, retain: bool)
+            -> Result<BTreeMap<(usize, usize), RecoveryReport>>
+    {
+        // We have to consider several cases: nothing previously loaded, that
+        // we're loading data older than what was previously loaded, or newer,
+        // or even overlapping. The algorithm we use is:
+        // 
+        //  while ss0 > 0 and not has_ss(ss0), ss0 -= 1
+        //  if ss0 == 0 and not has_ss(0), assume initial state
+        //  for ss in ss0..ss1:
+        //      if this snapshot was already loaded, skip
+        //      load snapshot if found, skip if not
+        //      load all logs found and rebuild states, aborting if parents are missing
+        
+        // Input arguments may be greater than the available snapshot numbers. Clamp:
+        let ss_len = self.control.io().ss_len();
+        let mut ss0 = min(ss0, if ss_len > 0 { ss_len - 1 } else { ss_len });
+        let mut ss1 = min(ss1, ss_len);
+        // If data is already loaded, we must load snapshots between it and the new range too:
+        let any_loaded = self.ss1 > self.ss0;
+        if any_loaded {
+            if ss0 > self.ss1 { ss0 = self.ss1; }
+            if ss1 < self.ss0 { ss1 = self.ss0; }
+        }
+        // If snapshot files are missing, we need to load older files:
+        while ss0 > 0 && !self.control.io().has_ss(ss0) { ss0 -= 1; }
+        if !any_loaded {
+            // Nothing loaded: the loaded range starts here
+            self.ss0 = ss0;
+            self.ss1 = ss0;
+        }
+        
+        if ss0 == 0 && !self.control.io().has_ss(ss0) &&
+            (ss_len == 0 || self.control.io().ss_cl_len(0) > 0)
+        {
+            // No initial snapshot; assume a blank state (unless history was
+            // pruned, in which case neither snapshot nor logs are present)
+            let state = PartState::new(self.control.as_mcm_ref_mut());
+            self.sum_filter_changed |= !self.sum_filter.contains(state.statesum());
+            self.sum_filter.insert(state.statesum());
+            self.tips.insert(state.statesum().clone());
+            self.states.insert(state);
+        }
+        
+        let progress = self.control.progress();
+        let mut require_ss = false;
+        let mut reports = BTreeMap::new();
+        for ss in ss0..ss1 {
+            // If already loaded, skip this snapshot:
+            if self.ss0 <= ss && ss < self.ss1 { continue; }
+            self.check_cancelled()?;
+            let at_tip = ss >= self.ss1;
+            
+            debug!("Partition {}: reading snapshot {}", self.name, ss);
+            let opt_result = if let Some(mut r) = self.control.io().read_ss(ss)? {
+                let head = read_head(&mut r)?;
+                if self.header.as_ref().is_none_or(|info| info.ss <= ss) {
+                    self.header = Some(HeaderInfo::new(ss, &head));
+                    self.archived = head.archived;
+                }
+                let mut elts = EltReader::new(self.control.elt_read_policy())
+                        .with_progress(progress.clone());
+                let mut r = body_reader(r, &head, self.control.cipher())?;
+                let state = read_snapshot(&mut r, head.ftype.ver(), head.dedup,
+                        &self.control.user_meta_limits(), &mut elts)?;
+                self.skipped.extend(elts.take_skipped());
+                if let Some(ref progress) = progress {
+                    progress.file_read(WrittenFile::Snapshot(ss))?;
+                }
+                Some((head, state))
+            } else {
+                warn!("Partition {}: missing snapshot {}", self.name, ss);
+                None
+            };
+            
+            if let Some((mut header, state)) = opt_result {
+                if let Some(tag) = header.tag.take() {
+                    self.add_tag(tag, state.statesum().clone());
+                }
+                self.verify_header(header)?;
+                self.record_acks(state.meta());
+                self.filter_sums(Some(WrittenFile::Snapshot(ss)), Some(state.statesum()));
+                
+                if !self.ancestors.contains(state.statesum()) {
+                    self.tips.insert(state.statesum().clone());
+                }
+                for parent in state.parents() {
+                    if !self.states.contains(parent) {
+                        self.ancestors.insert(parent.clone());
+                    }
+                }
+                // TODO: check that classification in state equals that of this partition? (Already done in this case.)
+                self.states.insert(state);
+                
+                require_ss = false;
+                if at_tip {
+                    self.control.snapshot_policy().reset();
+                }
+            } else {
+                // Missing snapshot; if at head require a new one
+                require_ss = at_tip;
+            }
+            
+            reports.append(&mut self.read_commits_for_ss(ss, false, recovery)?);
+            if at_tip {
+                self.ss1 = ss + 1;
+            }
+        }
+        
+        if ss0 < self.ss0 {
+            // Older history was loaded. In this case we can only update ss0
+            // once all older snapshots have been loaded. If there was a failure
+            // and retry, some snapshots could be reloaded unnecessarily.
+            self.ss0 = ss0;
+        }
+        assert!(self.ss0 <= ss1 && ss1 <= self.ss1);
+        
+        if require_ss {
+            self.control.snapshot_policy().force_snapshot();
+        }
+        self.save_sum_filter();
+        if retain {
+            self.apply_retention();
+        }
+        self.check_mem_limit()?;
+        Ok(reports)
+    }
+    
+    // Read commit logs for a snapshot, returning reports on damaged logs
+    // (only with `Recovery::Tolerant`). If `skip_ancestors`, commits of
+    // states not loaded but known as ancestors are skipped too.
+    fn read_commits_for_ss(&mut self, ss: usize, skip_ancestors: bool, recovery: Recovery)
+            -> Result<BTreeMap<(usize, usize), RecoveryReport>>
+    {
+        let mut queue = vec![];
+        let mut reports = BTreeMap::new();
+        let progress = self.control.progress();
+        let mut elts = EltReader::new(self.control.elt_read_policy())
+                .with_progress(progress.clone());
+        for cl in 0..self.control.io().ss_cl_len(ss) {
+            debug!("Partition {}: reading commit log {}-{}", self.name, ss, cl);
+            let start = queue.len();
+            let opt_header = if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+                let header = read_head(&mut r)?;
+                let mut r = body_reader(r, &header, self.control.cipher())?;
+                let limits = self.control.user_meta_limits();
+                if recovery == Recovery::Tolerant {
+                    let report = read_log_tolerant(&mut r, &mut queue, header.ftype.ver(),
+                            &limits, &mut elts)?;
+                    if report.is_damaged() {
+                        reports.insert((ss, cl), report);
+                    }
+                } else {
+                    read_log(&mut r, &mut queue, header.ftype.ver(), &limits, &mut elts)?;
+                }
+                Some(header)
+            } else {
+                warn!("Partition {}: missing commit log {}-{}", self.name, ss, cl);
+                None
+            };
+            if let Some(header) = opt_header {
+                if let Some(ref progress) = progress {
+                    progress.file_read(WrittenFile::CommitLog(ss, cl))?;
+                }
+                self.verify_header(header)?;
+                // Damaged logs are not covered since sums may be missing
+                let file = if reports.contains_key(&(ss, cl)) { None } else {
+                    Some(WrittenFile::CommitLog(ss, cl))
+                };
+                self.filter_sums(file, queue[start..].iter().map(|c| c.statesum()));
+            }
+        }
+        self.skipped.extend(elts.take_skipped());
+        let mut replayed = 0;
+        for commit in queue {
+            if let Some(ref progress) = progress {
+                progress.commit_replayed()?;
+            }
+            if skip_ancestors && self.ancestors.contains(commit.statesum()) {
+                continue;
+            }
+            if self.states.contains(commit.statesum()) || self.add_cached(&commit) {
+                self.record_acks(commit.meta());
+                self.record_squash(&commit);
+                continue;
+            }
+            if recovery == Recovery::Tolerant {
+                let sum = commit.statesum().clone();
+                if let Err(e) = self.add_commit(commit) {
+                    warn!("Partition {}: dropping commit {}: {}", self.name, sum, e);
+                    continue;
+                }
+            } else {
+                self.add_commit(commit)?;
+            }
+            replayed += 1;
+        }
+        self.cache_tips(replayed);
+        for report in reports.values_mut() {
+            report.recovered.retain(|(sum, _)| self.states.contains(sum));
+        }
+        Ok(reports)
+    }
+    
+    // Add the state of `commit` from the state cache instead of replaying
+    // it, if available. Cache failures are not fatal.
+    #[cfg(feature = "file-io")]
+    fn add_cached(&mut self, commit: &Commit<C::Element>) -> bool {
+        // Cached states are not encrypted, so are not used with a cipher
+        if self.control.cipher().is_some() { return false; }
+        let limits = self.control.user_meta_limits();
+        let state = match self.control.state_cache() {
+            Some(cache) => cache.get(commit.statesum(), &limits).unwrap_or_else(|e| {
+                warn!("Partition {}: state cache read failed: {}", self.name, e);
+                None
+            }),
+            None => None,
+        };
+        if let Some(state) = state {
+            trace!("Partition {}: using cached state {}", self.name, state.statesum());
+            self.add_state(state, commit.num_changes());
+            self.update_index(commit);
+            self.subs.notify_loaded(commit);
+            true
+        } else {
+            false
+        }
+    }
+    #[cfg(not(feature = "file-io"))]
+    fn add_cached(&mut self, _commit: &Commit<C::Element>) -> bool { false }
+    
+    // Add tips to the state cache if at least `min_commits` were replayed
+    #[cfg(feature = "file-io")]
+    fn cache_tips(&mut self, replayed: usize) {
+        if self.read_only || self.control.cipher().is_some() { return; }
+        if let Some(cache) = self.control.state_cache() {
+            if replayed == 0 || replayed < cache.min_commits() { return; }
+            for tip in &self.tips {
+                if let Some(state) = self.states.get(tip) {
+                    if let Err(e) = cache.put(&self.name, state) {
+                        warn!("Partition {}: state cache write failed: {}", self.name, e);
+                    }
+                }
+            }
+        }
+    }
+    #[cfg(not(feature = "file-io"))]
+    fn cache_tips(&mut self, _replayed: usize) {}
+    
+    /// The oldest snapshot number loaded
+    pub fn oldest_ss_loaded(&self) -> usize {
+        self.ss0
+    }
+    
+    /// The range of snapshot numbers loaded, `(ss0, ss1)` where snapshots
+    /// `ss` with `ss0 <= ss < ss1` (and their logs) have been loaded (as for
+    /// `load_range`). If nothing is loaded, `ss0 == ss1`.
+    pub fn loaded_range(&self) -> (usize, usize) {
+        (self.ss0, self.ss1)
+    }
+    
+    /// True if the state with this sum is loaded (see also `state()`).
+    pub fn has_state(&self, sum: &Sum) -> bool {
+        self.states.contains(sum)
+    }
+    
+    /// True unless the state with this sum is definitely not in the history
+    /// of this partition (including unloaded snapshots and logs).
+    /// 
+    /// This uses a filter of the sums of all states in files read or written
+    /// (see `rw::sumfilter`), stored via `RepoIO::write_sum_filter`. A false
+    /// result is only possible where this filter covers all files present;
+    /// otherwise (e.g. after another process wrote files) this returns true
+    /// until `rebuild_sum_filter` is called or all files are loaded. A true
+    /// result may be a false positive.
+    pub fn may_have_state(&self, sum: &Sum) -> bool {
+        if self.states.contains(sum) || self.ancestors.contains(sum) {
+            return true;
+        }
+        !self.sum_filter.covers(self.control.io()) || self.sum_filter.contains(sum)
+    }
+    
+    /// Determine whether the state with this sum is in the history of this
+    /// partition, loading older snapshots (as with `state_at`) only while
+    /// the sum filter indicates that it may be present (see
+    /// `may_have_state`). Where the filter is not available, nothing is
+    /// loaded.
+    /// 
+    /// Returns true if the state is loaded or known as an ancestor of a
+    /// loaded state, once this is done.
+    pub fn find_state(&mut self, sum: &Sum) -> Result<bool> {
+        let known = |part: &Self| part.states.contains(sum) || part.ancestors.contains(sum);
+        if !self.sum_filter.covers(self.control.io()) {
+            return Ok(known(self));
+        }
+        while !known(self) && self.sum_filter.contains(sum) && self.ss0 > 0 {
+            let ss0 = self.ss0;
+            debug!("Partition {}: loading snapshot {} for find_state", self.name, ss0 - 1);
+            self.load_range_impl(ss0 - 1, ss0, Recovery::Strict, false)?;
+            if self.ss0 == ss0 {
+                break;
+            }
+        }
+        Ok(known(self))
+    }
+    
+    /// Rebuild the sum filter (see `may_have_state`) by reading all snapshot
+    /// and commit log files, and store it (unless read-only). This is only
+    /// needed where files were written without updating the filter, e.g. by
+    /// older versions of this library or other processes.
+    pub fn rebuild_sum_filter(&mut self) -> Result<()> {
+        let limits = self.control.user_meta_limits();
+        let mut filter = SumFilter::new();
+        for ss in 0..self.control.io().ss_len() {
+            if let Some(mut r) = self.control.io().read_ss(ss)? {
+                let header = read_head(&mut r)?;
+                let mut r = body_reader(r, &header, self.control.cipher())?;
+                let state: PartState<C::Element> = read_snapshot(&mut r, header.ftype.ver(),
+                        header.dedup, &limits, &mut EltReader::default())?;
+                filter.insert(state.statesum());
+                filter.cover_snapshot(ss);
+            }
+            for cl in 0..self.control.io().ss_cl_len(ss) {
+                if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+                    let header = read_head(&mut r)?;
+                    let mut r = body_reader(r, &header, self.control.cipher())?;
+                    let mut commits: Vec<Commit<C::Element>> = Vec::new();
+                    read_log(&mut r, &mut commits, header.ftype.ver(), &limits,
+                            &mut EltReader::default())?;
+                    for commit in &commits {
+                        filter.insert(commit.statesum());
+                    }
+                    filter.cover_log(ss, cl);
+                }
+            }
+        }
+        if !self.control.io().has_ss(0) {
+            // The implied initial state
+            filter.insert(PartState::<C::Element>::new(self.control.as_mcm_ref_mut()).statesum());
+        }
+        self.sum_filter = filter;
+        self.sum_filter_changed = true;
+        self.save_sum_filter();
+        Ok(())
+    }
+    
+    /// Get elements which could not be deserialised when loading, and were
+    /// skipped or replaced by placeholders (see `Control::elt_read_policy`),
+    /// with the sum of the element data. This is cleared by `unload`.
+    /// 
+    /// Skipped elements are kept as erased elements. To repair, either make
+    /// the data readable and reload, or replace the element in a new state
+    /// (see `MutPartState::remove_erased`). Snapshots are not written of
+    /// states still holding skipped elements, since this would lose data.
+    pub fn skipped_elts(&self) -> &HashMap<EltId, Sum> {
+        &self.skipped
+    }
+    
+    /// Returns true when elements have been loaded (i.e. there is at least one
+    /// tip; see also `is_ready` and `merge_required`).
+    pub fn is_loaded(&self) -> bool {
+        !self.tips.is_empty()
+    }
+    
+    /// Returns true when ready for use (this is equivalent to
+    /// `self.is_loaded() && !self.merge_required()`, i.e. there is exactly
+    /// one tip).
+    pub fn is_ready(&self) -> bool {
+        self.tips.len() == 1
+    }
+    
+    /// Returns true while a merge is required (i.e. there is more than one
+    /// tip).
+    /// 
+    /// Returns false if not ready or no tip is found as well as when a single
+    /// tip is present and ready to use.
+    pub fn merge_required(&self) -> bool {
+        self.tips.len() > 1
+    }
+    
+    /// Approximate memory used by loaded states and their elements, in bytes.
+    /// 
+    /// Elements shared between states are counted once. The estimate relies
+    /// on `Element::mem_size()` and `PartState::mem_overhead()`; unsaved
+    /// commits and other bookkeeping are not included.
+    pub fn mem_usage(&self) -> usize {
+        let mut seen = HashSet::new();
+        let mut usage = 0;
+        for state in self.states.iter() {
+            usage += state.mem_overhead();
+            for (_, elt) in state.elts_iter() {
+                if seen.insert(&**elt as *const C::Element) {
+                    // element plus reference counts
+                    usage += elt.mem_size() + 2 * size_of::<usize>();
+                }
+            }
+        }
+        usage
+    }
+    
+    // Fail if commits may not be added (see `open_read_only`).
+    fn check_push(&self) -> Result<(), PatchOp> {
+        if self.read_only {
+            Err(PatchOp::ReadOnly)
+        } else if self.archived {
+            Err(PatchOp::Archived)
+        } else {
+            Ok(())
+        }
+    }
+    
+    // Fail if files may not be written (see `open_read_only`).
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only { ReadOnly::err() } else { Ok(()) }
+    }
+    
+    // Fail if cancellation was requested (see `Control::cancel_flag`).
+    fn check_cancelled(&self) -> Result<()> {
+        match self.control.cancel_flag() {
+            Some(ref flag) if flag.load(Ordering::Relaxed) => RepoError::err(RepoError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+    
+    // Fail if adding `n` commits would exceed `Control::max_unsaved()`.
+    fn check_unsaved_limit(&self, n: usize) -> Result<(), PatchOp> {
+        match self.control.max_unsaved() {
+            Some(limit) if self.unsaved.len() + n > limit => {
+                warn!("Partition {}: {} unsaved commits; refusing new commits", self.name, self.unsaved.len());
+                Err(PatchOp::UnsavedLimit)
+            },
+            _ => Ok(()),
+        }
+    }
+    
+    // Enforce `Control::mem_limit()`: evict history if necessary, and fail if
+    // usage still exceeds the limit.
+    fn check_mem_limit(&mut self) -> Result<()> {
+        if let Some(limit) = self.control.mem_limit() {
+            let mut usage = self.mem_usage();
+            if usage > limit && self.evict_history() > 0 {
+                usage = self.mem_usage();
+            }
+            if usage > limit {
+                return MemLimit::err(usage, limit);
+            }
+        }
+        Ok(())
+    }
+    
+    // Verify values in a header.
+    fn verify_header(&mut self, header: FileHeader) -> Result<()> {
+        if self.name != header.name {
+            return RepoError::err(RepoError::NameMismatch {
+                expected: self.name.clone(),
+                found: header.name,
+            });
+        }
+        
+        self.control.read_header(&header)?;
+        if let FileType::Snapshot(_) = header.ftype {
+            for data in &header.user {
+                self.control.snapshot_policy().read_user_data(data);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Create a header
+    fn make_header(&mut self, file_type: FileType) -> Result<FileHeader> {
+        let (reproducible, dedup, archived) = match file_type {
+            FileType::Snapshot(_) => (self.control.reproducible_snapshots(),
+                    self.control.dedup_snapshots(), self.archived),
+            FileType::CommitLog(_) => (false, false, false),
+        };
+        let mut header = FileHeader {
+            ftype: file_type,
+            name: self.name.clone(),
+            user: vec![],
+            tag: None,
+            compression: self.control.compression(),
+            dedup,
+            cipher: self.control.cipher().map(|c| CipherInfo::of(&*c)),
+            archived,
+        };
+        if !reproducible {
+            header.user = self.control.make_user_data(&header)?;
+            if let FileType::Snapshot(_) = header.ftype {
+                header.user.extend(self.control.snapshot_policy().user_data());
+            }
+        }
+        Ok(header)
+    }
+    
+    /// Unload data from memory. Note that unless `force == true` the operation
+    /// will fail if any changes have not yet been saved to disk.
+    /// 
+    /// Returns true if data was unloaded, false if not (implies `!force` and 
+    /// that unsaved changes exist).
+    pub fn unload(&mut self, force: bool) -> bool {
+        trace!("Unloading partition {} data", self.name);
+        if force || self.unsaved.is_empty() {
+            self.states.clear();
+            self.ancestors.clear();
+            self.squashed.clear();
+            self.skipped.clear();
+            self.tips.clear();
+            self.tags.clear();
+            self.index.clear();
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Drop all states except tips from memory, returning the number dropped.
+    /// 
+    /// Evicted states are remembered as ancestors, but their data is lost
+    /// until the partition is unloaded and reloaded; this may prevent merges
+    /// from finding a common ancestor. Files are not affected.
+    pub fn evict_history(&mut self) -> usize {
+        let n = self.evict_states(|_| true);
+        debug!("Partition {}: evicted {} historical states", self.name, n);
+        n
+    }
+    
+    // Drop non-tip states matching `pred` from memory, remembering them as
+    // ancestors. Returns the number dropped. All history eviction uses this.
+    fn evict_states<F: Fn(&PartState<C::Element>) -> bool>(&mut self, pred: F) -> usize {
+        let keys: Vec<Sum> = self.states.iter()
+                .filter(|state| !self.tips.contains(state.statesum()) && pred(state))
+                .map(|state| state.statesum().clone())
+                .collect();
+        for key in &keys {
+            self.states.remove(key);
+            self.ancestors.insert(key.clone());
+        }
+        keys.len()
+    }
+    
+    /// Drop states from memory except tips, up to `keep` of their nearest
+    /// ancestors, and the states of unsaved commits and their parents.
+    /// Returns the number of states dropped.
+    /// 
+    /// As with `evict_history`, dropped states are remembered as ancestors
+    /// (see `reload_history`). This is done automatically after loading and
+    /// merging when `Control::keep_states` is set and there is a single tip.
+    pub fn retain_states(&mut self, keep: usize) -> usize {
+        let mut retained: HashSet<Sum> = self.tips.iter().cloned().collect();
+        for commit in &self.unsaved {
+            retained.insert(commit.statesum().clone());
+            retained.extend(commit.parents().iter().cloned());
+        }
+        
+        // Breadth-first search from tips, nearest ancestors first:
+        let mut level: Vec<Sum> = self.tips.iter().cloned().collect();
+        let mut n = 0;
+        while n < keep && !level.is_empty() {
+            let mut next = Vec::new();
+            for sum in &level {
+                if let Some(state) = self.states.get(sum) {
+                    next.extend(state.parents().iter()
+                            .filter(|p| self.states.contains(*p) && !retained.contains(*p))
+                            .cloned());
+                }
+            }
+            next.sort();
+            next.dedup();
+            next.truncate(keep - n);
+            n += next.len();
+            retained.extend(next.iter().cloned());
+            level = next;
+        }
+        
+        let n = self.evict_states(|state| !retained.contains(state.statesum()));
+        if n > 0 {
+            debug!("Partition {}: dropped {} historical states", self.name, n);
+        }
+        n
+    }
+    
+    // Apply `Control::keep_states`, if set and there is a single tip.
+    fn apply_retention(&mut self) {
+        if let Some(keep) = self.control.keep_states() {
+            if self.tips.len() == 1 {
+                self.retain_states(keep);
+            }
+        }
+    }
+    
+    /// Reload all snapshots and logs previously loaded, including states
+    /// dropped from memory (e.g. by `retain_states`), without applying
+    /// `Control::keep_states`.
+    /// 
+    /// Fails if there are unsaved commits (write these first).
+    pub fn reload_history(&mut self) -> Result<()> {
+        if !self.unsaved.is_empty() {
+            return RepoError::err(RepoError::UnsavedCommits);
+        }
+        let (ss0, ss1) = (self.ss0, self.ss1);
+        self.unload(false);
+        self.ss0 = 0;
+        self.ss1 = 0;
+        self.load_range_impl(ss0, max(ss1, ss0 + 1), Recovery::Strict, false).map(|_| ())
+    }
+    
+    /// Drop non-tip states committed before `before` (a timestamp, as from
+    /// `CommitMeta::timestamp()`) from memory, then shrink internal
+    /// containers. Returns the number of states dropped.
+    /// 
+    /// As with `evict_history`, dropped states are remembered as ancestors
+    /// but their data is lost until reloaded. This is also done
+    /// automatically by `write_full` (dropping all non-tip states) when usage
+    /// exceeds `Control::mem_limit()`.
+    pub fn compact_memory(&mut self, before: i64) -> usize {
+        let n = self.evict_states(|state| state.meta().timestamp() < before);
+        self.states.shrink_to_fit();
+        self.ancestors.shrink_to_fit();
+        self.tips.shrink_to_fit();
+        self.unsaved.shrink_to_fit();
+        debug!("Partition {}: compacted; dropped {} historical states", self.name, n);
+        n
+    }
+    
+    /// Get a reference to the partition's `Control` (e.g. to access its
+    /// `RepoIO` for reading).
+    pub fn control(&self) -> &C {
+        &self.control
+    }
+    
+    /// Consume the `Partition` and return the held `RepoIO`.
+    /// 
+    /// This destroys all states held internally, but states may be cloned
+    /// before unwrapping. Since `Element`s are copy-on-write, cloning
+    /// shouldn't be too expensive.
+    pub fn unwrap_control(self) -> C {
+        self.control
+    }
+    
+    /// Close the partition: write unsaved commits (as `write_full`, thus
+    /// also writing a snapshot if the snapshot policy requests one), then
+    /// call `Control::close` to release locks or other resources, and drop.
+    /// Nothing is written if there are no unsaved commits or the partition
+    /// is read-only (see `open_read_only`).
+    /// 
+    /// Unlike simply dropping the partition, failures are reported. On error
+    /// the partition is dropped anyway; any data not yet written is lost.
+    /// Acknowledgements not attached to a written commit or snapshot (see
+    /// `mark_acked`) are also lost; a warning is logged.
+    pub fn close(mut self) -> Result<()> {
+        debug!("Partition {}: closing", self.name);
+        if !self.read_only && !self.unsaved.is_empty() {
+            self.write_full()?;
+        }
+        if !self.unsaved_acks.is_empty() {
+            warn!("Partition {}: closing with {} unwritten acknowledgements",
+                    self.name, self.unsaved_acks.len());
+        }
+        self.control.close()
+    }
+}
+
+// Methods accessing or modifying a partition's data
+impl<C: Control> Partition<C> {
+    /// Get a reference to the PartState of the current tip. You can read
+    /// this directly or make a clone in order to make your modifications.
+    /// 
+    /// This operation will fail if no data has been loaded yet or if a merge
+    /// is required (i.e. it fails if the number of tips is not exactly one).
+    pub fn tip(&self) -> result::Result<&PartState<C::Element>, TipError> {
+        Ok(self.states.get(self.tip_key()?).unwrap())
+    }
+    
+    /// Get the state-sum (key) of the tip. Fails when `tip()` fails.
+    pub fn tip_key(&self) -> result::Result<&Sum, TipError> {
+        if self.tips.len() == 1 {
+            Ok(self.tips.iter().next().unwrap())
+        } else if self.tips.is_empty() {
+            Err(TipError::NotReady)
+        } else {
+            Err(TipError::MergeRequired)
+        }
+    }
+    
+    /// Get the number of tips.
+    pub fn tips_len(&self) -> usize {
+        self.tips.len()
+    }
+    
+    /// Get an iterator over tips.
+    pub fn tips_iter(&self) -> TipIter {
+        TipIter { iter: self.tips.iter() }
+    }
+    
+    /// Get the set of all tips. Is empty before loading and has more than one
+    /// entry when `merge_required()`. May be useful for merging.
+    pub fn tips(&self) -> &HashSet<Sum> {
+        &self.tips
+    }
+    
+    /// Get the number of states.
+    /// 
+    /// Tips are a subset of states, so `tips_len() <= states_len()`.
+    pub fn states_len(&self) -> usize {
+        self.states.len()
+    }
+    
+    /// Iterate over all states which have been loaded (see `load_...` functions).
+    /// 
+    /// Items are unordered (actually, they follow the order of an internal
+    /// hash map, which is randomised and usually different each time the
+    /// program is loaded).
+    pub fn states_iter(&self) -> StateIter<C::Element> {
+        StateIter { iter: self.states.iter(), tips: &self.tips }
+    }
+    
+    /// Get a read-only reference to a state by its statesum, if found.
+    /// 
+    /// If you want to keep a copy, clone it.
+    pub fn state(&self, key: &Sum) -> Option<&PartState<C::Element>> {
+        self.states.get(key)
+    }
+    
+    /// Get the value of element `id` under each tip, as pairs of tip
+    /// state-sum and value, ordered by state-sum. Tips where the element is
+    /// not present are omitted.
+    /// 
+    /// Unlike `tip()`, this does not fail when a merge is required, thus
+    /// allows reading (e.g. to show conflicting values) while a merge is
+    /// pending. With a single tip, the result has at most one entry.
+    pub fn get_candidates(&self, id: EltId) -> Vec<(Sum, &Rc<C::Element>)> {
+        let mut candidates: Vec<_> = self.tips.iter()
+                .filter_map(|sum| self.states.get(sum))
+                .filter_map(|state| state.get_rc(id).ok()
+                        .map(|elt| (state.statesum().clone(), elt)))
+                .collect();
+        candidates.sort_by(|a, b| a.0.cmp(&b.0));
+        candidates
+    }
+    
+    /// Get multiple elements from the tip (see `StateRead::get_many`),
+    /// loading the latest state first if nothing is loaded.
+    /// 
+    /// Fails if loading fails or there is not a single tip.
+    pub fn get_many(&mut self, ids: &[EltId]) -> Result<Vec<Option<&Rc<C::Element>>>> {
+        if !self.is_loaded() {
+            self.load_latest()?;
+        }
+        Ok(self.tip()?.get_many(ids))
+    }
+    
+    /// Get the sums of all loaded states whose text form starts with
+    /// `prefix` (see `Sum::matches_prefix`: case-insensitive, ignoring
+    /// whitespace and `:` separators), sorted. Useful e.g. for completion.
+    pub fn sums_with_prefix(&self, prefix: &str) -> Vec<&Sum> {
+        let mut sums: Vec<&Sum> = self.states.iter()
+                .map(|state| state.statesum())
+                .filter(|sum| sum.matches_prefix(prefix))
+                .collect();
+        sums.sort();
+        sums
+    }
+    
+    /// Try to find a state given a string representation of the key (see
+    /// `sums_with_prefix`).
+    /// 
+    /// Like git, we accept partial keys (so long as they uniquely resolve a
+    /// key). Where several loaded states match, `MatchError::MultiMatch`
+    /// lists all of them.
+    pub fn state_from_string(&self, string: String) -> Result<&PartState<C::Element>, MatchError> {
+        let sums = self.sums_with_prefix(&string);
+        match sums.len() {
+            0 => Err(MatchError::NoMatch),
+            1 => Ok(self.states.get(sums[0]).expect("has state")),
+            _ => Err(MatchError::MultiMatch(sums.iter().map(|sum| sum.to_hex()).collect())),
+        }
+    }
+    
+    /// Move elements selected by `pred` into a new partition, created with
+    /// `control` and `name`, keeping their identifiers.
+    /// 
+    /// Element identifiers are not tied to a partition, thus external
+    /// references to moved elements remain valid; they need only be looked up
+    /// in the new partition. The new partition is written first (a commit
+    /// log and a snapshot), then the elements are removed from this
+    /// partition and a snapshot tagged `"split"` is written, recording the
+    /// state immediately after the split (see `tagged()`). Should an error
+    /// occur between the two steps, elements may be present in both
+    /// partitions but are not lost.
+    /// 
+    /// Fails if not ready (see `tip()`). Returns the new partition.
+    pub fn split_off<C2, F>(&mut self, control: C2, name: &str, mut pred: F) ->
+            Result<Partition<C2>>
+        where C2: Control<Element = C::Element>, F: FnMut(EltId, &C::Element) -> bool
+    {
+        self.check_writable()?;
+        let mut ours = self.tip()?.clone_mut();
+        let ids: Vec<EltId> = ours.elts_iter()
+                .filter(|&(id, elt)| pred(id, elt))
+                .map(|(id, _)| id)
+                .collect();
+        
+        let mut other = Partition::create(control, name)?;
+        let mut theirs = other.tip()?.clone_mut();
+        let n = ids.len();
+        for id in ids {
+            theirs.insert_rc(id, ours.remove(id)?)?;
+        }
+        other.push_state(theirs)?;
+        other.write_fast()?;
+        other.write_snapshot()?;
+        info!("Split {} elements from partition {} into {}", n, self.name, other.name);
+        
+        self.push_state(ours)?;
+        self.write_fast()?;
+        let tip_key = self.tip_key()?.clone();
+        self.write_snapshot_of(&tip_key, Some("split"))?;
+        self.add_tag("split".to_string(), tip_key);
+        self.record_audit(AuditOp::SplitOff, format!("moved {} elements to partition {}", n, other.name));
+        Ok(other)
+    }
+    
+    /// This adds a new commit to the list waiting to be written and updates
+    /// the states and 'tips' stored internally by creating a new state from
+    /// the commit.
+    /// 
+    /// Mutates the commit in the (very unlikely) case that its statesum
+    /// clashes with another commit whose data is different.
+    /// 
+    /// Fails if the commit's parent is not found or the patch cannot be
+    /// applied to it. In this case the commit is lost, but presumably either
+    /// there was a programmatic error or memory corruption for this to occur.
+    /// Also fails if the commit's user metadata exceeds
+    /// `Control::user_meta_limits()`.
+    /// 
+    /// Fails with `PatchOp::UnsavedLimit` if `Control::max_unsaved()` commits
+    /// are already waiting to be written.
+    /// 
+    /// Returns `Ok(true)` on success or `Ok(false)` if the commit matches an
+    /// already known state.
+    pub fn push_commit(&mut self, commit: Commit<C::Element>) -> Result<bool, PatchOp> {
+        self.check_push()?;
+        commit.meta().extra().validate(&self.control.user_meta_limits())
+            .map_err(|_| PatchOp::MetaLimit)?;
+        self.check_unsaved_limit(1)?;
+        let state = {
+            let parent = self.states.get(commit.first_parent())
+                .ok_or(PatchOp::NoParent)?;
+            PartState::from_state_commit(parent, &commit)?
+        };  // end borrow on self (from parent)
+        Ok(self.add_pair(commit, state))
+    }
+    
+    /// Push a chain of commits (see `CommitChain`), each the child of the
+    /// previous, atomically: either all commits are added or none are.
+    /// 
+    /// Fails if the first commit's parent is not found, if any other commit
+    /// is not parented on the previous one, if any patch fails to apply, if
+    /// user metadata exceeds limits (as for `push_commit`), if the chain would
+    /// exceed `Control::max_unsaved()`, or in the (very unlikely) case that a
+    /// state sum clashes with a different known state.
+    /// 
+    /// Returns the number of commits added (commits matching already known
+    /// states are skipped).
+    pub fn push_chain(&mut self, chain: Vec<Commit<C::Element>>) -> Result<usize, PatchOp> {
+        self.check_push()?;
+        let limits = self.control.user_meta_limits();
+        let mut pairs: Vec<(_, PartState<_>)> = Vec::with_capacity(chain.len());
+        for commit in chain {
+            commit.meta().extra().validate(&limits).map_err(|_| PatchOp::MetaLimit)?;
+            let state = {
+                let parent = match pairs.last() {
+                    Some(pair) => &pair.1,
+                    None => self.states.get(commit.first_parent()).ok_or(PatchOp::NoParent)?,
+                };
+                PartState::from_state_commit(parent, &commit)?
+            };
+            if let Some(old_state) = self.states.get(state.statesum()) {
+                if state != *old_state {
+                    return Err(PatchOp::PatchApply);
+                }
+            }
+            pairs.push((commit, state));
+        }
+        self.check_unsaved_limit(pairs.len())?;
+        
+        let mut n = 0;
+        for (commit, state) in pairs {
+            if self.add_pair(commit, state) {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+    
+    /// Add a new state, assumed to be derived from an existing known state.
+    /// 
+    /// This creates a commit from the given state, converts the `MutPartState`
+    /// to a `PartState` and adds it to the list of internal states, and
+    /// updates the tip. The commit is added to the internal list
+    /// waiting to be written to permanent storage (see `write()`).
+    /// 
+    /// Mutates the commit in the (very unlikely) case that its statesum
+    /// clashes with another commit whose data is different.
+    /// 
+    /// Fails if the parent is not found or if the user metadata created for
+    /// the commit exceeds `Control::user_meta_limits()`. Also fails with
+    /// `PatchOp::UnsavedLimit` if `Control::max_unsaved()` commits are
+    /// already waiting to be written; the caller should write (see
+    /// `write_fast`) and retry, or report the failure.
+    /// 
+    /// If `Control::coalesce_window` is set, the new commit may replace the
+    /// unsaved commit it follows (see there); this is not subject to the
+    /// unsaved limit.
+    /// 
+    /// If `Control::derived_elements` is set and the state has changes, the
+    /// hook is first invoked to update derived elements. If
+    /// `Control::text_canon` is set, changed elements are then replaced by
+    /// their canonical form (see `Element::canonicalise`).
+    /// 
+    /// The parent need not be a tip, in which case a new tip is created and a
+    /// merge is required. If the parent is not loaded but the state was
+    /// cloned via `PartState::clone_mut_detached`, the parent is restored
+    /// from that copy, provided it is known as an ancestor (i.e. was loaded
+    /// and later dropped from memory).
+    /// 
+    /// Returns `Ok(true)` on success, or `Ok(false)` if the state matches its
+    /// parent (i.e. hasn't been changed) or another already known state.
+    pub fn push_state(&mut self, mut state: MutPartState<C::Element>) -> Result<bool, PatchOp> {
+        self.check_push()?;
+        let parent_sum = state.parent().clone();
+        if let Some(parent) = state.take_detached() {
+            if !self.states.contains(&parent_sum) {
+                if !self.ancestors.contains(&parent_sum) || parent.statesum() != &parent_sum {
+                    return Err(PatchOp::NoParent);
+                }
+                trace!("Partition {}: restoring detached parent {}", self.name, parent_sum);
+                self.ancestors.remove(&parent_sum);
+                self.states.insert(parent);
+            }
+        }
+        if !state.changed_ids().is_empty() {
+            if let Some(derived) = self.control.derived_elements() {
+                let parent = self.states.get(&parent_sum).ok_or(PatchOp::NoParent)?;
+                derived.derive(parent, &mut state)?;
+            }
+            if let Some(canon) = self.control.text_canon() {
+                canonicalise_elts(&mut state, &*canon)?;
+            }
+        }
+        let ops = state.take_ops();
+        let new_state = PartState::from_mut(state, self.control.as_mcm_ref_mut());
+        new_state.meta().extra().validate(&self.control.user_meta_limits())
+            .map_err(|_| PatchOp::MetaLimit)?;
+        
+        // #0019: Commit::from_diff compares old and new states and code be slow.
+        // #0019: Instead, we could record each alteration as it happens.
+        let mut commit = match Commit::from_diff(
+                self.states.get(&parent_sum).ok_or(PatchOp::NoParent)?, &new_state) {
+            Some(commit) => commit,
+            None => return Ok(false),
+        };
+        
+        if let Some(base_sum) = self.coalesce_base(&parent_sum, new_state.meta().timestamp()) {
+            // Changes relative to the base are re-applied there; operations
+            // are relative to the parent thus are not kept.
+            let base = self.states.get(&base_sum).unwrap();
+            if let Some(changes) = Commit::from_diff(base, &new_state) {
+                let mut state = base.clone_mut();
+                changes.apply_mut(&mut state)?;
+                let state = PartState::from_mut(state, self.control.as_mcm_ref_mut());
+                let commit = Commit::from_diff(base, &state).expect("has changes");
+                trace!("Partition {}: coalescing commit {} into {}",
+                        self.name, parent_sum, state.statesum());
+                self.unsaved.pop_back();
+                self.states.remove(&parent_sum);
+                self.tips.remove(&parent_sum);
+                return Ok(self.add_pair(commit, state));
+            }
+        }
+        
+        self.check_unsaved_limit(1)?;
+        commit.set_operations(ops);
+        Ok(self.add_pair(commit, new_state))
+    }
+    
+    /// Check whether pushing `state` would require a merge.
+    /// 
+    /// Returns false if the state's parent is the (single) tip. Otherwise
+    /// returns true if any element changed in `state` (relative to its parent)
+    /// was also changed between the parent and some tip, i.e. the changes
+    /// conflict with something committed since `state` was cloned. If the
+    /// changes touch distinct elements this returns false, and the caller may
+    /// choose to rebase (re-apply the changes on top of the tip) instead of
+    /// pushing and merging.
+    /// 
+    /// Returns true if the parent state is not known (nothing can be
+    /// determined in this case).
+    /// 
+    /// Operation is `O(N + T * X)` where `N` is the number of elements, `T`
+    /// the number of tips and `X` the number of changed elements.
+    pub fn would_conflict(&self, state: &MutPartState<C::Element>) -> bool {
+        if self.tips.len() == 1 && self.tips.contains(state.parent()) {
+            return false;
+        }
+        let parent = match self.states.get(state.parent()) {
+            Some(parent) => parent,
+            None => return true,
+        };
+        
+        let mut changed = Vec::new();
+        for (id, elt) in state.elts_iter() {
+            if parent.get_rc(id).ok() != Some(elt) {
+                changed.push(id);
+            }
+        }
+        for (id, _) in parent.elts_iter() {
+            if !state.is_avail(id) {
+                changed.push(id);
+            }
+        }
+        
+        for tip_key in &self.tips {
+            if tip_key == state.parent() { continue; }
+            let tip = match self.states.get(tip_key) {
+                Some(tip) => tip,
+                None => return true,
+            };
+            if changed.iter().any(|id| tip.get_rc(*id).ok() != parent.get_rc(*id).ok()) {
+                return true;
+            }
+        }
+        false
+    }
+    
+    /// Revert the commit which yielded state `sum`: push a commit on the tip
+    /// restoring each element it changed to its value before the commit
+    /// (see `Commit::invert`). For merge commits, changes relative to the
+    /// first parent are reverted.
+    /// 
+    /// The state and its first parent must be loaded, and there must be a
+    /// single tip. Fails with `PatchOp::Conflict` if any element to be
+    /// restored was changed by a later commit (i.e. differs between `sum`
+    /// and the tip); nothing is changed in this case.
+    /// 
+    /// Returns `Ok(false)` if the commit made no changes to elements.
+    pub fn revert(&mut self, sum: &Sum) -> Result<bool> {
+        let tip = self.tip()?;
+        let state = self.states.get(sum).ok_or(PatchOp::NoParent)?;
+        let parent = state.parents().first()
+                .and_then(|parent| self.states.get(parent))
+                .ok_or(PatchOp::NoParent)?;
+        let inverse = Commit::recreate(parent, state).invert(parent, self.control.as_mcm_ref())?;
+        if inverse.num_changes() == 0 {
+            return Ok(false);
+        }
+        for (id, _) in inverse.changes_iter() {
+            if tip.get_rc(*id).ok() != state.get_rc(*id).ok() ||
+                    tip.erased_sum(*id) != state.erased_sum(*id)
+            {
+                return Err(Box::new(PatchOp::Conflict));
+            }
+        }
+        let mut new_state = tip.clone_mut();
+        inverse.apply_mut(&mut new_state)?;
+        Ok(self.push_state(new_state)?)
+    }
+    
+    /// Subscribe to changes of the given elements. After each new commit
+    /// affecting any of these elements (including merges, but not commits
+    /// loaded from storage), a `Notification` is queued; collect these with
+    /// `take_notifications`.
+    /// 
+    /// Notifications are queued until collected or the subscription is
+    /// removed with `unsubscribe`.
+    pub fn subscribe<I>(&mut self, ids: I) -> SubscriptionId
+        where I: IntoIterator<Item = EltId>
+    {
+        self.subs.add(ids.into_iter().collect())
+    }
+    
+    /// Remove a subscription. Returns false if not found.
+    pub fn unsubscribe(&mut self, sub: SubscriptionId) -> bool {
+        self.subs.remove(sub)
+    }
+    
+    /// Access the set of elements of a subscription, e.g. to add elements.
+    /// Returns `None` if not found.
+    pub fn subscription_ids_mut(&mut self, sub: SubscriptionId) -> Option<&mut HashSet<EltId>> {
+        self.subs.ids_mut(sub)
+    }
+    
+    /// Take all pending notifications of a subscription, oldest first.
+    /// Returns an empty list if the subscription is not found.
+    pub fn take_notifications(&mut self, sub: SubscriptionId) -> Vec<Notification> {
+        self.subs.take(sub).unwrap_or_default()
+    }
+    
+    /// Watch changes to elements passing `filter`: a `Notification` is sent
+    /// on the returned channel for each commit applied which affects such
+    /// elements, whether new (`push_state`, `push_commit`, merges, commits
+    /// received by `sync`) or loaded from storage (`add_commit`, load
+    /// operations). Commits whose state is already known are not reported.
+    /// 
+    /// The watcher is removed once the receiver is dropped.
+    pub fn watch(&mut self, filter: WatchFilter) -> Receiver<Notification> {
+        self.subs.watch(filter)
+    }
+    
+    /// Find elements of the tip with `key` in secondary index `index` (the
+    /// position of its function in `Control::index_fns`), sorted by
+    /// identifier.
+    /// 
+    /// Indexes are updated incrementally as commits are added on top of the
+    /// indexed state; otherwise (e.g. on first use, or after a merge) they
+    /// are rebuilt from the tip. Fails if there is not a single tip or the
+    /// index does not exist.
+    pub fn find_by_index(&mut self, index: usize, key: &IndexKey) -> Result<Vec<EltId>> {
+        let tip = self.tip_key()?.clone();
+        let fns = self.control.index_fns();
+        if !self.index.is_current(&tip, fns.len()) {
+            trace!("Partition {}: rebuilding indexes for {}", self.name, tip);
+            self.index.rebuild(self.states.get(&tip).expect("has tip"), fns);
+        }
+        match self.index.find(index, key) {
+            Some(ids) => Ok(ids),
+            None => ArgError::err("no such index"),
+        }
+    }
+    
+    // Update indexes after adding `commit` and its state
+    fn update_index(&mut self, commit: &Commit<C::Element>) {
+        if let Some(state) = self.states.get(commit.statesum()) {
+            self.index.apply(commit, state, self.control.index_fns());
+        }
+    }
+    
+    /// The number of commits waiting to be written to permanent storage by
+    /// the `write(...)` function.
+    pub fn unsaved_len(&self) -> usize {
+        self.unsaved.len()
+    }
+    
+    /// Require that a snapshot be written the next time `write_full` is called.
+    /// (This property is not persisted across save/load.)
+    pub fn require_snapshot(&mut self) {
+        self.control.snapshot_policy().force_snapshot()
+    }
+    
+    /// This will write all unsaved commits to a log on the disk. Does nothing
+    /// if there are no queued changes.
+    /// 
+    /// Also see `write_full()`.
+    /// 
+    /// Returns true if any commits were written (i.e. unsaved commits
+    /// were found). Returns false if nothing needed doing.
+    /// 
+    /// Note that writing to disk can fail. In this case all commits remain
+    /// unsaved (see `unsaved_len`) and it may be worth trying again.
+    pub fn write_fast(&mut self) -> Result<bool> {
+        self.check_writable()?;
+        // First step: write commits
+        if self.unsaved.is_empty() {
+            return Ok(false);
+        }
+        
+        self.attach_acks();
+        if self.append_log()? {
+            return Ok(true);
+        }
+        
+        let header = self.make_header(FileType::CommitLog(0))?;
+        let mut cl_num = self.control.io().ss_cl_len(self.ss1 - 1);
+        let cipher = self.control.cipher();
+        debug!("Partition {}: writing {} commits to log {}-{}",
+                self.name, self.unsaved.len(), self.ss1-1, cl_num);
+        loop {
+            let written = if let Some(writer) = self.control.io_mut().new_ss_cl(self.ss1 - 1, cl_num)? {
+                let unsaved = &self.unsaved;
+                let mut raw = CountingWriter::new(writer);
+                let result = (|| -> Result<(LogIndex, u64)> {
+                    // Write a header since this is a new file:
+                    write_head(&header, &mut raw)?;
+                    let head_len = raw.count();
+                    // Offsets in the index are of uncompressed data:
+                    let mut enc = EncryptWriter::new(&mut raw, &header, cipher.clone());
+                    let mut writer = CountingWriter::new(
+                            CompressWriter::new(&mut enc, header.compression)?);
+                    start_log(&mut writer)?;
+                    let mut index = LogIndex::new(head_len + writer.count());
+                    
+                    // Now write commits:
+                    let mut changed = 0;
+                    for commit in unsaved {
+                        let sum = write_commit(commit, &mut writer)?;
+                        index.push(head_len + writer.count(), sum);
+                        changed += changed_bytes(commit)?;
+                    }
+                    // Compression and encryption buffer data; nothing is
+                    // written until all layers are finished.
+                    writer.into_inner().finish()?;
+                    enc.finish()?;
+                    Ok((index, changed))
+                })();
+                result.map(|(index, changed)| (index, changed, raw.count()))
+            } else {
+                // Log file already exists! So try another number.
+                if cl_num > 1000_000 {
+                    // We should give up eventually. When is arbitrary.
+                    return RepoError::err(RepoError::LogNumberOverflow { ss_num: self.ss1 - 1 });
+                }
+                cl_num += 1;
+                continue;
+            };
+            
+            // After the writer has been closed; only now are commits removed
+            // from the list of 'unsaved' commits.
+            let (index, changed, log_bytes) = match written {
+                Ok(result) => result,
+                Err(e) => {
+                    // Do not leave a partial log:
+                    if !self.control.io_mut().remove_ss_cl(self.ss1 - 1, cl_num).unwrap_or(false) {
+                        warn!("Partition {}: unable to remove partial log {}-{}",
+                                self.name, self.ss1 - 1, cl_num);
+                    }
+                    return Err(e);
+                },
+            };
+            let sums: Vec<Sum> = self.unsaved.drain(..)
+                    .map(|commit| commit.statesum().clone()).collect();
+            self.stats.logs += 1;
+            self.stats.commits += sums.len();
+            self.stats.changed_bytes += changed;
+            self.stats.log_bytes += log_bytes;
+            self.merge_lock = None;
+            self.last_log_write = self.control.clock().now();
+            self.control.snapshot_policy().count_log_bytes(log_bytes);
+            self.control.file_written(WrittenFile::CommitLog(self.ss1 - 1, cl_num));
+            self.filter_sums(Some(WrittenFile::CommitLog(self.ss1 - 1, cl_num)), &sums);
+            self.save_sum_filter();
+            // The index is an optimisation; failure to write it is not an error.
+            // Offsets in compressed or encrypted files are unknown, so these
+            // are not indexed.
+            if header.compression == Compression::None && header.cipher.is_none() {
+                if let Err(e) = self.write_log_index(self.ss1 - 1, cl_num, &index) {
+                    warn!("Partition {}: failed to write index of log {}-{}: {}",
+                            self.name, self.ss1 - 1, cl_num, e);
+                }
+                self.log_tail = Some((self.ss1 - 1, cl_num, index));
+            }
+            return Ok(true);
+        }
+    }
+    
+    /// Write barrier: write all unsaved commits (as `write_fast`), then make
+    /// everything written durable (see `RepoIO::sync`) before returning.
+    /// Once this succeeds, all commits pushed before the call survive a crash
+    /// or power loss, thus their success may be reported to the user.
+    /// 
+    /// Fails if the partition is read-only. On failure, unwritten commits
+    /// remain queued and the barrier may be retried.
+    pub fn barrier(&mut self) -> Result<()> {
+        self.write_fast()?;
+        self.control.io_mut().sync()
+    }
+    
+    /// This will write all unsaved commits to a log on the disk, then write a
+    /// snapshot if needed. If memory usage exceeds `Control::mem_limit()`,
+    /// historical states are then dropped (see `compact_memory`).
+    /// 
+    /// Returns true if any commits were written (i.e. unsaved commits
+    /// were found). Returns false if no unsaved commits were present. This
+    /// value implies nothing about whether a snapshot was made.
+    /// 
+    /// Note that writing to disk can fail. In this case it may be worth trying
+    /// again.
+    pub fn write_full(&mut self) -> Result<bool> {
+        let has_changes = self.write_fast()?;
+        
+        // Second step: maintenance operations
+        if self.is_ready() && self.control.snapshot_policy().want_snapshot() {
+            if self.tip_key().is_ok_and(|key| self.has_skipped(key)) {
+                warn!("Partition {}: not writing snapshot: state has skipped elements", self.name);
+            } else {
+                self.check_cancelled()?;
+                self.write_snapshot()?;
+            }
+        }
+        if let Some(limit) = self.control.mem_limit() {
+            if self.mem_usage() > limit {
+                self.compact_memory(i64::MAX);
+            }
+        }
+        
+        Ok(has_changes)
+    }
+    
+    /// Write a new snapshot from the tip.
+    /// 
+    /// Normally you can just call `write_full()` and let the library figure out
+    /// when to write a new snapshot, though you can also call this directly.
+    /// 
+    /// Fails with `TipError` when `tip()` would (no data is loaded or a merge
+    /// is required); see `safety_snapshot` for diverged tips. Progress is
+    /// reported to `Control::progress`, if any (see `ProgressSink`), and
+    /// writing may be cancelled (see `Control::cancel_flag`). If writing
+    /// fails, the partial file is removed (where the `RepoIO` supports this).
+    pub fn write_snapshot(&mut self) -> Result<()> {
+        // fail early if not ready:
+        let tip_key = self.tip_key()?.clone();
+        self.write_snapshot_of(&tip_key, None)
+    }
+    
+    /// Write a *safety snapshot* before some risky operation (e.g. a merge or
+    /// pruning history): write all unsaved commits, then a snapshot of each
+    /// tip, with `tag` recorded in the snapshot header. Unlike
+    /// `write_snapshot` this works when a merge is required (tips have
+    /// diverged), in which case multiple snapshots are written.
+    /// 
+    /// Should the operation go wrong, tagged states can be found via
+    /// `tagged(tag)`, including after reloading (so long as the snapshot is
+    /// loaded) and the states retrieved with `state(sum)`.
+    /// 
+    /// Fails if no tip is loaded or the tag is empty or contains a zero byte.
+    pub fn safety_snapshot(&mut self, tag: &str) -> Result<()> {
+        if !self.is_loaded() {
+            return Err(Box::new(TipError::NotReady));
+        }
+        if tag.is_empty() || tag.contains('\0') {
+            return ArgError::err("invalid tag");
+        }
+        self.write_fast()?;
+        let mut tips: Vec<Sum> = self.tips.iter().cloned().collect();
+        tips.sort();
+        for tip in tips {
+            self.write_snapshot_of(&tip, Some(tag))?;
+            self.add_tag(tag.to_string(), tip);
+        }
+        Ok(())
+    }
+    
+    /// Get the states recorded under a tag (see `safety_snapshot`). Returns
+    /// an empty slice if the tag is not known.
+    pub fn tagged(&self, tag: &str) -> &[Sum] {
+        self.tags.get(tag).map_or(&[], |sums| &sums[..])
+    }
+    
+    /// Get all known tags, with the states recorded under each.
+    pub fn tags(&self) -> &HashMap<String, Vec<Sum>> {
+        &self.tags
+    }
+    
+    /// Record that `replica` has acknowledged (i.e. durably stores) the state
+    /// `sum`, and by implication all its ancestors.
+    /// 
+    /// Acknowledgements are stored in commit metadata (as extension data,
+    /// not affecting state sums): they are written with the next commit
+    /// written by `write_fast` or recorded in the next snapshot, and are
+    /// read back when loading. Until then they are held in memory only.
+    /// 
+    /// Returns false if this acknowledgement is already implied by a known
+    /// acknowledgement of the same replica.
+    pub fn mark_acked(&mut self, replica: ReplicaId, sum: Sum) -> bool {
+        let implied = self.acks.get(&replica).is_some_and(|sums| sums.iter()
+                .any(|s| self.loaded_ancestors(s).contains(&sum)));
+        if implied {
+            return false;
+        }
+        let ancestors = self.loaded_ancestors(&sum);
+        let sums = self.acks.entry(replica).or_default();
+        sums.retain(|s| !ancestors.contains(s));
+        sums.insert(sum.clone());
+        self.unsaved_acks.push((replica, sum));
+        true
+    }
+    
+    /// Get known acknowledgements: for each replica, acknowledged states
+    /// (excluding those known to be ancestors of another acknowledged state).
+    pub fn acks(&self) -> &HashMap<ReplicaId, HashSet<Sum>> {
+        &self.acks
+    }
+    
+    /// Get the latest state acknowledged by all of `replicas` (see
+    /// `mark_acked`), if any. Only loaded states are considered; "latest"
+    /// is by commit number.
+    /// 
+    /// This supports retention decisions: history newer than this state
+    /// should not be pruned (e.g. by `vacuum`) since some replica may not
+    /// yet have it.
+    pub fn latest_fully_acked(&self, replicas: &[ReplicaId]) -> Option<Sum> {
+        let mut common: Option<HashSet<Sum>> = None;
+        for replica in replicas {
+            let mut covered = HashSet::new();
+            for sum in self.acks.get(replica)? {
+                covered.extend(self.loaded_ancestors(sum));
+            }
+            common = Some(match common {
+                None => covered,
+                Some(c) => c.intersection(&covered).cloned().collect(),
+            });
+        }
+        common?.into_iter()
+                .max_by(|a, b| {
+                    let na = self.states.get(a).map(|s| s.meta().number());
+                    let nb = self.states.get(b).map(|s| s.meta().number());
+                    na.cmp(&nb).then_with(|| a.cmp(b))
+                })
+    }
+    
+    /// Get counts of data written since this partition was created or
+    /// opened (or since `reset_write_stats`).
+    pub fn write_stats(&self) -> &WriteStats {
+        &self.stats
+    }
+    
+    /// Reset the counts returned by `write_stats`.
+    pub fn reset_write_stats(&mut self) {
+        self.stats = WriteStats::default();
+    }
+    
+    /// Read the audit log of administrative operations (see `AuditOp`),
+    /// oldest first. Empty if the `RepoIO` does not store an audit log.
+    pub fn audit_log(&self) -> Result<Vec<AuditEntry>> {
+        match self.control.io().read_audit()? {
+            Some(mut r) => read_audit(&mut r),
+            None => Ok(vec![]),
+        }
+    }
+    
+    // Append an entry to the audit log, if supported. Failure is logged but
+    // not returned since the operation itself has completed.
+    fn record_audit(&mut self, op: AuditOp, detail: String) {
+        if self.read_only { return; }
+        let entry = AuditEntry {
+            timestamp: self.control.make_commit_timestamp(),
+            op,
+            detail,
+        };
+        let result = match self.control.io_mut().append_audit() {
+            Ok(Some(mut w)) => write_audit_entry(&entry, &mut w).and_then(|_| Ok(w.flush()?)),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Partition {}: failed to record {} in audit log: {}", self.name, op.name(), e);
+        }
+    }
+    
+    // Add `sums` to the sum filter, and record that `file` is covered
+    fn filter_sums<'a, I: IntoIterator<Item = &'a Sum>>(&mut self, file: Option<WrittenFile>,
+            sums: I)
+    {
+        for sum in sums {
+            if !self.sum_filter.contains(sum) {
+                self.sum_filter.insert(sum);
+                self.sum_filter_changed = true;
+            }
+        }
+        match file {
+            Some(WrittenFile::Snapshot(ss)) => self.sum_filter.cover_snapshot(ss),
+            Some(WrittenFile::CommitLog(ss, cl)) => self.sum_filter.cover_log(ss, cl),
+            None => return,
+        }
+        self.sum_filter_changed = true;
+    }
+    
+    // Store the sum filter, if changed. Failure is logged but not returned
+    // since the filter is an optimisation.
+    fn save_sum_filter(&mut self) {
+        if self.read_only || !self.sum_filter_changed { return; }
+        let result = match self.control.io_mut().write_sum_filter() {
+            Ok(Some(mut w)) => self.sum_filter.write_to(&mut w).and_then(|_| Ok(w.flush()?)),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => self.sum_filter_changed = false,
+            Err(e) => warn!("Partition {}: failed to write sum filter: {}", self.name, e),
+        }
+    }
+    
+    // When rewriting an encrypted file with `header`, update its cipher info
+    // and return the cipher to use; returns `None` for unencrypted files.
+    fn rewrite_cipher(&self, header: &mut FileHeader) -> Option<Rc<Cipher>> {
+        header.cipher.as_ref()?;
+        let cipher = self.control.cipher();
+        header.cipher = cipher.as_ref().map(|c| CipherInfo::of(&**c));
+        cipher
+    }
+    
+}
+
+// Internal support functions
+impl<C: Control> Partition<C> {
+    // True if the state with this key holds elements skipped when loading
+    fn has_skipped(&self, key: &Sum) -> bool {
+        let state = match self.states.get(key) {
+            Some(state) => state,
+            None => return false,
+        };
+        self.skipped.iter().any(|(id, sum)| state.erased_sum(*id) == Some(sum))
+    }
+    
+    // Write a snapshot of the state with the given key, which must be present.
+    fn write_snapshot_of(&mut self, key: &Sum, tag: Option<&str>) -> Result<()> {
+        self.check_writable()?;
+        if self.has_skipped(key) {
+            return RepoError::err(RepoError::SkippedElements);
+        }
+        let mut header = self.make_header(FileType::Snapshot(0))?;
+        header.tag = tag.map(|t| t.to_string());
+        let reproducible = self.control.reproducible_snapshots();
+        
+        // Record all acknowledgements with the snapshot (unless reproducible):
+        if !reproducible && !self.acks.is_empty() {
+            let mut acks: Vec<(ReplicaId, Sum)> = self.acks.iter()
+                    .flat_map(|(r, sums)| sums.iter().map(move |sum| (*r, sum.clone())))
+                    .collect();
+            acks.sort();
+            if acks.len() > MAX_ACKS {
+                warn!("Partition {}: too many acknowledgements to record in snapshot", self.name);
+                acks.truncate(MAX_ACKS);
+            }
+            let mut state = self.states.remove(key).expect("state");
+            state.set_acks(acks).expect("acks within limit");
+            self.states.insert(state);
+        }
+        
+        let cipher = self.control.cipher();
+        let progress = self.control.progress();
+        let cancel = self.control.cancel_flag();
+        let mut ss_num = self.ss1;
+        loop {
+            
+            // Try to get a writer for this snapshot number:
+            let written = if let Some(writer) = self.control.io_mut().new_ss(ss_num)? {
+                debug!("Partition {}: writing snapshot {}: {}",
+                    self.name, ss_num, key);
+                
+                let mut writer = CountingWriter::new(writer);
+                let state = self.states.get(key).unwrap();
+                let result = (|| -> Result<()> {
+                    write_head(&header, &mut writer)?;
+                    let mut enc = EncryptWriter::new(&mut writer, &header, cipher.clone());
+                    let mut w = CompressWriter::new(&mut enc, header.compression)?;
+                    {
+                        let mut w = ProgressWriter::new(&mut w, progress.clone(), cancel.clone());
+                        let result = if header.dedup {
+                            write_snapshot_dedup(state, &mut w, reproducible)
+                        } else if reproducible {
+                            write_snapshot_reproducible(state, &mut w)
+                        } else {
+                            write_snapshot(state, &mut w)
+                        };
+                        result.map_err(|e| w.take_error(e))?;
+                    }
+                    w.finish()?;
+                    enc.finish()?;
+                    Ok(())
+                })();
+                result.map(|()| writer.count())
+            } else {
+                // Snapshot file already exists! So try another number.
+                if ss_num > 1000_000 {
+                    // We should give up eventually. When is arbitrary.
+                    return RepoError::err(RepoError::SnapshotNumberOverflow { ss_num });
+                }
+                ss_num += 1;
+                continue;
+            };
+            
+            // After borrow on self.control expires:
+            match written {
+                Ok(bytes) => {
+                    self.stats.snapshots += 1;
+                    self.stats.snapshot_bytes += bytes;
+                },
+                Err(e) => {
+                    // Do not leave a partial snapshot:
+                    if !self.control.io_mut().remove_ss(ss_num).unwrap_or(false) {
+                        warn!("Partition {}: unable to remove partial snapshot {}", self.name, ss_num);
+                    }
+                    return Err(e);
+                },
+            }
+            self.control.file_written(WrittenFile::Snapshot(ss_num));
+            self.last_ss_write = self.control.clock().now();
+            self.filter_sums(Some(WrittenFile::Snapshot(ss_num)), Some(key));
+            self.save_sum_filter();
+            if !reproducible {
+                self.unsaved_acks.clear();
+            }
+            self.ss1 = ss_num + 1;
+            self.control.snapshot_policy().reset();
+            return Ok(())
+        }
+    }
+    
+    // Replace a snapshot (cl == None) or commit log with the given contents
+    fn replace_file(&mut self, ss: usize, cl: Option<usize>, data: &[u8]) -> Result<()> {
+        self.log_tail = None;
+        let io = self.control.io_mut();
+        let replaced = match cl {
+            None => io.replace_ss(ss, data)?,
+            Some(cl) => io.replace_ss_cl(ss, cl, data)?,
+        };
+        if !replaced {
+            return RepoError::err(RepoError::FileNotFound { ss_num: ss, cl_num: cl });
+        }
+        Ok(())
+    }
+    
+    // Record acknowledgements from loaded metadata
+    fn record_acks(&mut self, meta: &CommitMeta) {
+        for &(replica, ref sum) in meta.acks() {
+            self.acks.entry(replica).or_default().insert(sum.clone());
+        }
+    }
+    
+    // Record the base of a squashed commit: an ancestor but not a parent
+    fn record_squash(&mut self, commit: &Commit<C::Element>) {
+        if let Some(base) = commit.squash_base() {
+            self.tips.remove(base);
+            self.squashed.insert(commit.statesum().clone(), base.clone());
+        }
+    }
+    
+    // Attach unsaved acknowledgements to the last unsaved commit
+    fn attach_acks(&mut self) {
+        if self.unsaved_acks.is_empty() {
+            return;
+        }
+        if let Some(commit) = self.unsaved.back_mut() {
+            let mut acks = commit.meta().acks().to_vec();
+            let n = min(MAX_ACKS.saturating_sub(acks.len()), self.unsaved_acks.len());
+            acks.extend(self.unsaved_acks.drain(..n));
+            commit.meta_mut().set_acks(acks).expect("acks within limit");
+        }
+    }
+    
+    // If a new state with parent `parent` and timestamp `timestamp` should
+    // be coalesced with its parent (see `Control::coalesce_window`), get the
+    // parent's parent. The parent must be the latest unsaved commit, a tip,
+    // not a merge and not otherwise referenced.
+    fn coalesce_base(&self, parent: &Sum, timestamp: i64) -> Option<Sum> {
+        let window = self.control.coalesce_window()?;
+        let commit = self.unsaved.back()?;
+        if commit.statesum() != parent || commit.parents().len() != 1 ||
+            commit.squash_base().is_some() || !self.tips.contains(parent) ||
+            timestamp - commit.meta().timestamp() > window ||
+            self.tags.values().any(|sums| sums.contains(parent)) ||
+            self.acks.values().any(|sums| sums.contains(parent))
+        {
+            return None;
+        }
+        let base = commit.first_parent();
+        if self.states.contains(base) { Some(base.clone()) } else { None }
+    }
+    
+    // All loaded ancestors of a state, including itself (if loaded)
+    fn loaded_ancestors(&self, sum: &Sum) -> HashSet<Sum> {
+        let mut result = HashSet::new();
+        let mut next = vec![sum];
+        while let Some(k) = next.pop() {
+            if let Some(state) = self.states.get(k) {
+                if result.insert(k.clone()) {
+                    next.extend(state.parents());
+                }
+            }
+        }
+        result
+    }
+    
+    // Write the index of a commit log, if supported by the RepoIO
+    fn write_log_index(&mut self, ss: usize, cl: usize, index: &LogIndex) -> Result<()> {
+        if let Some(mut writer) = self.control.io_mut().write_ss_cl_index(ss, cl)? {
+            index.write_to(&mut writer)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+    
+    // Append unsaved commits to the latest commit log of the latest snapshot
+    // (see `Control::log_append_limit`), returning false if not possible.
+    // 
+    // The log's header must show it uncompressed, unencrypted and in the
+    // latest format, compression and encryption must not be configured, and
+    // the log must end exactly where its index (kept from the last write or
+    // read via the `RepoIO`) says, i.e. no other process appended to it.
+    // Unindexed logs are therefore only extended by the process which wrote
+    // them. All commits are written in a single operation, which checks the
+    // log's length under the same lock (see `RepoIO::append_ss_cl_at`).
+    fn append_log(&mut self) -> Result<bool> {
+        let limit = match self.control.log_append_limit() {
+            Some(limit) => limit,
+            None => return Ok(false),
+        };
+        if self.control.compression() != Compression::None || self.control.cipher().is_some() {
+            return Ok(false);
+        }
+        let ss = self.ss1 - 1;
+        let cl = match self.control.io().ss_cl_len(ss) {
+            0 => return Ok(false),
+            n => n - 1,
+        };
+        let tail = self.log_tail.take();
+        let mut index = match self.read_tail_index(ss, cl, tail) {
+            Ok(Some(index)) => index,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                warn!("Partition {}: not appending to log {}-{}: {}", self.name, ss, cl, e);
+                return Ok(false);
+            }
+        };
+        let start = index.end();
+        if start >= limit {
+            return Ok(false);
+        }
+        
+        let mut buf = Vec::new();
+        let mut sums = Vec::with_capacity(self.unsaved.len());
+        let mut changed = 0;
+        for commit in &self.unsaved {
+            let sum = write_commit(commit, &mut buf)?;
+            index.push(start + buf.len() as u64, sum);
+            sums.push(commit.statesum().clone());
+            changed += changed_bytes(commit)?;
+        }
+        if !self.control.io_mut().append_ss_cl_at(ss, cl, start, &buf)? {
+            return Ok(false);
+        }
+        debug!("Partition {}: appended {} commits to log {}-{}",
+                self.name, self.unsaved.len(), ss, cl);
+        self.stats.commits += self.unsaved.len();
+        self.stats.changed_bytes += changed;
+        self.stats.log_bytes += buf.len() as u64;
+        self.unsaved.clear();
+        
+        self.merge_lock = None;
+        self.last_log_write = self.control.clock().now();
+        self.control.snapshot_policy().count_log_bytes(buf.len() as u64);
+        self.control.file_written(WrittenFile::CommitLog(ss, cl));
+        self.filter_sums(Some(WrittenFile::CommitLog(ss, cl)), &sums);
+        self.save_sum_filter();
+        if let Err(e) = self.write_log_index(ss, cl, &index) {
+            warn!("Partition {}: failed to write index of log {}-{}: {}", self.name, ss, cl, e);
+        }
+        self.log_tail = Some((ss, cl, index));
+        Ok(true)
+    }
+    
+    // Get the index of log `ss-cl` if the log may be appended to (see
+    // `append_log`): `tail` if this is the log's index, otherwise the index
+    // written alongside the log (e.g. by another instance)
+    fn read_tail_index(&self, ss: usize, cl: usize, tail: Option<(usize, usize, LogIndex)>)
+            -> Result<Option<LogIndex>>
+    {
+        let io = self.control.io();
+        let header = match io.read_ss_cl(ss, cl)? {
+            Some(mut r) => read_head(&mut *r)?,
+            None => return Ok(None),
+        };
+        if !header.ftype.is_latest() || header.compression != Compression::None ||
+            header.cipher.is_some() || header.name != self.name
+        {
+            return Ok(None);
+        }
+        match tail {
+            Some((s, c, index)) if (s, c) == (ss, cl) => Ok(Some(index)),
+            _ => match io.read_ss_cl_index(ss, cl)? {
+                Some(mut r) => LogIndex::read_from(&mut *r).map(Some),
+                None => Ok(None),
+            },
+        }
+    }
+    
+    // Record a tagged state
+    fn add_tag(&mut self, tag: String, sum: Sum) {
+        let sums = self.tags.entry(tag).or_default();
+        if !sums.contains(&sum) {
+            sums.push(sum);
+        }
+    }
+    
+    /// Add a state, assuming that this isn't a new one (i.e. it's been loaded
+    /// from a file and doesn't need to be saved).
+    /// 
+    /// Unlike add_pair, mutating isn't possible, so this just returns false
+    /// if the sum is known without checking whether the state is different.
+    /// 
+    /// `n_edits` is the number of changes in a commit. Normally the state is
+    /// created from a commit, and `n_edits = commit.num_changes()`; in the
+    /// case the state is a loaded snapshot the "snapshot policy" should be
+    /// reset afterwards, hence n_edits does not matter.
+    /// 
+    /// Returns true unless the given state's sum equals an existing one.
+    fn add_state(&mut self, state: PartState<C::Element>, n_edits: usize) {
+        trace!("Partition {}: add state {}", self.name, state.statesum());
+        if self.states.contains(state.statesum()) {
+            trace!("Partition {} already contains state {}", self.name, state.statesum());
+            return;
+        }
+        
+        for parent in state.parents() {
+            // Remove from 'tips' if it happened to be there:
+            self.tips.remove(parent);
+            // Add to 'ancestors' if not in 'states':
+            if !self.states.contains(parent) {
+                self.ancestors.insert(parent.clone());
+            }
+        }
+        // We know from above 'state' is not in 'self.states'; if it's not in
+        // 'self.ancestors' either then it must be a tip:
+        if !self.ancestors.contains(state.statesum()) &&
+            !self.squashed.values().any(|base| base == state.statesum())
+        {
+            self.control.snapshot_policy().count(1, n_edits);
+            self.control.snapshot_policy().count_time(state.meta().timestamp());
+            self.tips.insert(state.statesum().clone());
+        }
+        // TODO: check that classification in state equals that of this partition?
+        self.states.insert(state);
+    }
+    
+    /// Creates a state from the commit and adds to self. Updates tip if this
+    /// state is new.
+    pub fn add_commit(&mut self, commit: Commit<C::Element>) -> Result<(), PatchOp> {
+        self.record_acks(commit.meta());
+        self.record_squash(&commit);
+        if self.states.contains(commit.statesum()) { return Ok(()); }
+        
+        let state = {
+            let parent = self.states.get(commit.first_parent())
+                .ok_or(PatchOp::NoParent)?;
+            PartState::from_state_commit(parent, &commit)?
+        };  // end borrow on self (from parent)
+        self.add_state(state, commit.num_changes());
+        self.update_index(&commit);
+        self.subs.notify_loaded(&commit);
+        Ok(())
+    }
+    
+    /// Add a paired commit and state, asserting that the checksums match and
+    /// the parent state is present. Also add to the queue awaiting `write()`.
+    /// 
+    /// If an element with the states's statesum already exists and differs
+    /// from the state passed, the state and commit passed will be mutated to
+    /// achieve a unique statesum.
+    /// 
+    /// Returns true unless the given state (including metadata) equals a
+    /// stored one (in which case nothing happens and false is returned).
+    fn add_pair(&mut self, mut commit: Commit<C::Element>, mut state: PartState<C::Element>) -> bool {
+        trace!("Partition {}: add commit {}", self.name, commit.statesum());
+        assert_eq!(commit.parents(), state.parents());
+        assert_eq!(commit.statesum(), state.statesum());
+        assert!(self.states.contains(commit.first_parent()));
+        self.record_acks(commit.meta());
+        
+        while let Some(old_state) = self.states.get(state.statesum()) {
+            if state == *old_state {
+                trace!("Partition {} already contains commit {}", self.name, commit.statesum());
+                return false;
+            } else {
+                commit.mutate_meta(state.mutate_meta());
+                trace!("Partition {}: mutated commit to {}", self.name, commit.statesum());
+            }
+        }
+        
+        if commit.meta().summary().is_none() {
+            let parent = self.states.get(commit.first_parent()).expect("has parent");
+            match commit_summary(parent, &state, &commit) {
+                Ok(summary) => {
+                    commit.meta_mut().set_summary(Some(summary));
+                    state.set_summary(Some(summary));
+                },
+                Err(e) => warn!("Partition {}: unable to summarise commit {}: {}",
+                        self.name, commit.statesum(), e),
+            }
+        }
+        
+        self.add_state(state, commit.num_changes());
+        self.update_index(&commit);
+        self.subs.notify(&commit);
+        self.unsaved.push_back(commit);
+        true
+    }
+}
+
+
+/// What the application intends to do after loading (see
+/// `Partition::load_auto`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LoadGoal {
+    /// Read the latest state
+    Read,
+    /// Make and commit changes to the latest state
+    Edit,
+    /// Merge tips, including those from other replicas
+    Merge,
+}
+
+// Read the stored sum filter, or make a new one if missing or unreadable
+fn load_sum_filter(io: &RepoIO) -> SumFilter {
+    let result = match io.read_sum_filter() {
+        Ok(Some(mut r)) => SumFilter::read_from(&mut r),
+        Ok(None) => Ok(SumFilter::new()),
+        Err(e) => Err(e),
+    };
+    result.unwrap_or_else(|e| {
+        warn!("Failed to read sum filter: {}", e);
+        SumFilter::new()
+    })
+}
+
+// Decrypt (if needed) and decompress the body of a file with `header`
+fn body_reader<'a, R: Read + 'a>(r: R, header: &FileHeader, cipher: Option<Rc<Cipher>>)
+        -> Result<Box<Read + 'a>>
+{
+    decompress(decrypt(r, header, cipher)?, header.compression)
+}
+
+// Replace changed elements of `state` by their canonical form
+fn canonicalise_elts<E: Element>(state: &mut MutPartState<E>, canon: &TextCanon)
+        -> Result<(), ElementOp>
+{
+    let ids: Vec<EltId> = state.changed_ids().iter().cloned().collect();
+    for id in ids {
+        // Removed elements are also listed as changed
+        let elt = match state.get(id) {
+            Ok(elt) => elt.canonicalise(canon),
+            Err(_) => None,
+        };
+        if let Some(elt) = elt {
+            state.replace(id, elt)?;
+        }
+    }
+    Ok(())
+}
+
+// Number of bytes of element data in a commit's changes
+fn changed_bytes<E: Element>(commit: &Commit<E>) -> Result<u64> {
+    let mut writer = CountingWriter::new(io::sink());
+    for (_, change) in commit.changes_iter() {
+        if let Some(elt) = change.element() {
+            elt.write_buf(&mut writer)?;
+        } else if let EltChange::Operation(ref ops, _) = *change {
+            for op in ops {
+                writer.write_all(op)?;
+            }
+        }
+    }
+    Ok(writer.count())
+}
+
+// Reports bytes written to a progress sink, if any, and checks for
+// cancellation. Failure of the sink or cancellation is stored (see
+// `take_error`) and reported to the writer as an I/O error.
+struct ProgressWriter<'a> {
+    inner: &'a mut Write,
+    progress: Option<Rc<ProgressSink>>,
+    cancel: Option<Arc<AtomicBool>>,
+    error: Option<Error>,
+}
+impl<'a> ProgressWriter<'a> {
+    fn new(inner: &'a mut Write, progress: Option<Rc<ProgressSink>>,
+            cancel: Option<Arc<AtomicBool>>) -> Self
+    {
+        ProgressWriter { inner, progress, cancel, error: None }
+    }
+    // The error of the progress sink if it failed, otherwise `e`
+    fn take_error(&mut self, e: Error) -> Error {
+        self.error.take().unwrap_or(e)
+    }
+}
+impl<'a> Write for ProgressWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cancel.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed)) {
+            self.error = Some(Box::new(RepoError::Cancelled));
+            return Err(io::Error::other("cancelled"));
+        }
+        let n = self.inner.write(buf)?;
+        if let Some(ref progress) = self.progress {
+            if let Err(e) = progress.bytes_written(n) {
+                self.error = Some(e);
+                return Err(io::Error::other("aborted by progress sink"));
+            }
+        }
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Summarise the effect of a commit on its first parent (see
+// `CommitMeta::summary`).
+fn commit_summary<E: Element>(parent: &PartState<E>, state: &PartState<E>, commit: &Commit<E>)
+        -> Result<CommitSummary>
+{
+    let size = |s: &PartState<E>, id: EltId| -> Result<i64> {
+        let mut writer = CountingWriter::new(io::sink());
+        if let Ok(elt) = s.get_rc(id) {
+            elt.write_buf(&mut writer)?;
+        }
+        Ok(writer.count() as i64)
+    };
+    let mut byte_delta = 0;
+    for (id, _) in commit.changes_iter() {
+        byte_delta += size(state, *id)? - size(parent, *id)?;
+    }
+    Ok(CommitSummary { elements: state.num_avail() as u64, byte_delta })
+}
+
+/// Counts of data written by a partition; see `Partition::write_stats()`.
+/// 
+/// Byte counts include file headers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct WriteStats {
+    /// Number of snapshots written
+    pub snapshots: usize,
+    /// Bytes written to snapshots
+    pub snapshot_bytes: u64,
+    /// Number of commit log files written
+    pub logs: usize,
+    /// Bytes written to commit logs
+    pub log_bytes: u64,
+    /// Number of commits written
+    pub commits: usize,
+    /// Bytes of element data in changes of commits written (deleted elements
+    /// are not counted); that is, the *logical* number of bytes changed
+    pub changed_bytes: u64,
+}
+impl WriteStats {
+    /// Total bytes written
+    pub fn total_bytes(&self) -> u64 {
+        self.snapshot_bytes + self.log_bytes
+    }
+    /// Bytes written per byte of element data changed (write amplification),
+    /// or `None` if no element data was changed
+    pub fn amplification(&self) -> Option<f64> {
+        self.per_changed(self.total_bytes())
+    }
+    /// Bytes written to snapshots per byte of element data changed
+    pub fn snapshot_amplification(&self) -> Option<f64> {
+        self.per_changed(self.snapshot_bytes)
+    }
+    /// Bytes written to commit logs per byte of element data changed
+    pub fn log_amplification(&self) -> Option<f64> {
+        self.per_changed(self.log_bytes)
+    }
+    fn per_changed(&self, bytes: u64) -> Option<f64> {
+        if self.changed_bytes == 0 {
+            None
+        } else {
+            Some(bytes as f64 / self.changed_bytes as f64)
+        }
+    }
+}
+
+/// Metadata from a snapshot header; see `Partition::header_info()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HeaderInfo {
+    /// Number of the snapshot
+    pub ss: usize,
+    /// File format version (as from `FileType::ver()`)
+    pub version: u32,
+    /// User data fields
+    pub user: Vec<UserData>,
+    /// Tag naming the state stored, if any
+    pub tag: Option<String>,
+    /// True if the snapshot marks the partition archived (see
+    /// `Partition::archive`)
+    pub archived: bool,
+}
+impl HeaderInfo {
+    fn new(ss: usize, header: &FileHeader) -> Self {
+        HeaderInfo {
+            ss,
+            version: header.ftype.ver(),
+            user: header.user.clone(),
+            tag: header.tag.clone(),
+            archived: header.archived,
+        }
+    }
+}
+
+// Source of elements for on-demand reads
+enum LazyIndex<E: Element> {
+    // Location of elements within a snapshot with an element index
+    Index {
+        // Snapshot number
+        ss: usize,
+        // Position of the snapshot data within the file (after the header)
+        start: u64,
+        // Position of each element's record, relative to `start`
+        offsets: BTreeMap<EltId, u64>,
+    },
+    // State read from a snapshot without an element index
+    Full(PartState<E>),
+}
+
+/// Wrapper around underlying iterator structure
+pub struct TipIter<'a> {
+    iter: hs::Iter<'a, Sum>
+}
+impl<'a> Clone for TipIter<'a> {
+    fn clone(&self) -> TipIter<'a> {
+        TipIter { iter: self.iter.clone() }
+    }
+}
+impl<'a> Iterator for TipIter<'a> {
+    type Item = &'a Sum;
+    fn next(&mut self) -> Option<&'a Sum> {
+        self.iter.next()
+    }
+}
+impl<'a> ExactSizeIterator for TipIter<'a> {
+    fn len(&self) -> usize {
+        self.iter.len()
+    }
+}
+
+/// Wrapper around a `PartState<E>`. Dereferences to this type.
+pub struct StateItem<'a, E: Element+'a> {
+    state: &'a PartState<E>,
+    tips: &'a HashSet<Sum>,
+}
+impl<'a, E: Element+'a> StateItem<'a, E> {
+    /// Returns true if and only if this state is a tip state (i.e. is not the
+    /// parent of any other state).
+    /// 
+    /// There is exactly one tip state unless a merge is required or no states
+    /// are loaded.
+    pub fn is_tip(&self) -> bool {
+        self.tips.contains(self.state.statesum())
+    }
+}
+impl<'a, E: Element+'a> Deref for StateItem<'a, E> {
+    type Target = PartState<E>;
+    fn deref(&self) -> &Self::Target {
+        self.state
+    }
+}
+
+/// Iterator over a partition's (historical or current) states
+pub struct StateIter<'a, E: Element+'a> {
+    iter: Iter<'a, PartState<E>, Sum, PartStateSumComparator>,
+    tips: &'a HashSet<Sum>,
+}
+impl<'a, E: Element+'a> Iterator for StateIter<'a, E> {
+    type Item = StateItem<'a, E>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|item|
+            StateItem {
+                state: item,
+                tips: self.tips,
+            }
+        )
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elt::EltId;
+    use commit::{Commit, CommitChain, EltChange, MakeCommitMeta};
+    use control::DefaultControl;
+    use io::DummyRepoIO;
+    use state::*;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    #[test]
+    fn commit_creation_and_replay(){
+        let mut queue = vec![];
+        let mut mcm = MCM;
+        
+        let insert = |state: &mut MutPartState<_>, num, string: &str| -> Result<_, _> {
+            state.insert(EltId::from(num), string.to_string())
+        };
+        
+        let mut state = PartState::new(&mut mcm).clone_mut();
+        insert(&mut state, 1, "one").unwrap();
+        insert(&mut state, 2, "two").unwrap();
+        let state_a = PartState::from_mut(state, &mut mcm);
+        
+        let mut state = state_a.clone_mut();
+        insert(&mut state, 3, "three").unwrap();
+        insert(&mut state, 4, "four").unwrap();
+        insert(&mut state, 5, "five").unwrap();
+        let state_b = PartState::from_mut(state, &mut mcm);
+        let commit = Commit::from_diff(&state_a, &state_b).unwrap();
+        queue.push(commit);
+        
+        let mut state = state_b.clone_mut();
+        insert(&mut state, 6, "six").unwrap();
+        insert(&mut state, 7, "seven").unwrap();
+        state.remove(EltId::from(4)).unwrap();
+        state.replace(EltId::from(3), "half six".to_string()).unwrap();
+        let state_c = PartState::from_mut(state, &mut mcm);
+        let commit = Commit::from_diff(&state_b, &state_c).unwrap();
+        queue.push(commit);
+        
+        let mut state = state_c.clone_mut();
+        insert(&mut state, 8, "eight").unwrap();
+        insert(&mut state, 4, "half eight").unwrap();
+        let state_d = PartState::from_mut(state, &mut mcm);
+        let commit = Commit::from_diff(&state_c, &state_d).unwrap();
+        queue.push(commit);
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "replay part").unwrap();
+        part.add_state(state_a, 0);
+        for commit in queue {
+            part.push_commit(commit).unwrap();
+        }
+        
+        assert_eq!(part.tips.len(), 1);
+        let replayed_state = part.tip().unwrap();
+        assert_eq!(*replayed_state, state_d);
+    }
+    
+    #[test]
+    fn on_new_partition() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "on_new_partition")
+                .expect("partition creation");
+        assert_eq!(part.tips.len(), 1);
+        
+        let state = part.tip().expect("getting tip").clone_mut();
+        assert_eq!(part.push_state(state).expect("committing"), false);
+        
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        assert!(!state.any_avail());
+        
+        let elt1 = "This is element one.".to_string();
+        let elt2 = "Element two data.".to_string();
+        let e1id = state.insert_new(elt1).expect("inserting elt");
+        let e2id = state.insert_new(elt2).expect("inserting elt");
+        
+        assert_eq!(part.push_state(state).expect("comitting"), true);
+        assert_eq!(part.unsaved.len(), 1);
+        assert_eq!(part.states.len(), 2);
+        let key = part.tip().expect("tip").statesum().clone();
+        {
+            let state = part.state(&key).expect("getting state by key");
+            assert!(state.is_avail(e1id));
+            assert_eq!(state.get(e2id), Ok(&"Element two data.".to_string()));
+        }   // `state` goes out of scope
+        assert_eq!(part.tips.len(), 1);
+        let state = part.tip().expect("getting tip").clone_mut();
+        assert_eq!(*state.parent(), key);
+        
+        assert_eq!(part.push_state(state).expect("committing"), false);
+    }
+    
+    #[test]
+    fn conflict_pre_check() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "conflict check").unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.insert(EltId::from(1), "one".to_string()).unwrap();
+        state.insert(EltId::from(2), "two".to_string()).unwrap();
+        part.push_state(state).unwrap();
+        
+        let mut s1 = part.tip().unwrap().clone_mut();
+        let mut s2 = part.tip().unwrap().clone_mut();
+        let mut s3 = part.tip().unwrap().clone_mut();
+        s1.replace(EltId::from(1), "uno".to_string()).unwrap();
+        assert!(!part.would_conflict(&s1));
+        part.push_state(s1).unwrap();
+        
+        s2.replace(EltId::from(2), "dos".to_string()).unwrap();
+        assert!(!part.would_conflict(&s2));
+        s3.remove(EltId::from(1)).unwrap();
+        assert!(part.would_conflict(&s3));
+    }
+    
+    #[test]
+    fn merge_default() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "merge default").unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.insert(EltId::from(1), "one".to_string()).unwrap();
+        state.insert(EltId::from(2), "two".to_string()).unwrap();
+        part.push_state(state).unwrap();
+        
+        let mut s1 = part.tip().unwrap().clone_mut();
+        let mut s2 = part.tip().unwrap().clone_mut();
+        s1.replace(EltId::from(1), "uno".to_string()).unwrap();
+        s2.remove(EltId::from(2)).unwrap();
+        part.push_state(s1).unwrap();
+        part.push_state(s2).unwrap();
+        assert!(part.merge_required());
+        
+        part.merge_default(false).unwrap();
+        let tip = part.tip().unwrap();
+        assert_eq!(tip.get(EltId::from(1)), Ok(&"uno".to_string()));
+        assert!(!tip.is_avail(EltId::from(2)));
+    }
+    
+    #[test]
+    fn push_chain() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "push chain").unwrap();
+        let mut mcm = MCM;
+        let base = part.tip().unwrap().clone_exact();
+        
+        let mut chain = CommitChain::new(&base);
+        let mut state = chain.tip().clone_mut();
+        state.insert(EltId::from(1), "one".to_string()).unwrap();
+        assert_eq!(chain.push_state(state, &mut mcm), Ok(true));
+        let state = chain.tip().clone_mut();
+        assert_eq!(chain.push_state(state, &mut mcm), Ok(false));
+        let mut state = chain.tip().clone_mut();
+        state.replace(EltId::from(1), "uno".to_string()).unwrap();
+        assert_eq!(chain.push_state(state, &mut mcm), Ok(true));
+        let stale = base.clone_mut();
+        assert_eq!(chain.push_state(stale, &mut mcm), Err(PatchOp::WrongParent));
+        let final_state = chain.tip().clone_exact();
+        
+        // A broken chain is rejected without adding anything:
+        let mut commits = chain.into_commits();
+        assert_eq!(commits.len(), 2);
+        let second = commits.pop().unwrap();
+        let mut broken = CommitChain::new(&base);
+        let mut state = broken.tip().clone_mut();
+        state.insert(EltId::from(2), "two".to_string()).unwrap();
+        broken.push_state(state, &mut mcm).unwrap();
+        let mut broken = broken.into_commits();
+        broken.push(second);
+        assert_eq!(part.push_chain(broken), Err(PatchOp::WrongParent));
+        assert_eq!(part.states_len(), 1);
+        assert_eq!(part.unsaved_len(), 0);
+        
+        let mut chain = CommitChain::new(&base);
+        let mut state = chain.tip().clone_mut();
+        state.insert(EltId::from(1), "one".to_string()).unwrap();
+        chain.push_state(state, &mut mcm).unwrap();
+        let mut state = chain.tip().clone_mut();
+        state.replace(EltId::from(1), "uno".to_string()).unwrap();
+        chain.push_state(state, &mut mcm).unwrap();
+        assert_eq!(part.push_chain(chain.into_commits()), Ok(2));
+        assert_eq!(part.unsaved_len(), 2);
+        assert_eq!(*part.tip().unwrap(), final_state);
+    }
+    
+    #[test]
+    fn verify_applies() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let part = Partition::create(control, "verify applies").unwrap();
+        let base = part.tip().unwrap().clone_exact();
+        let mut state = base.clone_mut();
+        state.insert(EltId::from(1), "one".to_string()).unwrap();
+        state.insert(EltId::from(2), "two".to_string()).unwrap();
+        let s1 = PartState::from_mut(state, &mut MCM);
+        let c1 = Commit::from_diff(&base, &s1).unwrap();
+        assert_eq!(c1.verify_applies(&base), Ok(()));
+        assert_eq!(c1.verify_applies(&s1), Err(PatchOp::WrongParent));
+        
+        let mut state = s1.clone_mut();
+        state.replace(EltId::from(1), "uno".to_string()).unwrap();
+        state.remove(EltId::from(2)).unwrap();
+        let s2 = PartState::from_mut(state, &mut MCM);
+        let c2 = Commit::from_diff(&s1, &s2).unwrap();
+        assert_eq!(c2.verify_applies(&s1), Ok(()));
+        
+        // A corrupted state-sum is detected:
+        let statesum = c2.statesum().clone();
+        let bad = Commit::new_explicit(&statesum ^ &Sum::calculate(b"x"),
+                c2.parents().to_vec(), HashMap::new(), c2.meta().clone());
+        assert_eq!(bad.verify_applies(&s1), Err(PatchOp::PatchApply));
+        
+        // A change which does not apply is detected:
+        let mut changes = HashMap::new();
+        changes.insert(EltId::from(1), EltChange::insertion(Rc::new("one".to_string())));
+        let c3 = Commit::new_explicit(statesum, vec![s1.statesum().clone()],
+                changes, c2.meta().clone());
+        assert_eq!(c3.verify_applies(&s1), Err(PatchOp::PatchApply));
+    }
+}
@@ -0,0 +1,271 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: checking partition files (format versions and consistency)
+
+use std::collections::{HashMap, BTreeMap};
+
+use control::{Control, WrittenFile};
+use error::{Result, ArgError, PatchOp};
+use rw::EltReader;
+use rw::header::{FileHeader, read_head};
+use rw::snapshot::{read_snapshot, read_snapshot_sums, diff_snapshot_files, SnapshotDiff};
+use rw::commitlog::{read_log, LogIndex, LogCheck};
+use state::PartState;
+use sum::Sum;
+
+use super::{Partition, body_reader};
+
+impl<C: Control> Partition<C> {
+    /// Report the file format versions used by this partition's snapshots
+    /// and commit logs, to allow planning migrations.
+    /// 
+    /// Only file headers are read. Files whose header cannot be read
+    /// (including those in unsupported versions) are counted as unreadable;
+    /// failure to open a file is an error.
+    pub fn format_report(&self) -> Result<FormatReport> {
+        let mut report = FormatReport::default();
+        let io = self.control.io();
+        let count = |head: Result<FileHeader>, report: &mut FormatReport| {
+            match head {
+                Ok(head) => {
+                    *report.versions.entry(head.ftype.ver()).or_insert(0) += 1;
+                    if head.ftype.is_deprecated() {
+                        report.deprecated += 1;
+                    }
+                },
+                Err(e) => {
+                    warn!("Unable to read file header: {}", e);
+                    report.unreadable += 1;
+                },
+            }
+        };
+        for ss in 0..io.ss_len() {
+            if let Some(mut r) = io.read_ss(ss)? {
+                report.oldest_ss = Some(report.oldest_ss.unwrap_or(ss));
+                report.newest_ss = Some(ss);
+                count(read_head(&mut *r), &mut report);
+            }
+            for cl in 0..io.ss_cl_len(ss) {
+                if let Some(mut r) = io.read_ss_cl(ss, cl)? {
+                    count(read_head(&mut *r), &mut report);
+                }
+            }
+        }
+        Ok(report)
+    }
+    
+    
+    /// Check the consistency of all snapshots and commit logs of this
+    /// partition, to detect corruption before a file is needed (see
+    /// `VerifyLevel` for what is checked). Nothing is loaded into the
+    /// partition; at level `Full` all states are however held in memory
+    /// while checking.
+    /// 
+    /// Problems found in files are recorded in the report; failure to open
+    /// a file is an error.
+    pub fn verify(&self, level: VerifyLevel) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let io = self.control.io();
+        let limits = self.control.user_meta_limits();
+        let mut states = HashMap::new();
+        let mut commits = Vec::new();
+        
+        for ss in 0..io.ss_len() {
+            if let Some(mut r) = io.read_ss(ss)? {
+                let file = WrittenFile::Snapshot(ss);
+                report.snapshots += 1;
+                let result = read_head(&mut *r).and_then(|head| {
+                    if head.name != self.name {
+                        report.problems.push((file, VerifyProblem::WrongName(head.name.clone())));
+                    }
+                    let mut r = body_reader(r, &head, self.control.cipher())?;
+                    match level {
+                        VerifyLevel::Headers => {},
+                        VerifyLevel::Checksums => {
+                            read_snapshot_sums(&mut r, head.ftype.ver(), head.dedup, &limits)?;
+                        },
+                        VerifyLevel::Full => {
+                            let state: PartState<C::Element> = read_snapshot(&mut r,
+                                    head.ftype.ver(), head.dedup, &limits,
+                                    &mut EltReader::new(self.control.elt_read_policy()))?;
+                            states.insert(state.statesum().clone(), state);
+                        },
+                    }
+                    Ok(())
+                });
+                if let Err(e) = result {
+                    report.problems.push((file, VerifyProblem::Unreadable(e.to_string())));
+                }
+            }
+            
+            for cl in 0..io.ss_cl_len(ss) {
+                if let Some(mut r) = io.read_ss_cl(ss, cl)? {
+                    let file = WrittenFile::CommitLog(ss, cl);
+                    report.logs += 1;
+                    let mut file_commits = Vec::new();
+                    let result = read_head(&mut *r).and_then(|head| {
+                        if head.name != self.name {
+                            report.problems.push((file, VerifyProblem::WrongName(head.name.clone())));
+                        }
+                        if level != VerifyLevel::Headers {
+                            let mut r = body_reader(r, &head, self.control.cipher())?;
+                            read_log(&mut r, &mut file_commits, head.ftype.ver(), &limits,
+                                    &mut EltReader::new(self.control.elt_read_policy()))?;
+                        }
+                        Ok(())
+                    });
+                    if let Err(e) = result {
+                        report.problems.push((file, VerifyProblem::Unreadable(e.to_string())));
+                    }
+                    report.commits += file_commits.len();
+                    if level == VerifyLevel::Full {
+                        commits.extend(file_commits.into_iter().map(|c| (file, c)));
+                    }
+                }
+            }
+        }
+        
+        // Apply commits in any order their parents allow (as when loading):
+        loop {
+            let num = commits.len();
+            let mut deferred = Vec::new();
+            for (file, commit) in commits {
+                if states.contains_key(commit.statesum()) {
+                    continue;   // already known (e.g. from a snapshot)
+                }
+                let result = match states.get(commit.first_parent()) {
+                    Some(parent) => PartState::from_state_commit(parent, &commit),
+                    None => {
+                        deferred.push((file, commit));
+                        continue;
+                    },
+                };
+                match result {
+                    Ok(state) => {
+                        states.insert(state.statesum().clone(), state);
+                    },
+                    Err(e) => report.problems.push((file,
+                            VerifyProblem::BadCommit(commit.statesum().clone(), e))),
+                }
+            }
+            commits = deferred;
+            if commits.len() == num {
+                break;
+            }
+        }
+        for (file, commit) in commits {
+            report.problems.push((file, VerifyProblem::MissingParent(commit.statesum().clone())));
+        }
+        
+        Ok(report)
+    }
+    
+    
+    /// Check commit log `cl` of snapshot `ss` against its stored index (see
+    /// `LogIndex`), detecting truncation or unindexed appends without parsing
+    /// the log.
+    /// 
+    /// Returns `None` if the log or its index is not found (indexes are only
+    /// stored by some `RepoIO` implementations). Fails on read errors, if
+    /// the index is corrupt or if the log does not match it.
+    pub fn check_log(&self, ss: usize, cl: usize) -> Result<Option<LogCheck>> {
+        let io = self.control.io();
+        let index = match io.read_ss_cl_index(ss, cl)? {
+            Some(mut r) => LogIndex::read_from(&mut *r)?,
+            None => return Ok(None),
+        };
+        match io.read_ss_cl(ss, cl)? {
+            Some(mut r) => Ok(Some(index.check(&mut *r)?)),
+            None => Ok(None),
+        }
+    }
+    
+    
+    /// Compare snapshots `old` and `new` (see `SnapshotDiff`). Snapshot
+    /// files are streamed; no states are loaded.
+    /// 
+    /// Fails if either snapshot is not found or cannot be read.
+    pub fn snapshot_diff(&self, old: usize, new: usize) -> Result<SnapshotDiff> {
+        let io = self.control.io();
+        match (io.read_ss(old)?, io.read_ss(new)?) {
+            (Some(mut r1), Some(mut r2)) => {
+                diff_snapshot_files(&mut *r1, &mut *r2, &self.control.user_meta_limits())
+            },
+            _ => ArgError::err("snapshot not found"),
+        }
+    }
+    
+}
+
+/// File format usage of a partition; see `Partition::format_report()`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct FormatReport {
+    /// Number of files using each format version (as from `FileType::ver()`)
+    pub versions: BTreeMap<u32, usize>,
+    /// Number of the oldest snapshot found, if any
+    pub oldest_ss: Option<usize>,
+    /// Number of the newest snapshot found, if any
+    pub newest_ss: Option<usize>,
+    /// Number of readable files using a deprecated format (see
+    /// `FileType::is_deprecated`)
+    pub deprecated: usize,
+    /// Number of files whose header could not be read
+    pub unreadable: usize,
+}
+impl FormatReport {
+    /// True if any file uses a deprecated or unreadable format.
+    pub fn needs_migration(&self) -> bool {
+        self.deprecated > 0 || self.unreadable > 0
+    }
+}
+
+/// How thoroughly `Partition::verify` checks files.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerifyLevel {
+    /// Read file headers only, checking versions and the partition name
+    Headers,
+    /// Also read snapshots and commit logs, verifying file and commit
+    /// checksums (snapshot elements are not deserialised)
+    Checksums,
+    /// Also rebuild states: verify element sums and state-sums of snapshots,
+    /// apply each commit to its parent, verifying the resulting state-sum
+    /// (including commit metadata), and check that each commit's parent is
+    /// found
+    Full,
+}
+
+/// A problem found by `Partition::verify`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum VerifyProblem {
+    /// The file could not be read (including checksum failures); the error
+    /// message is included
+    Unreadable(String),
+    /// The file header names another partition (the name found)
+    WrongName(String),
+    /// The first parent of a commit (given by its state-sum) is not found
+    MissingParent(Sum),
+    /// A commit (given by its state-sum) could not be applied to its parent
+    /// or does not yield the state-sum recorded
+    BadCommit(Sum, PatchOp),
+}
+
+/// Outcome of `Partition::verify`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct VerifyReport {
+    /// Number of snapshot files checked
+    pub snapshots: usize,
+    /// Number of commit log files checked
+    pub logs: usize,
+    /// Number of commits read (zero at level `Headers`)
+    pub commits: usize,
+    /// Problems found, with the file concerned
+    pub problems: Vec<(WrittenFile, VerifyProblem)>,
+}
+impl VerifyReport {
+    /// True if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
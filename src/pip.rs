@@ -8,19 +8,56 @@
 
 pub use ::LIB_VERSION;
 
-pub use commit::{UserMeta, CommitMeta, CommitMetaPartial, Commit, MakeCommitMeta, EltChange};
-pub use control::{Control, SnapshotPolicy, DefaultControl, DefaultSnapshot};
-pub use elt::{EltId, Element};
+pub use builder::PartitionBuilder;
+pub use commit::{UserMeta, InvalidText, UserMetaLimits, CommitMeta, CommitMetaPartial, Commit,
+        CommitChain, MakeCommitMeta, Clock, NoClock, EltChange, ReplicaId, MAX_ACKS,
+        MAX_AUTHOR_BYTES, CommitSummary};
+#[cfg(feature = "system-clock")]
+pub use commit::SystemClock;
+pub use control::{Control, SnapshotPolicy, DefaultControl, DefaultSnapshot, SnapshotConfig,
+        WrittenFile, DerivedElements, ProgressSink, DEFAULT_LOG_APPEND_LIMIT};
+pub use elt::{EltId, EltIdRange, EltMeta, Element, EltReadPolicy, Masked, MaskPolicy, ApplyOp, ApplyOpFn,
+        TextCanon, StdTextCanon};
 pub use error::{Result, Error, ReadError, ReadErrorFormatter, ArgError, ElementOp, PatchOp,
-        PathError, MatchError, TipError, MergeError, ReadOnly, UserError,
-        OtherError, make_io_err};
-pub use io::{DummyRepoIO, RepoIO};
-pub use io::discover::{part_from_path, discover_basename};
-pub use io::file::{PartPaths, RepoFileIO};
+        PathError, MatchError, TipError, MergeError, ReadOnly, MemLimit, UserError,
+        RepoError, OtherError, make_io_err};
+pub use index::{IndexKey, IndexFn};
+pub use io::{DummyRepoIO, RepoIO, MergeLock};
+pub use io::archive::{export_archive, import_archive};
+pub use io::mem::MemRepoIO;
+pub use io::retry::{RetryingRepoIO, RetryPolicy};
+#[cfg(feature = "sqlite")]
+pub use io::sqlite::RepoSqliteIO;
+pub use io::vfs::{Vfs, VfsEntry, VfsKind, PartitionVfs};
+#[cfg(feature = "file-io")]
+pub use io::cache::StateCache;
+#[cfg(feature = "file-io")]
+pub use io::discover::{part_from_path, part_from_path_filtered, discover_basename,
+        DiscoverFilter};
+#[cfg(feature = "file-io")]
+pub use io::file::{PartPaths, RepoFileIO, FileIoOptions};
+#[cfg(feature = "file-io")]
+pub use io::ingest::ingest;
 pub use merge::{TwoWayMerge, EltMerge, TwoWaySolver, TwoWaySolveUseA, TwoWaySolveUseB,
-        TwoWaySolveUseC, TwoWaySolveFail, TwoWaySolverChain, AncestorSolver2W, RenamingSolver2W};
-pub use part::{Partition, TipIter, StateItem, StateIter};
+        TwoWaySolveUseC, TwoWaySolveFail, TwoWaySolverChain, AncestorSolver2W, RenamingSolver2W,
+        NWayMerge, NWayEltMerge, NWaySolver, AncestorSolverNW, TwoWayAdapter};
+pub use part::{Partition, TipIter, StateItem, StateIter, LogIter, EltHistory, FormatReport, HeaderInfo,
+        PartitionHealth, MergeReadiness, LoadGoal, MergeBase, GcPolicy, CompactMode, WriteStats, ReceiveReport,
+        VerifyLevel, VerifyProblem, VerifyReport};
+pub use registry::{Registry, Registered, Tagged, RegistrySolver};
+pub use rw::EltReader;
+pub use rw::audit::{AuditOp, AuditEntry};
+pub use rw::sumfilter::SumFilter;
+pub use rw::compress::{Compression, CompressWriter, decompress};
+pub use rw::encrypt::{Cipher, CipherInfo, EncryptWriter, decrypt, encrypt_file};
+#[cfg(feature = "aes-gcm")]
+pub use rw::encrypt::AesGcmCipher;
+pub use rw::commitlog::{LogIndex, LogCheck, LogAppender, Recovery, RecoveryReport};
 pub use rw::header::{FileType, UserData, FileHeader, validate_repo_name};
+pub use rw::snapshot::{SnapshotDiff, SnapshotSums, read_snapshot_sums, diff_snapshot_files};
+pub use rw::fast_import::{write_fast_import, read_fast_export};
 pub use state::{PartState, MutPartState, StateRead, StateWrite, EltIter};
+pub use subscribe::{SubscriptionId, EltNotice, Notification, WatchFilter};
+pub use sync::{SyncTransport, StreamTransport};
 pub use sum::{Sum, SUM_BYTES};
 pub use util::{rtrim, ByteFormatter, HexFormatter};
@@ -0,0 +1,375 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for storing elements of several types within one partition
+//! 
+//! Partitions store a single `Element` type. Where several types of data are
+//! needed, the usual approach is an `enum` implementing `Element`; this module
+//! provides a generic alternative: a `Registry` mapping *type tags* to
+//! concrete `Element` types, and a `Tagged` element type which wraps a value
+//! of any registered type and serialises it prefixed by its tag.
+//! 
+//! The registry must be available when elements are deserialised, thus it is
+//! accessed statically through an implementation of `Registered` (usually on
+//! a marker type). Merge policies may be specified per type, and are used by
+//! `RegistrySolver`.
+//! 
+//! Example:
+//! 
+//! ```
+//! use std::sync::OnceLock;
+//! use pippin::registry::{Registry, Registered, Tagged};
+//! 
+//! struct MyTypes;
+//! impl Registered for MyTypes {
+//!     fn registry() -> &'static Registry<Tagged<MyTypes>> {
+//!         static REG: OnceLock<Registry<Tagged<MyTypes>>> = OnceLock::new();
+//!         REG.get_or_init(|| {
+//!             let mut reg = Registry::new();
+//!             reg.register::<String>(1, "text", None).expect("register");
+//!             reg
+//!         })
+//!     }
+//! }
+//! 
+//! let elt = Tagged::<MyTypes>::new("a string".to_string()).expect("registered");
+//! assert_eq!(elt.tag(), 1);
+//! assert_eq!(elt.downcast_ref::<String>().map(|s| s.as_str()), Some("a string"));
+//! ```
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use elt::Element;
+use error::{Result, ArgError};
+use merge::{EltMerge, TwoWaySolver, AncestorSolver2W};
+
+
+/// Object-safe view of an `Element`, allowing values of different types to
+/// be held by `Tagged`. Implemented for all `Element` types.
+pub trait AnyElement: fmt::Debug {
+    /// Get self as `Any` (for downcasting)
+    fn as_any(&self) -> &Any;
+    /// Write a serialisation (see `Element::write_buf`)
+    fn write_any(&self, writer: &mut Write) -> Result<()>;
+    /// Test equality; values of different types are never equal
+    fn eq_any(&self, other: &AnyElement) -> bool;
+}
+impl<T: Element> AnyElement for T {
+    fn as_any(&self) -> &Any {
+        self
+    }
+    fn write_any(&self, writer: &mut Write) -> Result<()> {
+        self.write_buf(writer)
+    }
+    fn eq_any(&self, other: &AnyElement) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+}
+
+/// Signature of per-type merge policies (see `TwoWaySolver::solve`).
+/// 
+/// The policy of a type is only used where all present elements have that type.
+pub type SolveFn<E> = fn(Option<&Rc<E>>, Option<&Rc<E>>, Option<&Rc<E>>) -> EltMerge<E>;
+
+struct Entry<E: Element> {
+    name: &'static str,
+    read: fn(&[u8]) -> Result<Box<AnyElement>>,
+    solver: Option<SolveFn<E>>,
+}
+
+fn read_any<T: Element>(buf: &[u8]) -> Result<Box<AnyElement>> {
+    Ok(Box::new(T::read_buf(buf)?))
+}
+
+/// A set of element types, each identified by a numeric tag.
+/// 
+/// Tags are written to files, thus must not be changed or reused once data
+/// has been written.
+pub struct Registry<E: Element> {
+    entries: HashMap<u32, Entry<E>>,
+    tags: HashMap<TypeId, u32>,
+}
+impl<E: Element> Registry<E> {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Registry { entries: HashMap::new(), tags: HashMap::new() }
+    }
+    
+    /// Register type `T` with the given `tag` and a descriptive `name`.
+    /// 
+    /// `solver` optionally specifies a merge policy for elements of this type.
+    /// 
+    /// Fails if either the tag or the type is already registered.
+    pub fn register<T: Element>(&mut self, tag: u32, name: &'static str,
+            solver: Option<SolveFn<E>>) -> Result<()>
+    {
+        if self.entries.contains_key(&tag) {
+            return ArgError::err("tag already registered");
+        }
+        let type_id = TypeId::of::<T>();
+        if self.tags.contains_key(&type_id) {
+            return ArgError::err("type already registered");
+        }
+        self.entries.insert(tag, Entry { name, read: read_any::<T>, solver });
+        self.tags.insert(type_id, tag);
+        Ok(())
+    }
+    
+    /// Get the number of registered types
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    
+    /// True if no types are registered
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    
+    /// Get the tag of type `T`, if registered
+    pub fn tag_of<T: Element>(&self) -> Option<u32> {
+        self.tags.get(&TypeId::of::<T>()).cloned()
+    }
+    
+    /// Get the name of the type registered with `tag`
+    pub fn name(&self, tag: u32) -> Option<&'static str> {
+        self.entries.get(&tag).map(|entry| entry.name)
+    }
+    
+    /// Get the merge policy of the type registered with `tag`, if any
+    pub fn solver(&self, tag: u32) -> Option<SolveFn<E>> {
+        self.entries.get(&tag).and_then(|entry| entry.solver)
+    }
+}
+impl<E: Element> Default for Registry<E> {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+impl<E: Element> fmt::Debug for Registry<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut tags: Vec<_> = self.entries.iter()
+                .map(|(tag, entry)| (*tag, entry.name)).collect();
+        tags.sort();
+        f.debug_struct("Registry").field("types", &tags).finish()
+    }
+}
+
+/// Provides static access to a `Registry`; usually implemented on a marker
+/// type (see module documentation).
+pub trait Registered: Sized + 'static {
+    /// Get the registry
+    fn registry() -> &'static Registry<Tagged<Self>>;
+}
+
+/// An element holding a value of any type registered in `R::registry()`.
+/// 
+/// This is serialised as the type tag (4 bytes, big-endian) followed by the
+/// serialisation of the value.
+pub struct Tagged<R: Registered> {
+    tag: u32,
+    value: Box<AnyElement>,
+    _reg: PhantomData<R>,
+}
+impl<R: Registered> Tagged<R> {
+    /// Wrap a value. Fails if its type is not registered.
+    pub fn new<T: Element>(value: T) -> Result<Self> {
+        match R::registry().tag_of::<T>() {
+            Some(tag) => Ok(Tagged { tag, value: Box::new(value), _reg: PhantomData }),
+            None => ArgError::err("type not registered"),
+        }
+    }
+    
+    /// Get the type tag
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+    
+    /// Get the registered name of the value's type
+    pub fn type_name(&self) -> &'static str {
+        R::registry().name(self.tag).expect("tag registered")
+    }
+    
+    /// True if the value has type `T`
+    pub fn is<T: Element>(&self) -> bool {
+        self.value.as_any().is::<T>()
+    }
+    
+    /// Get the value as type `T`, if it has this type
+    pub fn downcast_ref<T: Element>(&self) -> Option<&T> {
+        self.value.as_any().downcast_ref::<T>()
+    }
+    
+    /// Get the value (as a trait object)
+    pub fn value(&self) -> &AnyElement {
+        &*self.value
+    }
+}
+impl<R: Registered> PartialEq for Tagged<R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.tag == other.tag && self.value.eq_any(&*other.value)
+    }
+}
+impl<R: Registered> Eq for Tagged<R> {}
+impl<R: Registered> fmt::Debug for Tagged<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Tagged").field(&self.tag).field(&self.value).finish()
+    }
+}
+impl<R: Registered> Element for Tagged<R> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.tag)?;
+        self.value.write_any(writer)
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 4 {
+            return ArgError::err("tagged element too short");
+        }
+        let tag = BigEndian::read_u32(&buf[0..4]);
+        let read = match R::registry().entries.get(&tag) {
+            Some(entry) => entry.read,
+            None => return ArgError::err("unregistered element type tag"),
+        };
+        Ok(Tagged { tag, value: read(&buf[4..])?, _reg: PhantomData })
+    }
+}
+
+/// A `TwoWaySolver` for `Tagged` elements, using the merge policy registered
+/// for the elements' type where available and a fall-back solver otherwise
+/// (including where the elements have different types).
+pub struct RegistrySolver<R: Registered> {
+    fallback: Box<TwoWaySolver<Tagged<R>>>,
+}
+impl<R: Registered> RegistrySolver<R> {
+    /// Create, with an `AncestorSolver2W` as fall-back
+    pub fn new() -> Self {
+        RegistrySolver::with_fallback(Box::new(AncestorSolver2W::new()))
+    }
+    /// Create, with the given fall-back solver
+    pub fn with_fallback(fallback: Box<TwoWaySolver<Tagged<R>>>) -> Self {
+        RegistrySolver { fallback }
+    }
+}
+impl<R: Registered> Default for RegistrySolver<R> {
+    fn default() -> Self {
+        RegistrySolver::new()
+    }
+}
+impl<R: Registered> TwoWaySolver<Tagged<R>> for RegistrySolver<R> {
+    fn solve<'a>(&self, a: Option<&'a Rc<Tagged<R>>>, b: Option<&'a Rc<Tagged<R>>>,
+        c: Option<&'a Rc<Tagged<R>>>) -> EltMerge<Tagged<R>>
+    {
+        let mut tags = a.iter().chain(b.iter()).chain(c.iter()).map(|elt| elt.tag);
+        let solver = tags.next().and_then(|tag| {
+            if tags.all(|t| t == tag) { R::registry().solver(tag) } else { None }
+        });
+        match solver {
+            Some(solve) => solve(a, b, c),
+            None => self.fallback.solve(a, b, c),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::OnceLock;
+    
+    use super::*;
+    use elt::{Element, EltId};
+    use merge::{EltMerge, TwoWaySolver};
+    
+    #[derive(PartialEq, Eq, Debug)]
+    struct Counter(u32);
+    impl Element for Counter {
+        fn write_buf(&self, writer: &mut Write) -> Result<()> {
+            writer.write_u32::<BigEndian>(self.0)?;
+            Ok(())
+        }
+        fn read_buf(buf: &[u8]) -> Result<Self> {
+            if buf.len() != 4 {
+                return ArgError::err("bad length");
+            }
+            Ok(Counter(BigEndian::read_u32(buf)))
+        }
+    }
+    
+    // Merge counters by taking the maximum
+    fn solve_max(a: Option<&Rc<Tagged<Types>>>, b: Option<&Rc<Tagged<Types>>>,
+            _: Option<&Rc<Tagged<Types>>>) -> EltMerge<Tagged<Types>>
+    {
+        let value = |elt: Option<&Rc<Tagged<Types>>>|
+                elt.and_then(|e| e.downcast_ref::<Counter>()).map_or(0, |c| c.0);
+        if value(a) >= value(b) { EltMerge::A } else { EltMerge::B }
+    }
+    
+    struct Types;
+    impl Registered for Types {
+        fn registry() -> &'static Registry<Tagged<Types>> {
+            static REG: OnceLock<Registry<Tagged<Types>>> = OnceLock::new();
+            REG.get_or_init(|| {
+                let mut reg = Registry::new();
+                reg.register::<String>(1, "text", None).expect("register");
+                reg.register::<Counter>(2, "counter", Some(solve_max)).expect("register");
+                reg
+            })
+        }
+    }
+    
+    #[test]
+    fn register() {
+        let mut reg = Registry::<Tagged<Types>>::new();
+        assert!(reg.is_empty());
+        reg.register::<String>(1, "text", None).expect("register");
+        assert!(reg.register::<Counter>(1, "counter", None).is_err());
+        assert!(reg.register::<String>(2, "text again", None).is_err());
+        assert_eq!(reg.tag_of::<String>(), Some(1));
+        assert_eq!(reg.tag_of::<Counter>(), None);
+        assert_eq!(reg.len(), 1);
+    }
+    
+    #[test]
+    fn tagged_round_trip() {
+        assert_eq!(Types::registry().len(), 2);
+        
+        let text = Tagged::<Types>::new("text".to_string()).expect("registered");
+        let counter = Tagged::<Types>::new(Counter(7)).expect("registered");
+        assert_eq!((text.tag(), text.type_name()), (1, "text"));
+        assert!(counter.is::<Counter>() && !counter.is::<String>());
+        assert!(text != counter);
+        
+        for elt in &[text, counter] {
+            let mut buf = Vec::new();
+            elt.write_buf(&mut buf).expect("write");
+            let elt2 = Tagged::<Types>::read_buf(&buf).expect("read");
+            assert_eq!(*elt, elt2);
+            assert_eq!(elt.sum(EltId::from(1)), elt2.sum(EltId::from(1)));
+        }
+        assert!(Tagged::<Types>::read_buf(&[0, 0, 0, 9, 1]).is_err());
+    }
+    
+    #[test]
+    fn per_type_solver() {
+        let solver = RegistrySolver::<Types>::new();
+        let c = |n| Rc::new(Tagged::<Types>::new(Counter(n)).expect("registered"));
+        let t = |s: &str| Rc::new(Tagged::<Types>::new(s.to_string()).expect("registered"));
+        
+        let (c1, c5, c3) = (c(1), c(5), c(3));
+        assert!(solver.solve(Some(&c1), Some(&c5), Some(&c3)) == EltMerge::B);
+        assert!(solver.solve(Some(&c5), Some(&c1), None) == EltMerge::A);
+        // no policy for strings: fall back to ancestor solver
+        let (t1, t2) = (t("one"), t("two"));
+        assert!(solver.solve(Some(&t1), Some(&t2), Some(&t1)) == EltMerge::B);
+        assert!(solver.solve(Some(&t1), Some(&t2), None) == EltMerge::Fail);
+        // mixed types
+        assert!(solver.solve(Some(&c1), Some(&t2), None) == EltMerge::Fail);
+    }
+}
@@ -0,0 +1,149 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Audit log of administrative operations
+//! 
+//! Operations changing the structure of a partition's history (see
+//! `AuditOp`) are recorded in an append-only stream provided by the
+//! `RepoIO` (see `RepoIO::append_audit`). This is a text format with one
+//! entry per line:
+//! 
+//! ```text
+//! <timestamp> <operation> <details>
+//! ```
+//! 
+//! where the timestamp is in seconds since the UNIX epoch, the operation is
+//! one of the names given by `AuditOp::name` and details are free text
+//! (without line breaks).
+
+use std::io::{Read, Write};
+
+use error::{Result, ReadError};
+
+/// An administrative operation recorded in the audit log
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AuditOp {
+    /// `Partition::vacuum`
+    Vacuum,
+    /// `Partition::gc`
+    Gc,
+    /// `Partition::compact_history`
+    CompactHistory,
+    /// `Partition::erase_element_history`
+    EraseElement,
+    /// `Partition::reconcile`
+    Reconcile,
+    /// `Partition::split_off`
+    SplitOff,
+    /// `Partition::archive`
+    Archive,
+    /// `Partition::unarchive`
+    Unarchive,
+}
+
+impl AuditOp {
+    /// Name as recorded in the audit log
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditOp::Vacuum => "vacuum",
+            AuditOp::Gc => "gc",
+            AuditOp::CompactHistory => "compact-history",
+            AuditOp::EraseElement => "erase-element",
+            AuditOp::Reconcile => "reconcile",
+            AuditOp::SplitOff => "split-off",
+            AuditOp::Archive => "archive",
+            AuditOp::Unarchive => "unarchive",
+        }
+    }
+    
+    /// Get the operation with this name, if known
+    pub fn from_name(name: &str) -> Option<AuditOp> {
+        match name {
+            "vacuum" => Some(AuditOp::Vacuum),
+            "gc" => Some(AuditOp::Gc),
+            "compact-history" => Some(AuditOp::CompactHistory),
+            "erase-element" => Some(AuditOp::EraseElement),
+            "reconcile" => Some(AuditOp::Reconcile),
+            "split-off" => Some(AuditOp::SplitOff),
+            "archive" => Some(AuditOp::Archive),
+            "unarchive" => Some(AuditOp::Unarchive),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the audit log (see `Partition::audit_log`)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AuditEntry {
+    /// Time of the operation, in seconds since the UNIX epoch (see
+    /// `MakeCommitMeta::make_commit_timestamp`)
+    pub timestamp: i64,
+    /// The operation
+    pub op: AuditOp,
+    /// Description of the operation's arguments and outcome
+    pub detail: String,
+}
+
+/// Write one entry to an audit log stream. Line breaks in the details are
+/// replaced by spaces.
+pub fn write_audit_entry(entry: &AuditEntry, w: &mut Write) -> Result<()> {
+    let detail = entry.detail.replace(['\n', '\r'], " ");
+    writeln!(w, "{} {} {}", entry.timestamp, entry.op.name(), detail)?;
+    Ok(())
+}
+
+/// Read all entries of an audit log stream.
+/// 
+/// Entries with unknown operations (e.g. written by a newer version of this
+/// library) are skipped with a warning; malformed entries cause an error.
+pub fn read_audit(r: &mut Read) -> Result<Vec<AuditEntry>> {
+    let mut text = String::new();
+    r.read_to_string(&mut text)?;
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    for line in text.lines() {
+        let mut parts = line.splitn(3, ' ');
+        let timestamp = parts.next().and_then(|t| t.parse::<i64>().ok());
+        let name = parts.next();
+        let (timestamp, name) = match (timestamp, name) {
+            (Some(t), Some(name)) => (t, name),
+            _ => return ReadError::err("malformed audit log entry", pos, (0, line.len())),
+        };
+        match AuditOp::from_name(name) {
+            Some(op) => entries.push(AuditEntry {
+                timestamp,
+                op,
+                detail: parts.next().unwrap_or("").to_string(),
+            }),
+            None => warn!("skipping audit log entry with unknown operation: {}", name),
+        }
+        pos += line.len() + 1;
+    }
+    Ok(entries)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn audit_entries() {
+        let entries = vec![
+            AuditEntry { timestamp: 1_700_000_000, op: AuditOp::Gc, detail: "removed 4\nfiles".to_string() },
+            AuditEntry { timestamp: -5, op: AuditOp::Vacuum, detail: String::new() },
+        ];
+        let mut buf = Vec::new();
+        for entry in &entries {
+            write_audit_entry(entry, &mut buf).unwrap();
+        }
+        buf.extend_from_slice(b"17 frobnicate things\n");
+        let read = read_audit(&mut &buf[..]).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].detail, "removed 4 files");
+        assert_eq!(read[1], entries[1]);
+        
+        assert!(read_audit(&mut &b"gc 17 oops\n"[..]).is_err());
+    }
+}
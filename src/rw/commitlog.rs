@@ -13,11 +13,13 @@ use std::u32;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
-use rw::{sum, read_meta, write_meta};
-use commit::{Commit, EltChange};
-use elt::Element;
+use rw::{sum, read_meta, write_meta, EltReader, HEAD_VERSIONS};
+use rw::compress::Compression;
+use rw::header::{FileHeader, FileType, read_head, write_head, validate_repo_name};
+use commit::{Commit, EltChange, UserMetaLimits};
+use elt::{Element, EltId};
 use sum::{Sum, SUM_BYTES};
-use error::{Result, ReadError};
+use error::{Result, ReadError, ArgError, PatchOp};
 
 /// Implement this to use `read_log()`.
 /// 
@@ -39,15 +41,18 @@ impl<E: Element> CommitReceiver<E> for Vec<Commit<E>> {
 
 /// Read a commit log from a stream
 /// 
-/// `format_ver` is the decimalised file format version
-pub fn read_log<E: Element>(mut reader: &mut Read,
-        receiver: &mut CommitReceiver<E>, format_ver: u32) -> Result<()>
+/// `format_ver` is the decimalised file format version; user metadata is
+/// checked against `limits`. Elements are deserialised via `elts`; where
+/// an element is skipped, the change is read as `EltChange::Erased`.
+pub fn read_log<E: Element>(reader: &mut Read,
+        receiver: &mut CommitReceiver<E>, format_ver: u32,
+        limits: &UserMetaLimits, elts: &mut EltReader) -> Result<()>
 {
     let mut pos: usize = 0;
     let mut buf = vec![0; 32];
     
     reader.read_exact(&mut buf[0..16])?;
-    if buf[0..16] != *b"COMMIT LOG\x00\x00\x00\x00\x00\x00" {
+    if buf[0..16] != *LOG_START {
         return ReadError::err("unexpected contents (expected \
             COMMIT LOG\\x00\\x00\\x00\\x00\\x00\\x00)", pos, (0, 16));
     }
@@ -56,139 +61,290 @@ pub fn read_log<E: Element>(mut reader: &mut Read,
     // We now read commits. Since new commits can simply be appended to the
     // file, we only know we're at the end if we hit EOF. This is the only
     // condition where encountering EOF is not an error.
-    loop {
-        // A reader which calculates the checksum of what was read:
-        let mut r = sum::HashReader::new(reader);
-        
-        let l = r.read(&mut buf[0..16])?;
-        if l == 0 { break; /*end of file (EOF)*/ }
-        if l < 16 { r.read_exact(&mut buf[l..16])?; /*not EOF, buf haven't filled buffer*/ }
-        
-        let n_parents = if buf[0..6] == *b"COMMIT" {
-            1
-        } else if buf[0..5] == *b"MERGE" {
-            let n: u8 = buf[5];
-            if n < 2 { return ReadError::err("bad number of parents", pos, (5, 6)); }
-            n as usize
-        } else {
-            return ReadError::err("unexpected contents (expected COMMIT or MERGE)", pos, (0, 6));
-        };
-        if buf[6..8] != *b"\x00U" {
-            return ReadError::err("unexpected contents (expected \\x00U)", pos, (6, 8));
-        }
-        let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver)?;
-        
-        let mut parents = Vec::with_capacity(n_parents);
-        for _ in 0..n_parents {
-            r.read_exact(&mut buf[0..SUM_BYTES])?;
-            parents.push(Sum::load(&buf[0..SUM_BYTES]));
-            pos += SUM_BYTES;
+    while let Some(commit) = read_commit(reader, &mut buf, &mut pos, format_ver, limits, elts)? {
+        if !receiver.receive(commit) { break; }
+    }
+    Ok(())
+}
+
+/// How to deal with damaged commit logs when loading (see
+/// `Partition::load_range`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Recovery {
+    /// Fail on any error
+    Strict,
+    /// Skip unreadable sections of commit logs, salvaging readable commits
+    /// (see `read_log_tolerant`)
+    Tolerant,
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Recovery::Strict
+    }
+}
+
+/// Report on a commit log read by `read_log_tolerant`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct RecoveryReport {
+    /// Commits recovered: state-sum and identifiers of elements changed
+    pub recovered: Vec<(Sum, Vec<EltId>)>,
+    /// Byte ranges `(start, end)` which could not be read, as offsets from
+    /// the start of the log contents (following the file header)
+    pub lost: Vec<(u64, u64)>,
+}
+
+impl RecoveryReport {
+    /// True if any part of the log could not be read
+    pub fn is_damaged(&self) -> bool {
+        !self.lost.is_empty()
+    }
+}
+
+/// Read a commit log from a stream like `read_log`, but skip over damaged
+/// sections: on a parse or checksum failure, reading resumes from the next
+/// commit marker (`COMMIT`, `MERGE` or `SQUASH` at a 16-byte boundary).
+/// 
+/// The whole stream is read into memory. Errors are only returned if the
+/// stream itself cannot be read or the progress sink of `elts` fails. Commits are passed to `receiver` as they
+/// are read; which were recovered and which byte ranges were lost is
+/// returned as a report.
+pub fn read_log_tolerant<E: Element>(reader: &mut Read,
+        receiver: &mut CommitReceiver<E>, format_ver: u32,
+        limits: &UserMetaLimits, elts: &mut EltReader) -> Result<RecoveryReport>
+{
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let mut report = RecoveryReport::default();
+    let mut buf = vec![0; 32];
+    
+    let mut offset = if data.starts_with(LOG_START) {
+        LOG_START.len()
+    } else {
+        let next = next_commit_marker(&data, 0);
+        warn!("Commit log damaged at byte 0: no section identifier");
+        report.lost.push((0, next as u64));
+        next
+    };
+    while offset < data.len() {
+        let mut r = &data[offset..];
+        let mut pos = offset;
+        match read_commit::<E>(&mut r, &mut buf, &mut pos, format_ver, limits, elts) {
+            Ok(Some(commit)) => {
+                offset = data.len() - r.len();
+                let mut ids: Vec<EltId> = commit.changes_iter().map(|(id, _)| *id).collect();
+                ids.sort();
+                report.recovered.push((commit.statesum().clone(), ids));
+                if !receiver.receive(commit) { break; }
+            },
+            Ok(None) => break,
+            Err(e) => {
+                if elts.aborted() {
+                    return Err(e);
+                }
+                let next = next_commit_marker(&data, offset + 16);
+                warn!("Commit log damaged at byte {}: {}; skipping {} bytes",
+                        offset, e, next - offset);
+                report.lost.push((offset as u64, next as u64));
+                offset = next;
+            },
         }
-        
-        r.read_exact(&mut buf[0..16])?;
-        if buf[0..8] != *b"ELEMENTS" {
-            return ReadError::err("unexpected contents (expected ELEMENTS)", pos, (0, 8));
+    }
+    Ok(report)
+}
+
+// Find the next position from `start` which could be the start of a commit,
+// or the length of `data` if there is none. Sections are 16-byte aligned.
+fn next_commit_marker(data: &[u8], start: usize) -> usize {
+    let mut p = (start + 15) / 16 * 16;
+    while p + 16 <= data.len() {
+        let b = &data[p..p + 16];
+        if b[0..8] == *b"COMMIT\x00U" || b[0..6] == *b"SQUASH" ||
+            (b[0..5] == *b"MERGE" && b[6..8] == *b"\x00U")
+        {
+            return p;
         }
-        let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
-        pos += 16;
-        
-        let mut changes = HashMap::new();
-        
-        for _ in 0..num_elts {
-            r.read_exact(&mut buf[0..16])?;
-            if buf[0..4] != *b"ELT " {
-                return ReadError::err("unexpected contents (expected ELT\\x20)", pos, (0, 4));
-            }
-            let elt_id = BigEndian::read_u64(&buf[8..16]).into();
-            let change_t = match &buf[4..8] {
-                b"DEL\x00" => { Change::Delete },
-                b"INS\x00" => { Change::Insert },
-                b"REPL" => { Change::Replace },
-                _ => {
-                    return ReadError::err("unexpected contents (expected one \
-                        of DEL\\x00, INS\\x00, REPL)", pos, (4, 8));
-                }
-            };
-            pos += 16;
-            
-            let change = match change_t {
-                Change::Delete => EltChange::deletion(),
-                Change::Insert | Change::Replace => {
-                    r.read_exact(&mut buf[0..16])?;
-                    if buf[0..8] != *b"ELT DATA" {
-                        return ReadError::err("unexpected contents (expected ELT DATA)", pos, (0, 8));
-                    }
-                    let data_len = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
-                    pos += 16;
-                    
-                    let mut data = vec![0; data_len];
-                    r.read_exact(&mut data)?;
-                    pos += data_len;
-                    
-                    let pad_len = 16 * ((data_len + 15) / 16) - data_len;
-                    if pad_len > 0 {
-                        r.read_exact(&mut buf[0..pad_len])?;
-                        pos += pad_len;
-                    }
-                    
-                    let elt_sum = Sum::elt_sum(elt_id, &data);
-                    r.read_exact(&mut buf[0..SUM_BYTES])?;
-                    if elt_sum != buf[0..SUM_BYTES] {
-                        return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
-                    }
-                    pos += SUM_BYTES;
-                    
-                    let elt = Rc::new(E::from_vec_sum(data, elt_sum)?);
-                    match change_t {
-                        Change::Insert => EltChange::insertion(elt),
-                        Change::Replace => EltChange::replacement(elt),
-                        _ => panic!()
-                    }
-                },
-            };
-            changes.insert(elt_id, change);
+        p += 16;
+    }
+    data.len()
+}
+
+// Section identifier at the start of a commit log
+const LOG_START: &[u8; 16] = b"COMMIT LOG\x00\x00\x00\x00\x00\x00";
+
+// Read a single commit, or return `None` at the end of the stream.
+fn read_commit<E: Element>(mut reader: &mut Read, buf: &mut [u8], pos: &mut usize,
+        format_ver: u32, limits: &UserMetaLimits, elts: &mut EltReader)
+        -> Result<Option<Commit<E>>>
+{
+    // A reader which calculates the checksum of what was read:
+    let mut r = sum::HashReader::new(reader);
+    
+    let l = r.read(&mut buf[0..16])?;
+    if l == 0 { return Ok(None); /*end of file (EOF)*/ }
+    if l < 16 { r.read_exact(&mut buf[l..16])?; /*not EOF, buf haven't filled buffer*/ }
+    
+    // versions from 20261021 may have squashed commits
+    let squash = buf[0..6] == *b"SQUASH" && format_ver >= 2026_10_21;
+    let n_parents = if squash {
+        if buf[7] != b'U' {
+            return ReadError::err("unexpected contents (expected U)", *pos, (7, 8));
         }
-        
+        let n: u8 = buf[6];
+        if n < 1 { return ReadError::err("bad number of parents", *pos, (6, 7)); }
+        n as usize
+    } else if buf[0..6] == *b"COMMIT" {
+        1
+    } else if buf[0..5] == *b"MERGE" {
+        let n: u8 = buf[5];
+        if n < 2 { return ReadError::err("bad number of parents", *pos, (5, 6)); }
+        n as usize
+    } else {
+        return ReadError::err("unexpected contents (expected COMMIT, MERGE or SQUASH)", *pos, (0, 6));
+    };
+    if !squash && buf[6..8] != *b"\x00U" {
+        return ReadError::err("unexpected contents (expected \\x00U)", *pos, (6, 8));
+    }
+    let meta = read_meta(&mut r, buf, pos, format_ver, limits)?;
+    
+    let base = if squash {
         r.read_exact(&mut buf[0..SUM_BYTES])?;
-        let commit_sum = Sum::load(&buf[0..SUM_BYTES]);
-        pos += SUM_BYTES;
-        
-        let sum = r.sum();
-        reader = r.into_inner();
-        reader.read_exact(&mut buf[0..SUM_BYTES])?;
-        if sum != buf[0..SUM_BYTES] {
-            return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
+        *pos += SUM_BYTES;
+        Some(Sum::load(&buf[0..SUM_BYTES]))
+    } else {
+        None
+    };
+    let mut parents = Vec::with_capacity(n_parents);
+    for _ in 0..n_parents {
+        r.read_exact(&mut buf[0..SUM_BYTES])?;
+        parents.push(Sum::load(&buf[0..SUM_BYTES]));
+        *pos += SUM_BYTES;
+    }
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != *b"ELEMENTS" {
+        return ReadError::err("unexpected contents (expected ELEMENTS)", *pos, (0, 8));
+    }
+    let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
+    *pos += 16;
+    
+    let mut changes = HashMap::new();
+    
+    for _ in 0..num_elts {
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..4] != *b"ELT " {
+            return ReadError::err("unexpected contents (expected ELT\\x20)", *pos, (0, 4));
         }
+        let elt_id = BigEndian::read_u64(&buf[8..16]).into();
+        let change_t = match &buf[4..8] {
+            b"DEL\x00" => { Change::Delete },
+            b"INS\x00" => { Change::Insert },
+            b"REPL" => { Change::Replace },
+            // versions from 20261018 may have erased elements
+            b"ERAS" if format_ver >= 2026_10_18 => { Change::Erased },
+            // versions from 20261019 may have operations
+            b"PATC" if format_ver >= 2026_10_19 => { Change::Patch },
+            _ => {
+                return ReadError::err("unexpected contents (expected one \
+                    of DEL\\x00, INS\\x00, REPL, ERAS, PATC)", *pos, (4, 8));
+            }
+        };
+        *pos += 16;
         
-        trace!("Read commit ({} changes): {}; first parent: {}", changes.len(), commit_sum, parents[0]);
-        let cont = receiver.receive(Commit::new_explicit(commit_sum, parents, changes, meta));
-        if !cont { break; }
+        let change = match change_t {
+            Change::Delete => EltChange::deletion(),
+            Change::Erased => {
+                r.read_exact(&mut buf[0..SUM_BYTES])?;
+                *pos += SUM_BYTES;
+                EltChange::Erased(Sum::load(&buf[0..SUM_BYTES]))
+            },
+            Change::Patch => {
+                r.read_exact(&mut buf[0..16])?;
+                if buf[0..8] != *b"ELT OPS\x00" {
+                    return ReadError::err("unexpected contents (expected ELT OPS\\x00)", *pos, (0, 8));
+                }
+                let num_ops = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
+                *pos += 16;
+                if num_ops == 0 {
+                    return ReadError::err("no operations", *pos - 16, (8, 16));
+                }
+                
+                let mut ops = Vec::new();
+                for _ in 0..num_ops {
+                    ops.push(read_data(&mut r, buf, pos)?);
+                }
+                r.read_exact(&mut buf[0..SUM_BYTES])?;
+                *pos += SUM_BYTES;
+                EltChange::Operation(ops, Sum::load(&buf[0..SUM_BYTES]))
+            },
+            Change::Insert | Change::Replace => {
+                let data = read_data(&mut r, buf, pos)?;
+                
+                let elt_sum = Sum::elt_sum(elt_id, &data);
+                r.read_exact(&mut buf[0..SUM_BYTES])?;
+                if elt_sum != buf[0..SUM_BYTES] {
+                    return ReadError::err("element checksum mismatch", *pos, (0, SUM_BYTES));
+                }
+                *pos += SUM_BYTES;
+                
+                match (elts.read(elt_id, data, elt_sum.clone())?, change_t) {
+                    (Some(elt), Change::Insert) => EltChange::insertion(Rc::new(elt)),
+                    (Some(elt), Change::Replace) => EltChange::replacement(Rc::new(elt)),
+                    (None, _) => EltChange::Erased(elt_sum),
+                    _ => panic!()
+                }
+            },
+        };
+        changes.insert(elt_id, change);
     }
     
-    #[derive(Eq, PartialEq, Copy, Clone, Debug)]
-    enum Change {
-        Delete, Insert, Replace
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    let commit_sum = Sum::load(&buf[0..SUM_BYTES]);
+    *pos += SUM_BYTES;
+    
+    let sum = r.sum();
+    reader = r.into_inner();
+    reader.read_exact(&mut buf[0..SUM_BYTES])?;
+    if sum != buf[0..SUM_BYTES] {
+        return ReadError::err("checksum invalid", *pos, (0, SUM_BYTES));
     }
     
-    Ok(())
+    trace!("Read commit ({} changes): {}; first parent: {}", changes.len(), commit_sum, parents[0]);
+    Ok(Some(match base {
+        Some(base) => Commit::new_squash_explicit(commit_sum, base, parents, changes, meta),
+        None => Commit::new_explicit(commit_sum, parents, changes, meta),
+    }))
+}
+
+// Type of an element change, as read
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+enum Change {
+    Delete, Insert, Replace, Erased, Patch
 }
 
 /// Write the section identifier at the start of a commit log
 // #0016: do we actually need this?
 pub fn start_log(writer: &mut Write) -> Result<()> {
-    writer.write_all(b"COMMIT LOG\x00\x00\x00\x00\x00\x00")?;
+    writer.write_all(LOG_START)?;
     Ok(())
 }
 
 /// Write a single commit to a stream
-pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Result<()> {
+/// 
+/// Returns the checksum written at the end of the commit (as recorded by
+/// `LogIndex`).
+pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Result<Sum> {
     trace!("Writing commit ({} changes): {}",
         commit.num_changes(), commit.statesum());
     
     // A writer which calculates the checksum of what was written:
     let mut w = sum::HashWriter::new(writer);
     
-    if commit.parents().len() == 1 {
+    if commit.squash_base().is_some() {
+        assert!(commit.parents().len() < 0x100);
+        w.write_all(b"SQUASH")?;
+        w.write_all(&[commit.parents().len() as u8, b'U'])?;
+    } else if commit.parents().len() == 1 {
         w.write_all(b"COMMIT\x00U")?;
     } else {
         assert!(commit.parents().len() > 1 && commit.parents().len() < 0x100);
@@ -200,6 +356,10 @@ pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Resul
     
     write_meta(&mut w, commit.meta())?;
     
+    if let Some(base) = commit.squash_base() {
+        base.write_to(&mut w)?;
+    }
+    
     // Parent statesums (we wrote the number above already):
     for parent in commit.parents() {
         parent.write_to(&mut w)?;
@@ -218,21 +378,26 @@ pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Resul
             EltChange::Deletion => b"ELT DEL\x00",
             EltChange::Insertion(_) => b"ELT INS\x00",
             EltChange::Replacement(_) => b"ELT REPL",
+            EltChange::Erased(_) => b"ELT ERAS",
+            EltChange::Operation(..) => b"ELT PATC",
         };
         w.write_all(marker)?;
         w.write_u64::<BigEndian>((*elt_id).into())?;
+        if let EltChange::Erased(ref sum) = *change {
+            sum.write_to(&mut w)?;
+        }
+        if let EltChange::Operation(ref ops, ref sum) = *change {
+            w.write_all(b"ELT OPS\x00")?;
+            w.write_u64::<BigEndian>(ops.len() as u64)?;       // #0015
+            for op in ops {
+                write_data(&mut w, op)?;
+            }
+            sum.write_to(&mut w)?;
+        }
         if let Some(elt) = change.element() {
-            w.write_all(b"ELT DATA")?;
             elt_buf.clear();
             elt.write_buf(&mut &mut elt_buf)?;
-            w.write_u64::<BigEndian>(elt_buf.len() as u64)?;      // #0015
-            
-            w.write_all(&elt_buf)?;
-            let pad_len = 16 * ((elt_buf.len() + 15) / 16) - elt_buf.len();
-            if pad_len > 0 {
-                let padding = [0u8; 15];
-                w.write_all(&padding[0..pad_len])?;
-            }
+            write_data(&mut w, &elt_buf)?;
             
             elt.sum(*elt_id).write_to(&mut w)?;
         }
@@ -243,9 +408,304 @@ pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Resul
     let sum = w.sum();
     sum.write_to(&mut w.into_inner())?;
     
+    Ok(sum)
+}
+
+/// Appends pre-serialised commits to a commit log without loading the
+/// partition, e.g. for lightweight ingest tools.
+/// 
+/// Each commit is parsed (verifying checksums) and its first parent must
+/// match both the parent expected by the caller and the last commit in the
+/// log, if any. The commit's state-sum cannot be verified without the parent
+/// state; this happens when the partition is next loaded.
+/// 
+/// Commits are serialised as by `write_commit` (without file header).
+#[derive(Debug)]
+pub struct LogAppender {
+    name: String,
+    tip: Option<Sum>,
+    num_commits: usize,
+    limits: UserMetaLimits,
+}
+
+// Receiver recording only the number and last state-sum of commits
+struct LastCommit {
+    tip: Option<Sum>,
+    num: usize,
+}
+impl<E: Element> CommitReceiver<E> for LastCommit {
+    fn receive(&mut self, commit: Commit<E>) -> bool {
+        self.tip = Some(commit.statesum().clone());
+        self.num += 1;
+        true
+    }
+}
+
+impl LogAppender {
+    /// Read an existing commit log (header and all commits), keeping only the
+    /// repository name and the state-sum of the last commit.
+    /// 
+    /// Fails if the log does not use the latest file format version (since
+    /// commits would be appended in the latest format).
+    pub fn open<E: Element>(reader: &mut Read, limits: UserMetaLimits) -> Result<LogAppender> {
+        let header = read_head(reader)?;
+        if !header.ftype.is_latest() {
+            return ArgError::err("log does not use latest format version");
+        }
+        if header.compression != Compression::None {
+            return ArgError::err("cannot append to compressed log");
+        }
+        if header.cipher.is_some() {
+            return ArgError::err("cannot append to encrypted log");
+        }
+        let mut last = LastCommit { tip: None, num: 0 };
+        read_log::<E>(reader, &mut last, header.ftype.ver(), &limits, &mut EltReader::default())?;
+        Ok(LogAppender {
+            name: header.name,
+            tip: last.tip,
+            num_commits: last.num,
+            limits,
+        })
+    }
+    
+    /// Start a new commit log for repository `name`, writing the file header
+    /// and section identifier to `writer`.
+    pub fn new_log(name: &str, writer: &mut Write, limits: UserMetaLimits) -> Result<LogAppender> {
+        validate_repo_name(name)?;
+        let header = FileHeader {
+            ftype: FileType::CommitLog(0),
+            name: name.to_string(),
+            user: vec![],
+            tag: None,
+            compression: Compression::None,
+            dedup: false,
+            cipher: None,
+            archived: false,
+        };
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf)?;
+        start_log(&mut buf)?;
+        writer.write_all(&buf)?;
+        Ok(LogAppender { name: name.to_string(), tip: None, num_commits: 0, limits })
+    }
+    
+    /// Get the repository name, as found in the log header
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Get the state-sum of the last commit in the log, if any
+    pub fn tip(&self) -> Option<&Sum> {
+        self.tip.as_ref()
+    }
+    /// Get the number of commits in the log
+    pub fn num_commits(&self) -> usize {
+        self.num_commits
+    }
+    
+    /// Validate a single serialised commit and append it to `writer` (an
+    /// append-stream on the same log, e.g. from `RepoIO::append_ss_cl`), in a
+    /// single write operation. Returns the commit's state-sum.
+    /// 
+    /// Fails with `PatchOp::WrongParent` if the commit's first parent is not
+    /// `parent` or (where the log has commits) not the last commit's sum.
+    pub fn append<E: Element>(&mut self, parent: &Sum, commit: &[u8], writer: &mut Write)
+            -> Result<Sum>
+    {
+        let mut commits: Vec<Commit<E>> = Vec::new();
+        let marker: &[u8] = b"COMMIT LOG\x00\x00\x00\x00\x00\x00";
+        read_log(&mut marker.chain(commit), &mut commits,
+                HEAD_VERSIONS[HEAD_VERSIONS.len() - 1], &self.limits,
+                &mut EltReader::default())?;
+        if commits.len() != 1 {
+            return ArgError::err("expected exactly one commit");
+        }
+        let statesum = commits[0].statesum().clone();
+        if commits[0].first_parent() != parent ||
+            self.tip.as_ref().is_some_and(|tip| tip != parent)
+        {
+            return Err(Box::new(PatchOp::WrongParent));
+        }
+        writer.write_all(commit)?;
+        self.tip = Some(statesum.clone());
+        self.num_commits += 1;
+        Ok(statesum)
+    }
+}
+
+// Read `ELT DATA`, the data length and data (with padding), returning the data
+fn read_data(r: &mut Read, buf: &mut [u8], pos: &mut usize) -> Result<Vec<u8>> {
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != *b"ELT DATA" {
+        return ReadError::err("unexpected contents (expected ELT DATA)", *pos, (0, 8));
+    }
+    let data_len = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
+    *pos += 16;
+    
+    let mut data = vec![0; data_len];
+    r.read_exact(&mut data)?;
+    *pos += data_len;
+    
+    let pad_len = 16 * ((data_len + 15) / 16) - data_len;
+    if pad_len > 0 {
+        r.read_exact(&mut buf[0..pad_len])?;
+        *pos += pad_len;
+    }
+    Ok(data)
+}
+
+// Write `ELT DATA`, the data length and data (with padding)
+fn write_data(w: &mut Write, data: &[u8]) -> Result<()> {
+    w.write_all(b"ELT DATA")?;
+    w.write_u64::<BigEndian>(data.len() as u64)?;      // #0015
+    
+    w.write_all(data)?;
+    let pad_len = 16 * ((data.len() + 15) / 16) - data.len();
+    if pad_len > 0 {
+        let padding = [0u8; 15];
+        w.write_all(&padding[0..pad_len])?;
+    }
     Ok(())
 }
 
+/// Index of a commit log: the byte offset of each commit, the checksum
+/// written at the end of each and a running checksum over these.
+/// 
+/// This allows seeking to the *n*th commit without parsing the log and quick
+/// detection of truncation (see `check`). Indexes are stored alongside logs
+/// where the `RepoIO` supports this (see `RepoIO::write_ss_cl_index`).
+/// Offsets are from the start of the file (including its header).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LogIndex {
+    // Offset of the first commit, then the end offset of each commit
+    offsets: Vec<u64>,
+    // Checksum at the end of each commit
+    sums: Vec<Sum>,
+    // Running checksum over `sums`
+    running: Sum,
+}
+
+/// Result of `LogIndex::check`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogCheck {
+    /// The log matches the index exactly
+    Complete,
+    /// The log is shorter than indexed; only this many commits are complete
+    Truncated(usize),
+    /// All indexed commits are present and this many further bytes follow
+    /// (e.g. commits appended without updating the index)
+    Extended(u64),
+}
+
+impl LogIndex {
+    /// Create an empty index, where the first commit will start at `start`
+    pub fn new(start: u64) -> LogIndex {
+        LogIndex { offsets: vec![start], sums: vec![], running: Sum::zero() }
+    }
+    
+    /// Record a commit ending at offset `end` with checksum `sum` (as
+    /// returned by `write_commit`)
+    pub fn push(&mut self, end: u64, sum: Sum) {
+        let mut buf = Vec::with_capacity(2 * SUM_BYTES);
+        self.running.write_to(&mut buf).expect("writing to buf");
+        sum.write_to(&mut buf).expect("writing to buf");
+        self.running = Sum::calculate(&buf);
+        self.offsets.push(end);
+        self.sums.push(sum);
+    }
+    
+    /// Number of commits indexed
+    pub fn len(&self) -> usize {
+        self.sums.len()
+    }
+    
+    /// True if no commits are indexed
+    pub fn is_empty(&self) -> bool {
+        self.sums.is_empty()
+    }
+    
+    /// Byte offset of the start of commit `n` (counting from zero), if
+    /// indexed
+    pub fn commit_offset(&self, n: usize) -> Option<u64> {
+        if n < self.len() { Some(self.offsets[n]) } else { None }
+    }
+    
+    /// Byte offset of the end of the last commit indexed
+    pub fn end(&self) -> u64 {
+        self.offsets[self.offsets.len() - 1]
+    }
+    
+    /// Running checksum over all commits indexed (zero if empty)
+    pub fn running_sum(&self) -> &Sum {
+        &self.running
+    }
+    
+    /// Check a commit log (read from the start of the file) against this
+    /// index. This reads the log but does not parse it; the checksum at the
+    /// end of each complete commit is compared to that indexed.
+    /// 
+    /// Fails on read errors or if the log does not match the index.
+    pub fn check(&self, reader: &mut Read) -> Result<LogCheck> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let len = data.len() as u64;
+        for (i, sum) in self.sums.iter().enumerate() {
+            let end = self.offsets[i + 1];
+            if end > len {
+                return Ok(LogCheck::Truncated(i));
+            }
+            let pos = match (end as usize).checked_sub(SUM_BYTES) {
+                Some(pos) => pos,
+                None => return ReadError::err("commit log does not match index", 0, (0, end as usize)),
+            };
+            if *sum != data[pos..end as usize] {
+                return ReadError::err("commit log does not match index", pos, (0, SUM_BYTES));
+            }
+        }
+        Ok(if len > self.end() { LogCheck::Extended(len - self.end()) } else { LogCheck::Complete })
+    }
+    
+    /// Write the index to a stream
+    pub fn write_to(&self, writer: &mut Write) -> Result<()> {
+        writer.write_all(b"PIPPIN LOG INDEX")?;
+        writer.write_u64::<BigEndian>(self.len() as u64)?;
+        writer.write_u64::<BigEndian>(self.offsets[0])?;
+        for (end, sum) in self.offsets[1..].iter().zip(&self.sums) {
+            writer.write_u64::<BigEndian>(*end)?;
+            sum.write_to(writer)?;
+        }
+        self.running.write_to(writer)?;
+        Ok(())
+    }
+    
+    /// Read an index from a stream, verifying its running checksum
+    pub fn read_from(reader: &mut Read) -> Result<LogIndex> {
+        let mut buf = [0u8; SUM_BYTES];
+        reader.read_exact(&mut buf[0..16])?;
+        if buf[0..16] != *b"PIPPIN LOG INDEX" {
+            return ReadError::err("unexpected contents (expected PIPPIN LOG INDEX)", 0, (0, 16));
+        }
+        reader.read_exact(&mut buf[0..16])?;
+        let n = BigEndian::read_u64(&buf[0..8]) as usize;
+        let mut index = LogIndex::new(BigEndian::read_u64(&buf[8..16]));
+        let mut pos = 32;
+        for _ in 0..n {
+            reader.read_exact(&mut buf[0..8])?;
+            let end = BigEndian::read_u64(&buf[0..8]);
+            if end < index.end() {
+                return ReadError::err("log index offsets not increasing", pos, (0, 8));
+            }
+            reader.read_exact(&mut buf)?;
+            index.push(end, Sum::load(&buf));
+            pos += 8 + SUM_BYTES;
+        }
+        reader.read_exact(&mut buf)?;
+        if index.running != buf[..] {
+            return ReadError::err("log index checksum invalid", pos, (0, SUM_BYTES));
+        }
+        Ok(index)
+    }
+}
+
 #[test]
 fn commit_write_read(){
     use rw::HEAD_VERSIONS;
@@ -284,7 +744,8 @@ fn commit_write_read(){
     assert!(write_commit(&commit_2, &mut obj).is_ok());
     
     let mut commits = Vec::new();
-    match read_log(&mut &obj[..], &mut commits, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]) {
+    match read_log(&mut &obj[..], &mut commits, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1],
+            &UserMetaLimits::default(), &mut EltReader::default()) {
         Ok(()) => {},
         Err(e) => {
 //             // specialisation for a ReadError:
@@ -297,3 +758,84 @@ fn commit_write_read(){
     assert_eq!(commits[0], commit_1);
     assert_eq!(commits[1], commit_2);
 }
+
+#[test]
+fn user_meta_limits() {
+    use rw::HEAD_VERSIONS;
+    use elt::EltId;
+    use commit::{CommitMeta, UserMeta, MetaFlags, InvalidText};
+    
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    let mut changes = HashMap::new();
+    changes.insert(EltId::from(1), EltChange::insertion(Rc::new("one".to_string())));
+    let data = b"\xff\xfe binary".to_vec();
+    let meta = CommitMeta::new_explicit(1, 123456, MetaFlags::zero(), vec![],
+            UserMeta::Bytes(data.clone())).expect("new meta");
+    let commit = Commit::new_explicit(Sum::zero(), vec![Sum::zero()], changes, meta);
+    
+    let mut obj = Vec::new();
+    start_log(&mut obj).expect("start_log");
+    write_commit(&commit, &mut obj).expect("write_commit");
+    
+    let mut limits = UserMetaLimits::default();
+    let mut commits = Vec::new();
+    read_log(&mut &obj[..], &mut commits, ver, &limits, &mut EltReader::default()).expect("read_log");
+    assert_eq!(commits[0], commit);
+    
+    // Same data, marked as text (updating the commit's checksum to match):
+    let pos = obj.windows(4).position(|w| w == b"XMBB").expect("XMBB");
+    obj[pos + 2..pos + 4].copy_from_slice(b"TT");
+    let len = obj.len();
+    let sum = Sum::calculate(&obj[16..len - SUM_BYTES]);
+    sum.write_to(&mut &mut obj[len - SUM_BYTES..]).expect("write sum");
+    commits.clear();
+    assert!(read_log(&mut &obj[..], &mut commits, ver, &limits, &mut EltReader::default()).is_err());
+    limits.invalid_text = InvalidText::Bytes;
+    commits.clear();
+    read_log(&mut &obj[..], &mut commits, ver, &limits, &mut EltReader::default()).expect("read_log");
+    assert_eq!(*commits[0].meta().extra(), UserMeta::Bytes(data.clone()));
+    assert_eq!(commits[0].meta().extra().text_lossy(), "\u{FFFD}\u{FFFD} binary");
+    
+    limits.max_len = data.len() as u32 - 1;
+    commits.clear();
+    assert!(read_log(&mut &obj[..], &mut commits, ver, &limits, &mut EltReader::default()).is_err());
+}
+
+#[test]
+fn log_index() {
+    use elt::EltId;
+    use commit::{CommitMeta, UserMeta, MetaFlags};
+    
+    let mut obj = Vec::new();
+    start_log(&mut obj).expect("start_log");
+    let mut index = LogIndex::new(obj.len() as u64);
+    for i in 0..3 {
+        let mut changes = HashMap::new();
+        changes.insert(EltId::from(i), EltChange::insertion(Rc::new(format!("elt {}", i))));
+        let meta = CommitMeta::new_explicit(1, 123456, MetaFlags::zero(), vec![],
+                UserMeta::None).expect("new meta");
+        let commit = Commit::new_explicit(Sum::zero(), vec![Sum::zero()], changes, meta);
+        let sum = write_commit(&commit, &mut obj).expect("write_commit");
+        index.push(obj.len() as u64, sum);
+    }
+    assert_eq!(index.len(), 3);
+    assert_eq!(index.commit_offset(0), Some(16));
+    assert_eq!(index.commit_offset(3), None);
+    assert_eq!(index.end(), obj.len() as u64);
+    
+    let mut buf = Vec::new();
+    index.write_to(&mut buf).expect("write index");
+    let index2 = LogIndex::read_from(&mut &buf[..]).expect("read index");
+    assert_eq!(index2, index);
+    buf[40] ^= 1;
+    assert!(LogIndex::read_from(&mut &buf[..]).is_err());
+    
+    assert_eq!(index.check(&mut &obj[..]).expect("check"), LogCheck::Complete);
+    let end1 = index.commit_offset(2).expect("offset") as usize;
+    assert_eq!(index.check(&mut &obj[..end1 + 5]).expect("check"), LogCheck::Truncated(2));
+    let mut longer = obj.clone();
+    longer.extend_from_slice(&[0; 7]);
+    assert_eq!(index.check(&mut &longer[..]).expect("check"), LogCheck::Extended(7));
+    obj[end1 - 1] ^= 1;
+    assert!(index.check(&mut &obj[..]).is_err());
+}
@@ -0,0 +1,255 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Compatibility test vectors
+//! 
+//! Small canonical snapshot and commit log files for each supported file
+//! format version (see `HEAD_VERSIONS`), embedded in the library such that
+//! changes to reading code cannot silently break existing files. The same
+//! files are found in the `data/compat` directory of the source
+//! distribution, for use by other implementations.
+//! 
+//! Each vector holds the same history of a partition named `compat` with
+//! `String` elements. The snapshot holds a state with elements 1 = "one",
+//! 2 = "two" and 3 = "three". The log holds two commits: the first replaces
+//! element 1 with "ONE", removes element 2 and inserts 4 = "four"; the second
+//! inserts 5 = "five". Since neither the type of user metadata nor erasure of
+//! elements affects state-sums, the expected sums are the same for all
+//! versions.
+//! 
+//! Vectors use the features of their version: `CNUM` commit metadata and
+//! text user metadata (up to 2016 05 16), `F` commit metadata (since
+//! 2016 08 15), per-element metadata and binary user metadata (since
+//! 2026 10 17), erased elements (since 2026 10 18, where element 3 is erased)
+//! operations (since 2026 10 19, where element 1 is replaced via a text
+//! operation; see `ApplyOp for String`), an element index (since 2026 10 20,
+//! although writers only add an index to larger snapshots), squashed
+//! commits (since 2026 10 21, where the second commit is squashed onto the
+//! state of the first), provenance (since 2026 10 22, on the first commit)
+//! commit summaries (since 2026 10 23) and an author (since 2026 10 24, on
+//! the first commit).
+
+use commit::UserMetaLimits;
+use error::{Result, RepoError};
+use rw::EltReader;
+use rw::commitlog::read_log;
+use rw::header::{FileType, read_head};
+use rw::snapshot::{read_snapshot, read_index_footer, read_index, read_element_head,
+        INDEX_FOOTER_BYTES, ELEMENT_HEAD_BYTES};
+use state::{PartState, StateRead};
+use sum::Sum;
+
+/// A test vector: a snapshot and a commit log of one file format version
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TestVector {
+    /// File format version (see `HEAD_VERSIONS`)
+    pub version: u32,
+    /// Contents of the snapshot file
+    pub snapshot: &'static [u8],
+    /// Contents of the commit log file
+    pub log: &'static [u8],
+}
+
+/// Expected state-sum of the snapshot's state
+pub const SNAPSHOT_STATESUM: &str =
+        "6A8E5D402226F993728B774E2ADB002F2AC61C48271C3BC8F8B75D9CD82F424F";
+
+/// Expected state-sums of the states reached by applying each commit of the
+/// log in order, starting from the snapshot's state
+pub const LOG_STATESUMS: [&str; 2] = [
+    "8969AE1A70EC6576F929E5B33F080E5DBBC6648C41CF0150E3C6FFD9BE31B1E8",
+    "6909ACC3D743E19C61440BF518F1078F38715DA640540BE73E9EBB684906DF82",
+];
+
+/// Test vectors for all supported versions, oldest first
+pub const VECTORS: [TestVector; 11] = [
+    TestVector {
+        version: 2016_03_10,
+        snapshot: include_bytes!("../../data/compat/v20160310.pip"),
+        log: include_bytes!("../../data/compat/v20160310.piplog"),
+    },
+    TestVector {
+        version: 2016_05_16,
+        snapshot: include_bytes!("../../data/compat/v20160516.pip"),
+        log: include_bytes!("../../data/compat/v20160516.piplog"),
+    },
+    TestVector {
+        version: 2016_08_15,
+        snapshot: include_bytes!("../../data/compat/v20160815.pip"),
+        log: include_bytes!("../../data/compat/v20160815.piplog"),
+    },
+    TestVector {
+        version: 2026_10_17,
+        snapshot: include_bytes!("../../data/compat/v20261017.pip"),
+        log: include_bytes!("../../data/compat/v20261017.piplog"),
+    },
+    TestVector {
+        version: 2026_10_18,
+        snapshot: include_bytes!("../../data/compat/v20261018.pip"),
+        log: include_bytes!("../../data/compat/v20261018.piplog"),
+    },
+    TestVector {
+        version: 2026_10_19,
+        snapshot: include_bytes!("../../data/compat/v20261019.pip"),
+        log: include_bytes!("../../data/compat/v20261019.piplog"),
+    },
+    TestVector {
+        version: 2026_10_20,
+        snapshot: include_bytes!("../../data/compat/v20261020.pip"),
+        log: include_bytes!("../../data/compat/v20261020.piplog"),
+    },
+    TestVector {
+        version: 2026_10_21,
+        snapshot: include_bytes!("../../data/compat/v20261021.pip"),
+        log: include_bytes!("../../data/compat/v20261021.piplog"),
+    },
+    TestVector {
+        version: 2026_10_22,
+        snapshot: include_bytes!("../../data/compat/v20261022.pip"),
+        log: include_bytes!("../../data/compat/v20261022.piplog"),
+    },
+    TestVector {
+        version: 2026_10_23,
+        snapshot: include_bytes!("../../data/compat/v20261023.pip"),
+        log: include_bytes!("../../data/compat/v20261023.piplog"),
+    },
+    TestVector {
+        version: 2026_10_24,
+        snapshot: include_bytes!("../../data/compat/v20261024.pip"),
+        log: include_bytes!("../../data/compat/v20261024.piplog"),
+    },
+];
+
+impl TestVector {
+    /// Read the snapshot and apply each commit of the log, checking the
+    /// version of each file, all checksums (including that of any element
+    /// index) and that state-sums match `SNAPSHOT_STATESUM` and
+    /// `LOG_STATESUMS`.
+    /// 
+    /// On success, returns the states read (that of the snapshot followed by
+    /// that of each commit).
+    pub fn verify(&self) -> Result<Vec<PartState<String>>> {
+        let limits = UserMetaLimits::default();
+        
+        let mut r = self.snapshot;
+        let head = read_head(&mut r)?;
+        match head.ftype {
+            FileType::Snapshot(v) if v == self.version => {},
+            _ => return RepoError::err(RepoError::TestVectorMismatch("snapshot: unexpected file type or version")),
+        }
+        let body = r;
+        let state: PartState<String> = read_snapshot(&mut r, self.version, head.dedup, &limits,
+                &mut EltReader::default())?;
+        if *state.statesum() != Sum::from_hex(SNAPSHOT_STATESUM)? {
+            return RepoError::err(RepoError::TestVectorMismatch("snapshot: unexpected state-sum"));
+        }
+        // versions from 20261020 may have an element index (see HEAD_VERSIONS)
+        if !r.is_empty() {
+            if self.version < 2026_10_20 {
+                return RepoError::err(RepoError::TrailingData);
+            }
+            let n = r.len();
+            match read_index_footer(&r[n.saturating_sub(INDEX_FOOTER_BYTES)..]) {
+                Some((pos, len)) if pos as usize == body.len() - n && len as usize == body.len() => {},
+                _ => return RepoError::err(RepoError::TestVectorMismatch("snapshot: invalid element index")),
+            }
+            for (id, pos) in read_index(&r[..n - INDEX_FOOTER_BYTES])? {
+                let pos = pos as usize;
+                if pos + ELEMENT_HEAD_BYTES > body.len() || !state.is_avail(id) {
+                    return RepoError::err(RepoError::TestVectorMismatch("snapshot: invalid element index"));
+                }
+                read_element_head(id, &body[pos..pos + ELEMENT_HEAD_BYTES])?;
+            }
+        }
+        
+        let mut r = self.log;
+        let head = read_head(&mut r)?;
+        match head.ftype {
+            FileType::CommitLog(v) if v == self.version => {},
+            _ => return RepoError::err(RepoError::TestVectorMismatch("log: unexpected file type or version")),
+        }
+        let mut commits = Vec::new();
+        read_log(&mut r, &mut commits, self.version, &limits, &mut EltReader::default())?;
+        if commits.len() != LOG_STATESUMS.len() {
+            return RepoError::err(RepoError::TestVectorMismatch("log: unexpected number of commits"));
+        }
+        
+        let mut states = vec![state];
+        for (commit, expected) in commits.iter().zip(LOG_STATESUMS.iter()) {
+            let state = PartState::from_state_commit(states.last().unwrap(), commit)?;
+            if *state.statesum() != Sum::from_hex(expected)? {
+                return RepoError::err(RepoError::TestVectorMismatch("log: unexpected state-sum"));
+            }
+            states.push(state);
+        }
+        Ok(states)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commit::Commit;
+    use elt::EltId;
+    use rw::HEAD_VERSIONS;
+    use state::StateRead;
+    
+    fn contains(data: &[u8], pat: &[u8]) -> bool {
+        data.windows(pat.len()).any(|w| w == pat)
+    }
+    
+    #[test]
+    fn vectors() {
+        let versions: Vec<u32> = VECTORS.iter().map(|v| v.version).collect();
+        assert_eq!(versions, HEAD_VERSIONS);
+        
+        for vector in &VECTORS {
+            let states = vector.verify().unwrap_or_else(|e|
+                    panic!("version {}: {}", vector.version, e));
+            let get = |i: usize, id: u64| states[i].get(EltId::from(id)).ok().cloned();
+            assert_eq!(get(0, 2), Some("two".to_string()));
+            assert_eq!(states[0].is_avail(EltId::from(3)), vector.version < 2026_10_18);
+            assert_eq!(get(1, 1), Some("ONE".to_string()));
+            assert_eq!(get(1, 2), None);
+            assert_eq!(get(2, 5), Some("five".to_string()));
+            assert_eq!(contains(vector.log, b"ELT PATC"), vector.version >= 2026_10_19);
+            assert_eq!(contains(vector.snapshot, b"ELTINDEX"), vector.version >= 2026_10_20);
+            assert_eq!(contains(vector.log, b"SQUASH"), vector.version >= 2026_10_21);
+            assert_eq!(states[1].meta().provenance().len(), (vector.version >= 2026_10_22) as usize);
+            assert_eq!(states[2].meta().summary().map(|s| (s.elements, s.byte_delta)),
+                    if vector.version >= 2026_10_23 { Some((3, 4)) } else { None });
+            assert_eq!(states[1].meta().author(),
+                    if vector.version >= 2026_10_24 { Some("compat") } else { None });
+        }
+        
+        // Features are not read from files of versions before their own:
+        let old_log = |version: u32, since: u32| -> Result<Vec<Commit<String>>> {
+            let vector = VECTORS.iter().find(|v| v.version == since).expect("has vector");
+            let mut r = vector.log;
+            read_head(&mut r)?;
+            let mut commits = Vec::new();
+            read_log(&mut r, &mut commits, version, &UserMetaLimits::default(),
+                    &mut EltReader::default())?;
+            Ok(commits)
+        };
+        assert!(old_log(2026_10_19, 2026_10_19).is_ok());
+        assert!(old_log(2026_10_18, 2026_10_19).is_err());
+        assert!(old_log(2026_10_20, 2026_10_21).is_err());
+        // Inessential extensions are ignored:
+        let commits = old_log(2026_10_21, 2026_10_22).expect("reading log");
+        assert!(commits[0].meta().provenance().is_empty());
+        let commits = old_log(2026_10_22, 2026_10_23).expect("reading log");
+        assert!(commits[0].meta().provenance().len() == 1 && commits[0].meta().summary().is_none());
+        let commits = old_log(2026_10_23, 2026_10_24).expect("reading log");
+        assert!(commits[0].meta().summary().is_some() && commits[0].meta().author().is_none());
+        
+        // Corruption is detected:
+        let mut snapshot = VECTORS[0].snapshot.to_vec();
+        let n = snapshot.len() - 40;
+        snapshot[n] ^= 1;
+        let snapshot: &'static [u8] = Box::leak(snapshot.into_boxed_slice());
+        assert!(TestVector { snapshot, ..VECTORS[0] }.verify().is_err());
+    }
+}
@@ -0,0 +1,196 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Compression of file contents following the header
+//! 
+//! The header records the method used (see `FileHeader::compression`); the
+//! remainder of the file (the snapshot or commit log, including checksums)
+//! is compressed as one stream. Reading and writing are streamed.
+
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "zlib")]
+use flate2;
+#[cfg(feature = "zstd")]
+use zstd;
+
+use error::{Result, RepoError};
+
+/// Compression method for the contents of snapshot and commit log files
+/// (see `Control::compression`).
+/// 
+/// Methods other than `None` require the corresponding library feature
+/// (`zlib` or `zstd`) both to write and to read files.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Compression {
+    /// Not compressed
+    None,
+    /// zlib (deflate) compression
+    Zlib,
+    /// Zstandard compression
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    /// Name as recorded in file headers (`None` is not recorded)
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zlib => "zlib",
+            Compression::Zstd => "zstd",
+        }
+    }
+    
+    /// Get the method with this name, if known
+    pub fn from_name(name: &[u8]) -> Option<Compression> {
+        match name {
+            b"none" => Some(Compression::None),
+            b"zlib" => Some(Compression::Zlib),
+            b"zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+    
+    /// True if this method is supported by this build of the library
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Zlib => cfg!(feature = "zlib"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+}
+
+/// Wrap `reader` (positioned after the file header) to decompress contents.
+/// 
+/// Fails if the method is not supported.
+pub fn decompress<'a, R: Read + 'a>(reader: R, method: Compression) -> Result<Box<Read + 'a>> {
+    Ok(match method {
+        Compression::None => Box::new(reader),
+        #[cfg(feature = "zlib")]
+        Compression::Zlib => Box::new(flate2::read::ZlibDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        #[allow(unreachable_patterns)]
+        _ => return RepoError::err(RepoError::CompressionUnsupported { method: method.name() }),
+    })
+}
+
+/// Compress a whole file held in memory, leaving the first `head_len` bytes
+/// (the header) as they are. Data is returned unchanged if `method` is `None`.
+pub fn compress_file(data: Vec<u8>, head_len: usize, method: Compression) -> Result<Vec<u8>> {
+    if method == Compression::None {
+        return Ok(data);
+    }
+    let mut buf = Vec::with_capacity(data.len());
+    buf.extend_from_slice(&data[..head_len]);
+    {
+        let mut w = CompressWriter::new(&mut buf, method)?;
+        w.write_all(&data[head_len..])?;
+        w.finish()?;
+    }
+    Ok(buf)
+}
+
+/// Writer compressing file contents (following the header). `finish` must
+/// be called to complete the stream.
+pub struct CompressWriter<'a> {
+    inner: Inner<'a>,
+}
+
+enum Inner<'a> {
+    None(&'a mut Write),
+    #[cfg(feature = "zlib")]
+    Zlib(flate2::write::ZlibEncoder<&'a mut Write>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, &'a mut Write>),
+}
+
+impl<'a> CompressWriter<'a> {
+    /// Create, writing to `writer` (positioned after the file header).
+    /// 
+    /// Fails if the method is not supported.
+    pub fn new(writer: &'a mut Write, method: Compression) -> Result<CompressWriter<'a>> {
+        let inner = match method {
+            Compression::None => Inner::None(writer),
+            #[cfg(feature = "zlib")]
+            Compression::Zlib => Inner::Zlib(flate2::write::ZlibEncoder::new(writer,
+                    flate2::Compression::default())),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Inner::Zstd(zstd::Encoder::new(writer, 0)?),
+            #[allow(unreachable_patterns)]
+            _ => return RepoError::err(RepoError::CompressionUnsupported { method: method.name() }),
+        };
+        Ok(CompressWriter { inner })
+    }
+    
+    /// Complete the compressed stream and flush
+    pub fn finish(self) -> Result<()> {
+        match self.inner {
+            Inner::None(w) => w.flush()?,
+            #[cfg(feature = "zlib")]
+            Inner::Zlib(w) => w.finish()?.flush()?,
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(w) => w.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Write for CompressWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner {
+            Inner::None(ref mut w) => w.write(buf),
+            #[cfg(feature = "zlib")]
+            Inner::Zlib(ref mut w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(ref mut w) => w.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner {
+            Inner::None(ref mut w) => w.flush(),
+            #[cfg(feature = "zlib")]
+            Inner::Zlib(ref mut w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(ref mut w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, CompressWriter, decompress};
+    use std::io::{Read, Write};
+    
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 13) as u8).collect();
+        for &method in &[Compression::None, Compression::Zlib, Compression::Zstd] {
+            let mut buf = Vec::new();
+            match CompressWriter::new(&mut buf, method) {
+                Ok(mut w) => {
+                    w.write_all(&data).unwrap();
+                    w.finish().unwrap();
+                },
+                Err(_) => {
+                    assert!(!method.is_supported());
+                    continue;
+                },
+            }
+            if method != Compression::None {
+                assert!(buf.len() < data.len() / 10);
+            }
+            let mut result = Vec::new();
+            decompress(&buf[..], method).unwrap().read_to_end(&mut result).unwrap();
+            assert_eq!(result, data);
+        }
+    }
+}
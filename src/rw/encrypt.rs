@@ -0,0 +1,479 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Encryption of file contents following the header
+//! 
+//! The header records the scheme and key identifier used and a random
+//! identifier of the file (see `FileHeader::cipher`) but is itself not
+//! encrypted. The remainder of the file (after any compression) is split
+//! into chunks of at most `CHUNK_BYTES`, each sealed separately by a `Cipher`
+//! and stored as a 32-bit big-endian length followed by the sealed bytes.
+//! The file's type, format version, partition name, key identifier and file
+//! identifier, the position of each chunk and whether it is the last are
+//! authenticated, thus reordered, removed or truncated chunks are detected,
+//! as are chunks moved from another file or an older copy of the same file.
+
+use std::cmp::min;
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use byteorder::{ByteOrder, BigEndian};
+use rand::{Rng, thread_rng};
+
+use error::{Result, ArgError, RepoError};
+use rw::LATEST_VERSION;
+use rw::header::{FileHeader, FileType};
+
+#[cfg(feature = "aes-gcm")]
+pub use self::aes::AesGcmCipher;
+
+/// Maximum length of a chunk of plain data
+pub const CHUNK_BYTES: usize = 1 << 16;
+// Maximum overhead (e.g. nonce and tag) added by a cipher to a chunk
+const MAX_OVERHEAD: usize = 1024;
+// Prefix of data authenticated with each chunk
+const AAD_PREFIX: [u8; 8] = *b"PIPPINEC";
+
+/// Authenticated encryption of file contents (see `Control::cipher`).
+/// 
+/// Note that encrypted files are not reproducible (see
+/// `Control::reproducible_snapshots`) where the cipher uses random nonces.
+pub trait Cipher: Debug {
+    /// Name of the scheme, recorded in file headers (ASCII, 1 to 11 bytes)
+    fn scheme(&self) -> &str;
+    
+    /// Identifier of the key used to encrypt new files, recorded in file
+    /// headers (allowing keys to be rotated)
+    fn key_id(&self) -> u64;
+    
+    /// Encrypt `data` using the current key, also authenticating `aad`.
+    /// The result must include anything needed to decrypt (e.g. a nonce)
+    /// other than the key.
+    fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>>;
+    
+    /// Decrypt `sealed`, encrypted with key `key_id`. Must fail if `sealed`
+    /// or `aad` do not match what was sealed.
+    fn open(&self, key_id: u64, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Encryption scheme and key recorded in a file header
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CipherInfo {
+    /// Name of the scheme (see `Cipher::scheme`)
+    pub scheme: String,
+    /// Identifier of the key (see `Cipher::key_id`)
+    pub key_id: u64,
+    /// Random identifier of the file, authenticated with each chunk
+    pub file_id: [u8; 16],
+}
+
+impl CipherInfo {
+    /// Get the scheme and current key of `cipher`, with a new random file
+    /// identifier
+    pub fn of(cipher: &Cipher) -> CipherInfo {
+        let mut file_id = [0u8; 16];
+        thread_rng().fill_bytes(&mut file_id);
+        CipherInfo { scheme: cipher.scheme().to_string(), key_id: cipher.key_id(), file_id }
+    }
+}
+
+// Data authenticated with every chunk of a file with `header`, written in
+// format version `ver`. Chunk position is appended by `chunk_aad`.
+fn file_aad(header: &FileHeader, ver: u32) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(AAD_BYTES);
+    aad.extend_from_slice(&AAD_PREFIX);
+    let mut buf = [0u8; 8];
+    BigEndian::write_u32(&mut buf[0..4], ver);
+    aad.extend_from_slice(&buf[0..4]);
+    aad.push(match header.ftype {
+        FileType::Snapshot(_) => b'S',
+        FileType::CommitLog(_) => b'L',
+    });
+    let name = header.name.as_bytes();
+    aad.extend_from_slice(&name[..min(name.len(), 16)]);
+    aad.resize(8 + 4 + 1 + 16, 0);
+    if let Some(ref info) = header.cipher {
+        BigEndian::write_u64(&mut buf, info.key_id);
+        aad.extend_from_slice(&buf);
+        aad.extend_from_slice(&info.file_id);
+    } else {
+        aad.resize(8 + 4 + 1 + 16 + 8 + 16, 0);
+    }
+    aad
+}
+// Length of `file_aad` plus chunk position
+const AAD_BYTES: usize = 8 + 4 + 1 + 16 + 8 + 16 + 8 + 1;
+
+// Data authenticated with chunk `index` of a file (see `file_aad`)
+fn chunk_aad(file_aad: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(AAD_BYTES);
+    aad.extend_from_slice(file_aad);
+    let mut buf = [0u8; 8];
+    BigEndian::write_u64(&mut buf, index);
+    aad.extend_from_slice(&buf);
+    aad.push(last as u8);
+    aad
+}
+
+/// Wrap `reader` (positioned after the file header) to decrypt contents,
+/// if `header.cipher` is not `None`.
+/// 
+/// Fails if the file is encrypted and `cipher` is `None` or of a different
+/// scheme. Errors found while reading (e.g. failed authentication) are
+/// reported by the reader.
+pub fn decrypt<'a, R: Read + 'a>(reader: R, header: &FileHeader,
+        cipher: Option<Rc<Cipher>>) -> Result<Box<Read + 'a>>
+{
+    let info = match header.cipher {
+        None => return Ok(Box::new(reader)),
+        Some(ref info) => info,
+    };
+    match cipher {
+        Some(ref c) if c.scheme() == info.scheme => {},
+        Some(_) => return RepoError::err(RepoError::CipherMismatch { scheme: info.scheme.clone() }),
+        None => return RepoError::err(RepoError::NoCipher),
+    }
+    Ok(Box::new(DecryptReader {
+        inner: reader,
+        cipher: cipher.unwrap(),
+        key_id: info.key_id,
+        aad: file_aad(header, header.ftype.ver()),
+        index: 0,
+        buf: Vec::new(),
+        pos: 0,
+        done: false,
+    }))
+}
+
+/// Encrypt a whole file held in memory, leaving the first `head_len` bytes
+/// (the header, written from `header`) as they are. Data is returned
+/// unchanged if `cipher` is `None`.
+pub fn encrypt_file(data: Vec<u8>, head_len: usize, header: &FileHeader,
+        cipher: Option<Rc<Cipher>>) -> Result<Vec<u8>>
+{
+    if cipher.is_none() {
+        return Ok(data);
+    }
+    let mut buf = Vec::with_capacity(data.len() + data.len() / CHUNK_BYTES * 64 + 64);
+    buf.extend_from_slice(&data[..head_len]);
+    {
+        let mut w = EncryptWriter::new(&mut buf, header, cipher);
+        w.write_all(&data[head_len..])?;
+        w.finish()?;
+    }
+    Ok(buf)
+}
+
+/// Writer encrypting file contents (following the header), if a cipher is
+/// given. `finish` must be called to complete the file.
+pub struct EncryptWriter<'a> {
+    inner: &'a mut Write,
+    cipher: Option<Rc<Cipher>>,
+    aad: Vec<u8>,
+    index: u64,
+    buf: Vec<u8>,
+}
+
+impl<'a> EncryptWriter<'a> {
+    /// Create, writing to `writer` (positioned after the file header, which
+    /// was written from `header`; this should have `cipher` set by
+    /// `CipherInfo::of` if `cipher` is given).
+    pub fn new(writer: &'a mut Write, header: &FileHeader, cipher: Option<Rc<Cipher>>)
+            -> EncryptWriter<'a>
+    {
+        // Headers are always written in the latest version:
+        let aad = file_aad(header, LATEST_VERSION);
+        EncryptWriter { inner: writer, cipher, aad, index: 0, buf: Vec::new() }
+    }
+    
+    /// Write the last chunk and flush
+    pub fn finish(mut self) -> Result<()> {
+        if self.cipher.is_some() {
+            self.write_chunk(true)?;
+        }
+        self.inner.flush()?;
+        Ok(())
+    }
+    
+    fn write_chunk(&mut self, last: bool) -> Result<()> {
+        let sealed = self.cipher.as_ref().expect("has cipher")
+                .seal(&chunk_aad(&self.aad, self.index, last), &self.buf)?;
+        if sealed.len() > CHUNK_BYTES + MAX_OVERHEAD {
+            return ArgError::err("cipher overhead too large");
+        }
+        let mut len = [0u8; 4];
+        BigEndian::write_u32(&mut len, sealed.len() as u32);
+        self.inner.write_all(&len)?;
+        self.inner.write_all(&sealed)?;
+        self.index += 1;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<'a> Write for EncryptWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cipher.is_none() {
+            return self.inner.write(buf);
+        }
+        if self.buf.len() == CHUNK_BYTES {
+            self.write_chunk(false).map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        let n = min(buf.len(), CHUNK_BYTES - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        // Incomplete chunks are held until `finish`
+        self.inner.flush()
+    }
+}
+
+// Reader decrypting chunks
+struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: Rc<Cipher>,
+    key_id: u64,
+    aad: Vec<u8>,
+    index: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    // Read and decrypt the next chunk into `buf`
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("encrypted file truncated"),
+            _ => e,
+        })?;
+        let len = BigEndian::read_u32(&len) as usize;
+        if len > CHUNK_BYTES + MAX_OVERHEAD {
+            return Err(invalid("encrypted chunk too long"));
+        }
+        let mut sealed = vec![0; len];
+        self.inner.read_exact(&mut sealed)?;
+        // Only the last chunk may be shorter than CHUNK_BYTES; try it first
+        // if the chunk is short:
+        let aad = chunk_aad(&self.aad, self.index, false);
+        let full = self.cipher.open(self.key_id, &aad, &sealed);
+        self.buf = match full {
+            Ok(data) if data.len() == CHUNK_BYTES => data,
+            _ => {
+                let aad = chunk_aad(&self.aad, self.index, true);
+                let data = self.cipher.open(self.key_id, &aad, &sealed)
+                        .map_err(|_| invalid("decryption failed (wrong key or corrupt data)"))?;
+                self.done = true;
+                data
+            },
+        };
+        self.pos = 0;
+        self.index += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = min(buf.len(), self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "aes-gcm")]
+mod aes {
+    use std::collections::HashMap;
+    use std::fmt;
+    
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+    use rand::{Rng, thread_rng};
+    
+    use error::{Result, ArgError, RepoError};
+    use super::Cipher;
+    
+    /// AES-256 in GCM mode with random 96-bit nonces. Only available with
+    /// the `aes-gcm` feature.
+    /// 
+    /// Several keys may be held to read files written with older keys; new
+    /// files use the current key.
+    pub struct AesGcmCipher {
+        keys: HashMap<u64, Aes256Gcm>,
+        current: u64,
+    }
+    
+    impl AesGcmCipher {
+        /// Create, with the current key and its identifier
+        pub fn new(key_id: u64, key: &[u8; 32]) -> AesGcmCipher {
+            let mut keys = HashMap::new();
+            keys.insert(key_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)));
+            AesGcmCipher { keys, current: key_id }
+        }
+        
+        /// Add a key usable to read old files
+        pub fn add_key(&mut self, key_id: u64, key: &[u8; 32]) {
+            self.keys.insert(key_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)));
+        }
+    }
+    
+    impl fmt::Debug for AesGcmCipher {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            // keys are deliberately not shown
+            write!(f, "AesGcmCipher {{ current: {}, keys: {} }}", self.current, self.keys.len())
+        }
+    }
+    
+    impl Cipher for AesGcmCipher {
+        fn scheme(&self) -> &str {
+            "aes256gcm"
+        }
+        fn key_id(&self) -> u64 {
+            self.current
+        }
+        fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+            let mut nonce = [0u8; 12];
+            thread_rng().fill_bytes(&mut nonce);
+            let cipher = &self.keys[&self.current];
+            let sealed = match cipher.encrypt(Nonce::from_slice(&nonce),
+                    Payload { msg: data, aad }) {
+                Ok(sealed) => sealed,
+                Err(_) => return RepoError::err(RepoError::EncryptionFailed),
+            };
+            let mut result = Vec::with_capacity(nonce.len() + sealed.len());
+            result.extend_from_slice(&nonce);
+            result.extend_from_slice(&sealed);
+            Ok(result)
+        }
+        fn open(&self, key_id: u64, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+            let cipher = match self.keys.get(&key_id) {
+                Some(cipher) => cipher,
+                None => return ArgError::err("unknown encryption key"),
+            };
+            if sealed.len() < 12 {
+                return RepoError::err(RepoError::DecryptionFailed);
+            }
+            match cipher.decrypt(Nonce::from_slice(&sealed[..12]),
+                    Payload { msg: &sealed[12..], aad }) {
+                Ok(data) => Ok(data),
+                Err(_) => RepoError::err(RepoError::DecryptionFailed),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rw::compress::Compression;
+    
+    // Not secure! XOR with a key byte, appending a checksum of data and aad
+    #[derive(Debug)]
+    struct XorCipher(u8);
+    impl Cipher for XorCipher {
+        fn scheme(&self) -> &str { "xor" }
+        fn key_id(&self) -> u64 { self.0 as u64 }
+        fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+            let check = aad.iter().chain(data).fold(0u8, |a, b| a.wrapping_mul(31) ^ b);
+            Ok(data.iter().map(|b| b ^ self.0).chain(Some(check)).collect())
+        }
+        fn open(&self, key_id: u64, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+            let key = key_id as u8;
+            let (check, data) = sealed.split_last().expect("has check");
+            let data: Vec<u8> = data.iter().map(|b| b ^ key).collect();
+            if aad.iter().chain(&data).fold(0u8, |a, b| a.wrapping_mul(31) ^ b) != *check {
+                return RepoError::err(RepoError::DecryptionFailed);
+            }
+            Ok(data)
+        }
+    }
+    
+    fn header(name: &str, cipher: &Cipher) -> FileHeader {
+        FileHeader {
+            ftype: FileType::CommitLog(LATEST_VERSION),
+            name: name.to_string(),
+            user: vec![],
+            tag: None,
+            compression: Compression::None,
+            dedup: false,
+            cipher: Some(CipherInfo::of(cipher)),
+            archived: false,
+        }
+    }
+    
+    fn read_all(data: &[u8], header: &FileHeader, cipher: &Rc<Cipher>) -> Result<Vec<u8>> {
+        let mut result = Vec::new();
+        decrypt(data, header, Some(cipher.clone()))?.read_to_end(&mut result)?;
+        Ok(result)
+    }
+    
+    #[test]
+    fn round_trip() {
+        let cipher: Rc<Cipher> = Rc::new(XorCipher(0x5A));
+        let head = header("part", &*cipher);
+        for &len in &[0, 10, CHUNK_BYTES, CHUNK_BYTES * 2 + 7] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let enc = encrypt_file(data.clone(), 0, &head, Some(cipher.clone())).unwrap();
+            assert!(enc.len() > data.len());
+            assert_eq!(read_all(&enc, &head, &cipher).unwrap(), data);
+            
+            // Truncation is detected:
+            if len > CHUNK_BYTES {
+                let cut = 4 + CHUNK_BYTES + 1;
+                assert!(read_all(&enc[..cut], &head, &cipher).is_err());
+            }
+        }
+        
+        assert!(decrypt(&b""[..], &head, None).is_err());
+        let mut plain = header("part", &*cipher);
+        plain.cipher = None;
+        let mut r = decrypt(&b""[..], &plain, None).unwrap();
+        assert_eq!(r.read_to_end(&mut Vec::new()).unwrap(), 0);
+    }
+    
+    #[test]
+    fn bound_to_file() {
+        let cipher: Rc<Cipher> = Rc::new(XorCipher(0x5A));
+        let head = header("part", &*cipher);
+        let data = b"some data".to_vec();
+        let enc = encrypt_file(data.clone(), 0, &head, Some(cipher.clone())).unwrap();
+        assert_eq!(read_all(&enc, &head, &cipher).unwrap(), data);
+        
+        // The body cannot be moved to a file with a different header, nor
+        // to another copy (with a new file identifier) of the same file:
+        let mut other = header("other", &*cipher);
+        other.cipher = head.cipher.clone();
+        assert!(read_all(&enc, &other, &cipher).is_err());
+        let mut other = header("part", &*cipher);
+        other.cipher = head.cipher.clone();
+        other.ftype = FileType::Snapshot(LATEST_VERSION);
+        assert!(read_all(&enc, &other, &cipher).is_err());
+        let other = header("part", &*cipher);
+        assert!(read_all(&enc, &other, &cipher).is_err());
+    }
+    
+    #[cfg(feature = "aes-gcm")]
+    #[test]
+    fn aes_gcm() {
+        let mut cipher = AesGcmCipher::new(2, &[7; 32]);
+        let data = b"some secret data".to_vec();
+        let sealed = cipher.seal(b"aad", &data).unwrap();
+        assert_eq!(cipher.open(2, b"aad", &sealed).unwrap(), data);
+        assert!(cipher.open(2, b"other", &sealed).is_err());
+        assert!(cipher.open(1, b"aad", &sealed).is_err());
+        cipher.add_key(1, &[8; 32]);
+        assert!(cipher.open(1, b"aad", &sealed).is_err());
+    }
+}
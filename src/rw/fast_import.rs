@@ -0,0 +1,696 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Export and import of partition history as `git fast-import` streams
+//! 
+//! On export, each loaded state becomes one git commit, with the same parents
+//! (where these are loaded). The tree of each commit has one file per
+//! element, named by the element's identifier (in decimal) and holding the
+//! element's serialised data (`Element::write_buf`). The commit message holds
+//! the state-sum, commit number and any text user metadata; the committer
+//! time is the commit's timestamp. The result can be inspected with git tools
+//! (e.g. `git init export && git -C export fast-import < stream`).
+//! 
+//! On import (`read_fast_export`), a stream as written by `git fast-export`
+//! is converted to new commits: each file becomes an element (via a
+//! user-supplied mapper), and commit timestamps and messages are preserved
+//! in commit metadata. Importing an export does not reproduce the original
+//! states (metadata differs), but does reproduce element identifiers and data.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Read, Write};
+use std::rc::Rc;
+
+use commit::{Commit, CommitMeta, EltChange, MakeCommitMeta, UserMeta, MAX_AUTHOR_BYTES};
+use control::Control;
+use elt::{Element, EltId};
+use error::{Result, ArgError, ReadError};
+use part::Partition;
+use state::{PartState, MutPartState, StateRead, StateWrite};
+use sum::Sum;
+
+/// Write all loaded states of `part` as a `git fast-import` stream.
+/// 
+/// Commits are written to branch `refs/heads/<branch>`, which points to the
+/// newest tip; where there are several tips, the others are given branches
+/// `<branch>-2`, `<branch>-3`, etc. Load history first (e.g. with
+/// `Partition::load_all`) to export all of it; states whose parents are not
+/// loaded become root commits. Returns the number of commits written.
+pub fn write_fast_import<C: Control>(part: &Partition<C>, branch: &str, w: &mut Write)
+        -> Result<usize>
+{
+    if branch.is_empty() || branch.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        return ArgError::err("invalid branch name");
+    }
+    
+    let sums: Vec<Sum> = part.states_iter().map(|s| s.statesum().clone()).collect();
+    let states: HashMap<&Sum, &PartState<C::Element>> = sums.iter()
+            .filter_map(|sum| part.state(sum))
+            .map(|s| (s.statesum(), s))
+            .collect();
+    
+    // Order states such that parents precede children; ties are broken by
+    // commit number then state-sum so that output is reproducible:
+    let mut keys: Vec<&Sum> = states.keys().cloned().collect();
+    keys.sort_by_key(|k| (states[k].meta().number(), *k));
+    let mut order = Vec::with_capacity(keys.len());
+    let mut seen = HashSet::new();
+    for key in keys {
+        visit(key, &states, &mut seen, &mut order);
+    }
+    
+    let mut marks: HashMap<&Sum, usize> = HashMap::new();
+    let mut buf = Vec::new();
+    for (i, state) in order.iter().enumerate() {
+        let mark = i + 1;
+        let meta = state.meta();
+        let mut msg = format!("State {} (commit {})\n", state.statesum(), meta.number());
+        if let UserMeta::Text(ref text) = *meta.extra() {
+            msg.push('\n');
+            msg.push_str(text);
+            msg.push('\n');
+        }
+        let parents: Vec<&Sum> = state.parents().iter()
+                .filter(|p| marks.contains_key(p))
+                .collect();
+        if parents.is_empty() {
+            // Otherwise the commit would follow the branch's current head:
+            writeln!(w, "reset refs/heads/{}", branch)?;
+        }
+        write!(w, "commit refs/heads/{}\nmark :{}\n", branch, mark)?;
+        writeln!(w, "committer Pippin <pippin> {} +0000", meta.timestamp())?;
+        write!(w, "data {}\n{}\n", msg.len(), msg)?;
+        
+        for (j, parent) in parents.iter().enumerate() {
+            writeln!(w, "{} :{}", if j == 0 { "from" } else { "merge" }, marks[parent])?;
+        }
+        
+        // Write changes relative to the first parent, or all elements:
+        let parent = parents.first().map(|p| states[*p]);
+        if parent.is_none() {
+            w.write_all(b"deleteall\n")?;
+        }
+        let mut ids: Vec<EltId> = state.elts_iter().map(|(id, _)| id).collect();
+        ids.sort();
+        for id in ids {
+            let elt = state.get_rc(id)?;
+            buf.clear();
+            elt.write_buf(&mut &mut buf)?;
+            if let Some(old) = parent.and_then(|p| p.get_rc(id).ok()) {
+                if Rc::ptr_eq(old, elt) || old.sum(id) == elt.sum(id) {
+                    continue;
+                }
+            }
+            write!(w, "M 100644 inline {}\ndata {}\n", id, buf.len())?;
+            w.write_all(&buf)?;
+            w.write_all(b"\n")?;
+        }
+        if let Some(parent) = parent {
+            let mut removed: Vec<EltId> = parent.elts_iter()
+                    .map(|(id, _)| id)
+                    .filter(|id| !state.is_avail(*id))
+                    .collect();
+            removed.sort();
+            for id in removed {
+                writeln!(w, "D {}", id)?;
+            }
+        }
+        w.write_all(b"\n")?;
+        marks.insert(state.statesum(), mark);
+    }
+    
+    // Point branches at tips, newest first:
+    let mut tips: Vec<&Sum> = part.tips_iter().filter(|t| marks.contains_key(t)).collect();
+    tips.sort_by_key(|t| ::std::cmp::Reverse(marks[t]));
+    for (i, tip) in tips.iter().enumerate() {
+        if i == 0 {
+            write!(w, "reset refs/heads/{}\nfrom :{}\n\n", branch, marks[tip])?;
+        } else {
+            write!(w, "reset refs/heads/{}-{}\nfrom :{}\n\n", branch, i + 1, marks[tip])?;
+        }
+    }
+    Ok(order.len())
+}
+
+// Push `key` to `order` after its (unvisited, loaded) parents
+fn visit<'a, E: Element>(key: &'a Sum, states: &HashMap<&'a Sum, &'a PartState<E>>,
+        seen: &mut HashSet<&'a Sum>, order: &mut Vec<&'a PartState<E>>)
+{
+    if !seen.insert(key) {
+        return;
+    }
+    let state = states[key];
+    for parent in state.parents() {
+        if let Some((k, _)) = states.get_key_value(parent) {
+            visit(k, states, seen, order);
+        }
+    }
+    order.push(state);
+}
+
+/// Import commits from a stream as written by `git fast-export`, returning
+/// the number of commits added to `part`.
+/// 
+/// Each file added or modified is passed to `mapper` with its path and
+/// content; the element returned (if any) is inserted or replaced, while
+/// `None` causes the file to be ignored (removing any previous element for
+/// this path). Files named by a decimal number (as written by
+/// `write_fast_import`) use this as the element identifier where possible;
+/// other paths are assigned free identifiers, consistently within the import.
+/// 
+/// Commits without a parent in the stream are based on the tip of `part`
+/// (which must be unique). The committer time becomes the commit timestamp,
+/// the message becomes text user metadata and the author's name the commit
+/// author (where short enough, see `MAX_AUTHOR_BYTES`). Tags, features,
+/// progress messages and the like are ignored; renames and copies (as
+/// written with `-M` or `-C`) and references to objects outside the stream
+/// are not supported. New commits are not written; see
+/// `Partition::write_fast`.
+pub fn read_fast_export<C, F>(part: &mut Partition<C>, r: &mut BufRead, mut mapper: F)
+        -> Result<usize>
+        where C: Control, F: FnMut(&str, Vec<u8>) -> Result<Option<C::Element>>
+{
+    let base = part.tip()?.statesum().clone();
+    let mut r = LineReader { r: r, line: Vec::new(), pos: 0, next_pos: 0, pushed_back: false };
+    let mut blobs: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    let mut marks: HashMap<Vec<u8>, Sum> = HashMap::new();
+    let mut branches: HashMap<Vec<u8>, Sum> = HashMap::new();
+    let mut ids = PathIds { ids: HashMap::new(), used: HashSet::new() };
+    let mut num = 0;
+    
+    while r.next()? {
+        let cmd = r.line.clone();
+        if cmd.is_empty() || cmd.starts_with(b"#") {
+            continue;
+        } else if cmd == b"blob" {
+            let mut mark = None;
+            r.next()?;
+            if let Some(m) = r.line.strip_prefix(b"mark ") {
+                mark = Some(m.to_vec());
+                r.next()?;
+            }
+            if r.line.starts_with(b"original-oid ") {
+                r.next()?;
+            }
+            let data = r.data()?;
+            if let Some(mark) = mark {
+                blobs.insert(mark, data);
+            }
+        } else if let Some(branch) = cmd.strip_prefix(b"commit ") {
+            let mut mark = None;
+            let mut author = None;
+            r.next()?;
+            if let Some(m) = r.line.strip_prefix(b"mark ") {
+                mark = Some(m.to_vec());
+                r.next()?;
+            }
+            if r.line.starts_with(b"original-oid ") {
+                r.next()?;
+            }
+            if let Some(ident) = r.line.strip_prefix(b"author ") {
+                author = ident_name(ident);
+                r.next()?;
+            }
+            let timestamp = match r.line.strip_prefix(b"committer ") {
+                Some(ident) => {
+                    if author.is_none() {
+                        author = ident_name(ident);
+                    }
+                    match ident_time(ident) {
+                        Some(time) => time,
+                        None => return r.err("invalid committer time"),
+                    }
+                },
+                None => return r.err("expected committer"),
+            };
+            r.next()?;
+            if r.line.starts_with(b"encoding ") {
+                r.next()?;
+            }
+            let msg = r.data()?;
+            
+            let mut parents = Vec::new();
+            let mut ops = Vec::new();
+            while r.next()? {
+                if r.line.is_empty() {
+                    break;
+                } else if let Some(from) = r.line.strip_prefix(b"from ") {
+                    if !parents.is_empty() {
+                        return r.err("unexpected from");
+                    }
+                    parents.push(resolve(from, &marks, &branches, &r)?);
+                } else if let Some(merge) = r.line.strip_prefix(b"merge ") {
+                    let parent = resolve(merge, &marks, &branches, &r)?;
+                    if parents.is_empty() {
+                        parents.push(branches.get(branch).unwrap_or(&base).clone());
+                    }
+                    parents.push(parent);
+                } else if r.line.starts_with(b"M ") {
+                    let line = r.line.clone();
+                    let mut parts = line[2..].splitn(3, |b| *b == b' ');
+                    let (mode, dataref, path) = match (parts.next(), parts.next(), parts.next()) {
+                        (Some(mode), Some(dataref), Some(path)) => (mode, dataref, path),
+                        _ => return r.err("invalid file modification"),
+                    };
+                    let path = r.path(path)?;
+                    let data = if dataref == b"inline" {
+                        r.next()?;
+                        r.data()?
+                    } else if let Some(data) = blobs.get(dataref) {
+                        data.clone()
+                    } else {
+                        return r.err("unknown blob (only marks and inline data are supported)");
+                    };
+                    // Sub-modules and directories have no content:
+                    if mode != b"160000" && mode != b"040000" {
+                        ops.push(FileOp::Modify(path, data));
+                    }
+                } else if let Some(path) = r.line.strip_prefix(b"D ") {
+                    ops.push(FileOp::Delete(r.path(path)?));
+                } else if r.line == b"deleteall" {
+                    ops.push(FileOp::DeleteAll);
+                } else if r.line.starts_with(b"R ") || r.line.starts_with(b"C ") ||
+                        r.line.starts_with(b"N ") {
+                    return r.err("unsupported file command (export without -M or -C)");
+                } else {
+                    r.push_back();
+                    break;
+                }
+            }
+            if parents.is_empty() {
+                parents.push(branches.get(branch).unwrap_or(&base).clone());
+            }
+            if parents.len() >= 0x100 {
+                return r.err("too many parents");
+            }
+            
+            let extra = if msg.is_empty() {
+                UserMeta::None
+            } else {
+                match String::from_utf8(msg) {
+                    Ok(text) => UserMeta::Text(text),
+                    Err(e) => UserMeta::Bytes(e.into_bytes()),
+                }
+            };
+            let mcm = ImportMeta { timestamp: timestamp, extra: extra, author: author };
+            let commit = make_commit(part, parents, ops, &mcm, &mut ids, &mut mapper)?;
+            let statesum = commit.statesum().clone();
+            if part.push_commit(commit)? {
+                num += 1;
+            }
+            if let Some(mark) = mark {
+                marks.insert(mark, statesum.clone());
+            }
+            branches.insert(branch.to_vec(), statesum);
+        } else if let Some(branch) = cmd.strip_prefix(b"reset ") {
+            if r.next()? {
+                if let Some(from) = r.line.strip_prefix(b"from ") {
+                    let sum = resolve(from, &marks, &branches, &r)?;
+                    branches.insert(branch.to_vec(), sum);
+                    continue;
+                }
+                r.push_back();
+            }
+            branches.remove(branch);
+        } else if cmd.starts_with(b"tag ") {
+            // Skip to the end of the tag message:
+            loop {
+                if !r.next()? {
+                    return r.err("unexpected end of tag");
+                }
+                if r.line.starts_with(b"data ") {
+                    r.data()?;
+                    break;
+                }
+            }
+        } else if cmd == b"done" {
+            break;
+        } else if cmd.starts_with(b"feature ") || cmd.starts_with(b"option ") ||
+                cmd.starts_with(b"progress ") || cmd == b"checkpoint" {
+            continue;
+        } else {
+            return r.err("unsupported command");
+        }
+    }
+    Ok(num)
+}
+
+// A file command within a commit
+enum FileOp {
+    Modify(String, Vec<u8>),
+    Delete(String),
+    DeleteAll,
+}
+
+// Commit metadata for an imported commit
+struct ImportMeta {
+    timestamp: i64,
+    extra: UserMeta,
+    author: Option<String>,
+}
+impl MakeCommitMeta for ImportMeta {
+    fn make_commit_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+    fn make_commit_extra(&self, _number: u32, _parents: Vec<(&Sum, &CommitMeta)>) -> UserMeta {
+        self.extra.clone()
+    }
+    fn make_commit_author(&self) -> Option<String> {
+        self.author.clone()
+    }
+}
+
+// Assignment of element identifiers to paths
+struct PathIds {
+    ids: HashMap<String, EltId>,
+    used: HashSet<EltId>,
+}
+impl PathIds {
+    fn get<E: Element>(&mut self, path: &str, state: &mut MutPartState<E>) -> Result<EltId> {
+        if let Some(id) = self.ids.get(path) {
+            return Ok(*id);
+        }
+        let mut id = path.parse::<u64>().map(EltId::from).unwrap_or_else(|_| EltId::random());
+        loop {
+            id = state.free_id_near(id)?;
+            if self.used.insert(id) {
+                break;
+            }
+            id = id.next_elt();
+        }
+        self.ids.insert(path.to_string(), id);
+        Ok(id)
+    }
+}
+
+// Build a commit applying `ops` to the first parent
+fn make_commit<C, F>(part: &Partition<C>, parents: Vec<Sum>, ops: Vec<FileOp>,
+        mcm: &ImportMeta, ids: &mut PathIds, mapper: &mut F) -> Result<Commit<C::Element>>
+        where C: Control, F: FnMut(&str, Vec<u8>) -> Result<Option<C::Element>>
+{
+    let mut metas = Vec::with_capacity(parents.len());
+    for sum in &parents {
+        match part.state(sum) {
+            Some(state) => metas.push((sum, state.meta())),
+            None => return ArgError::err("parent state of imported commit not loaded"),
+        }
+    }
+    let parent = part.state(&parents[0]).unwrap();
+    let mut state = parent.clone_mut();
+    for op in ops {
+        match op {
+            FileOp::Modify(path, data) => {
+                let id = ids.get(&path, &mut state)?;
+                match mapper(&path, data)? {
+                    Some(elt) if state.is_avail(id) => { state.replace(id, elt)?; },
+                    Some(elt) => { state.insert(id, elt)?; },
+                    None if state.is_avail(id) => { state.remove(id)?; },
+                    None => {},
+                }
+            },
+            FileOp::Delete(path) => {
+                // The path may be a file or a directory:
+                let prefix = format!("{}/", path);
+                let removed: Vec<EltId> = ids.ids.iter()
+                        .filter(|&(p, _)| *p == path || p.starts_with(&prefix))
+                        .map(|(_, id)| *id)
+                        .collect();
+                for id in removed {
+                    if state.is_avail(id) {
+                        state.remove(id)?;
+                    }
+                }
+            },
+            FileOp::DeleteAll => {
+                let all: Vec<EltId> = state.elts_iter().map(|(id, _)| id).collect();
+                for id in all {
+                    state.remove(id)?;
+                }
+            },
+        }
+    }
+    
+    let mut changes = HashMap::new();
+    for id in state.changed_ids() {
+        match (parent.get_rc(*id).ok(), state.get_rc(*id).ok()) {
+            (Some(old), Some(new)) => if old != new {
+                changes.insert(*id, EltChange::replacement(new.clone()));
+            },
+            (None, Some(new)) => { changes.insert(*id, EltChange::insertion(new.clone())); },
+            (Some(_), None) => { changes.insert(*id, EltChange::deletion()); },
+            (None, None) => {},
+        }
+    }
+    let meta = CommitMeta::new_parents(metas, mcm);
+    let statesum = state.elt_sum() ^ &Sum::state_meta_sum(&parents, &meta);
+    Ok(Commit::new_explicit(statesum, parents, changes, meta))
+}
+
+// Resolve a commit reference: a mark or a branch previously written
+fn resolve(name: &[u8], marks: &HashMap<Vec<u8>, Sum>, branches: &HashMap<Vec<u8>, Sum>,
+        r: &LineReader) -> Result<Sum>
+{
+    match marks.get(name).or_else(|| branches.get(name)) {
+        Some(sum) => Ok(sum.clone()),
+        None => r.err("unknown commit (only marks and branches are supported)"),
+    }
+}
+
+// Get the name from an identity `Name <email> time tz`, if short enough
+fn ident_name(ident: &[u8]) -> Option<String> {
+    let end = ident.iter().position(|b| *b == b'<').unwrap_or(ident.len());
+    let name = String::from_utf8_lossy(&ident[..end]).trim().to_string();
+    if name.is_empty() || name.len() > MAX_AUTHOR_BYTES { None } else { Some(name) }
+}
+
+// Get the time from an identity `Name <email> time tz`
+fn ident_time(ident: &[u8]) -> Option<i64> {
+    let end = ident.iter().rposition(|b| *b == b'>')?;
+    let mut fields = ident[end + 1..].split(|b| *b == b' ').filter(|f| !f.is_empty());
+    ::std::str::from_utf8(fields.next()?).ok()?.parse().ok()
+}
+
+// Line-based reader tracking positions for error reporting
+struct LineReader<'a> {
+    r: &'a mut BufRead,
+    line: Vec<u8>,
+    pos: usize,
+    next_pos: usize,
+    pushed_back: bool,
+}
+impl<'a> LineReader<'a> {
+    // Read the next line (without line ending) into `line`; false at end
+    fn next(&mut self) -> Result<bool> {
+        if self.pushed_back {
+            self.pushed_back = false;
+            return Ok(true);
+        }
+        self.line.clear();
+        self.pos = self.next_pos;
+        let n = self.r.read_until(b'\n', &mut self.line)?;
+        self.next_pos += n;
+        if self.line.last() == Some(&b'\n') {
+            self.line.pop();
+        }
+        Ok(n > 0)
+    }
+    
+    // Make `next` return the current line again
+    fn push_back(&mut self) {
+        self.pushed_back = true;
+    }
+    
+    // Read data, given the current line `data <len>`
+    fn data(&mut self) -> Result<Vec<u8>> {
+        let len = match self.line.strip_prefix(b"data ") {
+            Some(len) => match ::std::str::from_utf8(len).ok().and_then(|l| l.parse::<u64>().ok()) {
+                Some(len) => len,
+                None => return self.err("unsupported data length (delimited data?)"),
+            },
+            None => return self.err("expected data"),
+        };
+        let mut data = Vec::new();
+        (&mut self.r).take(len).read_to_end(&mut data)?;
+        if (data.len() as u64) < len {
+            return self.err("unexpected end of data");
+        }
+        self.next_pos += data.len();
+        // An optional line feed may follow:
+        if self.r.fill_buf()?.first() == Some(&b'\n') {
+            self.r.consume(1);
+            self.next_pos += 1;
+        }
+        Ok(data)
+    }
+    
+    // Parse a path, which may be quoted
+    fn path(&self, path: &[u8]) -> Result<String> {
+        let path = if path.first() == Some(&b'"') {
+            match unquote(&path[1..]) {
+                Some(path) => path,
+                None => return self.err("invalid quoted path"),
+            }
+        } else {
+            path.to_vec()
+        };
+        match String::from_utf8(path) {
+            Ok(path) => Ok(path),
+            Err(_) => self.err("path is not valid UTF-8"),
+        }
+    }
+    
+    fn err<T>(&self, msg: &'static str) -> Result<T> {
+        ReadError::err(msg, self.pos, (0, self.line.len()))
+    }
+}
+
+// Unquote a C-style quoted string, given the text after the opening quote
+fn unquote(s: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut iter = s.iter().cloned();
+    loop {
+        match iter.next()? {
+            b'"' => return if iter.next().is_none() { Some(out) } else { None },
+            b'\\' => {
+                let c = iter.next()?;
+                out.push(match c {
+                    b'a' => 7, b'b' => 8, b'f' => 12, b'n' => b'\n',
+                    b'r' => b'\r', b't' => b'\t', b'v' => 11,
+                    b'0'..=b'7' => {
+                        let (c1, c2) = (iter.next()?, iter.next()?);
+                        if !(b'0'..=b'7').contains(&c1) || !(b'0'..=b'7').contains(&c2) {
+                            return None;
+                        }
+                        (c - b'0') * 64 + (c1 - b'0') * 8 + (c2 - b'0')
+                    },
+                    c => c,
+                });
+            },
+            c => out.push(c),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use control::DefaultControl;
+    use io::DummyRepoIO;
+    use state::StateWrite;
+    
+    #[test]
+    fn fast_import() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "export").unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        let a = state.insert_new("a".to_string()).unwrap();
+        let b = state.insert_new("b".to_string()).unwrap();
+        part.push_state(state).unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.replace(a, "A".to_string()).unwrap();
+        state.remove(b).unwrap();
+        part.push_state(state).unwrap();
+        
+        let mut out = Vec::new();
+        assert_eq!(write_fast_import(&part, "main", &mut out).unwrap(), 3);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("commit refs/heads/main\n").count(), 3);
+        assert_eq!(out.matches("deleteall\n").count(), 1);
+        assert!(out.contains(&format!("M 100644 inline {}\ndata 1\nb\n", b)));
+        assert!(out.contains(&format!("M 100644 inline {}\ndata 1\nA\n", a)));
+        assert!(out.contains(&format!("D {}\n", b)));
+        assert!(out.ends_with("reset refs/heads/main\nfrom :3\n\n"));
+        
+        assert!(out.starts_with("reset refs/heads/main\ncommit refs/heads/main\n"));
+        assert_eq!(out.matches("reset refs/heads/main\n").count(), 2);
+        
+        assert!(write_fast_import(&part, "bad name", &mut Vec::new()).is_err());
+        
+        // Round trip: element identifiers and data are preserved
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part2 = Partition::create(control, "import").unwrap();
+        let n = read_fast_export(&mut part2, &mut out.as_bytes(),
+                |_, data| String::from_vec(data).map(Some)).unwrap();
+        assert_eq!(n, 3);
+        let tip = part2.tip().unwrap();
+        assert_eq!(tip.elts_iter().count(), 1);
+        assert_eq!(**tip.get_rc(a).unwrap(), "A");
+    }
+    
+    #[test]
+    fn fast_import_roots() {
+        use io::mem::MemRepoIO;
+        use rw::commitlog::Recovery;
+        
+        // Two diverged tips; a safety snapshot is written of each:
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "roots").unwrap();
+        let base = part.tip().unwrap().clone_exact();
+        for s in &["a", "b"] {
+            let mut state = base.clone_mut();
+            state.insert_new(s.to_string()).unwrap();
+            part.push_state(state).unwrap();
+        }
+        part.safety_snapshot("roots").unwrap();
+        let io = part.unwrap_control().unwrap_io();
+        
+        // Loading only these snapshots gives two states without parents:
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io), false).unwrap();
+        part.load_range(1, usize::MAX, Recovery::Strict).unwrap();
+        assert_eq!(part.states_iter().count(), 2);
+        let mut out = Vec::new();
+        assert_eq!(write_fast_import(&part, "main", &mut out).unwrap(), 2);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("reset refs/heads/main\ncommit refs/heads/main\n").count(), 2);
+        assert!(!out.contains("from :1\nM"));
+        assert!(out.contains("reset refs/heads/main-2\n"));
+    }
+    
+    #[test]
+    fn fast_export() {
+        let stream = b"blob\nmark :1\ndata 5\nhello\n\
+blob\nmark :2\ndata 5\nworld\n\
+reset refs/heads/main\n\
+commit refs/heads/main\nmark :3\nauthor Ann <ann@example.com> 1000 +0100\n\
+committer Bob <bob@example.com> 1500 +0000\ndata 7\nfirst\n\n\
+M 100644 :1 a.txt\nM 100644 :2 \"dir/b \\\"q\\\"\"\n\n\
+commit refs/heads/side\nmark :4\ncommitter Bob <bob@example.com> 2000 +0000\ndata 0\n\
+from :3\nD dir\n\n\
+commit refs/heads/main\nmark :5\ncommitter Bob <bob@example.com> 3000 +0000\ndata 5\nmerge\
+from :3\nmerge :4\nM 100644 inline c.txt\ndata 1\nc\n\n\
+tag v1\nfrom :5\ntagger Bob <bob@example.com> 3000 +0000\ndata 3\ntag\ndone\n";
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "import").unwrap();
+        let mut paths = Vec::new();
+        let n = read_fast_export(&mut part, &mut &stream[..], |path, data| {
+            paths.push(path.to_string());
+            String::from_vec(data).map(Some)
+        }).unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(paths, vec!["a.txt", "dir/b \"q\"", "c.txt"]);
+        
+        let tip = part.tip().unwrap();
+        assert_eq!(tip.parents().len(), 2);
+        assert_eq!(tip.meta().timestamp(), 3000);
+        assert_eq!(*tip.meta().extra(), UserMeta::Text("merge".to_string()));
+        // Merged from "main" (with b) and "side" (without), changing only c:
+        let mut elts: Vec<String> = tip.elts_iter().map(|(_, e)| (**e).clone()).collect();
+        elts.sort();
+        assert_eq!(elts, vec!["c", "hello", "world"]);
+        let first = part.state(&tip.parents()[0]).unwrap();
+        assert_eq!(first.meta().timestamp(), 1500);
+        assert_eq!(first.meta().author(), Some("Ann"));
+        let side = part.state(&tip.parents()[1]).unwrap();
+        assert_eq!(side.elts_iter().count(), 1);
+        assert_eq!(side.meta().extra(), &UserMeta::None);
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "import").unwrap();
+        let bad = b"commit refs/heads/main\ncommitter A <a> 1 +0000\ndata 0\nR a b\n";
+        assert!(read_fast_export(&mut part, &mut &bad[..],
+                |_, data| String::from_vec(data).map(Some)).is_err());
+    }
+}
@@ -9,19 +9,27 @@ use std::cmp::min;
 use std::result::Result as stdResult;
 
 use error::{Result, ArgError, ReadError, make_io_err};
-use rw::{HEAD_VERSIONS, sum};
+use rw::{HEAD_VERSIONS, DEPRECATED_BEFORE, sum};
+use byteorder::{ByteOrder, BigEndian};
+
+use rw::compress::Compression;
+use rw::encrypt::CipherInfo;
 use sum::SUM_BYTES;
 use util::rtrim;
 
 // Snapshot header. This is the latest version.
-const HEAD_SNAPSHOT : [u8; 16] = *b"PIPPINSS20160815";
+const HEAD_SNAPSHOT : [u8; 16] = *b"PIPPINSS20261024";
 // Commit log header. This is the latest version.
-const HEAD_COMMITLOG : [u8; 16] = *b"PIPPINCL20160815";
+const HEAD_COMMITLOG : [u8; 16] = *b"PIPPINCL20261024";
 
 const SUM_SHA256 : [u8; 16] = *b"HSUM SHA-2 256\x00\x00";
 const SUM_BLAKE2_16 : [u8; 16] = *b"HSUM BLAKE2 16\x00\x00";
 const PARTID : [u8; 8] = *b"HPARTID ";
 const CLASS_RANGE : [u8; 4] = *b"HCSF";
+const COMPRESS : [u8; 10] = *b"HCOMPRESS ";
+const DEDUP : [u8; 16] = *b"HDEDUP\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+const CIPHER : [u8; 9] = *b"Q3CIPHER ";
+const ARCHIVED : [u8; 16] = *b"Harchived\x00\x00\x00\x00\x00\x00\x00";
 
 /// File type and version.
 /// 
@@ -43,6 +51,16 @@ impl FileType {
             FileType::Snapshot(v) | FileType::CommitLog(v) => v,
         }
     }
+    /// True if the file uses the latest format version (that which would be
+    /// used when writing a new file).
+    pub fn is_latest(&self) -> bool {
+        self.ver() == HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]
+    }
+    /// True if the file uses a version which is still read but deprecated
+    /// (see `DEPRECATED_BEFORE`).
+    pub fn is_deprecated(&self) -> bool {
+        self.ver() < DEPRECATED_BEFORE
+    }
 }
 
 /// Types of user-data which can be stored in header fields.
@@ -65,6 +83,21 @@ pub struct FileHeader {
     pub name: String,
     /// User data fields, remarks, etc.
     pub user: Vec<UserData>,
+    /// Tag naming the state stored in a snapshot (see
+    /// `Partition::safety_snapshot`). Not used in commit logs.
+    pub tag: Option<String>,
+    /// Compression of the file contents following the header (see
+    /// `rw::compress`)
+    pub compression: Compression,
+    /// Snapshot elements with identical data may be stored once and
+    /// referenced (see `write_snapshot_dedup`). Not used in commit logs.
+    pub dedup: bool,
+    /// Encryption of the file contents following the header (see
+    /// `rw::encrypt`), if any
+    pub cipher: Option<CipherInfo>,
+    /// Snapshot marks the partition archived (see `Partition::archive`). Not
+    /// used in commit logs.
+    pub archived: bool,
 }
 
 // Decodes from a string to the format used in HEAD_VERSIONS. Returns zero on
@@ -120,6 +153,11 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
     pos += 16;
     
     let mut user_fields = Vec::new();
+    let mut tag = None;
+    let mut compression = Compression::None;
+    let mut dedup = false;
+    let mut cipher = None;
+    let mut archived = false;
     loop {
         r.read_exact(&mut buf[0..16])?;
         let (block, off): (&[u8], usize) = if buf[0] == b'H' {
@@ -164,10 +202,32 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
             // ignore; feature removed
         } else if block[0..3] == CLASS_RANGE[1..] {
             // ignore; feature removed
+        } else if block.len() >= 9 && block[0..9] == COMPRESS[1..] {
+            compression = match Compression::from_name(rtrim(&block[9..], 0)) {
+                Some(c) if c.is_supported() => c,
+                Some(_) => return ReadError::err("file compression method not supported",
+                        pos, (9+off, off+block.len())),
+                None => return ReadError::err("unknown file compression method",
+                        pos, (9+off, off+block.len())),
+            };
+        } else if rtrim(block, 0) == &DEDUP[1..6] {
+            dedup = true;
+        } else if block.len() == 46 && block[0..7] == CIPHER[2..] {
+            let scheme = match String::from_utf8(rtrim(&block[7..18], 0).to_vec()) {
+                Ok(ref s) if !s.is_empty() => s.clone(),
+                _ => return ReadError::err("invalid encryption scheme", pos, (7+off, 18+off)),
+            };
+            let mut file_id = [0u8; 16];
+            file_id.copy_from_slice(&block[30..46]);
+            cipher = Some(CipherInfo { scheme, key_id: BigEndian::read_u64(&block[18..26]), file_id });
+        } else if rtrim(block, 0) == &ARCHIVED[1..9] {
+            archived = true;
         } else if block[0] == b'R' {
             user_fields.push(UserData::Text(String::from_utf8(rtrim(&block[1..], 0).to_vec())?));
         } else if block[0] == b'U' {
             user_fields.push(UserData::Data(block[1..].to_vec()));
+        } else if block[0] == b't' {
+            tag = Some(String::from_utf8(rtrim(&block[1..], 0).to_vec())?);
         } else if block[0] >= b'A' && block[0] <= b'Z' {
             // Match unknown essential extensions here
             // Note: we *could* go ahead and read file with caution, but how
@@ -197,6 +257,11 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
         ftype: ftype,
         name: repo_name,
         user: user_fields,
+        tag,
+        compression,
+        dedup,
+        cipher,
+        archived,
     })
 }
 
@@ -220,15 +285,54 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
     
     for u in &header.user {
         // We allow padding in text mode:
-        let (t, uf, is_text) = match *u {
-            UserData::Data(ref b) => (b'U', &b[..], false),
-            UserData::Text(ref t) => (b'R', t.as_bytes(), true),
+        match *u {
+            UserData::Data(ref b) => write_block(&mut w, b'U', &b[..], false)?,
+            UserData::Text(ref t) => write_block(&mut w, b'R', t.as_bytes(), true)?,
         };
+    }
+    if let Some(ref tag) = header.tag {
+        if tag.is_empty() || tag.contains('\0') {
+            return ArgError::err("invalid tag");
+        }
+        write_block(&mut w, b't', tag.as_bytes(), true)?;
+    }
+    if header.compression != Compression::None {
+        let mut line = [0u8; 16];
+        line[0..10].copy_from_slice(&COMPRESS);
+        line[10..14].copy_from_slice(header.compression.name().as_bytes());
+        w.write_all(&line)?;
+    }
+    if header.dedup {
+        w.write_all(&DEDUP)?;
+    }
+    if let Some(ref info) = header.cipher {
+        let scheme = info.scheme.as_bytes();
+        if scheme.is_empty() || scheme.len() > 11 || scheme.contains(&0) {
+            return ArgError::err("invalid encryption scheme name");
+        }
+        let mut line = [0u8; 48];
+        line[0..9].copy_from_slice(&CIPHER);
+        line[9..9 + scheme.len()].copy_from_slice(scheme);
+        BigEndian::write_u64(&mut line[20..28], info.key_id);
+        line[32..48].copy_from_slice(&info.file_id);
+        w.write_all(&line)?;
+    }
+    if header.archived {
+        w.write_all(&ARCHIVED)?;
+    }
+    
+    w.write_all(&SUM_BLAKE2_16)?;
+    
+    // Write the checksum of everything above:
+    let sum = w.sum();
+    sum.write_to(&mut w.into_inner())?;
+    
+    fn write_block<W: Write>(w: &mut W, t: u8, uf: &[u8], is_text: bool) -> Result<()> {
         let mut l = [b'B', 0, b'Q', b'H', t];
         if uf.len() <= 14 && (is_text || uf.len() == 14) {
             w.write_all(&l[3..5])?;
             w.write_all(uf)?;
-            pad(&mut w, 14 - uf.len())?;
+            pad(w, 14 - uf.len())?;
         } else if uf.len() + 3 <= 16 * 36 && 
             (is_text || (uf.len() + 3) % 16 == 0)
         {
@@ -236,7 +340,7 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
             l[3] = if n <= 9 { b'0' + n as u8 } else { b'A' - 10 + n as u8 };
             w.write_all(&l[2..5])?;
             w.write_all(uf)?;
-            pad(&mut w, n * 16 - uf.len() - 3)?;
+            pad(w, n * 16 - uf.len() - 3)?;
         } else if uf.len() <= (2 << 24) - 5 {
             let len = uf.len() + 5; // length written includes leading `Bbbb` and 'U' or 'R'
             l[1] = ((len >> 16) & 0xFF) as u8;
@@ -244,18 +348,13 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
             l[3] = (len & 0xFF) as u8;
             w.write_all(&l[0..5])?;
             w.write_all(uf)?;
-            pad(&mut w, ((len + 15) / 16) * 16 - len)?;
+            pad(w, ((len + 15) / 16) * 16 - len)?;
         } else {
             return ArgError::err("user field too long");
         }
+        Ok(())
     }
     
-    w.write_all(&SUM_BLAKE2_16)?;
-    
-    // Write the checksum of everything above:
-    let sum = w.sum();
-    sum.write_to(&mut w.into_inner())?;
-    
     fn pad<W: Write>(w: &mut W, n1: usize) -> Result<()> {
         let zeros = [0u8; 16];
         let mut n = n1;
@@ -315,11 +414,16 @@ fn write_header() {
             UserData::Data(b"0123456789abcdefghijklmnopqrs".to_vec()),
             UserData::Data(b" rsei noasr auyv 10()% xovn".to_vec()),
         ],
+        tag: None,
+        compression: Compression::None,
+        dedup: false,
+        cipher: None,
+        archived: false,
     };
     let mut buf = Vec::new();
     write_head(&header, &mut buf).unwrap();
     
-    let head_bytes = b"PIPPINSS20160815\
+    let head_bytes = b"PIPPINSS20261024\
             \xc3\x84hnliche Unsinn\
             HRRemark \xcf\x89\x00\x00\x00\x00\x00\
             Q2R Quatsch Quatsch \
@@ -329,7 +433,7 @@ fn write_header() {
             B\x00\x00\x20U rsei noasr a\
             uyv 10()% xovn\
             HSUM BLAKE2 16\x00\x00\
-            \xdd0N\"\xceb#\x0d\x88\xa1\x19\xceu\xdd\x0e\xdej\xc8\xea|\xc3\xb3q\xdaO\x92)u\xa1\xf6\xe9\x01";
+            t(=r\xb6\x04De\xa5Tt\x8f_*\xf3\xaai\xcf\xa5\xc5\xf7#+\xd1\xbf\xf5\x82\xd6\\@\xdd\xd1";
     use ::util::ByteFormatter;
     println!("Checksum: '{}'", ByteFormatter::from(&buf[buf.len()-SUM_BYTES..buf.len()]));
     println!("(Replace last line of head_bytes with new checksum.)");
@@ -339,3 +443,69 @@ fn write_header() {
         assert!(false);
     }
 }
+
+#[test]
+fn header_tag() {
+    let mut header = FileHeader {
+        ftype: FileType::Snapshot(0),
+        name: "tagged".to_string(),
+        user: vec![UserData::Text("remark".to_string())],
+        tag: Some("before merge".to_string()),
+        compression: Compression::None,
+        dedup: false,
+        cipher: None,
+        archived: false,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.user, header.user);
+    assert_eq!(header2.tag, header.tag);
+    
+    header.tag = Some(String::new());
+    assert!(write_head(&header, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn header_archived() {
+    let mut header = FileHeader {
+        ftype: FileType::Snapshot(0),
+        name: "archived".to_string(),
+        user: vec![],
+        tag: None,
+        compression: Compression::None,
+        dedup: false,
+        cipher: None,
+        archived: true,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert!(read_head(&mut &buf[..]).unwrap().archived);
+    
+    header.archived = false;
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert!(!read_head(&mut &buf[..]).unwrap().archived);
+}
+
+#[test]
+fn header_cipher() {
+    let mut header = FileHeader {
+        ftype: FileType::CommitLog(0),
+        name: "secret".to_string(),
+        user: vec![],
+        tag: None,
+        compression: Compression::None,
+        dedup: false,
+        cipher: Some(CipherInfo { scheme: "aes256gcm".to_string(), key_id: 0x0102,
+                file_id: *b"0123456789abcdef" }),
+        archived: false,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(read_head(&mut &buf[..]).unwrap().cipher, header.cipher);
+    
+    header.cipher = Some(CipherInfo { scheme: "much too long".to_string(), key_id: 0,
+            file_id: [0; 16] });
+    assert!(write_head(&header, &mut Vec::new()).is_err());
+}
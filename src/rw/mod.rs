@@ -10,15 +10,25 @@ mod sum;
 pub mod header;
 pub mod snapshot;
 pub mod commitlog;
+pub mod compress;
+pub mod encrypt;
+pub mod audit;
+pub mod sumfilter;
+pub mod compat;
+pub mod fast_import;
 
 use std::io::{Read, Write};
 use std::iter::repeat;
+use std::rc::Rc;
 use std::u32;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
-use commit::{CommitMeta, UserMeta, MetaFlags};
-use error::{Result, ReadError};
+use commit::{CommitMeta, UserMeta, UserMetaLimits, InvalidText, MetaFlags};
+use control::ProgressSink;
+use elt::{Element, EltId, EltReadPolicy};
+use error::{Result, ReadError, ArgError};
+use sum::Sum;
 
 // —————  module-private data and functions  —————
 
@@ -29,7 +39,7 @@ use error::{Result, ReadError};
 // Note: new versions can be implemented just by updating the three HEAD_...
 // constants and updating code, so long as the code will still read old
 // versions. The file format documentation should also be updated.
-const HEAD_VERSIONS : [u32; 3] = [
+const HEAD_VERSIONS : [u32; 11] = [
     /* unsupported versions:
     2015_09_29, // initial standardisation
     2016_01_05, // add 'PARTID' to header blocks (snapshot only)
@@ -41,8 +51,101 @@ const HEAD_VERSIONS : [u32; 3] = [
     2016_03_10, // new element and state sums break compatibility
     2016_05_16, // support Bbbb header sections
     2016_08_15, // allow non-breaking extensions to commit-meta
+    2026_10_17, // optional per-element metadata (snapshots only), binary user metadata
+    2026_10_18, // erased elements (tombstones)
+    2026_10_19, // operation-based element changes (logs only)
+    2026_10_20, // element index following the snapshot (snapshots only)
+    2026_10_21, // squashed commits (logs only)
+    2026_10_22, // provenance extension to commit-meta
+    2026_10_23, // summary extension to commit-meta
+    2026_10_24, // author extension to commit-meta
 ];
 
+/// The latest file format version (see `HEAD_VERSIONS`), as written by this
+/// library. Data in other formats (e.g. commits exchanged by `sync`) is
+/// read as if from a file of this version.
+pub const LATEST_VERSION: u32 = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+
+/// Versions older than this are deprecated (see `FileType::is_deprecated`):
+/// they are still read, but cannot hold extension data in commit metadata,
+/// thus should be migrated by rewriting (e.g. `Partition::vacuum`). All
+/// later versions in `HEAD_VERSIONS` are fully supported.
+pub const DEPRECATED_BEFORE: u32 = 2016_08_15;
+
+/// Deserialises elements read from snapshots and commit logs according to
+/// an `EltReadPolicy`, recording elements which could not be read.
+/// Optionally, the size of each element read is reported to a
+/// `ProgressSink`.
+/// 
+/// The default uses `EltReadPolicy::Fail`.
+#[derive(Debug, Default)]
+pub struct EltReader {
+    policy: EltReadPolicy,
+    skipped: Vec<(EltId, Sum)>,
+    progress: Option<Rc<ProgressSink>>,
+    aborted: bool,
+}
+impl EltReader {
+    /// Create, with the given policy
+    pub fn new(policy: EltReadPolicy) -> Self {
+        EltReader { policy, skipped: vec![], progress: None, aborted: false }
+    }
+    
+    /// Set or clear the receiver of progress reports
+    /// (`ProgressSink::bytes_read`). Reading fails if this fails.
+    pub fn with_progress(mut self, progress: Option<Rc<ProgressSink>>) -> Self {
+        self.progress = progress;
+        self
+    }
+    
+    /// Get the policy
+    pub fn policy(&self) -> EltReadPolicy {
+        self.policy
+    }
+    
+    /// Get identifiers and sums of elements skipped or replaced by
+    /// placeholders so far
+    pub fn skipped(&self) -> &[(EltId, Sum)] {
+        &self.skipped
+    }
+    
+    /// Take the list of skipped elements, leaving it empty
+    pub fn take_skipped(&mut self) -> Vec<(EltId, Sum)> {
+        ::std::mem::take(&mut self.skipped)
+    }
+    
+    // True if reading was aborted by the progress sink (such failures are
+    // not recoverable damage)
+    fn aborted(&self) -> bool {
+        self.aborted
+    }
+    
+    /// Deserialise element `id` from `data` (already verified against
+    /// `sum`). Returns `None` if the element should be skipped.
+    pub fn read<E: Element>(&mut self, id: EltId, data: Vec<u8>, sum: Sum) -> Result<Option<E>> {
+        if let Some(ref progress) = self.progress {
+            if let Err(e) = progress.bytes_read(data.len()) {
+                self.aborted = true;
+                return Err(e);
+            }
+        }
+        let copy = if self.policy == EltReadPolicy::Placeholder { Some(data.clone()) } else { None };
+        match E::from_vec_sum(data, sum.clone()) {
+            Ok(elt) => Ok(Some(elt)),
+            Err(e) => {
+                if self.policy == EltReadPolicy::Fail {
+                    return Err(e);
+                }
+                let elt = copy.and_then(|data| E::placeholder(id, data, sum.clone()));
+                warn!("Unable to read element {}: {}; {}", id, e,
+                        if elt.is_some() { "using placeholder" } else { "skipping" });
+                self.skipped.push((id, sum));
+                Ok(elt)
+            },
+        }
+    }
+}
+
 /// Read metadata
 /// 
 /// This is a bit involved. It expects:
@@ -50,11 +153,14 @@ const HEAD_VERSIONS : [u32; 3] = [
 /// *   `r`: a reader
 /// *   `buf`: a buffer of length at least 16 and with bytes 8..16 filled
 /// *   `pos`: a counter, which needs incrementing by 16 after finishing 8 bytes from buf
-fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_ver: u32) -> Result<CommitMeta> {
+/// *   `limits`: limits applied to user metadata
+fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_ver: u32,
+        limits: &UserMetaLimits) -> Result<CommitMeta>
+{
     let secs = BigEndian::read_i64(&buf[8..16]);
     (*pos) += 16;
     
-    r.read_exact(&mut buf[0..16])?;
+    r.read_exact(&mut buf[0..8])?;
     let (ext_len, ext_flags) = if format_ver < 2016_08_15 {
         if buf[0..4] != *b"CNUM" {
             return ReadError::err("unexpected contents (expected CNUM)", *pos, (0, 4));
@@ -69,24 +175,36 @@ fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_v
         (len, flags)
     };
     let cnum = BigEndian::read_u32(&buf[4..8]);
+    // extension data follows the commit number (see file format doc):
     let mut ext_data: Vec<u8> = repeat(0).take(ext_len).collect();
     r.read_exact(&mut ext_data)?;
+    r.read_exact(&mut buf[8..16])?;
     
     if buf[8..10] != *b"XM" {
         return ReadError::err("unexpected contents (expected XM)", *pos, (8, 10));
     }
-    let xm_type_txt = buf[10..12] == *b"TT";
+    let xm_type = [buf[10], buf[11]];
     let xm_len = BigEndian::read_u32(&buf[12..16]) as usize;
+    if xm_len > limits.max_len as usize {
+        return ReadError::err("user metadata exceeds length limit", *pos, (12, 16));
+    }
     (*pos) += 16;
     
     let mut xm_data = vec![0; xm_len];
     r.read_exact(&mut xm_data)?;
-    let xm = if xm_type_txt {
-        UserMeta::Text(String::from_utf8(xm_data)
-            .map_err(|_| ReadError::new("content not valid UTF-8", *pos, (0, xm_len)))?)
-    } else {
+    let xm = match &xm_type {
+        b"TT" => match String::from_utf8(xm_data) {
+            Ok(text) => UserMeta::Text(text),
+            Err(e) => match limits.invalid_text {
+                InvalidText::Error => {
+                    return ReadError::err("content not valid UTF-8", *pos, (0, xm_len));
+                },
+                InvalidText::Bytes => UserMeta::Bytes(e.into_bytes()),
+            },
+        },
+        b"BB" => UserMeta::Bytes(xm_data),
         // even if xm_len > 0 we ignore it
-        UserMeta::None
+        _ => UserMeta::None,
     };
     
     (*pos) += xm_len;
@@ -96,7 +214,7 @@ fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_v
         (*pos) += pad_len;
     }
     
-    let ext_flags = MetaFlags::from_raw(ext_flags);
+    let ext_flags = MetaFlags::from_raw(ext_flags).defined_in(format_ver);
     Ok(CommitMeta::new_explicit(cnum, secs, ext_flags, ext_data, xm)?)
 }
 
@@ -104,23 +222,28 @@ fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_v
 fn write_meta(w: &mut Write, meta: &CommitMeta) -> Result<()> {
     w.write_i64::<BigEndian>(meta.timestamp())?;
     
+    let ext_data = meta.ext_data();
+    assert!(ext_data.len() % 8 == 0 && ext_data.len() <= 255 * 8);
     w.write_all(b"F")?;
-    w.write_all(&[0u8; 1])?; // 0 extension data: we don't use this currently
+    w.write_all(&[(ext_data.len() / 8) as u8])?;
     w.write_u16::<BigEndian>(meta.ext_flags().raw())?;
     w.write_u32::<BigEndian>(meta.number())?;
-    // extension data would go here, but we don't currently have any
+    w.write_all(&ext_data)?;
     
     match *meta.extra() {
         UserMeta::None => {
             // last four zeros is 0u32 encoded in bytes
             w.write_all(b"XM\x00\x00\x00\x00\x00\x00")?;
         },
-        UserMeta::Text(ref txt) => {
-            w.write_all(b"XMTT")?;
-            assert!(txt.len() <= u32::MAX as usize);
-            w.write_u32::<BigEndian>(txt.len() as u32)?;
-            w.write_all(txt.as_bytes())?;
-            let pad_len = 16 * ((txt.len() + 15) / 16) - txt.len();
+        UserMeta::Text(_) | UserMeta::Bytes(_) => {
+            let data = meta.extra().as_bytes();
+            if data.len() > u32::MAX as usize {
+                return ArgError::err("user metadata too long to write");
+            }
+            w.write_all(if let UserMeta::Text(_) = *meta.extra() { b"XMTT" } else { b"XMBB" })?;
+            w.write_u32::<BigEndian>(data.len() as u32)?;
+            w.write_all(data)?;
+            let pad_len = 16 * ((data.len() + 15) / 16) - data.len();
             if pad_len > 0 {
                 let padding = [0u8; 15];
                 w.write_all(&padding[0..pad_len])?;
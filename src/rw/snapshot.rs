@@ -4,16 +4,22 @@
 
 //! Support for reading and writing Rust snapshots
 
-use std::io::{Read, Write};
+use std::cell::Cell;
+use std::io::{self, Read, Write};
 use std::rc::Rc;
 use std::{u8, u32};
 use std::collections::hash_map::{HashMap, Entry};
+use std::collections::BTreeMap;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
-use elt::Element;
-use error::{Result, ReadError, ElementOp, OtherError};
-use rw::{sum, read_meta, write_meta};
+use commit::UserMetaLimits;
+use elt::{EltId, Element, EltMeta};
+use error::{Result, ReadError, ElementOp, RepoError};
+use rw::{sum, read_meta, write_meta, EltReader};
+use rw::header::read_head;
+use rw::compress::decompress;
+use rw::encrypt::decrypt;
 use state::{PartState, StateRead};
 use sum::{Sum, SUM_BYTES};
 
@@ -24,9 +30,16 @@ use sum::{Sum, SUM_BYTES};
 /// according to the specified file format this should be the case.
 /// 
 /// The file version affects how data is read. Get it from a header with
-/// `header.ftype.ver()`.
+/// `header.ftype.ver()`. User metadata is checked against `limits`.
+/// Elements are deserialised via `elts`; skipped elements are kept as
+/// erased elements.
+/// 
+/// If `dedup` is true (see `FileHeader::dedup`), elements may reference the
+/// data of a previous element (see `write_snapshot_dedup`); such elements
+/// share the referenced element's memory.
 pub fn read_snapshot<T: Element>(reader: &mut Read,
-        format_ver: u32) -> Result<PartState<T>>
+        format_ver: u32, dedup: bool, limits: &UserMetaLimits, elts_reader: &mut EltReader)
+        -> Result<PartState<T>>
 {
     // A reader which calculates the checksum of what was read:
     let mut r = sum::HashReader::new(reader);
@@ -40,7 +53,7 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
         return ReadError::err("unexpected contents (expected SNAPSH_U where _ is any)", pos, (0, 8));
     }
     let num_parents = buf[6] as usize;
-    let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver)?;
+    let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver, limits)?;
     
     let mut parents = Vec::with_capacity(num_parents);
     for _ in 0..num_parents {
@@ -56,45 +69,125 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
     pos += 16;
     
     let mut elts = HashMap::new();
+    let mut elt_meta = HashMap::new();
+    let mut erased = HashMap::new();
     let mut combined_elt_sum = Sum::zero();
+    // Elements which may be referenced (by element sum), with their data:
+    let mut stored: HashMap<Sum, (Option<Rc<T>>, Vec<u8>)> = HashMap::new();
     for _ in 0..num_elts {
-        r.read_exact(&mut buf[0..32])?;
-        if buf[0..8] != *b"ELEMENT\x00" {
-            println!("buf: \"{}\", {:?}", String::from_utf8_lossy(&buf[0..8]), &buf[0..8]);
-            return ReadError::err("unexpected contents (expected ELEMENT\\x00)", pos, (0, 8));
-        }
-        let ident = BigEndian::read_u64(&buf[8..16]).into();
-        pos += 16;
-        
-        if buf[16..24] != *b"BYTES\x00\x00\x00" {
-            return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", pos, (16, 24));
+        r.read_exact(&mut buf[0..16])?;
+        // versions from 20261018 may have erased elements (tombstones)
+        if buf[0..8] == *b"ERASED\x00\x00" && format_ver >= 2026_10_18 {
+            let ident = BigEndian::read_u64(&buf[8..16]).into();
+            pos += 16;
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            let elt_sum = Sum::load(&buf[0..SUM_BYTES]);
+            pos += SUM_BYTES;
+            combined_elt_sum.permute(&elt_sum);
+            if elts.contains_key(&ident) || erased.insert(ident, elt_sum).is_some() {
+                return Err(Box::new(ElementOp::IdClash));
+            }
+            continue;
         }
-        let data_len = BigEndian::read_u64(&buf[24..32]) as usize;   // #0015
-        pos += 16;
-        
-        let mut data = vec![0; data_len];
-        r.read_exact(&mut data)?;
-        pos += data_len;
+        // deduplicated snapshots may reference the data of a previous
+        // element by its element sum (ELTREF\x00 or ELTREF\x00M)
+        let is_ref = dedup && buf[0..7] == *b"ELTREF\x00" && (buf[7] == 0 || buf[7] == b'M');
+        let (ident, has_meta, elt_sum, data, shared) = if is_ref {
+            let ident = BigEndian::read_u64(&buf[8..16]).into();
+            let has_meta = buf[7] == b'M';
+            pos += 16;
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            let (target, target_data) = match stored.get(&Sum::load(&buf[0..SUM_BYTES])) {
+                Some(entry) => entry,
+                None => return ReadError::err("element references unknown element", pos, (0, SUM_BYTES)),
+            };
+            pos += SUM_BYTES;
+            
+            let elt_sum = Sum::elt_sum(ident, target_data);
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            if elt_sum != buf[0..SUM_BYTES] {
+                return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
+            }
+            pos += SUM_BYTES;
+            // data is only needed if the referenced element was skipped:
+            let data = if target.is_none() { target_data.clone() } else { Vec::new() };
+            (ident, has_meta, elt_sum, data, target.clone())
+        } else {
+            r.read_exact(&mut buf[16..32])?;
+            // versions from 20261017 may have per-element metadata (ELEMENTM)
+            let has_meta = buf[0..7] == *b"ELEMENT" && buf[7] == b'M' && format_ver >= 2026_10_17;
+            if buf[0..8] != *b"ELEMENT\x00" && !has_meta {
+                println!("buf: \"{}\", {:?}", String::from_utf8_lossy(&buf[0..8]), &buf[0..8]);
+                return ReadError::err("unexpected contents (expected ELEMENT\\x00, ELEMENTM or ERASED)", pos, (0, 8));
+            }
+            let ident = BigEndian::read_u64(&buf[8..16]).into();
+            pos += 16;
+            
+            if buf[16..24] != *b"BYTES\x00\x00\x00" {
+                return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", pos, (16, 24));
+            }
+            let data_len = BigEndian::read_u64(&buf[24..32]) as usize;   // #0015
+            pos += 16;
+            
+            let mut data = vec![0; data_len];
+            r.read_exact(&mut data)?;
+            pos += data_len;
+            
+            let pad_len = 16 * ((data_len + 15) / 16) - data_len;
+            if pad_len > 0 {
+                r.read_exact(&mut buf[0..pad_len])?;
+                pos += pad_len;
+            }
+            
+            let elt_sum = Sum::elt_sum(ident, &data);
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            if elt_sum != buf[0..SUM_BYTES] {
+                return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
+            }
+            pos += SUM_BYTES;
+            (ident, has_meta, elt_sum, data, None)
+        };
         
-        let pad_len = 16 * ((data_len + 15) / 16) - data_len;
-        if pad_len > 0 {
-            r.read_exact(&mut buf[0..pad_len])?;
-            pos += pad_len;
+        if has_meta {
+            r.read_exact(&mut buf[0..16])?;
+            if buf[0..8] != *b"MODIFIED" {
+                return ReadError::err("unexpected contents (expected MODIFIED)", pos, (0, 8));
+            }
+            let number = BigEndian::read_u32(&buf[8..12]);
+            pos += 16;
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            elt_meta.insert(ident, EltMeta::new(number, Sum::load(&buf[0..SUM_BYTES])));
+            pos += SUM_BYTES;
         }
         
-        let elt_sum = Sum::elt_sum(ident, &data);
-        r.read_exact(&mut buf[0..SUM_BYTES])?;
-        if elt_sum != buf[0..SUM_BYTES] {
-            return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
-        }
-        pos += SUM_BYTES;
-        
         combined_elt_sum.permute(&elt_sum);
         
-        let elt = T::from_vec_sum(data, elt_sum)?;
+        if erased.contains_key(&ident) {
+            return Err(Box::new(ElementOp::IdClash));
+        }
+        let elt = match shared {
+            Some(elt) => elt,
+            None => {
+                let kept = if dedup && !is_ref { Some(data.clone()) } else { None };
+                let elt = elts_reader.read(ident, data, elt_sum.clone())?.map(Rc::new);
+                if let Some(data) = kept {
+                    stored.insert(elt_sum.clone(), (elt.clone(), data));
+                }
+                match elt {
+                    Some(elt) => elt,
+                    None => {
+                        if elts.contains_key(&ident) {
+                            return Err(Box::new(ElementOp::IdClash));
+                        }
+                        erased.insert(ident, elt_sum);
+                        continue;
+                    },
+                }
+            },
+        };
         match elts.entry(ident) {
             Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
-            Entry::Vacant(e) => e.insert(Rc::new(elt)),
+            Entry::Vacant(e) => e.insert(elt),
         };
     }
     
@@ -103,7 +196,7 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
         // feature removed
         let n_moves = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
         if n_moves != 0 {
-            return OtherError::err("element move support removed");
+            return RepoError::err(RepoError::EltMovesUnsupported);
         }
         
         // re-fill buffer for next section:
@@ -111,7 +204,7 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
     }
     
     let state = PartState::new_explicit(parents,
-            elts, meta, combined_elt_sum);
+            elts, meta, combined_elt_sum, elt_meta, erased);
     
     if buf[0..8] != *b"STATESUM" {
         return ReadError::err("unexpected contents (expected STATESUM or ELTMOVES)", pos, (0, 8));
@@ -144,13 +237,52 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
 /// 
 /// The snapshot is derived from a partition state, but also includes a
 /// partition identifier range.
+/// 
+/// Elements are always written in order of identifier, with zero padding.
+/// Per-element metadata is written where known (see `PartState::elt_meta`);
+/// since this depends on how the state was derived, two equal states may
+/// produce different files. Use `write_snapshot_reproducible` to avoid this.
 pub fn write_snapshot<T: Element>(state: &PartState<T>,
     writer: &mut Write) -> Result<()>
+{
+    write_snapshot_impl(state, writer, true, false)
+}
+
+/// Write a snapshot such that the output depends only on the state's
+/// elements, parents and commit metadata (i.e. on the state-sum), thus equal
+/// states always produce identical bytes. This omits per-element metadata.
+pub fn write_snapshot_reproducible<T: Element>(state: &PartState<T>,
+    writer: &mut Write) -> Result<()>
+{
+    write_snapshot_impl(state, writer, false, false)
+}
+
+/// Write a snapshot where elements whose data is identical to that of a
+/// previous element (in order of identifier) reference that element by its
+/// element sum instead of repeating the data. The header must have `dedup`
+/// set (see `FileHeader::dedup`). No element index is written.
+/// 
+/// If `reproducible` is true, per-element metadata is omitted as for
+/// `write_snapshot_reproducible`.
+pub fn write_snapshot_dedup<T: Element>(state: &PartState<T>,
+    writer: &mut Write, reproducible: bool) -> Result<()>
+{
+    write_snapshot_impl(state, writer, !reproducible, true)
+}
+
+fn write_snapshot_impl<T: Element>(state: &PartState<T>,
+    writer: &mut Write, with_elt_meta: bool, dedup: bool) -> Result<()>
 {
     trace!("Writing snapshot (with {} elements): {}", state.num_avail(), state.statesum());
     
+    // Position of elements (relative to the start of the snapshot) is
+    // recorded for the index:
+    let pos = Cell::new(0u64);
+    let mut counter = CountingWriter { inner: writer, count: &pos };
+    let mut index = Vec::new();
+    
     // A writer which calculates the checksum of what was written:
-    let mut w = sum::HashWriter::new(writer);
+    let mut w = sum::HashWriter::new(&mut counter);
     
     let mut snapsh_u: [u8; 8] = *b"SNAPSH_U";
     assert!(state.parents().len() <= (u8::MAX as usize));
@@ -165,31 +297,70 @@ pub fn write_snapshot<T: Element>(state: &PartState<T>,
     w.write_all(b"ELEMENTS")?;
     
     let mut elt_buf = Vec::new();
+    let mut stored: HashMap<Sum, Sum> = HashMap::new();
     
-    let mut keys: Vec<_> = state.elts_iter().map(|(k,_)| k).collect();
-    keys.sort();
+    // Elements and erased elements (with their sums), in order:
+    let mut keys: Vec<_> = state.elts_iter().map(|(k,_)| (k, None))
+            .chain(state.erased_iter().map(|(k, sum)| (*k, Some(sum))))
+            .collect();
+    keys.sort_by_key(|k| k.0);
     
     let num_elts = keys.len() as u64;  // #0015
     w.write_u64::<BigEndian>(num_elts)?;
     
-    for ident in keys {
-        w.write_all(b"ELEMENT\x00")?;
-        w.write_u64::<BigEndian>(ident.into())?;
+    for (ident, erased_sum) in keys {
+        if let Some(sum) = erased_sum {
+            w.write_all(b"ERASED\x00\x00")?;
+            w.write_u64::<BigEndian>(ident.into())?;
+            sum.write_to(&mut w)?;
+            continue;
+        }
         
+        let elt_meta = if with_elt_meta { state.elt_meta(ident) } else { None };
         let elt = state.get_rc(ident).expect("get elt by key");
-        w.write_all(b"BYTES\x00\x00\x00")?;
         elt_buf.clear();
         elt.write_buf(&mut &mut elt_buf)?;
-        w.write_u64::<BigEndian>(elt_buf.len() as u64 /* #0015 */)?;
+        let elt_sum = elt.sum(ident);
+        
+        // With deduplication, data is written once, keyed by its checksum:
+        let target = if dedup {
+            match stored.entry(Sum::calculate(&elt_buf)) {
+                Entry::Occupied(e) => Some(e.get().clone()),
+                Entry::Vacant(e) => {
+                    e.insert(elt_sum.clone());
+                    None
+                },
+            }
+        } else { None };
         
-        w.write_all(&elt_buf)?;
-        let pad_len = 16 * ((elt_buf.len() + 15) / 16) - elt_buf.len();
-        if pad_len > 0 {
-            let padding = [0u8; 15];
-            w.write_all(&padding[0..pad_len])?;
+        if let Some(target) = target {
+            w.write_all(if elt_meta.is_some() { b"ELTREF\x00M" } else { b"ELTREF\x00\x00" })?;
+            w.write_u64::<BigEndian>(ident.into())?;
+            target.write_to(&mut w)?;
+        } else {
+            index.push((ident, pos.get()));
+            w.write_all(if elt_meta.is_some() { b"ELEMENTM" } else { b"ELEMENT\x00" })?;
+            w.write_u64::<BigEndian>(ident.into())?;
+            
+            w.write_all(b"BYTES\x00\x00\x00")?;
+            w.write_u64::<BigEndian>(elt_buf.len() as u64 /* #0015 */)?;
+            
+            w.write_all(&elt_buf)?;
+            let pad_len = 16 * ((elt_buf.len() + 15) / 16) - elt_buf.len();
+            if pad_len > 0 {
+                let padding = [0u8; 15];
+                w.write_all(&padding[0..pad_len])?;
+            }
         }
         
-        elt.sum(ident).write_to(&mut w)?;
+        elt_sum.write_to(&mut w)?;
+        
+        if let Some(m) = elt_meta {
+            w.write_all(b"MODIFIED")?;
+            w.write_u32::<BigEndian>(m.number())?;
+            w.write_all(&[0u8; 4])?;
+            m.statesum().write_to(&mut w)?;
+        }
     }
     
     // We write the checksum we kept in memory, the idea being that in-memory
@@ -200,11 +371,342 @@ pub fn write_snapshot<T: Element>(state: &PartState<T>,
     
     // Write the checksum of everything above:
     let sum = w.sum();
-    sum.write_to(&mut w.into_inner())?;
+    let counter = w.into_inner();
+    sum.write_to(counter)?;
+    if dedup || index.len() < INDEX_MIN_ELTS {
+        // references cannot be read via an index, and small snapshots are
+        // cheap to read in full
+        return Ok(());
+    }
+    
+    // Element index (after the checksum, thus ignored by `read_snapshot`):
+    let index_pos = pos.get();
+    let mut buf = Vec::with_capacity(16 * (index.len() + 1));
+    buf.extend_from_slice(b"ELTINDEX");
+    buf.write_u64::<BigEndian>(index.len() as u64)?;
+    for (ident, elt_pos) in index {
+        buf.write_u64::<BigEndian>(ident.into())?;
+        buf.write_u64::<BigEndian>(elt_pos)?;
+    }
+    counter.write_all(&buf)?;
+    Sum::calculate(&buf).write_to(counter)?;
+    counter.write_all(b"IDXSTART")?;
+    counter.write_u64::<BigEndian>(index_pos)?;
+    let len = pos.get() + 16;
+    counter.write_all(b"IDXEND\x00\x00")?;
+    counter.write_u64::<BigEndian>(len)?;
     
     Ok(())
 }
 
+// Passes through writes, counting bytes written
+struct CountingWriter<'a, 'b> {
+    inner: &'a mut Write,
+    count: &'b Cell<u64>,
+}
+impl<'a, 'b> Write for CountingWriter<'a, 'b> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Number of bytes at the end of a snapshot locating its element index (see
+/// `read_index_footer`).
+pub const INDEX_FOOTER_BYTES: usize = 32;
+
+/// Minimum number of element records for which a snapshot has an element
+/// index; smaller snapshots have none.
+pub const INDEX_MIN_ELTS: usize = 16;
+
+/// Locate the element index of a snapshot from the last `INDEX_FOOTER_BYTES`
+/// bytes of a snapshot file. Returns the position of the index and the total
+/// length of the snapshot, both in bytes relative to the start of the
+/// snapshot (after the file header), or `None` if the snapshot has no index.
+/// 
+/// Only snapshots of file format version 2026 10 20 or later may have an
+/// index; the caller should check the version first.
+pub fn read_index_footer(footer: &[u8]) -> Option<(u64, u64)> {
+    if footer.len() != INDEX_FOOTER_BYTES || footer[0..8] != *b"IDXSTART" ||
+            footer[16..24] != *b"IDXEND\x00\x00" {
+        return None;
+    }
+    let index_pos = BigEndian::read_u64(&footer[8..16]);
+    let len = BigEndian::read_u64(&footer[24..32]);
+    if index_pos >= len { None } else { Some((index_pos, len)) }
+}
+
+/// Read the element index of a snapshot: `data` should be the bytes from
+/// the index position (see `read_index_footer`) up to the footer.
+/// 
+/// The result maps each element (excluding tombstones) to the position of its
+/// record relative to the start of the snapshot; see `read_element`.
+pub fn read_index(data: &[u8]) -> Result<BTreeMap<EltId, u64>> {
+    if data.len() < 16 + SUM_BYTES || data[0..8] != *b"ELTINDEX" {
+        return ReadError::err("unexpected contents (expected ELTINDEX)", 0, (0, 8));
+    }
+    let num = BigEndian::read_u64(&data[8..16]) as usize;   // #0015
+    let end = 16 + 16 * num;
+    if data.len() != end + SUM_BYTES {
+        return ReadError::err("element index has unexpected length", 8, (0, 8));
+    }
+    if Sum::calculate(&data[0..end]) != data[end..] {
+        return ReadError::err("element index checksum invalid", end, (0, SUM_BYTES));
+    }
+    let mut index = BTreeMap::new();
+    for rec in data[16..end].chunks(16) {
+        index.insert(BigEndian::read_u64(&rec[0..8]).into(), BigEndian::read_u64(&rec[8..16]));
+    }
+    Ok(index)
+}
+
+/// Number of bytes at the start of an element record which must be read
+/// to determine its length (see `read_element_head`).
+pub const ELEMENT_HEAD_BYTES: usize = 32;
+
+/// Check the start of an element record (the first `ELEMENT_HEAD_BYTES`
+/// bytes) for element `ident`, and return the number of bytes which follow
+/// (data, padding and checksum; any element metadata is not included).
+pub fn read_element_head(ident: EltId, head: &[u8]) -> Result<usize> {
+    if head.len() != ELEMENT_HEAD_BYTES ||
+            (head[0..8] != *b"ELEMENT\x00" && head[0..8] != *b"ELEMENTM") {
+        return ReadError::err("unexpected contents (expected ELEMENT\\x00 or ELEMENTM)", 0, (0, 8));
+    }
+    if BigEndian::read_u64(&head[8..16]) != ident.into() {
+        return ReadError::err("element index points to wrong element", 8, (0, 8));
+    }
+    if head[16..24] != *b"BYTES\x00\x00\x00" {
+        return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", 16, (0, 8));
+    }
+    let data_len = BigEndian::read_u64(&head[24..32]) as usize;   // #0015
+    Ok(16 * ((data_len + 15) / 16) + SUM_BYTES)
+}
+
+/// Read element `ident` from the remainder of its record (following the
+/// head passed to `read_element_head`), verifying its checksum.
+pub fn read_element<T: Element>(ident: EltId, head: &[u8], data: &[u8]) -> Result<T> {
+    let data_len = BigEndian::read_u64(&head[24..32]) as usize;   // #0015
+    if data.len() < data_len + SUM_BYTES {
+        return ReadError::err("element record truncated", ELEMENT_HEAD_BYTES, (0, 0));
+    }
+    let sum_pos = data.len() - SUM_BYTES;
+    let elt_sum = Sum::elt_sum(ident, &data[0..data_len]);
+    if elt_sum != data[sum_pos..] {
+        return ReadError::err("element checksum mismatch", ELEMENT_HEAD_BYTES + sum_pos,
+                (0, SUM_BYTES));
+    }
+    T::from_vec_sum(data[0..data_len].to_vec(), elt_sum)
+}
+
+/// Element sums and data lengths of a snapshot (see `read_snapshot_sums`)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotSums {
+    /// The state-sum
+    pub statesum: Sum,
+    /// For each element, its sum and data length in bytes. Erased elements
+    /// (tombstones) are included with length zero.
+    pub elts: HashMap<EltId, (Sum, u64)>,
+}
+
+/// Read the sums of a snapshot's elements (see `SnapshotSums`), streaming
+/// element data without keeping or deserialising it. This is much cheaper
+/// than `read_snapshot` for large snapshots.
+/// 
+/// The snapshot's checksum is verified, but not sums of individual elements.
+/// Arguments are as for `read_snapshot`.
+pub fn read_snapshot_sums(reader: &mut Read, format_ver: u32, dedup: bool,
+        limits: &UserMetaLimits) -> Result<SnapshotSums>
+{
+    let mut r = sum::HashReader::new(reader);
+    let mut pos: usize = 0;
+    let mut buf = vec![0; 32];
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..6] != *b"SNAPSH" || buf[7] != b'U' {
+        return ReadError::err("unexpected contents (expected SNAPSH_U where _ is any)", pos, (0, 8));
+    }
+    let num_parents = buf[6] as usize;
+    read_meta(&mut r, &mut buf, &mut pos, format_ver, limits)?;
+    for _ in 0..num_parents {
+        r.read_exact(&mut buf[0..SUM_BYTES])?;
+        pos += SUM_BYTES;
+    }
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != *b"ELEMENTS" {
+        return ReadError::err("unexpected contents (expected ELEMENTS)", pos, (0, 8));
+    }
+    let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
+    pos += 16;
+    
+    let mut elts = HashMap::new();
+    // Data lengths by element sum, for references:
+    let mut lens = HashMap::new();
+    for _ in 0..num_elts {
+        r.read_exact(&mut buf[0..16])?;
+        let ident: EltId = BigEndian::read_u64(&buf[8..16]).into();
+        if buf[0..8] == *b"ERASED\x00\x00" && format_ver >= 2026_10_18 {
+            pos += 16;
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            pos += SUM_BYTES;
+            if elts.insert(ident, (Sum::load(&buf[0..SUM_BYTES]), 0)).is_some() {
+                return Err(Box::new(ElementOp::IdClash));
+            }
+            continue;
+        }
+        if dedup && buf[0..7] == *b"ELTREF\x00" && (buf[7] == 0 || buf[7] == b'M') {
+            let has_meta = buf[7] == b'M';
+            pos += 16;
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            let data_len = match lens.get(&Sum::load(&buf[0..SUM_BYTES])) {
+                Some(len) => *len,
+                None => return ReadError::err("element references unknown element", pos, (0, SUM_BYTES)),
+            };
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            pos += 2 * SUM_BYTES;
+            if elts.insert(ident, (Sum::load(&buf[0..SUM_BYTES]), data_len)).is_some() {
+                return Err(Box::new(ElementOp::IdClash));
+            }
+            if has_meta {
+                r.read_exact(&mut buf[0..16])?;
+                r.read_exact(&mut buf[0..SUM_BYTES])?;
+                pos += 16 + SUM_BYTES;
+            }
+            continue;
+        }
+        let has_meta = buf[0..7] == *b"ELEMENT" && buf[7] == b'M' && format_ver >= 2026_10_17;
+        if buf[0..8] != *b"ELEMENT\x00" && !has_meta {
+            return ReadError::err("unexpected contents (expected ELEMENT\\x00, ELEMENTM or ERASED)", pos, (0, 8));
+        }
+        pos += 16;
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..8] != *b"BYTES\x00\x00\x00" {
+            return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", pos, (0, 8));
+        }
+        let data_len = BigEndian::read_u64(&buf[8..16]);
+        pos += 16;
+        
+        // Skip data and padding:
+        let skip = 16 * data_len.div_ceil(16);
+        if io::copy(&mut Read::take(&mut r, skip), &mut io::sink())? != skip {
+            return ReadError::err("unexpected end of snapshot", pos, (0, 0));
+        }
+        pos += skip as usize;
+        r.read_exact(&mut buf[0..SUM_BYTES])?;
+        pos += SUM_BYTES;
+        let elt_sum = Sum::load(&buf[0..SUM_BYTES]);
+        if dedup {
+            lens.insert(elt_sum.clone(), data_len);
+        }
+        if elts.insert(ident, (elt_sum, data_len)).is_some() {
+            return Err(Box::new(ElementOp::IdClash));
+        }
+        if has_meta {
+            r.read_exact(&mut buf[0..16])?;
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            pos += 16 + SUM_BYTES;
+        }
+    }
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] == *b"ELTMOVES" {
+        r.read_exact(&mut buf[0..16])?;
+    }
+    if buf[0..8] != *b"STATESUM" {
+        return ReadError::err("unexpected contents (expected STATESUM or ELTMOVES)", pos, (0, 8));
+    }
+    pos += 16;
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    let statesum = Sum::load(&buf[0..SUM_BYTES]);
+    pos += SUM_BYTES;
+    
+    let sum = r.sum();
+    let r = r.into_inner();
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    if sum != buf[0..SUM_BYTES] {
+        return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
+    }
+    Ok(SnapshotSums { statesum, elts })
+}
+
+/// Statistics on differences between two snapshots (see `SnapshotDiff::new`
+/// and `Partition::snapshot_diff`)
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SnapshotDiff {
+    /// Number of elements only in the new snapshot
+    pub added: usize,
+    /// Number of elements only in the old snapshot
+    pub removed: usize,
+    /// Number of elements in both snapshots, with different data
+    pub changed: usize,
+    /// Number of elements in both snapshots, with the same data
+    pub unchanged: usize,
+    /// Total bytes of element data in the old snapshot
+    pub old_bytes: u64,
+    /// Total bytes of element data in the new snapshot
+    pub new_bytes: u64,
+    /// Bytes of data of added elements and new versions of changed elements
+    pub added_bytes: u64,
+    /// Bytes of data of removed elements and old versions of changed elements
+    pub removed_bytes: u64,
+}
+
+impl SnapshotDiff {
+    /// Compare element sums of an old and a new snapshot
+    pub fn new(old: &SnapshotSums, new: &SnapshotSums) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        for (id, (sum, len)) in &old.elts {
+            diff.old_bytes += *len;
+            match new.elts.get(id) {
+                Some((new_sum, _)) if new_sum == sum => diff.unchanged += 1,
+                Some(&(_, new_len)) => {
+                    diff.changed += 1;
+                    diff.removed_bytes += len;
+                    diff.added_bytes += new_len;
+                },
+                None => {
+                    diff.removed += 1;
+                    diff.removed_bytes += len;
+                },
+            }
+        }
+        for (id, &(_, len)) in &new.elts {
+            diff.new_bytes += len;
+            if !old.elts.contains_key(id) {
+                diff.added += 1;
+                diff.added_bytes += len;
+            }
+        }
+        diff
+    }
+    
+    /// Change in total bytes of element data (new minus old)
+    pub fn byte_delta(&self) -> i64 {
+        self.new_bytes as i64 - self.old_bytes as i64
+    }
+}
+
+/// Compare two snapshot files (including headers), streaming their
+/// contents (see `read_snapshot_sums`). User metadata is checked against
+/// `limits`. Encrypted snapshots are not supported.
+pub fn diff_snapshot_files(old: &mut Read, new: &mut Read, limits: &UserMetaLimits)
+        -> Result<SnapshotDiff>
+{
+    let old_head = read_head(old)?;
+    let old = read_snapshot_sums(
+            &mut decompress(decrypt(old, &old_head, None)?, old_head.compression)?,
+            old_head.ftype.ver(), old_head.dedup, limits)?;
+    let new_head = read_head(new)?;
+    let new = read_snapshot_sums(
+            &mut decompress(decrypt(new, &new_head, None)?, new_head.compression)?,
+            new_head.ftype.ver(), new_head.dedup, limits)?;
+    Ok(SnapshotDiff::new(&old, &new))
+}
+
 #[test]
 fn snapshot_writing() {
     use state::StateWrite;
@@ -251,6 +753,89 @@ fn snapshot_writing() {
     let mut result = Vec::new();
     assert!(write_snapshot(&state, &mut result).is_ok());
     
-    let state2 = read_snapshot(&mut &result[..], HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]).unwrap();
+    let state2 = read_snapshot(&mut &result[..], HEAD_VERSIONS[HEAD_VERSIONS.len() - 1], false,
+            &UserMetaLimits::default(), &mut EltReader::default()).unwrap();
     assert_eq!(state, state2);
+    for (id, _) in state.elts_iter() {
+        assert_eq!(state2.elt_meta(id), Some(&EltMeta::new(1, state.statesum().clone())));
+    }
+    
+    // Small snapshots have no index:
+    let footer_pos = result.len() - INDEX_FOOTER_BYTES;
+    assert_eq!(read_index_footer(&result[footer_pos..]), None);
+    
+    // Elements can be read via the index:
+    let mut state = state.clone_mut();
+    for i in 0..INDEX_MIN_ELTS {
+        state.insert_new(format!("element {}", i)).unwrap();
+    }
+    let state = PartState::from_mut(state, &mut MMTT {});
+    let mut result = Vec::new();
+    assert!(write_snapshot(&state, &mut result).is_ok());
+    let footer_pos = result.len() - INDEX_FOOTER_BYTES;
+    let (index_pos, len) = read_index_footer(&result[footer_pos..]).unwrap();
+    assert_eq!(len as usize, result.len());
+    let index = read_index(&result[index_pos as usize..footer_pos]).unwrap();
+    assert_eq!(index.len(), INDEX_MIN_ELTS + 2);
+    for (id, elt) in state.elts_iter() {
+        let pos = index[&id] as usize;
+        let head = &result[pos..pos + ELEMENT_HEAD_BYTES];
+        let n = read_element_head(id, head).unwrap();
+        let data = &result[pos + ELEMENT_HEAD_BYTES..pos + ELEMENT_HEAD_BYTES + n];
+        assert_eq!(read_element::<String>(id, head, data).unwrap(), **elt);
+    }
+}
+
+#[test]
+fn snapshot_dedup() {
+    use state::StateWrite;
+    use rw::HEAD_VERSIONS;
+    use commit::{CommitMeta, UserMeta, MakeCommitMeta};
+    
+    struct MMNone {}
+    impl MakeCommitMeta for MMNone {
+        fn make_commit_extra(&self, _number: u32, _parents: Vec<(&Sum, &CommitMeta)>) -> UserMeta {
+            UserMeta::None
+        }
+    }
+    
+    let mut state = PartState::<String>::new(&mut MMNone {}).clone_mut();
+    let data = "a payload shared by several elements, long enough to matter".to_string();
+    let mut ids = vec![];
+    for _ in 0..4 {
+        ids.push(state.insert_new(data.clone()).unwrap());
+    }
+    let other = state.insert_new("another".to_string()).unwrap();
+    let state = PartState::from_mut(state, &mut MMNone {});
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    let limits = UserMetaLimits::default();
+    
+    let mut plain = Vec::new();
+    write_snapshot(&state, &mut plain).unwrap();
+    let mut dedup = Vec::new();
+    write_snapshot_dedup(&state, &mut dedup, false).unwrap();
+    assert!(dedup.len() < plain.len());
+    
+    let state2: PartState<String> = read_snapshot(&mut &dedup[..], ver, true, &limits,
+            &mut EltReader::default()).unwrap();
+    assert_eq!(state, state2);
+    assert_eq!(state2.elt_meta(other), state.elt_meta(other));
+    let first = state2.get_rc(ids[0]).unwrap();
+    for id in &ids[1..] {
+        assert!(Rc::ptr_eq(first, state2.get_rc(*id).unwrap()));
+    }
+    
+    // References are only accepted when the header flag is set:
+    assert!(read_snapshot::<String>(&mut &dedup[..], ver, false, &limits,
+            &mut EltReader::default()).is_err());
+    
+    let sums = read_snapshot_sums(&mut &dedup[..], ver, true, &limits).unwrap();
+    assert_eq!(sums, read_snapshot_sums(&mut &plain[..], ver, false, &limits).unwrap());
+    
+    // Output is reproducible:
+    let mut r1 = Vec::new();
+    write_snapshot_dedup(&state, &mut r1, true).unwrap();
+    let mut r2 = Vec::new();
+    write_snapshot_dedup(&state2, &mut r2, true).unwrap();
+    assert_eq!(r1, r2);
 }
@@ -12,7 +12,7 @@ use crypto::blake2b::Blake2b;
 use byteorder::{ByteOrder, BigEndian};
 
 use elt::EltId;
-use commit::{CommitMeta, UserMeta};
+use commit::CommitMeta;
 use sum::{Sum, SUM_BYTES};
 
 
@@ -52,12 +52,8 @@ impl Sum {
             hasher.input(&buf);
         }
         
-        match *meta.extra() {
-            UserMeta::None => {},
-            UserMeta::Text(ref text) => {
-                hasher.input(text.as_bytes());
-            },
-        }
+        // Type is not included; see UserMeta doc
+        hasher.input(meta.extra().as_bytes());
         Sum::load_hasher(hasher)
     }
     /// Calculate a standard checksum
@@ -0,0 +1,283 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Bloom filter over the state-sums of a partition's history
+//! 
+//! A `SumFilter` records the sums of all states written to a partition's
+//! snapshots and commit logs, allowing `Partition::may_have_state` to
+//! answer "definitely not present" without reading old files. It also
+//! records which files it covers: it is only used where it covers all
+//! files present (thus not if other processes or older versions of this
+//! library wrote files without updating it).
+//! 
+//! The filter is stored via `RepoIO::write_sum_filter`. Format: the bytes
+//! `PIPPIN SUMFILTER`, the covered snapshot numbers (a `u32` count, then a
+//! `u32` each), the covered commit logs (a `u32` count, then a pair of
+//! `u32`s each), the layers (a `u32` count, then for each the capacity,
+//! number of sums added and number of 64-bit words as `u32`s followed by
+//! the words), then a checksum of all preceding bytes. Numbers are
+//! big-endian.
+//! 
+//! Layers are standard bloom filters using `HASHES` bit positions per sum,
+//! derived from the sum's first 16 bytes. When a layer is full a new layer
+//! of double the capacity is added (a *scalable* bloom filter), keeping the
+//! false-positive rate below about 2%.
+
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use error::{Result, ReadError};
+use io::RepoIO;
+use sum::{Sum, SUM_BYTES};
+
+/// Number of bit positions set per sum
+pub const HASHES: u32 = 7;
+// Bits per sum of layer capacity (about 1% false positives with 7 hashes)
+const BITS_PER_SUM: usize = 10;
+// Capacity of the first layer
+const FIRST_CAPACITY: u32 = 1024;
+const MAGIC: [u8; 16] = *b"PIPPIN SUMFILTER";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Layer {
+    capacity: u32,
+    count: u32,
+    bits: Vec<u64>,
+}
+
+impl Layer {
+    fn new(capacity: u32) -> Layer {
+        let words = (capacity as usize * BITS_PER_SUM).div_ceil(64);
+        Layer { capacity, count: 0, bits: vec![0; words] }
+    }
+    
+    // Bit positions of `sum`
+    fn positions<'a>(&self, sum: &'a Sum) -> impl Iterator<Item = usize> + 'a {
+        let bytes = sum.as_bytes();
+        let h1 = BigEndian::read_u64(&bytes[0..8]);
+        let h2 = BigEndian::read_u64(&bytes[8..16]) | 1;
+        let n = self.bits.len() as u64 * 64;
+        (0..HASHES as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % n) as usize)
+    }
+    
+    fn contains(&self, sum: &Sum) -> bool {
+        self.positions(sum).all(|p| self.bits[p / 64] & (1 << (p % 64)) != 0)
+    }
+    
+    fn insert(&mut self, sum: &Sum) {
+        let positions: Vec<usize> = self.positions(sum).collect();
+        for p in positions {
+            self.bits[p / 64] |= 1 << (p % 64);
+        }
+        self.count += 1;
+    }
+}
+
+/// Bloom filter over state-sums, with the files it covers (see module
+/// documentation)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SumFilter {
+    layers: Vec<Layer>,
+    snapshots: BTreeSet<usize>,
+    logs: BTreeSet<(usize, usize)>,
+}
+
+impl Default for SumFilter {
+    fn default() -> Self {
+        SumFilter::new()
+    }
+}
+
+impl SumFilter {
+    /// Create, empty
+    pub fn new() -> SumFilter {
+        SumFilter { layers: vec![Layer::new(FIRST_CAPACITY)], snapshots: BTreeSet::new(),
+                logs: BTreeSet::new() }
+    }
+    
+    /// Add a sum
+    pub fn insert(&mut self, sum: &Sum) {
+        if self.contains(sum) {
+            return;
+        }
+        let layer = self.layers.last().expect("has layer");
+        if layer.count >= layer.capacity {
+            let capacity = layer.capacity.saturating_mul(2);
+            self.layers.push(Layer::new(capacity));
+        }
+        self.layers.last_mut().expect("has layer").insert(sum);
+    }
+    
+    /// True if `sum` may have been added; false if definitely not
+    pub fn contains(&self, sum: &Sum) -> bool {
+        self.layers.iter().any(|layer| layer.contains(sum))
+    }
+    
+    /// Number of sums added (excluding those matching a sum already added)
+    pub fn len(&self) -> usize {
+        self.layers.iter().map(|layer| layer.count as usize).sum()
+    }
+    
+    /// True if no sum was added
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    /// Record that sums of snapshot `ss` have been added
+    pub fn cover_snapshot(&mut self, ss: usize) {
+        self.snapshots.insert(ss);
+    }
+    
+    /// Record that sums of commit log `cl` of snapshot `ss` have been added
+    pub fn cover_log(&mut self, ss: usize, cl: usize) {
+        self.logs.insert((ss, cl));
+    }
+    
+    /// Remove coverage of all commit logs of snapshot `ss` (e.g. when
+    /// these are replaced)
+    pub fn uncover_logs(&mut self, ss: usize) {
+        self.logs.retain(|&(s, _)| s != ss);
+    }
+    
+    /// True if all files listed by `io` are covered
+    pub fn covers(&self, io: &RepoIO) -> bool {
+        for ss in 0..io.ss_len() {
+            if io.has_ss(ss) && !self.snapshots.contains(&ss) {
+                return false;
+            }
+            for cl in 0..io.ss_cl_len(ss) {
+                if !self.logs.contains(&(ss, cl)) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+    
+    /// Write the filter to a stream
+    pub fn write_to(&self, writer: &mut Write) -> Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&MAGIC);
+        buf.write_u32::<BigEndian>(self.snapshots.len() as u32)?;
+        for ss in &self.snapshots {
+            buf.write_u32::<BigEndian>(*ss as u32)?;
+        }
+        buf.write_u32::<BigEndian>(self.logs.len() as u32)?;
+        for &(ss, cl) in &self.logs {
+            buf.write_u32::<BigEndian>(ss as u32)?;
+            buf.write_u32::<BigEndian>(cl as u32)?;
+        }
+        buf.write_u32::<BigEndian>(self.layers.len() as u32)?;
+        for layer in &self.layers {
+            buf.write_u32::<BigEndian>(layer.capacity)?;
+            buf.write_u32::<BigEndian>(layer.count)?;
+            buf.write_u32::<BigEndian>(layer.bits.len() as u32)?;
+            for word in &layer.bits {
+                buf.write_u64::<BigEndian>(*word)?;
+            }
+        }
+        let sum = Sum::calculate(&buf);
+        writer.write_all(&buf)?;
+        sum.write_to(writer)?;
+        Ok(())
+    }
+    
+    /// Read a filter from a stream, verifying its checksum
+    pub fn read_from(reader: &mut Read) -> Result<SumFilter> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if data.len() < MAGIC.len() + SUM_BYTES || data[0..16] != MAGIC {
+            return ReadError::err("unexpected contents (expected PIPPIN SUMFILTER)", 0, (0, 16));
+        }
+        let body_len = data.len() - SUM_BYTES;
+        if Sum::calculate(&data[..body_len]) != data[body_len..] {
+            return ReadError::err("sum filter checksum invalid", body_len, (0, SUM_BYTES));
+        }
+        let mut r = Words { data: &data[..body_len], pos: 16 };
+        let mut filter = SumFilter { layers: vec![], snapshots: BTreeSet::new(),
+                logs: BTreeSet::new() };
+        for _ in 0..r.u32()? {
+            filter.snapshots.insert(r.u32()? as usize);
+        }
+        for _ in 0..r.u32()? {
+            let ss = r.u32()? as usize;
+            filter.logs.insert((ss, r.u32()? as usize));
+        }
+        for _ in 0..r.u32()? {
+            let (capacity, count, words) = (r.u32()?, r.u32()?, r.u32()? as usize);
+            if words == 0 || words > r.remaining() / 8 {
+                return ReadError::err("invalid sum filter layer", r.pos, (0, 4));
+            }
+            let mut bits = Vec::with_capacity(words);
+            for _ in 0..words {
+                bits.push(r.u64()?);
+            }
+            filter.layers.push(Layer { capacity, count, bits });
+        }
+        if filter.layers.is_empty() || r.remaining() != 0 {
+            return ReadError::err("invalid sum filter", r.pos, (0, 0));
+        }
+        Ok(filter)
+    }
+}
+
+// Reader of big-endian numbers from a buffer
+struct Words<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> Words<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+    fn u32(&mut self) -> Result<u32> {
+        if self.remaining() < 4 {
+            return ReadError::err("sum filter truncated", self.pos, (0, 4));
+        }
+        self.pos += 4;
+        Ok(BigEndian::read_u32(&self.data[self.pos - 4..self.pos]))
+    }
+    fn u64(&mut self) -> Result<u64> {
+        if self.remaining() < 8 {
+            return ReadError::err("sum filter truncated", self.pos, (0, 8));
+        }
+        self.pos += 8;
+        Ok(BigEndian::read_u64(&self.data[self.pos - 8..self.pos]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::DummyRepoIO;
+    
+    #[test]
+    fn insert_contains() {
+        let mut filter = SumFilter::new();
+        let sums: Vec<Sum> = (0..5000u32).map(|i| Sum::calculate(&i.to_be_bytes())).collect();
+        for sum in &sums {
+            filter.insert(sum);
+        }
+        // Sums matching those already added (false positives) are not counted
+        assert!(filter.len() > 4800 && filter.len() <= 5000);
+        assert!(filter.layers.len() > 1);
+        assert!(sums.iter().all(|sum| filter.contains(sum)));
+        let false_pos = (5000..15000u32)
+                .filter(|i| filter.contains(&Sum::calculate(&i.to_be_bytes())))
+                .count();
+        assert!(false_pos < 300, "false positives: {}", false_pos);
+        
+        filter.cover_snapshot(0);
+        filter.cover_log(0, 0);
+        assert!(filter.covers(&DummyRepoIO::new()));
+        let mut buf = Vec::new();
+        filter.write_to(&mut buf).unwrap();
+        assert_eq!(SumFilter::read_from(&mut &buf[..]).unwrap(), filter);
+        let n = buf.len();
+        buf[n - 40] ^= 1;
+        assert!(SumFilter::read_from(&mut &buf[..]).is_err());
+    }
+}
@@ -15,17 +15,18 @@
 //! This module also contains the `StateRead` and `StateWrite` traits which
 //! abstract over operations on partition and repository states.
 
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map as hs;
 use std::clone::Clone;
+use std::mem::{self, size_of};
 use std::rc::Rc;
 
 use hashindexed::KeyComparator;
 
-use elt::{Element, EltId};
+use elt::{Element, EltId, EltMeta};
 use sum::Sum;
 use commit::*;
-use error::{ElementOp, PatchOp};
+use error::{ElementOp, PatchOp, ArgError};
 
 /// Trait abstracting over read operations on the state of a partition or
 /// repository.
@@ -59,6 +60,12 @@ pub trait StateRead<E: Element> {
     /// Low-level version of `get(id)`: returns a reference to the
     /// reference-counted wrapped container of the element.
     fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp>;
+    
+    /// Get references to multiple elements. Results are in the same order as
+    /// `ids`; each is `None` if the element is not available.
+    fn get_many(&self, ids: &[EltId]) -> Vec<Option<&Rc<E>>> {
+        ids.iter().map(|id| self.get_rc(*id).ok()).collect()
+    }
 }
 
 /// Trait abstracting over write operations on the state of a partition or
@@ -123,12 +130,18 @@ pub trait StateWrite<E: Element>: StateRead<E> {
 /// 
 /// Essentially this holds a map of elements indexed by their identifiers,
 /// partition-metadata and commit-metadata.
-#[derive(PartialEq, Debug)]
+/// 
+/// Equality compares the state's data and metadata but not per-element
+/// metadata (`elt_meta`), which is derived information and may not be known.
+#[derive(Debug)]
 pub struct PartState<E: Element> {
     parents: Vec<Sum>,
     statesum: Sum,
     elts: HashMap<EltId, Rc<E>>,
     meta: CommitMeta,
+    elt_meta: HashMap<EltId, EltMeta>,
+    // Elements whose data was erased, with their sums (see `erase`)
+    erased: HashMap<EltId, Sum>,
 }
 
 /// An editable version of `PartState`.
@@ -150,6 +163,24 @@ pub struct MutPartState<E: Element> {
     elt_sum: Sum,
     elts: HashMap<EltId, Rc<E>>,
     meta: CommitMetaPartial,
+    elt_meta: HashMap<EltId, EltMeta>,
+    // Elements whose data was erased, with their sums
+    erased: HashMap<EltId, Sum>,
+    // Elements inserted, replaced or removed since cloning from the parent
+    changed: HashSet<EltId>,
+    // Operations applied to elements since cloning from the parent, for
+    // elements changed only via operations (see `apply_op`)
+    ops: HashMap<EltId, Vec<Vec<u8>>>,
+    // Copy of the parent, if cloned via `clone_mut_detached`
+    detached: Option<Box<PartState<E>>>,
+}
+
+impl<E: Element> PartialEq for PartState<E> {
+    fn eq(&self, other: &PartState<E>) -> bool {
+        self.parents == other.parents && self.statesum == other.statesum &&
+            self.elts == other.elts && self.meta == other.meta &&
+            self.erased == other.erased
+    }
 }
 
 // Constructors
@@ -168,6 +199,8 @@ impl<E: Element> PartState<E> {
             statesum: metasum /* no elts, so statesum = metasum */,
             elts: HashMap::new(),
             meta: meta,
+            elt_meta: HashMap::new(),
+            erased: HashMap::new(),
         }
     }
     
@@ -175,15 +208,23 @@ impl<E: Element> PartState<E> {
     /// 
     /// This is for internal use; don't use externally unless you're really
     /// sure of what you're doing.
+    /// 
+    /// `elt_meta` may contain entries for any subset of elements. `erased`
+    /// lists sums of erased elements (see `erase`); these must be included
+    /// in `elt_sum`.
     pub fn new_explicit(parents: Vec<Sum>,
             elts: HashMap<EltId, Rc<E>>,
-            meta: CommitMeta, elt_sum: Sum) -> PartState<E> {
+            meta: CommitMeta, elt_sum: Sum,
+            elt_meta: HashMap<EltId, EltMeta>,
+            erased: HashMap<EltId, Sum>) -> PartState<E> {
         let metasum = Sum::state_meta_sum(&parents, &meta);
         PartState {
             parents: parents,
             statesum: &metasum ^ &elt_sum,
             elts: elts,
-            meta: meta
+            meta: meta,
+            elt_meta: elt_meta,
+            erased,
         }
     }
     
@@ -192,11 +233,22 @@ impl<E: Element> PartState<E> {
         let meta = CommitMeta::from_partial(mut_state.meta, mcm);
         let parents = vec![mut_state.parent.clone()];
         let metasum = Sum::state_meta_sum(&parents, &meta);
+        let statesum = &mut_state.elt_sum ^ &metasum;
+        let mut elt_meta = mut_state.elt_meta;
+        for id in mut_state.changed {
+            if mut_state.elts.contains_key(&id) {
+                elt_meta.insert(id, EltMeta::new(meta.number(), statesum.clone()));
+            } else {
+                elt_meta.remove(&id);
+            }
+        }
         PartState {
             parents: parents,
-            statesum: &mut_state.elt_sum ^ &metasum,
+            statesum: statesum,
             elts: mut_state.elts,
-            meta: meta
+            meta: meta,
+            elt_meta: elt_meta,
+            erased: mut_state.erased,
         }
     }
     /// Create a `PartState` from a parent `PartState` and a `Commit`.
@@ -211,11 +263,21 @@ impl<E: Element> PartState<E> {
         let statesum = &mut_state.elt_sum ^ &metasum;
         if statesum != *commit.statesum() { return Err(PatchOp::PatchApply); }
         
+        let mut elt_meta = mut_state.elt_meta;
+        for (id, _) in commit.changes_iter() {
+            if mut_state.elts.contains_key(id) {
+                elt_meta.insert(*id, EltMeta::new(commit.meta().number(), statesum.clone()));
+            } else {
+                elt_meta.remove(id);
+            }
+        }
         Ok(PartState {
             parents: commit.parents().to_vec(),
             statesum: statesum,
             elts: mut_state.elts,
-            meta: commit.meta().clone()
+            meta: commit.meta().clone(),
+            elt_meta: elt_meta,
+            erased: mut_state.erased,
         })
     }
 }
@@ -234,7 +296,14 @@ impl<E: Element> PartState<E> {
             panic!("Unable to mutate meta!");   // out of numbers; what can we do?
         }
         let new_metasum = Sum::state_meta_sum(&self.parents, &self.meta);
+        let old_statesum = self.statesum.clone();
         self.statesum = &(&self.statesum ^ &old_metasum) ^ &new_metasum;
+        // Elements modified by this state's commit refer to the old sum:
+        for m in self.elt_meta.values_mut() {
+            if *m.statesum() == old_statesum {
+                *m = EltMeta::new(self.meta.number(), self.statesum.clone());
+            }
+        }
         (self.meta.number(), self.statesum.clone())
     }
     
@@ -251,18 +320,41 @@ impl<E: Element> PartState<E> {
     pub fn parents(&self) -> &[Sum] { &self.parents }
     /// Get the commit meta-data associated with this state
     pub fn meta(&self) -> &CommitMeta { &self.meta }
+    /// Set or clear the commit summary in the meta-data (see
+    /// `CommitMeta::summary`). This does not affect the state sum.
+    pub fn set_summary(&mut self, summary: Option<CommitSummary>) {
+        self.meta.set_summary(summary);
+    }
+    /// Get the per-element metadata (provenance) of an element, if known.
+    /// 
+    /// This is `None` if the element is not present or if the commit which
+    /// last modified it is unknown (e.g. it was loaded from an old snapshot).
+    pub fn elt_meta(&self, id: EltId) -> Option<&EltMeta> {
+        self.elt_meta.get(&id)
+    }
     
     /// Iterate over all elements
     pub fn elts_iter(&self) -> EltIter<E> {
         EltIter { iter: self.elts.iter() }
     }
     
+    /// Approximate memory used by this state in bytes, *excluding* elements
+    /// (which may be shared with other states; see `Element::mem_size()`).
+    pub fn mem_overhead(&self) -> usize {
+        size_of::<Self>() +
+            self.parents.capacity() * size_of::<Sum>() +
+            self.elts.capacity() * (size_of::<EltId>() + size_of::<Rc<E>>()) +
+            self.elt_meta.capacity() * (size_of::<EltId>() + size_of::<EltMeta>()) +
+            self.erased.capacity() * (size_of::<EltId>() + size_of::<Sum>())
+    }
+    
     /// As `gen_id()`, but ensure the generated id is free in both self and
     /// another state.
     pub fn gen_id_binary(&self, s2: &PartState<E>) -> Result<EltId, ElementOp> {
         let mut id = EltId::random();;
         for _ in 0..10000 {
-            if !self.elts.contains_key(&id) && !s2.elts.contains_key(&id)
+            if !self.elts.contains_key(&id) && !s2.elts.contains_key(&id) &&
+                !self.erased.contains_key(&id) && !s2.erased.contains_key(&id)
             {
                 return Ok(id)
             }
@@ -286,9 +378,28 @@ impl<E: Element> PartState<E> {
             elt_sum: self.statesum() ^ &self.metasum(),
             elts: self.elts.clone(),
             meta: CommitMeta::new_partial(self.statesum.clone(), self.meta.clone()),
+            elt_meta: self.elt_meta.clone(),
+            erased: self.erased.clone(),
+            changed: HashSet::new(),
+            ops: HashMap::new(),
+            detached: None,
         }
     }
     
+    /// As `clone_mut`, but the new state also holds a copy of this state
+    /// (its parent). This allows checking out any historical state in order
+    /// to continue editing from it: `Partition::push_state` accepts the
+    /// result even if this state has since been dropped from memory (e.g. by
+    /// `Partition::evict_history`), making it a new tip.
+    /// 
+    /// Since the partition's existing tip remains, a merge is then required
+    /// (unless the tip was an ancestor of this state).
+    pub fn clone_mut_detached(&self) -> MutPartState<E> {
+        let mut state = self.clone_mut();
+        state.detached = Some(Box::new(self.clone_exact()));
+        state
+    }
+    
     /// Clone the state, creating an exact copy. The new state will have the
     /// same parents as the current one.
     /// 
@@ -301,8 +412,43 @@ impl<E: Element> PartState<E> {
             statesum: self.statesum.clone(),
             elts: self.elts.clone(),
             meta: self.meta.clone(),
+            elt_meta: self.elt_meta.clone(),
+            erased: self.erased.clone(),
+        }
+    }
+    
+    /// Erase an element's data: the element is removed but its sum is
+    /// retained (as a *tombstone*), thus the state-sum is unchanged. Used by
+    /// `Partition::erase_element_history`.
+    /// 
+    /// Returns true if the element was present.
+    pub fn erase(&mut self, id: EltId) -> bool {
+        match self.elts.remove(&id) {
+            Some(elt) => {
+                self.elt_meta.remove(&id);
+                self.erased.insert(id, elt.sum(id));
+                true
+            },
+            None => false,
         }
     }
+    
+    /// Set acknowledgements recorded in this state's metadata (see
+    /// `CommitMeta::set_acks`). This does not affect the state sum.
+    pub fn set_acks(&mut self, acks: Vec<(ReplicaId, Sum)>) -> Result<(), ArgError> {
+        self.meta.set_acks(acks)
+    }
+    
+    /// Get the sum of an erased element, if this element was erased (see
+    /// `erase`). Erased elements are not otherwise visible.
+    pub fn erased_sum(&self, id: EltId) -> Option<&Sum> {
+        self.erased.get(&id)
+    }
+    
+    /// Iterate over erased elements and their sums
+    pub fn erased_iter(&self) -> hs::Iter<EltId, Sum> {
+        self.erased.iter()
+    }
 }
     
 impl<E: Element> MutPartState<E> {
@@ -317,6 +463,21 @@ impl<E: Element> MutPartState<E> {
         EltIter { iter: self.elts.iter() }
     }
     
+    /// Get the identifiers of elements inserted, replaced, removed or
+    /// modified via an operation since cloning from the parent state.
+    /// 
+    /// This may include elements whose final value equals that in the
+    /// parent (e.g. an element replaced then restored, or inserted then
+    /// removed); compare with the parent where this matters.
+    pub fn changed_ids(&self) -> &HashSet<EltId> {
+        &self.changed
+    }
+    
+    /// True if the element with this identifier is in `changed_ids()`
+    pub fn is_changed(&self, id: EltId) -> bool {
+        self.changed.contains(&id)
+    }
+    
     /// Get access to (partial) metadata
     pub fn meta(&self) -> &CommitMetaPartial { &self.meta }
     /// Get write access to metadata
@@ -336,13 +497,117 @@ impl<E: Element> MutPartState<E> {
     /// assuming random distribution of ids.
     pub fn free_id_near(&mut self, mut id: EltId) -> Result<EltId, ElementOp> {
         for _ in 0..10000 {
-            if !self.elts.contains_key(&id) {
+            if !self.elts.contains_key(&id) && !self.erased.contains_key(&id) {
                 return Ok(id);
             }
             id = id.next_elt();
         }
         Err(ElementOp::IdGenFailure)
     }
+    
+    /// Insert or replace an element. `f` is passed the current element with
+    /// this identifier, if any, and returns the new element.
+    /// 
+    /// Returns the replaced element, if any.
+    pub fn upsert<F>(&mut self, id: EltId, f: F) -> Option<Rc<E>>
+        where F: FnOnce(Option<&E>) -> E
+    {
+        let elt = Rc::new(f(self.elts.get(&id).map(|elt| &**elt)));
+        if self.elts.contains_key(&id) {
+            self.replace_rc(id, elt).ok()
+        } else {
+            self.insert_rc(id, elt).expect("id is free");
+            None
+        }
+    }
+    
+    /// Replace an element only if its current value has the sum `expected`
+    /// (as given by `Element::sum(id)`), i.e. compare-and-swap.
+    /// 
+    /// This allows writers working from separate states to detect that an
+    /// element was modified since they read it, instead of overwriting it.
+    /// Fails with `ElementOp::SumMismatch` in this case, or with
+    /// `ElementOp::EltNotFound` if there is no element with this identifier.
+    /// On success, returns the replaced element.
+    pub fn replace_if(&mut self, id: EltId, expected: &Sum, elt: E) -> Result<Rc<E>, ElementOp> {
+        if self.get_rc(id)?.sum(id) != *expected {
+            return Err(ElementOp::SumMismatch);
+        }
+        self.replace_rc(id, Rc::new(elt))
+    }
+    
+    /// True if an element with this identifier was erased (see
+    /// `PartState::erase`)
+    pub fn is_erased(&self, id: EltId) -> bool {
+        self.erased.contains_key(&id)
+    }
+    
+    /// Insert or replace an erased element, given its sum. This is used when
+    /// applying commits whose data was erased.
+    /// 
+    /// Fails with `ElementOp::IdClash` if a (non-erased) element with this
+    /// identifier is present.
+    pub fn set_erased(&mut self, id: EltId, sum: Sum) -> Result<(), ElementOp> {
+        if self.elts.contains_key(&id) {
+            return Err(ElementOp::IdClash);
+        }
+        if let Some(old) = self.erased.insert(id, sum.clone()) {
+            self.elt_sum.permute(&old);
+        }
+        self.elt_sum.permute(&sum);
+        self.changed.insert(id);
+        self.ops.remove(&id);
+        Ok(())
+    }
+    
+    /// Remove an erased element. Fails with `ElementOp::EltNotFound` if no
+    /// erased element has this identifier.
+    pub fn remove_erased(&mut self, id: EltId) -> Result<(), ElementOp> {
+        let old = self.erased.remove(&id).ok_or(ElementOp::EltNotFound)?;
+        self.elt_sum.permute(&old);
+        self.changed.insert(id);
+        self.ops.remove(&id);
+        Ok(())
+    }
+    
+    /// Modify an element by applying an operation (see `ApplyOp`), returning
+    /// the replaced element.
+    /// 
+    /// If the element is changed only via operations in this state, the
+    /// commit created from this state records the operations instead of the
+    /// new element (see `EltChange::Operation`); otherwise this is equivalent
+    /// to `replace`.
+    /// 
+    /// Fails with `ElementOp::EltNotFound` if there is no element with this
+    /// identifier or with `ElementOp::OpFailed` if the element type does not
+    /// support operations or `op` fails to apply.
+    pub fn apply_op(&mut self, id: EltId, op: Vec<u8>) -> Result<Rc<E>, ElementOp> {
+        let f = E::apply_op_fn().ok_or(ElementOp::OpFailed)?;
+        let elt = f(&**self.get_rc(id)?, &op).map_err(|_| ElementOp::OpFailed)?;
+        let ops = if self.changed.contains(&id) {
+            self.ops.remove(&id)
+        } else {
+            Some(Vec::new())
+        };
+        let old = self.replace_rc(id, Rc::new(elt))?;
+        if let Some(mut ops) = ops {
+            ops.push(op);
+            self.ops.insert(id, ops);
+        }
+        Ok(old)
+    }
+    
+    /// Take the record of operations applied since cloning (see `apply_op`).
+    /// Used by `Partition::push_state`.
+    pub fn take_ops(&mut self) -> HashMap<EltId, Vec<Vec<u8>>> {
+        mem::take(&mut self.ops)
+    }
+    
+    /// Take the copy of the parent held if cloned via `clone_mut_detached`.
+    /// Used by `Partition::push_state`.
+    pub fn take_detached(&mut self) -> Option<PartState<E>> {
+        self.detached.take().map(|parent| *parent)
+    }
 }
 
 impl<E: Element> StateRead<E> for PartState<E> {
@@ -375,9 +640,13 @@ impl<E: Element> StateRead<E> for MutPartState<E> {
 }
 impl<E: Element> StateWrite<E> for MutPartState<E> {
     fn insert_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<EltId, ElementOp> {
-        if self.elts.contains_key(&id) { return Err(ElementOp::IdClash); }
+        if self.elts.contains_key(&id) || self.erased.contains_key(&id) {
+            return Err(ElementOp::IdClash);
+        }
         self.elt_sum.permute(&elt.sum(id));
         self.elts.insert(id, elt);
+        self.changed.insert(id);
+        self.ops.remove(&id);
         Ok(id)
     }
     
@@ -388,7 +657,13 @@ impl<E: Element> StateWrite<E> for MutPartState<E> {
     
     fn replace_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<Rc<E>, ElementOp> {
         match self.elts.entry(id) {
-            hs::Entry::Occupied(ref mut entry) => Ok(entry.insert(elt)),
+            hs::Entry::Occupied(ref mut entry) => {
+                self.elt_sum.permute(&entry.get().sum(id));
+                self.elt_sum.permute(&elt.sum(id));
+                self.changed.insert(id);
+                self.ops.remove(&id);
+                Ok(entry.insert(elt))
+            },
             hs::Entry::Vacant(_) => Err(ElementOp::EltNotFound),
         }
     }
@@ -398,6 +673,8 @@ impl<E: Element> StateWrite<E> for MutPartState<E> {
             None => Err(ElementOp::EltNotFound),
             Some(removed) => {
                 self.elt_sum.permute(&removed.sum(id));
+                self.changed.insert(id);
+                self.ops.remove(&id);
                 Ok(removed)
             }
         }
@@ -0,0 +1,166 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Subscriptions to changes of particular elements
+//! 
+//! A caller registers a set of element identifiers with
+//! `Partition::subscribe` and may then collect a compact `Notification` for
+//! each new commit affecting any of these elements via
+//! `Partition::take_notifications`, without needing to scan commits itself.
+//! 
+//! Alternatively, `Partition::watch` returns a channel `Receiver` to which a
+//! `Notification` is sent for each commit applied affecting elements matching
+//! a `WatchFilter`, including commits loaded from storage or received from
+//! other replicas. This suits e.g. a GUI updating views incrementally.
+//! 
+//! Elements do not move between partitions (identifiers are fixed), thus an
+//! element transferred elsewhere (e.g. by `Partition::split_off`) is reported
+//! as removed.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+use commit::{Commit, EltChange};
+use elt::{EltId, Element};
+use sum::Sum;
+
+
+/// Identifies a subscription within a partition
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SubscriptionId(usize);
+
+/// How an element was affected by a commit
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EltNotice {
+    /// Element was inserted or replaced
+    Changed,
+    /// Element was removed
+    Removed,
+}
+
+/// Notification of a commit affecting subscribed elements
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Notification {
+    /// Sum of the state created by the commit
+    pub statesum: Sum,
+    /// Subscribed elements affected by the commit, in order of identifier
+    pub changes: Vec<(EltId, EltNotice)>,
+}
+
+/// Elements of interest to a watcher (see `Partition::watch`)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum WatchFilter {
+    /// All elements
+    All,
+    /// Only the given elements
+    Elements(HashSet<EltId>),
+}
+
+impl WatchFilter {
+    /// True if element `id` passes the filter
+    pub fn matches(&self, id: EltId) -> bool {
+        match *self {
+            WatchFilter::All => true,
+            WatchFilter::Elements(ref ids) => ids.contains(&id),
+        }
+    }
+}
+
+/// A set of subscriptions, each with a queue of pending notifications, and
+/// of watchers, each with a channel.
+/// 
+/// Notifications are queued until collected, thus memory usage grows if a
+/// subscription is not polled. Watchers are dropped once their receiver is.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    next: usize,
+    subs: HashMap<SubscriptionId, (HashSet<EltId>, Vec<Notification>)>,
+    watchers: Vec<(WatchFilter, Sender<Notification>)>,
+}
+
+impl Subscriptions {
+    /// Create, with no subscriptions
+    pub fn new() -> Self {
+        Default::default()
+    }
+    
+    /// True if there are no subscriptions or watchers
+    pub fn is_empty(&self) -> bool {
+        self.subs.is_empty() && self.watchers.is_empty()
+    }
+    
+    /// Add a subscription to the given element identifiers
+    pub fn add(&mut self, ids: HashSet<EltId>) -> SubscriptionId {
+        let sub = SubscriptionId(self.next);
+        self.next += 1;
+        self.subs.insert(sub, (ids, Vec::new()));
+        sub
+    }
+    
+    /// Remove a subscription, discarding any pending notifications. Returns
+    /// false if not found.
+    pub fn remove(&mut self, sub: SubscriptionId) -> bool {
+        self.subs.remove(&sub).is_some()
+    }
+    
+    /// Access the set of element identifiers of a subscription, allowing
+    /// modification. Returns `None` if not found.
+    pub fn ids_mut(&mut self, sub: SubscriptionId) -> Option<&mut HashSet<EltId>> {
+        self.subs.get_mut(&sub).map(|s| &mut s.0)
+    }
+    
+    /// Take all pending notifications of a subscription, oldest first.
+    /// Returns `None` if not found.
+    pub fn take(&mut self, sub: SubscriptionId) -> Option<Vec<Notification>> {
+        self.subs.get_mut(&sub).map(|s| s.1.drain(..).collect())
+    }
+    
+    /// Add a watcher, returning the receiving end of its channel
+    pub fn watch(&mut self, filter: WatchFilter) -> Receiver<Notification> {
+        let (sender, receiver) = channel();
+        self.watchers.push((filter, sender));
+        receiver
+    }
+    
+    /// Queue notifications for a new commit, and notify watchers
+    pub fn notify<E: Element>(&mut self, commit: &Commit<E>) {
+        for &mut (ref ids, ref mut queue) in self.subs.values_mut() {
+            if let Some(n) = notification(commit, |id| ids.contains(&id)) {
+                queue.push(n);
+            }
+        }
+        self.notify_loaded(commit);
+    }
+    
+    /// Notify watchers (only) of a commit loaded from storage
+    pub fn notify_loaded<E: Element>(&mut self, commit: &Commit<E>) {
+        self.watchers.retain(|(filter, sender)| {
+            match notification(commit, |id| filter.matches(id)) {
+                Some(n) => sender.send(n).is_ok(),
+                None => true,
+            }
+        });
+    }
+}
+
+// Make a notification of the changes of `commit` to elements passing
+// `filter`, if any
+fn notification<E: Element, F: Fn(EltId) -> bool>(commit: &Commit<E>, filter: F)
+        -> Option<Notification>
+{
+    let mut changes: Vec<(EltId, EltNotice)> = commit.changes_iter()
+        .filter(|&(id, _)| filter(*id))
+        .map(|(id, change)| (*id, match *change {
+            EltChange::Deletion => EltNotice::Removed,
+            EltChange::Insertion(_) | EltChange::Replacement(_) |
+                    EltChange::Erased(_) | EltChange::Operation(..) =>
+                    EltNotice::Changed,
+        }))
+        .collect();
+    if changes.is_empty() {
+        return None;
+    }
+    changes.sort_by_key(|c| c.0);
+    Some(Notification { statesum: commit.statesum().clone(), changes })
+}
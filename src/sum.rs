@@ -5,16 +5,19 @@
 //! Pippin in-memory checksum operations
 
 use std::io::{Write, Result};
-use std::ops;
-use std::fmt;
+use std::{ops, fmt, result};
 
 use ::util::ByteFormatter;
+use error::ArgError;
 
 
 /// Number of bytes in a Sum.
+/// 
+/// This is part of the stable representation: sums are always written to
+/// files as exactly this many bytes, and as twice this many hexadecimal
+/// digits in text form (see `Sum::to_hex`).
 // #0018: it might be possible to move this inside Sum in future versions of Rust
 pub const SUM_BYTES: usize = 32;
-const BYTES_U8: u8 = SUM_BYTES as u8;
 
 
 // #0031: when simd is stable, it could be used
@@ -22,6 +25,15 @@ const BYTES_U8: u8 = SUM_BYTES as u8;
 /// A convenient way to manage and manipulate a checksum.
 /// 
 /// This is not marked `Copy` but in any case should be fairly cheap to clone.
+/// 
+/// ### Stable representation
+/// 
+/// The binary form (`load`, `write_to`) is `SUM_BYTES` bytes in order. The
+/// text form (`to_hex`, `from_hex`) is these bytes rendered as upper-case
+/// hexadecimal, two digits per byte, without separators. Both are stable
+/// and may be stored externally to identify states; `from_hex` and
+/// `matches_prefix` additionally accept lower-case digits and ignore
+/// separators (whitespace and `:`), e.g. `ab:cd 12`.
 #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Sum {
 //     s1: u8x16, s2: u8x16
@@ -44,6 +56,11 @@ impl Sum {
         Sum{ s: s }
     }
     
+    /// Get the checksum bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.s
+    }
+    
     /// Write the checksum bytes to a stream
     pub fn write_to(&self, w: &mut Write) -> Result<()> {
 //         let mut buf = [0u8; 32];
@@ -59,6 +76,32 @@ impl Sum {
         (*self) = &*self ^ other;
     }
     
+    /// Parse from text form (see type documentation). Upper- and lower-case
+    /// digits are accepted and spaces ignored; anything else (including a
+    /// wrong number of digits) is an error.
+    pub fn from_hex(string: &str) -> result::Result<Sum, ArgError> {
+        let mut s = [0u8; SUM_BYTES];
+        let mut len = 0;
+        for (n, c) in string.bytes().filter(|c| !is_separator(*c)).enumerate() {
+            let digit = hex_value(c).ok_or(ArgError::new("invalid hex digit in sum"))?;
+            if n >= 2 * SUM_BYTES {
+                return Err(ArgError::new("sum too long"));
+            }
+            s[n / 2] |= if n % 2 == 0 { digit << 4 } else { digit };
+            len = n + 1;
+        }
+        if len < 2 * SUM_BYTES {
+            return Err(ArgError::new("sum too short"));
+        }
+        Ok(Sum { s })
+    }
+    
+    /// Format in the stable text form: upper-case hexadecimal without
+    /// separators. Equivalent to `as_string(false)`.
+    pub fn to_hex(&self) -> String {
+        self.as_string(false)
+    }
+    
     /// Format as a string.
     /// 
     /// If `separate_pairs` is true, a space is inserted between every pair
@@ -84,7 +127,8 @@ impl Sum {
     /// symbols 0-9, A-F.
     /// 
     /// To improve matching, you may wish to strip spaces from and capitalise
-    /// all letters of the string before calling this function.
+    /// all letters of the string before calling this function. See also
+    /// `matches_prefix`, which does this for you.
     // #0019: I'm sure this function could be faster (in particular, by not using write!())
     pub fn matches_string(&self, string: &[u8]) -> bool {
         if string.len() > 2 * SUM_BYTES {
@@ -100,7 +144,7 @@ impl Sum {
             }
         }
         if string.len() % 2 == 1 {
-            buf[0] = HEX_CHARS[(self.s[string.len() / 2] / BYTES_U8) as usize];
+            buf[0] = HEX_CHARS[(self.s[string.len() / 2] >> 4) as usize];
             if string[string.len() - 1] != buf[0] {
                 return false;
             }
@@ -108,6 +152,24 @@ impl Sum {
         true
     }
     
+    /// Return true if `prefix` is the text form of this sum or an abbreviation
+    /// of it (i.e. a prefix of the text form). Matching is case-insensitive
+    /// and ignores separators (whitespace and `:`). An empty prefix matches
+    /// any sum.
+    pub fn matches_prefix(&self, prefix: &str) -> bool {
+        for (n, c) in prefix.bytes().filter(|c| !is_separator(*c)).enumerate() {
+            if n >= 2 * SUM_BYTES {
+                return false;
+            }
+            let byte = self.s[n / 2];
+            let digit = if n % 2 == 0 { byte >> 4 } else { byte & 0xF };
+            if hex_value(c) != Some(digit) {
+                return false;
+            }
+        }
+        true
+    }
+    
     /// Write a formatted version to a formatter
     fn fmt_to(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // #0019: this could probably be faster
@@ -130,6 +192,21 @@ impl PartialEq<[u8]> for Sum {
 
 const HEX_CHARS : &'static [u8; 16] = b"0123456789ABCDEF";
 
+// Value of a hexadecimal digit (either case), if valid
+// Characters ignored when parsing the text form
+fn is_separator(c: u8) -> bool {
+    c == b':' || c.is_ascii_whitespace()
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0' ..= b'9' => Some(c - b'0'),
+        b'A' ..= b'F' => Some(c - b'A' + 10),
+        b'a' ..= b'f' => Some(c - b'a' + 10),
+        _ => None,
+    }
+}
+
 impl<'a> ops::BitXor for &'a Sum {
     type Output = Sum;
     fn bitxor(self, rhs: &'a Sum) -> Sum {
@@ -152,3 +229,27 @@ impl fmt::Debug for Sum {
         self.fmt_to(f)
     }
 }
+
+#[test]
+fn hex_round_trip() {
+    let v: Vec<u8> = (0u8..).map(|x| x.wrapping_mul(37).wrapping_add(11)).take(SUM_BYTES).collect();
+    let sum = Sum::load(&v);
+    let hex = sum.to_hex();
+    assert_eq!(hex.len(), 2 * SUM_BYTES);
+    assert_eq!(Sum::from_hex(&hex), Ok(sum.clone()));
+    assert_eq!(Sum::from_hex(&sum.as_string(true).to_lowercase()), Ok(sum.clone()));
+    assert!(Sum::from_hex(&hex[1..]).is_err());
+    assert!(Sum::from_hex(&format!("{}0", hex)).is_err());
+    assert!(Sum::from_hex(&hex.replace("B", "G")).is_err());
+    
+    assert!(sum.matches_prefix(""));
+    assert!(sum.matches_prefix(&hex[0..7].to_lowercase()));
+    assert!(sum.matches_string(&hex.as_bytes()[0..7]));
+    assert!(sum.matches_prefix(&hex));
+    assert!(!sum.matches_prefix(&format!("{}0", hex)));
+    assert!(!sum.matches_prefix("FF"));
+    let separated = sum.as_string(true).replace(' ', ":");
+    assert!(sum.matches_prefix(&separated[0..11]));
+    assert!(sum.matches_prefix(&format!(" {}\t", &hex[0..4].to_lowercase())));
+    assert_eq!(Sum::from_hex(&separated), Ok(sum.clone()));
+}
@@ -0,0 +1,415 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Exchange of commits between replicas of a partition
+//! 
+//! One side runs `serve` on its partition; the other calls `pull`, `push` or
+//! `sync`. Messages are exchanged via a `SyncTransport`; `StreamTransport`
+//! frames messages over any `Read + Write` stream (e.g. a TCP connection).
+//! 
+//! Replicas negotiate by state-sum: the pulling side sends the sums of all
+//! states it has loaded, and the serving side replies with commits for the
+//! states it has which the puller does not, back to the first known state.
+//! Pushing is the reverse. Only loaded history is exchanged, thus both sides
+//! must have sufficient common history loaded (see `Partition::load_range`).
+//! Received commits are checked and added as new commits (written on the
+//! next `write_fast`); where this results in multiple tips, `sync` merges
+//! them using `Partition::merge_default`.
+//! 
+//! Each message is an 8-byte identifier followed by data. Requests are
+//! `TIPS` (get tips), `WANT` (get commits), `PUSH` (send commits) and `BYE`
+//! (end); replies are `TIPS`, `COMMITS`, `ADDED` or `ERROR`. Lists of sums
+//! and commits are preceded by a `u32` count; commits use the commit log
+//! format (see `doc/file-format.md`).
+//! 
+//! `COMMITS` and `PUSH` messages also carry the sender's replica identifier,
+//! if any (see `Control::replica_id`): a `u8` flag, followed by a `u64` if
+//! the flag is 1. The receiver records this as provenance of each commit
+//! added (see `CommitMeta::provenance`).
+//! 
+//! Where no interaction is possible, commits written in the commit log
+//! format (as in `COMMITS` messages, without the count) can be applied with
+//! `Partition::receive_commits`.
+
+use std::collections::HashSet;
+use std::io::{Read, Write, ErrorKind};
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use commit::{Commit, ReplicaId};
+use control::Control;
+use elt::Element;
+use error::{Result, RepoError};
+use part::Partition;
+use rw::{LATEST_VERSION, EltReader};
+use rw::commitlog::{read_log, start_log, write_commit};
+use sum::{Sum, SUM_BYTES};
+
+/// Default limit on the size of a received message: 64 MiB
+pub const DEFAULT_MAX_MESSAGE: usize = 1 << 26;
+
+/// Transport for request/response messages between replicas
+pub trait SyncTransport {
+    /// Send one message
+    fn send(&mut self, msg: &[u8]) -> Result<()>;
+    
+    /// Receive one message, or `None` if the other side closed the
+    /// connection.
+    fn receive(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+/// Transport over a stream. Each message is framed by a `u32` length.
+#[derive(Debug)]
+pub struct StreamTransport<S: Read + Write> {
+    stream: S,
+    max_message: usize,
+}
+
+impl<S: Read + Write> StreamTransport<S> {
+    /// Create, with a message size limit of `DEFAULT_MAX_MESSAGE`
+    pub fn new(stream: S) -> StreamTransport<S> {
+        StreamTransport { stream, max_message: DEFAULT_MAX_MESSAGE }
+    }
+    
+    /// Set the maximum size of received messages. Larger messages cause
+    /// `receive` to fail.
+    pub fn set_max_message(&mut self, bytes: usize) {
+        self.max_message = bytes;
+    }
+    
+    /// Unwrap, returning the stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read + Write> SyncTransport for StreamTransport<S> {
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        if msg.len() > u32::MAX as usize {
+            return RepoError::err(RepoError::SyncMessageLength { len: msg.len() });
+        }
+        self.stream.write_u32::<BigEndian>(msg.len() as u32)?;
+        self.stream.write_all(msg)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+    
+    fn receive(&mut self) -> Result<Option<Vec<u8>>> {
+        let len = match self.stream.read_u32::<BigEndian>() {
+            Ok(len) => len as usize,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        if len > self.max_message {
+            return RepoError::err(RepoError::SyncMessageLength { len });
+        }
+        let mut msg = vec![0; len];
+        self.stream.read_exact(&mut msg)?;
+        Ok(Some(msg))
+    }
+}
+
+
+/// Handle requests from the other side until it sends `BYE` or closes the
+/// connection. Pushed commits are added to `part` (but not written).
+pub fn serve<C: Control>(part: &mut Partition<C>, transport: &mut SyncTransport) -> Result<()> {
+    while serve_one(part, transport)? {}
+    Ok(())
+}
+
+/// Handle a single request. Returns false if the other side ended the
+/// session.
+pub fn serve_one<C: Control>(part: &mut Partition<C>, transport: &mut SyncTransport)
+        -> Result<bool>
+{
+    let msg = match transport.receive()? {
+        Some(msg) => msg,
+        None => return Ok(false),
+    };
+    if msg.len() < 8 {
+        return RepoError::err(RepoError::SyncMessageLength { len: msg.len() });
+    }
+    let mut reply = Vec::new();
+    match &msg[0..8] {
+        b"BYE\0\0\0\0\0" => return Ok(false),
+        b"TIPS\0\0\0\0" => {
+            let tips: Vec<Sum> = part.tips().iter().cloned().collect();
+            reply.extend_from_slice(b"TIPS\0\0\0\0");
+            write_sums(&mut reply, &tips)?;
+        },
+        b"WANT\0\0\0\0" => {
+            let mut r = &msg[8..];
+            let have: HashSet<Sum> = read_sums(&mut r)?.into_iter().collect();
+            let want = read_sums(&mut r)?;
+            let commits = commits_since(part, &want, &have);
+            reply.extend_from_slice(b"COMMITS\0");
+            write_source(&mut reply, part.control().replica_id())?;
+            write_commits(&mut reply, &commits)?;
+        },
+        b"PUSH\0\0\0\0" => {
+            let mut r = &msg[8..];
+            let source = read_source(&mut r)?;
+            let commits = read_commits(&mut r, part)?;
+            match add_commits(part, commits, source) {
+                Ok(n) => {
+                    reply.extend_from_slice(b"ADDED\0\0\0");
+                    reply.write_u32::<BigEndian>(n as u32)?;
+                },
+                Err(e) => {
+                    warn!("Partition {}: rejected pushed commits: {}", part.name(), e);
+                    reply.extend_from_slice(b"ERROR\0\0\0");
+                    reply.extend_from_slice(e.to_string().as_bytes());
+                },
+            }
+        },
+        _ => {
+            reply.extend_from_slice(b"ERROR\0\0\0unknown request");
+        },
+    }
+    transport.send(&reply)?;
+    Ok(true)
+}
+
+/// Get commits from the other side for all its tips, and add them to
+/// `part` (but do not write or merge them).
+/// 
+/// Tips already in the history of `part` are not requested; this may load
+/// older snapshots where the sum filter cannot rule them out (see
+/// `Partition::find_state`).
+/// 
+/// Returns the number of commits added.
+pub fn pull<C: Control>(part: &mut Partition<C>, transport: &mut SyncTransport) -> Result<usize> {
+    let mut want = Vec::new();
+    for tip in remote_tips(transport)? {
+        if !part.find_state(&tip)? {
+            want.push(tip);
+        }
+    }
+    if want.is_empty() {
+        return Ok(0);
+    }
+    let have: Vec<Sum> = part.states_iter().map(|state| state.statesum().clone()).collect();
+    let mut msg = Vec::new();
+    msg.extend_from_slice(b"WANT\0\0\0\0");
+    write_sums(&mut msg, &have)?;
+    write_sums(&mut msg, &want)?;
+    let reply = request(transport, &msg, b"COMMITS\0")?;
+    let mut r = &reply[..];
+    let source = read_source(&mut r)?;
+    let commits = read_commits(&mut r, part)?;
+    let n = add_commits(part, commits, source)?;
+    info!("Partition {}: pulled {} commits", part.name(), n);
+    Ok(n)
+}
+
+/// Send commits for all states of `part` not known to the other side.
+/// 
+/// Only ancestors of the other side's tips are assumed known there, thus
+/// if it has tips unknown to `part` this may send unnecessary commits; it
+/// is better to `pull` first (see `sync`).
+/// 
+/// Returns the number of commits added by the other side.
+pub fn push<C: Control>(part: &mut Partition<C>, transport: &mut SyncTransport) -> Result<usize> {
+    let tips = remote_tips(transport)?;
+    let mut known = HashSet::new();
+    let mut next: Vec<Sum> = tips.into_iter().filter(|tip| part.has_state(tip)).collect();
+    while let Some(sum) = next.pop() {
+        if let Some(state) = part.state(&sum) {
+            next.extend(state.parents().iter().filter(|p| !known.contains(*p)).cloned());
+        }
+        known.insert(sum);
+    }
+    let ours: Vec<Sum> = part.tips().iter().cloned().collect();
+    let commits = commits_since(part, &ours, &known);
+    if commits.is_empty() {
+        return Ok(0);
+    }
+    let mut msg = Vec::new();
+    msg.extend_from_slice(b"PUSH\0\0\0\0");
+    write_source(&mut msg, part.control().replica_id())?;
+    write_commits(&mut msg, &commits)?;
+    let reply = request(transport, &msg, b"ADDED\0\0\0")?;
+    if reply.len() != 4 {
+        return RepoError::err(RepoError::SyncProtocol("bad reply"));
+    }
+    let n = BigEndian::read_u32(&reply) as usize;
+    info!("Partition {}: pushed {} commits", part.name(), n);
+    Ok(n)
+}
+
+/// Synchronise with the other side: `pull`, merge tips if necessary (see
+/// `Partition::merge_default`; `auto_load` is passed), `push`, then end
+/// the session. Commits are not written; call e.g. `write_full` after.
+/// 
+/// Returns the numbers of commits pulled and pushed.
+pub fn sync<C: Control>(part: &mut Partition<C>, transport: &mut SyncTransport, auto_load: bool)
+        -> Result<(usize, usize)>
+{
+    let pulled = pull(part, transport)?;
+    if part.merge_required() {
+        part.merge_default(auto_load)?;
+    }
+    let pushed = push(part, transport)?;
+    transport.send(b"BYE\0\0\0\0\0")?;
+    Ok((pulled, pushed))
+}
+
+
+// Send a request and receive the reply, checking its identifier. Returns
+// data after the identifier.
+fn request(transport: &mut SyncTransport, msg: &[u8], expect: &[u8; 8]) -> Result<Vec<u8>> {
+    transport.send(msg)?;
+    let reply = match transport.receive()? {
+        Some(reply) => reply,
+        None => return RepoError::err(RepoError::SyncClosed),
+    };
+    if reply.len() >= 8 && reply[0..8] == *expect {
+        Ok(reply[8..].to_vec())
+    } else if reply.len() >= 8 && reply[0..8] == *b"ERROR\0\0\0" {
+        warn!("Sync: remote error: {}", String::from_utf8_lossy(&reply[8..]));
+        RepoError::err(RepoError::SyncRemoteFailure)
+    } else {
+        RepoError::err(RepoError::SyncProtocol("unexpected reply"))
+    }
+}
+
+fn remote_tips(transport: &mut SyncTransport) -> Result<Vec<Sum>> {
+    let reply = request(transport, b"TIPS\0\0\0\0", b"TIPS\0\0\0\0")?;
+    read_sums(&mut &reply[..])
+}
+
+// Commits yielding states reachable from `from` (excluding those in
+// `known` and their ancestors), parents first. States whose first parent
+// is not loaded are skipped.
+fn commits_since<C: Control>(part: &Partition<C>, from: &[Sum], known: &HashSet<Sum>)
+        -> Vec<Commit<C::Element>>
+{
+    let mut commits = Vec::new();
+    let mut visited = HashSet::new();
+    // Depth-first; a `true` entry means all parents have been visited
+    let mut next: Vec<(Sum, bool)> = from.iter().map(|sum| (sum.clone(), false)).collect();
+    while let Some((sum, ready)) = next.pop() {
+        if ready {
+            let state = part.state(&sum).expect("state");
+            if let Some(parent) = state.parents().first().and_then(|p| part.state(p)) {
+                commits.push(Commit::recreate(parent, state));
+            }
+            continue;
+        }
+        if known.contains(&sum) || !visited.insert(sum.clone()) {
+            continue;
+        }
+        if let Some(state) = part.state(&sum) {
+            next.push((sum.clone(), true));
+            for parent in state.parents() {
+                next.push((parent.clone(), false));
+            }
+        }
+    }
+    commits
+}
+
+// Add commits, recording provenance if `source` is known
+fn add_commits<C: Control>(part: &mut Partition<C>, commits: Vec<Commit<C::Element>>,
+        source: Option<ReplicaId>) -> Result<usize>
+{
+    let received = part.control().make_commit_timestamp();
+    let mut n = 0;
+    for mut commit in commits {
+        if let Some(source) = source {
+            commit.meta_mut().add_provenance(source, received);
+        }
+        if part.push_commit(commit)? {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+fn write_source(w: &mut Write, source: Option<ReplicaId>) -> Result<()> {
+    match source {
+        Some(id) => {
+            w.write_u8(1)?;
+            w.write_u64::<BigEndian>(id)?;
+        },
+        None => w.write_u8(0)?,
+    }
+    Ok(())
+}
+
+fn read_source(r: &mut &[u8]) -> Result<Option<ReplicaId>> {
+    match r.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(r.read_u64::<BigEndian>()?)),
+        _ => RepoError::err(RepoError::SyncProtocol("bad replica identifier")),
+    }
+}
+
+fn write_sums(w: &mut Write, sums: &[Sum]) -> Result<()> {
+    w.write_u32::<BigEndian>(sums.len() as u32)?;
+    for sum in sums {
+        sum.write_to(w)?;
+    }
+    Ok(())
+}
+
+fn read_sums(r: &mut &[u8]) -> Result<Vec<Sum>> {
+    let n = r.read_u32::<BigEndian>()? as usize;
+    if r.len() < n * SUM_BYTES {
+        return RepoError::err(RepoError::SyncProtocol("message truncated"));
+    }
+    let mut sums = Vec::with_capacity(n);
+    for _ in 0..n {
+        sums.push(Sum::load(&r[0..SUM_BYTES]));
+        *r = &r[SUM_BYTES..];
+    }
+    Ok(sums)
+}
+
+fn write_commits<E: Element>(w: &mut Write, commits: &[Commit<E>]) -> Result<()> {
+    w.write_u32::<BigEndian>(commits.len() as u32)?;
+    start_log(w)?;
+    for commit in commits {
+        write_commit(commit, w)?;
+    }
+    Ok(())
+}
+
+fn read_commits<C: Control>(r: &mut &[u8], part: &Partition<C>) -> Result<Vec<Commit<C::Element>>> {
+    let n = r.read_u32::<BigEndian>()? as usize;
+    let mut commits = Vec::new();
+    read_log(r, &mut commits, LATEST_VERSION, &part.control().user_meta_limits(),
+            &mut EltReader::default())?;
+    if commits.len() != n {
+        return RepoError::err(RepoError::SyncProtocol("wrong number of commits"));
+    }
+    Ok(commits)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    
+    use super::{StreamTransport, SyncTransport};
+    
+    #[test]
+    fn stream_transport() {
+        let mut transport = StreamTransport::new(Cursor::new(Vec::new()));
+        transport.send(b"one").expect("sending");
+        transport.send(b"").expect("sending");
+        transport.send(&[7; 100]).expect("sending");
+        let mut stream = transport.into_inner();
+        stream.set_position(0);
+        
+        let mut transport = StreamTransport::new(stream);
+        assert_eq!(transport.receive().expect("receiving"), Some(b"one".to_vec()));
+        assert_eq!(transport.receive().expect("receiving"), Some(vec![]));
+        transport.set_max_message(99);
+        assert!(transport.receive().is_err());
+        
+        let mut transport = StreamTransport::new(Cursor::new(Vec::new()));
+        assert_eq!(transport.receive().expect("receiving"), None);
+    }
+}
@@ -6,6 +6,7 @@
 
 use std::cmp;
 use std::fmt::{self, Write};
+use std::io;
 
 /// "trim" applied to generic arrays: while the last byte is pat, remove it.
 ///  
@@ -83,3 +84,33 @@ impl <'a> fmt::Display for HexFormatter<'a> {
         Ok(())
     }
 }
+
+/// Wrapper around a writer which counts the bytes written.
+pub struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+impl<W: io::Write> CountingWriter<W> {
+    /// Wrap a writer, starting the count from zero
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner, count: 0 }
+    }
+    /// Get the number of bytes written so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+    /// Unwrap, returning the inner writer
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
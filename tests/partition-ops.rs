@@ -8,8 +8,15 @@ extern crate pippin;
 extern crate vec_map;
 extern crate log;
 extern crate env_logger;
+#[cfg(any(feature = "gen", feature = "chaos"))]
+extern crate rand;
 
 use std::io::{Read, Write, ErrorKind};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use vec_map::VecMap;
 
@@ -80,21 +87,99 @@ impl RepoIO for PartitionStreams {
             make_io_err(ErrorKind::NotFound, "no snapshot corresponding to new commit log")
         }
     }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        Ok(self.ss.get_mut(ss_num).and_then(|&mut (ref mut ss, _)| ss.take()).is_some())
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        Ok(self.ss.get_mut(ss_num)
+            .and_then(|&mut (_, ref mut logs)| logs.remove(cl_num)).is_some())
+    }
+    fn replace_ss(&mut self, ss_num: usize, data: &[u8]) -> Result<bool> {
+        Ok(self.ss.get_mut(ss_num).and_then(|&mut (ref mut ss, _)| ss.as_mut())
+            .map(|ss| *ss = data.to_vec()).is_some())
+    }
+    fn replace_ss_cl(&mut self, ss_num: usize, cl_num: usize, data: &[u8]) -> Result<bool> {
+        Ok(self.ss.get_mut(ss_num)
+            .and_then(|&mut (_, ref mut logs)| logs.get_mut(cl_num))
+            .map(|log| *log = data.to_vec()).is_some())
+    }
+}
+
+impl PartitionStreams {
+    fn new() -> PartitionStreams {
+        PartitionStreams { ss: VecMap::new() }
+    }
+}
+
+// Control used by most tests: `String` elements in in-memory streams
+type StreamControl = DefaultControl<String, PartitionStreams>;
+
+// A new control with empty in-memory streams
+fn new_control() -> StreamControl {
+    StreamControl::new(PartitionStreams::new())
+}
+
+// Mutable copy of the tip; panics unless the partition is ready
+fn tip_mut<C: Control>(part: &Partition<C>) -> MutPartState<C::Element> {
+    part.tip().expect("has tip").clone_mut()
+}
+
+/// Control recording written files, with optional memory limit
+struct WatchControl {
+    io: PartitionStreams,
+    ss_policy: DefaultSnapshot,
+    written: Vec<WrittenFile>,
+    mem_limit: Option<usize>,
+}
+impl MakeCommitMeta for WatchControl {}
+impl Control for WatchControl {
+    type Element = String;
+    fn io(&self) -> &RepoIO { &self.io }
+    fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+    fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+    fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+    fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+    fn file_written(&mut self, file: WrittenFile) {
+        self.written.push(file);
+    }
+    fn mem_limit(&self) -> Option<usize> {
+        self.mem_limit
+    }
+}
+
+/// Control using a manually set clock
+struct TimedControl {
+    io: MemRepoIO,
+    ss_policy: DefaultSnapshot,
+    time: Cell<i64>,
+}
+impl MakeCommitMeta for TimedControl {
+    fn make_commit_timestamp(&self) -> i64 {
+        self.time.get()
+    }
+}
+impl Control for TimedControl {
+    type Element = String;
+    fn io(&self) -> &RepoIO { &self.io }
+    fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+    fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+    fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+    fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
 }
 
+
+// Creating, opening and loading partitions
+
 #[test]
 fn create_small() {
-    type Control = DefaultControl<String, PartitionStreams>;
-    
     env_logger::init().unwrap();
     
-    let part_streams = PartitionStreams { ss: VecMap::new() };
-    let control = Control::new(part_streams);
+    let control = new_control();
     let mut part = Partition::create(control, "create_small")
             .expect("creating partition");
     
     // 2 Add a few elements over multiple commits
-    let mut state = part.tip().expect("has tip").clone_mut();
+    let mut state = tip_mut(&part);
     state.insert_new("thirty five".to_string()).expect("inserting elt 35");
     state.insert_new("six thousand, five hundred and thirteen"
             .to_string()).expect("inserting elt 6513");
@@ -104,12 +189,12 @@ fn create_small() {
     part.push_state(state).expect("committing");
     let state1 = part.tip().expect("has tip").clone_exact();
     
-    let mut state = part.tip().expect("getting tip").clone_mut();
+    let mut state = tip_mut(&part);
     state.insert_new("sixty eight thousand, one hundred and sixty eight"
             .to_string()).expect("inserting elt 68168");
     part.push_state(state).expect("committing");
     
-    let mut state = part.tip().expect("getting tip").clone_mut();
+    let mut state = tip_mut(&part);
     state.insert_new("eighty nine".to_string()).expect("inserting elt 89");
     state.insert_new("one thousand and sixty three".to_string())
             .expect("inserting elt 1063");
@@ -155,8 +240,9 @@ fn create_small() {
         // in which elements occur can and does vary (thanks to Rust's hash
         // function randomisation). Instead we compare file length here and
         // read the files back below.
+        // (each commit in the log includes a 16-byte summary)
         assert_eq!(ss_data.as_ref().map_or(0, |d| d.len()), 208);
-        assert_eq!(log.len(), 1168);
+        assert_eq!(log.len(), 1216);
     }
     
     // 5 Read streams back again and compare
@@ -166,3 +252,3532 @@ fn create_small() {
         *part2.state(state1.statesum()).expect("get state1 by sum"));
     assert_eq!(state3, *part2.tip().expect("part2 tip"));
 }
+
+#[test]
+fn builder() {
+    type Builder = PartitionBuilder<DefaultControl<String, PartitionStreams>>;
+    let builder = Builder::with_io(PartitionStreams::new());
+    let mut part = builder.open_or_create("built").expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    
+    let io = part.unwrap_control().unwrap_io();
+    let builder = Builder::with_control(DefaultControl::new(io));
+    let part = builder.open_or_create("built").expect("opening partition");
+    assert_eq!(*part.tip().expect("has tip"), tip);
+    
+    let io = part.unwrap_control().unwrap_io();
+    assert!(Builder::with_io(io).open_or_create("other name").is_err());
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn builder_files() {
+    use std::fs;
+    
+    type Builder = PartitionBuilder<DefaultControl<String, RepoFileIO>>;
+    let dir = std::env::temp_dir().join(format!("pippin-builder-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut part = Builder::at_prefix(dir.join("notes")).open_or_create("notes")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    
+    let reopened = Builder::at_prefix(dir.join("notes")).open_or_create("notes");
+    let discovered = Builder::discover(&dir).and_then(|b| b.open());
+    fs::remove_dir_all(&dir).expect("removing dir");
+    assert_eq!(*reopened.expect("opening partition").tip().expect("has tip"), tip);
+    assert_eq!(*discovered.expect("opening partition").tip().expect("has tip"), tip);
+}
+
+#[test]
+fn header_info() {
+    let control = new_control();
+    let mut part = Partition::create(control, "header info").expect("creating partition");
+    assert!(part.header_info().is_none());
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_snapshot().expect("writing snapshot");
+    
+    let mut control = part.unwrap_control();
+    // Truncate snapshot 1 so that its data cannot be read
+    {
+        let data = control.io_mut().ss[1].0.as_mut().expect("has snapshot");
+        let len = data.len();
+        data.truncate(len - 10);
+    }
+    let mut part = Partition::open(control, false).expect("opening partition");
+    let info = part.header_info().expect("has header").clone();
+    assert_eq!(info.ss, 1);
+    assert!(FileType::Snapshot(info.version).is_latest());
+    assert_eq!(info.tag, None);
+    assert!(part.load_latest().is_err());
+    assert_eq!(part.header_info(), Some(&info));
+}
+
+#[test]
+fn read_only() {
+    let control = new_control();
+    let mut part = Partition::create(control, "read only").expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let tip = part.tip_key().expect("has tip").clone();
+    
+    let io = part.unwrap_control().unwrap_io();
+    let mut part = Partition::open_read_only(StreamControl::new(io), true).expect("opening partition");
+    assert!(part.is_read_only());
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    let mut state = tip_mut(&part);
+    state.insert_new("two".to_string()).expect("inserting elt");
+    assert_eq!(part.push_state(state), Err(PatchOp::ReadOnly));
+    assert!(part.write_fast().is_err());
+    assert!(part.write_snapshot().is_err());
+    assert!(part.gc(GcPolicy::KeepSnapshots(1)).is_err());
+    assert_eq!(part.control().io().ss_len(), 1);
+    assert_eq!(part.control().io().ss_cl_len(0), 1);
+    part.close().expect("closing");
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn close() {
+    use std::fs;
+    
+    type Builder = PartitionBuilder<DefaultControl<String, RepoFileIO>>;
+    let dir = std::env::temp_dir().join(format!("pippin-close-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut part = Builder::at_prefix(dir.join("part")).open_or_create("close")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("unsaved".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    let closed = part.close();
+    
+    let reopened = Builder::at_prefix(dir.join("part")).open_or_create("close");
+    fs::remove_dir_all(&dir).expect("removing dir");
+    closed.expect("closing");
+    assert_eq!(*reopened.expect("opening partition").tip().expect("has tip"), tip);
+}
+
+#[test]
+fn load_lazy() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "lazy")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let mut ids = Vec::new();
+    for i in 0..20 {
+        ids.push(state.insert_new(format!("element {}", i)).expect("inserting elt"));
+    }
+    part.push_state(state).expect("committing");
+    part.write_snapshot().expect("writing snapshot");
+    let io = part.unwrap_control().unwrap_io();
+    
+    let mut part = Partition::open(Control::new(io.clone()), false).expect("opening partition");
+    assert!(part.get_elt_on_demand(ids[0]).is_err());
+    assert_eq!(part.load_lazy().expect("loading index"), true);
+    assert!(!part.is_loaded());
+    assert_eq!(part.get_elt_on_demand(ids[7]).expect("reading elt").map(|e| (*e).clone()),
+            Some("element 7".to_string()));
+    assert_eq!(part.get_elt_on_demand(EltId::from(1)).expect("reading elt"), None);
+    let mut sorted = ids.clone();
+    sorted.sort();
+    let elts = part.read_snapshot_filtered(EltIdRange::new(sorted[5], sorted[14]))
+            .expect("reading range");
+    assert_eq!(elts.len(), 10);
+    assert!(sorted[5..15].iter().all(|id| elts.contains_key(id)));
+    let i7 = ids.iter().position(|id| *id == sorted[7]).expect("has id");
+    assert_eq!(*elts[&sorted[7]], format!("element {}", i7));
+    assert_eq!(part.read_snapshot_filtered(EltIdRange::all()).expect("reading all").len(), 20);
+    
+    // Once loaded, the tip is used:
+    part.load_latest().expect("loading");
+    assert_eq!(part.get_elt_on_demand(ids[19]).expect("reading elt").map(|e| (*e).clone()),
+            Some("element 19".to_string()));
+    
+    // Corruption of the element is detected:
+    let mut io = io;
+    {
+        let data = io.ss_data_mut(1).expect("has snapshot");
+        let pos = data.windows(9).position(|w| w == b"element 3").expect("found elt");
+        data[pos] ^= 1;
+    }
+    let mut part = Partition::open(Control::new(io), false).expect("opening partition");
+    part.load_lazy().expect("loading index");
+    assert!(part.get_elt_on_demand(ids[3]).is_err());
+    assert!(part.get_elt_on_demand(ids[4]).is_ok());
+    
+    // Small snapshots have no element index; the whole snapshot is read:
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "lazy small")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let id = state.insert_new("small".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_snapshot().expect("writing snapshot");
+    let io = part.unwrap_control().unwrap_io();
+    let mut part = Partition::open(Control::new(io), false).expect("opening partition");
+    assert_eq!(part.load_lazy().expect("loading lazily"), true);
+    assert!(!part.is_loaded());
+    assert_eq!(part.get_elt_on_demand(id).expect("reading elt").map(|e| (*e).clone()),
+            Some("small".to_string()));
+    assert_eq!(part.get_elt_on_demand(EltId::from(1)).expect("reading elt"), None);
+    assert_eq!(part.read_snapshot_filtered(EltIdRange::all()).expect("reading all").len(), 1);
+}
+
+#[test]
+fn load_auto() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "load auto")
+            .expect("creating partition");
+    for i in 0..2 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("elt {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_full().expect("writing");
+        part.write_snapshot().expect("writing snapshot");
+    }
+    let tip = part.tip_key().expect("has tip").clone();
+    let io = part.unwrap_control().io().clone();
+    
+    let mut part = Partition::open(Control::new(io.clone()), false).expect("opening partition");
+    part.load_auto(LoadGoal::Read).expect("loading");
+    assert_eq!(part.loaded_range(), (2, 3));
+    part.load_auto(LoadGoal::Edit).expect("loading");
+    assert_eq!(part.loaded_range(), (2, 3));
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    
+    let mut part = Partition::open(Control::new(io), false).expect("opening partition");
+    part.load_auto(LoadGoal::Merge).expect("loading");
+    assert_eq!(part.loaded_range(), (1, 3));
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+}
+
+#[test]
+fn recover_log() {
+    let control = new_control();
+    let mut part = Partition::create(control, "recover").expect("creating partition");
+    let initial = part.tip().expect("has tip").clone_exact();
+    let mut state = initial.clone_mut();
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let s1 = part.tip().expect("has tip").clone_exact();
+    let mut state = initial.clone_mut();
+    state.insert_new("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let mut state = s1.clone_mut();
+    state.insert_new("three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let tips: Vec<Sum> = part.tips().iter().cloned().collect();
+    assert_eq!(tips.len(), 2);
+    
+    // Damage the second commit:
+    let mut io = part.unwrap_control().unwrap_io();
+    let len = {
+        let log = &mut io.ss.get_mut(0).expect("has ss 0").1.get_mut(0).expect("has log");
+        let starts: Vec<usize> = (0..log.len() / 16).map(|i| i * 16)
+                .filter(|&p| log[p..p + 8] == *b"COMMIT\x00U").collect();
+        assert_eq!(starts.len(), 3);
+        log[starts[1] + 40] ^= 0xFF;
+        (starts[2] - starts[1]) as u64
+    };
+    
+    let mut part = Partition::open(StreamControl::new(io), false).expect("opening partition");
+    assert!(part.load_range(0, usize::MAX, Recovery::Strict).is_err());
+    let io = part.unwrap_control().unwrap_io();
+    let mut part = Partition::open(StreamControl::new(io), false).expect("opening partition");
+    let reports = part.load_range(0, usize::MAX, Recovery::Tolerant).expect("loading");
+    assert_eq!(reports.len(), 1);
+    let report = &reports[&(0, 0)];
+    assert_eq!(report.lost.len(), 1);
+    assert_eq!(report.lost[0].1 - report.lost[0].0, len);
+    let recovered: Vec<&Sum> = report.recovered.iter().map(|(sum, _)| sum).collect();
+    assert_eq!(recovered.len(), 2);
+    assert_eq!(recovered[0], s1.statesum());
+    assert!(tips.contains(recovered[1]));
+    assert_eq!(part.tip_key().expect("has tip"), recovered[1]);
+}
+
+#[test]
+fn elt_read_policy() {
+    // Data starting "bad" can be written but not read back
+    #[derive(PartialEq, Eq, Debug)]
+    struct Picky(String);
+    impl Element for Picky {
+        fn write_buf(&self, writer: &mut Write) -> Result<()> {
+            writer.write_all(self.0.as_bytes())?;
+            Ok(())
+        }
+        fn read_buf(buf: &[u8]) -> Result<Self> {
+            if buf.starts_with(b"bad") {
+                return OtherError::err("unreadable element");
+            }
+            Ok(Picky(String::from_utf8(buf.to_vec())?))
+        }
+        fn placeholder(_id: EltId, vec: Vec<u8>, _sum: Sum) -> Option<Self> {
+            Some(Picky(String::from_utf8(vec).ok()?))
+        }
+    }
+    type Control = DefaultControl<Picky, MemRepoIO>;
+    fn open(io: &MemRepoIO, policy: EltReadPolicy) -> Result<Partition<Control>> {
+        let mut control = Control::new(io.clone());
+        control.set_elt_read_policy(policy);
+        Partition::open(control, true)
+    }
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "picky")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new(Picky("good".to_string())).expect("inserting elt");
+    state.insert_new(Picky("bad one".to_string())).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_snapshot().expect("writing snapshot");
+    let mut state = tip_mut(&part);
+    let bad2 = state.insert_new(Picky("bad two".to_string())).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let tip = part.tip_key().expect("has tip").clone();
+    let io = part.control().io().clone();
+    
+    assert!(open(&io, EltReadPolicy::Fail).is_err());
+    
+    let mut part = open(&io, EltReadPolicy::Placeholder).expect("opening partition");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert_eq!(part.tip().expect("has tip").num_avail(), 3);
+    assert_eq!(part.skipped_elts().len(), 2);
+    part.write_snapshot().expect("writing snapshot");
+    
+    let mut part = open(&io, EltReadPolicy::Skip).expect("opening partition");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert_eq!(part.tip().expect("has tip").num_avail(), 1);
+    assert!(part.skipped_elts().contains_key(&bad2));
+    assert!(part.write_snapshot().is_err());
+    
+    // Repair one element; the other remains skipped:
+    let mut state = tip_mut(&part);
+    state.remove_erased(bad2).expect("removing erased elt");
+    state.insert(bad2, Picky("fixed".to_string())).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert!(part.write_snapshot().is_err());
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn refresh() {
+    use std::fs;
+    
+    type Control = DefaultControl<String, RepoFileIO>;
+    let dir = std::env::temp_dir().join(format!("pippin-refresh-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut part1 = Partition::create(Control::new(RepoFileIO::new(dir.join("part"))), "refresh")
+            .expect("creating partition");
+    part1.write_full().expect("writing");
+    
+    // A second "process" opens the same files:
+    let io = part_from_path(&dir).expect("discovering files");
+    let mut part2 = Partition::open(Control::new(io), true).expect("opening partition");
+    part2.load_latest().expect("loading");
+    assert!(!part2.refresh().expect("refreshing"));
+    
+    // Both write a log for snapshot 0; numbers must not clash:
+    for (part, name) in [(&mut part1, "one"), (&mut part2, "two")] {
+        let mut state = tip_mut(&part);
+        state.insert(EltId::from(1), name.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+    }
+    assert!(part1.refresh().expect("refreshing"));
+    assert_eq!(part1.tips_len(), 2);
+    part1.merge(&TwoWaySolveUseA::new(), false).expect("merging");
+    part1.write_full().expect("writing");
+    part1.write_snapshot().expect("writing snapshot");
+    
+    assert!(part2.refresh().expect("refreshing"));
+    assert_eq!(part2.loaded_range(), (0, 2));
+    assert_eq!(part2.tip_key().expect("has tip"), part1.tip_key().expect("has tip"));
+    
+    // Writing a log which exists on disk but is unknown to this instance:
+    let mut io = RepoFileIO::new(dir.join("part"));
+    assert!(io.new_ss_cl(0, 0).expect("creating log").is_none());
+    let lock = dir.join("part.lock").exists();
+    
+    // Read-only access does not create a lock file:
+    fs::remove_file(dir.join("part.lock")).expect("removing lock file");
+    let mut io = part_from_path(&dir).expect("discovering files");
+    io.set_readonly(true);
+    let part3 = Partition::open_read_only(Control::new(io), true).expect("opening partition");
+    assert_eq!(part3.tip_key().expect("has tip"), part1.tip_key().expect("has tip"));
+    let lock_ro = dir.join("part.lock").exists();
+    fs::remove_dir_all(&dir).expect("removing dir");
+    assert!(lock);
+    assert!(!lock_ro);
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn concurrent_reads() {
+    use std::fs;
+    use std::thread;
+    
+    fn read_all(io: &RepoIO, ss: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        io.read_ss(ss).expect("reading").expect("has snapshot")
+                .read_to_end(&mut data).expect("reading");
+        data
+    }
+    
+    type Builder = PartitionBuilder<DefaultControl<String, RepoFileIO>>;
+    let dir = std::env::temp_dir().join(format!("pippin-concurrent-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut part = Builder::at_prefix(dir.join("part")).open_or_create("concurrent")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("elt".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_full().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    let io = part.unwrap_control().unwrap_io();
+    let expected: Vec<Vec<u8>> = (0..2).map(|ss| read_all(&io, ss)).collect();
+    
+    // Interleaved reads from two open streams:
+    let mut r0 = io.read_ss(0).expect("reading").expect("has snapshot");
+    let mut r1 = io.read_ss(1).expect("reading").expect("has snapshot");
+    let mut data = (Vec::new(), Vec::new());
+    let mut buf = [0u8; 16];
+    loop {
+        let n0 = r0.read(&mut buf).expect("reading");
+        data.0.extend_from_slice(&buf[..n0]);
+        let n1 = r1.read(&mut buf).expect("reading");
+        data.1.extend_from_slice(&buf[..n1]);
+        if n0 == 0 && n1 == 0 { break; }
+    }
+    drop((r0, r1));
+    
+    // Reads from multiple threads:
+    let results: Vec<Vec<u8>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..2).map(|ss| {
+            let io = &io;
+            scope.spawn(move || read_all(io, ss))
+        }).collect();
+        handles.into_iter().map(|h| h.join().expect("joining")).collect()
+    });
+    fs::remove_dir_all(&dir).expect("removing dir");
+    assert_eq!(data.0, expected[0]);
+    assert_eq!(data.1, expected[1]);
+    assert_eq!(results, expected);
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn file_io_options() {
+    use std::fs;
+    
+    let dir = std::env::temp_dir().join(format!("pippin-file-options-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut io = RepoFileIO::new(dir.join("part"));
+    assert_eq!(io.options(), FileIoOptions { fsync: true, atomic_rename: true, lock: true });
+    let path = dir.join("part-ss0.pip");
+    let temp = dir.join("part-ss0.pip.tmp");
+    {
+        let mut w = io.new_ss(0).expect("creating").expect("new snapshot");
+        w.write_all(b"data").expect("writing");
+        assert!(temp.exists() && !path.exists());
+        w.flush().expect("flushing");
+    }
+    assert!(path.exists() && !temp.exists());
+    assert_eq!(fs::read(&path).expect("reading"), b"data");
+    {
+        let mut w = io.new_ss(1).expect("creating").expect("new snapshot");
+        w.write_all(b"incomplete").expect("writing");
+    }
+    let abandoned = (dir.join("part-ss1.pip").exists(), dir.join("part-ss1.pip.tmp").exists());
+    
+    io.set_options(FileIoOptions { fsync: false, atomic_rename: false, lock: false });
+    {
+        let mut w = io.new_ss(2).expect("creating").expect("new snapshot");
+        w.write_all(b"direct").expect("writing");
+    }
+    let direct = fs::read(dir.join("part-ss2.pip"));
+    fs::remove_dir_all(&dir).expect("removing dir");
+    assert_eq!(abandoned, (false, false));
+    assert_eq!(direct.expect("reading"), b"direct");
+}
+
+#[test]
+fn vfs() {
+    fn names(entries: Vec<VfsEntry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+    
+    let control = new_control();
+    let mut part = Partition::create(control, "vfs").expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert(EltId::from(12), "twelve".to_string()).expect("inserting elt");
+    state.insert(EltId::from(3), "three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    
+    let vfs = PartitionVfs::new(&part);
+    assert_eq!(names(vfs.list("/").expect("listing")), vec!["snapshots", "logs", "current"]);
+    assert_eq!(names(vfs.list("snapshots").expect("listing")), vec!["ss0.pip"]);
+    assert_eq!(names(vfs.list("logs/").expect("listing")), vec!["ss0-cl0.piplog"]);
+    assert_eq!(names(vfs.list("current").expect("listing")), vec!["3", "12"]);
+    
+    assert_eq!(vfs.stat("current/12").expect("stat"), VfsEntry::file("12".to_string(), 6));
+    assert_eq!(vfs.stat("logs").expect("stat").kind, VfsKind::Dir);
+    let mut buf = [0u8; 4];
+    assert_eq!(vfs.read("/current/12", 2, &mut buf).expect("reading"), 4);
+    assert_eq!(&buf, b"elve");
+    assert_eq!(vfs.read("current/12", 6, &mut buf).expect("reading"), 0);
+    
+    let size = vfs.stat("snapshots/ss0.pip").expect("stat").size;
+    let mut data = vec![0; size as usize];
+    assert_eq!(vfs.read("snapshots/ss0.pip", 0, &mut data).expect("reading"), data.len());
+    assert_eq!(&data[0..8], b"PIPPINSS");
+    
+    assert!(vfs.stat("current/4").is_err());
+    assert!(vfs.read("logs/ss1-cl0.piplog", 0, &mut buf).is_err());
+    assert!(vfs.list("current/3").is_err());
+    assert!(vfs.read("current", 0, &mut buf).is_err());
+    
+    struct Redact;
+    impl MaskPolicy<String> for Redact {
+        fn mask(&self, id: EltId, elt: &String) -> Masked<String> {
+            match id.into() {
+                3 => Masked::Omit,
+                _ => Masked::Replace(elt.chars().map(|_| '*').collect()),
+            }
+        }
+    }
+    let vfs = PartitionVfs::with_mask(&part, &Redact);
+    assert_eq!(names(vfs.list("/").expect("listing")), vec!["current"]);
+    assert_eq!(names(vfs.list("current").expect("listing")), vec!["12"]);
+    let mut buf = [0u8; 8];
+    assert_eq!(vfs.read("current/12", 0, &mut buf).expect("reading"), 6);
+    assert_eq!(&buf[..6], b"******");
+    assert!(vfs.read("current/3", 0, &mut buf).is_err());
+    assert!(vfs.list("snapshots").is_err());
+    assert!(vfs.stat("logs/ss0-cl0.piplog").is_err());
+}
+
+#[test]
+fn repo_errors() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let err = Partition::open(Control::new(MemRepoIO::new()), true).err().expect("no snapshot");
+    let repo_err = RepoError::find(&*err).expect("is a RepoError");
+    assert_eq!(repo_err.code(), 6);
+    match *repo_err {
+        RepoError::NoSnapshot => {},
+        ref e => panic!("unexpected error: {}", e),
+    }
+    
+    let part = Partition::create(Control::new(MemRepoIO::new()), "repo_errors")
+            .expect("creating partition");
+    let io = part.unwrap_control().io().clone();
+    let err = Partition::create(Control::new(io), "repo_errors").err().expect("snapshot exists");
+    match RepoError::find(&*err) {
+        Some(&RepoError::SnapshotExists { ss_num: 0 }) => {},
+        e => panic!("unexpected error: {:?}", e),
+    }
+    assert_eq!(err.to_string(), "snapshot 0 already exists");
+    
+    // Wrapped errors are found via the source chain:
+    let err = RepoError::in_file(1, Some(2), Box::new(RepoError::SyncClosed));
+    assert_eq!(err.to_string(), "commit log 1-2: sync: connection closed");
+    assert_eq!(RepoError::find(&*err).map(|e| e.code()), Some(25));
+}
+
+
+// Reading and editing states
+
+#[test]
+fn replace_statesum() {
+    let control = new_control();
+    let mut part = Partition::create(control, "replace")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let id = state.insert_new("old".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    // Replacing must update the element sum as removal then insertion does:
+    let mut state1 = tip_mut(&part);
+    state1.replace_rc(id, "new".to_string().into()).expect("replacing elt");
+    let mut state2 = tip_mut(&part);
+    state2.remove(id).expect("removing elt");
+    state2.insert(id, "new".to_string()).expect("inserting elt");
+    assert_eq!(state1.elt_sum(), state2.elt_sum());
+    part.push_state(state1).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    
+    // The statesum is checked when the commit is replayed on load:
+    part.write_fast().expect("writing");
+    let control = part.unwrap_control();
+    let mut part2 = Partition::open(control, true).expect("opening partition");
+    part2.load_all().expect("part2.load");
+    assert_eq!(tip, *part2.tip().expect("part2 tip"));
+}
+
+#[test]
+fn get_many() {
+    let control = new_control();
+    let mut part = Partition::create(control, "get_many")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let id1 = state.insert_new("one".to_string()).expect("inserting elt");
+    let id2 = state.insert_new("two".to_string()).expect("inserting elt");
+    let missing = id1.next_elt();
+    assert!(missing != id2);
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    
+    let control = part.unwrap_control();
+    let mut part2 = Partition::open(control, false).expect("opening partition");
+    assert!(!part2.is_loaded());
+    let elts: Vec<Option<String>> = part2.get_many(&[id2, missing, id1])
+            .expect("get_many")
+            .into_iter().map(|e| e.map(|rc| (**rc).clone())).collect();
+    assert_eq!(elts, vec![Some("two".to_string()), None, Some("one".to_string())]);
+}
+
+#[test]
+fn get_candidates() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "get_candidates")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let id = state.insert_new("base".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.get_candidates(id).len(), 1);
+    
+    let base = part.tip().expect("has tip").clone_exact();
+    let mut state_a = base.clone_mut();
+    state_a.replace(id, "a".to_string()).expect("replacing elt");
+    let mut state_b = base.clone_mut();
+    state_b.remove(id).expect("removing elt");
+    let other = state_b.insert_new("other".to_string()).expect("inserting elt");
+    part.push_state(state_a).expect("committing");
+    part.push_state(state_b).expect("committing");
+    assert!(part.merge_required());
+    assert!(part.tip().is_err());
+    
+    let candidates = part.get_candidates(id);
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(**candidates[0].1, "a");
+    let candidates = part.get_candidates(other);
+    assert_eq!(candidates.len(), 1);
+    assert!(part.tips().contains(&candidates[0].0));
+    
+    let mut state_c = base.clone_mut();
+    state_c.replace(id, "c".to_string()).expect("replacing elt");
+    part.push_state(state_c).expect("committing");
+    let candidates = part.get_candidates(id);
+    let values: Vec<&str> = candidates.iter().map(|c| c.1.as_str()).collect();
+    assert_eq!(values.len(), 2);
+    assert!(values.contains(&"a") && values.contains(&"c"));
+    assert!(candidates[0].0 < candidates[1].0);
+}
+
+#[test]
+fn sum_prefixes() {
+    let control = new_control();
+    let mut part = Partition::create(control, "prefixes").expect("creating partition");
+    for s in &["one", "two", "three"] {
+        let mut state = tip_mut(&part);
+        state.insert_new(s.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    let tip = part.tip_key().expect("has tip").clone();
+    assert_eq!(part.sums_with_prefix("").len(), 4);
+    match part.state_from_string(String::new()) {
+        Err(MatchError::MultiMatch(matches)) => assert_eq!(matches.len(), 4),
+        _ => panic!("expected multiple matches"),
+    }
+    let text = tip.as_string(true).to_lowercase().replace(' ', ":");
+    assert_eq!(part.sums_with_prefix(&text[0..29]), vec![&tip]);
+    assert_eq!(part.state_from_string(text).map(|state| state.statesum()), Ok(&tip));
+}
+
+#[test]
+fn upsert_and_replace_if() {
+    let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+    let mut part = Partition::create(control, "upsert").expect("creating partition");
+    let id = EltId::from(7);
+    
+    let mut state = tip_mut(&part);
+    assert_eq!(state.upsert(id, |old| { assert!(old.is_none()); "one".to_string() }), None);
+    let old = state.upsert(id, |old| format!("{}+", old.expect("has elt")));
+    assert_eq!(old.map(|elt| (*elt).clone()), Some("one".to_string()));
+    part.push_state(state).expect("committing");
+    
+    // Two writers read the element; A replaces it first:
+    let read_sum = part.tip().expect("has tip").get_rc(id).expect("has elt").sum(id);
+    let mut state = tip_mut(&part);
+    state.replace_if(id, &read_sum, "a".to_string()).expect("replacing");
+    part.push_state(state).expect("committing");
+    // B's replacement, applied to the latest state, fails:
+    let mut state = tip_mut(&part);
+    assert_eq!(state.replace_if(id, &read_sum, "b".to_string()), Err(ElementOp::SumMismatch));
+    assert_eq!(state.replace_if(EltId::from(8), &read_sum, "b".to_string()),
+            Err(ElementOp::EltNotFound));
+    assert_eq!(part.tip().expect("has tip").get(id).expect("has elt"), "a");
+}
+
+#[test]
+fn element_operations() {
+    fn contains(data: &[u8], pat: &[u8]) -> bool {
+        data.windows(pat.len()).any(|w| w == pat)
+    }
+    
+    // A list of lines; the only operation appends a line
+    #[derive(PartialEq, Eq, Debug)]
+    struct Lines(String);
+    impl Element for Lines {
+        fn write_buf(&self, writer: &mut Write) -> Result<()> {
+            writer.write_all(self.0.as_bytes())?;
+            Ok(())
+        }
+        fn read_buf(buf: &[u8]) -> Result<Self> {
+            Ok(Lines(String::from_utf8(buf.to_vec())?))
+        }
+        fn apply_op_fn() -> Option<ApplyOpFn<Self>> {
+            Some(<Self as ApplyOp>::apply_op)
+        }
+    }
+    impl ApplyOp for Lines {
+        fn apply_op(&self, op: &[u8]) -> Result<Self> {
+            Ok(Lines(format!("{}{}\n", self.0, String::from_utf8(op.to_vec())?)))
+        }
+    }
+    
+    type Control = DefaultControl<Lines, PartitionStreams>;
+    let control = Control::new(PartitionStreams::new());
+    let mut part = Partition::create(control, "operations").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let id = state.insert_new(Lines("first line\n".to_string())).expect("inserting elt");
+    let other = state.insert_new(Lines(String::new())).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    
+    let mut state = tip_mut(&part);
+    state.apply_op(id, b"second line".to_vec()).expect("applying op");
+    state.apply_op(id, b"third line".to_vec()).expect("applying op");
+    // After a replacement the full element is recorded:
+    state.replace(other, Lines("replaced\n".to_string())).expect("replacing elt");
+    state.apply_op(other, b"appended".to_vec()).expect("applying op");
+    assert_eq!(state.apply_op(EltId::from(1), vec![]), Err(ElementOp::EltNotFound));
+    part.push_state(state).expect("committing");
+    let expected = "first line\nsecond line\nthird line\n";
+    assert_eq!(part.tip().expect("has tip").get(id).expect("has elt").0, expected);
+    part.write_fast().expect("writing");
+    
+    let control = part.unwrap_control();
+    let log = &control.io().ss[1].1[0];
+    assert!(contains(log, b"ELT PATC") && contains(log, b"third line"));
+    assert!(!contains(log, b"first line"));
+    assert!(contains(log, b"replaced\nappended"));
+    
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    let tip = part.tip().expect("has tip");
+    assert_eq!(tip.get(id).expect("has elt").0, expected);
+    assert_eq!(tip.get(other).expect("has elt").0, "replaced\nappended\n");
+}
+
+#[test]
+fn clone_mut_detached() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "detached")
+            .expect("creating partition");
+    let mut old = None;
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        if i == 0 {
+            old = Some(part.tip().expect("has tip").clone_exact());
+        }
+    }
+    let old = old.unwrap();
+    let detached = old.clone_mut_detached();
+    let attached = old.clone_mut();
+    part.evict_history();
+    assert!(part.state(old.statesum()).is_none());
+    
+    // Only the detached clone can be committed once its parent is dropped:
+    assert_eq!(part.push_state(attached), Err(PatchOp::NoParent));
+    let mut state = detached;
+    state.insert_new("reverted".to_string()).expect("inserting elt");
+    assert_eq!(part.push_state(state), Ok(true));
+    assert!(part.state(old.statesum()).is_some());
+    assert_eq!(part.tips_len(), 2);
+    assert!(part.merge_required());
+    
+    // States not known to the partition are still rejected:
+    let mut other = Partition::create(Control::new(MemRepoIO::new()), "other")
+            .expect("creating partition");
+    let mut state = tip_mut(&other);
+    state.insert_new("other".to_string()).expect("inserting elt");
+    other.push_state(state).expect("committing");
+    let mut state = other.tip().expect("has tip").clone_mut_detached();
+    state.insert_new("foreign".to_string()).expect("inserting elt");
+    assert_eq!(part.push_state(state), Err(PatchOp::NoParent));
+}
+
+#[test]
+fn changed_ids() {
+    let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+    let mut part = Partition::create(control, "changed").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let a = state.insert_new("a".to_string()).expect("inserting elt");
+    let b = state.insert_new("b".to_string()).expect("inserting elt");
+    let c = state.insert_new("c".to_string()).expect("inserting elt");
+    assert_eq!(state.changed_ids().len(), 3);
+    part.push_state(state).expect("committing");
+    
+    let mut state = tip_mut(&part);
+    assert!(state.changed_ids().is_empty());
+    state.replace(a, "A".to_string()).expect("replacing elt");
+    state.remove(b).expect("removing elt");
+    let d = state.insert_new("d".to_string()).expect("inserting elt");
+    let expected: HashSet<EltId> = vec![a, b, d].into_iter().collect();
+    assert_eq!(*state.changed_ids(), expected);
+    assert!(state.is_changed(d) && !state.is_changed(c));
+}
+
+#[test]
+fn derived_elements() {
+    // Maintains element 0 as the number of other elements
+    #[derive(Debug)]
+    struct Count;
+    impl DerivedElements<String> for Count {
+        fn derive(&mut self, _parent: &PartState<String>, state: &mut MutPartState<String>)
+                -> Result<(), ElementOp>
+        {
+            let id = EltId::from(0);
+            let n = state.elts_iter().filter(|&(i, _)| i != id).count();
+            state.upsert(id, |_| n.to_string());
+            Ok(())
+        }
+    }
+    
+    let mut control = DefaultControl::<String, _>::new(MemRepoIO::new());
+    control.set_derived_elements(Some(Box::new(Count)));
+    let mut part = Partition::create(control, "derived").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let a = state.insert_new("a".to_string()).expect("inserting elt");
+    state.insert_new("b".to_string()).expect("inserting elt");
+    assert!(part.push_state(state).expect("committing"));
+    assert_eq!(part.tip().expect("has tip").get(EltId::from(0)).expect("has count"), "2");
+    
+    let mut state = tip_mut(&part);
+    state.remove(a).expect("removing elt");
+    assert!(part.push_state(state).expect("committing"));
+    assert_eq!(part.tip().expect("has tip").get(EltId::from(0)).expect("has count"), "1");
+    assert_eq!(part.unsaved_len(), 2);
+    
+    // Unchanged states are not committed:
+    let state = tip_mut(&part);
+    assert!(!part.push_state(state).expect("committing"));
+}
+
+#[test]
+fn find_by_index() {
+    fn first_char(elt: &String) -> Vec<IndexKey> {
+        elt.chars().take(1).map(|c| IndexKey::from(c.to_string())).collect()
+    }
+    fn words(elt: &String) -> Vec<IndexKey> {
+        elt.split_whitespace().map(IndexKey::from).collect()
+    }
+    
+    let mut control = DefaultControl::<String, _>::new(MemRepoIO::new());
+    assert_eq!(control.add_index(first_char), 0);
+    assert_eq!(control.add_index(words), 1);
+    let mut part = Partition::create(control, "index").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let a = state.insert_new("apple pie".to_string()).expect("inserting elt");
+    let b = state.insert_new("banana pie".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    assert_eq!(part.find_by_index(0, &"a".into()).expect("finding"), vec![a]);
+    let mut pies = vec![a, b];
+    pies.sort();
+    assert_eq!(part.find_by_index(1, &"pie".into()).expect("finding"), pies);
+    assert!(part.find_by_index(2, &"pie".into()).is_err());
+    
+    // Updated incrementally:
+    let mut state = tip_mut(&part);
+    state.replace(a, "apricot tart".to_string()).expect("replacing elt");
+    state.remove(b).expect("removing elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.find_by_index(0, &"a".into()).expect("finding"), vec![a]);
+    assert!(part.find_by_index(1, &"pie".into()).expect("finding").is_empty());
+    assert_eq!(part.find_by_index(1, &"tart".into()).expect("finding"), vec![a]);
+    
+    // Rebuilt after reloading:
+    part.write_fast().expect("writing");
+    let mut part = Partition::open(part.unwrap_control(), true).expect("opening partition");
+    assert_eq!(part.find_by_index(1, &"apricot".into()).expect("finding"), vec![a]);
+}
+
+#[test]
+fn text_canon() {
+    use std::rc::Rc;
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let canon = StdTextCanon::new().with_line_endings();
+    assert_eq!(canon.canonicalise("a\nb"), None);
+    assert_eq!(canon.canonicalise("a\r\nb\rc").as_deref(), Some("a\nb\nc"));
+    
+    // Replicas differing only in line endings reach the same state:
+    let part = Partition::create(Control::new(MemRepoIO::new()), "text_canon")
+            .expect("creating partition");
+    let io = part.unwrap_control().io().clone();
+    let mut sums = vec![];
+    for text in ["one\r\ntwo", "one\ntwo"] {
+        let mut control = Control::new(io.clone());
+        control.set_text_canon(Some(Rc::new(canon)));
+        let mut part = Partition::open(control, true).expect("opening partition");
+        let mut state = tip_mut(&part);
+        state.insert(EltId::from(1), text.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        let tip = part.tip().expect("has tip");
+        assert_eq!(tip.get(EltId::from(1)).map(|s| s.as_str()), Ok("one\ntwo"));
+        sums.push(tip.statesum().clone());
+    }
+    assert_eq!(sums[0], sums[1]);
+}
+
+#[cfg(feature = "unicode")]
+#[test]
+fn text_canon_nfc() {
+    let canon = StdTextCanon::new().with_nfc();
+    assert_eq!(canon.canonicalise("caf\u{e9}"), None);
+    assert_eq!(canon.canonicalise("cafe\u{301}"), Some("caf\u{e9}".to_string()));
+}
+
+#[test]
+fn revert() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "revert")
+            .expect("creating partition");
+    let (one, two, three) = (EltId::from(1), EltId::from(2), EltId::from(3));
+    let mut state = tip_mut(&part);
+    state.insert(one, "one".to_string()).expect("inserting elt");
+    state.insert(two, "two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    // Commit to revert: change one, remove two, insert three
+    let mut state = tip_mut(&part);
+    state.replace(one, "changed".to_string()).expect("replacing elt");
+    state.remove(two).expect("removing elt");
+    state.insert(three, "three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let reverted = part.tip_key().expect("has tip").clone();
+    
+    // Unrelated later commit:
+    let mut state = tip_mut(&part);
+    state.insert(EltId::from(4), "four".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    let commit = {
+        let state = part.state(&reverted).expect("has state");
+        let parent = part.state(&state.parents()[0]).expect("has parent");
+        Commit::recreate(parent, state)
+    };
+    assert_eq!(commit.num_changes(), 3);
+    
+    assert!(part.revert(&reverted).expect("reverting"));
+    {
+        let tip = part.tip().expect("has tip");
+        assert_eq!(tip.get(one).map(|s| s.as_str()), Ok("one"));
+        assert_eq!(tip.get(two).map(|s| s.as_str()), Ok("two"));
+        assert!(!tip.is_avail(three));
+        assert!(tip.is_avail(EltId::from(4)));
+    }
+    
+    // Reverting again conflicts, since the elements have since changed:
+    let tip = part.tip_key().expect("has tip").clone();
+    let err = part.revert(&reverted).unwrap_err();
+    assert_eq!(err.downcast_ref::<PatchOp>(), Some(&PatchOp::Conflict));
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    
+    // Reverting the revert restores the changes:
+    assert!(part.revert(&tip).expect("reverting"));
+    let tip = part.tip().expect("has tip");
+    assert_eq!(tip.get(one).map(|s| s.as_str()), Ok("changed"));
+    assert!(!tip.is_avail(two));
+    assert!(tip.is_avail(three));
+}
+
+#[test]
+fn unsaved_limit() {
+    fn commit(part: &mut Partition<StreamControl>, s: &str) -> Result<bool, PatchOp> {
+        let mut state = tip_mut(&part);
+        state.insert_new(s.to_string()).expect("inserting elt");
+        part.push_state(state)
+    }
+    let mut control = new_control();
+    control.set_max_unsaved(Some(2));
+    let mut part = Partition::create(control, "unsaved").expect("creating partition");
+    assert_eq!(commit(&mut part, "one"), Ok(true));
+    assert_eq!(commit(&mut part, "two"), Ok(true));
+    assert_eq!(commit(&mut part, "three"), Err(PatchOp::UnsavedLimit));
+    assert_eq!(part.unsaved_len(), 2);
+    
+    part.write_fast().expect("writing");
+    assert_eq!(commit(&mut part, "three"), Ok(true));
+}
+
+#[test]
+fn coalesce_commits() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut control = Control::new(MemRepoIO::new());
+    control.set_coalesce_window(Some(60));
+    let mut part = Partition::create(control, "coalesce").expect("creating partition");
+    let initial = part.tip_key().expect("has tip").clone();
+    for i in 0..5 {
+        let mut state = tip_mut(&part);
+        state.insert(EltId::from(i), format!("elt {}", i)).expect("inserting elt");
+        if i > 0 {
+            state.remove(EltId::from(0)).ok();
+        }
+        assert!(part.push_state(state).expect("committing"));
+    }
+    assert_eq!(part.states_len(), 2);
+    let tip = part.tip_key().expect("has tip").clone();
+    {
+        let state = part.tip().expect("has tip");
+        assert_eq!(state.parents(), &[initial]);
+        assert_eq!(state.num_avail(), 4);
+        assert!(!state.is_avail(EltId::from(0)));
+    }
+    
+    // Written commits are not replaced:
+    part.write_fast().expect("writing");
+    let mut state = tip_mut(&part);
+    state.insert(EltId::from(9), "elt 9".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.states_len(), 3);
+    part.write_fast().expect("writing");
+    let tip2 = part.tip_key().expect("has tip").clone();
+    
+    let io = part.unwrap_control().unwrap_io();
+    let mut part = Partition::open(Control::new(io), true).expect("opening partition");
+    part.load_all().expect("loading");
+    assert_eq!(part.tip_key().expect("has tip"), &tip2);
+    assert!(part.state(&tip).is_some());
+    assert_eq!(part.states_len(), 3);
+}
+
+#[test]
+fn commit_summary() {
+    let control = new_control();
+    let mut part = Partition::create(control, "summary").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let id = state.insert_new("one".to_string()).expect("inserting elt");
+    state.insert_new("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let s1 = part.tip_key().expect("has tip").clone();
+    let mut state = tip_mut(&part);
+    state.replace(id, "three".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let s2 = part.tip_key().expect("has tip").clone();
+    assert_eq!(part.tip().expect("has tip").meta().summary(),
+            Some(CommitSummary { elements: 2, byte_delta: 2 }));
+    part.write_fast().expect("writing");
+    
+    let io = part.unwrap_control().unwrap_io();
+    let part = Partition::open(StreamControl::new(io), true).expect("opening partition");
+    let summary = |sum| part.state(sum).expect("has state").meta().summary();
+    assert_eq!(summary(&s1), Some(CommitSummary { elements: 2, byte_delta: 6 }));
+    assert_eq!(summary(&s2), Some(CommitSummary { elements: 2, byte_delta: 2 }));
+}
+
+#[test]
+fn commit_author() {
+    let mut control = new_control();
+    control.set_author(Some("laptop".to_string()));
+    let mut part = Partition::create(control, "author").expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let s1 = part.tip_key().expect("has tip").clone();
+    part.write_fast().expect("writing");
+    
+    let mut control = part.unwrap_control();
+    control.set_author(Some("p".repeat(MAX_AUTHOR_BYTES)));
+    let mut part = Partition::open(control, true).expect("opening partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let s2 = part.tip_key().expect("has tip").clone();
+    part.write_fast().expect("writing");
+    
+    let io = part.unwrap_control().unwrap_io();
+    let part = Partition::open(StreamControl::new(io), true).expect("opening partition");
+    let author = |sum| part.state(sum).expect("has state").meta().author().map(|a| a.to_string());
+    assert_eq!(author(&s1), Some("laptop".to_string()));
+    assert_eq!(author(&s2), Some("p".repeat(MAX_AUTHOR_BYTES)));
+    
+    let mut meta = part.tip().expect("has tip").meta().clone();
+    assert!(meta.set_author(Some("x".repeat(MAX_AUTHOR_BYTES + 1))).is_err());
+    assert!(meta.set_author(Some(String::new())).is_err());
+}
+
+
+// Writing snapshots and commit logs
+
+#[test]
+fn write_stats() {
+    let control = new_control();
+    let mut part = Partition::create(control, "write_stats").expect("creating partition");
+    let stats = *part.write_stats();
+    assert_eq!((stats.snapshots, stats.logs, stats.commits), (1, 0, 0));
+    assert!(stats.snapshot_bytes > 0);
+    assert_eq!(stats.amplification(), None);
+    assert!(part.maintenance_advice().is_empty());
+    
+    let mut state = tip_mut(&part);
+    let id = state.insert_new("x".repeat(1000)).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    part.reset_write_stats();
+    
+    // Small changes to a large state, with frequent snapshots:
+    for i in 0..4 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("{}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        part.write_snapshot().expect("writing snapshot");
+    }
+    let stats = *part.write_stats();
+    assert_eq!((stats.snapshots, stats.logs, stats.commits), (4, 4, 4));
+    assert_eq!(stats.changed_bytes, 4);
+    assert_eq!(stats.total_bytes(), stats.snapshot_bytes + stats.log_bytes);
+    assert!(stats.snapshot_amplification().expect("has changes") > 1000.0);
+    let advice = part.maintenance_advice();
+    assert_eq!(advice.len(), 2);
+    assert!(advice[1].contains("snapshot policy"));
+    
+    let mut state = tip_mut(&part);
+    state.remove(id).expect("removing elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    assert_eq!(part.write_stats().changed_bytes, 4);
+}
+
+#[test]
+fn file_written() {
+    let control = WatchControl {
+        io: PartitionStreams::new(),
+        ss_policy: DefaultSnapshot::default(),
+        written: vec![],
+        mem_limit: None,
+    };
+    let mut part = Partition::create(control, "file_written")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert!(part.write_fast().expect("writing"));
+    part.write_snapshot().expect("writing snapshot");
+    assert!(!part.write_fast().expect("writing"));  // nothing to write
+    
+    let control = part.unwrap_control();
+    assert_eq!(control.written, vec![WrittenFile::Snapshot(0),
+            WrittenFile::CommitLog(0, 0), WrittenFile::Snapshot(1)]);
+}
+
+#[test]
+fn safety_snapshot() {
+    let control = new_control();
+    let mut part = Partition::create(control, "safety")
+            .expect("creating partition");
+    
+    // Create two diverged tips:
+    let mut state1 = tip_mut(&part);
+    let mut state2 = tip_mut(&part);
+    state1.insert_new("one".to_string()).expect("inserting elt");
+    state2.insert_new("two".to_string()).expect("inserting elt");
+    part.push_state(state1).expect("committing");
+    part.push_state(state2).expect("committing");
+    assert!(part.merge_required());
+    assert!(part.write_snapshot().is_err());
+    
+    assert!(part.safety_snapshot("").is_err());
+    part.safety_snapshot("before merge").expect("safety snapshot");
+    assert_eq!(part.unsaved_len(), 0);
+    let mut tagged = part.tagged("before merge").to_vec();
+    tagged.sort();
+    let mut tips: Vec<Sum> = part.tips_iter().cloned().collect();
+    tips.sort();
+    assert_eq!(tagged, tips);
+    assert!(part.tagged("other").is_empty());
+    
+    let control = part.unwrap_control();
+    assert_eq!(control.io().ss_len(), 3);
+    let mut part2 = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part2.tagged("before merge").len(), 1);
+    part2.load_all().expect("part2.load");
+    let mut tagged2 = part2.tagged("before merge").to_vec();
+    tagged2.sort();
+    assert_eq!(tagged2, tagged);
+    assert!(part2.state(&tagged2[0]).is_some());
+}
+
+#[test]
+fn snapshot_config() {
+    let config = SnapshotConfig::new(Some(2), None, Some(3600), Some(1 << 20));
+    assert_eq!(SnapshotConfig::from_text(&config.to_text()), Some(config.clone()));
+    assert_eq!(SnapshotConfig::from_text("snapshot-policy commits=x"), None);
+    
+    let mut control = new_control();
+    control.set_snapshot_config(config.clone());
+    let mut part = Partition::create(control, "ss config").expect("creating partition");
+    for s in &["one", "two", "three"] {
+        let mut state = tip_mut(&part);
+        state.insert_new(s.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_full().expect("writing");
+    }
+    assert_eq!(part.control().io().ss_len(), 2);
+    
+    let io = part.unwrap_control().unwrap_io();
+    let part = Partition::open(StreamControl::new(io), true).expect("opening partition");
+    assert!(part.control().snapshot_config().same_limits(&config));
+}
+
+#[test]
+fn reproducible_snapshots() {
+    let mut control = new_control();
+    control.set_reproducible_snapshots(true);
+    let mut part = Partition::create(control, "reproducible").expect("creating partition");
+    for s in &["one", "two", "three", "four"] {
+        let mut state = tip_mut(&part);
+        state.insert_new(s.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    
+    // Reload from the original snapshot and log, then write again:
+    let control = part.unwrap_control();
+    let mut part = Partition::open(control, false).expect("opening partition");
+    part.load_range(0, 1, Recovery::Strict).expect("loading");
+    part.write_snapshot().expect("writing snapshot");
+    
+    let control = part.unwrap_control();
+    let ss1 = control.io().ss.get(1).and_then(|x| x.0.clone()).expect("has ss1");
+    let ss2 = control.io().ss.get(2).and_then(|x| x.0.clone()).expect("has ss2");
+    assert!(ss1.windows(8).all(|w| w != b"ELEMENTM"));
+    assert_eq!(ss1, ss2);
+}
+
+#[test]
+fn dedup_snapshots() {
+    let mut control = new_control();
+    control.set_dedup_snapshots(true);
+    let mut part = Partition::create(control, "dedup").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let ids: Vec<EltId> = ["same", "other", "same", "same"].iter()
+            .map(|s| state.insert_new(s.to_string()).expect("inserting elt"))
+            .collect();
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    let statesum = part.tip_key().expect("has tip").clone();
+    
+    let control = part.unwrap_control();
+    let ss1 = control.io().ss.get(1).and_then(|x| x.0.clone()).expect("has ss1");
+    assert_eq!(ss1.windows(6).filter(|w| *w == b"ELTREF").count(), 2);
+    let mut part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(*part.tip_key().expect("has tip"), statesum);
+    let tip = part.tip().expect("has tip");
+    assert!(Rc::ptr_eq(tip.get_rc(ids[0]).expect("has elt"), tip.get_rc(ids[3]).expect("has elt")));
+    assert_eq!(tip.get(ids[1]).expect("has elt"), "other");
+    // Deduplicated snapshots have no element index; all is read instead:
+    part.load_lazy().expect("loading lazily");
+    assert_eq!(part.read_snapshot_filtered(EltIdRange::all()).expect("reading all").len(), 4);
+}
+
+#[test]
+fn compression() {
+    for &method in &[Compression::Zlib, Compression::Zstd] {
+        let mut control = new_control();
+        control.set_compression(method);
+        let result = Partition::create(control, "compressed");
+        if !method.is_supported() {
+            assert!(result.is_err());
+            continue;
+        }
+        let mut part = result.expect("creating partition");
+        let mut state = tip_mut(&part);
+        for i in 0..50 {
+            state.insert_new(format!("element number {}", i)).expect("inserting elt");
+        }
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        let tip = part.tip_key().expect("has tip").clone();
+        
+        let io = part.unwrap_control().unwrap_io();
+        let log = io.ss.get(0).and_then(|x| x.1.get(0).cloned()).expect("has log");
+        assert!(log.windows(10).any(|w| w == b"HCOMPRESS "));
+        assert!(log.windows(14).filter(|w| *w == b"element number").count() < 10);
+        let mut part = Partition::open(StreamControl::new(io), true).expect("opening partition");
+        assert_eq!(part.tip_key().expect("has tip"), &tip);
+        part.write_snapshot().expect("writing snapshot");
+        
+        let io = part.unwrap_control().unwrap_io();
+        let part = Partition::open(StreamControl::new(io), true).expect("opening partition");
+        assert_eq!(part.tip().expect("has tip").statesum(), &tip);
+        assert_eq!(part.tip().expect("has tip").num_avail(), 50);
+    }
+}
+
+// Not secure! XOR with the key byte, appending a checksum of data and aad
+#[derive(Debug)]
+struct XorCipher(u8);
+impl Cipher for XorCipher {
+    fn scheme(&self) -> &str { "xor" }
+    fn key_id(&self) -> u64 { self.0 as u64 }
+    fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let check = aad.iter().chain(data).fold(0u8, |a, b| a.wrapping_mul(31) ^ b);
+        Ok(data.iter().map(|b| b ^ self.0).chain(Some(check)).collect())
+    }
+    fn open(&self, key_id: u64, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let (check, data) = sealed.split_last().expect("has check");
+        let data: Vec<u8> = data.iter().map(|b| b ^ key_id as u8).collect();
+        if aad.iter().chain(&data).fold(0u8, |a, b| a.wrapping_mul(31) ^ b) != *check {
+            return OtherError::err("check failed");
+        }
+        Ok(data)
+    }
+}
+
+#[test]
+fn encryption() {
+    let cipher: Rc<Cipher> = Rc::new(XorCipher(0x5A));
+    let mut control = new_control();
+    control.set_cipher(Some(cipher.clone()));
+    let mut part = Partition::create(control, "encrypted").expect("creating partition");
+    let mut state = tip_mut(&part);
+    for i in 0..50 {
+        state.insert_new(format!("element number {}", i)).expect("inserting elt");
+    }
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let tip = part.tip_key().expect("has tip").clone();
+    
+    let io = part.unwrap_control().unwrap_io();
+    let log = io.ss.get(0).and_then(|x| x.1.get(0).cloned()).expect("has log");
+    assert!(log.windows(9).any(|w| w == b"Q3CIPHER "));
+    assert!(!log.windows(14).any(|w| w == b"element number"));
+    // Without the cipher, files cannot be read:
+    let copy = PartitionStreams { ss: io.ss.clone() };
+    assert!(Partition::open(StreamControl::new(copy), true).is_err());
+    
+    let mut control = StreamControl::new(io);
+    control.set_cipher(Some(cipher.clone()));
+    let mut part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    part.write_snapshot().expect("writing snapshot");
+    
+    let io = part.unwrap_control().unwrap_io();
+    let ss = io.ss.get(1).and_then(|x| x.0.clone()).expect("has snapshot");
+    assert!(!ss.windows(14).any(|w| w == b"element number"));
+    let mut control = StreamControl::new(io);
+    control.set_cipher(Some(cipher));
+    let part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part.tip().expect("has tip").statesum(), &tip);
+    assert_eq!(part.tip().expect("has tip").num_avail(), 50);
+}
+
+/// Wraps `MemRepoIO`; while `fail` is set, new logs fail when flushed.
+#[derive(Debug)]
+struct FailingLogIO {
+    io: MemRepoIO,
+    fail: Rc<Cell<bool>>,
+}
+
+struct FailOnFlush<'a>(Box<Write+'a>);
+impl<'a> Write for FailOnFlush<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.0.write(buf) }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Err(std::io::Error::other("flush failed"))
+    }
+}
+
+impl RepoIO for FailingLogIO {
+    fn ss_len(&self) -> usize { self.io.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.io.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.io.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_ss(ss_num)
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let fail = self.fail.get();
+        Ok(self.io.new_ss_cl(ss_num, cl_num)?.map(|w| if fail {
+            Box::new(FailOnFlush(w)) as Box<Write+'a>
+        } else {
+            w
+        }))
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        self.io.remove_ss(ss_num)
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        self.io.remove_ss_cl(ss_num, cl_num)
+    }
+    fn replace_ss(&mut self, ss_num: usize, data: &[u8]) -> Result<bool> {
+        self.io.replace_ss(ss_num, data)
+    }
+    fn replace_ss_cl(&mut self, ss_num: usize, cl_num: usize, data: &[u8]) -> Result<bool> {
+        self.io.replace_ss_cl(ss_num, cl_num, data)
+    }
+}
+
+#[test]
+fn write_failure() {
+    type Control = DefaultControl<String, FailingLogIO>;
+    let cipher: Rc<Cipher> = Rc::new(XorCipher(0x5A));
+    for &(method, encrypt) in &[(Compression::None, false), (Compression::Zlib, false),
+            (Compression::None, true), (Compression::Zlib, true)] {
+        if !method.is_supported() {
+            continue;
+        }
+        let cipher = if encrypt { Some(cipher.clone()) } else { None };
+        let fail = Rc::new(Cell::new(false));
+        let mut control = Control::new(FailingLogIO { io: MemRepoIO::new(), fail: fail.clone() });
+        control.set_compression(method);
+        control.set_cipher(cipher.clone());
+        let mut part = Partition::create(control, "failing").expect("creating partition");
+        for i in 0..3 {
+            let mut state = tip_mut(&part);
+            state.insert_new(format!("element number {}", i)).expect("inserting elt");
+            part.push_state(state).expect("committing");
+        }
+        let tip = part.tip_key().expect("has tip").clone();
+        
+        // Commits are not lost when writing fails, and may be written later:
+        fail.set(true);
+        assert!(part.write_fast().is_err());
+        assert_eq!(part.unsaved_len(), 3);
+        assert_eq!(part.control().io().io.ss_cl_len(0), 0);
+        fail.set(false);
+        assert!(part.write_fast().expect("writing"));
+        assert_eq!(part.unsaved_len(), 0);
+        
+        let mut control = Control::new(part.unwrap_control().unwrap_io());
+        control.set_cipher(cipher);
+        let mut part = Partition::open(control, true).expect("opening partition");
+        part.load_all().expect("loading");
+        assert_eq!(part.tip_key().expect("has tip"), &tip);
+        assert_eq!(part.tip().expect("has tip").num_avail(), 3);
+    }
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn log_index() {
+    use std::fs;
+    
+    let dir = std::env::temp_dir().join(format!("pippin-log-index-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let io = RepoFileIO::new(dir.join("part"));
+    let control = DefaultControl::<String, _>::new(io);
+    let mut part = Partition::create(control, "log index").expect("creating partition");
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    let result = part.check_log(0, 0);
+    let log = dir.join("part-ss0-cl0.piplog");
+    let len = fs::metadata(&log).expect("log metadata").len();
+    fs::OpenOptions::new().write(true).open(&log).expect("opening log")
+            .set_len(len - 10).expect("truncating log");
+    let truncated = part.check_log(0, 0);
+    let missing = part.check_log(0, 1);
+    fs::remove_dir_all(&dir).expect("removing dir");
+    
+    assert_eq!(result.expect("checking log"), Some(LogCheck::Complete));
+    assert_eq!(truncated.expect("checking log"), Some(LogCheck::Truncated(2)));
+    assert_eq!(missing.expect("checking log"), None);
+    
+    // A malformed index with an entry ending before the first sum:
+    let mut index = LogIndex::new(0);
+    index.push(5, Sum::zero());
+    assert!(index.check(&mut &[0u8; 64][..]).is_err());
+}
+
+#[test]
+fn log_appender() {
+    use pippin::rw::commitlog::write_commit;
+    
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "appender")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    
+    // An external tool prepares commits on top of the tip:
+    let mut mcm = Control::new(MemRepoIO::new());
+    let mut chain = CommitChain::new(part.tip().expect("has tip"));
+    for text in &["two", "three"] {
+        let mut state = chain.tip().clone_mut();
+        state.insert_new(text.to_string()).expect("inserting elt");
+        chain.push_state(state, &mut mcm).expect("committing");
+    }
+    let commits: Vec<Vec<u8>> = chain.commits().iter().map(|commit| {
+        let mut buf = Vec::new();
+        write_commit(commit, &mut buf).expect("serialising");
+        buf
+    }).collect();
+    let parent = part.tip_key().expect("has tip").clone();
+    let sum1 = chain.commits()[0].statesum().clone();
+    
+    let mut io = part.unwrap_control().io().clone();
+    let mut appender = LogAppender::open::<String>(
+            &mut io.ss_cl_data(0, 0).expect("has log"), UserMetaLimits::default())
+            .expect("opening log");
+    assert_eq!(appender.name(), "appender");
+    assert_eq!(appender.tip(), Some(&parent));
+    {
+        let mut w = io.append_ss_cl(0, 0).expect("appending").expect("has log");
+        // Wrong order or corrupt data is rejected:
+        assert!(appender.append::<String>(&parent, &commits[1], &mut w).is_err());
+        assert!(appender.append::<String>(&parent, &commits[0][1..], &mut w).is_err());
+        assert_eq!(appender.append::<String>(&parent, &commits[0], &mut w).expect("appending"),
+                sum1);
+        assert!(appender.append::<String>(&parent, &commits[1], &mut w).is_err());
+        appender.append::<String>(&sum1, &commits[1], &mut w).expect("appending");
+    }
+    assert_eq!(appender.num_commits(), 3);
+    
+    let mut part = Partition::open(Control::new(io), true).expect("opening partition");
+    part.load_all().expect("loading");
+    assert_eq!(part.tip_key().expect("has tip"), chain.tip().statesum());
+}
+
+#[test]
+fn append_log() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let appending = |io: MemRepoIO| {
+        let mut control = Control::new(io);
+        control.set_log_append_limit(Some(DEFAULT_LOG_APPEND_LIMIT));
+        control
+    };
+    let push = |part: &mut Partition<Control>, i: u64| {
+        let mut state = tip_mut(&part);
+        state.insert(EltId::from(i), format!("elt {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+    };
+    
+    // Appending is not enabled by default:
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "append_log")
+            .expect("creating partition");
+    push(&mut part, 1);
+    push(&mut part, 2);
+    assert_eq!(part.control().io().ss_cl_len(0), 2);
+    
+    let mut part = Partition::create(appending(MemRepoIO::new()), "append_log")
+            .expect("creating partition");
+    for i in 1..4 {
+        push(&mut part, i);
+    }
+    assert_eq!(part.control().io().ss_cl_len(0), 1);
+    assert_eq!(part.write_stats().logs, 1);
+    assert_eq!(part.write_stats().commits, 3);
+    assert_eq!(part.check_log(0, 0).expect("checking log"), Some(LogCheck::Complete));
+    
+    // The index allows another instance to append:
+    let io = part.unwrap_control().io().clone();
+    let mut part = Partition::open(appending(io), true).expect("opening partition");
+    push(&mut part, 4);
+    assert_eq!(part.control().io().ss_cl_len(0), 1);
+    
+    // Beyond the limit a new log is started:
+    let size = part.control().io().ss_cl_size(0, 0).expect("reading size");
+    let mut control = part.unwrap_control();
+    control.set_log_append_limit(size);
+    let mut part = Partition::open(control, true).expect("opening partition");
+    push(&mut part, 5);
+    push(&mut part, 6);
+    assert_eq!(part.control().io().ss_cl_len(0), 2);
+    assert_eq!(part.check_log(0, 1).expect("checking log"), Some(LogCheck::Complete));
+    
+    let io = part.unwrap_control().io().clone();
+    let part = Partition::open(Control::new(io), true).expect("opening partition");
+    let state = part.tip().expect("has tip");
+    assert_eq!(state.num_avail(), 6);
+    assert_eq!(state.get(EltId::from(4)).map(|s| s.as_str()), Ok("elt 4"));
+    
+    // A compressed log is not appended to, even if compression is no longer
+    // configured:
+    if Compression::Zlib.is_supported() {
+        let mut control = Control::new(MemRepoIO::new());
+        control.set_compression(Compression::Zlib);
+        let mut part = Partition::create(control, "append_log").expect("creating partition");
+        push(&mut part, 1);
+        let io = part.unwrap_control().io().clone();
+        let mut part = Partition::open(appending(io), true).expect("opening partition");
+        push(&mut part, 2);
+        assert_eq!(part.control().io().ss_cl_len(0), 2);
+    }
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn append_log_files() {
+    use std::fs;
+    
+    type Control = DefaultControl<String, RepoFileIO>;
+    let appending = |io: RepoFileIO| {
+        let mut control = Control::new(io);
+        control.set_log_append_limit(Some(DEFAULT_LOG_APPEND_LIMIT));
+        control
+    };
+    let push = |part: &mut Partition<Control>, i: u64| {
+        let mut state = tip_mut(&part);
+        state.insert(EltId::from(i), format!("elt {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+    };
+    let dir = std::env::temp_dir().join(format!("pippin-append-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut part1 = Partition::create(appending(RepoFileIO::new(dir.join("part"))), "append")
+            .expect("creating partition");
+    push(&mut part1, 1);
+    let io = part_from_path(&dir).expect("discovering files");
+    let mut part2 = Partition::open(appending(io), true).expect("opening partition");
+    push(&mut part2, 2);
+    
+    // part1's copy of the index is stale, thus it must not append:
+    push(&mut part1, 3);
+    let log_len = |part: &Partition<Control>| part.control().io().ss_cl_size(0, 0)
+            .expect("reading size").expect("has log");
+    let len = log_len(&part1);
+    let mut io = part_from_path(&dir).expect("discovering files");
+    let stale = io.append_ss_cl_at(0, 0, len - 1, b"junk").expect("appending");
+    let n_logs = io.ss_cl_len(0);
+    let mut part = Partition::open(Control::new(io), true).expect("opening partition");
+    part.load_all().expect("loading");
+    let result = (part.tips_len(), log_len(&part));
+    fs::remove_dir_all(&dir).expect("removing dir");
+    
+    assert!(!stale);
+    assert_eq!(n_logs, 2);
+    assert_eq!(result, (2, len));
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn barrier() {
+    use std::fs;
+    
+    type Control = DefaultControl<String, RepoFileIO>;
+    let dir = std::env::temp_dir().join(format!("pippin-barrier-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut io = RepoFileIO::new(dir.join("part"));
+    io.set_options(FileIoOptions { fsync: false, atomic_rename: false, lock: false });
+    let mut part = Partition::create(Control::new(io), "barrier").expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("durable".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.barrier().expect("barrier");
+    assert_eq!(part.unsaved_len(), 0);
+    part.barrier().expect("barrier with nothing to write");
+    let tip = part.tip_key().expect("has tip").clone();
+    
+    let io = part_from_path(&dir).expect("discovering files");
+    let part = Partition::open(Control::new(io), true).expect("opening partition");
+    let result = part.tip_key().cloned();
+    fs::remove_dir_all(&dir).expect("removing dir");
+    assert_eq!(result.expect("has tip"), tip);
+}
+
+#[test]
+fn sum_filter() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "sum filter")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert(EltId::from(1), "one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let old = part.tip_key().expect("has tip").clone();
+    for i in 2..4 {
+        let mut state = tip_mut(&part);
+        state.insert(EltId::from(i), format!("elt {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_snapshot().expect("writing snapshot");
+    let absent = Sum::calculate(b"absent");
+    assert!(!part.may_have_state(&absent));
+    let io = part.unwrap_control().io().clone();
+    
+    let mut part = Partition::open(Control::new(io.clone()), true).expect("opening partition");
+    assert_eq!(part.loaded_range(), (1, 2));
+    assert!(!part.has_state(&old) && part.may_have_state(&old));
+    assert!(!part.may_have_state(&absent));
+    assert!(!part.find_state(&absent).expect("finding state"));
+    assert_eq!(part.loaded_range(), (1, 2));
+    assert!(part.find_state(&old).expect("finding state"));
+    assert!(part.has_state(&old));
+    
+    // Without a stored filter, nothing can be ruled out until rebuilt:
+    let mut files = MemRepoIO::new();
+    files.insert_ss(0, io.ss_data(0).expect("has snapshot").to_vec());
+    files.insert_ss_cl(0, 0, io.ss_cl_data(0, 0).expect("has log").to_vec());
+    files.insert_ss(1, io.ss_data(1).expect("has snapshot").to_vec());
+    let mut part = Partition::open(Control::new(files), true).expect("opening partition");
+    assert!(part.may_have_state(&absent));
+    assert!(!part.find_state(&old).expect("finding state"));
+    part.rebuild_sum_filter().expect("rebuilding filter");
+    assert!(!part.may_have_state(&absent) && part.may_have_state(&old));
+    assert!(part.find_state(&old).expect("finding state"));
+}
+
+#[derive(Debug, Default)]
+struct CountProgress {
+    bytes_read: Cell<usize>,
+    files: Cell<usize>,
+    commits: Cell<usize>,
+    bytes_written: Cell<usize>,
+    read_limit: Option<usize>,
+}
+impl ProgressSink for CountProgress {
+    fn bytes_read(&self, bytes: usize) -> Result<()> {
+        self.bytes_read.set(self.bytes_read.get() + bytes);
+        match self.read_limit {
+            Some(limit) if self.bytes_read.get() > limit => Err(Box::new(UserError::new(7, "cancelled"))),
+            _ => Ok(()),
+        }
+    }
+    fn file_read(&self, _file: WrittenFile) -> Result<()> {
+        self.files.set(self.files.get() + 1);
+        Ok(())
+    }
+    fn commit_replayed(&self) -> Result<()> {
+        self.commits.set(self.commits.get() + 1);
+        Ok(())
+    }
+    fn bytes_written(&self, bytes: usize) -> Result<()> {
+        self.bytes_written.set(self.bytes_written.get() + bytes);
+        Ok(())
+    }
+}
+
+#[test]
+fn progress() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let mut control = Control::new(MemRepoIO::new());
+    let sink = Rc::new(CountProgress::default());
+    control.set_progress(Some(sink.clone()));
+    let mut part = Partition::create(control, "progress").expect("creating partition");
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    assert_eq!(sink.bytes_written.get(), 0);
+    part.write_snapshot().expect("writing snapshot");
+    assert!(sink.bytes_written.get() > 0);
+    let mut state = tip_mut(&part);
+    state.insert_new("element 3".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let io = part.unwrap_control().io().clone();
+    
+    // Loading everything reads two snapshots and two logs:
+    let mut control = Control::new(io.clone());
+    let sink = Rc::new(CountProgress::default());
+    control.set_progress(Some(sink.clone()));
+    let mut part = Partition::open(control, false).expect("opening partition");
+    part.load_all().expect("loading");
+    assert_eq!(sink.files.get(), 4);
+    assert_eq!(sink.commits.get(), 4);
+    assert_eq!(sink.bytes_read.get(), 3 * 9 + 3 * 9 + 9);
+    assert_eq!(part.tip().expect("has tip").num_avail(), 4);
+    
+    // A failing sink aborts loading:
+    let mut control = Control::new(io);
+    control.set_progress(Some(Rc::new(CountProgress { read_limit: Some(30), ..Default::default() })));
+    let mut part = Partition::open(control, false).expect("opening partition");
+    let err = part.load_all().expect_err("load cancelled");
+    assert_eq!(err.downcast_ref::<UserError>().map(|e| e.code), Some(7));
+}
+
+// Requests cancellation (once) after `limit` bytes have been written
+#[derive(Debug)]
+struct CancelAfter {
+    flag: Arc<AtomicBool>,
+    limit: Cell<Option<usize>>,
+}
+impl ProgressSink for CancelAfter {
+    fn bytes_written(&self, bytes: usize) -> Result<()> {
+        if let Some(limit) = self.limit.get() {
+            if bytes >= limit {
+                self.flag.store(true, Ordering::Relaxed);
+                self.limit.set(None);
+            } else {
+                self.limit.set(Some(limit - bytes));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn cancel() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let is_cancelled = |err: Error| matches!(RepoError::find(&*err), Some(&RepoError::Cancelled));
+    
+    let flag = Arc::new(AtomicBool::new(false));
+    let mut control = Control::new(MemRepoIO::new());
+    control.set_cancel_flag(Some(flag.clone()));
+    control.set_progress(Some(Rc::new(CancelAfter {
+        flag: flag.clone(), limit: Cell::new(Some(40)) })));
+    let mut part = Partition::create(control, "cancel").expect("creating partition");
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    
+    // Cancellation while writing a snapshot leaves no partial file:
+    assert!(is_cancelled(part.write_snapshot().expect_err("cancelled")));
+    assert!(!part.control().io().has_ss(1));
+    flag.store(false, Ordering::Relaxed);
+    part.write_snapshot().expect("writing snapshot");
+    assert!(part.control().io().has_ss(1));
+    
+    // Diverge, then cancel the merge:
+    let base = part.tip().expect("has tip").clone_exact();
+    for i in 0..2 {
+        let mut state = base.clone_mut();
+        state.insert(EltId::from(100 + i), format!("branch {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    flag.store(true, Ordering::Relaxed);
+    assert!(is_cancelled(part.merge(&TwoWaySolveUseA::new(), false).expect_err("cancelled")));
+    assert_eq!(part.tips_len(), 2);
+    let io = part.unwrap_control().io().clone();
+    
+    // Loading stops before the first snapshot; once cleared, loading works:
+    let mut control = Control::new(io);
+    control.set_cancel_flag(Some(flag.clone()));
+    let mut part = Partition::open(control, false).expect("opening partition");
+    assert!(is_cancelled(part.load_all().expect_err("cancelled")));
+    assert!(!part.is_loaded());
+    flag.store(false, Ordering::Relaxed);
+    part.load_all().expect("loading");
+    assert_eq!(part.tips_len(), 2);
+    part.merge_default(false).expect("merging");
+    assert_eq!(part.tip().expect("has tip").num_avail(), 5);
+}
+
+
+// Memory use
+
+#[test]
+fn mem_limit() {
+    let control = WatchControl {
+        io: PartitionStreams::new(),
+        ss_policy: DefaultSnapshot::default(),
+        written: vec![],
+        mem_limit: None,
+    };
+    let mut part = Partition::create(control, "mem_limit")
+            .expect("creating partition");
+    let mut usage = part.mem_usage();
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        assert!(part.mem_usage() > usage);
+        usage = part.mem_usage();
+    }
+    part.write_fast().expect("writing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    
+    // Measure usage of reloaded data (capacities may differ from the original):
+    let mut part = Partition::open(part.unwrap_control(), true).expect("opening partition");
+    let usage = part.mem_usage();
+    assert_eq!(part.evict_history(), 3);
+    assert_eq!(part.states_len(), 1);
+    let tip_usage = part.mem_usage();
+    assert!(tip_usage < usage);
+    
+    let mut control = part.unwrap_control();
+    control.mem_limit = Some(tip_usage);
+    let part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part.states_len(), 1);
+    assert_eq!(*part.tip().expect("has tip"), tip);
+    
+    let mut control = part.unwrap_control();
+    control.mem_limit = Some(tip_usage - 1);
+    let err = Partition::open(control, true).err().expect("exceeds limit");
+    assert_eq!(err.downcast_ref::<MemLimit>(), Some(&MemLimit::new(tip_usage, tip_usage - 1)));
+}
+
+#[test]
+fn compact_memory() {
+    let control = WatchControl {
+        io: PartitionStreams::new(),
+        ss_policy: DefaultSnapshot::default(),
+        written: vec![],
+        mem_limit: None,
+    };
+    let mut part = Partition::create(control, "compact_memory")
+            .expect("creating partition");
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    assert_eq!(part.states_len(), 4);
+    assert_eq!(part.compact_memory(0), 0);
+    let before = part.tip().expect("has tip").meta().timestamp() + 1;
+    assert_eq!(part.compact_memory(before), 3);
+    assert_eq!(part.states_len(), 1);
+    part.write_fast().expect("writing");
+    
+    // Automatic compaction when memory usage exceeds the limit:
+    let mut part = Partition::open(part.unwrap_control(), true).expect("opening partition");
+    part.evict_history();
+    let tip_usage = part.mem_usage();
+    let mut control = part.unwrap_control();
+    control.mem_limit = Some(tip_usage);
+    let mut part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part.states_len(), 1);
+    for i in 3..5 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    assert_eq!(part.states_len(), 3);
+    part.write_full().expect("writing");
+    assert_eq!(part.states_len(), 1);
+}
+
+#[test]
+fn keep_states() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "keep")
+            .expect("creating partition");
+    for i in 0..10 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    
+    let mut control = part.unwrap_control();
+    control.set_keep_states(Some(2));
+    let mut part = Partition::open(control, false).expect("opening partition");
+    part.load_all().expect("loading");
+    assert_eq!(part.states_len(), 3);
+    assert_eq!(*part.tip().expect("has tip"), tip);
+    
+    // Unsaved commits prevent reloading dropped history:
+    let mut state = tip_mut(&part);
+    state.insert_new("unsaved".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.retain_states(0), 2);
+    assert_eq!(part.states_len(), 2);
+    assert!(part.reload_history().is_err());
+    
+    part.write_fast().expect("writing");
+    part.reload_history().expect("reloading");
+    assert_eq!(part.states_len(), 12);
+    assert_eq!(part.tips_len(), 1);
+}
+
+#[test]
+#[cfg(feature = "file-io")]
+fn state_cache() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let dir = std::env::temp_dir().join(format!("pippin-part-cache-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let open_cache = || {
+        let mut cache = StateCache::open(&dir, 1 << 20).expect("opening cache");
+        cache.set_min_commits(5);
+        cache
+    };
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "cache")
+            .expect("creating partition");
+    for i in 0..8 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    let tip = part.tip_key().expect("has tip").clone();
+    let io = part.unwrap_control().unwrap_io();
+    
+    // First load replays commits and caches the tip:
+    let mut control = Control::new(io.clone());
+    control.set_state_cache(Some(open_cache()));
+    let mut part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    let mut control = part.unwrap_control();
+    assert!(control.state_cache().expect("has cache").contains(&tip));
+    
+    // Later loads use the cached state:
+    let mut control = Control::new(io);
+    control.set_state_cache(Some(open_cache()));
+    let part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert_eq!(part.tip().expect("has tip").num_avail(), 8);
+    std::fs::remove_dir_all(&dir).expect("removing dir");
+}
+
+#[test]
+#[cfg(feature = "file-io")]
+fn state_cache_erase() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let dir = std::env::temp_dir().join(format!("pippin-erase-cache-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let open_cache = || {
+        let mut cache = StateCache::open(&dir, 1 << 20).expect("opening cache");
+        cache.set_min_commits(1);
+        cache
+    };
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "erase cache")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let id = state.insert_new("secret".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let cached = part.tip_key().expect("has tip").clone();
+    
+    // Loading caches a state holding the element:
+    let mut control = part.unwrap_control();
+    control.set_state_cache(Some(open_cache()));
+    let mut part = Partition::open(control, true).expect("opening partition");
+    let mut control = part.unwrap_control();
+    assert!(control.state_cache().expect("has cache").contains(&cached));
+    
+    part = Partition::open(control, true).expect("opening partition");
+    let mut state = tip_mut(&part);
+    state.remove(id).expect("removing elt");
+    part.push_state(state).expect("committing");
+    part.erase_element_history(id).expect("erasing");
+    let mut control = part.unwrap_control();
+    assert!(!control.state_cache().expect("has cache").contains(&cached));
+    
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    let state = part.state(&cached).expect("has state");
+    std::fs::remove_dir_all(&dir).expect("removing dir");
+    assert!(!state.is_avail(id));
+    assert!(state.erased_sum(id).is_some());
+}
+
+
+// Merging
+
+#[test]
+fn can_merge() {
+    let control = new_control();
+    let mut part = Partition::create(control, "can_merge").expect("creating partition");
+    let base = part.tip().expect("has tip").clone_exact();
+    let mut state = base.clone_mut();
+    state.insert_new("a".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let mut state = base.clone_mut();
+    state.insert_new("b".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.tips_len(), 2);
+    // Write each tip to a new snapshot:
+    part.safety_snapshot("fork").expect("writing snapshots");
+    let tips = part.tagged("fork").to_vec();
+    assert_eq!(part.can_merge(&tips[0], &tips[1]), MergeReadiness::Ready);
+    
+    let mut part = Partition::open(part.unwrap_control(), false).expect("opening partition");
+    assert_eq!(part.can_merge(&tips[0], &tips[1]), MergeReadiness::NoState);
+    part.load_range(1, usize::MAX, Recovery::Strict).expect("loading");
+    assert_eq!(part.loaded_range(), (1, 3));
+    assert!(part.has_state(&tips[0]) && part.has_state(&tips[1]));
+    assert!(!part.has_state(base.statesum()));
+    let (ss0, ss1) = match part.can_merge(&tips[0], &tips[1]) {
+        MergeReadiness::NeedsHistory(ss0, ss1) => (ss0, ss1),
+        r => panic!("unexpected: {:?}", r),
+    };
+    assert_eq!((ss0, ss1), (0, 1));
+    part.load_range(ss0, ss1, Recovery::Strict).expect("loading");
+    assert_eq!(part.loaded_range(), (0, 3));
+    assert_eq!(part.can_merge(&tips[0], &tips[1]), MergeReadiness::Ready);
+    assert!(part.merge_two(&tips[0], &tips[1]).is_ok());
+    
+    part.evict_history();
+    assert_eq!(part.can_merge(&tips[0], &tips[1]), MergeReadiness::Unavailable);
+}
+
+#[test]
+fn merge_n() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    // Three tips: two make the same change to elt 1, others change or delete
+    // elts 2 and 3; optionally one also changes elt 1 differently.
+    let make_part = |conflict: bool| {
+        let mut part = Partition::create(Control::new(MemRepoIO::new()), "merge n")
+                .expect("creating partition");
+        let mut state = tip_mut(&part);
+        for i in 1..4 {
+            state.insert(EltId::from(i), format!("base {}", i)).expect("inserting elt");
+        }
+        part.push_state(state).expect("committing");
+        let base = part.tip().expect("has tip").clone_exact();
+        
+        let mut state = base.clone_mut();
+        state.replace(EltId::from(1), "changed 1".to_string()).expect("replacing elt");
+        part.push_state(state).expect("committing");
+        let mut state = base.clone_mut();
+        state.replace(EltId::from(1), "changed 1".to_string()).expect("replacing elt");
+        state.replace(EltId::from(2), "changed 2".to_string()).expect("replacing elt");
+        part.push_state(state).expect("committing");
+        let mut state = base.clone_mut();
+        state.remove(EltId::from(3)).expect("removing elt");
+        if conflict {
+            state.replace(EltId::from(1), "other 1".to_string()).expect("replacing elt");
+        }
+        part.push_state(state).expect("committing");
+        assert_eq!(part.tips_len(), 3);
+        part
+    };
+    let solver = AncestorSolverNW::new();
+    
+    let mut part = make_part(false);
+    let tips: Vec<Sum> = part.tips().iter().cloned().collect();
+    part.merge_n(&solver, false).expect("merging");
+    assert_eq!(part.tips_len(), 1);
+    {
+        let tip = part.tip().expect("has tip");
+        assert_eq!(tip.parents().len(), 3);
+        assert!(tips.iter().all(|sum| tip.parents().contains(sum)));
+        let mut elts: Vec<String> = tip.elts_iter().map(|(_, elt)| (**elt).clone()).collect();
+        elts.sort();
+        assert_eq!(elts, vec!["changed 1", "changed 2"]);
+    }
+    let tip = part.tip_key().expect("has tip").clone();
+    part.write_fast().expect("writing");
+    let io = part.unwrap_control().io().clone();
+    let mut part = Partition::open(Control::new(io), true).expect("opening partition");
+    part.load_all().expect("loading");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    
+    let mut part = make_part(true);
+    assert!(part.merge_n(&solver, false).is_err());
+    assert_eq!(part.tips_len(), 3);
+    let fallback = TwoWaySolveUseA::new();
+    part.merge_n(&TwoWayAdapter::new(&fallback), false).expect("merging");
+    assert_eq!(part.tips_len(), 1);
+
+    // A commit may have at most 255 parents:
+    let mut part = make_part(false);
+    let base = {
+        let tip = part.tips().iter().next().expect("has tip").clone();
+        let parent = part.state(&tip).expect("has state").parents()[0].clone();
+        part.state(&parent).expect("has state").clone_exact()
+    };
+    for i in 0..253 {
+        let mut state = base.clone_mut();
+        state.insert(EltId::from(10 + i), format!("tip {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    let tips: Vec<Sum> = part.tips().iter().cloned().collect();
+    assert_eq!(tips.len(), 256);
+    match part.merge_tips(&tips) {
+        Err(MergeError::TooManyStates) => {},
+        _ => panic!("expected TooManyStates"),
+    }
+    let states = tips.iter().map(|sum| part.state(sum).expect("has state")).collect();
+    let merge = NWayMerge::new(states, &base).solve_inline(&TwoWayAdapter::new(&fallback));
+    match merge.make_commit(part.control().as_mcm_ref()) {
+        Err(MergeError::TooManyStates) => {},
+        _ => panic!("expected TooManyStates"),
+    }
+    part.merge_n(&solver, false).expect("merging");
+    assert_eq!(part.tips_len(), 1);
+}
+
+#[cfg(feature = "chaos")]
+#[test]
+fn merge_chaos() {
+    use rand::{SeedableRng, ChaChaRng};
+    
+    // Four tips, each replacing one element and inserting another:
+    let make_part = || {
+        let control = new_control();
+        let mut part = Partition::create(control, "merge chaos").expect("creating partition");
+        let mut state = tip_mut(&part);
+        for i in 1..5 {
+            state.insert(EltId::from(i), format!("base {}", i)).expect("inserting elt");
+        }
+        part.push_state(state).expect("committing");
+        let base = part.tip().expect("has tip").clone_exact();
+        for i in 1..5 {
+            let mut state = base.clone_mut();
+            state.replace(EltId::from(i), format!("changed {}", i)).expect("replacing elt");
+            state.insert(EltId::from(10 + i), format!("new {}", i)).expect("inserting elt");
+            part.push_state(state).expect("committing");
+        }
+        assert_eq!(part.tips_len(), 4);
+        part
+    };
+    let solver = AncestorSolver2W::new();
+    
+    let mut part = make_part();
+    part.merge(&solver, false).expect("merging");
+    let expected = tip_mut(&part).elt_sum().clone();
+    assert_eq!(part.tip().expect("has tip").num_avail(), 8);
+    
+    for seed in 0..10 {
+        let mut part = make_part();
+        part.merge_chaos(&solver, false, ChaChaRng::from_seed(&[seed])).expect("merging");
+        assert_eq!(part.tips_len(), 1);
+        assert_eq!(*tip_mut(&part).elt_sum(), expected);
+    }
+}
+
+#[test]
+fn criss_cross_merge() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mcm = Control::new(MemRepoIO::new());
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "criss-cross")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert(EltId::from(1), "o".to_string()).expect("inserting elt");
+    state.insert(EltId::from(2), "o".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let base = part.tip().expect("has tip").clone_exact();
+    
+    let mut state = base.clone_mut();
+    state.replace(EltId::from(2), "a".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let a = part.tip_key().expect("has tip").clone();
+    let mut state = base.clone_mut();
+    state.replace(EltId::from(1), "b".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let b = part.tips().iter().find(|s| *s != &a).expect("has tip").clone();
+    
+    // Two distinct merges of a and b, each extended by one commit:
+    let solver = AncestorSolver2W::new();
+    let m1 = part.merge_two(&a, &b).expect("merge").solve_inline(&solver)
+            .make_commit(&mcm).expect("solved");
+    let mut m2 = part.merge_two(&a, &b).expect("merge").solve_inline(&solver)
+            .make_commit(&mcm).expect("solved");
+    let mut m2_state = {
+        let parent = part.state(m2.first_parent()).expect("has state");
+        PartState::from_state_commit(parent, &m2).expect("applying commit")
+    };
+    m2.mutate_meta(m2_state.mutate_meta());
+    let (m1_sum, m2_sum) = (m1.statesum().clone(), m2.statesum().clone());
+    assert!(m1_sum != m2_sum);
+    part.push_commit(m1).expect("committing");
+    part.push_commit(m2).expect("committing");
+    let mut state = part.state(&m1_sum).expect("has state").clone_mut();
+    state.replace(EltId::from(1), "t".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let mut state = part.state(&m2_sum).expect("has state").clone_mut();
+    state.insert(EltId::from(3), "z".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let (t1, t2) = {
+        let mut tips: Vec<Sum> = part.tips().iter().cloned().collect();
+        tips.sort();
+        (tips[0].clone(), tips[1].clone())
+    };
+    
+    let mut bases = part.merge_bases(&t1, &t2);
+    bases.sort();
+    let mut expected = vec![a.clone(), b.clone()];
+    expected.sort();
+    assert_eq!(bases, expected);
+    
+    // Element 1 was changed since a on both sides, but not since b:
+    let merge = part.merge_two_with(&t1, &t2, &MergeBase::Given(a.clone())).expect("merge");
+    assert!(!merge.solve_inline(&solver).is_solved());
+    let merge = part.merge_two_with(&t1, &t2, &MergeBase::Given(b.clone())).expect("merge");
+    assert!(merge.solve_inline(&solver).is_solved());
+    let commit = part.merge_two_with(&t1, &t2, &MergeBase::Recursive).expect("merge")
+            .solve_inline(&solver).make_commit(&mcm).expect("solved");
+    part.push_commit(commit).expect("committing");
+    let state = part.tip().expect("has tip");
+    assert_eq!(state.get(EltId::from(1)).expect("has elt"), "t");
+    assert_eq!(state.get(EltId::from(2)).expect("has elt"), "a");
+    assert_eq!(state.get(EltId::from(3)).expect("has elt"), "z");
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn merge_lock() {
+    use std::fs;
+    
+    type Control = DefaultControl<String, RepoFileIO>;
+    let dir = std::env::temp_dir().join(format!("pippin-merge-lock-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut part1 = Partition::create(Control::new(RepoFileIO::new(dir.join("part"))), "merge_lock")
+            .expect("creating partition");
+    let base = part1.tip().expect("has tip").clone_exact();
+    for name in ["one", "two"] {
+        let mut state = base.clone_mut();
+        state.insert(EltId::from(1), name.to_string()).expect("inserting elt");
+        part1.push_state(state).expect("committing");
+    }
+    part1.write_fast().expect("writing");
+    
+    let io = part_from_path(&dir).expect("discovering files");
+    let mut part2 = Partition::open(Control::new(io), true).expect("opening partition");
+    assert_eq!(part2.tips_len(), 2);
+    
+    // The lock is held until the merge is written:
+    part1.merge(&TwoWaySolveUseA::new(), false).expect("merging");
+    let err = part2.merge(&TwoWaySolveUseA::new(), false).err().expect("merge is locked");
+    let in_progress = err.downcast_ref::<MergeError>() == Some(&MergeError::InProgress);
+    part1.write_fast().expect("writing");
+    
+    // Now the merge written by part1 is used:
+    part2.merge(&TwoWaySolveUseA::new(), false).expect("merging");
+    let same_tip = part2.tip_key().ok() == part1.tip_key().ok();
+    let unsaved = part2.unsaved_len();
+    fs::remove_dir_all(&dir).expect("removing dir");
+    assert!(in_progress);
+    assert!(same_tip);
+    assert_eq!(unsaved, 0);
+}
+
+#[test]
+fn reconcile() {
+    fn new_part() -> Partition<StreamControl> {
+        let control = new_control();
+        Partition::create(control, "replica").expect("creating partition")
+    }
+    fn elts(state: &PartState<String>) -> Vec<String> {
+        let mut v: Vec<String> = state.elts_iter().map(|(_, e)| (**e).clone()).collect();
+        v.sort();
+        v
+    }
+    let identity = |elt: &String| elt.chars().next();
+    
+    let mut part_a = new_part();
+    let mut state = tip_mut(&part_a);
+    let a1 = state.insert_new("a1".to_string()).expect("inserting elt");
+    let b1 = state.insert_new("b1".to_string()).expect("inserting elt");
+    part_a.push_state(state).expect("committing");
+    
+    let mut part_b = new_part();
+    let mut state = tip_mut(&part_b);
+    for s in &["a1", "b2", "c1"] {
+        state.insert_new(s.to_string()).expect("inserting elt");
+    }
+    part_b.push_state(state).expect("committing");
+    let foreign = part_b.tip().expect("has tip").clone_exact();
+    
+    let ancestor = AncestorSolver2W::new();
+    assert!(part_a.reconcile(&foreign, identity, &ancestor).is_err());
+    assert_eq!(part_a.tips_len(), 1);
+    
+    let use_b = TwoWaySolveUseB::new();
+    let solver = TwoWaySolverChain::new(&ancestor, &use_b);
+    let tip = part_a.reconcile(&foreign, identity, &solver).expect("reconcile");
+    assert!(part_a.reconcile(&foreign, identity, &solver).is_err());
+    assert_eq!(part_a.tip_key().expect("has tip"), &tip);
+    let tip_state = part_a.tip().expect("has tip").clone_exact();
+    assert_eq!(elts(&tip_state), vec!["a1", "b2", "c1"]);
+    assert_eq!(tip_state.get(a1).expect("a1"), "a1");
+    assert_eq!(tip_state.get(b1).expect("b1"), "b2");
+    assert_eq!(part_a.tagged("graft"), &[foreign.statesum().clone()]);
+    assert_eq!(part_a.unsaved_len(), 0);
+    
+    // History including the graft can be reloaded:
+    let mut part_a = Partition::open(part_a.unwrap_control(), true).expect("opening partition");
+    part_a.load_all().expect("loading");
+    assert_eq!(part_a.tips_len(), 1);
+    assert_eq!(*part_a.tip().expect("has tip"), tip_state);
+    
+    // Later changes from the other replica merge normally (this requires
+    // the latest of several common ancestors as merge base):
+    let mut state = tip_mut(&part_b);
+    state.insert_new("d1".to_string()).expect("inserting elt");
+    part_b.push_state(state).expect("committing");
+    let commit = Commit::from_diff(&foreign, part_b.tip().expect("has tip"))
+            .expect("commit");
+    part_a.push_commit(commit).expect("pushing commit");
+    assert!(part_a.merge_required());
+    part_a.merge(&ancestor, false).expect("merging");
+    assert_eq!(elts(part_a.tip().expect("has tip")), vec!["a1", "b2", "c1", "d1"]);
+}
+
+#[test]
+fn split_off() {
+    let control = new_control();
+    let mut part = Partition::create(control, "split source").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let mut ids = Vec::new();
+    for s in &["apple", "banana", "avocado", "cherry"] {
+        ids.push(state.insert_new(s.to_string()).expect("inserting elt"));
+    }
+    part.push_state(state).expect("committing");
+    
+    let control = new_control();
+    let other = part.split_off(control, "split target", |_, elt| elt.starts_with('a'))
+            .expect("splitting");
+    let tip = part.tip().expect("has tip");
+    let other_tip = other.tip().expect("has tip");
+    assert_eq!(tip.num_avail(), 2);
+    assert_eq!(other_tip.num_avail(), 2);
+    // Identifiers are preserved:
+    assert_eq!(other_tip.get(ids[0]).expect("apple"), "apple");
+    assert_eq!(other_tip.get(ids[2]).expect("avocado"), "avocado");
+    assert_eq!(tip.get(ids[1]).expect("banana"), "banana");
+    assert_eq!(tip.get(ids[3]).expect("cherry"), "cherry");
+    assert!(!tip.is_avail(ids[0]));
+    assert_eq!(part.tagged("split"), &[tip.statesum().clone()]);
+    assert_eq!(part.unsaved_len(), 0);
+    assert_eq!(other.unsaved_len(), 0);
+    
+    // The new partition can be reloaded:
+    let mut other = Partition::open(other.unwrap_control(), true).expect("opening partition");
+    other.load_latest().expect("loading");
+    assert_eq!(other.tip().expect("has tip").get(ids[2]).expect("avocado"), "avocado");
+}
+
+
+// History queries
+
+#[test]
+fn history_queries() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "history")
+            .expect("creating partition");
+    let initial = part.tip_key().expect("has tip").clone();
+    let mut state = tip_mut(&part);
+    state.insert(EltId::from(1), "one".to_string()).expect("inserting elt");
+    state.insert(EltId::from(2), "two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let base = part.tip().expect("has tip").clone_exact();
+    
+    let mut state = base.clone_mut();
+    state.replace(EltId::from(1), "changed".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let base_branch = part.tip_key().expect("has tip").clone();
+    let mut state = base.clone_mut();
+    state.remove(EltId::from(2)).expect("removing elt");
+    state.insert(EltId::from(3), "three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let branch = part.tips().iter().find(|s| *s != &base_branch).expect("has tip").clone();
+    let tips: Vec<Sum> = part.tips().iter().cloned().collect();
+    part.merge(&TwoWaySolveUseA::new(), false).expect("merging");
+    let tip = part.tip_key().expect("has tip").clone();
+    
+    let log: Vec<Sum> = part.log_iter(&tip).expect("log").map(|s| s.statesum().clone()).collect();
+    assert_eq!(log.len(), 5);
+    assert_eq!(log[0], tip);
+    assert!(tips.contains(&log[1]) && tips.contains(&log[2]));
+    assert_eq!(&log[3], base.statesum());
+    assert_eq!(log[4], initial);
+    let ancestors: Vec<Sum> = part.ancestors(&tips[0]).expect("ancestors")
+            .map(|s| s.statesum().clone()).collect();
+    assert_eq!(ancestors, vec![base.statesum().clone(), initial.clone()]);
+    
+    let diff = part.diff(base.statesum(), &branch).expect("diff");
+    let ids: Vec<EltId> = diff.iter().map(|&(id, _)| id).collect();
+    assert_eq!(ids, vec![EltId::from(2), EltId::from(3)]);
+    assert_eq!(diff[0].1, EltChange::Deletion);
+    assert!(part.diff(&tip, &tip).expect("diff").is_empty());
+    assert!(part.log_iter(&Sum::load(&[7; 32])).is_err());
+}
+
+#[test]
+fn elt_history() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "elt history")
+            .expect("creating partition");
+    let id = EltId::from(1);
+    let mut sums = Vec::new();
+    for value in &["one", "uno", "eins"] {
+        let mut state = tip_mut(&part);
+        state.upsert(id, |_| value.to_string());
+        state.insert(EltId::from(2), value.to_string()).ok();
+        part.push_state(state).expect("committing");
+        sums.push(part.tip_key().expect("has tip").clone());
+    }
+    let mut state = tip_mut(&part);
+    state.remove(EltId::from(2)).expect("removing elt");
+    part.push_state(state).expect("committing");
+    let mut state = tip_mut(&part);
+    state.remove(id).expect("removing elt");
+    part.push_state(state).expect("committing");
+    sums.push(part.tip_key().expect("has tip").clone());
+    
+    let history: Vec<_> = part.elt_history(id)
+            .map(|(sum, meta, elt)| (sum.clone(), meta.number(), elt.map(|e| (*e).clone())))
+            .collect();
+    assert_eq!(history.len(), 4);
+    assert_eq!(history.iter().map(|h| h.0.clone()).collect::<Vec<_>>(), sums);
+    assert_eq!(history[0].2, Some("one".to_string()));
+    assert_eq!(history[2].2, Some("eins".to_string()));
+    assert_eq!(history[3].2, None);
+    assert!(history[0].1 < history[1].1);
+    assert_eq!(part.elt_history(EltId::from(2)).len(), 2);
+    assert_eq!(part.elt_history(EltId::from(3)).len(), 0);
+}
+
+#[test]
+fn state_at() {
+    let control = TimedControl {
+        io: MemRepoIO::new(),
+        ss_policy: DefaultSnapshot::default(),
+        time: Cell::new(100),
+    };
+    let mut part = Partition::create(control, "state_at").expect("creating partition");
+    let t100 = part.tip_key().expect("has tip").clone();
+    let mut sums = vec![];
+    for &time in &[200, 300] {
+        part.control().time.set(time);
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("at {}", time)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        sums.push(part.tip_key().expect("has tip").clone());
+        part.write_fast().expect("writing");
+        if time == 200 {
+            part.write_snapshot().expect("writing snapshot");
+        }
+    }
+    
+    let mut part = Partition::open(part.unwrap_control(), false).expect("opening partition");
+    assert_eq!(part.state_at(1000).expect("has state").statesum(), &sums[1]);
+    assert_eq!(part.state_at(299).expect("has state").statesum(), &sums[0]);
+    assert_eq!(part.oldest_ss_loaded(), 1);
+    // Requires loading snapshot 0:
+    assert_eq!(part.state_at(199).expect("has state").statesum(), &t100);
+    assert_eq!(part.oldest_ss_loaded(), 0);
+    assert!(part.state_at(99).is_err());
+}
+
+#[test]
+fn snapshot_diff() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "diff")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let a = state.insert_new("a".to_string()).expect("inserting elt");
+    let b = state.insert_new("bb".to_string()).expect("inserting elt");
+    state.insert_new("ccc".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_snapshot().expect("writing snapshot");
+    
+    let mut state = tip_mut(&part);
+    state.replace(a, "aaaa".to_string()).expect("replacing elt");
+    state.remove(b).expect("removing elt");
+    state.insert_new("ddddd".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_snapshot().expect("writing snapshot");
+    
+    let diff = part.snapshot_diff(1, 2).expect("comparing");
+    assert_eq!(diff, SnapshotDiff {
+        added: 1, removed: 1, changed: 1, unchanged: 1,
+        old_bytes: 6, new_bytes: 12, added_bytes: 9, removed_bytes: 3,
+    });
+    assert_eq!(diff.byte_delta(), 6);
+    assert_eq!(part.snapshot_diff(0, 1).expect("comparing").added, 3);
+    assert!(part.snapshot_diff(1, 3).is_err());
+}
+
+
+// Maintenance: removing, compacting and checking history
+
+#[test]
+fn vacuum() {
+    let control = new_control();
+    let mut part = Partition::create(control, "vacuum")
+            .expect("creating partition");
+    
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        part.write_snapshot().expect("writing snapshot");
+    }
+    let mut state = tip_mut(&part);
+    state.insert_new("unsaved".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    assert!(part.states_len() > 1);
+    
+    // snapshots 0-3 and logs 0-0 to 3-0 exist; snapshot 4 is written by vacuum
+    assert_eq!(part.vacuum(1).expect("vacuum"), 6);
+    assert_eq!(part.unsaved_len(), 0);
+    assert_eq!(part.states_len(), 1);
+    assert_eq!(part.oldest_ss_loaded(), 4);
+    assert_eq!(*part.tip().expect("has tip"), tip);
+    
+    let control = part.unwrap_control();
+    {
+        let io = control.io();
+        assert_eq!(io.ss_len(), 5);
+        assert!((0..3).all(|ss| !io.has_ss(ss) && io.ss_cl_len(ss) == 0));
+        assert!(io.has_ss(3) && io.has_ss(4));
+    }
+    
+    let mut part2 = Partition::open(control, true).expect("opening partition");
+    part2.load_all().expect("part2.load");
+    assert_eq!(part2.tips_len(), 1);
+    assert_eq!(tip, *part2.tip().expect("part2 tip"));
+}
+
+#[test]
+fn vacuum_unmerged() {
+    let control = new_control();
+    let mut part = Partition::create(control, "vacuum").expect("creating partition");
+    let base = part.tip().expect("has tip").clone_exact();
+    let mut state = base.clone_mut();
+    state.insert_new("main".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let main = part.tip_key().expect("has tip").clone();
+    
+    // A branch, never merged, in its own log:
+    let mut state = base.clone_mut();
+    state.insert_new("branch".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let branch = part.tips_iter().find(|sum| **sum != main).expect("branch tip").clone();
+    let mut control = part.unwrap_control();
+    let branch_log = control.io_mut().ss.get_mut(0).and_then(|x| x.1.remove(1))
+            .expect("has log 0-1");
+    
+    // Continue without the branch, then restore its log:
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    part.write_snapshot().expect("writing snapshot");
+    let mut state = tip_mut(&part);
+    state.insert_new("more".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let mut control = part.unwrap_control();
+    control.io_mut().ss.get_mut(0).expect("has ss 0").1.insert(1, branch_log);
+    
+    // Only the latest state is loaded; vacuum must not remove the branch:
+    let mut part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part.tips_len(), 1);
+    assert_eq!(part.vacuum(0).expect("vacuum"), 0);
+    part.load_all().expect("loading");
+    assert_eq!(part.tips_len(), 2);
+    assert!(part.state(&branch).is_some());
+}
+
+#[test]
+fn gc() {
+    let control = new_control();
+    let mut part = Partition::create(control, "gc").expect("creating partition");
+    for i in 0..4 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        part.write_snapshot().expect("writing snapshot");
+    }
+    let tip = part.tip().expect("has tip").clone_exact();
+    
+    // snapshots 0-4 and logs 0-0 to 3-0 exist
+    assert_eq!(part.gc(GcPolicy::KeepSince(i64::MIN)).expect("gc"), 0);
+    assert_eq!(part.gc(GcPolicy::KeepSnapshots(3)).expect("gc"), 4);
+    assert_eq!(part.gc(GcPolicy::KeepSnapshots(3)).expect("gc"), 0);
+    assert_eq!(part.oldest_ss_loaded(), 2);
+    let time = tip.meta().timestamp();
+    assert_eq!(part.gc(GcPolicy::KeepSince(time)).expect("gc"), 4);
+    assert_eq!(part.gc(GcPolicy::KeepSnapshots(0)).expect("gc"), 0);
+    
+    let control = part.unwrap_control();
+    {
+        let io = control.io();
+        assert_eq!(io.ss_len(), 5);
+        assert!((0..4).all(|ss| !io.has_ss(ss) && io.ss_cl_len(ss) == 0));
+    }
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    assert_eq!(*part.tip().expect("has tip"), tip);
+}
+
+#[test]
+fn gc_unmerged() {
+    let control = new_control();
+    let mut part = Partition::create(control, "gc").expect("creating partition");
+    let base = part.tip().expect("has tip").clone_exact();
+    let mut state = base.clone_mut();
+    state.insert_new("main".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    
+    // A branch, never merged, in its own log:
+    let mut state = base.clone_mut();
+    state.insert_new("branch".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    assert_eq!(part.tips_len(), 2);
+    let mut control = part.unwrap_control();
+    let branch_log = control.io_mut().ss.get_mut(0).and_then(|x| x.1.remove(1))
+            .expect("has log 0-1");
+    
+    // Continue without the branch, then restore its log:
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    part.write_snapshot().expect("writing snapshot");
+    for i in 0..2 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        part.write_snapshot().expect("writing snapshot");
+    }
+    let mut control = part.unwrap_control();
+    control.io_mut().ss.get_mut(0).expect("has ss 0").1.insert(1, branch_log);
+    
+    // Snapshot 0 and its logs are needed for the branch:
+    let mut part = Partition::open(control, true).expect("opening partition");
+    assert_eq!(part.gc(GcPolicy::KeepSnapshots(1)).expect("gc"), 0);
+    part.load_all().expect("loading");
+    assert_eq!(part.tips_len(), 2);
+    
+    // Once merged, old files may be removed:
+    part.merge_default(false).expect("merging");
+    part.write_fast().expect("writing");
+    assert_eq!(part.gc(GcPolicy::KeepSnapshots(1)).expect("gc"), 7);
+    let control = part.unwrap_control();
+    assert!((0..3).all(|ss| !control.io().has_ss(ss) && control.io().ss_cl_len(ss) == 0));
+}
+
+#[test]
+fn compact_history() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "compact")
+            .expect("creating partition");
+    let mut middle = None;
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        if i == 1 {
+            middle = Some(part.tip_key().expect("has tip").clone());
+        }
+    }
+    part.write_snapshot().expect("writing snapshot");
+    let squashed = part.tip_key().expect("has tip").clone();
+    let mut state = tip_mut(&part);
+    state.insert_new("element 3".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    // Three commits in three logs are replaced by one; snapshot 1 is latest:
+    assert_eq!(part.compact_history(0, 10).expect("compacting"), 2);
+    assert_eq!(part.compact_history(0, 10).expect("compacting"), 0);
+    let n_logs = part.control().io().ss_cl_len(0);
+    let present = (0..n_logs).filter(|&cl|
+            part.control().io().read_ss_cl(0, cl).expect("reading").is_some()).count();
+    assert_eq!(present, 1);
+    
+    let control = part.unwrap_control();
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    assert!(part.state(&squashed).is_some());
+    assert!(part.state(middle.as_ref().unwrap()).is_none());
+    assert_eq!(part.tips_iter().count(), 1);
+    assert_eq!(part.tip().expect("has tip").num_avail(), 4);
+}
+
+#[test]
+fn elide_empty_merges() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "elide")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let id = state.insert_new("element 0".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let kept = part.tip_key().expect("has tip").clone();
+    
+    // Two branches reach the same elements, thus their merge is empty:
+    let base = part.tip().expect("has tip").clone_exact();
+    let mut state = base.clone_mut();
+    state.insert(EltId::from(10), "x".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let mut state = base.clone_mut();
+    state.insert(EltId::from(10), "y".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let tip = part.tips_iter().find(|t| part.state(t).unwrap().get(EltId::from(10)) == Ok(&"y".to_string()))
+            .expect("branch tip").clone();
+    let mut state = part.state(&tip).expect("state").clone_mut();
+    state.replace(EltId::from(10), "x".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    part.merge_default(false).expect("merging");
+    let merge = part.tip_key().expect("has tip").clone();
+    assert!(part.state(&merge).expect("state").parents().len() > 1);
+    let mut state = tip_mut(&part);
+    state.replace(id, "changed".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    let target = part.tip_key().expect("has tip").clone();
+    
+    // The merge and the branch it joined are dropped; other commits remain:
+    let removed = part.compact_history_with(0, 10, CompactMode::ElideEmptyMerges)
+            .expect("compacting");
+    assert!(removed >= 2);
+    assert_eq!(part.compact_history_with(0, 10, CompactMode::ElideEmptyMerges)
+            .expect("compacting"), 0);
+    
+    let control = part.unwrap_control();
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    assert_eq!(part.tips_len(), 1);
+    assert_eq!(part.tip_key().expect("has tip"), &target);
+    assert!(part.state(&kept).is_some());
+    assert!(part.state(&merge).is_none());
+    let merges = part.states_iter().filter(|s| s.parents().len() > 1).count();
+    assert_eq!(merges, 0);
+    assert_eq!(part.tip().expect("has tip").get(id).map(|s| s.as_str()), Ok("changed"));
+}
+
+#[test]
+fn erase_element_history() {
+    fn contains(data: &[u8], pat: &[u8]) -> bool {
+        data.windows(pat.len()).any(|w| w == pat)
+    }
+    let control = new_control();
+    let mut part = Partition::create(control, "erase").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let secret = state.insert_new("secret one".to_string()).expect("inserting elt");
+    let other = state.insert_new("other".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    let mut state = tip_mut(&part);
+    state.replace(secret, "secret two".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let with_secret = part.tip_key().expect("has tip").clone();
+    
+    assert!(part.erase_element_history(secret).is_err());
+    let mut state = tip_mut(&part);
+    state.remove(secret).expect("removing elt");
+    part.push_state(state).expect("committing");
+    
+    // log 0-0, snapshot 1 and log 1-0 (unsaved until now):
+    assert_eq!(part.erase_element_history(secret).expect("erasing"), 3);
+    let state = part.state(&with_secret).expect("has state");
+    assert!(!state.is_avail(secret));
+    assert!(state.erased_sum(secret).is_some());
+    
+    let control = part.unwrap_control();
+    for &(ref ss, ref logs) in control.io().ss.values() {
+        for data in ss.iter().chain(logs.values()) {
+            assert!(!contains(data, b"secret"));
+            assert!(contains(data, b"PIPPIN"));
+        }
+    }
+    
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    let state = part.state(&with_secret).expect("has state");
+    assert!(state.erased_sum(secret).is_some());
+    assert_eq!(state.get(other).expect("has elt"), "other");
+    assert_eq!(part.tip().expect("has tip").num_avail(), 1);
+    let mut state = tip_mut(&part);
+    state.insert_new("another".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_snapshot().expect("writing snapshot");
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn erase_element_history_files() {
+    use std::fs;
+    
+    type Control = DefaultControl<String, RepoFileIO>;
+    let dir = std::env::temp_dir().join(format!("pippin-erase-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let mut part = Partition::create(Control::new(RepoFileIO::new(dir.join("part"))), "erase")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    let secret = state.insert_new("secret".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let mut state = tip_mut(&part);
+    state.remove(secret).expect("removing elt");
+    part.push_state(state).expect("committing");
+    part.write_full().expect("writing");
+    
+    // snapshot 0 is empty; log 0-0 holds the element:
+    assert_eq!(part.erase_element_history(secret).expect("erasing"), 1);
+    let mut names = fs::read_dir(&dir).expect("reading dir")
+            .map(|entry| entry.expect("reading dir").file_name().into_string().expect("name"))
+            .collect::<Vec<_>>();
+    names.sort();
+    let log = fs::read(dir.join("part-ss0-cl0.piplog")).expect("reading log");
+    
+    // Replacing a file which does not exist is an error:
+    let mut io = part_from_path(&dir).expect("discovering files");
+    let not_found = io.replace_ss_cl(0, 1, b"").expect("replacing");
+    let mut part = Partition::open(Control::new(io), true).expect("opening partition");
+    part.load_all().expect("loading");
+    let erased = part.states_iter().any(|state| state.erased_sum(secret).is_some());
+    fs::remove_dir_all(&dir).expect("removing dir");
+    
+    assert!(!names.iter().any(|name| name.ends_with(".tmp")));
+    assert!(!log.windows(6).any(|w| w == b"secret"));
+    assert!(!not_found);
+    assert!(erased);
+}
+
+#[test]
+fn archive() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "archive")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert(EltId::from(1), "one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert!(part.archive().expect("archiving"));
+    assert!(!part.archive().expect("archiving"));
+    assert!(part.is_archived() && !part.is_loaded());
+    assert_eq!(part.vacuum(0).expect("vacuum"), 0);
+    
+    let io = part.unwrap_control().io().clone();
+    let mut part = Partition::open(Control::new(io), true).expect("opening partition");
+    assert!(part.is_archived() && !part.is_loaded());
+    assert_eq!(part.header_info().map(|info| info.archived), Some(true));
+    part.load_auto(LoadGoal::Edit).expect("loading");
+    assert!(!part.is_loaded());
+    part.load_latest().expect("loading");
+    let make_state = |part: &Partition<Control>| {
+        let mut state = tip_mut(&part);
+        assert_eq!(state.get(EltId::from(1)).map(|s| s.as_str()), Ok("one"));
+        state.insert(EltId::from(2), "two".to_string()).expect("inserting elt");
+        state
+    };
+    assert_eq!(part.push_state(make_state(&part)), Err(PatchOp::Archived));
+    
+    assert!(part.unarchive().expect("unarchiving"));
+    assert!(!part.is_archived());
+    part.push_state(make_state(&part)).expect("committing");
+    part.write_full().expect("writing");
+    let ops: Vec<AuditOp> = part.audit_log().expect("reading audit log")
+            .into_iter().map(|entry| entry.op).collect();
+    assert_eq!(ops, vec![AuditOp::Archive, AuditOp::Unarchive]);
+    
+    let io = part.unwrap_control().io().clone();
+    let part = Partition::open(Control::new(io), true).expect("opening partition");
+    assert!(!part.is_archived() && part.is_loaded());
+}
+
+#[test]
+fn audit_log() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "audit")
+            .expect("creating partition");
+    for i in 0..3 {
+        let mut state = tip_mut(&part);
+        state.insert_new(format!("element {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("writing");
+        part.write_snapshot().expect("writing snapshot");
+    }
+    assert!(part.audit_log().expect("reading audit log").is_empty());
+    part.gc(GcPolicy::KeepSnapshots(2)).expect("gc");
+    part.vacuum(0).expect("vacuum");
+    
+    let entries = part.audit_log().expect("reading audit log");
+    assert_eq!(entries.iter().map(|e| e.op).collect::<Vec<_>>(), vec![AuditOp::Gc, AuditOp::Vacuum]);
+    assert!(entries[0].detail.contains("removed 4 files"));
+    
+    let io = part.unwrap_control().unwrap_io();
+    assert!(!io.audit_data().is_empty());
+    let part = Partition::open(Control::new(io), true).expect("opening partition");
+    assert_eq!(part.audit_log().expect("reading audit log"), entries);
+}
+
+#[test]
+fn health() {
+    let control = new_control();
+    let mut part = Partition::create(control, "health").expect("creating partition");
+    let health = part.health();
+    assert!(health.is_healthy());
+    assert_eq!((health.tips, health.unsaved, health.read_only), (1, 0, false));
+    assert_eq!(health.last_snapshot_write.is_some(), cfg!(feature = "system-clock"));
+    assert_eq!(health.last_log_write, None);
+    
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.health().unsaved, 1);
+    part.write_fast().expect("writing");
+    let health = part.health();
+    assert_eq!(health.unsaved, 0);
+    assert_eq!(health.last_log_write.is_some(), cfg!(feature = "system-clock"));
+    
+    part.unload(true);
+    let health = part.health();
+    assert!(!health.loaded);
+    assert!(!health.is_healthy());
+    assert_eq!(health.io_error, None);
+}
+
+#[test]
+fn format_report() {
+    let control = new_control();
+    let mut part = Partition::create(control, "format report").expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    
+    let report = part.format_report().expect("reporting");
+    assert_eq!(report.oldest_ss, Some(0));
+    assert_eq!(report.newest_ss, Some(1));
+    assert_eq!(report.versions.values().sum::<usize>(), 3);
+    assert_eq!(report.versions.len(), 1);
+    assert_eq!(report.deprecated, 0);
+    assert_eq!(report.unreadable, 0);
+    assert!(!report.needs_migration());
+    
+    // Older versions are not deprecated unless before the cutoff:
+    assert!(!FileType::CommitLog(2016_08_15).is_latest());
+    assert!(!FileType::CommitLog(2016_08_15).is_deprecated());
+    assert!(FileType::Snapshot(2016_05_16).is_deprecated());
+}
+
+#[test]
+fn verify() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "verify")
+            .expect("creating partition");
+    for s in &["one", "two", "three"] {
+        let mut state = tip_mut(&part);
+        state.insert_new(s.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    let mut state = tip_mut(&part);
+    state.insert_new("four".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    
+    let report = part.verify(VerifyLevel::Full).expect("verifying");
+    assert!(report.is_ok(), "problems: {:?}", report.problems);
+    assert_eq!((report.snapshots, report.logs, report.commits), (2, 2, 4));
+    assert_eq!(part.verify(VerifyLevel::Headers).expect("verifying").commits, 0);
+    
+    // Corruption of a log is detected:
+    let mut control = part.unwrap_control();
+    let log = control.io().ss_cl_data(1, 0).expect("has log").to_vec();
+    let n = control.io().ss_cl_data(0, 0).expect("has log").len() - 40;
+    control.io_mut().ss_cl_data_mut(0, 0).expect("has log")[n] ^= 1;
+    let part = Partition::open(control, false).expect("opening partition");
+    assert!(part.verify(VerifyLevel::Headers).expect("verifying").is_ok());
+    for level in &[VerifyLevel::Checksums, VerifyLevel::Full] {
+        let report = part.verify(*level).expect("verifying");
+        assert_eq!(report.problems.len(), 1);
+        match report.problems[0] {
+            (WrittenFile::CommitLog(0, 0), VerifyProblem::Unreadable(_)) => {},
+            ref p => panic!("unexpected problem: {:?}", p),
+        }
+    }
+    
+    // A commit whose parent is not found is reported:
+    let part = Partition::create(Control::new(MemRepoIO::new()), "verify")
+            .expect("creating partition");
+    let mut control = part.unwrap_control();
+    control.io_mut().insert_ss_cl(0, 0, log);
+    let part = Partition::open(control, false).expect("opening partition");
+    let report = part.verify(VerifyLevel::Full).expect("verifying");
+    assert_eq!(report.problems.len(), 1);
+    match report.problems[0] {
+        (WrittenFile::CommitLog(0, 0), VerifyProblem::MissingParent(_)) => {},
+        ref p => panic!("unexpected problem: {:?}", p),
+    }
+}
+
+
+// Synchronisation and exchange of data
+
+#[test]
+fn sync() {
+    use std::collections::VecDeque;
+    use pippin::sync;
+    
+    type Control = DefaultControl<String, MemRepoIO>;
+    // Transport passing messages to a partition serving requests
+    struct Loopback<'a> {
+        server: &'a mut Partition<Control>,
+        replies: VecDeque<Vec<u8>>,
+    }
+    struct Pipe {
+        inbox: VecDeque<Vec<u8>>,
+        outbox: VecDeque<Vec<u8>>,
+    }
+    impl SyncTransport for Pipe {
+        fn send(&mut self, msg: &[u8]) -> Result<()> {
+            self.outbox.push_back(msg.to_vec());
+            Ok(())
+        }
+        fn receive(&mut self) -> Result<Option<Vec<u8>>> {
+            Ok(self.inbox.pop_front())
+        }
+    }
+    impl<'a> SyncTransport for Loopback<'a> {
+        fn send(&mut self, msg: &[u8]) -> Result<()> {
+            let mut pipe = Pipe { inbox: vec![msg.to_vec()].into(), outbox: VecDeque::new() };
+            sync::serve_one(self.server, &mut pipe)?;
+            self.replies.extend(pipe.outbox);
+            Ok(())
+        }
+        fn receive(&mut self) -> Result<Option<Vec<u8>>> {
+            Ok(self.replies.pop_front())
+        }
+    }
+    fn add(part: &mut Partition<Control>, elt: &str) {
+        let mut state = tip_mut(&part);
+        state.insert_new(elt.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    
+    let mut a = Partition::create(Control::new(MemRepoIO::new()), "sync")
+            .expect("creating partition");
+    add(&mut a, "common");
+    a.write_fast().expect("writing");
+    let io = a.control().io().clone();
+    let mut control = Control::new(io);
+    control.set_replica_id(Some(2));
+    let mut b = Partition::open(control, true).expect("opening partition");
+    add(&mut a, "from a");
+    add(&mut b, "from b");
+    add(&mut b, "also from b");
+    
+    let result = {
+        let mut transport = Loopback { server: &mut a, replies: VecDeque::new() };
+        sync::sync(&mut b, &mut transport, false)
+    };
+    // Pulled one commit; pushed two plus the merge commit:
+    assert_eq!(result.expect("syncing"), (1, 3));
+    assert!(a.is_ready() && b.is_ready());
+    assert_eq!(a.tip_key().expect("has tip"), b.tip_key().expect("has tip"));
+    assert_eq!(a.tip().expect("has tip").num_avail(), 4);
+    
+    // Commits received by `a` record `b` as their source:
+    let sources: Vec<Vec<u64>> = a.states_iter()
+            .map(|state| state.meta().provenance().iter().map(|p| p.source).collect())
+            .filter(|sources: &Vec<u64>| !sources.is_empty())
+            .collect();
+    assert_eq!(sources, vec![vec![2]; 3]);
+    
+    // Nothing more to exchange:
+    let mut transport = Loopback { server: &mut a, replies: VecDeque::new() };
+    assert_eq!(sync::sync(&mut b, &mut transport, false).expect("syncing"), (0, 0));
+}
+
+#[test]
+fn adopt_stream() {
+    let control = new_control();
+    let mut part = Partition::create(control, "adopt").expect("creating partition");
+    let ss0 = part.control().io().ss.get(0).and_then(|x| x.0.clone()).expect("has ss0");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    part.write_snapshot().expect("writing snapshot");
+    let tip = part.tip_key().expect("has tip").clone();
+    let io = &part.control().io().ss;
+    let log = io.get(0).and_then(|x| x.1.get(0).cloned()).expect("has log");
+    let ss1 = io.get(1).and_then(|x| x.0.clone()).expect("has ss1");
+    
+    let mut streams = PartitionStreams::new();
+    streams.ss.insert(0, (Some(ss0), VecMap::new()));
+    let mut part2 = Partition::open(StreamControl::new(streams), true).expect("opening partition");
+    let mut bad = log.clone();
+    let len = bad.len();
+    bad[len - 40] ^= 1;
+    assert!(part2.adopt_stream(&mut &bad[..]).is_err());
+    assert_eq!(part2.control().io().ss_cl_len(0), 0);
+    assert_eq!(part2.adopt_stream(&mut &log[..]).expect("adopting log"),
+            WrittenFile::CommitLog(0, 0));
+    assert_eq!(part2.adopt_stream(&mut &ss1[..]).expect("adopting snapshot"),
+            WrittenFile::Snapshot(1));
+    part2.unload(true);
+    part2.load_all().expect("loading");
+    assert_eq!(part2.tip_key().expect("has tip"), &tip);
+    
+    let control = new_control();
+    let mut other = Partition::create(control, "other").expect("creating partition");
+    assert!(other.adopt_stream(&mut &log[..]).is_err());
+    assert_eq!(other.control().io().ss_cl_len(0), 0);
+}
+
+#[test]
+fn adopt_provenance() {
+    let control = new_control();
+    let mut part = Partition::create(control, "adopt").expect("creating partition");
+    let ss0 = part.control().io().ss.get(0).and_then(|x| x.0.clone()).expect("has ss0");
+    let mut state = tip_mut(&part);
+    state.insert_new("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let tip = part.tip_key().expect("has tip").clone();
+    let log = part.control().io().ss.get(0).and_then(|x| x.1.get(0).cloned()).expect("has log");
+    
+    let mut streams = PartitionStreams::new();
+    streams.ss.insert(0, (Some(ss0), VecMap::new()));
+    let mut part2 = Partition::open(StreamControl::new(streams), true).expect("opening partition");
+    part2.adopt_stream_from(&mut &log[..], 7).expect("adopting log");
+    let io = part2.unwrap_control().unwrap_io();
+    let part3 = Partition::open(StreamControl::new(io), true).expect("opening partition");
+    let state = part3.tip().expect("has tip");
+    assert_eq!(state.statesum(), &tip);
+    let provenance = state.meta().provenance();
+    assert_eq!(provenance.len(), 1);
+    assert_eq!(provenance[0].source, 7);
+}
+
+#[test]
+fn receive_commits() {
+    use pippin::rw::commitlog::{read_log, start_log, write_commit};
+    use pippin::rw::header::read_head;
+    
+    let control = new_control();
+    let mut part = Partition::create(control, "receive").expect("creating partition");
+    let ss0 = part.control().io().ss.get(0).and_then(|x| x.0.clone()).expect("has ss0");
+    for s in &["one", "two", "three"] {
+        let mut state = tip_mut(&part);
+        state.insert_new(s.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write_fast().expect("writing");
+    let tip = part.tip_key().expect("has tip").clone();
+    let log = part.control().io().ss.get(0).and_then(|x| x.1.get(0).cloned()).expect("has log");
+    let mut r = &log[..];
+    let header = read_head(&mut r).expect("reading header");
+    let mut commits: Vec<Commit<String>> = Vec::new();
+    read_log(&mut r, &mut commits, header.ftype.ver(), &part.control().user_meta_limits(),
+            &mut EltReader::default()).expect("reading log");
+    assert_eq!(commits.len(), 3);
+    let stream = |order: &[usize]| {
+        let mut buf = Vec::new();
+        start_log(&mut buf).expect("writing");
+        for i in order {
+            write_commit(&commits[*i], &mut buf).expect("writing");
+        }
+        buf
+    };
+    
+    let mut streams = PartitionStreams::new();
+    streams.ss.insert(0, (Some(ss0), VecMap::new()));
+    let mut part2 = Partition::open(StreamControl::new(streams), true).expect("opening partition");
+    let report = part2.receive_commits(&mut &stream(&[2])[..]).expect("receiving");
+    assert!(report.applied.is_empty());
+    assert_eq!(report.deferred.len(), 1);
+    
+    let report = part2.receive_commits_from(&mut &stream(&[1, 0, 0])[..], 3).expect("receiving");
+    assert_eq!(report.applied, vec![commits[0].statesum().clone(), commits[1].statesum().clone()]);
+    assert_eq!(report.known, vec![commits[0].statesum().clone()]);
+    assert!(report.deferred.is_empty() && report.rejected.is_empty());
+    assert_eq!(part2.tip().expect("has tip").meta().provenance()[0].source, 3);
+    
+    let mut bad = stream(&[2]);
+    let len = bad.len();
+    bad[len - 40] ^= 1;
+    assert!(part2.receive_commits(&mut &bad[..]).is_err());
+    let report = part2.receive_commits(&mut &stream(&[2])[..]).expect("receiving");
+    assert_eq!(report.applied.len(), 1);
+    assert_eq!(part2.tip_key().expect("has tip"), &tip);
+}
+
+#[test]
+fn acknowledgements() {
+    fn commit(part: &mut Partition<StreamControl>, s: &str) -> Sum {
+        let mut state = tip_mut(&part);
+        state.insert_new(s.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.tip_key().expect("has tip").clone()
+    }
+    let control = new_control();
+    let mut part = Partition::create(control, "acks").expect("creating partition");
+    let s1 = commit(&mut part, "one");
+    let s2 = commit(&mut part, "two");
+    part.write_fast().expect("writing");
+    
+    assert!(part.mark_acked(1, s1.clone()));
+    assert!(part.mark_acked(2, s2.clone()));
+    assert_eq!(part.latest_fully_acked(&[1, 2]), Some(s1.clone()));
+    assert_eq!(part.latest_fully_acked(&[1, 3]), None);
+    assert!(!part.mark_acked(2, s1.clone()));
+    assert!(part.mark_acked(1, s2.clone()));
+    assert_eq!(part.acks()[&1].len(), 1);
+    assert_eq!(part.latest_fully_acked(&[1, 2]), Some(s2.clone()));
+    
+    // Acknowledgements are written with the next commit and don't affect sums:
+    let s3 = commit(&mut part, "three");
+    part.write_fast().expect("writing");
+    let control = part.unwrap_control();
+    let mut part = Partition::open(control, true).expect("opening partition");
+    part.load_all().expect("loading");
+    assert_eq!(part.tip_key().expect("has tip"), &s3);
+    assert_eq!(part.latest_fully_acked(&[1, 2]), Some(s2.clone()));
+    
+    // ... or recorded in the next snapshot:
+    assert!(part.mark_acked(2, s3.clone()));
+    part.write_snapshot().expect("writing snapshot");
+    let control = part.unwrap_control();
+    let mut part = Partition::open(control, false).expect("opening partition");
+    part.load_latest().expect("loading");
+    assert_eq!(part.latest_fully_acked(&[2]), Some(s3.clone()));
+    assert!(part.acks()[&1].contains(&s2));
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn import_archive_to_files() {
+    use std::fs;
+    
+    let mut part = Partition::create(DefaultControl::new(MemRepoIO::new()), "archived")
+            .expect("creating partition");
+    let mut state = tip_mut(&part);
+    state.insert_new("in snapshot".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_snapshot().expect("writing snapshot");
+    let mut state = tip_mut(&part);
+    state.insert_new("in log".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write_fast().expect("writing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    let io = part.unwrap_control().unwrap_io();
+    let mut buf = Vec::new();
+    export_archive(&[("archived", &io)], &mut buf).expect("exporting");
+    
+    let dir = std::env::temp_dir().join(format!("pippin-import-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    let imported = import_archive(&mut &buf[..], |name| Ok(RepoFileIO::new(dir.join(name))))
+            .expect("importing");
+    drop(imported);
+    let io = part_from_path(&dir).expect("discovering files");
+    let result = Partition::open(DefaultControl::<String, _>::new(io), true)
+            .and_then(|mut part| { part.load_all()?; Ok(part) })
+            .map(|part| part.tip().map(|tip| tip.clone_exact()).ok());
+    fs::remove_dir_all(&dir).expect("removing dir");
+    assert_eq!(result.expect("opening partition"), Some(tip));
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn ingest_dir() {
+    use std::fs;
+    
+    let dir = std::env::temp_dir().join(format!("pippin-ingest-{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub")).expect("creating dir");
+    fs::write(dir.join("a.txt"), "alpha").expect("writing file");
+    fs::write(dir.join("sub/b.txt"), "beta").expect("writing file");
+    fs::write(dir.join("skip.bin"), [0u8, 1]).expect("writing file");
+    
+    let control = new_control();
+    let mut part = Partition::create(control, "ingest").expect("creating partition");
+    let n = ingest(&mut part, &dir, |path| {
+        if path.extension() == Some("txt".as_ref()) {
+            Ok(Some(fs::read_to_string(path)?))
+        } else {
+            Ok(None)
+        }
+    });
+    fs::remove_dir_all(&dir).expect("removing dir");
+    assert_eq!(n.expect("ingest"), 2);
+    assert_eq!(part.unsaved_len(), 0);
+    let mut elts: Vec<String> = part.tip().expect("has tip").elts_iter()
+            .map(|(_, elt)| (**elt).clone()).collect();
+    elts.sort();
+    assert_eq!(elts, vec!["alpha".to_string(), "beta".to_string()]);
+    
+    let control = part.unwrap_control();
+    assert!(control.io().has_ss(1));
+    assert_eq!(control.io().ss_cl_len(1), 0);
+}
+
+#[cfg(feature = "file-io")]
+#[test]
+fn discover_filtered() {
+    use std::fs;
+    
+    let dir = std::env::temp_dir().join(format!("pippin-discover-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating dir");
+    for name in &["p-ss0.pip", "p-ss0-cl0.piplog", "p-ss1 (Bob's conflicted copy).pip",
+        "p-ss1.sync-conflict-20161017-120000.pip", "old-ss0.pip", "notes.txt"]
+    {
+        fs::write(dir.join(name), "").expect("writing file");
+    }
+    
+    let filter = DiscoverFilter::new().exclude("^old-").expect("valid pattern");
+    assert!(filter.accepts("p-ss0.pip"));
+    assert!(!filter.accepts("old-ss0.pip"));
+    assert!(filter.is_conflict_copy("p-ss1 (Bob's Conflicted Copy).pip"));
+    assert!(!filter.clone().no_conflicts().is_conflict_copy("p-ss1 (Bob's conflicted copy).pip"));
+    assert!(DiscoverFilter::new().include("(").is_err());
+    
+    let result = part_from_path_filtered(&dir, &filter);
+    fs::remove_dir_all(&dir).expect("removing dir");
+    let (io, mut conflicts) = result.expect("discovering");
+    assert_eq!(io.ss_len(), 1);
+    assert_eq!(io.ss_cl_len(0), 1);
+    conflicts.sort();
+    let names: Vec<_> = conflicts.iter().map(|p| p.file_name().expect("file name")).collect();
+    assert_eq!(names, vec!["p-ss1 (Bob's conflicted copy).pip",
+            "p-ss1.sync-conflict-20161017-120000.pip"]);
+}
+
+#[cfg(feature = "gen")]
+#[test]
+fn gen_populate() {
+    use rand::{SeedableRng, ChaChaRng};
+    use pippin::gen::{Sequence, GenSpec, LenDist};
+    
+    type Control = DefaultControl<Sequence, PartitionStreams>;
+    let spec = GenSpec { num_elts: 20, len_dist: LenDist::Uniform(0, 30), max_len: 25 };
+    let populate = || {
+        let control = Control::new(PartitionStreams::new());
+        let mut part = Partition::create(control, "gen").expect("creating partition");
+        let mut rng = ChaChaRng::from_seed(&[17]);
+        let stats = spec.populate(&mut part, 3, &mut rng).expect("populating");
+        (part, stats)
+    };
+    
+    let (mut part1, stats) = populate();
+    assert_eq!(stats.num_elts, 60);
+    assert!(stats.longest <= 25);
+    assert_eq!(part1.tip().expect("has tip").num_avail(), 60);
+    part1.write_full().expect("writing");
+    
+    // Same seed and specification: same data
+    let (part2, stats2) = populate();
+    assert_eq!(stats, stats2);
+    assert_eq!(part1.tip_key().expect("has tip"), part2.tip_key().expect("has tip"));
+}
+
+
+// Notifications
+
+#[test]
+fn subscribe() {
+    let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+    let mut part = Partition::create(control, "subscribe").expect("creating partition");
+    let mut state = tip_mut(&part);
+    let a = state.insert_new("a".to_string()).expect("inserting elt");
+    let b = state.insert_new("b".to_string()).expect("inserting elt");
+    let c = state.insert_new("c".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    let sub = part.subscribe(vec![a, b]);
+    let other = part.subscribe(vec![c]);
+    let mut state = tip_mut(&part);
+    state.replace(a, "A".to_string()).expect("replacing elt");
+    state.remove(b).expect("removing elt");
+    part.push_state(state).expect("committing");
+    let tip1 = part.tip_key().expect("has tip").clone();
+    
+    let mut state = tip_mut(&part);
+    state.replace(c, "C".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    
+    let mut expected = vec![(a, EltNotice::Changed), (b, EltNotice::Removed)];
+    expected.sort_by_key(|x| x.0);
+    assert_eq!(part.take_notifications(sub),
+            vec![Notification { statesum: tip1, changes: expected }]);
+    assert!(part.take_notifications(sub).is_empty());
+    
+    part.subscription_ids_mut(sub).expect("has subscription").insert(c);
+    let mut state = tip_mut(&part);
+    state.remove(c).expect("removing elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.take_notifications(sub)[0].changes, vec![(c, EltNotice::Removed)]);
+    assert_eq!(part.take_notifications(other).len(), 2);
+    
+    assert!(part.unsubscribe(sub));
+    assert!(!part.unsubscribe(sub));
+    assert!(part.take_notifications(sub).is_empty());
+}
+
+#[test]
+fn watch() {
+    type Control = DefaultControl<String, MemRepoIO>;
+    let mut part = Partition::create(Control::new(MemRepoIO::new()), "watch")
+            .expect("creating partition");
+    let all = part.watch(WatchFilter::All);
+    let mut state = tip_mut(&part);
+    let a = state.insert_new("a".to_string()).expect("inserting elt");
+    let b = state.insert_new("b".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let tip1 = part.tip_key().expect("has tip").clone();
+    
+    let only_b = part.watch(WatchFilter::Elements(vec![b].into_iter().collect()));
+    let mut state = tip_mut(&part);
+    state.replace(a, "A".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let mut state = tip_mut(&part);
+    state.remove(b).expect("removing elt");
+    part.push_state(state).expect("committing");
+    let tip3 = part.tip_key().expect("has tip").clone();
+    
+    let events: Vec<Notification> = all.try_iter().collect();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].statesum, tip1);
+    assert_eq!(events[0].changes.len(), 2);
+    assert_eq!(events[1].changes, vec![(a, EltNotice::Changed)]);
+    assert_eq!(only_b.try_iter().collect::<Vec<_>>(),
+            vec![Notification { statesum: tip3, changes: vec![(b, EltNotice::Removed)] }]);
+    drop(only_b);
+    part.write_fast().expect("writing");
+    
+    // loaded commits are reported to watchers
+    let mut part = Partition::open(part.unwrap_control(), false).expect("opening partition");
+    let loaded = part.watch(WatchFilter::Elements(vec![a].into_iter().collect()));
+    part.load_all().expect("loading");
+    let events: Vec<Notification> = loaded.try_iter().collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[1].changes, vec![(a, EltNotice::Changed)]);
+}